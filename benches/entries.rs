@@ -0,0 +1,113 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The throughput of putting objects of different sizes in cache entries
+//! and getting them back, in the tar format entries are written in against
+//! the zip format before it, and the size of the entries.
+//!
+//! Run with `cargo bench --bench entries`.
+
+use sccache::cache::{CacheRead, CacheWrite};
+use std::io::{Cursor, Read};
+use std::time::{Duration, Instant};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// How long to put or get each size for.
+const DURATION: Duration = Duration::from_secs(2);
+
+const SIZES: &[usize] = &[4 * 1024, 64 * 1024, 1024 * 1024, 16 * 1024 * 1024];
+
+/// Bytes of `object` handled per second by `run`, and what it returned the
+/// last time, so that it can't be left out.
+fn throughput<T>(object: &[u8], run: impl Fn() -> T) -> (f64, T) {
+    let start = Instant::now();
+    let mut runs = 0;
+    loop {
+        let last = run();
+        runs += 1;
+        if start.elapsed() >= DURATION {
+            let per_sec = (object.len() * runs) as f64 / start.elapsed().as_secs_f64();
+            return (per_sec, last);
+        }
+    }
+}
+
+/// An entry of `object` and some stdout in the tar format.
+fn put_tar(object: &[u8]) -> Vec<u8> {
+    let mut entry = CacheWrite::new();
+    entry
+        .put_object("obj", &mut Cursor::new(object), Some(0o644))
+        .unwrap();
+    entry.put_stdout(b"warning: unused variable").unwrap();
+    entry.finish().unwrap()
+}
+
+/// The same entry in the zip format, written as it was.
+fn put_zip(object: &[u8]) -> Vec<u8> {
+    let mut zip = ZipWriter::new(Cursor::new(vec![]));
+    let opts = FileOptions::default().compression_method(CompressionMethod::Stored);
+    zip.start_file("obj", opts.unix_permissions(0o644)).unwrap();
+    zstd::stream::copy_encode(Cursor::new(object), &mut zip, 3).unwrap();
+    zip.start_file("stdout", opts).unwrap();
+    zstd::stream::copy_encode(&b"warning: unused variable"[..], &mut zip, 3).unwrap();
+    zip.finish().unwrap().into_inner()
+}
+
+fn get(entry: &[u8]) -> Vec<u8> {
+    let mut entry = CacheRead::from(Cursor::new(entry.to_vec())).unwrap();
+    let mut object = vec![];
+    entry.get_object("obj", &mut object).unwrap();
+    object
+}
+
+/// Get the object of a zip entry as it was, checking its CRC.
+fn get_zip(entry: &[u8]) -> Vec<u8> {
+    let mut zip = ZipArchive::new(Cursor::new(entry.to_vec())).unwrap();
+    let mut file = zip.by_name("obj").unwrap();
+    let mut compressed = vec![];
+    file.read_to_end(&mut compressed).unwrap();
+    let mut object = vec![];
+    zstd::stream::copy_decode(Cursor::new(compressed), &mut object).unwrap();
+    object
+}
+
+fn main() {
+    println!(
+        "{:>10} {:>12} {:>12} {:>12} {:>12} {:>12} {:>12}",
+        "size", "put zip", "put tar", "get zip", "get tar", "zip bytes", "tar bytes"
+    );
+    for &size in SIZES {
+        // Somewhat compressible, like objects
+        let object: Vec<u8> = (0..size).map(|i| (i * 7 + i / 4096) as u8 & 0x3f).collect();
+        let (put_zip_rate, zip) = throughput(&object, || put_zip(&object));
+        let (put_tar_rate, tar) = throughput(&object, || put_tar(&object));
+        let (get_zip_rate, from_zip) = throughput(&object, || get_zip(&zip));
+        let (get_tar_rate, from_tar) = throughput(&object, || get(&tar));
+        assert_eq!((&from_zip, &from_tar), (&object, &object));
+        // Entries in the zip format are still read
+        assert_eq!(get(&zip), object);
+        let mb = |per_sec: f64| format!("{:.0} MB/s", per_sec / 1e6);
+        println!(
+            "{:>10} {:>12} {:>12} {:>12} {:>12} {:>12} {:>12}",
+            size,
+            mb(put_zip_rate),
+            mb(put_tar_rate),
+            mb(get_zip_rate),
+            mb(get_tar_rate),
+            zip.len(),
+            tar.len()
+        );
+    }
+}
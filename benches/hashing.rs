@@ -0,0 +1,79 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The throughput of hashing inputs of different sizes, as sccache does with
+//! `Digest`, against hashing them on one thread, and of hashing files.
+//!
+//! Run with `cargo bench --bench hashing`.
+
+use sccache::util::Digest;
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+/// How long to hash each size for.
+const DURATION: Duration = Duration::from_secs(2);
+
+const SIZES: &[usize] = &[
+    64 * 1024,
+    1024 * 1024,
+    4 * 1024 * 1024,
+    16 * 1024 * 1024,
+    64 * 1024 * 1024,
+];
+
+/// Bytes hashed per second by `hash`, and the digests, so that hashing
+/// can't be left out.
+fn throughput(bytes: &[u8], hash: impl Fn(&[u8]) -> String) -> (f64, Vec<String>) {
+    let start = Instant::now();
+    let mut digests = vec![];
+    while start.elapsed() < DURATION {
+        digests.push(hash(bytes));
+    }
+    let per_sec = (bytes.len() * digests.len()) as f64 / start.elapsed().as_secs_f64();
+    digests.dedup();
+    (per_sec, digests)
+}
+
+fn main() {
+    println!(
+        "{:>10} {:>14} {:>14} {:>14}",
+        "size", "one thread", "Digest", "Digest reader"
+    );
+    for &size in SIZES {
+        let bytes: Vec<u8> = (0..size).map(|i| (i * 7 + i / 4096) as u8).collect();
+        let (serial, serial_digests) = throughput(&bytes, |bytes| {
+            let mut hasher = blake3::Hasher::new();
+            hasher.update(bytes);
+            hasher.finalize().to_hex().to_string()
+        });
+        let (digest, digests) = throughput(&bytes, |bytes| {
+            let mut m = Digest::new();
+            m.update(bytes);
+            m.finish()
+        });
+        let (reader, reader_digests) = throughput(&bytes, |bytes| {
+            Digest::reader_sync(Cursor::new(bytes)).unwrap()
+        });
+        assert_eq!(digests, reader_digests);
+        assert_eq!((serial_digests.len(), digests.len()), (1, 1));
+        let mb = |per_sec: f64| format!("{:.0} MB/s", per_sec / 1e6);
+        println!(
+            "{:>10} {:>14} {:>14} {:>14}",
+            size,
+            mb(serial),
+            mb(digest),
+            mb(reader)
+        );
+    }
+}
@@ -14,20 +14,19 @@
 // limitations under the License.
 
 use crate::azure::credentials::*;
-use futures::{Future, Stream};
 use hmac::{Hmac, Mac, NewMac};
 use hyper::header::HeaderValue;
 use hyper::Method;
 use hyperx::header;
 use md5::{Digest, Md5};
-use reqwest::r#async::{Client, Request};
 use reqwest::Url;
+use reqwest::{Client, Request};
 use sha2::Sha256;
 use std::fmt;
 use std::str::FromStr;
 
 use crate::errors::*;
-use crate::util::HeadersExt;
+use crate::util::{http_client, HeadersExt};
 
 const BLOB_API_VERSION: &str = "2017-04-17";
 
@@ -68,11 +67,11 @@ impl BlobContainer {
         );
         Ok(BlobContainer {
             url: format!("{}{}/", base_url, container_name),
-            client: Client::new(),
+            client: http_client("AZURE")?,
         })
     }
 
-    pub fn get(&self, key: &str, creds: &AzureCredentials) -> SFuture<Vec<u8>> {
+    pub async fn get(&self, key: &str, creds: &AzureCredentials) -> Result<Vec<u8>> {
         let url_string = format!("{}{}", self.url, key);
         let uri = Url::from_str(&url_string).unwrap();
         let date = chrono::offset::Utc::now().to_rfc2822();
@@ -90,7 +89,6 @@ impl BlobContainer {
         );
 
         let uri_copy = uri.clone();
-        let uri_second_copy = uri.clone();
 
         let mut request = Request::new(Method::GET, uri);
         request.headers_mut().insert(
@@ -107,46 +105,34 @@ impl BlobContainer {
             );
         }
 
-        Box::new(
-            self.client
-                .execute(request)
-                .fwith_context(move || format!("failed GET: {}", uri_copy))
-                .and_then(|res| {
-                    if res.status().is_success() {
-                        let content_length = res
-                            .headers()
-                            .get_hyperx::<header::ContentLength>()
-                            .map(|header::ContentLength(len)| len);
-                        Ok((res.into_body(), content_length))
-                    } else {
-                        Err(BadHttpStatusError(res.status()).into())
-                    }
-                })
-                .and_then(|(body, content_length)| {
-                    body.fold(Vec::new(), |mut body, chunk| {
-                        body.extend_from_slice(&chunk);
-                        Ok::<_, reqwest::Error>(body)
-                    })
-                    .fcontext("failed to read HTTP body")
-                    .and_then(move |bytes| {
-                        if let Some(len) = content_length {
-                            if len != bytes.len() as u64 {
-                                bail!(format!(
-                                    "Bad HTTP body size read: {}, expected {}",
-                                    bytes.len(),
-                                    len
-                                ));
-                            } else {
-                                info!("Read {} bytes from {}", bytes.len(), uri_second_copy);
-                            }
-                        }
-                        Ok(bytes)
-                    })
-                }),
-        )
+        let res = self
+            .client
+            .execute(request)
+            .await
+            .with_context(|| format!("failed GET: {}", uri_copy))?;
+        if !res.status().is_success() {
+            return Err(BadHttpStatusError(res.status()).into());
+        }
+        let content_length = res
+            .headers()
+            .get_hyperx::<header::ContentLength>()
+            .map(|header::ContentLength(len)| len);
+        let bytes = res.bytes().await.context("failed to read HTTP body")?;
+        if let Some(len) = content_length {
+            if len != bytes.len() as u64 {
+                bail!(format!(
+                    "Bad HTTP body size read: {}, expected {}",
+                    bytes.len(),
+                    len
+                ));
+            } else {
+                info!("Read {} bytes from {}", bytes.len(), uri_copy);
+            }
+        }
+        Ok(bytes.to_vec())
     }
 
-    pub fn put(&self, key: &str, content: Vec<u8>, creds: &AzureCredentials) -> SFuture<()> {
+    pub async fn put(&self, key: &str, content: Vec<u8>, creds: &AzureCredentials) -> Result<()> {
         let url_string = format!("{}{}", self.url, key);
         let uri = Url::from_str(&url_string).unwrap();
         let date = chrono::offset::Utc::now().to_rfc2822();
@@ -204,7 +190,7 @@ impl BlobContainer {
 
         *request.body_mut() = Some(content.into());
 
-        Box::new(self.client.execute(request).then(|result| match result {
+        match self.client.execute(request).await {
             Ok(res) => {
                 if res.status().is_success() {
                     trace!("PUT succeeded");
@@ -218,7 +204,56 @@ impl BlobContainer {
                 trace!("PUT failed with error: {:?}", e);
                 Err(e.into())
             }
-        }))
+        }
+    }
+
+    pub async fn delete(&self, key: &str, creds: &AzureCredentials) -> Result<()> {
+        let url_string = format!("{}{}", self.url, key);
+        let uri = Url::from_str(&url_string).unwrap();
+        let date = chrono::offset::Utc::now().to_rfc2822();
+
+        let canonical_headers = format!("x-ms-date:{}\nx-ms-version:{}\n", date, BLOB_API_VERSION);
+
+        let auth = compute_auth_header(
+            "DELETE",
+            "", // content_length
+            "", // content_md5
+            "", // content_type
+            &canonical_headers,
+            &uri,
+            creds,
+        );
+
+        let mut request = Request::new(Method::DELETE, uri);
+        request.headers_mut().insert(
+            "x-ms-date",
+            HeaderValue::from_str(&date).expect("Invalid x-ms-date header"),
+        );
+        request
+            .headers_mut()
+            .insert("x-ms-version", HeaderValue::from_static(BLOB_API_VERSION));
+        if let Some(auth) = auth {
+            request.headers_mut().insert(
+                "Authorization",
+                HeaderValue::from_str(&auth).expect("Invalid Authorization header"),
+            );
+        }
+
+        match self.client.execute(request).await {
+            Ok(res) => {
+                if res.status().is_success() {
+                    trace!("DELETE succeeded");
+                    Ok(())
+                } else {
+                    trace!("DELETE failed with HTTP status: {}", res.status());
+                    Err(BadHttpStatusError(res.status()).into())
+                }
+            }
+            Err(e) => {
+                trace!("DELETE failed with error: {:?}", e);
+                Err(e.into())
+            }
+        }
     }
 }
 
@@ -283,7 +318,7 @@ fn canonicalize_resource(uri: &Url, account_name: &str) -> String {
 #[cfg(test)]
 mod test {
     use super::*;
-    use tokio_compat::runtime::current_thread::Runtime;
+    use crate::util;
 
     #[test]
     fn test_signing() {
@@ -336,7 +371,7 @@ mod test {
             container_name.to_string(),
         );
 
-        let mut runtime = Runtime::new().unwrap();
+        let runtime = util::runtime().unwrap();
 
         let container = BlobContainer::new(creds.azure_blob_endpoint(), container_name).unwrap();
 
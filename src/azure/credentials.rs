@@ -15,6 +15,8 @@
 
 use std::env::*;
 
+use crate::util::secret_var;
+
 use crate::errors::*;
 
 #[derive(Clone, Debug)]
@@ -77,7 +79,7 @@ impl AzureCredentialsProvider for EnvironmentProvider {
 }
 
 fn credentials_from_environment() -> Result<AzureCredentials> {
-    let env_conn_str = var("SCCACHE_AZURE_CONNECTION_STRING")
+    let env_conn_str = secret_var("SCCACHE_AZURE_CONNECTION_STRING")?
         .context("No SCCACHE_AZURE_CONNECTION_STRING in environment")?;
 
     let container_name = var("SCCACHE_AZURE_BLOB_CONTAINER")
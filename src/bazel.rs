@@ -0,0 +1,455 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Serving the storage as a Bazel HTTP cache: with `SCCACHE_BAZEL_CACHE_ADDR`
+//! set, the server answers `GET`, `HEAD` and `PUT` requests for the action
+//! cache (`/ac/<hash>`) and the content addressed store (`/cas/<hash>`) on
+//! that address, keeping each blob as an entry of its own in the storage.
+//!
+//! Anyone who can connect could fill the storage, so only a loopback address
+//! is served unless `SCCACHE_BAZEL_CACHE_TOKEN` is set, and then requests
+//! have to bear that token. Blobs of the content addressed store have to
+//! match their SHA-256 hash, and blobs and requests are kept small, as they
+//! are held in memory.
+
+use crate::cache::{Cache, CacheWrite, Storage};
+use crate::util::constant_time_eq;
+use futures::future::{self, Either};
+use hyper::body::HttpBody;
+use hyper::header::{HeaderValue, AUTHORIZATION, CONTENT_LENGTH};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use sha2::{Digest, Sha256};
+use std::cell::Cell;
+use std::convert::Infallible;
+use std::env;
+use std::future::Future;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tokio::time::Instant;
+
+use crate::errors::*;
+
+/// The longest request head read.
+const MAX_HEAD: usize = 16 * 1024;
+
+/// The largest blob stored. Larger ones are refused, which Bazel only warns
+/// about, building without them.
+const MAX_BLOB: u64 = 64 * 1024 * 1024;
+
+/// How many connections are served at once. Others wait to be accepted.
+const MAX_CONNECTIONS: usize = 8;
+
+/// How long reading the body of a request may take, so that connections
+/// that stall don't keep others from being served.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long a connection is kept open for another request once one has been
+/// answered, or for the first one. It's short, as connections that are kept
+/// open keep others from being accepted.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The name of the object holding the blob in its entry.
+const BLOB: &str = "blob";
+
+/// The kind and hash of the blob at `path`, which ends with `/ac/<hash>`
+/// or `/cas/<hash>` after the prefix of the cache's URL, if any.
+fn blob_path(path: &str) -> Option<(&str, &str)> {
+    let mut segments = path.rsplit('/');
+    let hash = segments.next()?;
+    let kind = segments.next()?;
+    let is_hash = !hash.is_empty()
+        && hash
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+    match kind {
+        "ac" | "cas" if is_hash => Some((kind, hash)),
+        _ => None,
+    }
+}
+
+/// Whether `blob` is the one of the content addressed store at `hash`.
+fn matches_hash(blob: &[u8], hash: &str) -> bool {
+    let digest = Sha256::digest(blob);
+    let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    hex == hash
+}
+
+/// Whether a request with the `Authorization` header `authorization` may be
+/// answered, when requests have to bear `token`.
+fn authorized(authorization: Option<&str>, token: Option<&str>) -> bool {
+    let token = match token {
+        Some(token) => token,
+        None => return true,
+    };
+    let bearer = authorization.and_then(|value| {
+        let (scheme, credentials) = value.split_at(value.find(' ')?);
+        if scheme.eq_ignore_ascii_case("bearer") {
+            Some(credentials.trim())
+        } else {
+            None
+        }
+    });
+    bearer.map_or(false, |bearer| {
+        constant_time_eq(bearer.as_bytes(), token.as_bytes())
+    })
+}
+
+/// A response with `body`, or only its length if `head_only`.
+fn response(status: StatusCode, body: Vec<u8>, head_only: bool) -> Response<Body> {
+    let len = HeaderValue::from(body.len());
+    let body = if head_only {
+        Body::empty()
+    } else {
+        Body::from(body)
+    };
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    response.headers_mut().insert(CONTENT_LENGTH, len);
+    response
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    response(status, vec![], false)
+}
+
+/// Read `body`, or return `None` if it's larger than `MAX_BLOB`.
+async fn read_body(mut body: Body) -> Result<Option<Vec<u8>>> {
+    if body.size_hint().lower() > MAX_BLOB {
+        return Ok(None);
+    }
+    let mut buf = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if (buf.len() + chunk.len()) as u64 > MAX_BLOB {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(Some(buf))
+}
+
+/// The response to the request for `path` with `method`, with the blob of
+/// its entry in `storage` or putting `body` there.
+async fn handle(
+    storage: Arc<dyn Storage>,
+    method: &Method,
+    path: &str,
+    body: Vec<u8>,
+) -> Result<Response<Body>> {
+    let (kind, hash) = match blob_path(path) {
+        Some(blob) => blob,
+        None => return Ok(status(StatusCode::NOT_FOUND)),
+    };
+    let key = format!("bazel-{}-{}", kind, hash);
+    match *method {
+        Method::GET | Method::HEAD => {
+            let head_only = method == Method::HEAD;
+            Ok(match storage.get(&key).await? {
+                Cache::Hit(mut entry) => {
+                    let mut blob = vec![];
+                    entry.get_object(BLOB, &mut blob)?;
+                    response(StatusCode::OK, blob, head_only)
+                }
+                Cache::Miss | Cache::Recache => response(StatusCode::NOT_FOUND, vec![], head_only),
+            })
+        }
+        Method::PUT => {
+            if kind == "cas" && !matches_hash(&body, hash) {
+                debug!("Refusing a Bazel blob that doesn't match its hash {}", hash);
+                return Ok(status(StatusCode::BAD_REQUEST));
+            }
+            let mut entry = CacheWrite::new();
+            entry.put_object(BLOB, &mut Cursor::new(body), None)?;
+            storage.put(&key, entry).await?;
+            Ok(status(StatusCode::OK))
+        }
+        _ => Ok(status(StatusCode::METHOD_NOT_ALLOWED)),
+    }
+}
+
+/// The response to `request` from `storage`, if it bears `token`.
+async fn respond(
+    storage: Arc<dyn Storage>,
+    token: Option<&str>,
+    request: Request<Body>,
+) -> Response<Body> {
+    let authorization = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    if !authorized(authorization, token) {
+        return status(StatusCode::UNAUTHORIZED);
+    }
+    let (parts, body) = request.into_parts();
+    let body = match tokio::time::timeout(REQUEST_TIMEOUT, read_body(body)).await {
+        Ok(Ok(Some(body))) => body,
+        Ok(Ok(None)) => return status(StatusCode::PAYLOAD_TOO_LARGE),
+        Ok(Err(e)) => {
+            debug!("Failed to read a Bazel cache request: {:#}", e);
+            return status(StatusCode::BAD_REQUEST);
+        }
+        Err(_) => return status(StatusCode::REQUEST_TIMEOUT),
+    };
+    handle(storage, &parts.method, parts.uri.path(), body)
+        .await
+        .unwrap_or_else(|e| {
+            warn!("Failed to answer a Bazel cache request: {:#}", e);
+            status(StatusCode::INTERNAL_SERVER_ERROR)
+        })
+}
+
+/// Runs the tasks hyper spawns on the server's thread, as the storage isn't
+/// `Send`.
+#[derive(Clone, Copy)]
+struct LocalExecutor;
+
+impl<F> hyper::rt::Executor<F> for LocalExecutor
+where
+    F: Future + 'static,
+{
+    fn execute(&self, future: F) {
+        tokio::task::spawn_local(future);
+    }
+}
+
+/// Listening for Bazel cache requests.
+pub struct BazelCache {
+    listener: std::net::TcpListener,
+    /// The token requests have to bear, if they do.
+    token: Option<Arc<String>>,
+}
+
+impl BazelCache {
+    /// Listen on `SCCACHE_BAZEL_CACHE_ADDR`, if it's set and it's a loopback
+    /// address or `SCCACHE_BAZEL_CACHE_TOKEN` is set.
+    pub fn from_env(addr: Option<SocketAddr>) -> Result<Option<BazelCache>> {
+        let addr = match addr {
+            Some(addr) => addr,
+            None => return Ok(None),
+        };
+        let token = env::var("SCCACHE_BAZEL_CACHE_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty());
+        if token.is_none() && !addr.ip().is_loopback() {
+            bail!(
+                "refusing to serve the Bazel cache on {} without SCCACHE_BAZEL_CACHE_TOKEN",
+                addr
+            );
+        }
+        let listener = std::net::TcpListener::bind(&addr)
+            .with_context(|| format!("failed to listen for Bazel cache requests on {}", addr))?;
+        Ok(Some(BazelCache {
+            listener,
+            token: token.map(Arc::new),
+        }))
+    }
+
+    /// Answer the requests of up to `MAX_CONNECTIONS` connections at a time
+    /// from the storage `storage` returns as each is accepted.
+    pub async fn serve<F>(self, storage: F)
+    where
+        F: Fn() -> Arc<dyn Storage>,
+    {
+        let BazelCache { listener, token } = self;
+        let listener = match listener
+            .set_nonblocking(true)
+            .and_then(|()| TcpListener::from_std(listener))
+        {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("failed to listen for Bazel cache requests: {}", e);
+                return;
+            }
+        };
+        let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+        loop {
+            let permit = connections
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("the semaphore is never closed");
+            let socket = match listener.accept().await {
+                Ok((socket, _)) => socket,
+                Err(e) => {
+                    error!("failed to accept a Bazel cache connection: {}", e);
+                    return;
+                }
+            };
+            let (storage, token) = (storage(), token.clone());
+            tokio::task::spawn_local(async move {
+                if let Err(e) = serve(socket, storage, token).await {
+                    debug!("failed to serve a Bazel cache connection: {:#}", e);
+                }
+                drop(permit);
+            });
+        }
+    }
+}
+
+/// Answer the requests on `socket` from `storage`, if they bear `token`,
+/// until it's closed or idle for `IDLE_TIMEOUT`.
+async fn serve(
+    socket: TcpStream,
+    storage: Arc<dyn Storage>,
+    token: Option<Arc<String>>,
+) -> Result<()> {
+    // When the connection last had no request in flight, if it doesn't now.
+    let idle_since = Rc::new(Cell::new(Some(Instant::now())));
+    let service = {
+        let idle_since = idle_since.clone();
+        service_fn(move |request| {
+            let (storage, token, idle_since) = (storage.clone(), token.clone(), idle_since.clone());
+            idle_since.set(None);
+            async move {
+                let token = token.as_ref().map(|token| token.as_str());
+                let response = respond(storage, token, request).await;
+                idle_since.set(Some(Instant::now()));
+                Ok::<_, Infallible>(response)
+            }
+        })
+    };
+    let mut connection = Http::new()
+        .with_executor(LocalExecutor)
+        .http1_only(true)
+        .http1_keep_alive(true)
+        .max_buf_size(MAX_HEAD)
+        .serve_connection(socket, service);
+    loop {
+        let deadline = idle_since.get().unwrap_or_else(Instant::now) + IDLE_TIMEOUT;
+        let idle = Box::pin(tokio::time::sleep_until(deadline));
+        match future::select(&mut connection, idle).await {
+            Either::Left((result, _)) => return Ok(result?),
+            Either::Right(_) => {
+                if idle_since
+                    .get()
+                    .map_or(false, |since| since + IDLE_TIMEOUT <= Instant::now())
+                {
+                    // This closes the connection once it's done with the
+                    // response being written, if any.
+                    Pin::new(&mut connection).graceful_shutdown();
+                    return Ok(connection.await?);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::disk::DiskCache;
+    use crate::test::utils::ThreadPoolExt;
+    use futures::executor::{block_on, ThreadPool};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::task::LocalSet;
+
+    #[test]
+    fn test_blob_path() {
+        assert_eq!(blob_path("/ac/0123abcd"), Some(("ac", "0123abcd")));
+        assert_eq!(blob_path("/prefix/cas/ff00"), Some(("cas", "ff00")));
+        assert_eq!(blob_path("/cas/FF00"), None);
+        assert_eq!(blob_path("/cas/"), None);
+        assert_eq!(blob_path("/other/ff00"), None);
+        assert_eq!(blob_path("/metrics"), None);
+    }
+
+    #[test]
+    fn test_authorized() {
+        assert!(authorized(None, None));
+        assert!(authorized(Some("Bearer secret"), Some("secret")));
+        assert!(authorized(Some("bearer  secret"), Some("secret")));
+        assert!(!authorized(None, Some("secret")));
+        assert!(!authorized(Some("Bearer secre"), Some("secret")));
+        assert!(!authorized(Some("Basic secret"), Some("secret")));
+        assert!(!authorized(Some("secret"), Some("secret")));
+    }
+
+    #[test]
+    fn test_handle() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = ThreadPool::sized(1);
+        let storage: Arc<dyn Storage> = Arc::new(DiskCache::new(&dir.path(), 1 << 20, &pool));
+        let request = |method: Method, path: &str, body: &[u8]| {
+            block_on(async {
+                let response = handle(storage.clone(), &method, path, body.to_vec())
+                    .await
+                    .unwrap();
+                let len = response.headers()[CONTENT_LENGTH]
+                    .to_str()
+                    .unwrap()
+                    .to_owned();
+                let status = response.status().as_u16();
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                (status, len, body.to_vec())
+            })
+        };
+
+        // The SHA-256 hash of "blob"
+        let cas = "/cas/fa2c8cc4f28176bbeed4b736df569a34c79cd3723e9ec42f9674b4d46ac6b8b8";
+        assert_eq!(request(Method::GET, cas, b"").0, 404);
+        assert_eq!(request(Method::PUT, cas, b"blob").0, 200);
+        assert_eq!(
+            request(Method::GET, cas, b""),
+            (200, "4".to_owned(), b"blob".to_vec())
+        );
+        assert_eq!(
+            request(Method::HEAD, cas, b""),
+            (200, "4".to_owned(), vec![])
+        );
+        // Blobs of the content addressed store have to match their hash
+        assert_eq!(request(Method::PUT, "/cas/abcd", b"blob").0, 400);
+        assert_eq!(request(Method::GET, "/cas/abcd", b"").0, 404);
+        // Unlike those of the action cache, which is kept apart
+        assert_eq!(request(Method::GET, "/ac/abcd", b"").0, 404);
+        assert_eq!(request(Method::PUT, "/ac/abcd", b"result").0, 200);
+        assert_eq!(request(Method::DELETE, cas, b"").0, 405);
+    }
+
+    #[test]
+    fn test_serve_keep_alive() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = ThreadPool::sized(1);
+        let storage: Arc<dyn Storage> = Arc::new(DiskCache::new(&dir.path(), 1 << 20, &pool));
+        let cache = BazelCache::from_env(Some(([127, 0, 0, 1], 0).into()))
+            .unwrap()
+            .unwrap();
+        let addr = cache.listener.local_addr().unwrap();
+        let runtime = crate::util::runtime().unwrap();
+        let local = LocalSet::new();
+        local.spawn_local(cache.serve(move || storage.clone()));
+        let responses = local.block_on(&runtime, async move {
+            let mut socket = TcpStream::connect(addr).await.unwrap();
+            // Both requests are answered on the one connection, which is
+            // closed as the last asks.
+            let requests = "PUT /ac/abcd HTTP/1.1\r\nHost: localhost\r\n\
+                            Content-Length: 6\r\n\r\nresult\
+                            GET /ac/abcd HTTP/1.1\r\nHost: localhost\r\n\
+                            Connection: close\r\n\r\n";
+            socket.write_all(requests.as_bytes()).await.unwrap();
+            let mut responses = vec![];
+            socket.read_to_end(&mut responses).await.unwrap();
+            String::from_utf8(responses).unwrap()
+        });
+        assert_eq!(responses.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+        assert!(responses.ends_with("\r\n\r\nresult"));
+    }
+}
@@ -12,30 +12,46 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(target_os = "linux")]
+use crate::cgroup::{self, JobCgroup, JobCgroups};
 use anyhow::{anyhow, bail, Context, Error, Result};
 use flate2::read::GzDecoder;
+#[cfg(target_os = "linux")]
 use libmount::Overlay;
+#[cfg(target_os = "linux")]
+use sccache::config::server::JobLimits;
 use sccache::dist::{
-    BuildResult, BuilderIncoming, CompileCommand, InputsReader, OutputData, ProcessOutput, TcCache,
-    Toolchain,
+    BuildResult, BuilderIncoming, CompileCommand, Compression, InputsReader, OutputData,
+    ProcessOutput, TcCache, Toolchain,
 };
 use sccache::lru_disk_cache::Error as LruError;
-use std::collections::{hash_map, HashMap};
+#[cfg(target_os = "linux")]
+use std::collections::hash_map;
+use std::collections::HashMap;
+use std::fmt;
 use std::fs;
 use std::io;
+#[cfg(target_os = "linux")]
 use std::iter;
+use std::os::unix::process::CommandExt as _;
 use std::path::{self, Path, PathBuf};
-use std::process::{ChildStdin, Command, Output, Stdio};
-use std::sync::Mutex;
-use std::time::Instant;
+#[cfg(target_os = "linux")]
+use std::process::ChildStdin;
+use std::process::{Command, Output, Stdio};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+#[cfg(target_os = "linux")]
 use version_compare::Version;
 
+#[cfg(target_os = "linux")]
 trait CommandExt {
     fn check_stdout_trim(&mut self) -> Result<String>;
     fn check_piped(&mut self, pipe: &mut dyn FnMut(&mut ChildStdin) -> Result<()>) -> Result<()>;
     fn check_run(&mut self) -> Result<()>;
 }
 
+#[cfg(target_os = "linux")]
 impl CommandExt for Command {
     fn check_stdout_trim(&mut self) -> Result<String> {
         let output = self.output().context("Failed to start command")?;
@@ -66,6 +82,7 @@ impl CommandExt for Command {
     }
 }
 
+#[cfg(target_os = "linux")]
 fn check_output(output: &Output) -> Result<()> {
     if !output.status.success() {
         warn!(
@@ -87,6 +104,67 @@ fn join_suffix<P: AsRef<Path>>(path: &Path, suffix: P) -> PathBuf {
     path.join(components)
 }
 
+/// The error for a compile killed for using more memory than the job limit.
+#[derive(Debug)]
+pub struct OutOfMemory;
+
+impl fmt::Display for OutOfMemory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "compile ran out of memory")
+    }
+}
+
+impl std::error::Error for OutOfMemory {}
+
+/// The error for a compile killed for running for longer than the job limit.
+#[derive(Debug)]
+pub struct TimedOut(pub Duration);
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "compile timed out after {}s", self.0.as_secs())
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+// Like `Command::output`, but kill the command, along with anything it has
+// started, if it doesn't finish within `timeout`.
+pub fn output_with_timeout(cmd: &mut Command, timeout: Option<Duration>) -> Result<Output> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return Ok(cmd.output()?),
+    };
+    // Compilers run other processes, e.g. cc1 and as, so give it a process
+    // group of its own to kill
+    unsafe {
+        cmd.pre_exec(|| {
+            if libc::setpgid(0, 0) == 0 {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        });
+    }
+    let child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let pgid = nix::unistd::Pid::from_raw(child.id() as i32);
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || tx.send(child.wait_with_output()));
+    match rx.recv_timeout(timeout) {
+        Ok(output) => Ok(output?),
+        Err(_) => {
+            if let Err(e) = nix::sys::signal::killpg(pgid, nix::sys::signal::Signal::SIGKILL) {
+                warn!("Failed to kill timed out compile: {}", e)
+            }
+            Err(TimedOut(timeout).into())
+        }
+    }
+}
+
 #[derive(Debug)]
 struct OverlaySpec {
     build_dir: PathBuf,
@@ -100,14 +178,113 @@ struct DeflatedToolchain {
     ctime: Instant,
 }
 
+// Remove anything left in `dir` by a previous run of the builder and create
+// the directories for unpacked toolchains and builds.
+fn create_builder_dirs(dir: &Path) -> Result<()> {
+    if dir.exists() {
+        fs::remove_dir_all(dir).context("Failed to clean up builder directory")?
+    }
+    fs::create_dir(dir).context("Failed to create base directory for builder")?;
+    fs::create_dir(dir.join("builds")).context("Failed to create builder builds directory")?;
+    fs::create_dir(dir.join("toolchains"))
+        .context("Failed to create builder toolchains directory")?;
+    Ok(())
+}
+
+fn prepare_build_dirs(
+    dir: &Path,
+    toolchain_dir_map: &Mutex<HashMap<Toolchain, DeflatedToolchain>>,
+    tc: &Toolchain,
+    tccache: &Mutex<TcCache>,
+) -> Result<OverlaySpec> {
+    let DeflatedToolchain {
+        path: toolchain_dir,
+        build_count: id,
+        ctime: _,
+    } = {
+        let mut toolchain_dir_map = toolchain_dir_map.lock().unwrap();
+        // Create the toolchain dir (if necessary) while we have an exclusive lock
+        let toolchain_dir = dir.join("toolchains").join(&tc.archive_id);
+        if toolchain_dir_map.contains_key(tc) && toolchain_dir.exists() {
+            // TODO: use if let when sccache can use NLL
+            let entry = toolchain_dir_map
+                .get_mut(tc)
+                .expect("Key missing after checking");
+            entry.build_count += 1;
+            entry.clone()
+        } else {
+            trace!("Creating toolchain directory for {}", tc.archive_id);
+            fs::create_dir(&toolchain_dir)?;
+
+            let mut tccache = tccache.lock().unwrap();
+            let toolchain_rdr = match tccache.get(tc) {
+                Ok(rdr) => rdr,
+                Err(LruError::FileNotInCache) => {
+                    bail!("expected toolchain {}, but not available", tc.archive_id)
+                }
+                Err(e) => return Err(Error::from(e).context("failed to get toolchain from cache")),
+            };
+
+            tar::Archive::new(GzDecoder::new(toolchain_rdr))
+                .unpack(&toolchain_dir)
+                .or_else(|e| {
+                    warn!("Failed to unpack toolchain: {:?}", e);
+                    fs::remove_dir_all(&toolchain_dir)
+                        .context("Failed to remove unpacked toolchain")?;
+                    tccache
+                        .remove(tc)
+                        .context("Failed to remove corrupt toolchain")?;
+                    Err(Error::from(e))
+                })?;
+
+            let entry = DeflatedToolchain {
+                path: toolchain_dir,
+                build_count: 1,
+                ctime: Instant::now(),
+            };
+
+            toolchain_dir_map.insert(tc.clone(), entry.clone());
+            if toolchain_dir_map.len() > tccache.len() {
+                let dir_map = toolchain_dir_map.clone();
+                let mut entries: Vec<_> = dir_map.iter().collect();
+                // In the pathological case, creation time for unpacked
+                // toolchains could be the opposite of the least recently
+                // recently used, so we clear out half of the accumulated
+                // toolchains to prevent repeated sort/delete cycles.
+                entries.sort_by(|a, b| (a.1).ctime.cmp(&(b.1).ctime));
+                entries.truncate(entries.len() / 2);
+                for (tc, _) in entries {
+                    warn!("Removing old un-compressed toolchain: {:?}", tc);
+                    assert!(toolchain_dir_map.remove(tc).is_some());
+                    fs::remove_dir_all(&dir.join("toolchains").join(&tc.archive_id))
+                        .context("Failed to remove old toolchain directory")?;
+                }
+            }
+            entry
+        }
+    };
+
+    trace!("Creating build directory for {}-{}", tc.archive_id, id);
+    let build_dir = dir.join("builds").join(format!("{}-{}", tc.archive_id, id));
+    fs::create_dir(&build_dir)?;
+    Ok(OverlaySpec {
+        build_dir,
+        toolchain_dir,
+    })
+}
+
+#[cfg(target_os = "linux")]
 pub struct OverlayBuilder {
     bubblewrap: PathBuf,
     dir: PathBuf,
     toolchain_dir_map: Mutex<HashMap<Toolchain, DeflatedToolchain>>,
+    cgroups: Option<JobCgroups>,
+    timeout: Option<Duration>,
 }
 
+#[cfg(target_os = "linux")]
 impl OverlayBuilder {
-    pub fn new(bubblewrap: PathBuf, dir: PathBuf) -> Result<Self> {
+    pub fn new(bubblewrap: PathBuf, dir: PathBuf, job_limits: JobLimits) -> Result<Self> {
         info!("Creating overlay builder");
 
         if !nix::unistd::getuid().is_root() || !nix::unistd::geteuid().is_root() {
@@ -143,26 +320,22 @@ impl OverlayBuilder {
             );
         }
 
+        let timeout = job_limits.timeout.map(Duration::from_secs);
+        let cgroups = if job_limits.memory.is_some() || job_limits.cpus.is_some() {
+            Some(JobCgroups::new(job_limits).context("Failed to set up cgroups for job limits")?)
+        } else {
+            None
+        };
+
         // TODO: pidfile
-        let ret = Self {
+        create_builder_dirs(&dir)?;
+        Ok(Self {
             bubblewrap,
             dir,
             toolchain_dir_map: Mutex::new(HashMap::new()),
-        };
-        ret.cleanup()?;
-        fs::create_dir(&ret.dir).context("Failed to create base directory for builder")?;
-        fs::create_dir(ret.dir.join("builds"))
-            .context("Failed to create builder builds directory")?;
-        fs::create_dir(ret.dir.join("toolchains"))
-            .context("Failed to create builder toolchains directory")?;
-        Ok(ret)
-    }
-
-    fn cleanup(&self) -> Result<()> {
-        if self.dir.exists() {
-            fs::remove_dir_all(&self.dir).context("Failed to clean up builder directory")?
-        }
-        Ok(())
+            cgroups,
+            timeout,
+        })
     }
 
     fn prepare_overlay_dirs(
@@ -170,93 +343,17 @@ impl OverlayBuilder {
         tc: &Toolchain,
         tccache: &Mutex<TcCache>,
     ) -> Result<OverlaySpec> {
-        let DeflatedToolchain {
-            path: toolchain_dir,
-            build_count: id,
-            ctime: _,
-        } = {
-            let mut toolchain_dir_map = self.toolchain_dir_map.lock().unwrap();
-            // Create the toolchain dir (if necessary) while we have an exclusive lock
-            let toolchain_dir = self.dir.join("toolchains").join(&tc.archive_id);
-            if toolchain_dir_map.contains_key(tc) && toolchain_dir.exists() {
-                // TODO: use if let when sccache can use NLL
-                let entry = toolchain_dir_map
-                    .get_mut(tc)
-                    .expect("Key missing after checking");
-                entry.build_count += 1;
-                entry.clone()
-            } else {
-                trace!("Creating toolchain directory for {}", tc.archive_id);
-                fs::create_dir(&toolchain_dir)?;
-
-                let mut tccache = tccache.lock().unwrap();
-                let toolchain_rdr = match tccache.get(tc) {
-                    Ok(rdr) => rdr,
-                    Err(LruError::FileNotInCache) => {
-                        bail!("expected toolchain {}, but not available", tc.archive_id)
-                    }
-                    Err(e) => {
-                        return Err(Error::from(e).context("failed to get toolchain from cache"))
-                    }
-                };
-
-                tar::Archive::new(GzDecoder::new(toolchain_rdr))
-                    .unpack(&toolchain_dir)
-                    .or_else(|e| {
-                        warn!("Failed to unpack toolchain: {:?}", e);
-                        fs::remove_dir_all(&toolchain_dir)
-                            .context("Failed to remove unpacked toolchain")?;
-                        tccache
-                            .remove(tc)
-                            .context("Failed to remove corrupt toolchain")?;
-                        Err(Error::from(e))
-                    })?;
-
-                let entry = DeflatedToolchain {
-                    path: toolchain_dir,
-                    build_count: 1,
-                    ctime: Instant::now(),
-                };
-
-                toolchain_dir_map.insert(tc.clone(), entry.clone());
-                if toolchain_dir_map.len() > tccache.len() {
-                    let dir_map = toolchain_dir_map.clone();
-                    let mut entries: Vec<_> = dir_map.iter().collect();
-                    // In the pathological case, creation time for unpacked
-                    // toolchains could be the opposite of the least recently
-                    // recently used, so we clear out half of the accumulated
-                    // toolchains to prevent repeated sort/delete cycles.
-                    entries.sort_by(|a, b| (a.1).ctime.cmp(&(b.1).ctime));
-                    entries.truncate(entries.len() / 2);
-                    for (tc, _) in entries {
-                        warn!("Removing old un-compressed toolchain: {:?}", tc);
-                        assert!(toolchain_dir_map.remove(tc).is_some());
-                        fs::remove_dir_all(&self.dir.join("toolchains").join(&tc.archive_id))
-                            .context("Failed to remove old toolchain directory")?;
-                    }
-                }
-                entry
-            }
-        };
-
-        trace!("Creating build directory for {}-{}", tc.archive_id, id);
-        let build_dir = self
-            .dir
-            .join("builds")
-            .join(format!("{}-{}", tc.archive_id, id));
-        fs::create_dir(&build_dir)?;
-        Ok(OverlaySpec {
-            build_dir,
-            toolchain_dir,
-        })
+        prepare_build_dirs(&self.dir, &self.toolchain_dir_map, tc, tccache)
     }
 
     fn perform_build(
-        bubblewrap: &Path,
+        &self,
         compile_command: CompileCommand,
         inputs_rdr: InputsReader,
         output_paths: Vec<String>,
+        outputs_compression: Compression,
         overlay: &OverlaySpec,
+        cgroup: Option<&JobCgroup>,
     ) -> Result<BuildResult> {
         trace!("Compile environment: {:?}", compile_command.env_vars);
         trace!(
@@ -349,7 +446,10 @@ impl OverlayBuilder {
                     //   the user or cgroups namespace, so we list everything explicitly
                     // - The order of bind vs proc + dev is important - the new root must be put in place
                     //   first, otherwise proc and dev get hidden
-                    let mut cmd = Command::new(bubblewrap);
+                    let mut cmd = match cgroup {
+                        Some(cgroup) => cgroup.command(&self.bubblewrap),
+                        None => Command::new(&self.bubblewrap),
+                    };
                     cmd.arg("--die-with-parent")
                         .args(&["--cap-drop", "ALL"])
                         .args(&[
@@ -378,10 +478,12 @@ impl OverlayBuilder {
                     cmd.arg("--");
                     cmd.arg(executable);
                     cmd.args(arguments);
-                    let compile_output = cmd
-                        .output()
+                    let compile_output = output_with_timeout(&mut cmd, self.timeout)
                         .context("Failed to retrieve output from compile")?;
                     trace!("compile_output: {:?}", compile_output);
+                    if cgroup.map_or(false, JobCgroup::oom_killed) {
+                        return Err(OutOfMemory.into());
+                    }
 
                     let mut outputs = vec![];
                     trace!("retrieving {:?}", output_paths);
@@ -389,7 +491,7 @@ impl OverlayBuilder {
                         let abspath = join_suffix(&target_dir, cwd.join(&path)); // Resolve in case it's relative since we copy it from the root level
                         match fs::File::open(abspath) {
                             Ok(file) => {
-                                let output = OutputData::try_from_reader(file)
+                                let output = OutputData::try_from_reader(file, outputs_compression)
                                     .context("Failed to read output file")?;
                                 outputs.push((path, output))
                             }
@@ -438,6 +540,7 @@ impl OverlayBuilder {
     }
 }
 
+#[cfg(target_os = "linux")]
 impl BuilderIncoming for OverlayBuilder {
     fn run_build(
         &self,
@@ -445,14 +548,26 @@ impl BuilderIncoming for OverlayBuilder {
         command: CompileCommand,
         outputs: Vec<String>,
         inputs_rdr: InputsReader,
+        outputs_compression: Compression,
         tccache: &Mutex<TcCache>,
     ) -> Result<BuildResult> {
+        let cgroup = match &self.cgroups {
+            Some(cgroups) => Some(cgroups.create()?),
+            None => None,
+        };
         debug!("Preparing overlay");
         let overlay = self
             .prepare_overlay_dirs(&tc, tccache)
             .context("failed to prepare overlay dirs")?;
         debug!("Performing build in {:?}", overlay);
-        let res = Self::perform_build(&self.bubblewrap, command, inputs_rdr, outputs, &overlay);
+        let res = self.perform_build(
+            command,
+            inputs_rdr,
+            outputs,
+            outputs_compression,
+            &overlay,
+            cgroup.as_ref(),
+        );
         debug!("Finishing with overlay");
         self.finish_overlay(&tc, overlay);
         debug!("Returning result");
@@ -460,41 +575,126 @@ impl BuilderIncoming for OverlayBuilder {
     }
 }
 
-const BASE_DOCKER_IMAGE: &str = "aidanhs/busybox";
+// Fully qualified, as Podman and containerd don't assume Docker Hub
+#[cfg(target_os = "linux")]
+const BASE_DOCKER_IMAGE: &str = "docker.io/aidanhs/busybox";
 // Make sure sh doesn't exec the final command, since we need it to do
 // init duties (reaping zombies). Also, because we kill -9 -1, that kills
 // the sleep (it's not a builtin) so it needs to be a loop.
+#[cfg(target_os = "linux")]
 const DOCKER_SHELL_INIT: &str = "while true; do /busybox sleep 365d && /busybox true; done";
 
-// Check the diff and clean up the FS
-fn docker_diff(cid: &str) -> Result<String> {
-    Command::new("docker")
-        .args(&["diff", cid])
-        .check_stdout_trim()
-        .context("Failed to Docker diff container")
+// Whether an image or container image name is one of ours. Podman and
+// containerd add a registry to the names of committed images, e.g.
+// localhost/sccache-builder-...
+#[cfg(target_os = "linux")]
+fn is_builder_image(image_name: &str) -> bool {
+    let name = image_name.rsplit('/').next().unwrap_or(image_name);
+    name.starts_with("sccache-builder-")
 }
 
-// Force remove the container
-fn docker_rm(cid: &str) -> Result<()> {
-    Command::new("docker")
-        .args(&["rm", "-f", &cid])
-        .check_run()
-        .context("Failed to force delete container")
+/// The container engine a `ContainerBuilder` drives, through its Docker
+/// compatible command line.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+pub enum ContainerCli {
+    Docker,
+    /// Podman, which can run rootless.
+    Podman {
+        podman: PathBuf,
+    },
+    /// containerd, through nerdctl.
+    Containerd {
+        nerdctl: PathBuf,
+        namespace: Option<String>,
+    },
 }
 
-pub struct DockerBuilder {
+#[cfg(target_os = "linux")]
+impl ContainerCli {
+    fn name(&self) -> &'static str {
+        match self {
+            ContainerCli::Docker => "Docker",
+            ContainerCli::Podman { .. } => "Podman",
+            ContainerCli::Containerd { .. } => "containerd",
+        }
+    }
+
+    fn command(&self) -> Command {
+        match self {
+            ContainerCli::Docker => Command::new("docker"),
+            ContainerCli::Podman { podman } => Command::new(podman),
+            ContainerCli::Containerd { nerdctl, namespace } => {
+                let mut cmd = Command::new(nerdctl);
+                if let Some(namespace) = namespace {
+                    cmd.args(&["--namespace", namespace]);
+                }
+                cmd
+            }
+        }
+    }
+
+    // nerdctl has no `diff`, so its containers can't be checked to be clean
+    // for reuse
+    fn can_diff(&self) -> bool {
+        !matches!(self, ContainerCli::Containerd { .. })
+    }
+
+    // Check the diff and clean up the FS
+    fn diff(&self, cid: &str) -> Result<String> {
+        self.command()
+            .args(&["diff", cid])
+            .check_stdout_trim()
+            .with_context(|| format!("Failed to {} diff container", self.name()))
+    }
+
+    // Force remove the container
+    fn rm(&self, cid: &str) -> Result<()> {
+        self.command()
+            .args(&["rm", "-f", &cid])
+            .check_run()
+            .context("Failed to force delete container")
+    }
+
+    // Extract the uncompressed tar from `tar_rdr` at the root of the running
+    // container
+    fn copy_in(&self, cid: &str, tar_rdr: &mut dyn io::Read) -> Result<()> {
+        let mut cmd = self.command();
+        match self {
+            // nerdctl can't copy from stdin
+            ContainerCli::Containerd { .. } => {
+                cmd.args(&["exec", "-i", cid, "/busybox", "tar", "-xf", "-", "-C", "/"])
+            }
+            ContainerCli::Docker | ContainerCli::Podman { .. } => {
+                cmd.args(&["cp", "-", &format!("{}:/", cid)])
+            }
+        };
+        cmd.check_piped(&mut |stdin| {
+            io::copy(tar_rdr, stdin)?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub struct ContainerBuilder {
+    cli: ContainerCli,
+    job_limits: JobLimits,
     image_map: Mutex<HashMap<Toolchain, String>>,
     container_lists: Mutex<HashMap<Toolchain, Vec<String>>>,
 }
 
-impl DockerBuilder {
+#[cfg(target_os = "linux")]
+impl ContainerBuilder {
     // TODO: this should accept a unique string, e.g. inode of the tccache directory
     // having locked a pidfile, or at minimum should loudly detect other running
     // instances - pidfile in /tmp
-    pub fn new() -> Result<Self> {
-        info!("Creating docker builder");
+    pub fn new(cli: ContainerCli, job_limits: JobLimits) -> Result<Self> {
+        info!("Creating {} builder", cli.name());
 
         let ret = Self {
+            cli,
+            job_limits,
             image_map: Mutex::new(HashMap::new()),
             container_lists: Mutex::new(HashMap::new()),
         };
@@ -505,12 +705,14 @@ impl DockerBuilder {
     // TODO: this should really reclaim, and should check in the image map and container lists, so
     // that when things are removed from there it becomes a form of GC
     fn cleanup(&self) -> Result<()> {
-        info!("Performing initial Docker cleanup");
+        info!("Performing initial {} cleanup", self.cli.name());
 
-        let containers = Command::new("docker")
+        let containers = self
+            .cli
+            .command()
             .args(&["ps", "-a", "--format", "{{.ID}} {{.Image}}"])
             .check_stdout_trim()
-            .context("Unable to list all Docker containers")?;
+            .context("Unable to list all containers")?;
         if containers != "" {
             let mut containers_to_rm = vec![];
             for line in containers.split(|c| c == '\n') {
@@ -524,12 +726,13 @@ impl DockerBuilder {
                 if iter.next() != None {
                     bail!("Malformed container listing - third field on row")
                 }
-                if image_name.starts_with("sccache-builder-") {
+                if is_builder_image(image_name) {
                     containers_to_rm.push(container_id)
                 }
             }
             if !containers_to_rm.is_empty() {
-                Command::new("docker")
+                self.cli
+                    .command()
                     .args(&["rm", "-f"])
                     .args(containers_to_rm)
                     .check_run()
@@ -537,10 +740,12 @@ impl DockerBuilder {
             }
         }
 
-        let images = Command::new("docker")
+        let images = self
+            .cli
+            .command()
             .args(&["images", "--format", "{{.ID}} {{.Repository}}"])
             .check_stdout_trim()
-            .context("Failed to list all docker images")?;
+            .context("Failed to list all images")?;
         if images != "" {
             let mut images_to_rm = vec![];
             for line in images.split(|c| c == '\n') {
@@ -554,12 +759,13 @@ impl DockerBuilder {
                 if iter.next() != None {
                     bail!("Malformed image listing - third field on row")
                 }
-                if image_name.starts_with("sccache-builder-") {
+                if is_builder_image(image_name) {
                     images_to_rm.push(image_id)
                 }
             }
             if !images_to_rm.is_empty() {
-                Command::new("docker")
+                self.cli
+                    .command()
                     .args(&["rmi"])
                     .args(images_to_rm)
                     .check_run()
@@ -567,12 +773,12 @@ impl DockerBuilder {
             }
         }
 
-        info!("Completed initial Docker cleanup");
+        info!("Completed initial {} cleanup", self.cli.name());
         Ok(())
     }
 
     // If we have a spare running container, claim it and remove it from the available list,
-    // otherwise try and create a new container (possibly creating the Docker image along
+    // otherwise try and create a new container (possibly creating the image along
     // the way)
     fn get_container(&self, tc: &Toolchain, tccache: &Mutex<TcCache>) -> Result<String> {
         let container = {
@@ -589,26 +795,27 @@ impl DockerBuilder {
                     match map.entry(tc.clone()) {
                         hash_map::Entry::Occupied(e) => e.get().clone(),
                         hash_map::Entry::Vacant(e) => {
-                            info!("Creating Docker image for {:?} (may block requests)", tc);
-                            let image = Self::make_image(tc, tccache)?;
+                            info!("Creating image for {:?} (may block requests)", tc);
+                            let image = self.make_image(tc, tccache)?;
                             e.insert(image.clone());
                             image
                         }
                     }
                 };
-                Self::start_container(&image)
+                self.start_container(&image)
             }
         }
     }
 
     fn clean_container(&self, cid: &str) -> Result<()> {
         // Clean up any running processes
-        Command::new("docker")
+        self.cli
+            .command()
             .args(&["exec", &cid, "/busybox", "kill", "-9", "-1"])
             .check_run()
             .context("Failed to run kill on all processes in container")?;
 
-        let diff = docker_diff(&cid)?;
+        let diff = self.cli.diff(&cid)?;
         if diff != "" {
             let mut lastpath = None;
             for line in diff.split(|c| c == '\n') {
@@ -634,15 +841,17 @@ impl DockerBuilder {
                         changetype
                     );
                 }
-                // Docker diff paths are in alphabetical order and we do `rm -rf`, so we might be able to skip
-                // calling Docker more than necessary (since it's slow)
+                // Diff paths are in alphabetical order and we do `rm -rf`, so we might be able to skip
+                // calling the container engine more than necessary (since it's slow)
                 if let Some(lastpath) = lastpath {
                     if Path::new(changepath).starts_with(lastpath) {
                         continue;
                     }
                 }
                 lastpath = Some(changepath);
-                if let Err(e) = Command::new("docker")
+                if let Err(e) = self
+                    .cli
+                    .command()
                     .args(&["exec", &cid, "/busybox", "rm", "-rf", changepath])
                     .check_run()
                 {
@@ -651,7 +860,7 @@ impl DockerBuilder {
                 }
             }
 
-            let newdiff = docker_diff(&cid)?;
+            let newdiff = self.cli.diff(&cid)?;
             // See note about changepath == "/tmp" above
             if newdiff != "" && newdiff != "C /tmp" {
                 bail!(
@@ -669,9 +878,16 @@ impl DockerBuilder {
     fn finish_container(&self, tc: &Toolchain, cid: String) {
         // TODO: collect images
 
+        if !self.cli.can_diff() {
+            if let Err(e) = self.cli.rm(&cid) {
+                warn!("Failed to remove container {}: {}", cid, e);
+            }
+            return;
+        }
+
         if let Err(e) = self.clean_container(&cid) {
             info!("Failed to clean container {}: {}", cid, e);
-            if let Err(e) = docker_rm(&cid) {
+            if let Err(e) = self.cli.rm(&cid) {
                 warn!(
                     "Failed to remove container {} after failed clean: {}",
                     cid, e
@@ -689,17 +905,17 @@ impl DockerBuilder {
                 "Was ready to reclaim container {} but toolchain went missing",
                 cid
             );
-            if let Err(e) = docker_rm(&cid) {
+            if let Err(e) = self.cli.rm(&cid) {
                 warn!("Failed to remove container {}: {}", cid, e);
             }
         }
     }
 
-    fn make_image(tc: &Toolchain, tccache: &Mutex<TcCache>) -> Result<String> {
-        let cid = Command::new("docker")
-            .args(&["create", BASE_DOCKER_IMAGE, "/busybox", "true"])
-            .check_stdout_trim()
-            .context("Failed to create docker container")?;
+    fn make_image(&self, tc: &Toolchain, tccache: &Mutex<TcCache>) -> Result<String> {
+        // Running, so that the toolchain can be copied in with exec if need be
+        let cid = self
+            .start_container(BASE_DOCKER_IMAGE)
+            .context("Failed to create container")?;
 
         let mut tccache = tccache.lock().unwrap();
         let mut toolchain_rdr = match tccache.get(tc) {
@@ -714,48 +930,74 @@ impl DockerBuilder {
         };
 
         trace!("Copying in toolchain");
-        Command::new("docker")
-            .args(&["cp", "-", &format!("{}:/", cid)])
-            .check_piped(&mut |stdin| {
-                io::copy(&mut toolchain_rdr, stdin)?;
-                Ok(())
-            })
+        self.cli
+            .copy_in(&cid, &mut GzDecoder::new(&mut toolchain_rdr))
             .context("Failed to copy toolchain tar into container")?;
         drop(toolchain_rdr);
 
         let imagename = format!("sccache-builder-{}", &tc.archive_id);
-        Command::new("docker")
+        self.cli
+            .command()
             .args(&["commit", &cid, &imagename])
             .check_run()
             .context("Failed to commit container after build")?;
 
-        Command::new("docker")
-            .args(&["rm", "-f", &cid])
-            .check_run()
+        self.cli
+            .rm(&cid)
             .context("Failed to remove temporary build container")?;
 
         Ok(imagename)
     }
 
-    fn start_container(image: &str) -> Result<String> {
-        Command::new("docker")
+    fn start_container(&self, image: &str) -> Result<String> {
+        let mut cmd = self.cli.command();
+        cmd.args(&["run", "-d"]);
+        // Containers only run one job at a time, so the job limits can be
+        // the container's
+        if let Some(memory) = self.job_limits.memory {
+            cmd.arg(format!("--memory={}", memory))
+                .arg(format!("--memory-swap={}", memory));
+        }
+        if let Some(cpus) = self.job_limits.cpus {
+            cmd.arg(format!("--cpus={}", cpus));
+        }
+        cmd.args(&[image, "/busybox", "sh", "-c", DOCKER_SHELL_INIT])
+            .check_stdout_trim()
+            .context("Failed to run container")
+    }
+
+    // Whether a process in the container was killed for running out of
+    // memory, from the container's own view of its cgroup v2
+    fn oom_killed(&self, cid: &str) -> bool {
+        if self.job_limits.memory.is_none() {
+            return false;
+        }
+        match self
+            .cli
+            .command()
             .args(&[
-                "run",
-                "-d",
-                image,
+                "exec",
+                cid,
                 "/busybox",
-                "sh",
-                "-c",
-                DOCKER_SHELL_INIT,
+                "cat",
+                "/sys/fs/cgroup/memory.events",
             ])
             .check_stdout_trim()
-            .context("Failed to run container")
+        {
+            Ok(events) => cgroup::oom_kills(&events) > 0,
+            Err(e) => {
+                warn!("Failed to read memory events of container {}: {}", cid, e);
+                false
+            }
+        }
     }
 
     fn perform_build(
+        &self,
         compile_command: CompileCommand,
         mut inputs_rdr: InputsReader,
         output_paths: Vec<String>,
+        outputs_compression: Compression,
         cid: &str,
     ) -> Result<BuildResult> {
         trace!("Compile environment: {:?}", compile_command.env_vars);
@@ -766,12 +1008,8 @@ impl DockerBuilder {
         );
 
         trace!("copying in inputs");
-        Command::new("docker")
-            .args(&["cp", "-", &format!("{}:/", cid)])
-            .check_piped(&mut |stdin| {
-                io::copy(&mut inputs_rdr, stdin)?;
-                Ok(())
-            })
+        self.cli
+            .copy_in(cid, &mut inputs_rdr)
             .context("Failed to copy inputs tar into container")?;
         drop(inputs_rdr);
 
@@ -785,7 +1023,7 @@ impl DockerBuilder {
 
         trace!("creating output directories");
         assert!(!output_paths.is_empty());
-        let mut cmd = Command::new("docker");
+        let mut cmd = self.cli.command();
         cmd.args(&["exec", cid, "/busybox", "mkdir", "-p"]).arg(cwd);
         for path in output_paths.iter() {
             // If it doesn't have a parent, nothing needs creating
@@ -801,7 +1039,7 @@ impl DockerBuilder {
 
         trace!("performing compile");
         // TODO: likely shouldn't perform the compile as root in the container
-        let mut cmd = Command::new("docker");
+        let mut cmd = self.cli.command();
         cmd.arg("exec");
         for (k, v) in env_vars {
             if k.contains('=') {
@@ -819,21 +1057,28 @@ impl DockerBuilder {
         cmd.arg(cwd);
         cmd.arg(executable);
         cmd.args(arguments);
-        let compile_output = cmd.output().context("Failed to start executing compile")?;
+        let timeout = self.job_limits.timeout.map(Duration::from_secs);
+        let compile_output =
+            output_with_timeout(&mut cmd, timeout).context("Failed to start executing compile")?;
         trace!("compile_output: {:?}", compile_output);
+        if self.oom_killed(cid) {
+            return Err(OutOfMemory.into());
+        }
 
         let mut outputs = vec![];
         trace!("retrieving {:?}", output_paths);
         for path in output_paths {
             let abspath = cwd.join(&path); // Resolve in case it's relative since we copy it from the root level
                                            // TODO: this isn't great, but cp gives it out as a tar
-            let output = Command::new("docker")
+            let output = self
+                .cli
+                .command()
                 .args(&["exec", cid, "/busybox", "cat"])
                 .arg(abspath)
                 .output()
                 .context("Failed to start command to retrieve output file")?;
             if output.status.success() {
-                let output = OutputData::try_from_reader(&*output.stdout)
+                let output = OutputData::try_from_reader(&*output.stdout, outputs_compression)
                     .expect("Failed to read compress output stdout");
                 outputs.push((path, output))
             } else {
@@ -850,7 +1095,8 @@ impl DockerBuilder {
     }
 }
 
-impl BuilderIncoming for DockerBuilder {
+#[cfg(target_os = "linux")]
+impl BuilderIncoming for ContainerBuilder {
     // From Server
     fn run_build(
         &self,
@@ -858,6 +1104,7 @@ impl BuilderIncoming for DockerBuilder {
         command: CompileCommand,
         outputs: Vec<String>,
         inputs_rdr: InputsReader,
+        outputs_compression: Compression,
         tccache: &Mutex<TcCache>,
     ) -> Result<BuildResult> {
         debug!("Finding container");
@@ -865,11 +1112,351 @@ impl BuilderIncoming for DockerBuilder {
             .get_container(&tc, tccache)
             .context("Failed to get a container for build")?;
         debug!("Performing build with container {}", cid);
-        let res = Self::perform_build(command, inputs_rdr, outputs, &cid)
-            .context("Failed to perform build")?;
+        let res = match self.perform_build(command, inputs_rdr, outputs, outputs_compression, &cid)
+        {
+            Ok(res) => res,
+            Err(e) => {
+                // The container's memory events would show the kill for
+                // every later job too, and killing `exec` leaves a timed out
+                // compile running in the container
+                if e.is::<OutOfMemory>() || e.is::<TimedOut>() {
+                    if let Err(e) = self.cli.rm(&cid) {
+                        warn!("Failed to remove container {}: {}", cid, e);
+                    }
+                }
+                return Err(e.context("Failed to perform build"));
+            }
+        };
         debug!("Finishing with container {}", cid);
         self.finish_container(&tc, cid);
         debug!("Returning result");
         Ok(res)
     }
 }
+
+// Rewrite an absolute path from the client to where it is on this machine:
+// in the unpacked toolchain if the toolchain provides it, otherwise among the
+// unpacked inputs.
+fn relocate_path(root: &Path, toolchain_dir: &Path, path: &Path) -> PathBuf {
+    if !path.has_root() {
+        return path.to_owned();
+    }
+    let in_toolchain = join_suffix(toolchain_dir, path);
+    if in_toolchain.exists() {
+        in_toolchain
+    } else {
+        join_suffix(root, path)
+    }
+}
+
+// Rewrite the absolute path in a compiler argument, which may be the whole
+// argument (`/src/a.c`), follow an `=` (`--sysroot=/usr`) or be glued to a
+// short flag (`-I/src/include`).
+fn relocate_arg(root: &Path, toolchain_dir: &Path, arg: &str) -> String {
+    let split = if arg.starts_with('/') {
+        Some(0)
+    } else if let Some(i) = arg.find("=/") {
+        Some(i + 1)
+    } else if arg.starts_with('-') {
+        arg.find('/')
+            .filter(|&i| i > 1 && arg[1..i].chars().all(char::is_alphanumeric))
+    } else {
+        None
+    };
+    match split {
+        Some(i) => format!(
+            "{}{}",
+            &arg[..i],
+            relocate_path(root, toolchain_dir, Path::new(&arg[i..])).display()
+        ),
+        None => arg.to_owned(),
+    }
+}
+
+// Remove every occurrence of `prefix` from `data`, so that paths in compiler
+// output refer to the files the client knows about.
+fn strip_path_prefix(data: Vec<u8>, prefix: &Path) -> Vec<u8> {
+    let prefix = match prefix.to_str() {
+        Some(p) if !p.is_empty() => p.as_bytes(),
+        _ => return data,
+    };
+    let mut res = Vec::with_capacity(data.len());
+    let mut rest = &data[..];
+    while let Some(i) = rest.windows(prefix.len()).position(|w| w == prefix) {
+        res.extend_from_slice(&rest[..i]);
+        rest = &rest[i + prefix.len()..];
+    }
+    res.extend_from_slice(rest);
+    res
+}
+
+// A sandbox-exec profile that lets the compiler read anything, but only
+// write to its build directory and forbids network access.
+fn sandbox_profile(build_dir: &Path) -> Result<String> {
+    let build_dir = build_dir
+        .to_str()
+        .filter(|d| !d.contains('"') && !d.contains('\\'))
+        .ok_or_else(|| anyhow!("Unsupported build directory path {:?}", build_dir))?;
+    Ok(format!(
+        "(version 1)\
+         (allow default)\
+         (deny network*)\
+         (deny file-write*)\
+         (allow file-write* (subpath \"{}\") (literal \"/dev/null\"))",
+        build_dir
+    ))
+}
+
+/// Runs compiles as plain processes, for hosts without overlayfs and
+/// bubblewrap like macOS. Toolchains and inputs are unpacked into directories
+/// of their own and absolute paths in the compile command are rewritten to
+/// point into them. If `sandbox-exec` is available, compiles can't write
+/// outside their build directory or use the network.
+pub struct ProcessBuilder {
+    sandbox_exec: Option<PathBuf>,
+    dir: PathBuf,
+    toolchain_dir_map: Mutex<HashMap<Toolchain, DeflatedToolchain>>,
+    timeout: Option<Duration>,
+}
+
+impl ProcessBuilder {
+    pub fn new(
+        dir: PathBuf,
+        sandbox_exec: Option<PathBuf>,
+        timeout: Option<Duration>,
+    ) -> Result<Self> {
+        info!("Creating process builder");
+
+        match sandbox_exec {
+            Some(ref sandbox_exec) if !sandbox_exec.is_file() => {
+                bail!("sandbox-exec not found at {:?}", sandbox_exec)
+            }
+            Some(_) => (),
+            None => warn!("No sandbox-exec configured, compiles will run unsandboxed"),
+        }
+
+        create_builder_dirs(&dir)?;
+        // Sandbox profiles match on the real path, and the default temporary
+        // directory on macOS is reached through a symlink.
+        let dir = dir
+            .canonicalize()
+            .context("Failed to resolve builder directory")?;
+        Ok(Self {
+            sandbox_exec,
+            dir,
+            toolchain_dir_map: Mutex::new(HashMap::new()),
+            timeout,
+        })
+    }
+
+    fn perform_build(
+        sandbox_exec: Option<&Path>,
+        timeout: Option<Duration>,
+        compile_command: CompileCommand,
+        inputs_rdr: InputsReader,
+        output_paths: Vec<String>,
+        outputs_compression: Compression,
+        spec: &OverlaySpec,
+    ) -> Result<BuildResult> {
+        trace!("Compile environment: {:?}", compile_command.env_vars);
+        trace!(
+            "Compile command: {:?} {:?}",
+            compile_command.executable,
+            compile_command.arguments
+        );
+
+        let root = spec.build_dir.join("root");
+        let tmp_dir = spec.build_dir.join("tmp");
+        fs::create_dir(&root).context("Failed to create build root directory")?;
+        fs::create_dir(&tmp_dir).context("Failed to create build temporary directory")?;
+
+        trace!("copying in inputs");
+        tar::Archive::new(inputs_rdr)
+            .unpack(&root)
+            .context("Failed to unpack inputs")?;
+
+        let CompileCommand {
+            executable,
+            arguments,
+            env_vars,
+            cwd,
+        } = compile_command;
+        let cwd = Path::new(&cwd);
+        let toolchain_dir = spec.toolchain_dir.as_path();
+
+        trace!("creating output directories");
+        fs::create_dir_all(join_suffix(&root, cwd)).context("Failed to create cwd")?;
+        for path in output_paths.iter() {
+            // If it doesn't have a parent, nothing needs creating
+            let output_parent = if let Some(p) = Path::new(path).parent() {
+                p
+            } else {
+                continue;
+            };
+            fs::create_dir_all(join_suffix(&root, cwd.join(output_parent)))
+                .context("Failed to create an output directory")?;
+        }
+
+        trace!("performing compile");
+        let executable = relocate_path(&root, toolchain_dir, Path::new(&executable));
+        let mut cmd = match sandbox_exec {
+            Some(sandbox_exec) => {
+                let mut cmd = Command::new(sandbox_exec);
+                cmd.arg("-p")
+                    .arg(sandbox_profile(&spec.build_dir)?)
+                    .arg(executable);
+                cmd
+            }
+            None => Command::new(executable),
+        };
+        cmd.args(
+            arguments
+                .iter()
+                .map(|arg| relocate_arg(&root, toolchain_dir, arg)),
+        )
+        .current_dir(join_suffix(&root, cwd))
+        .env_clear()
+        .envs(env_vars)
+        .env("TMPDIR", &tmp_dir);
+        let mut compile_output = output_with_timeout(&mut cmd, timeout)
+            .context("Failed to retrieve output from compile")?;
+        trace!("compile_output: {:?}", compile_output);
+        compile_output.stdout = strip_path_prefix(compile_output.stdout, &root);
+        compile_output.stderr = strip_path_prefix(compile_output.stderr, &root);
+
+        let mut outputs = vec![];
+        trace!("retrieving {:?}", output_paths);
+        for path in output_paths {
+            let abspath = join_suffix(&root, cwd.join(&path)); // Resolve in case it's relative since we copy it from the root level
+            match fs::File::open(abspath) {
+                Ok(file) => {
+                    let output = OutputData::try_from_reader(file, outputs_compression)
+                        .context("Failed to read output file")?;
+                    outputs.push((path, output))
+                }
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::NotFound {
+                        debug!("Missing output path {:?}", path)
+                    } else {
+                        return Err(Error::from(e).context("Failed to open output file"));
+                    }
+                }
+            }
+        }
+        let compile_output = ProcessOutput::try_from(compile_output)
+            .context("Failed to convert compilation exit status")?;
+        Ok(BuildResult {
+            output: compile_output,
+            outputs,
+        })
+    }
+
+    fn finish_build(&self, spec: OverlaySpec) {
+        if let Err(e) = fs::remove_dir_all(&spec.build_dir) {
+            error!(
+                "Failed to remove build directory {}: {}",
+                spec.build_dir.display(),
+                e
+            );
+        }
+    }
+}
+
+impl BuilderIncoming for ProcessBuilder {
+    fn run_build(
+        &self,
+        tc: Toolchain,
+        command: CompileCommand,
+        outputs: Vec<String>,
+        inputs_rdr: InputsReader,
+        outputs_compression: Compression,
+        tccache: &Mutex<TcCache>,
+    ) -> Result<BuildResult> {
+        debug!("Preparing build directories");
+        let spec = prepare_build_dirs(&self.dir, &self.toolchain_dir_map, &tc, tccache)
+            .context("failed to prepare build dirs")?;
+        debug!("Performing build in {:?}", spec);
+        let res = Self::perform_build(
+            self.sandbox_exec.as_deref(),
+            self.timeout,
+            command,
+            inputs_rdr,
+            outputs,
+            outputs_compression,
+            &spec,
+        );
+        debug!("Finishing with build directory");
+        self.finish_build(spec);
+        debug!("Returning result");
+        res.context("Compilation execution failed")
+    }
+}
+
+#[test]
+fn test_relocate_arg() {
+    let td = tempfile::Builder::new()
+        .prefix("sccache")
+        .tempdir()
+        .unwrap();
+    let root = td.path().join("root");
+    let toolchain_dir = td.path().join("toolchain");
+    fs::create_dir_all(toolchain_dir.join("usr/bin")).unwrap();
+    fs::write(toolchain_dir.join("usr/bin/clang"), b"").unwrap();
+    let relocate = |arg| relocate_arg(&root, &toolchain_dir, arg);
+
+    assert_eq!(
+        relocate("/usr/bin/clang"),
+        toolchain_dir.join("usr/bin/clang").to_str().unwrap()
+    );
+    assert_eq!(relocate("/src/a.c"), root.join("src/a.c").to_str().unwrap());
+    assert_eq!(
+        relocate("-I/src/include"),
+        format!("-I{}", root.join("src/include").display())
+    );
+    assert_eq!(
+        relocate("--sysroot=/usr"),
+        format!("--sysroot={}", toolchain_dir.join("usr").display())
+    );
+    assert_eq!(relocate("a.c"), "a.c");
+    assert_eq!(relocate("-O2"), "-O2");
+    assert_eq!(relocate("-Wl,-rpath,/lib"), "-Wl,-rpath,/lib");
+}
+
+#[test]
+fn test_strip_path_prefix() {
+    let root = Path::new("/build/root");
+    assert_eq!(
+        strip_path_prefix(
+            b"/build/root/src/a.c:1:1: error\n/build/root/src/b.h:2:3: note".to_vec(),
+            root
+        ),
+        b"/src/a.c:1:1: error\n/src/b.h:2:3: note".to_vec()
+    );
+    assert_eq!(strip_path_prefix(b"ok".to_vec(), root), b"ok".to_vec());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_is_builder_image() {
+    assert!(is_builder_image("sccache-builder-abc"));
+    assert!(is_builder_image("localhost/sccache-builder-abc"));
+    assert!(is_builder_image(
+        "docker.io/library/sccache-builder-abc:latest"
+    ));
+    assert!(!is_builder_image("docker.io/aidanhs/busybox"));
+    assert!(!is_builder_image("sccache-builder/abc"));
+}
+
+#[test]
+fn test_output_with_timeout() {
+    let timeout = Some(Duration::from_secs(10));
+    let output = output_with_timeout(Command::new("echo").arg("hello"), timeout).unwrap();
+    assert_eq!(output.stdout, b"hello\n");
+
+    let start = Instant::now();
+    let timeout = Some(Duration::from_millis(100));
+    let err = output_with_timeout(Command::new("sh").args(&["-c", "sleep 10; true"]), timeout)
+        .unwrap_err();
+    assert!(err.is::<TimedOut>());
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
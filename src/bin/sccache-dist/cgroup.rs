@@ -0,0 +1,144 @@
+// Per-job resource limits, with a cgroup v2 for each job under the cgroup
+// the build server was started in.
+
+use anyhow::{bail, Context, Result};
+use sccache::config::server::JobLimits;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const CGROUP_MOUNT: &str = "/sys/fs/cgroup";
+// The default cpu.max period, in microseconds
+const CPU_PERIOD: u64 = 100_000;
+
+pub struct JobCgroups {
+    dir: PathBuf,
+    limits: JobLimits,
+    next_id: AtomicU64,
+}
+
+impl JobCgroups {
+    /// Set up for creating job cgroups in the cgroup of this process, which
+    /// must be delegated to it, e.g. with `Delegate=yes` under systemd.
+    pub fn new(limits: JobLimits) -> Result<Self> {
+        let proc_cgroup =
+            fs::read_to_string("/proc/self/cgroup").context("Failed to read /proc/self/cgroup")?;
+        let path = match proc_cgroup.lines().find_map(|l| l.strip_prefix("0::")) {
+            Some(path) => path.trim_start_matches('/'),
+            None => bail!("Job limits need cgroup v2, but this process isn't in a cgroup v2"),
+        };
+        let dir = Path::new(CGROUP_MOUNT).join(path);
+
+        // Processes can't be in a cgroup that has controllers enabled for
+        // its children, so move this one into a child of its own
+        let server_dir = dir.join("server");
+        fs::create_dir_all(&server_dir)
+            .with_context(|| format!("Failed to create cgroup {}", server_dir.display()))?;
+        let procs = fs::read_to_string(dir.join("cgroup.procs"))
+            .context("Failed to list the processes in the server cgroup")?;
+        for pid in procs.lines() {
+            // Processes may have exited since
+            let _ = fs::write(server_dir.join("cgroup.procs"), pid);
+        }
+        let mut controllers = vec![];
+        if limits.memory.is_some() {
+            controllers.push("+memory")
+        }
+        if limits.cpus.is_some() {
+            controllers.push("+cpu")
+        }
+        fs::write(dir.join("cgroup.subtree_control"), controllers.join(" "))
+            .context("Failed to enable the cgroup controllers for job limits")?;
+
+        // Remove the cgroups of jobs from a previous run
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with("job-") {
+                let _ = fs::remove_dir(entry.path());
+            }
+        }
+
+        Ok(JobCgroups {
+            dir,
+            limits,
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    pub fn create(&self) -> Result<JobCgroup> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let path = self.dir.join(format!("job-{}", id));
+        fs::create_dir(&path)
+            .with_context(|| format!("Failed to create cgroup {}", path.display()))?;
+        let cgroup = JobCgroup { path };
+        if let Some(memory) = self.limits.memory {
+            cgroup.write("memory.max", &memory.to_string())?;
+            // There's no swap limit without swap accounting
+            if cgroup.path.join("memory.swap.max").exists() {
+                cgroup.write("memory.swap.max", "0")?;
+            }
+        }
+        if let Some(cpus) = self.limits.cpus {
+            let quota = (cpus * CPU_PERIOD as f64) as u64;
+            cgroup.write("cpu.max", &format!("{} {}", quota, CPU_PERIOD))?;
+        }
+        Ok(cgroup)
+    }
+}
+
+/// A cgroup for a single job, removed when dropped.
+pub struct JobCgroup {
+    path: PathBuf,
+}
+
+impl JobCgroup {
+    fn write(&self, file: &str, value: &str) -> Result<()> {
+        fs::write(self.path.join(file), value)
+            .with_context(|| format!("Failed to set {} for cgroup {}", file, self.path.display()))
+    }
+
+    /// A command that runs `program` in the cgroup, along with anything it
+    /// starts.
+    pub fn command<S: AsRef<OsStr>>(&self, program: S) -> Command {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(&["-c", "echo $$ > \"$0\" && exec \"$@\""])
+            .arg(self.path.join("cgroup.procs"))
+            .arg(program);
+        cmd
+    }
+
+    /// Whether a process in the cgroup was killed for running out of memory.
+    pub fn oom_killed(&self) -> bool {
+        let events = fs::read_to_string(self.path.join("memory.events")).unwrap_or_default();
+        oom_kills(&events) > 0
+    }
+}
+
+impl Drop for JobCgroup {
+    fn drop(&mut self) {
+        // Anything left running must be killed before the cgroup can go
+        let _ = fs::write(self.path.join("cgroup.kill"), "1");
+        if let Err(e) = fs::remove_dir(&self.path) {
+            warn!("Failed to remove cgroup {}: {}", self.path.display(), e)
+        }
+    }
+}
+
+/// The `oom_kill` count from the contents of a memory.events file.
+pub fn oom_kills(events: &str) -> u64 {
+    events
+        .lines()
+        .find_map(|l| l.strip_prefix("oom_kill "))
+        .and_then(|n| n.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+#[test]
+fn test_oom_kills() {
+    let events = "low 0\nhigh 0\nmax 12\noom 1\noom_kill 1\noom_group_kill 0\n";
+    assert_eq!(oom_kills(events), 1);
+    assert_eq!(oom_kills("low 0\noom 0\noom_kill 0\n"), 0);
+    assert_eq!(oom_kills(""), 0);
+}
@@ -0,0 +1,363 @@
+// A listener for the distcc protocol, so that distcc clients can use build
+// servers while they move to sccache. Jobs are run with the compilers
+// installed on the server, outside of the builder. Only version 1 of the
+// protocol is supported, which is distcc without the lzo or cpp options.
+
+use crate::build;
+use anyhow::{bail, Context, Result};
+use sccache::config::server::DistccConfig;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{IpAddr, TcpListener};
+#[cfg(test)]
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const PROTOCOL_VERSION: u32 = 1;
+// Clients aren't authenticated, so don't let them make us allocate much
+const MAX_ARGS: u32 = 10_000;
+const MAX_ARG_LEN: u32 = 1024 * 1024;
+const MAX_SOURCE_LEN: u32 = 1024 * 1024 * 1024;
+
+struct Listener {
+    allowed_clients: HashSet<IpAddr>,
+    compilers: Vec<PathBuf>,
+    timeout: Option<Duration>,
+}
+
+/// Listen for distcc clients, with a thread for each job that may run at
+/// once.
+pub fn start(config: DistccConfig, timeout: Option<Duration>) -> Result<()> {
+    let DistccConfig {
+        listen_addr,
+        allowed_clients,
+        compilers,
+    } = config;
+    if allowed_clients.is_empty() {
+        bail!("No clients are allowed to connect to the distcc listener")
+    }
+    let listener = Arc::new(
+        TcpListener::bind(listen_addr)
+            .with_context(|| format!("Failed to listen for distcc clients on {}", listen_addr))?,
+    );
+    let state = Arc::new(Listener {
+        allowed_clients: allowed_clients.into_iter().collect(),
+        compilers,
+        timeout,
+    });
+    info!("Listening for distcc clients on {}", listen_addr);
+    for _ in 0..num_cpus::get() {
+        let listener = listener.clone();
+        let state = state.clone();
+        thread::spawn(move || loop {
+            let (stream, addr) = match listener.accept() {
+                Ok(conn) => conn,
+                Err(e) => {
+                    error!("Failed to accept a distcc connection: {}", e);
+                    continue;
+                }
+            };
+            if !state.allowed_clients.contains(&addr.ip()) {
+                warn!("Refused distcc connection from {}", addr.ip());
+                continue;
+            }
+            let res = serve(
+                BufReader::new(&stream),
+                BufWriter::new(&stream),
+                &state.compilers,
+                state.timeout,
+            );
+            if let Err(e) = res {
+                warn!("distcc job from {} failed: {:?}", addr.ip(), e)
+            }
+        });
+    }
+    Ok(())
+}
+
+fn serve<R: Read, W: Write>(
+    mut reader: R,
+    mut writer: W,
+    compilers: &[PathBuf],
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let (args, source) = read_request(&mut reader)?;
+    let compiler = find_compiler(compilers, &args[0])?;
+    let input_name = find_input(&args).context("No source file in the arguments")?;
+    let dir = tempfile::Builder::new()
+        .prefix("sccache_distcc")
+        .tempdir()
+        .context("Failed to create a directory for the job")?;
+    let input = dir
+        .path()
+        .join(format!("input.{}", preprocessed_extension(input_name)));
+    let output = dir.path().join("output.o");
+    fs::write(&input, source).context("Failed to write the source file")?;
+    let args = rewrite_args(&args[1..], &input, &output)?;
+
+    trace!("distcc compile: {} {:?}", compiler.display(), args);
+    let mut cmd = Command::new(compiler);
+    cmd.args(&args).current_dir(dir.path());
+    let compile_output = build::output_with_timeout(&mut cmd, timeout)?;
+    let status = &compile_output.status;
+    // The client expects a wait status, as from waitpid
+    let wait_status = match status.code() {
+        Some(code) => (code as u32 & 0xff) << 8,
+        None => status.signal().unwrap_or(0) as u32,
+    };
+
+    write_token(&mut writer, b"DONE", PROTOCOL_VERSION)?;
+    write_token(&mut writer, b"STAT", wait_status)?;
+    write_bytes(&mut writer, b"SERR", &compile_output.stderr)?;
+    write_bytes(&mut writer, b"SOUT", &compile_output.stdout)?;
+    // Clients only read the output of compiles that succeeded
+    if status.success() {
+        let object = fs::read(&output).context("Failed to read the compiler output")?;
+        write_bytes(&mut writer, b"DOTO", &object)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn read_request<R: Read>(reader: &mut R) -> Result<(Vec<String>, Vec<u8>)> {
+    let version = read_token(reader, b"DIST")?;
+    if version != PROTOCOL_VERSION {
+        bail!(
+            "distcc protocol version {} isn't supported, only {}",
+            version,
+            PROTOCOL_VERSION
+        )
+    }
+    let argc = read_token(reader, b"ARGC")?;
+    if argc == 0 || argc > MAX_ARGS {
+        bail!("Invalid number of arguments: {}", argc)
+    }
+    let args = (0..argc)
+        .map(|_| {
+            let arg = read_bytes(reader, b"ARGV", MAX_ARG_LEN)?;
+            String::from_utf8(arg).context("Argument is not valid UTF-8")
+        })
+        .collect::<Result<_>>()?;
+    let source = read_bytes(reader, b"DOTI", MAX_SOURCE_LEN)?;
+    Ok((args, source))
+}
+
+// Tokens are four letters followed by a parameter in eight hex digits
+fn read_token<R: Read>(reader: &mut R, name: &[u8; 4]) -> Result<u32> {
+    let mut token = [0; 12];
+    reader.read_exact(&mut token)?;
+    if token[..4] != name[..] {
+        bail!(
+            "Expected a {} token, got {:?}",
+            String::from_utf8_lossy(name),
+            String::from_utf8_lossy(&token[..4])
+        )
+    }
+    str::from_utf8(&token[4..])
+        .ok()
+        .and_then(|param| u32::from_str_radix(param, 16).ok())
+        .with_context(|| format!("Malformed {} token", String::from_utf8_lossy(name)))
+}
+
+fn read_bytes<R: Read>(reader: &mut R, name: &[u8; 4], max_len: u32) -> Result<Vec<u8>> {
+    let len = read_token(reader, name)?;
+    if len > max_len {
+        bail!("{} is too long", String::from_utf8_lossy(name))
+    }
+    let mut bytes = vec![0; len as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn write_token<W: Write>(writer: &mut W, name: &[u8; 4], param: u32) -> io::Result<()> {
+    writer.write_all(name)?;
+    write!(writer, "{:08x}", param)
+}
+
+fn write_bytes<W: Write>(writer: &mut W, name: &[u8; 4], bytes: &[u8]) -> io::Result<()> {
+    write_token(writer, name, bytes.len() as u32)?;
+    writer.write_all(bytes)
+}
+
+// Clients name the compiler as they run it, which may be a path on the
+// client, so they're matched by file name
+fn find_compiler<'a>(compilers: &'a [PathBuf], name: &str) -> Result<&'a Path> {
+    let name = Path::new(name).file_name();
+    match compilers.iter().find(|c| c.file_name() == name) {
+        Some(compiler) => Ok(compiler),
+        None => bail!("Compiler {:?} isn't allowed", name.unwrap_or_default()),
+    }
+}
+
+fn source_extension(arg: &str) -> Option<&str> {
+    let ext = Path::new(arg).extension()?.to_str()?;
+    match ext {
+        "c" | "cc" | "cpp" | "cxx" | "cp" | "c++" | "C" | "m" | "mm" | "M" | "i" | "ii" | "mi"
+        | "mii" | "s" | "S" => Some(ext),
+        _ => None,
+    }
+}
+
+fn find_input(args: &[String]) -> Option<&str> {
+    args.iter()
+        .skip(1)
+        .map(String::as_str)
+        .find(|arg| !arg.starts_with('-') && source_extension(arg).is_some())
+}
+
+// The source has already been preprocessed by the client
+fn preprocessed_extension(input: &str) -> &'static str {
+    match source_extension(input) {
+        Some("c") | Some("i") => "i",
+        Some("m") | Some("mi") => "mi",
+        Some("mm") | Some("M") | Some("mii") => "mii",
+        Some("s") | Some("S") => "s",
+        _ => "ii",
+    }
+}
+
+// Point the compiler at the local copies of the source and the object file
+fn rewrite_args(args: &[String], input: &Path, output: &Path) -> Result<Vec<String>> {
+    let input = input.to_str().context("Invalid input path")?;
+    let output = output.to_str().context("Invalid output path")?;
+    if !args.iter().any(|arg| arg == "-c" || arg == "-S") {
+        bail!("Only compiles with -c or -S can be distributed")
+    }
+    let mut rewritten = vec![];
+    let mut saw_input = false;
+    let mut saw_output = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "-o" {
+            args.next().context("-o has no value")?;
+            rewritten.extend(vec!["-o".to_owned(), output.to_owned()]);
+            saw_output = true;
+        } else if arg.starts_with("-o") {
+            rewritten.push(format!("-o{}", output));
+            saw_output = true;
+        } else if !arg.starts_with('-') && source_extension(arg).is_some() {
+            if saw_input {
+                bail!("Only one source file can be compiled at once")
+            }
+            rewritten.push(input.to_owned());
+            saw_input = true;
+        } else {
+            rewritten.push(arg.to_owned());
+        }
+    }
+    if !saw_output {
+        rewritten.extend(vec!["-o".to_owned(), output.to_owned()]);
+    }
+    Ok(rewritten)
+}
+
+#[cfg(test)]
+fn request(args: &[&str], source: &[u8]) -> Vec<u8> {
+    let mut req = vec![];
+    write_token(&mut req, b"DIST", 1).unwrap();
+    write_token(&mut req, b"ARGC", args.len() as u32).unwrap();
+    for arg in args {
+        write_bytes(&mut req, b"ARGV", arg.as_bytes()).unwrap();
+    }
+    write_bytes(&mut req, b"DOTI", source).unwrap();
+    req
+}
+
+#[test]
+fn test_read_request() {
+    let req = request(&["gcc", "-c", "foo.c", "-o", "foo.o"], b"int x;\n");
+    assert_eq!(&req[..24], b"DIST00000001ARGC00000005");
+    let (args, source) = read_request(&mut &req[..]).unwrap();
+    assert_eq!(args, vec!["gcc", "-c", "foo.c", "-o", "foo.o"]);
+    assert_eq!(source, b"int x;\n");
+
+    let mut req = req;
+    req[11] = b'2';
+    assert!(read_request(&mut &req[..]).is_err());
+    assert!(read_request(&mut &b"ARGC00000001"[..]).is_err());
+}
+
+#[test]
+fn test_rewrite_args() {
+    let args = |args: &[&str]| args.iter().map(|&a| a.to_owned()).collect::<Vec<_>>();
+    let input = Path::new("/tmp/job/input.ii");
+    let output = Path::new("/tmp/job/output.o");
+    assert_eq!(
+        find_input(&args(&["g++", "-O2", "-c", "foo.cpp"])),
+        Some("foo.cpp")
+    );
+    assert_eq!(preprocessed_extension("foo.cpp"), "ii");
+    assert_eq!(preprocessed_extension("foo.c"), "i");
+    assert_eq!(
+        rewrite_args(
+            &args(&["-O2", "-c", "src/foo.cpp", "-o", "foo.o"]),
+            input,
+            output
+        )
+        .unwrap(),
+        args(&["-O2", "-c", "/tmp/job/input.ii", "-o", "/tmp/job/output.o"])
+    );
+    assert_eq!(
+        rewrite_args(&args(&["-c", "foo.cpp", "-ofoo.o"]), input, output).unwrap(),
+        args(&["-c", "/tmp/job/input.ii", "-o/tmp/job/output.o"])
+    );
+    assert_eq!(
+        rewrite_args(&args(&["-c", "foo.cpp"]), input, output).unwrap(),
+        args(&["-c", "/tmp/job/input.ii", "-o", "/tmp/job/output.o"])
+    );
+    assert!(rewrite_args(&args(&["foo.cpp", "-o", "foo"]), input, output).is_err());
+    assert!(rewrite_args(&args(&["-c", "foo.cpp", "bar.cpp"]), input, output).is_err());
+}
+
+#[test]
+fn test_find_compiler() {
+    let compilers = vec![PathBuf::from("/usr/bin/gcc"), PathBuf::from("/usr/bin/g++")];
+    assert_eq!(
+        find_compiler(&compilers, "/opt/bin/g++").unwrap(),
+        Path::new("/usr/bin/g++")
+    );
+    assert_eq!(
+        find_compiler(&compilers, "gcc").unwrap(),
+        Path::new("/usr/bin/gcc")
+    );
+    assert!(find_compiler(&compilers, "sh").is_err());
+}
+
+#[test]
+fn test_serve() {
+    let td = tempfile::Builder::new()
+        .prefix("sccache_distcc_test")
+        .tempdir()
+        .unwrap();
+    // Copies the source to the output
+    let compiler = td.path().join("cc");
+    fs::write(
+        &compiler,
+        "#!/bin/sh\n\
+         while [ $# -gt 0 ]; do\n\
+         case $1 in -o) out=$2; shift;; -c) ;; *) src=$1;; esac; shift\n\
+         done\n\
+         echo compiling >&2\n\
+         cp \"$src\" \"$out\"\n",
+    )
+    .unwrap();
+    fs::set_permissions(&compiler, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let req = request(&["cc", "-c", "foo.c", "-o", "foo.o"], b"int x;\n");
+    let mut res = vec![];
+    serve(&req[..], &mut res, &[compiler], None).unwrap();
+    let mut res = &res[..];
+    assert_eq!(read_token(&mut res, b"DONE").unwrap(), 1);
+    assert_eq!(read_token(&mut res, b"STAT").unwrap(), 0);
+    assert_eq!(read_bytes(&mut res, b"SERR", 100).unwrap(), b"compiling\n");
+    assert_eq!(read_bytes(&mut res, b"SOUT", 100).unwrap(), b"");
+    assert_eq!(read_bytes(&mut res, b"DOTO", 100).unwrap(), b"int x;\n");
+    assert!(res.is_empty());
+}
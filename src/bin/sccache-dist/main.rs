@@ -2,10 +2,10 @@ extern crate base64;
 #[macro_use]
 extern crate clap;
 extern crate crossbeam_utils;
-extern crate env_logger;
 extern crate flate2;
 extern crate hyperx;
 extern crate jsonwebtoken as jwt;
+#[cfg(target_os = "linux")]
 extern crate libmount;
 #[macro_use]
 extern crate log;
@@ -21,39 +21,61 @@ extern crate syslog;
 extern crate tar;
 extern crate void;
 
-use anyhow::{bail, Context, Error, Result};
+use anyhow::{anyhow, bail, Context, Error, Result};
 use clap::{App, Arg, ArgMatches, SubCommand};
+use nix::sys::signal::{SigSet, Signal};
 use rand::{rngs::OsRng, RngCore};
 use sccache::config::{
-    scheduler as scheduler_config, server as server_config, INSECURE_DIST_CLIENT_TOKEN,
+    scheduler as scheduler_config, server as server_config, DistTlsConfig,
+    INSECURE_DIST_CLIENT_TOKEN,
 };
+use sccache::dist::oauth2::AuthToken;
+use sccache::dist::signing::{ToolchainSigner, TrustedSigners};
+use sccache::dist::tls::TlsCerts;
 use sccache::dist::{
-    self, AllocJobResult, AssignJobResult, BuilderIncoming, CompileCommand, HeartbeatServerResult,
-    InputsReader, JobAlloc, JobAuthorizer, JobComplete, JobId, JobState, RunJobResult,
-    SchedulerIncoming, SchedulerOutgoing, SchedulerStatusResult, ServerId, ServerIncoming,
-    ServerNonce, ServerOutgoing, SubmitToolchainResult, TcCache, Toolchain, ToolchainReader,
-    UpdateJobStateResult,
+    self, AllocJobResult, AssignJobResult, BuildServerMetrics, BuilderIncoming, CompileCommand,
+    Compression, DeregisterServerResult, DrainServerResult, HeartbeatServerResult, InputsReader,
+    JobAlloc, JobAuthorizer, JobComplete, JobDetailsResult, JobHistoryResult, JobId, JobOutcome,
+    JobState, RunJobResult, SchedulerAutoscalingResult, SchedulerDetailsResult, SchedulerIncoming,
+    SchedulerMetrics, SchedulerOutgoing, SchedulerStatusResult, ServerDetailsResult, ServerId,
+    ServerIncoming, ServerNonce, ServerOutgoing, SubmitToolchainResult, TcCache, Toolchain,
+    ToolchainReader, UpdateJobStateResult,
 };
+use sccache::log_event;
 use sccache::util::daemonize;
-use std::collections::{btree_map, BTreeMap, HashMap, HashSet};
+use scheduling::JobOwner;
+use std::collections::{btree_map, BTreeMap, HashMap, HashSet, VecDeque};
 use std::env;
-use std::io;
-use std::net::SocketAddr;
-use std::path::Path;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::fs;
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Mutex, MutexGuard};
-use std::time::{Duration, Instant};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use syslog::Facility;
 
 mod build;
+#[cfg(target_os = "linux")]
+mod cgroup;
+mod distcc;
+mod scheduling;
 mod token_check;
 
 pub const INSECURE_DIST_SERVER_TOKEN: &str = "dangerously_insecure_server";
 
+#[allow(clippy::large_enum_variant)]
 enum Command {
     Auth(AuthSubcommand),
     Scheduler(scheduler_config::Config),
     Server(server_config::Config),
+    Drain {
+        config: server_config::Config,
+        drain: bool,
+        wait: bool,
+    },
 }
 
 enum AuthSubcommand {
@@ -64,12 +86,19 @@ enum AuthSubcommand {
         secret_key: String,
         server_id: ServerId,
     },
+    ToolchainSigningKey {
+        key_path: PathBuf,
+    },
+    SignToolchain {
+        key_path: PathBuf,
+        archive: PathBuf,
+    },
 }
 
-// Only supported on x86_64 Linux machines
-#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+// Only supported on x86_64 Linux and macOS machines
+#[cfg(any(all(target_os = "linux", target_arch = "x86_64"), target_os = "macos"))]
 fn main() {
-    init_logging();
+    sccache::logging::init("RUST_LOG");
     std::process::exit(match parse() {
         Ok(cmd) => match run(cmd) {
             Ok(s) => s,
@@ -128,6 +157,21 @@ pub fn get_app<'a, 'b>() -> App<'a, 'b> {
                         )
                         .default_value("256"),
                     ),
+                )
+                .subcommand(
+                    SubCommand::with_name("generate-toolchain-signing-key")
+                        .about("Generate a key to sign toolchains with, and print its public key")
+                        .arg(Arg::from_usage(
+                            "--key-path <PATH> 'Write the key to a new file at PATH'",
+                        )),
+                )
+                .subcommand(
+                    SubCommand::with_name("sign-toolchain")
+                        .about("Print a signature of a toolchain archive")
+                        .arg(Arg::from_usage(
+                            "--key-path <PATH> 'Sign with the key in the file at PATH'",
+                        ))
+                        .arg(Arg::from_usage("<ARCHIVE> 'The toolchain archive to sign'")),
                 ),
         )
         .subcommand(
@@ -152,6 +196,23 @@ pub fn get_app<'a, 'b>() -> App<'a, 'b> {
                         .possible_values(LOG_LEVELS),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("drain")
+                .about("Stop a build server getting new jobs, and wait for its jobs to finish")
+                .arg(Arg::from_usage(
+                    "--config <PATH> 'Drain the server with the config file at PATH'",
+                ))
+                .arg(Arg::from_usage(
+                    "--no-wait 'Return without waiting for jobs to finish'",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("undrain")
+                .about("Put a drained build server back into service")
+                .arg(Arg::from_usage(
+                    "--config <PATH> 'Undrain the server with the config file at PATH'",
+                )),
+        )
 }
 
 fn check_init_syslog<'a>(name: &str, matches: &ArgMatches<'a>) {
@@ -179,7 +240,9 @@ fn parse() -> Result<Command> {
                             match config.server_auth {
                                 scheduler_config::ServerAuth::JwtHS256 { secret_key } => secret_key,
                                 scheduler_config::ServerAuth::Insecure
-                                | scheduler_config::ServerAuth::Token { token: _ } => {
+                                | scheduler_config::ServerAuth::Token { token: _ }
+                                | scheduler_config::ServerAuth::Oidc { .. }
+                                | scheduler_config::ServerAuth::Kubernetes { .. } => {
                                     bail!("Scheduler not configured with JWT HS256")
                                 }
                             }
@@ -206,6 +269,15 @@ fn parse() -> Result<Command> {
                         num_bytes: bits / 8,
                     }
                 }
+                ("generate-toolchain-signing-key", Some(matches)) => {
+                    AuthSubcommand::ToolchainSigningKey {
+                        key_path: value_t_or_exit!(matches, "key-path", PathBuf),
+                    }
+                }
+                ("sign-toolchain", Some(matches)) => AuthSubcommand::SignToolchain {
+                    key_path: value_t_or_exit!(matches, "key-path", PathBuf),
+                    archive: value_t_or_exit!(matches, "ARCHIVE", PathBuf),
+                },
                 _ => bail!("No subcommand of auth specified"),
             })
         }
@@ -235,6 +307,22 @@ fn parse() -> Result<Command> {
                 bail!("Could not load config")
             }
         }
+        (subcommand @ "drain", Some(matches)) | (subcommand @ "undrain", Some(matches)) => {
+            let config_path = Path::new(
+                matches
+                    .value_of("config")
+                    .expect("missing config in parsed subcommand"),
+            );
+            if let Some(config) = server_config::from_path(config_path)? {
+                Command::Drain {
+                    config,
+                    drain: subcommand == "drain",
+                    wait: !matches.is_present("no-wait"),
+                }
+            } else {
+                bail!("Could not load config")
+            }
+        }
         _ => bail!("No subcommand specified"),
     })
 }
@@ -250,6 +338,33 @@ fn check_server_token(server_token: &str, auth_token: &str) -> Option<ServerId>
         Some(_) | None => None,
     }
 }
+// As for a fixed token, but the token is an OIDC token of the server
+fn check_oidc_server_token(server_token: &str, check: &token_check::OidcCheck) -> Option<ServerId> {
+    let mut split = server_token.splitn(2, |c| c == ' ');
+    let server_addr = split.next().and_then(|addr| addr.parse().ok())?;
+    match check.check_token(split.next()?) {
+        Ok(()) => Some(ServerId::new(server_addr)),
+        Err(e) => {
+            warn!("OIDC server token validation failed: {}", e);
+            None
+        }
+    }
+}
+// As for a fixed token, but the token is a Kubernetes service account token
+fn check_kubernetes_server_token(
+    server_token: &str,
+    check: &token_check::KubernetesCheck,
+) -> Option<ServerId> {
+    let mut split = server_token.splitn(2, |c| c == ' ');
+    let server_addr = split.next().and_then(|addr| addr.parse().ok())?;
+    match check.check_token(split.next()?) {
+        Ok(()) => Some(ServerId::new(server_addr)),
+        Err(e) => {
+            warn!("Kubernetes server token validation failed: {}", e);
+            None
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -300,11 +415,31 @@ fn run(command: Command) -> Result<i32> {
             println!("{}", token);
             Ok(0)
         }
+        Command::Auth(AuthSubcommand::ToolchainSigningKey { key_path }) => {
+            let pkcs8 = ToolchainSigner::generate()?;
+            let signer = ToolchainSigner::from_pkcs8(&pkcs8)?;
+            fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&key_path)
+                .and_then(|mut f| f.write_all(&pkcs8))
+                .with_context(|| format!("Failed to write key to {:?}", key_path))?;
+            println!("{}", signer.public_key());
+            Ok(0)
+        }
+        Command::Auth(AuthSubcommand::SignToolchain { key_path, archive }) => {
+            let signer = ToolchainSigner::load(&key_path)?;
+            println!("{}", signer.sign_archive(&archive)?);
+            Ok(0)
+        }
 
         Command::Scheduler(scheduler_config::Config {
             public_addr,
             client_auth,
             server_auth,
+            tls,
+            scheduling,
         }) => {
             let check_client_auth: Box<dyn dist::http::ClientAuthCheck> = match client_auth {
                 scheduler_config::ClientAuth::Insecure => Box::new(token_check::EqCheck::new(
@@ -327,6 +462,15 @@ fn run(command: Command) -> Result<i32> {
                 scheduler_config::ClientAuth::ProxyToken { url, cache_secs } => {
                     Box::new(token_check::ProxyTokenCheck::new(url, cache_secs))
                 }
+                scheduler_config::ClientAuth::Oidc {
+                    issuer,
+                    audience,
+                    jwks_url,
+                    required_claims,
+                } => Box::new(
+                    token_check::OidcCheck::new(audience, issuer, jwks_url, required_claims)
+                        .context("Failed to create a checker for OIDC tokens")?,
+                ),
             };
 
             let check_server_auth: dist::http::ServerAuthCheck = match server_auth {
@@ -357,15 +501,34 @@ fn run(command: Command) -> Result<i32> {
                         check_jwt_server_token(server_token, &secret_key, &validation)
                     })
                 }
+                scheduler_config::ServerAuth::Oidc {
+                    issuer,
+                    audience,
+                    jwks_url,
+                    required_claims,
+                } => {
+                    let check =
+                        token_check::OidcCheck::new(audience, issuer, jwks_url, required_claims)
+                            .context("Failed to create a checker for OIDC server tokens")?;
+                    Box::new(move |server_token| check_oidc_server_token(server_token, &check))
+                }
+                scheduler_config::ServerAuth::Kubernetes { service_accounts } => {
+                    let check = token_check::KubernetesCheck::new(service_accounts)
+                        .context("Failed to create a checker for Kubernetes server tokens")?;
+                    Box::new(move |server_token| {
+                        check_kubernetes_server_token(server_token, &check)
+                    })
+                }
             };
 
             daemonize()?;
-            let scheduler = Scheduler::new();
+            let scheduler = Scheduler::new(scheduling);
             let http_scheduler = dist::http::Scheduler::new(
                 public_addr,
                 scheduler,
                 check_client_auth,
                 check_server_auth,
+                load_tls(tls)?,
             );
             void::unreachable(http_scheduler.start()?);
         }
@@ -375,114 +538,353 @@ fn run(command: Command) -> Result<i32> {
             cache_dir,
             public_addr,
             scheduler_url,
+            standby_scheduler_urls,
             scheduler_auth,
             toolchain_cache_size,
+            tls,
+            job_limits,
+            shutdown_timeout_secs,
+            toolchain_signers,
+            distcc,
         }) => {
+            // Jobs in progress are finished before exiting on SIGTERM, which
+            // is how Kubernetes stops pods. It's blocked before any threads
+            // are started, so that only the thread waiting for it gets it.
+            let mut sigterm = SigSet::empty();
+            sigterm.add(Signal::SIGTERM);
+            sigterm.thread_block().context("Failed to block SIGTERM")?;
+
+            let job_timeout = job_limits.timeout.map(Duration::from_secs);
             let builder: Box<dyn dist::BuilderIncoming> = match builder {
-                server_config::BuilderType::Docker => {
-                    Box::new(build::DockerBuilder::new().context("Docker builder failed to start")?)
+                #[cfg(target_os = "linux")]
+                server_config::BuilderType::Docker => Box::new(
+                    build::ContainerBuilder::new(build::ContainerCli::Docker, job_limits)
+                        .context("Docker builder failed to start")?,
+                ),
+                #[cfg(target_os = "linux")]
+                server_config::BuilderType::Podman { podman_path } => {
+                    let podman = podman_path.unwrap_or_else(|| "podman".into());
+                    Box::new(
+                        build::ContainerBuilder::new(
+                            build::ContainerCli::Podman { podman },
+                            job_limits,
+                        )
+                        .context("Podman builder failed to start")?,
+                    )
+                }
+                #[cfg(target_os = "linux")]
+                server_config::BuilderType::Containerd {
+                    nerdctl_path,
+                    namespace,
+                } => {
+                    let nerdctl = nerdctl_path.unwrap_or_else(|| "nerdctl".into());
+                    Box::new(
+                        build::ContainerBuilder::new(
+                            build::ContainerCli::Containerd { nerdctl, namespace },
+                            job_limits,
+                        )
+                        .context("containerd builder failed to start")?,
+                    )
                 }
+                #[cfg(target_os = "linux")]
                 server_config::BuilderType::Overlay {
                     bwrap_path,
                     build_dir,
                 } => Box::new(
-                    build::OverlayBuilder::new(bwrap_path, build_dir)
+                    build::OverlayBuilder::new(bwrap_path, build_dir, job_limits)
                         .context("Overlay builder failed to start")?,
                 ),
-            };
-
-            let server_id = ServerId::new(public_addr);
-            let scheduler_auth = match scheduler_auth {
-                server_config::SchedulerAuth::Insecure => {
-                    warn!("Server starting with DANGEROUSLY_INSECURE scheduler authentication");
-                    create_server_token(server_id, &INSECURE_DIST_SERVER_TOKEN)
-                }
-                server_config::SchedulerAuth::Token { token } => {
-                    create_server_token(server_id, &token)
-                }
-                server_config::SchedulerAuth::JwtToken { token } => {
-                    let token_server_id: ServerId =
-                        dangerous_insecure_extract_jwt_server_token(&token)
-                            .context("Could not decode scheduler auth jwt")?;
-                    if token_server_id != server_id {
-                        bail!(
-                            "JWT server id ({:?}) did not match configured server id ({:?})",
-                            token_server_id,
-                            server_id
-                        )
+                #[cfg(not(target_os = "linux"))]
+                server_config::BuilderType::Docker
+                | server_config::BuilderType::Podman { .. }
+                | server_config::BuilderType::Containerd { .. }
+                | server_config::BuilderType::Overlay { .. } => bail!(
+                    "The docker, podman, containerd and overlay builders are only supported on Linux"
+                ),
+                server_config::BuilderType::Process {
+                    build_dir,
+                    sandbox_exec_path,
+                } => {
+                    if job_limits.memory.is_some() || job_limits.cpus.is_some() {
+                        bail!("Memory and CPU job limits aren't supported by the process builder")
                     }
-                    token
+                    Box::new(
+                        build::ProcessBuilder::new(build_dir, sandbox_exec_path, job_timeout)
+                            .context("Process builder failed to start")?,
+                    )
                 }
             };
 
-            let server = Server::new(builder, &cache_dir, toolchain_cache_size)
+            let (public_addr, scheduler_auth) = create_scheduler_auth(public_addr, scheduler_auth)?;
+            let toolchain_signers = TrustedSigners::new(&toolchain_signers)
+                .context("Failed to parse toolchain signers")?;
+            let server = Server::new(builder, &cache_dir, toolchain_cache_size, toolchain_signers)
                 .context("Failed to create sccache server instance")?;
+            if let Some(distcc) = distcc {
+                distcc::start(distcc, job_timeout)?;
+            }
             let http_server = dist::http::Server::new(
                 public_addr,
-                scheduler_url.to_url(),
+                std::iter::once(scheduler_url)
+                    .chain(standby_scheduler_urls)
+                    .map(|url| url.to_url())
+                    .collect(),
                 scheduler_auth,
                 server,
+                load_tls(tls)?,
             )
-            .context("Failed to create sccache HTTP server instance")?;
+            .context("Failed to create sccache HTTP server instance")?
+            .shutdown_on(
+                move || {
+                    if let Err(e) = sigterm.wait() {
+                        error!("Failed to wait for SIGTERM: {}", e)
+                    }
+                },
+                Duration::from_secs(shutdown_timeout_secs),
+            );
             void::unreachable(http_server.start()?)
         }
+
+        Command::Drain {
+            config:
+                server_config::Config {
+                    public_addr,
+                    scheduler_url,
+                    standby_scheduler_urls,
+                    scheduler_auth,
+                    tls,
+                    ..
+                },
+            drain,
+            wait,
+        } => {
+            let (_, scheduler_auth) = create_scheduler_auth(public_addr, scheduler_auth)?;
+            let scheduler_urls: Vec<_> = std::iter::once(scheduler_url)
+                .chain(standby_scheduler_urls)
+                .map(|url| url.to_url())
+                .collect();
+            let tls = load_tls(tls)?;
+            let mut last_jobs_assigned = None;
+            loop {
+                let jobs_assigned =
+                    dist::http::drain_server(&scheduler_urls, &scheduler_auth, tls.as_ref(), drain)
+                        .context("Failed to drain the server")?;
+                if !drain {
+                    println!("The server is back in service");
+                    return Ok(0);
+                }
+                if jobs_assigned == 0 {
+                    println!("The server is drained");
+                    return Ok(0);
+                }
+                if !wait {
+                    println!(
+                        "The server is draining, with {} jobs to finish",
+                        jobs_assigned
+                    );
+                    return Ok(0);
+                }
+                if last_jobs_assigned != Some(jobs_assigned) {
+                    println!("Waiting for {} jobs to finish", jobs_assigned);
+                    last_jobs_assigned = Some(jobs_assigned);
+                }
+                thread::sleep(DRAIN_POLL_INTERVAL);
+            }
+        }
     }
 }
 
-fn init_logging() {
-    if env::var("RUST_LOG").is_ok() {
-        match env_logger::try_init() {
-            Ok(_) => (),
-            Err(e) => panic!(format!("Failed to initalize logging: {:?}", e)),
+// The address the server is reached at, which may only be known once it
+// starts, and how it authenticates to the scheduler
+fn create_scheduler_auth(
+    public_addr: SocketAddr,
+    scheduler_auth: server_config::SchedulerAuth,
+) -> Result<(SocketAddr, dist::http::SchedulerAuth)> {
+    // In Kubernetes, the address of a pod is only known once it starts
+    let public_addr = match (&scheduler_auth, env::var("POD_IP")) {
+        (server_config::SchedulerAuth::Kubernetes { .. }, Ok(pod_ip))
+            if public_addr.ip().is_unspecified() =>
+        {
+            let ip = pod_ip
+                .parse()
+                .with_context(|| format!("POD_IP {:?} is not an IP address", pod_ip))?;
+            SocketAddr::new(ip, public_addr.port())
         }
-    }
+        _ => public_addr,
+    };
+    let server_id = ServerId::new(public_addr);
+    let scheduler_auth: dist::http::SchedulerAuth = match scheduler_auth {
+        server_config::SchedulerAuth::Insecure => {
+            warn!("Server starting with DANGEROUSLY_INSECURE scheduler authentication");
+            let token = create_server_token(server_id, &INSECURE_DIST_SERVER_TOKEN);
+            Box::new(move || Ok(token.clone()))
+        }
+        server_config::SchedulerAuth::Token { token } => {
+            let token = create_server_token(server_id, &token);
+            Box::new(move || Ok(token.clone()))
+        }
+        server_config::SchedulerAuth::JwtToken { token } => {
+            let token_server_id: ServerId = dangerous_insecure_extract_jwt_server_token(&token)
+                .context("Could not decode scheduler auth jwt")?;
+            if token_server_id != server_id {
+                bail!(
+                    "JWT server id ({:?}) did not match configured server id ({:?})",
+                    token_server_id,
+                    server_id
+                )
+            }
+            Box::new(move || Ok(token.clone()))
+        }
+        server_config::SchedulerAuth::Oauth2ClientCredentials {
+            client_id,
+            client_secret,
+            token_url,
+            scope,
+            audience,
+        } => {
+            let auth_token = AuthToken::client_credentials(
+                &client_id,
+                &client_secret,
+                &token_url,
+                scope.as_deref(),
+                audience.as_deref(),
+            );
+            Box::new(move || Ok(create_server_token(server_id, &auth_token.token()?)))
+        }
+        server_config::SchedulerAuth::Kubernetes { token_path } => {
+            let token_path =
+                token_path.unwrap_or_else(|| token_check::KUBERNETES_TOKEN_PATH.into());
+            // The token is rotated by the kubelet, so is read each time
+            Box::new(move || {
+                let token = fs::read_to_string(&token_path)
+                    .with_context(|| format!("Failed to read {}", token_path.display()))?;
+                Ok(create_server_token(server_id, token.trim()))
+            })
+        }
+    };
+    Ok((public_addr, scheduler_auth))
+}
+
+fn load_tls(tls: Option<DistTlsConfig>) -> Result<Option<TlsCerts>> {
+    tls.map(|tls| TlsCerts::load(&tls).context("Failed to load TLS certificates"))
+        .transpose()
 }
 
 const MAX_PER_CORE_LOAD: f64 = 10f64;
+// Prefer a server that already has the toolchain for a job unless its load
+// is this much higher than the least loaded server, as sending a toolchain
+// can take longer than a compile
+const TOOLCHAIN_AFFINITY_LOAD: f64 = 1f64;
 const SERVER_REMEMBER_ERROR_TIMEOUT: Duration = Duration::from_secs(300);
 const UNCLAIMED_PENDING_TIMEOUT: Duration = Duration::from_secs(300);
 const UNCLAIMED_READY_TIMEOUT: Duration = Duration::from_secs(60);
+// How many finished jobs to keep for the details API
+const JOB_HISTORY_LEN: usize = 1000;
+// How often `sccache-dist drain` checks whether a server's jobs are done
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_secs(2);
+// How far back the average wait of jobs for autoscaling goes
+const AUTOSCALING_WINDOW: Duration = Duration::from_secs(300);
 
-#[derive(Copy, Clone)]
 struct JobDetail {
     server_id: ServerId,
     state: JobState,
+    owner: JobOwner,
+    toolchain: String,
+    created: Instant,
 }
 
 // To avoid deadlicking, make sure to do all locking at once (i.e. no further locking in a downward scope),
 // in alphabetical order
 pub struct Scheduler {
-    job_count: AtomicUsize,
+    alloc_requests: AtomicU64,
+    // Requests refused, or with no server free
+    alloc_failures: AtomicU64,
+    assign_failures: AtomicU64,
+
+    history: Mutex<JobHistory>,
+
+    // Starts at a random offset, so that the jobs of standby schedulers
+    // sharing the same servers don't get the same ids
+    job_count: AtomicU64,
 
     // Currently running jobs, can never be Complete
     jobs: Mutex<BTreeMap<JobId, JobDetail>>,
 
     servers: Mutex<HashMap<ServerId, ServerDetails>>,
+
+    scheduling: scheduler_config::Scheduling,
+}
+
+#[derive(Default)]
+struct JobHistory {
+    // Recently finished jobs, newest first
+    recent: VecDeque<JobHistoryResult>,
+    // Counts of all finished jobs, by outcome
+    complete: u64,
+    server_lost: u64,
+    abandoned: u64,
+    // When jobs started within the autoscaling window, and how long they
+    // waited to, oldest first
+    waits: VecDeque<(Instant, Duration)>,
+}
+
+impl JobHistory {
+    fn record_wait(&mut self, wait: Duration) {
+        self.waits.push_back((Instant::now(), wait));
+    }
+
+    fn average_wait(&mut self) -> Option<Duration> {
+        while let Some(&(started, _)) = self.waits.front() {
+            if started.elapsed() < AUTOSCALING_WINDOW {
+                break;
+            }
+            self.waits.pop_front();
+        }
+        if self.waits.is_empty() {
+            return None;
+        }
+        let total: Duration = self.waits.iter().map(|&(_, wait)| wait).sum();
+        Some(total / self.waits.len() as u32)
+    }
 }
 
 struct ServerDetails {
-    jobs_assigned: HashSet<JobId>,
+    jobs_assigned: HashMap<JobId, JobOwner>,
     // Jobs assigned that haven't seen a state change. Can only be pending
     // or ready.
     jobs_unclaimed: HashMap<JobId, Instant>,
     last_seen: Instant,
     last_error: Option<Instant>,
+    // When jobs failed to be assigned, in the last SERVER_REMEMBER_ERROR_TIMEOUT
+    errors: VecDeque<Instant>,
     num_cpus: usize,
     server_nonce: ServerNonce,
     job_authorizer: Box<dyn JobAuthorizer>,
+    // Toolchains the server has been given, or already had
+    toolchains: HashSet<String>,
+    // Shutting down, and removed once its jobs are done
+    draining: bool,
+    // Drained for maintenance, until it restarts or is put back into service
+    maintenance: bool,
 }
 
 impl Scheduler {
-    pub fn new() -> Self {
+    pub fn new(scheduling: scheduler_config::Scheduling) -> Self {
         Scheduler {
-            job_count: AtomicUsize::new(0),
+            alloc_requests: AtomicU64::new(0),
+            alloc_failures: AtomicU64::new(0),
+            assign_failures: AtomicU64::new(0),
+            history: Mutex::new(JobHistory::default()),
+            job_count: AtomicU64::new(u64::from(OsRng.next_u32()) << 32),
             jobs: Mutex::new(BTreeMap::new()),
             servers: Mutex::new(HashMap::new()),
+            scheduling,
         }
     }
 
     fn prune_servers(
         &self,
+        history: &mut MutexGuard<JobHistory>,
         servers: &mut MutexGuard<HashMap<ServerId, ServerDetails>>,
         jobs: &mut MutexGuard<BTreeMap<JobId, JobDetail>>,
     ) {
@@ -495,6 +897,13 @@ impl Scheduler {
                 dead_servers.push(server_id);
             }
         }
+        servers.retain(|server_id, details| {
+            let drained = details.draining && details.jobs_assigned.is_empty();
+            if drained {
+                info!("Server {} has shut down", server_id.addr());
+            }
+            !drained
+        });
 
         for server_id in dead_servers {
             warn!(
@@ -504,40 +913,100 @@ impl Scheduler {
             let server_details = servers
                 .remove(&server_id)
                 .expect("server went missing from map");
-            for job_id in server_details.jobs_assigned {
+            for job_id in server_details.jobs_assigned.keys() {
                 warn!(
                     "Non-terminated job {} was cleaned up in server pruning",
                     job_id
                 );
                 // A job may be missing here if it failed to allocate
                 // initially, so just warn if it's not present.
-                if jobs.remove(&job_id).is_none() {
-                    warn!(
+                match jobs.remove(&job_id) {
+                    Some(detail) => record_job(history, *job_id, detail, JobOutcome::ServerLost),
+                    None => warn!(
                         "Non-terminated job {} assignment originally failed.",
                         job_id
-                    );
+                    ),
                 }
             }
         }
     }
 }
 
+fn record_job(history: &mut JobHistory, job_id: JobId, detail: JobDetail, outcome: JobOutcome) {
+    let finished_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let duration = detail.created.elapsed();
+    log_event!(log::Level::Info, "job finished";
+        "job_id" => job_id.0,
+        "server" => detail.server_id.addr().to_string(),
+        "toolchain" => detail.toolchain,
+        "outcome" => format!("{:?}", outcome),
+        "duration_ms" => duration.as_millis() as u64,
+    );
+    match outcome {
+        JobOutcome::Complete => history.complete += 1,
+        JobOutcome::ServerLost => history.server_lost += 1,
+        JobOutcome::Abandoned => history.abandoned += 1,
+    }
+    history.recent.push_front(JobHistoryResult {
+        job_id,
+        server_id: detail.server_id,
+        client: detail.owner.client,
+        priority: detail.owner.priority,
+        toolchain: detail.toolchain,
+        outcome,
+        duration_ms: duration.as_millis() as u64,
+        finished_at: finished_at.as_millis() as u64,
+    });
+    history.recent.truncate(JOB_HISTORY_LEN);
+}
+
 impl SchedulerIncoming for Scheduler {
     fn handle_alloc_job(
         &self,
         requester: &dyn SchedulerOutgoing,
+        client: IpAddr,
+        priority: u32,
         tc: Toolchain,
     ) -> Result<AllocJobResult> {
+        self.alloc_requests.fetch_add(1, Ordering::SeqCst);
+        let owner = JobOwner::new(&self.scheduling, client, priority);
+        let created = Instant::now();
+        let toolchain = tc.archive_id.clone();
         let (job_id, server_id, auth) = {
             // LOCKS
             let mut servers = self.servers.lock().unwrap();
 
+            let assigned: Vec<JobOwner> = servers
+                .values()
+                .flat_map(|details| details.jobs_assigned.values().copied())
+                .collect();
+            let num_cpus = servers
+                .values()
+                .filter(|details| !details.draining && !details.maintenance)
+                .map(|details| details.num_cpus)
+                .sum();
+            if let Err(msg) = scheduling::check_share(&self.scheduling, &assigned, num_cpus, owner)
+            {
+                info!("Refused a job for {}: {}", client, msg);
+                self.alloc_failures.fetch_add(1, Ordering::SeqCst);
+                return Ok(AllocJobResult::Fail { msg });
+            }
+
             let res = {
                 let mut best = None;
                 let mut best_err = None;
                 let mut best_load: f64 = MAX_PER_CORE_LOAD;
+                // The best server that already has the toolchain, which is
+                // never the same as `best`
+                let mut best_with_tc = None;
+                let mut best_with_tc_load: f64 = MAX_PER_CORE_LOAD;
                 let now = Instant::now();
                 for (&server_id, details) in servers.iter_mut() {
+                    if details.draining || details.maintenance {
+                        continue;
+                    }
                     let load = details.jobs_assigned.len() as f64 / details.num_cpus as f64;
 
                     if let Some(last_error) = details.last_error {
@@ -572,21 +1041,36 @@ impl SchedulerIncoming for Scheduler {
                                 }
                             }
                         }
+                    } else if details.toolchains.contains(&toolchain) {
+                        if load < best_with_tc_load {
+                            trace!(
+                                "Selected {:?} as the server with the toolchain with the best load",
+                                server_id
+                            );
+                            best_with_tc = Some((server_id, details));
+                            best_with_tc_load = load;
+                            if load == 0f64 {
+                                break;
+                            }
+                        }
                     } else if load < best_load {
                         best = Some((server_id, details));
                         trace!("Selected {:?} as the server with the best load", server_id);
                         best_load = load;
-                        if load == 0f64 {
-                            break;
-                        }
                     }
                 }
+                if best_with_tc_load <= best_load + TOOLCHAIN_AFFINITY_LOAD {
+                    best = best_with_tc.or(best);
+                } else {
+                    trace!("Servers with the toolchain are too busy, it will be sent to another");
+                    best = best.or(best_with_tc);
+                }
 
                 // Assign the job to our best choice
                 if let Some((server_id, server_details)) = best.or(best_err) {
-                    let job_count = self.job_count.fetch_add(1, Ordering::SeqCst) as u64;
+                    let job_count = self.job_count.fetch_add(1, Ordering::SeqCst);
                     let job_id = JobId(job_count);
-                    assert!(server_details.jobs_assigned.insert(job_id));
+                    assert!(server_details.jobs_assigned.insert(job_id, owner).is_none());
                     assert!(server_details
                         .jobs_unclaimed
                         .insert(job_id, Instant::now())
@@ -614,6 +1098,7 @@ impl SchedulerIncoming for Scheduler {
                     "Insufficient capacity across {} available servers",
                     servers.len()
                 );
+                self.alloc_failures.fetch_add(1, Ordering::SeqCst);
                 return Ok(AllocJobResult::Fail { msg });
             }
         };
@@ -623,12 +1108,14 @@ impl SchedulerIncoming for Scheduler {
         } = requester
             .do_assign_job(server_id, job_id, tc, auth.clone())
             .with_context(|| {
+                self.assign_failures.fetch_add(1, Ordering::SeqCst);
                 // LOCKS
                 let mut servers = self.servers.lock().unwrap();
                 if let Some(entry) = servers.get_mut(&server_id) {
                     entry.last_error = Some(Instant::now());
+                    entry.errors.push_back(Instant::now());
                     entry.jobs_unclaimed.remove(&job_id);
-                    if !entry.jobs_assigned.remove(&job_id) {
+                    if entry.jobs_assigned.remove(&job_id).is_none() {
                         "assign job failed and job not known to the server"
                     } else {
                         "assign job failed, job un-assigned from the server"
@@ -640,14 +1127,32 @@ impl SchedulerIncoming for Scheduler {
         {
             // LOCKS
             let mut jobs = self.jobs.lock().unwrap();
+            let mut servers = self.servers.lock().unwrap();
 
-            info!(
-                "Job {} successfully assigned and saved with state {:?}",
-                job_id, state
+            log_event!(log::Level::Info, "job assigned";
+                "job_id" => job_id.0,
+                "server" => server_id.addr().to_string(),
+                "toolchain" => toolchain,
+                "state" => state.to_string(),
+                "need_toolchain" => need_toolchain,
             );
-            assert!(jobs
-                .insert(job_id, JobDetail { server_id, state })
-                .is_none());
+            // The server may have dropped the toolchain from its cache since
+            // it was last given it
+            if let Some(details) = servers.get_mut(&server_id) {
+                if need_toolchain {
+                    details.toolchains.remove(&toolchain);
+                } else {
+                    details.toolchains.insert(toolchain.clone());
+                }
+            }
+            let detail = JobDetail {
+                server_id,
+                state,
+                owner,
+                toolchain,
+                created,
+            };
+            assert!(jobs.insert(job_id, detail).is_none());
         }
         let job_alloc = JobAlloc {
             auth,
@@ -672,10 +1177,11 @@ impl SchedulerIncoming for Scheduler {
         }
 
         // LOCKS
+        let mut history = self.history.lock().unwrap();
         let mut jobs = self.jobs.lock().unwrap();
         let mut servers = self.servers.lock().unwrap();
 
-        self.prune_servers(&mut servers, &mut jobs);
+        self.prune_servers(&mut history, &mut servers, &mut jobs);
 
         match servers.get_mut(&server_id) {
             Some(ref mut details) if details.server_nonce == server_nonce => {
@@ -714,7 +1220,7 @@ impl SchedulerIncoming for Scheduler {
                     );
 
                     for job_id in stale_jobs {
-                        if !details.jobs_assigned.remove(&job_id) {
+                        if details.jobs_assigned.remove(&job_id).is_none() {
                             warn!(
                                 "Stale job for server {} not assigned: {}",
                                 server_id.addr(),
@@ -728,12 +1234,15 @@ impl SchedulerIncoming for Scheduler {
                                 job_id
                             );
                         }
-                        if jobs.remove(&job_id).is_none() {
-                            warn!(
+                        match jobs.remove(&job_id) {
+                            Some(detail) => {
+                                record_job(&mut history, job_id, detail, JobOutcome::Abandoned)
+                            }
+                            None => warn!(
                                 "Unknown stale job for server {}: {}",
                                 server_id.addr(),
                                 job_id
-                            );
+                            ),
                         }
                     }
                 }
@@ -741,13 +1250,16 @@ impl SchedulerIncoming for Scheduler {
                 return Ok(HeartbeatServerResult { is_new: false });
             }
             Some(ref mut details) if details.server_nonce != server_nonce => {
-                for job_id in details.jobs_assigned.iter() {
-                    if jobs.remove(&job_id).is_none() {
-                        warn!(
+                for &job_id in details.jobs_assigned.keys() {
+                    match jobs.remove(&job_id) {
+                        Some(detail) => {
+                            record_job(&mut history, job_id, detail, JobOutcome::ServerLost)
+                        }
+                        None => warn!(
                             "Unknown job found when replacing server {}: {}",
                             server_id.addr(),
                             job_id
-                        );
+                        ),
                     }
                 }
             }
@@ -759,16 +1271,63 @@ impl SchedulerIncoming for Scheduler {
             ServerDetails {
                 last_seen: Instant::now(),
                 last_error: None,
-                jobs_assigned: HashSet::new(),
+                errors: VecDeque::new(),
+                jobs_assigned: HashMap::new(),
                 jobs_unclaimed: HashMap::new(),
                 num_cpus,
                 server_nonce,
                 job_authorizer,
+                toolchains: HashSet::new(),
+                draining: false,
+                maintenance: false,
             },
         );
         Ok(HeartbeatServerResult { is_new: true })
     }
 
+    fn handle_deregister_server(
+        &self,
+        server_id: ServerId,
+        server_nonce: ServerNonce,
+    ) -> Result<DeregisterServerResult> {
+        // LOCKS
+        let mut history = self.history.lock().unwrap();
+        let mut jobs = self.jobs.lock().unwrap();
+        let mut servers = self.servers.lock().unwrap();
+
+        let jobs_assigned = match servers.get_mut(&server_id) {
+            Some(details) if details.server_nonce == server_nonce => {
+                info!("Server {} is shutting down", server_id.addr());
+                details.draining = true;
+                details.jobs_assigned.len()
+            }
+            Some(_) => bail!("Server {} has restarted since", server_id.addr()),
+            None => 0,
+        };
+        self.prune_servers(&mut history, &mut servers, &mut jobs);
+        Ok(DeregisterServerResult { jobs_assigned })
+    }
+
+    fn handle_drain_server(&self, server_id: ServerId, drain: bool) -> Result<DrainServerResult> {
+        // LOCKS
+        let mut servers = self.servers.lock().unwrap();
+
+        let details = servers
+            .get_mut(&server_id)
+            .with_context(|| format!("Server {} is not registered", server_id.addr()))?;
+        if details.maintenance != drain {
+            if drain {
+                info!("Server {} is draining for maintenance", server_id.addr());
+            } else {
+                info!("Server {} is back in service", server_id.addr());
+            }
+            details.maintenance = drain;
+        }
+        Ok(DrainServerResult {
+            jobs_assigned: details.jobs_assigned.len(),
+        })
+    }
+
     fn handle_update_job_state(
         &self,
         job_id: JobId,
@@ -776,6 +1335,7 @@ impl SchedulerIncoming for Scheduler {
         job_state: JobState,
     ) -> Result<UpdateJobStateResult> {
         // LOCKS
+        let mut history = self.history.lock().unwrap();
         let mut jobs = self.jobs.lock().unwrap();
         let mut servers = self.servers.lock().unwrap();
 
@@ -796,8 +1356,15 @@ impl SchedulerIncoming for Scheduler {
             };
 
             match (job_detail.state, job_state) {
-                (JobState::Pending, JobState::Ready) => entry.get_mut().state = job_state,
+                (JobState::Pending, JobState::Ready) => {
+                    // The toolchain has been submitted to the server
+                    if let Some(details) = server_details {
+                        details.toolchains.insert(job_detail.toolchain.clone());
+                    }
+                    entry.get_mut().state = job_state
+                }
                 (JobState::Ready, JobState::Started) => {
+                    history.record_wait(job_detail.created.elapsed());
                     if let Some(details) = server_details {
                         details.jobs_unclaimed.remove(&job_id);
                     } else {
@@ -806,9 +1373,10 @@ impl SchedulerIncoming for Scheduler {
                     entry.get_mut().state = job_state
                 }
                 (JobState::Started, JobState::Complete) => {
-                    let (job_id, _) = entry.remove_entry();
+                    let (job_id, detail) = entry.remove_entry();
+                    record_job(&mut history, job_id, detail, JobOutcome::Complete);
                     if let Some(entry) = server_details {
-                        assert!(entry.jobs_assigned.remove(&job_id))
+                        assert!(entry.jobs_assigned.remove(&job_id).is_some())
                     } else {
                         bail!("Job was marked as finished, but server is not known to scheduler")
                     }
@@ -816,18 +1384,52 @@ impl SchedulerIncoming for Scheduler {
                 (from, to) => bail!("Invalid job state transition from {} to {}", from, to),
             }
             info!("Job {} updated state to {:?}", job_id, job_state);
+        } else if let Some(details) = servers.get_mut(&server_id) {
+            // The job was allocated before this scheduler restarted, or by
+            // another scheduler that has gone down. Take it over so that it
+            // counts towards the server's load and can finish
+            let now = Instant::now();
+            details.last_seen = now;
+            if job_state == JobState::Complete {
+                info!("Untracked job {} completed", job_id);
+                return Ok(UpdateJobStateResult::Success);
+            }
+            info!(
+                "Adopting job {} on server {:?} in state {:?}",
+                job_id, server_id, job_state
+            );
+            // Who the job was allocated for isn't known any more
+            let owner = JobOwner {
+                client: Ipv4Addr::UNSPECIFIED.into(),
+                priority: 0,
+            };
+            details.jobs_assigned.insert(job_id, owner);
+            if job_state != JobState::Started {
+                details.jobs_unclaimed.insert(job_id, now);
+            }
+            jobs.insert(
+                job_id,
+                JobDetail {
+                    server_id,
+                    state: job_state,
+                    owner,
+                    toolchain: String::new(),
+                    created: now,
+                },
+            );
         } else {
-            bail!("Unknown job")
+            return Ok(UpdateJobStateResult::UnknownServer);
         }
         Ok(UpdateJobStateResult::Success)
     }
 
     fn handle_status(&self) -> Result<SchedulerStatusResult> {
         // LOCKS
+        let mut history = self.history.lock().unwrap();
         let mut jobs = self.jobs.lock().unwrap();
         let mut servers = self.servers.lock().unwrap();
 
-        self.prune_servers(&mut servers, &mut jobs);
+        self.prune_servers(&mut history, &mut servers, &mut jobs);
 
         Ok(SchedulerStatusResult {
             num_servers: servers.len(),
@@ -835,12 +1437,132 @@ impl SchedulerIncoming for Scheduler {
             in_progress: jobs.len(),
         })
     }
+
+    fn handle_details(&self) -> Result<SchedulerDetailsResult> {
+        // LOCKS
+        let mut history = self.history.lock().unwrap();
+        let mut jobs = self.jobs.lock().unwrap();
+        let mut servers = self.servers.lock().unwrap();
+
+        self.prune_servers(&mut history, &mut servers, &mut jobs);
+
+        let ms_ago = |at: Instant| at.elapsed().as_millis() as u64;
+        let mut servers: Vec<_> = servers
+            .iter_mut()
+            .map(|(&server_id, details)| {
+                while let Some(&at) = details.errors.front() {
+                    if at.elapsed() < SERVER_REMEMBER_ERROR_TIMEOUT {
+                        break;
+                    }
+                    details.errors.pop_front();
+                }
+                let mut toolchains: Vec<_> = details.toolchains.iter().cloned().collect();
+                toolchains.sort();
+                let jobs_started = details
+                    .jobs_assigned
+                    .keys()
+                    .filter(|job_id| {
+                        jobs.get(job_id)
+                            .map_or(false, |job| job.state == JobState::Started)
+                    })
+                    .count();
+                ServerDetailsResult {
+                    server_id,
+                    num_cpus: details.num_cpus,
+                    jobs_assigned: details.jobs_assigned.len(),
+                    jobs_unclaimed: details.jobs_unclaimed.len(),
+                    jobs_started,
+                    last_seen_ms_ago: ms_ago(details.last_seen),
+                    last_error_ms_ago: details.last_error.map(ms_ago),
+                    recent_errors: details.errors.len(),
+                    toolchains,
+                    draining: details.draining || details.maintenance,
+                }
+            })
+            .collect();
+        servers.sort_by_key(|server| server.server_id.addr());
+        let jobs = jobs
+            .iter()
+            .map(|(&job_id, detail)| JobDetailsResult {
+                job_id,
+                server_id: detail.server_id,
+                state: detail.state,
+                client: detail.owner.client,
+                priority: detail.owner.priority,
+                toolchain: detail.toolchain.clone(),
+                age_ms: ms_ago(detail.created),
+            })
+            .collect();
+        Ok(SchedulerDetailsResult {
+            servers,
+            jobs,
+            history: history.recent.iter().cloned().collect(),
+        })
+    }
+
+    fn handle_metrics(&self) -> Result<SchedulerMetrics> {
+        let details = self.handle_details()?;
+        let jobs_in = |state| details.jobs.iter().filter(|j| j.state == state).count();
+        // LOCKS
+        let mut history = self.history.lock().unwrap();
+        Ok(SchedulerMetrics {
+            jobs_pending: jobs_in(JobState::Pending),
+            jobs_ready: jobs_in(JobState::Ready),
+            jobs_started: jobs_in(JobState::Started),
+            alloc_requests: self.alloc_requests.load(Ordering::SeqCst),
+            alloc_failures: self.alloc_failures.load(Ordering::SeqCst),
+            assign_failures: self.assign_failures.load(Ordering::SeqCst),
+            jobs_complete: history.complete,
+            jobs_server_lost: history.server_lost,
+            jobs_abandoned: history.abandoned,
+            average_wait_ms: history.average_wait().map(|wait| wait.as_millis() as u64),
+            servers: details.servers,
+        })
+    }
+
+    fn handle_autoscaling(&self) -> Result<SchedulerAutoscalingResult> {
+        // LOCKS
+        let mut history = self.history.lock().unwrap();
+        let mut jobs = self.jobs.lock().unwrap();
+        let mut servers = self.servers.lock().unwrap();
+
+        self.prune_servers(&mut history, &mut servers, &mut jobs);
+
+        let servers: Vec<_> = servers
+            .values()
+            .filter(|s| !s.draining && !s.maintenance)
+            .collect();
+        let num_cpus = servers.iter().map(|s| s.num_cpus).sum();
+        let jobs_running = jobs
+            .values()
+            .filter(|j| j.state == JobState::Started)
+            .count();
+        let jobs_queued = jobs.len() - jobs_running;
+        Ok(SchedulerAutoscalingResult {
+            num_servers: servers.len(),
+            num_cpus,
+            jobs_queued,
+            jobs_running,
+            load: jobs.len() as f64 / num_cpus.max(1) as f64,
+            average_wait_ms: history.average_wait().map(|wait| wait.as_millis() as u64),
+        })
+    }
 }
 
 pub struct Server {
     builder: Box<dyn BuilderIncoming>,
     cache: Mutex<TcCache>,
     job_toolchains: Mutex<HashMap<JobId, Toolchain>>,
+    toolchain_signers: TrustedSigners,
+    // Toolchains with a signature checked since starting, as the cache
+    // may have toolchains from before the signers were configured
+    signed_toolchains: Mutex<HashSet<Toolchain>>,
+    jobs_running: AtomicUsize,
+    builds_complete: AtomicU64,
+    // Builds that couldn't be run, rather than ones that failed to compile
+    builds_failed: AtomicU64,
+    builds_out_of_memory: AtomicU64,
+    builds_timed_out: AtomicU64,
 }
 
 impl Server {
@@ -848,6 +1570,7 @@ impl Server {
         builder: Box<dyn BuilderIncoming>,
         cache_dir: &Path,
         toolchain_cache_size: u64,
+        toolchain_signers: TrustedSigners,
     ) -> Result<Server> {
         let cache = TcCache::new(&cache_dir.join("tc"), toolchain_cache_size)
             .context("Failed to create toolchain cache")?;
@@ -855,13 +1578,26 @@ impl Server {
             builder,
             cache: Mutex::new(cache),
             job_toolchains: Mutex::new(HashMap::new()),
+            toolchain_signers,
+            signed_toolchains: Mutex::new(HashSet::new()),
+            jobs_running: AtomicUsize::new(0),
+            builds_complete: AtomicU64::new(0),
+            builds_failed: AtomicU64::new(0),
+            builds_out_of_memory: AtomicU64::new(0),
+            builds_timed_out: AtomicU64::new(0),
         })
     }
+
+    fn toolchain_trusted(&self, tc: &Toolchain) -> bool {
+        self.toolchain_signers.is_empty() || self.signed_toolchains.lock().unwrap().contains(tc)
+    }
 }
 
 impl ServerIncoming for Server {
     fn handle_assign_job(&self, job_id: JobId, tc: Toolchain) -> Result<AssignJobResult> {
-        let need_toolchain = !self.cache.lock().unwrap().contains_toolchain(&tc);
+        // The client sends the signature along with the toolchain
+        let need_toolchain =
+            !self.cache.lock().unwrap().contains_toolchain(&tc) || !self.toolchain_trusted(&tc);
         assert!(self
             .job_toolchains
             .lock()
@@ -883,6 +1619,7 @@ impl ServerIncoming for Server {
         &self,
         requester: &dyn ServerOutgoing,
         job_id: JobId,
+        signature: Option<String>,
         tc_rdr: ToolchainReader,
     ) -> Result<SubmitToolchainResult> {
         requester
@@ -894,6 +1631,20 @@ impl ServerIncoming for Server {
             Some(tc) => tc,
             None => return Ok(SubmitToolchainResult::JobNotFound),
         };
+        if !self.toolchain_trusted(&tc) {
+            let res = match signature {
+                Some(signature) => self.toolchain_signers.verify(&tc, &signature),
+                None => Err(anyhow!("the toolchain isn't signed")),
+            };
+            if let Err(e) = res {
+                warn!(
+                    "Refusing toolchain {} for job {}: {}",
+                    tc.archive_id, job_id, e
+                );
+                return Ok(SubmitToolchainResult::Untrusted(e.to_string()));
+            }
+            self.signed_toolchains.lock().unwrap().insert(tc.clone());
+        }
         let mut cache = self.cache.lock().unwrap();
         // TODO: this returns before reading all the data, is that valid?
         if cache.contains_toolchain(&tc) {
@@ -913,6 +1664,7 @@ impl ServerIncoming for Server {
         command: CompileCommand,
         outputs: Vec<String>,
         inputs_rdr: InputsReader,
+        outputs_compression: Compression,
     ) -> Result<RunJobResult> {
         requester
             .do_update_job_state(job_id, JobState::Started)
@@ -920,16 +1672,57 @@ impl ServerIncoming for Server {
         let tc = self.job_toolchains.lock().unwrap().remove(&job_id);
         let res = match tc {
             None => Ok(RunJobResult::JobNotFound),
+            Some(tc) if !self.toolchain_trusted(&tc) => Err(anyhow!(
+                "Toolchain {} hasn't been signed by a trusted signer",
+                tc.archive_id
+            )),
             Some(tc) => {
-                match self
-                    .builder
-                    .run_build(tc, command, outputs, inputs_rdr, &self.cache)
-                {
-                    Err(e) => Err(e.context("run build failed")),
-                    Ok(res) => Ok(RunJobResult::Complete(JobComplete {
-                        output: res.output,
-                        outputs: res.outputs,
-                    })),
+                self.jobs_running.fetch_add(1, Ordering::SeqCst);
+                let start = Instant::now();
+                let archive_id = tc.archive_id.clone();
+                let res = self.builder.run_build(
+                    tc,
+                    command,
+                    outputs,
+                    inputs_rdr,
+                    outputs_compression,
+                    &self.cache,
+                );
+                self.jobs_running.fetch_sub(1, Ordering::SeqCst);
+                let result = match &res {
+                    Ok(_) => "complete",
+                    Err(e) if e.is::<build::OutOfMemory>() => "out_of_memory",
+                    Err(e) if e.is::<build::TimedOut>() => "timed_out",
+                    Err(_) => "failed",
+                };
+                log_event!(log::Level::Info, "build finished";
+                    "job_id" => job_id.0,
+                    "toolchain" => archive_id,
+                    "result" => result,
+                    "duration_ms" => start.elapsed().as_millis() as u64,
+                );
+                match res {
+                    Err(e) if e.is::<build::OutOfMemory>() => {
+                        warn!("Job {} ran out of memory", job_id);
+                        self.builds_out_of_memory.fetch_add(1, Ordering::SeqCst);
+                        Ok(RunJobResult::OutOfMemory)
+                    }
+                    Err(e) if e.is::<build::TimedOut>() => {
+                        warn!("Job {} timed out", job_id);
+                        self.builds_timed_out.fetch_add(1, Ordering::SeqCst);
+                        Ok(RunJobResult::TimedOut)
+                    }
+                    Err(e) => {
+                        self.builds_failed.fetch_add(1, Ordering::SeqCst);
+                        Err(e.context("run build failed"))
+                    }
+                    Ok(res) => {
+                        self.builds_complete.fetch_add(1, Ordering::SeqCst);
+                        Ok(RunJobResult::Complete(JobComplete {
+                            output: res.output,
+                            outputs: res.outputs,
+                        }))
+                    }
                 }
             }
         };
@@ -938,4 +1731,19 @@ impl ServerIncoming for Server {
             .context("Updating job state failed")?;
         res
     }
+    fn handle_metrics(&self) -> Result<BuildServerMetrics> {
+        let jobs_assigned = self.job_toolchains.lock().unwrap().len();
+        let cache = self.cache.lock().unwrap();
+        Ok(BuildServerMetrics {
+            jobs_assigned,
+            jobs_running: self.jobs_running.load(Ordering::SeqCst),
+            builds_complete: self.builds_complete.load(Ordering::SeqCst),
+            builds_failed: self.builds_failed.load(Ordering::SeqCst),
+            builds_out_of_memory: self.builds_out_of_memory.load(Ordering::SeqCst),
+            builds_timed_out: self.builds_timed_out.load(Ordering::SeqCst),
+            toolchains: cache.len(),
+            toolchain_cache_size: cache.size(),
+            toolchain_cache_capacity: cache.capacity(),
+        })
+    }
 }
@@ -0,0 +1,132 @@
+// Sharing out the servers between clients, so that one client with a very
+// parallel build can't starve everyone else once every core is busy.
+
+use sccache::config::scheduler::Scheduling;
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+/// The client a job was allocated for, and the priority it was given.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct JobOwner {
+    pub client: IpAddr,
+    pub priority: u32,
+}
+
+impl JobOwner {
+    // Clients can't ask for more than their configured maximum priority
+    pub fn new(config: &Scheduling, client: IpAddr, priority: u32) -> Self {
+        let max_priority = config
+            .clients
+            .get(&client)
+            .and_then(|limits| limits.max_priority)
+            .or(config.max_priority);
+        JobOwner {
+            client,
+            priority: max_priority.map_or(priority, |max| priority.min(max)),
+        }
+    }
+}
+
+/// Check that `owner` may be allocated another job, given the owners of the
+/// jobs already assigned and the number of cores across all servers. The
+/// error explains why not, for the client.
+pub fn check_share(
+    config: &Scheduling,
+    assigned: &[JobOwner],
+    num_cpus: usize,
+    owner: JobOwner,
+) -> Result<(), String> {
+    let client_jobs = assigned.iter().filter(|o| o.client == owner.client).count();
+    let max_jobs = config
+        .clients
+        .get(&owner.client)
+        .and_then(|limits| limits.max_jobs)
+        .or(config.max_jobs_per_client);
+    if let Some(max_jobs) = max_jobs {
+        if client_jobs >= max_jobs {
+            return Err(format!(
+                "Client {} has reached its limit of {} jobs",
+                owner.client, max_jobs
+            ));
+        }
+    }
+
+    // There are free cores, so nobody is waiting
+    if !config.fair_share || assigned.len() < num_cpus {
+        return Ok(());
+    }
+    let top_priority = assigned
+        .iter()
+        .map(|o| o.priority)
+        .max()
+        .unwrap_or(0)
+        .max(owner.priority);
+    if owner.priority < top_priority {
+        return Err(format!(
+            "All servers are busy with jobs of priority {}, higher than {}",
+            top_priority, owner.priority
+        ));
+    }
+    // Split the cores evenly between the clients with jobs at the top
+    // priority. A client on its own may keep queueing jobs on the servers.
+    let mut clients: HashSet<IpAddr> = assigned
+        .iter()
+        .filter(|o| o.priority == top_priority)
+        .map(|o| o.client)
+        .collect();
+    clients.insert(owner.client);
+    let share = (num_cpus / clients.len()).max(1);
+    if clients.len() > 1 && client_jobs >= share {
+        return Err(format!(
+            "All servers are busy, and client {} has its share of {} jobs",
+            owner.client, share
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_check_share() {
+    let a: IpAddr = "10.0.0.1".parse().unwrap();
+    let b: IpAddr = "10.0.0.2".parse().unwrap();
+    let owner = |client, priority| JobOwner { client, priority };
+    let config = Scheduling::default();
+
+    // A client on its own may use more than every core
+    let assigned = vec![owner(a, 0); 8];
+    assert!(check_share(&config, &assigned, 8, owner(a, 0)).is_ok());
+    // Another client gets jobs while the first has more than its share...
+    assert!(check_share(&config, &assigned, 8, owner(b, 0)).is_ok());
+    // ...and the first gets no more until it drops below its share
+    let mut assigned = assigned;
+    assigned.push(owner(b, 0));
+    assert!(check_share(&config, &assigned, 8, owner(a, 0)).is_err());
+    assert!(check_share(&config, &assigned, 8, owner(b, 0)).is_ok());
+    assigned.drain(..5);
+    assert!(check_share(&config, &assigned, 8, owner(a, 0)).is_ok());
+
+    // Lower priority jobs wait while higher priority ones fill the servers
+    let assigned = vec![owner(a, 1); 8];
+    assert!(check_share(&config, &assigned, 8, owner(b, 0)).is_err());
+    assert!(check_share(&config, &assigned, 9, owner(b, 0)).is_ok());
+    assert!(check_share(&config, &[owner(a, 0); 8], 8, owner(b, 1)).is_ok());
+
+    // Without fair sharing, only the per-client limit applies
+    let mut config = Scheduling {
+        fair_share: false,
+        max_jobs_per_client: Some(4),
+        ..Default::default()
+    };
+    assert!(check_share(&config, &[owner(b, 0); 3], 2, owner(a, 0)).is_ok());
+    assert!(check_share(&config, &[owner(a, 0); 4], 100, owner(a, 0)).is_err());
+    config.clients.insert(
+        a,
+        sccache::config::scheduler::ClientLimits {
+            max_jobs: Some(5),
+            max_priority: Some(2),
+        },
+    );
+    assert!(check_share(&config, &[owner(a, 0); 4], 100, owner(a, 0)).is_ok());
+    assert_eq!(JobOwner::new(&config, a, 10).priority, 2);
+    assert_eq!(JobOwner::new(&config, b, 10).priority, 10);
+}
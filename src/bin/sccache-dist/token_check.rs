@@ -77,7 +77,7 @@ const MOZ_USERINFO_ENDPOINT: &str = "https://auth.mozilla.auth0.com/userinfo";
 // Mozilla-specific check by forwarding the token onto the auth0 userinfo endpoint
 pub struct MozillaCheck {
     auth_cache: Mutex<HashMap<String, Instant>>, // token, token_expiry
-    client: reqwest::Client,
+    client: reqwest::blocking::Client,
     required_groups: Vec<String>,
 }
 
@@ -96,7 +96,7 @@ impl MozillaCheck {
     pub fn new(required_groups: Vec<String>) -> Self {
         Self {
             auth_cache: Mutex::new(HashMap::new()),
-            client: reqwest::Client::new(),
+            client: reqwest::blocking::Client::new(),
             required_groups,
         }
     }
@@ -145,22 +145,18 @@ impl MozillaCheck {
         let header = hyperx::header::Authorization(hyperx::header::Bearer {
             token: token.to_owned(),
         });
-        let mut res = self
+        let res = self
             .client
             .get(url.clone())
             .set_header(header)
             .send()
             .context("Failed to make request to mozilla userinfo")?;
+        let status = res.status();
         let res_text = res
             .text()
             .context("Failed to interpret response from mozilla userinfo as string")?;
-        if !res.status().is_success() {
-            bail!(
-                "JWT forwarded to {} returned {}: {}",
-                url,
-                res.status(),
-                res_text
-            )
+        if !status.is_success() {
+            bail!("JWT forwarded to {} returned {}: {}", url, status, res_text)
         }
 
         // The API didn't return a HTTP error code, let's check the response
@@ -241,7 +237,7 @@ fn test_auth_verify_check_mozilla_profile() {
 // Don't check a token is valid (it may not even be a JWT) just forward it to
 // an API and check for success
 pub struct ProxyTokenCheck {
-    client: reqwest::Client,
+    client: reqwest::blocking::Client,
     maybe_auth_cache: Option<Mutex<(HashMap<String, Instant>, Duration)>>,
     url: String,
 }
@@ -265,7 +261,7 @@ impl ProxyTokenCheck {
         let maybe_auth_cache: Option<Mutex<(HashMap<String, Instant>, Duration)>> =
             cache_secs.map(|secs| Mutex::new((HashMap::new(), Duration::from_secs(secs))));
         Self {
-            client: reqwest::Client::new(),
+            client: reqwest::blocking::Client::new(),
             maybe_auth_cache,
             url,
         }
@@ -330,7 +326,7 @@ impl ClientAuthCheck for ValidJWTCheck {
 
 impl ValidJWTCheck {
     pub fn new(audience: String, issuer: String, jwks_url: &str) -> Result<Self> {
-        let mut res = reqwest::get(jwks_url).context("Failed to make request to JWKs url")?;
+        let res = reqwest::blocking::get(jwks_url).context("Failed to make request to JWKs url")?;
         if !res.status().is_success() {
             bail!("Could not retrieve JWKs, HTTP error: {}", res.status())
         }
@@ -369,3 +365,356 @@ impl ValidJWTCheck {
         Ok(())
     }
 }
+
+// How often an OIDC check may fetch the JWKs again on seeing an unknown kid,
+// which is how it notices key rotation
+const OIDC_JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+// https://openid.net/specs/openid-connect-discovery-1_0.html#ProviderConfig
+const OIDC_DISCOVERY_PATH: &str = ".well-known/openid-configuration";
+
+// Check a JWT issued by an OpenID Connect provider is valid, and that its
+// claims are allowed
+pub struct OidcCheck {
+    audience: String,
+    issuer: String,
+    jwks_url: String,
+    required_claims: HashMap<String, Vec<String>>,
+    client: reqwest::blocking::Client,
+    // kid -> pkcs1, and when they were fetched
+    keys: Mutex<(HashMap<String, Vec<u8>>, Instant)>,
+}
+
+impl ClientAuthCheck for OidcCheck {
+    fn check(&self, token: &str) -> StdResult<(), ClientVisibleMsg> {
+        self.check_token(token).map_err(|e| {
+            warn!("OIDC token validation failed: {}", e);
+            ClientVisibleMsg::from_nonsensitive(
+                "OIDC token could not be validated or is not authorized".to_owned(),
+            )
+        })
+    }
+}
+
+impl OidcCheck {
+    pub fn new(
+        audience: String,
+        issuer: String,
+        jwks_url: Option<String>,
+        required_claims: HashMap<String, Vec<String>>,
+    ) -> Result<Self> {
+        let client = reqwest::blocking::Client::new();
+        let jwks_url = match jwks_url {
+            Some(jwks_url) => jwks_url,
+            None => discover_jwks_url(&client, &issuer)
+                .with_context(|| format!("Failed to discover the JWKs url of {}", issuer))?,
+        };
+        let keys = fetch_jwks(&client, &jwks_url)?;
+        Ok(Self {
+            audience,
+            issuer,
+            jwks_url,
+            required_claims,
+            client,
+            keys: Mutex::new((keys, Instant::now())),
+        })
+    }
+
+    pub fn check_token(&self, token: &str) -> Result<()> {
+        let header = jwt::decode_header(token).context("Could not decode jwt header")?;
+        trace!("Validating OIDC token in scheduler");
+        match header.alg {
+            jwt::Algorithm::RS256
+            | jwt::Algorithm::RS384
+            | jwt::Algorithm::RS512
+            | jwt::Algorithm::PS256
+            | jwt::Algorithm::PS384
+            | jwt::Algorithm::PS512 => (),
+            alg => bail!("Unsupported JWT algorithm {:?}", alg),
+        }
+        let kid = header.kid.context("No kid found")?;
+        let pkcs1 = self.key(&kid)?;
+        let mut validation = jwt::Validation::new(header.alg);
+        validation.set_audience(&[&self.audience]);
+        validation.iss = Some(self.issuer.clone());
+        let tokendata = jwt::decode::<serde_json::Value>(
+            token,
+            &jwt::DecodingKey::from_rsa_der(&pkcs1),
+            &validation,
+        )
+        .context("Unable to validate and decode jwt")?;
+        check_claims(&tokendata.claims, &self.required_claims)
+    }
+
+    fn key(&self, kid: &str) -> Result<Vec<u8>> {
+        let mut keys = self.keys.lock().unwrap();
+        if !keys.0.contains_key(kid) && keys.1.elapsed() > OIDC_JWKS_REFRESH_INTERVAL {
+            debug!("kid {} not known, fetching JWKs again", kid);
+            *keys = (fetch_jwks(&self.client, &self.jwks_url)?, Instant::now());
+        }
+        keys.0.get(kid).cloned().context("kid not found in jwks")
+    }
+}
+
+fn discover_jwks_url(client: &reqwest::blocking::Client, issuer: &str) -> Result<String> {
+    #[derive(Deserialize)]
+    struct ProviderMetadata {
+        jwks_uri: String,
+    }
+    let url = format!("{}/{}", issuer.trim_end_matches('/'), OIDC_DISCOVERY_PATH);
+    let res = client
+        .get(&url)
+        .send()
+        .context("Failed to make request to OIDC discovery url")?;
+    if !res.status().is_success() {
+        bail!(
+            "Could not retrieve OIDC provider metadata, HTTP error: {}",
+            res.status()
+        )
+    }
+    let metadata: ProviderMetadata = res
+        .json()
+        .context("Failed to parse OIDC provider metadata json")?;
+    Ok(metadata.jwks_uri)
+}
+
+// Providers may publish keys other than RSA signing keys, which are skipped
+fn fetch_jwks(
+    client: &reqwest::blocking::Client,
+    jwks_url: &str,
+) -> Result<HashMap<String, Vec<u8>>> {
+    #[derive(Deserialize)]
+    struct AnyJwks {
+        keys: Vec<serde_json::Value>,
+    }
+    let res = client
+        .get(jwks_url)
+        .send()
+        .context("Failed to make request to JWKs url")?;
+    if !res.status().is_success() {
+        bail!("Could not retrieve JWKs, HTTP error: {}", res.status())
+    }
+    let jwks: AnyJwks = res.json().context("Failed to parse JWKs json")?;
+    jwks.keys
+        .into_iter()
+        .filter(|k| k["kty"] == "RSA" && k.get("use").map_or(true, |u| u == "sig"))
+        .map(|k| {
+            let k: Jwk = serde_json::from_value(k).context("Failed to parse JWK")?;
+            k.to_der_pkcs1().map(|pkcs1| (k.kid, pkcs1))
+        })
+        .collect::<Result<_>>()
+        .context("Failed to convert JWKs into pkcs1")
+}
+
+// Each required claim must have one of the allowed values, or for a list of
+// values (like groups) contain one of them
+fn check_claims(
+    claims: &serde_json::Value,
+    required_claims: &HashMap<String, Vec<String>>,
+) -> Result<()> {
+    let sub = claims["sub"].as_str().unwrap_or("<unknown>");
+    for (name, allowed) in required_claims {
+        let is_allowed = |value: &serde_json::Value| match value {
+            serde_json::Value::String(s) => allowed.contains(s),
+            serde_json::Value::Bool(_) | serde_json::Value::Number(_) => {
+                allowed.contains(&value.to_string())
+            }
+            _ => false,
+        };
+        let ok = match claims.get(name) {
+            Some(serde_json::Value::Array(values)) => values.iter().any(is_allowed),
+            Some(value) => is_allowed(value),
+            None => false,
+        };
+        if !ok {
+            bail!(
+                "Claim {} of {} is not one of the allowed values {:?}",
+                name,
+                sub,
+                allowed
+            )
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_auth_verify_check_claims() {
+    let claims = serde_json::json!({
+        "sub": "builder-1",
+        "groups": ["everyone", "sccache-builders"],
+        "azp": "sccache-builder",
+        "email_verified": true
+    });
+    let required = |claims: &[(&str, &[&str])]| -> HashMap<String, Vec<String>> {
+        claims
+            .iter()
+            .map(|(name, values)| {
+                let values = values.iter().map(|v| (*v).to_owned()).collect();
+                ((*name).to_owned(), values)
+            })
+            .collect()
+    };
+
+    assert!(check_claims(&claims, &required(&[])).is_ok());
+    assert!(check_claims(&claims, &required(&[("groups", &["sccache-builders"])])).is_ok());
+    assert!(check_claims(
+        &claims,
+        &required(&[
+            ("azp", &["sccache-client", "sccache-builder"]),
+            ("email_verified", &["true"])
+        ])
+    )
+    .is_ok());
+
+    assert!(check_claims(&claims, &required(&[("groups", &["admins"])])).is_err());
+    assert!(check_claims(
+        &claims,
+        &required(&[
+            ("azp", &["sccache-builder"]),
+            ("email_verified", &["false"])
+        ])
+    )
+    .is_err());
+    assert!(check_claims(&claims, &required(&[("hd", &["example.com"])])).is_err());
+    assert!(check_claims(&claims, &required(&[("sub", &["builder-2"])])).is_err());
+}
+
+// Where Kubernetes mounts the service account token and the cluster's CA
+// certificate in pods
+pub const KUBERNETES_TOKEN_PATH: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
+const KUBERNETES_CA_PATH: &str = "/var/run/secrets/kubernetes.io/serviceaccount/ca.crt";
+// Servers send a token with every heartbeat and job state update, so reviews
+// are cached rather than asking the API server each time
+const KUBERNETES_REVIEW_CACHE_TIME: Duration = Duration::from_secs(60);
+
+// Check a Kubernetes service account token with a TokenReview, from within
+// the cluster
+// https://kubernetes.io/docs/reference/access-authn-authz/authentication/#webhook-token-authentication
+pub struct KubernetesCheck {
+    service_accounts: Vec<String>,
+    client: reqwest::blocking::Client,
+    review_url: String,
+    // token -> when it was reviewed
+    auth_cache: Mutex<HashMap<String, Instant>>,
+}
+
+impl KubernetesCheck {
+    // The scheduler's own service account needs the
+    // `system:auth-delegator` cluster role to make reviews
+    pub fn new(service_accounts: Vec<String>) -> Result<Self> {
+        let host = std::env::var("KUBERNETES_SERVICE_HOST")
+            .context("KUBERNETES_SERVICE_HOST is not set, is the scheduler running in a pod?")?;
+        let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_owned());
+        let ca_pem = std::fs::read(KUBERNETES_CA_PATH)
+            .with_context(|| format!("Failed to read {}", KUBERNETES_CA_PATH))?;
+        let client = reqwest::blocking::Client::builder()
+            .add_root_certificate(
+                reqwest::Certificate::from_pem(&ca_pem)
+                    .context("Failed to parse the cluster CA certificate")?,
+            )
+            .build()
+            .context("Failed to create a HTTP client for the API server")?;
+        Ok(Self {
+            service_accounts,
+            client,
+            review_url: format!(
+                "https://{}:{}/apis/authentication.k8s.io/v1/tokenreviews",
+                host, port
+            ),
+            auth_cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn check_token(&self, token: &str) -> Result<()> {
+        {
+            let mut auth_cache = self.auth_cache.lock().unwrap();
+            auth_cache
+                .retain(|_, reviewed_at| reviewed_at.elapsed() < KUBERNETES_REVIEW_CACHE_TIME);
+            if auth_cache.contains_key(token) {
+                return Ok(());
+            }
+        }
+        trace!("Reviewing service account token with {}", self.review_url);
+        // Service account tokens are rotated, so read it for each review
+        let own_token = std::fs::read_to_string(KUBERNETES_TOKEN_PATH)
+            .with_context(|| format!("Failed to read {}", KUBERNETES_TOKEN_PATH))?;
+        let review = serde_json::json!({
+            "apiVersion": "authentication.k8s.io/v1",
+            "kind": "TokenReview",
+            "spec": { "token": token },
+        });
+        let res = self
+            .client
+            .post(&self.review_url)
+            .bearer_auth(own_token.trim())
+            .json(&review)
+            .send()
+            .context("Failed to make request to the API server")?;
+        if !res.status().is_success() {
+            bail!("TokenReview failed, HTTP error: {}", res.status())
+        }
+        let review: serde_json::Value = res.json().context("Failed to parse TokenReview json")?;
+        check_token_review(&review, &self.service_accounts)?;
+        self.auth_cache
+            .lock()
+            .unwrap()
+            .insert(token.to_owned(), Instant::now());
+        Ok(())
+    }
+}
+
+// The token must be authenticated, and for one of the allowed service accounts
+fn check_token_review(review: &serde_json::Value, service_accounts: &[String]) -> Result<()> {
+    let status = &review["status"];
+    if status["authenticated"] != true {
+        match status["error"].as_str() {
+            Some(error) => bail!("Token not authenticated: {}", error),
+            None => bail!("Token not authenticated"),
+        }
+    }
+    let username = status["user"]["username"]
+        .as_str()
+        .context("No username in TokenReview")?;
+    match username.strip_prefix("system:serviceaccount:") {
+        Some(account) if service_accounts.iter().any(|a| a == account) => Ok(()),
+        Some(account) => bail!("Service account {} is not allowed", account),
+        None => bail!("{} is not a service account", username),
+    }
+}
+
+#[test]
+fn test_auth_verify_check_token_review() {
+    let accounts = vec!["sccache:build-server".to_owned()];
+    let review = |status| serde_json::json!({ "kind": "TokenReview", "status": status });
+
+    assert!(check_token_review(
+        &review(serde_json::json!({
+            "authenticated": true,
+            "user": { "username": "system:serviceaccount:sccache:build-server" }
+        })),
+        &accounts
+    )
+    .is_ok());
+
+    assert!(check_token_review(
+        &review(serde_json::json!({
+            "authenticated": true,
+            "user": { "username": "system:serviceaccount:default:default" }
+        })),
+        &accounts
+    )
+    .is_err());
+    assert!(check_token_review(
+        &review(serde_json::json!({
+            "authenticated": true,
+            "user": { "username": "sccache:build-server" }
+        })),
+        &accounts
+    )
+    .is_err());
+    assert!(check_token_review(
+        &review(serde_json::json!({ "error": "token has expired" })),
+        &accounts
+    )
+    .is_err());
+}
@@ -0,0 +1,306 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Recording which machine, user and CI job wrote the entries of the
+//! storage: in the entries themselves if `SCCACHE_PROVENANCE=1`, and in an
+//! append-only audit log in the cache dir if `SCCACHE_AUDIT_LOG=1`, which
+//! `sccache --audit-log` shows.
+
+use crate::cache::{Cache, CacheWrite, ClearScope, Storage};
+use crate::config::Config;
+use async_trait::async_trait;
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::errors::*;
+
+/// Where and by whom cache entries are written.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Provenance {
+    pub host: Option<String>,
+    pub user: Option<String>,
+    /// The URL of the CI job the server runs in, if it runs in one.
+    pub ci_job: Option<String>,
+    /// The version of sccache.
+    pub version: String,
+}
+
+impl Provenance {
+    /// That of the entries this server writes.
+    pub fn current() -> Provenance {
+        Provenance {
+            host: hostname(),
+            user: var("USER").or_else(|| var("USERNAME")),
+            ci_job: ci_job_url(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+        }
+    }
+}
+
+/// The variable `name`, unless it's unset or empty.
+fn var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let mut name = [0u8; 256];
+    if unsafe { libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len()) } != 0 {
+        return None;
+    }
+    let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    Some(String::from_utf8_lossy(&name[..len]).into_owned())
+}
+
+#[cfg(windows)]
+fn hostname() -> Option<String> {
+    env::var("COMPUTERNAME").ok()
+}
+
+/// The URL of the CI job, from `SCCACHE_CI_JOB_URL` or the variables of the
+/// common CI services.
+fn ci_job_url() -> Option<String> {
+    let github = || {
+        Some(format!(
+            "{}/{}/actions/runs/{}",
+            var("GITHUB_SERVER_URL")?,
+            var("GITHUB_REPOSITORY")?,
+            var("GITHUB_RUN_ID")?
+        ))
+    };
+    var("SCCACHE_CI_JOB_URL")
+        .or_else(github)
+        // GitLab
+        .or_else(|| var("CI_JOB_URL"))
+        .or_else(|| var("BUILDKITE_BUILD_URL"))
+        .or_else(|| var("CIRCLE_BUILD_URL"))
+        // Jenkins
+        .or_else(|| var("BUILD_URL"))
+}
+
+/// A write of an entry, as it's recorded in the audit log.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AuditRecord {
+    /// When the entry was written, in seconds since the Unix epoch.
+    pub time: u64,
+    pub key: String,
+    /// The location of the storage it was written to.
+    pub storage: String,
+    #[serde(flatten)]
+    pub provenance: Provenance,
+}
+
+/// The audit log of the cache configured in `config`.
+pub fn audit_log_path(config: &Config) -> PathBuf {
+    config.fallback_cache.dir.join("audit.log")
+}
+
+/// The records of the audit log at `path`, of the writes of `key` if it's
+/// given, oldest first.
+pub fn read_audit_log(path: &Path, key: Option<&str>) -> Result<Vec<AuditRecord>> {
+    let file = File::open(path)
+        .with_context(|| format!("failed to open the audit log {}", path.display()))?;
+    let mut records = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        match serde_json::from_str::<AuditRecord>(&line) {
+            Ok(record) if key.map_or(true, |key| record.key == key) => records.push(record),
+            Ok(_) => {}
+            // The server was killed while writing it
+            Err(e) => warn!("Ignoring a bad audit log record {:?}: {}", line, e),
+        }
+    }
+    Ok(records)
+}
+
+/// A `Storage` that records the provenance of the entries written to the
+/// storage it wraps, in them, in an audit log or both.
+pub struct AuditedStorage {
+    inner: Arc<dyn Storage>,
+    provenance: Provenance,
+    /// Whether the provenance is put in the entries.
+    embed: bool,
+    /// The audit log, opened to append to.
+    log: Option<Arc<File>>,
+}
+
+impl AuditedStorage {
+    pub fn new(
+        inner: Arc<dyn Storage>,
+        provenance: Provenance,
+        embed: bool,
+        log: Option<File>,
+    ) -> AuditedStorage {
+        AuditedStorage {
+            inner,
+            provenance,
+            embed,
+            log: log.map(Arc::new),
+        }
+    }
+
+    /// Record the provenance of the entries written to `inner` in them if
+    /// `SCCACHE_PROVENANCE` is `1`, and in the audit log of the cache
+    /// configured in `config` if `SCCACHE_AUDIT_LOG` is `1`.
+    pub fn from_env(inner: Arc<dyn Storage>, config: &Config) -> Arc<dyn Storage> {
+        let enabled = |name| env::var(name).map_or(false, |value| value == "1");
+        let embed = enabled("SCCACHE_PROVENANCE");
+        let log = if enabled("SCCACHE_AUDIT_LOG") {
+            let path = audit_log_path(config);
+            let log = path
+                .parent()
+                .map_or(Ok(()), std::fs::create_dir_all)
+                .and_then(|()| OpenOptions::new().create(true).append(true).open(&path));
+            match log {
+                Ok(log) => Some(log),
+                Err(e) => {
+                    warn!("Failed to open the audit log {}: {}", path.display(), e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        if !embed && log.is_none() {
+            return inner;
+        }
+        Arc::new(AuditedStorage::new(
+            inner,
+            Provenance::current(),
+            embed,
+            log,
+        ))
+    }
+}
+
+#[async_trait(?Send)]
+impl Storage for AuditedStorage {
+    async fn get(&self, key: &str) -> Result<Cache> {
+        self.inner.get(key).await
+    }
+
+    async fn put(&self, key: &str, mut entry: CacheWrite) -> Result<Duration> {
+        if self.embed {
+            entry.put_provenance(&self.provenance)?;
+        }
+        let log = match self.log {
+            Some(ref log) => log.clone(),
+            None => return self.inner.put(key, entry).await,
+        };
+        let mut record = AuditRecord {
+            time: 0,
+            key: key.to_owned(),
+            storage: self.inner.location(),
+            provenance: self.provenance.clone(),
+        };
+        let duration = self.inner.put(key, entry).await?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH);
+        record.time = now.unwrap_or_default().as_secs();
+        // One write per record, so that those of servers sharing the log
+        // aren't interleaved
+        let mut line = serde_json::to_vec(&record).expect("audit record serializes");
+        line.push(b'\n');
+        if let Err(e) = (&*log).write_all(&line) {
+            warn!("Failed to write to the audit log: {}", e);
+        }
+        Ok(duration)
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.inner.delete(key).await
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+
+    async fn clear(&self, scope: ClearScope) -> Result<()> {
+        self.inner.clear(scope).await
+    }
+
+    fn local(&self) -> Option<Arc<dyn Storage>> {
+        self.inner.local()
+    }
+
+    fn location(&self) -> String {
+        self.inner.location()
+    }
+
+    fn kind(&self) -> &'static str {
+        self.inner.kind()
+    }
+
+    async fn current_size(&self) -> Result<Option<u64>> {
+        self.inner.current_size().await
+    }
+
+    async fn max_size(&self) -> Result<Option<u64>> {
+        self.inner.max_size().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::disk::DiskCache;
+    use crate::test::utils::ThreadPoolExt;
+    use futures::executor::{block_on, ThreadPool};
+
+    #[test]
+    fn test_audited_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = ThreadPool::sized(1);
+        let inner = Arc::new(DiskCache::new(&dir.path().join("cache"), 1 << 20, &pool));
+        let path = dir.path().join("audit.log");
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap();
+        let provenance = Provenance {
+            host: Some("builder".to_owned()),
+            user: Some("ci".to_owned()),
+            ci_job: Some("https://ci.example.com/jobs/1".to_owned()),
+            version: "0.2.15".to_owned(),
+        };
+        let storage = AuditedStorage::new(inner, provenance.clone(), true, Some(log));
+        let mut entry = CacheWrite::new();
+        entry.put_stdout(b"out").unwrap();
+        block_on(storage.put("aaaa", entry)).unwrap();
+        block_on(storage.put("bbbb", CacheWrite::new())).unwrap();
+
+        match block_on(storage.get("aaaa")).unwrap() {
+            Cache::Hit(mut entry) => {
+                let mut embedded = vec![];
+                entry.get_object("provenance", &mut embedded).unwrap();
+                let embedded: Provenance = serde_json::from_slice(&embedded).unwrap();
+                assert_eq!(embedded, provenance);
+                assert_eq!(entry.get_stdout(), b"out");
+            }
+            cache => panic!("Unexpected {:?}", cache),
+        }
+
+        let records = read_audit_log(&path, None).unwrap();
+        let keys: Vec<_> = records.iter().map(|r| r.key.as_str()).collect();
+        assert_eq!(keys, ["aaaa", "bbbb"]);
+        assert_eq!(records[0].provenance, provenance);
+        assert_eq!(records[0].storage, storage.location());
+        let records = read_audit_log(&path, Some("bbbb")).unwrap();
+        assert_eq!(records.len(), 1);
+    }
+}
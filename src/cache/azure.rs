@@ -16,7 +16,7 @@
 use crate::azure::BlobContainer;
 use crate::azure::*;
 use crate::cache::{Cache, CacheRead, CacheWrite, Storage};
-use futures::future::Future;
+use async_trait::async_trait;
 use std::io;
 use std::rc::Rc;
 use std::time::{Duration, Instant};
@@ -50,47 +50,52 @@ impl AzureBlobCache {
     }
 }
 
+#[async_trait(?Send)]
 impl Storage for AzureBlobCache {
-    fn get(&self, key: &str) -> SFuture<Cache> {
-        Box::new(
-            self.container
-                .get(key, &self.credentials)
-                .then(|result| match result {
-                    Ok(data) => {
-                        let hit = CacheRead::from(io::Cursor::new(data))?;
-                        Ok(Cache::Hit(hit))
-                    }
-                    Err(e) => {
-                        warn!("Got Azure error: {:?}", e);
-                        Ok(Cache::Miss)
-                    }
-                }),
-        )
+    async fn get(&self, key: &str) -> Result<Cache> {
+        match self.container.get(key, &self.credentials).await {
+            Ok(data) => {
+                let hit = CacheRead::from(io::Cursor::new(data))?;
+                Ok(Cache::Hit(hit))
+            }
+            Err(e) => {
+                warn!("Got Azure error: {:?}", e);
+                Ok(Cache::Miss)
+            }
+        }
     }
 
-    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+    async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration> {
         let start = Instant::now();
-        let data = match entry.finish() {
-            Ok(data) => data,
-            Err(e) => return f_err(e),
-        };
+        let data = entry.finish()?;
 
-        let response = self
-            .container
+        self.container
             .put(key, data, &self.credentials)
-            .fcontext("Failed to put cache entry in Azure");
+            .await
+            .context("Failed to put cache entry in Azure")?;
+
+        Ok(start.elapsed())
+    }
 
-        Box::new(response.map(move |_| start.elapsed()))
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.container
+            .delete(key, &self.credentials)
+            .await
+            .context("Failed to delete cache entry from Azure")
     }
 
     fn location(&self) -> String {
         format!("Azure, container: {}", self.container)
     }
 
-    fn current_size(&self) -> SFuture<Option<u64>> {
-        f_ok(None)
+    fn kind(&self) -> &'static str {
+        "azure"
+    }
+
+    async fn current_size(&self) -> Result<Option<u64>> {
+        Ok(None)
     }
-    fn max_size(&self) -> SFuture<Option<u64>> {
-        f_ok(None)
+    async fn max_size(&self) -> Result<Option<u64>> {
+        Ok(None)
     }
 }
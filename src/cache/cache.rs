@@ -12,31 +12,38 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::cache::audit::Provenance;
 #[cfg(feature = "azure")]
 use crate::cache::azure::AzureBlobCache;
 use crate::cache::disk::DiskCache;
+use crate::cache::encrypted::{EncryptedStorage, KeyRing};
 #[cfg(feature = "gcs")]
 use crate::cache::gcs::{self, GCSCache, GCSCredentialProvider, RWMode, ServiceAccountInfo};
+use crate::cache::layered::LayeredStorage;
 #[cfg(feature = "memcached")]
 use crate::cache::memcached::MemcachedCache;
 #[cfg(feature = "redis")]
 use crate::cache::redis::RedisCache;
 #[cfg(feature = "s3")]
 use crate::cache::s3::S3Cache;
-use crate::config::{self, CacheType, Config};
-use crate::util::SpawnExt;
-use futures_03::executor::ThreadPool;
+use crate::cache::spill::SpillBuffer;
+use crate::config::{self, CacheBackend, CacheRole, CacheType, Config};
+use crate::util::{self, SpawnExt};
+use async_trait::async_trait;
+use futures::executor::ThreadPool;
+use futures::{future, Future};
+use std::borrow::Cow;
+use std::env;
 use std::fmt;
 use std::fs;
 #[cfg(feature = "gcs")]
 use std::fs::File;
-use std::io::{self, Cursor, Read, Seek, Write};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 use tempfile::NamedTempFile;
-use zip::write::FileOptions;
-use zip::{CompressionMethod, ZipArchive, ZipWriter};
+use zip::{CompressionMethod, ZipArchive};
 
 use crate::errors::*;
 
@@ -46,9 +53,12 @@ fn get_file_mode(file: &fs::File) -> Result<Option<u32>> {
     Ok(Some(file.metadata()?.mode()))
 }
 
+/// Windows only has the read-only attribute, which is kept as a mode
+/// without write permissions.
 #[cfg(windows)]
-fn get_file_mode(_file: &fs::File) -> Result<Option<u32>> {
-    Ok(None)
+fn get_file_mode(file: &fs::File) -> Result<Option<u32>> {
+    let readonly = file.metadata()?.permissions().readonly();
+    Ok(Some(if readonly { 0o100444 } else { 0o100644 }))
 }
 
 #[cfg(unix)]
@@ -61,10 +71,24 @@ fn set_file_mode(path: &Path, mode: u32) -> Result<()> {
 }
 
 #[cfg(windows)]
-fn set_file_mode(_path: &Path, _mode: u32) -> Result<()> {
+fn set_file_mode(path: &Path, mode: u32) -> Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o222 == 0);
+    fs::set_permissions(path, permissions)?;
     Ok(())
 }
 
+#[cfg(unix)]
+fn symlink(target: &str, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+/// Creating symlinks needs a privilege or developer mode on Windows.
+#[cfg(windows)]
+fn symlink(target: &str, path: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(target, path)
+}
+
 /// Result of a cache lookup.
 pub enum Cache {
     /// Result was found in cache.
@@ -90,9 +114,66 @@ pub trait ReadSeek: Read + Seek + Send {}
 
 impl<T: Read + Seek + Send> ReadSeek for T {}
 
+/// Entries start with this, then the version of their format. Those that
+/// start like a zip archive instead are in the format before it.
+pub const ENTRY_MAGIC: &[u8; 7] = b"sccache";
+
+/// The version of the format entries are written in: a tar archive of
+/// their objects, each compressed on its own so that they can be
+/// compressed in parallel, read without the others and copied as they're
+/// stored.
+const ENTRY_VERSION: u8 = 1;
+
+/// The version of entries with symlinks among their objects, which those
+/// of `ENTRY_VERSION` can't have. Entries without them are still written as
+/// `ENTRY_VERSION`, so that older versions of sccache can read them.
+const SYMLINK_ENTRY_VERSION: u8 = 2;
+
+/// The version the format entries were in before `ENTRY_VERSION`, a zip
+/// archive, is reported as.
+pub const ZIP_ENTRY_VERSION: u8 = 0;
+
+/// The version of entries encrypted as a whole, as `EncryptedStorage`
+/// writes them, which can't be read without the key they're encrypted with.
+pub const ENCRYPTED_ENTRY_VERSION: u8 = 0x80;
+
+/// The name of the object recording the version of the hashing the key of
+/// its entry was computed with, as `compiler::cache_schema` names it.
+/// Entries written before it was recorded, or not for compiles, don't have
+/// it.
+const SCHEMA: &str = "schema";
+
+/// The magic number zstd frames start with. Objects stored uncompressed
+/// are those that don't start with it.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+lazy_static! {
+    /// Whether objects are compressed in cache entries, unless
+    /// `SCCACHE_COMPRESS_OBJECTS=0`.
+    static ref COMPRESS_OBJECTS: bool =
+        env::var("SCCACHE_COMPRESS_OBJECTS").map_or(true, |value| value != "0");
+}
+
+/// An object of a cache entry, as it's stored in it.
+struct Object {
+    name: String,
+    /// Where the object starts in the entry.
+    start: u64,
+    len: u64,
+    mode: Option<u32>,
+    /// The target, if the object is a symlink.
+    link: Option<String>,
+}
+
 /// Data stored in the compiler cache.
 pub struct CacheRead {
-    zip: ZipArchive<Box<dyn ReadSeek>>,
+    reader: Box<dyn ReadSeek>,
+    objects: Vec<Object>,
+    /// The version of the format the entry is in.
+    format: u8,
+    /// The file the entry is read from, if it's in one, to copy objects
+    /// stored uncompressed out of.
+    file: Option<fs::File>,
 }
 
 /// Represents a failure to decompress stored object data.
@@ -107,15 +188,185 @@ impl std::fmt::Display for DecompressionFailure {
 
 impl std::error::Error for DecompressionFailure {}
 
+/// The objects of an entry in the zip format, which are zstd-compressed
+/// blobs declared as stored, unless they're stored uncompressed.
+fn zip_objects<R: Read + Seek>(reader: R) -> Result<Vec<Object>> {
+    let mut zip = ZipArchive::new(reader)?;
+    (0..zip.len())
+        .map(|i| {
+            let file = zip.by_index(i)?;
+            if file.compression() != CompressionMethod::Stored {
+                bail!(DecompressionFailure);
+            }
+            Ok(Object {
+                name: file.name().to_owned(),
+                start: file.data_start(),
+                len: file.compressed_size(),
+                mode: file.unix_mode(),
+                link: None,
+            })
+        })
+        .collect()
+}
+
+/// Read the `len` bytes of a GNU long name or link target.
+fn read_long_name<R: Read>(reader: &mut R, len: u64) -> Result<Vec<u8>> {
+    let mut name = vec![];
+    reader.take(len).read_to_end(&mut name)?;
+    while name.last() == Some(&0) {
+        name.pop();
+    }
+    Ok(name)
+}
+
+/// The objects of an entry in the tar format, whose archive starts where
+/// `reader` is, found by reading their headers alone.
+fn tar_objects<R: Read + Seek>(reader: &mut R) -> Result<Vec<Object>> {
+    let mut objects = vec![];
+    let mut long_name = None;
+    let mut long_link = None;
+    loop {
+        let mut block = [0; 512];
+        reader.read_exact(&mut block)?;
+        // The archive ends with blocks of zeros
+        if block.iter().all(|&b| b == 0) {
+            return Ok(objects);
+        }
+        // The checksum is of the header with its own field as spaces
+        let cksum: u32 = block[..148]
+            .iter()
+            .chain(&[b' '; 8])
+            .chain(&block[156..])
+            .map(|&b| u32::from(b))
+            .sum();
+        let header = tar::Header::from_byte_slice(&block);
+        if header.cksum()? != cksum {
+            bail!("Bad checksum of object header");
+        }
+        let len = header.entry_size()?;
+        let start = reader.seek(SeekFrom::Current(0))?;
+        match header.entry_type() {
+            // The name of the next object, if it's too long for its header
+            tar::EntryType::GNULongName => long_name = Some(read_long_name(reader, len)?),
+            // The target of the next symlink, if it's too long for its header
+            tar::EntryType::GNULongLink => long_link = Some(read_long_name(reader, len)?),
+            entry_type @ tar::EntryType::Regular | entry_type @ tar::EntryType::Symlink => {
+                let name = long_name
+                    .take()
+                    .unwrap_or_else(|| header.path_bytes().into_owned());
+                let link = if entry_type == tar::EntryType::Symlink {
+                    let link = long_link.take().unwrap_or_else(|| {
+                        header
+                            .link_name_bytes()
+                            .map_or_else(Vec::new, Cow::into_owned)
+                    });
+                    Some(String::from_utf8(link)?)
+                } else {
+                    None
+                };
+                objects.push(Object {
+                    name: String::from_utf8(name)?,
+                    start,
+                    len,
+                    // A mode of 0 is none stored
+                    mode: Some(header.mode()?).filter(|&mode| mode != 0),
+                    link,
+                });
+            }
+            other => bail!("Unexpected {:?} in cache entry", other),
+        }
+        // Objects are padded to whole blocks
+        reader.seek(SeekFrom::Start(start + (len + 511) / 512 * 512))?;
+    }
+}
+
+/// The objects of an entry, in the format it starts with.
+fn entry_objects<R: Read + Seek>(reader: &mut R) -> Result<(u8, Vec<Object>)> {
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if magic.starts_with(b"PK") {
+        reader.seek(SeekFrom::Start(0))?;
+        return Ok((ZIP_ENTRY_VERSION, zip_objects(reader)?));
+    }
+    if !magic.starts_with(ENTRY_MAGIC) {
+        bail!("Unknown format");
+    }
+    match magic[ENTRY_MAGIC.len()] {
+        version @ ENTRY_VERSION | version @ SYMLINK_ENTRY_VERSION => {
+            Ok((version, tar_objects(reader)?))
+        }
+        // Their objects are only known once they're decrypted
+        ENCRYPTED_ENTRY_VERSION => Ok((ENCRYPTED_ENTRY_VERSION, vec![])),
+        version => bail!("Unsupported format version {}", version),
+    }
+}
+
+/// Whether `bytes` are an entry in a version of the format newer than
+/// those this version of sccache reads.
+pub fn is_newer_format(bytes: &[u8]) -> bool {
+    bytes.starts_with(ENTRY_MAGIC)
+        && bytes.get(ENTRY_MAGIC.len()).map_or(false, |&version| {
+            version > SYMLINK_ENTRY_VERSION && version != ENCRYPTED_ENTRY_VERSION
+        })
+}
+
+/// Whether `bytes` are an entry encrypted as a whole.
+pub fn is_encrypted(bytes: &[u8]) -> bool {
+    bytes.starts_with(ENTRY_MAGIC) && bytes.get(ENTRY_MAGIC.len()) == Some(&ENCRYPTED_ENTRY_VERSION)
+}
+
 impl CacheRead {
-    /// Create a cache entry from `reader`.
+    /// Create a cache entry from `reader`, in either format.
     pub fn from<R>(reader: R) -> Result<CacheRead>
     where
         R: ReadSeek + 'static,
     {
-        let z = ZipArchive::new(Box::new(reader) as Box<dyn ReadSeek>)
-            .context("Failed to parse cache entry")?;
-        Ok(CacheRead { zip: z })
+        let mut reader = Box::new(reader) as Box<dyn ReadSeek>;
+        let (format, objects) =
+            entry_objects(&mut reader).context("Failed to parse cache entry")?;
+        Ok(CacheRead {
+            reader,
+            objects,
+            format,
+            file: None,
+        })
+    }
+
+    /// Create a cache entry from the file it's stored in.
+    pub fn from_file(file: fs::File) -> Result<CacheRead> {
+        let copy = file.try_clone()?;
+        let mut entry = CacheRead::from(file)?;
+        entry.file = Some(copy);
+        Ok(entry)
+    }
+
+    /// Where the object at `name` is in the entry, and its permissions.
+    fn object(&self, name: &str) -> Result<(u64, u64, Option<u32>)> {
+        let object = self.find(name)?;
+        Ok((object.start, object.len, object.mode))
+    }
+
+    fn find(&self, name: &str) -> Result<&Object> {
+        if self.format == ENCRYPTED_ENTRY_VERSION {
+            bail!("The cache entry is encrypted, and no key to decrypt it is configured");
+        }
+        Ok(self
+            .objects
+            .iter()
+            .find(|object| object.name == name)
+            .ok_or(DecompressionFailure)?)
+    }
+
+    /// Read the object at `name` as it's stored, with its permissions.
+    fn read_object(&mut self, name: &str) -> Result<(Vec<u8>, Option<u32>)> {
+        let (start, len, mode) = self.object(name)?;
+        let mut bytes = vec![];
+        self.reader.seek(SeekFrom::Start(start))?;
+        (&mut self.reader).take(len).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 != len {
+            bail!(DecompressionFailure);
+        }
+        Ok((bytes, mode))
     }
 
     /// Get an object from this cache entry at `name` and write it to `to`.
@@ -124,15 +375,55 @@ impl CacheRead {
     where
         T: Write,
     {
-        let file = self.zip.by_name(name).or(Err(DecompressionFailure))?;
-        if file.compression() != CompressionMethod::Stored {
-            bail!(DecompressionFailure);
+        let (start, len, mode) = self.object(name)?;
+        self.reader.seek(SeekFrom::Start(start))?;
+        let mut from = (&mut self.reader).take(len);
+        let mut head = vec![];
+        (&mut from).take(4).read_to_end(&mut head)?;
+        let compressed = head == ZSTD_MAGIC;
+        let mut from = Cursor::new(head).chain(from);
+        if compressed {
+            zstd::stream::copy_decode(from, to).or(Err(DecompressionFailure))?;
+        } else {
+            io::copy(&mut from, to)?;
         }
-        let mode = file.unix_mode();
-        zstd::stream::copy_decode(file, to).or(Err(DecompressionFailure))?;
         Ok(mode)
     }
 
+    /// Get an object from this cache entry at `name` and write it to the
+    /// file `to`, having the kernel copy it if it's stored uncompressed in
+    /// the file the entry is read from. If the file has stored permissions,
+    /// return them.
+    fn extract_object(&mut self, name: &str, to: &mut fs::File) -> Result<Option<u32>> {
+        let stored = match self.file {
+            Some(_) => self.stored_range(name)?,
+            None => None,
+        };
+        if let (Some(source), Some((start, len, mode))) = (&self.file, stored) {
+            util::copy_file_range(source, start, len, to)?;
+            return Ok(mode);
+        }
+        self.get_object(name, to)
+    }
+
+    /// Where the object at `name` is in the entry, and its permissions, if
+    /// it's stored uncompressed.
+    fn stored_range(&mut self, name: &str) -> Result<Option<(u64, u64, Option<u32>)>> {
+        let (start, len, mode) = self.object(name)?;
+        let mut head = vec![];
+        self.reader.seek(SeekFrom::Start(start))?;
+        (&mut self.reader).take(len.min(4)).read_to_end(&mut head)?;
+        if head == ZSTD_MAGIC {
+            return Ok(None);
+        }
+        Ok(Some((start, len, mode)))
+    }
+
+    /// The size of this cache entry's objects as they're stored.
+    pub fn size(&self) -> u64 {
+        self.objects.iter().map(|object| object.len).sum()
+    }
+
     /// Get the stdout from this cache entry, if it exists.
     pub fn get_stdout(&mut self) -> Vec<u8> {
         self.get_bytes("stdout")
@@ -143,18 +434,73 @@ impl CacheRead {
         self.get_bytes("stderr")
     }
 
+    /// The version of the hashing the key of this entry was computed with,
+    /// if it's recorded.
+    pub fn schema(&mut self) -> Option<String> {
+        let schema = self.get_bytes(SCHEMA);
+        if schema.is_empty() {
+            return None;
+        }
+        Some(String::from_utf8_lossy(&schema).into_owned())
+    }
+
+    /// The version of the format this entry is in.
+    pub fn format(&self) -> u8 {
+        self.format
+    }
+
+    /// The versions of the hashing and format of this entry, as `sccache
+    /// --show-stats` counts cache hits by.
+    pub fn version(&mut self) -> String {
+        if self.format == ENCRYPTED_ENTRY_VERSION {
+            return "encrypted".to_owned();
+        }
+        let schema = self.schema();
+        format!(
+            "{}, format {}",
+            schema.as_deref().unwrap_or("unversioned"),
+            self.format
+        )
+    }
+
     fn get_bytes(&mut self, name: &str) -> Vec<u8> {
         let mut bytes = Vec::new();
         drop(self.get_object(name, &mut bytes));
         bytes
     }
 
-    pub fn extract_objects<T>(mut self, objects: T, pool: &ThreadPool) -> SFuture<()>
+    /// Get this cache entry as it's stored, to store it elsewhere.
+    pub fn into_bytes(self) -> Result<Vec<u8>> {
+        let mut reader = self.reader;
+        let mut bytes = vec![];
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub fn extract_objects<T>(
+        mut self,
+        objects: T,
+        pool: &ThreadPool,
+    ) -> impl Future<Output = Result<()>>
     where
         T: IntoIterator<Item = (String, PathBuf)> + Send + Sync + 'static,
     {
-        Box::new(pool.spawn_fn(move || {
+        pool.spawn_fn(move || {
             for (key, path) in objects {
+                let path = util::long_path(&path).into_owned();
+                if let Some(target) = self.find(&key)?.link.clone() {
+                    // Replace what's there, as the compiler would
+                    match fs::remove_file(&path) {
+                        Err(ref e) if e.kind() != io::ErrorKind::NotFound => {
+                            bail!("Failed to replace {:?}: {}", path, e)
+                        }
+                        _ => {}
+                    }
+                    symlink(&target, &path)
+                        .with_context(|| format!("Failed to create symlink {:?}", path))?;
+                    continue;
+                }
                 let dir = match path.parent() {
                     Some(d) => d,
                     None => bail!("Output file without a parent directory!"),
@@ -163,46 +509,253 @@ impl CacheRead {
                 // move it to its final location so that other rustc invocations
                 // happening in parallel don't see a partially-written file.
                 let mut tmp = NamedTempFile::new_in(dir)?;
-                let mode = self.get_object(&key, &mut tmp)?;
+                let mode = self.extract_object(&key, tmp.as_file_mut())?;
                 tmp.persist(&path)?;
                 if let Some(mode) = mode {
                     set_file_mode(&path, mode)?;
                 }
             }
             Ok(())
-        }))
+        })
+    }
+}
+
+/// Write an object read from `from` to a buffer as it's stored in entries,
+/// returning the buffer, rewound, and its length. The buffer only stays in
+/// memory within `SCCACHE_ENTRY_MEMORY_LIMIT`, as the entry it's added to.
+fn spool_object<R: Read>(from: &mut R) -> Result<(SpillBuffer, u64)> {
+    let mut spool = SpillBuffer::new();
+    encode_object(from, &mut spool)?;
+    let len = spool.seek(SeekFrom::Current(0))?;
+    spool.seek(SeekFrom::Start(0))?;
+    Ok((spool, len))
+}
+
+/// Write an object read from `from` to `to` as it's stored in entries.
+fn encode_object<R, W>(from: &mut R, to: &mut W) -> Result<()>
+where
+    R: Read,
+    W: Write,
+{
+    // Objects are stored as zstd-compressed blobs, unless they're stored
+    // uncompressed. Those that start like a zstd frame are compressed
+    // anyway, to tell them apart.
+    let mut head = vec![];
+    (&mut *from).take(4).read_to_end(&mut head)?;
+    let compress = *COMPRESS_OBJECTS || head == ZSTD_MAGIC;
+    let mut from = Cursor::new(head).chain(from);
+    if compress {
+        zstd::stream::copy_encode(from, to, 3)?;
+    } else {
+        io::copy(&mut from, to)?;
     }
+    Ok(())
+}
+
+/// Add the entry of `header` to `tar` at `name`, with the `data` read from
+/// `data`. Names are written as they are rather than as paths, which tar
+/// would refuse if they're absolute or have `..` in them, and go in a GNU
+/// long name entry before the header if they don't fit in it.
+fn append_named<W, R>(
+    tar: &mut tar::Builder<W>,
+    mut header: tar::Header,
+    name: &str,
+    data: R,
+) -> Result<()>
+where
+    W: Write,
+    R: Read,
+{
+    if name.is_empty() || name.contains('\0') {
+        bail!("Invalid cache entry object name {:?}", name);
+    }
+    let field = &mut header.as_old_mut().name;
+    if name.len() > field.len() {
+        let mut long = tar::Header::new_gnu();
+        long.as_old_mut().name[..13].copy_from_slice(b"././@LongLink");
+        long.set_entry_type(tar::EntryType::GNULongName);
+        long.set_size(name.len() as u64 + 1);
+        long.set_cksum();
+        tar.append(&long, &[name.as_bytes(), b"\0"].concat()[..])?;
+    }
+    let len = name.len().min(field.len());
+    field[..len].copy_from_slice(&name.as_bytes()[..len]);
+    header.set_cksum();
+    tar.append(&header, data)?;
+    Ok(())
+}
+
+/// An object read for an entry, as it's stored in it.
+enum Encoded {
+    /// A file, spooled with its length and mode.
+    File(SpillBuffer, u64, Option<u32>),
+    /// A symlink, to the target.
+    Symlink(String),
 }
 
 /// Data to be stored in the compiler cache.
+///
+/// Entries are kept in memory while all those being written fit in
+/// `SCCACHE_ENTRY_MEMORY_LIMIT`, and in temporary files beyond it.
 pub struct CacheWrite {
-    zip: ZipWriter<io::Cursor<Vec<u8>>>,
+    /// The archive of the entry's objects, once the first is added.
+    tar: Option<tar::Builder<SpillBuffer>>,
+    /// Whether any of them is a symlink.
+    has_symlinks: bool,
+    /// The entry as it's stored, if it's encrypted as a whole.
+    encrypted: Option<Vec<u8>>,
 }
 
 impl CacheWrite {
     /// Create a new, empty cache entry.
     pub fn new() -> CacheWrite {
         CacheWrite {
-            zip: ZipWriter::new(io::Cursor::new(vec![])),
+            tar: None,
+            has_symlinks: false,
+            encrypted: None,
         }
     }
 
-    /// Create a new cache entry populated with the contents of `objects`.
-    pub fn from_objects<T>(objects: T, pool: &ThreadPool) -> SFuture<CacheWrite>
+    /// Create a cache entry that's stored as `bytes`, an entry encrypted as a
+    /// whole, which no objects can be added to.
+    pub fn encrypted(bytes: Vec<u8>) -> CacheWrite {
+        CacheWrite {
+            encrypted: Some(bytes),
+            ..CacheWrite::new()
+        }
+    }
+
+    /// Create a new cache entry populated with the contents of `objects`,
+    /// reading and compressing each of them on a thread of `pool`.
+    pub fn from_objects<T>(
+        objects: T,
+        pool: &ThreadPool,
+    ) -> impl Future<Output = Result<CacheWrite>>
     where
         T: IntoIterator<Item = (String, PathBuf)> + Send + Sync + 'static,
     {
-        Box::new(pool.spawn_fn(move || -> Result<_> {
-            let mut entry = CacheWrite::new();
-            for (key, path) in objects {
-                let mut f = fs::File::open(&path)?;
-                let mode = get_file_mode(&f)?;
-                entry
-                    .put_object(&key, &mut f, mode)
-                    .with_context(|| format!("failed to put object `{:?}` in cache entry", path))?;
+        let encoded: Vec<_> = objects
+            .into_iter()
+            .map(|(key, path)| {
+                pool.spawn_fn(move || -> Result<_> {
+                    if fs::symlink_metadata(&path)?.file_type().is_symlink() {
+                        let target = fs::read_link(&path)?.into_os_string().into_string();
+                        let target = target.map_err(|target| {
+                            anyhow!(
+                                "the target {:?} of symlink `{:?}` isn't UTF-8",
+                                target,
+                                path
+                            )
+                        })?;
+                        return Ok((key, Encoded::Symlink(target)));
+                    }
+                    let mut f = fs::File::open(&path)?;
+                    let mode = get_file_mode(&f)?;
+                    let (spool, len) = spool_object(&mut f).with_context(|| {
+                        format!("failed to put object `{:?}` in cache entry", path)
+                    })?;
+                    Ok((key, Encoded::File(spool, len, mode)))
+                })
+            })
+            .collect();
+        let pool = pool.clone();
+        async move {
+            let objects = future::try_join_all(encoded).await?;
+            pool.spawn_fn(move || {
+                let mut entry = CacheWrite::new();
+                for (key, encoded) in objects {
+                    match encoded {
+                        Encoded::File(mut spool, len, mode) => {
+                            entry.append_object(&key, &mut spool, len, mode)?
+                        }
+                        Encoded::Symlink(target) => entry.append_symlink(&key, &target)?,
+                    }
+                }
+                Ok(entry)
+            })
+            .await
+        }
+    }
+
+    /// Create a new cache entry with the objects of an entry as it's stored,
+    /// in either format, copying them without decompressing them. Encrypted
+    /// entries are copied as they are.
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<CacheWrite> {
+        if is_encrypted(&bytes) {
+            return Ok(CacheWrite::encrypted(bytes));
+        }
+        let mut from = CacheRead::from(Cursor::new(bytes))?;
+        let objects: Vec<_> = from
+            .objects
+            .iter()
+            .map(|o| (o.name.clone(), o.link.clone()))
+            .collect();
+        let mut entry = CacheWrite::new();
+        for (name, link) in objects {
+            if let Some(target) = link {
+                entry.append_symlink(&name, &target)?;
+                continue;
             }
-            Ok(entry)
-        }))
+            let (bytes, mode) = from.read_object(&name)?;
+            entry.append_object(&name, &mut &bytes[..], bytes.len() as u64, mode)?;
+        }
+        Ok(entry)
+    }
+
+    /// The archive of the entry, started with the header of its format if
+    /// it wasn't yet.
+    fn tar(&mut self) -> Result<&mut tar::Builder<SpillBuffer>> {
+        if self.encrypted.is_some() {
+            bail!("Can't add objects to an encrypted cache entry");
+        }
+        if self.tar.is_none() {
+            let mut buffer = SpillBuffer::new();
+            buffer.write_all(ENTRY_MAGIC)?;
+            buffer.write_all(&[ENTRY_VERSION])?;
+            self.tar = Some(tar::Builder::new(buffer));
+        }
+        Ok(self.tar.as_mut().unwrap())
+    }
+
+    /// Add the object at `name` as it's stored, the `len` bytes read from
+    /// `from`. If `mode` is `Some`, store the file entry with that mode.
+    fn append_object<R: Read>(
+        &mut self,
+        name: &str,
+        from: &mut R,
+        len: u64,
+        mode: Option<u32>,
+    ) -> Result<()> {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(len);
+        header.set_mode(mode.unwrap_or(0));
+        append_named(self.tar()?, header, name, from.take(len))
+            .context("Failed to add cache entry object")
+    }
+
+    /// Add a symlink to `target` at `name`.
+    fn append_symlink(&mut self, name: &str, target: &str) -> Result<()> {
+        self.has_symlinks = true;
+        let tar = self.tar()?;
+        let mut header = tar::Header::new_gnu();
+        let linkname = &mut header.as_old_mut().linkname;
+        if target.len() > linkname.len() {
+            // The GNU extension for targets too long for the header, which
+            // has as much of it as fits
+            let mut long = tar::Header::new_gnu();
+            long.set_entry_type(tar::EntryType::GNULongLink);
+            long.set_size(target.len() as u64 + 1);
+            let data = [target.as_bytes(), b"\0"].concat();
+            append_named(tar, long, "././@LongLink", &data[..])
+                .context("Failed to add cache entry symlink")?;
+        }
+        let len = target.len().min(linkname.len());
+        linkname[..len].copy_from_slice(&target.as_bytes()[..len]);
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode(0o777);
+        append_named(tar, header, name, io::empty()).context("Failed to add cache entry symlink")
     }
 
     /// Add an object containing the contents of `from` to this cache entry at `name`.
@@ -211,19 +764,9 @@ impl CacheWrite {
     where
         T: Read,
     {
-        // We're going to declare the compression method as "stored",
-        // but we're actually going to store zstd-compressed blobs.
-        let opts = FileOptions::default().compression_method(CompressionMethod::Stored);
-        let opts = if let Some(mode) = mode {
-            opts.unix_permissions(mode)
-        } else {
-            opts
-        };
-        self.zip
-            .start_file(name, opts)
-            .context("Failed to start cache entry object")?;
-        zstd::stream::copy_encode(from, &mut self.zip, 3)?;
-        Ok(())
+        // Spooled first, as the size goes in the header before the object
+        let (mut spool, len) = spool_object(from)?;
+        self.append_object(name, &mut spool, len, mode)
     }
 
     pub fn put_stdout(&mut self, bytes: &[u8]) -> Result<()> {
@@ -234,6 +777,17 @@ impl CacheWrite {
         self.put_bytes("stderr", bytes)
     }
 
+    /// Record the version of the hashing the key of the entry was computed
+    /// with in it.
+    pub fn put_schema(&mut self, schema: &str) -> Result<()> {
+        self.put_bytes(SCHEMA, schema.as_bytes())
+    }
+
+    /// Record where and by whom the entry was written in it.
+    pub fn put_provenance(&mut self, provenance: &Provenance) -> Result<()> {
+        self.put_bytes("provenance", &serde_json::to_vec(provenance)?)
+    }
+
     fn put_bytes(&mut self, name: &str, bytes: &[u8]) -> Result<()> {
         if !bytes.is_empty() {
             let mut cursor = Cursor::new(bytes);
@@ -243,10 +797,18 @@ impl CacheWrite {
     }
 
     /// Finish writing data to the cache entry writer, and return the data.
-    pub fn finish(self) -> Result<Vec<u8>> {
-        let CacheWrite { mut zip } = self;
-        let cur = zip.finish().context("Failed to finish cache entry zip")?;
-        Ok(cur.into_inner())
+    pub fn finish(mut self) -> Result<Vec<u8>> {
+        if let Some(bytes) = self.encrypted.take() {
+            return Ok(bytes);
+        }
+        self.tar()?;
+        let tar = self.tar.take().unwrap();
+        let mut buffer = tar.into_inner().context("Failed to finish cache entry")?;
+        if self.has_symlinks {
+            buffer.seek(SeekFrom::Start(ENTRY_MAGIC.len() as u64))?;
+            buffer.write_all(&[SYMLINK_ENTRY_VERSION])?;
+        }
+        Ok(buffer.into_bytes()?)
     }
 }
 
@@ -256,7 +818,30 @@ impl Default for CacheWrite {
     }
 }
 
+/// Which entries `Storage::clear` removes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ClearScope {
+    /// Those on the local disk, including those of a local cache in front of
+    /// remote storage.
+    Local,
+    /// Those in remote storage.
+    Remote,
+    /// Both.
+    All,
+}
+
+impl ClearScope {
+    pub fn local(self) -> bool {
+        self != ClearScope::Remote
+    }
+
+    pub fn remote(self) -> bool {
+        self != ClearScope::Local
+    }
+}
+
 /// An interface to cache storage.
+#[async_trait(?Send)]
 pub trait Storage {
     /// Get a cache entry by `key`.
     ///
@@ -265,139 +850,589 @@ pub trait Storage {
     /// it should return a `Cache::Miss`.
     /// If the entry is successfully found in the cache, it should
     /// return a `Cache::Hit`.
-    fn get(&self, key: &str) -> SFuture<Cache>;
+    async fn get(&self, key: &str) -> Result<Cache>;
 
-    /// Put `entry` in the cache under `key`.
-    ///
-    /// Returns a `Future` that will provide the result or error when the put is
-    /// finished.
-    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration>;
+    /// Put `entry` in the cache under `key`, returning how long it took.
+    async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration>;
+
+    /// Remove the entry under `key`.
+    async fn delete(&self, key: &str) -> Result<()>;
+
+    /// Whether entries are never written, so `put` and `delete` do nothing.
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// Remove every entry in `scope`. Remote storage that doesn't support it
+    /// fails, and storage with nothing in `scope` does nothing.
+    async fn clear(&self, scope: ClearScope) -> Result<()> {
+        if scope.remote() {
+            bail!("{} can't be cleared", self.location())
+        }
+        Ok(())
+    }
+
+    /// The part of the storage on the local disk, for storage that keeps
+    /// entries there as well as remotely. The server uses it alone while
+    /// it's offline.
+    fn local(&self) -> Option<Arc<dyn Storage>> {
+        None
+    }
 
     /// Get the storage location.
     fn location(&self) -> String;
 
+    /// The kind of storage backend, such as `disk` or `s3`.
+    fn kind(&self) -> &'static str;
+
     /// Get the current storage usage, if applicable.
-    fn current_size(&self) -> SFuture<Option<u64>>;
+    async fn current_size(&self) -> Result<Option<u64>>;
 
     /// Get the maximum storage size, if applicable.
-    fn max_size(&self) -> SFuture<Option<u64>>;
+    async fn max_size(&self) -> Result<Option<u64>>;
 }
 
-/// Get a suitable `Storage` implementation from configuration.
-#[allow(clippy::cognitive_complexity)] // TODO simplify!
+/// A `Storage` that keeps its entries apart from those of the storage it
+/// wraps by prefixing their keys with `namespace`.
+pub struct NamespacedStorage {
+    inner: Arc<dyn Storage>,
+    namespace: Cow<'static, str>,
+}
+
+impl NamespacedStorage {
+    pub fn new(
+        inner: Arc<dyn Storage>,
+        namespace: impl Into<Cow<'static, str>>,
+    ) -> NamespacedStorage {
+        NamespacedStorage {
+            inner,
+            namespace: namespace.into(),
+        }
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}-{}", self.namespace, key)
+    }
+}
+
+#[async_trait(?Send)]
+impl Storage for NamespacedStorage {
+    async fn get(&self, key: &str) -> Result<Cache> {
+        self.inner.get(&self.key(key)).await
+    }
+
+    async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration> {
+        self.inner.put(&self.key(key), entry).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.inner.delete(&self.key(key)).await
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+
+    fn location(&self) -> String {
+        format!("{} ({} namespace)", self.inner.location(), self.namespace)
+    }
+
+    fn kind(&self) -> &'static str {
+        self.inner.kind()
+    }
+
+    async fn current_size(&self) -> Result<Option<u64>> {
+        self.inner.current_size().await
+    }
+
+    async fn max_size(&self) -> Result<Option<u64>> {
+        self.inner.max_size().await
+    }
+}
+
+/// The error of a write to read-only storage, which refuses it rather than
+/// trying, so that it's counted as suppressed rather than failed.
+#[derive(Debug)]
+pub struct ReadOnly;
+
+impl fmt::Display for ReadOnly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the storage is read-only")
+    }
+}
+
+impl std::error::Error for ReadOnly {}
+
+/// A `Storage` that reads from the storage it wraps but never writes to it,
+/// like storage configured to be read-only.
+pub struct ReadOnlyStorage {
+    inner: Arc<dyn Storage>,
+}
+
+impl ReadOnlyStorage {
+    pub fn new(inner: Arc<dyn Storage>) -> ReadOnlyStorage {
+        ReadOnlyStorage { inner }
+    }
+}
+
+#[async_trait(?Send)]
+impl Storage for ReadOnlyStorage {
+    async fn get(&self, key: &str) -> Result<Cache> {
+        self.inner.get(key).await
+    }
+
+    async fn put(&self, _key: &str, _entry: CacheWrite) -> Result<Duration> {
+        Err(ReadOnly.into())
+    }
+
+    async fn delete(&self, _key: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> bool {
+        true
+    }
+
+    async fn clear(&self, scope: ClearScope) -> Result<()> {
+        // Only what's on the local disk is ours to remove
+        if scope.remote() {
+            bail!("{} can't be cleared", self.location());
+        }
+        self.inner.clear(scope).await
+    }
+
+    fn location(&self) -> String {
+        format!("{} (read-only)", self.inner.location())
+    }
+
+    fn kind(&self) -> &'static str {
+        self.inner.kind()
+    }
+
+    async fn current_size(&self) -> Result<Option<u64>> {
+        self.inner.current_size().await
+    }
+
+    async fn max_size(&self) -> Result<Option<u64>> {
+        self.inner.max_size().await
+    }
+}
+
+/// Check that `storage` works by writing a small entry, reading it back and
+/// deleting it, or for read-only storage by looking one up.
+pub async fn check_storage(storage: Arc<dyn Storage>) -> Result<()> {
+    const CONTENT: &[u8] = b"sccache storage check";
+
+    let key = format!("check-{:016x}", rand::random::<u64>());
+    let location = storage.location();
+    if storage.is_read_only() {
+        storage
+            .get(&key)
+            .await
+            .with_context(|| format!("couldn't read from {}", location))?;
+        return Ok(());
+    }
+    let mut entry = CacheWrite::new();
+    entry.put_stdout(CONTENT)?;
+    if let Err(e) = storage.put(&key, entry).await {
+        let context = match storage_hint(&e) {
+            Some(hint) => format!("couldn't write to {}; {}", location, hint),
+            None => format!("couldn't write to {}", location),
+        };
+        return Err(e.context(context));
+    }
+    match storage.get(&key).await {
+        Ok(Cache::Hit(mut entry)) => {
+            if entry.get_stdout() != CONTENT {
+                bail!(
+                    "read back a different entry than was written to {}",
+                    location
+                );
+            }
+        }
+        // Errors reading from most remote storage are logged, and treated as
+        // misses
+        Ok(_) => bail!(
+            "wrote an entry to {} but couldn't read it back; check that the \
+             credentials are allowed to read from it, and the warnings logged",
+            location
+        ),
+        Err(e) => return Err(e.context(format!("couldn't read from {}", location))),
+    }
+    storage
+        .delete(&key)
+        .await
+        .with_context(|| format!("couldn't delete from {}", location))
+}
+
+/// Suggest what to fix for common errors from remote storage.
+#[cfg(feature = "hyper")]
+fn storage_hint(e: &Error) -> Option<&'static str> {
+    let status = e.downcast_ref::<BadHttpStatusError>()?.0;
+    match status.as_u16() {
+        401 | 403 => Some("check the credentials, and that they're allowed to write to it"),
+        404 => Some("check that the bucket or container exists"),
+        _ => None,
+    }
+}
+
+#[cfg(not(feature = "hyper"))]
+fn storage_hint(_e: &Error) -> Option<&'static str> {
+    None
+}
+
+/// Get a suitable `Storage` implementation from configuration, encrypting
+/// entries and read-only if it's configured to be.
 pub fn storage_from_config(config: &Config, pool: &ThreadPool) -> Arc<dyn Storage> {
+    let mut storage = configured_storage(config, pool);
+    if let Some(ref encryption) = config.cache_encryption {
+        trace!("Encrypting entries of {}", storage.location());
+        let keys = KeyRing::from_config(encryption)
+            .expect("the encryption keys are checked as the config is loaded");
+        storage = Arc::new(EncryptedStorage::new(storage, keys, pool));
+    }
+    if config.cache_readonly {
+        trace!("Refusing writes to {}", storage.location());
+        Arc::new(ReadOnlyStorage::new(storage))
+    } else {
+        storage
+    }
+}
+
+fn configured_storage(config: &Config, pool: &ThreadPool) -> Arc<dyn Storage> {
+    if !config.layers.is_empty() {
+        return layered_storage_from_config(config, pool);
+    }
     for cache_type in config.caches.iter() {
-        match *cache_type {
-            CacheType::Azure(config::AzureCacheConfig) => {
-                debug!("Trying Azure Blob Store account");
-                #[cfg(feature = "azure")]
-                match AzureBlobCache::new() {
-                    Ok(storage) => {
-                        trace!("Using AzureBlobCache");
-                        return Arc::new(storage);
-                    }
-                    Err(e) => warn!("Failed to create Azure cache: {:?}", e),
+        if let Some(storage) = storage_from_cache_type(cache_type, pool) {
+            return storage;
+        }
+    }
+
+    info!("No configured caches successful, falling back to default");
+    let (dir, size) = (&config.fallback_cache.dir, config.fallback_cache.size);
+    trace!("Using DiskCache({:?}, {})", dir, size);
+    Arc::new(DiskCache::new(&dir, size, pool))
+}
+
+/// The storage of each layer of the cache, skipping those that can't be set
+/// up, or local disk storage if none can.
+fn layered_storage_from_config(config: &Config, pool: &ThreadPool) -> Arc<dyn Storage> {
+    let layers: Vec<_> = config
+        .layers
+        .iter()
+        .filter_map(|layer| {
+            let storage = if layer.backend == CacheBackend::Disk {
+                let (dir, size) = (&config.fallback_cache.dir, config.fallback_cache.size);
+                Some(Arc::new(DiskCache::new(&dir, size, pool)) as Arc<dyn Storage>)
+            } else {
+                config
+                    .caches
+                    .iter()
+                    .find(|c| c.backend() == layer.backend)
+                    .and_then(|cache_type| storage_from_cache_type(cache_type, pool))
+            };
+            storage.map(|storage| (storage, layer.role))
+        })
+        .collect();
+    if layers.iter().all(|&(_, role)| role == CacheRole::Mirror) {
+        warn!("No cache layer to look up entries in could be set up, falling back to default");
+        let (dir, size) = (&config.fallback_cache.dir, config.fallback_cache.size);
+        return Arc::new(DiskCache::new(&dir, size, pool));
+    }
+    Arc::new(LayeredStorage::new(layers, pool))
+}
+
+/// The storage of `cache_type`, unless it can't be set up.
+#[allow(clippy::cognitive_complexity)] // TODO simplify!
+fn storage_from_cache_type(cache_type: &CacheType, pool: &ThreadPool) -> Option<Arc<dyn Storage>> {
+    match *cache_type {
+        CacheType::Azure(config::AzureCacheConfig) => {
+            debug!("Trying Azure Blob Store account");
+            #[cfg(feature = "azure")]
+            match AzureBlobCache::new() {
+                Ok(storage) => {
+                    trace!("Using AzureBlobCache");
+                    return Some(Arc::new(storage));
                 }
+                Err(e) => warn!("Failed to create Azure cache: {:?}", e),
             }
-            CacheType::GCS(config::GCSCacheConfig {
-                ref bucket,
-                ref cred_path,
-                ref url,
-                rw_mode,
-            }) => {
-                debug!(
-                    "Trying GCS bucket({}, {:?}, {:?}, {:?})",
-                    bucket, cred_path, url, rw_mode
-                );
-                #[cfg(feature = "gcs")]
-                {
-                    let service_account_info_opt: Option<gcs::ServiceAccountInfo> =
-                        if let Some(ref cred_path) = *cred_path {
-                            // Attempt to read the service account key from file
-                            let service_account_key_res: Result<gcs::ServiceAccountKey> = (|| {
-                                let mut file = File::open(&cred_path)?;
-                                let mut service_account_json = String::new();
-                                file.read_to_string(&mut service_account_json)?;
-                                Ok(serde_json::from_str(&service_account_json)?)
-                            })(
-                            );
+        }
+        CacheType::GCS(config::GCSCacheConfig {
+            ref bucket,
+            ref cred_path,
+            ref url,
+            rw_mode,
+        }) => {
+            debug!(
+                "Trying GCS bucket({}, {:?}, {:?}, {:?})",
+                bucket, cred_path, url, rw_mode
+            );
+            #[cfg(feature = "gcs")]
+            {
+                let service_account_info_opt: Option<gcs::ServiceAccountInfo> =
+                    if let Some(ref cred_path) = *cred_path {
+                        // Attempt to read the service account key from file
+                        let service_account_key_res: Result<gcs::ServiceAccountKey> = (|| {
+                            let mut file = File::open(&cred_path)?;
+                            let mut service_account_json = String::new();
+                            file.read_to_string(&mut service_account_json)?;
+                            Ok(serde_json::from_str(&service_account_json)?)
+                        })(
+                        );
 
-                            // warn! if an error was encountered reading the key from the file
-                            if let Err(ref e) = service_account_key_res {
-                                warn!(
-                                    "Failed to parse service account credentials from file: {:?}. \
-                                     Continuing without authentication.",
-                                    e
-                                );
-                            }
-
-                            service_account_key_res
-                                .ok()
-                                .map(ServiceAccountInfo::AccountKey)
-                        } else if let Some(ref url) = *url {
-                            Some(ServiceAccountInfo::URL(url.clone()))
-                        } else {
+                        // warn! if an error was encountered reading the key from the file
+                        if let Err(ref e) = service_account_key_res {
                             warn!(
+                                "Failed to parse service account credentials from file: {:?}. \
+                                 Continuing without authentication.",
+                                e
+                            );
+                        }
+
+                        service_account_key_res
+                            .ok()
+                            .map(ServiceAccountInfo::AccountKey)
+                    } else if let Some(ref url) = *url {
+                        Some(ServiceAccountInfo::URL(url.clone()))
+                    } else {
+                        warn!(
                             "No SCCACHE_GCS_KEY_PATH specified-- no authentication will be used."
                         );
-                            None
-                        };
-
-                    let gcs_read_write_mode = match rw_mode {
-                        config::GCSCacheRWMode::ReadOnly => RWMode::ReadOnly,
-                        config::GCSCacheRWMode::ReadWrite => RWMode::ReadWrite,
+                        None
                     };
 
-                    let gcs_cred_provider = service_account_info_opt
-                        .map(|info| GCSCredentialProvider::new(gcs_read_write_mode, info));
+                let gcs_read_write_mode = match rw_mode {
+                    config::GCSCacheRWMode::ReadOnly => RWMode::ReadOnly,
+                    config::GCSCacheRWMode::ReadWrite => RWMode::ReadWrite,
+                };
 
-                    match GCSCache::new(bucket.to_owned(), gcs_cred_provider, gcs_read_write_mode) {
-                        Ok(s) => {
-                            trace!("Using GCSCache");
-                            return Arc::new(s);
-                        }
-                        Err(e) => warn!("Failed to create GCS Cache: {:?}", e),
+                let gcs_cred_provider = service_account_info_opt
+                    .map(|info| GCSCredentialProvider::new(gcs_read_write_mode, info));
+
+                match GCSCache::new(bucket.to_owned(), gcs_cred_provider, gcs_read_write_mode) {
+                    Ok(s) => {
+                        trace!("Using GCSCache");
+                        return Some(Arc::new(s));
                     }
+                    Err(e) => warn!("Failed to create GCS Cache: {:?}", e),
                 }
             }
-            CacheType::Memcached(config::MemcachedCacheConfig { ref url }) => {
-                debug!("Trying Memcached({})", url);
-                #[cfg(feature = "memcached")]
-                match MemcachedCache::new(&url, pool) {
-                    Ok(s) => {
-                        trace!("Using Memcached: {}", url);
-                        return Arc::new(s);
-                    }
-                    Err(e) => warn!("Failed to create MemcachedCache: {:?}", e),
+        }
+        CacheType::Memcached(config::MemcachedCacheConfig { ref url }) => {
+            debug!("Trying Memcached({})", url);
+            #[cfg(feature = "memcached")]
+            match MemcachedCache::new(&url, pool) {
+                Ok(s) => {
+                    trace!("Using Memcached: {}", url);
+                    return Some(Arc::new(s));
                 }
+                Err(e) => warn!("Failed to create MemcachedCache: {:?}", e),
             }
-            CacheType::Redis(config::RedisCacheConfig { ref url }) => {
-                debug!("Trying Redis({})", url);
-                #[cfg(feature = "redis")]
-                match RedisCache::new(&url) {
-                    Ok(s) => {
-                        trace!("Using Redis: {}", url);
-                        return Arc::new(s);
-                    }
-                    Err(e) => warn!("Failed to create RedisCache: {:?}", e),
+        }
+        CacheType::Redis(config::RedisCacheConfig {
+            ref url,
+            ref password,
+        }) => {
+            debug!("Trying Redis({})", url);
+            #[cfg(feature = "redis")]
+            match RedisCache::new(&url, password.as_deref()) {
+                Ok(s) => {
+                    trace!("Using Redis: {}", url);
+                    return Some(Arc::new(s));
                 }
+                Err(e) => warn!("Failed to create RedisCache: {:?}", e),
             }
-            CacheType::S3(ref c) => {
-                debug!("Trying S3Cache({}, {})", c.bucket, c.endpoint);
-                #[cfg(feature = "s3")]
-                match S3Cache::new(&c.bucket, &c.endpoint, c.use_ssl, &c.key_prefix) {
-                    Ok(s) => {
-                        trace!("Using S3Cache");
-                        return Arc::new(s);
-                    }
-                    Err(e) => warn!("Failed to create S3Cache: {:?}", e),
+        }
+        CacheType::S3(ref c) => {
+            debug!("Trying S3Cache({}, {})", c.bucket, c.endpoint);
+            #[cfg(feature = "s3")]
+            match S3Cache::new(&c.bucket, &c.endpoint, c.use_ssl, &c.key_prefix) {
+                Ok(s) => {
+                    trace!("Using S3Cache");
+                    return Some(Arc::new(s));
                 }
+                Err(e) => warn!("Failed to create S3Cache: {:?}", e),
             }
         }
     }
+    None
+}
 
-    info!("No configured caches successful, falling back to default");
-    let (dir, size) = (&config.fallback_cache.dir, config.fallback_cache.size);
-    trace!("Using DiskCache({:?}, {})", dir, size);
-    Arc::new(DiskCache::new(&dir, size, pool))
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::utils::ThreadPoolExt;
+    use futures::executor::block_on;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    #[test]
+    fn test_entry_formats() {
+        let long_name = "o".repeat(150);
+        let mut entry = CacheWrite::new();
+        entry
+            .put_object("obj", &mut Cursor::new(b"object"), Some(0o100644))
+            .unwrap();
+        entry
+            .put_object(&long_name, &mut Cursor::new(b"long"), None)
+            .unwrap();
+        entry.put_stdout(b"out").unwrap();
+        entry.put_schema("c-11").unwrap();
+        let bytes = entry.finish().unwrap();
+        assert!(bytes.starts_with(ENTRY_MAGIC));
+
+        let mut entry = CacheRead::from(Cursor::new(bytes.clone())).unwrap();
+        let mut object = vec![];
+        let mode = entry.get_object("obj", &mut object).unwrap();
+        assert_eq!((object, mode), (b"object".to_vec(), Some(0o100644)));
+        let mut object = vec![];
+        let mode = entry.get_object(&long_name, &mut object).unwrap();
+        assert_eq!((object, mode), (b"long".to_vec(), None));
+        assert_eq!(entry.get_stdout(), b"out");
+        assert!(entry.get_object("dwo", &mut vec![]).is_err());
+        assert_eq!(entry.version(), "c-11, format 1");
+
+        // Entries of the format before are read, and copied into the new one
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        let opts = FileOptions::default().compression_method(CompressionMethod::Stored);
+        zip.start_file("obj", opts.unix_permissions(0o755)).unwrap();
+        encode_object(&mut Cursor::new(b"old object"), &mut zip).unwrap();
+        let old = zip.finish().unwrap().into_inner();
+        let mut entry = CacheRead::from(Cursor::new(old.clone())).unwrap();
+        let mut object = vec![];
+        entry.get_object("obj", &mut object).unwrap();
+        assert_eq!(object, b"old object");
+        assert_eq!(entry.version(), "unversioned, format 0");
+        let copy = CacheWrite::from_bytes(old).unwrap().finish().unwrap();
+        assert!(copy.starts_with(ENTRY_MAGIC));
+        let mut entry = CacheRead::from(Cursor::new(copy)).unwrap();
+        let mut object = vec![];
+        let mode = entry.get_object("obj", &mut object).unwrap();
+        assert_eq!(object, b"old object");
+        assert_eq!(mode.map(|mode| mode & 0o777), Some(0o755));
+
+        // Those of later versions aren't
+        let mut newer = bytes;
+        newer[ENTRY_MAGIC.len()] = SYMLINK_ENTRY_VERSION + 1;
+        assert!(is_newer_format(&newer));
+        assert!(CacheRead::from(Cursor::new(newer)).is_err());
+    }
+
+    #[test]
+    fn test_read_only_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = ThreadPool::sized(1);
+        let config = Config {
+            fallback_cache: config::DiskCacheConfig {
+                dir: dir.path().to_owned(),
+                size: 1 << 20,
+            },
+            cache_readonly: true,
+            ..Default::default()
+        };
+        let storage = storage_from_config(&config, &pool);
+        assert!(storage.is_read_only());
+        let err = block_on(storage.put("aaaa", CacheWrite::new())).unwrap_err();
+        assert!(err.is::<ReadOnly>());
+        assert!(matches!(block_on(storage.get("aaaa")), Ok(Cache::Miss)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks_and_modes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let pool = ThreadPool::sized(1);
+        let from = dir.path().join("from");
+        fs::create_dir(&from).unwrap();
+        fs::write(from.join("lib.so.1"), b"library").unwrap();
+        fs::set_permissions(from.join("lib.so.1"), fs::Permissions::from_mode(0o640)).unwrap();
+        symlink("lib.so.1", &from.join("lib.so")).unwrap();
+        let long_target = format!("{}/lib.so.1", "../".repeat(40) + "x");
+        symlink(&long_target, &from.join("long")).unwrap();
+
+        let objects = vec!["lib.so.1", "lib.so", "long"];
+        let paths = |dir: &Path| -> Vec<_> {
+            objects
+                .iter()
+                .map(|&name| (name.to_owned(), dir.join(name)))
+                .collect()
+        };
+        let entry = block_on(CacheWrite::from_objects(paths(&from), &pool)).unwrap();
+        let bytes = entry.finish().unwrap();
+        assert_eq!(bytes[ENTRY_MAGIC.len()], SYMLINK_ENTRY_VERSION);
+        // Copies keep them
+        let bytes = CacheWrite::from_bytes(bytes).unwrap().finish().unwrap();
+
+        let to = dir.path().join("to");
+        fs::create_dir(&to).unwrap();
+        // Replacing what's there
+        fs::write(to.join("lib.so"), b"old").unwrap();
+        let entry = CacheRead::from(Cursor::new(bytes)).unwrap();
+        block_on(entry.extract_objects(paths(&to), &pool)).unwrap();
+        assert_eq!(fs::read(to.join("lib.so")).unwrap(), b"library");
+        assert_eq!(
+            fs::read_link(to.join("lib.so")).unwrap(),
+            Path::new("lib.so.1")
+        );
+        assert_eq!(
+            fs::read_link(to.join("long")).unwrap(),
+            Path::new(&long_target)
+        );
+        let mode = fs::metadata(to.join("lib.so.1"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o7777, 0o640);
+
+        // Entries without symlinks are still written in the version before
+        let mut entry = CacheWrite::new();
+        entry.put_stdout(b"out").unwrap();
+        assert_eq!(entry.finish().unwrap()[ENTRY_MAGIC.len()], ENTRY_VERSION);
+    }
+
+    #[test]
+    fn test_object_names() {
+        // Names are keys rather than paths
+        let long = "o".repeat(200);
+        let names = ["/abs/foo.o", "../up.o", "a/./b//c", &long];
+        let mut entry = CacheWrite::new();
+        for name in names.iter() {
+            entry
+                .put_object(name, &mut Cursor::new(name.as_bytes()), None)
+                .unwrap();
+        }
+        let err = entry
+            .put_object("", &mut Cursor::new(b"x"), None)
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("Invalid"), "{:#}", err);
+        let mut entry = CacheRead::from(Cursor::new(entry.finish().unwrap())).unwrap();
+        for name in names.iter() {
+            let mut out = vec![];
+            entry.get_object(name, &mut out).unwrap();
+            assert_eq!(out, name.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_extract_objects_long_path() {
+        // Outputs longer than MAX_PATH on Windows
+        let dir = tempfile::tempdir().unwrap();
+        let pool = ThreadPool::sized(1);
+        let out_dir: PathBuf = (0..6).map(|i| format!("{}{}", i, "d".repeat(50))).collect();
+        let out_dir = dir.path().join(out_dir);
+        fs::create_dir_all(util::long_path(&out_dir)).unwrap();
+        let path = out_dir.join("foo.o");
+        assert!(path.as_os_str().len() > 260);
+
+        let mut entry = CacheWrite::new();
+        entry
+            .put_object("obj", &mut Cursor::new(b"object"), None)
+            .unwrap();
+        let entry = CacheRead::from(Cursor::new(entry.finish().unwrap())).unwrap();
+        block_on(entry.extract_objects(vec![("obj".to_owned(), path.clone())], &pool)).unwrap();
+        assert_eq!(fs::read(util::long_path(&path)).unwrap(), b"object");
+    }
 }
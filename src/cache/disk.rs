@@ -12,11 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::cache::{Cache, CacheRead, CacheWrite, Storage};
+use crate::cache::{Cache, CacheRead, CacheWrite, ClearScope, Storage};
 use crate::lru_disk_cache::Error as LruError;
 use crate::lru_disk_cache::LruDiskCache;
 use crate::util::SpawnExt;
-use futures_03::executor::ThreadPool;
+use async_trait::async_trait;
+use futures::executor::ThreadPool;
 use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
@@ -51,53 +52,88 @@ fn make_key_path(key: &str) -> PathBuf {
     Path::new(&key[0..1]).join(&key[1..2]).join(key)
 }
 
+#[async_trait(?Send)]
 impl Storage for DiskCache {
-    fn get(&self, key: &str) -> SFuture<Cache> {
+    async fn get(&self, key: &str) -> Result<Cache> {
         trace!("DiskCache::get({})", key);
         let path = make_key_path(key);
         let lru = self.lru.clone();
         let key = key.to_owned();
-        Box::new(self.pool.spawn_fn(move || {
-            let mut lru = lru.lock().unwrap();
-            let f = match lru.get(&path) {
-                Ok(f) => f,
-                Err(LruError::FileNotInCache) => {
-                    trace!("DiskCache::get({}): FileNotInCache", key);
-                    return Ok(Cache::Miss);
-                }
-                Err(LruError::Io(e)) => {
-                    trace!("DiskCache::get({}): IoError: {:?}", key, e);
-                    return Err(e.into());
-                }
-                Err(_) => unreachable!(),
-            };
-            let hit = CacheRead::from(f)?;
-            Ok(Cache::Hit(hit))
-        }))
+        self.pool
+            .spawn_fn(move || {
+                let mut lru = lru.lock().unwrap();
+                let f = match lru.get_file(&path) {
+                    Ok(f) => f,
+                    Err(LruError::FileNotInCache) => {
+                        trace!("DiskCache::get({}): FileNotInCache", key);
+                        return Ok(Cache::Miss);
+                    }
+                    Err(LruError::Io(e)) => {
+                        trace!("DiskCache::get({}): IoError: {:?}", key, e);
+                        return Err(e.into());
+                    }
+                    Err(_) => unreachable!(),
+                };
+                let hit = CacheRead::from_file(f)?;
+                Ok(Cache::Hit(hit))
+            })
+            .await
     }
 
-    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+    async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration> {
         // We should probably do this on a background thread if we're going to buffer
         // everything in memory...
         trace!("DiskCache::finish_put({})", key);
         let lru = self.lru.clone();
         let key = make_key_path(key);
-        Box::new(self.pool.spawn_fn(move || {
-            let start = Instant::now();
-            let v = entry.finish()?;
-            lru.lock().unwrap().insert_bytes(key, &v)?;
-            Ok(start.elapsed())
-        }))
+        self.pool
+            .spawn_fn(move || {
+                let start = Instant::now();
+                let v = entry.finish()?;
+                lru.lock().unwrap().insert_bytes(key, &v)?;
+                Ok(start.elapsed())
+            })
+            .await
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        trace!("DiskCache::delete({})", key);
+        let lru = self.lru.clone();
+        let key = make_key_path(key);
+        self.pool
+            .spawn_fn(move || {
+                lru.lock().unwrap().remove(key)?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn clear(&self, scope: ClearScope) -> Result<()> {
+        trace!("DiskCache::clear({:?})", scope);
+        if !scope.local() {
+            return Ok(());
+        }
+        let lru = self.lru.clone();
+        self.pool
+            .spawn_fn(move || {
+                lru.lock().unwrap().clear()?;
+                Ok(())
+            })
+            .await
     }
 
     fn location(&self) -> String {
         format!("Local disk: {:?}", self.lru.lock().unwrap().path())
     }
 
-    fn current_size(&self) -> SFuture<Option<u64>> {
-        f_ok(Some(self.lru.lock().unwrap().size()))
+    fn kind(&self) -> &'static str {
+        "disk"
+    }
+
+    async fn current_size(&self) -> Result<Option<u64>> {
+        Ok(Some(self.lru.lock().unwrap().size()))
     }
-    fn max_size(&self) -> SFuture<Option<u64>> {
-        f_ok(Some(self.lru.lock().unwrap().capacity()))
+    async fn max_size(&self) -> Result<Option<u64>> {
+        Ok(Some(self.lru.lock().unwrap().capacity()))
     }
 }
@@ -0,0 +1,331 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Encrypting cache entries before they're written to the storage, so that
+//! whoever can read the storage can't read the objects compiled, nor write
+//! entries that are looked up.
+//!
+//! Entries are encrypted as a whole with AES-256-GCM, under the cache key
+//! as associated data so that they can't be moved to another key. After
+//! the `sccache` magic and `ENCRYPTED_ENTRY_VERSION`, an entry has the
+//! length of the ID of the key it's encrypted with as a byte, the ID, the
+//! random nonce and the ciphertext. New entries are encrypted with the
+//! active key, and entries are decrypted with whichever key of the ring
+//! their ID names, so that the active key can be rotated without losing
+//! the entries encrypted with the one before.
+
+use crate::cache::{
+    Cache, CacheRead, CacheWrite, ClearScope, Storage, ENCRYPTED_ENTRY_VERSION, ENTRY_MAGIC,
+};
+use crate::config::EncryptionConfig;
+use crate::util::SpawnExt;
+use async_trait::async_trait;
+use futures::executor::ThreadPool;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::errors::*;
+
+/// The keys entries are decrypted with, by their IDs, and the ID of the
+/// one they're encrypted with.
+pub struct KeyRing {
+    active: String,
+    keys: HashMap<String, LessSafeKey>,
+    rng: SystemRandom,
+}
+
+impl KeyRing {
+    pub fn from_config(config: &EncryptionConfig) -> Result<KeyRing> {
+        let keys = config
+            .decode_keys()?
+            .into_iter()
+            .map(|(id, key)| {
+                let key = UnboundKey::new(&AES_256_GCM, &key)
+                    .map_err(|_| anyhow!("Encryption key {:?} isn't an AES-256 key", id))?;
+                Ok((id, LessSafeKey::new(key)))
+            })
+            .collect::<Result<_>>()?;
+        Ok(KeyRing {
+            active: config.active.clone(),
+            keys,
+            rng: SystemRandom::new(),
+        })
+    }
+
+    /// Encrypt `entry`, as it's stored, with the active key, to store at
+    /// `key`.
+    fn seal(&self, key: &str, mut entry: Vec<u8>) -> Result<Vec<u8>> {
+        let mut nonce = [0; NONCE_LEN];
+        self.rng
+            .fill(&mut nonce)
+            .map_err(|_| anyhow!("Failed to generate a nonce"))?;
+        self.keys[&self.active]
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(key.as_bytes()),
+                &mut entry,
+            )
+            .map_err(|_| anyhow!("Failed to encrypt cache entry"))?;
+        let mut sealed = Vec::with_capacity(ENTRY_MAGIC.len() + 2 + NONCE_LEN + entry.len());
+        sealed.extend_from_slice(ENTRY_MAGIC);
+        sealed.push(ENCRYPTED_ENTRY_VERSION);
+        sealed.push(self.active.len() as u8);
+        sealed.extend_from_slice(self.active.as_bytes());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&entry);
+        Ok(sealed)
+    }
+
+    /// Decrypt `sealed`, stored at `key`, or `None` if the key it's
+    /// encrypted with isn't in the ring.
+    fn open(&self, key: &str, mut sealed: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let start = ENTRY_MAGIC.len() + 1;
+        let id_len = usize::from(
+            *sealed
+                .get(start)
+                .context("Truncated encrypted cache entry")?,
+        );
+        let nonce_start = start + 1 + id_len;
+        if sealed.len() < nonce_start + NONCE_LEN + AES_256_GCM.tag_len() {
+            bail!("Truncated encrypted cache entry");
+        }
+        let id = String::from_utf8_lossy(&sealed[start + 1..nonce_start]).into_owned();
+        let cipher = match self.keys.get(&id) {
+            Some(cipher) => cipher,
+            None => {
+                debug!(
+                    "{} is encrypted with key {:?}, which isn't configured",
+                    key, id
+                );
+                return Ok(None);
+            }
+        };
+        let nonce = Nonce::try_assume_unique_for_key(&sealed[nonce_start..][..NONCE_LEN])
+            .map_err(|_| anyhow!("Bad nonce in encrypted cache entry"))?;
+        let mut entry = sealed.split_off(nonce_start + NONCE_LEN);
+        let len = cipher
+            .open_in_place(nonce, Aad::from(key.as_bytes()), &mut entry)
+            .map_err(|_| {
+                anyhow!(
+                    "Failed to decrypt {} with key {:?}; it was modified or stored at another key",
+                    key,
+                    id
+                )
+            })?
+            .len();
+        entry.truncate(len);
+        Ok(Some(entry))
+    }
+}
+
+/// A `Storage` that encrypts the entries written to the storage it wraps,
+/// and decrypts those looked up. Entries that aren't encrypted, or are with
+/// a key that isn't in the ring, are misses.
+pub struct EncryptedStorage {
+    inner: Arc<dyn Storage>,
+    keys: Arc<KeyRing>,
+    pool: ThreadPool,
+}
+
+impl EncryptedStorage {
+    pub fn new(inner: Arc<dyn Storage>, keys: KeyRing, pool: &ThreadPool) -> EncryptedStorage {
+        EncryptedStorage {
+            inner,
+            keys: Arc::new(keys),
+            pool: pool.clone(),
+        }
+    }
+}
+
+/// The entry `entry`, found at `key`, decrypted with `keys`.
+fn decrypt(keys: &KeyRing, key: &str, entry: CacheRead) -> Result<Cache> {
+    if entry.format() != ENCRYPTED_ENTRY_VERSION {
+        debug!("Ignoring {}, which isn't encrypted", key);
+        return Ok(Cache::Miss);
+    }
+    match keys.open(key, entry.into_bytes()?)? {
+        Some(entry) => Ok(Cache::Hit(CacheRead::from(Cursor::new(entry))?)),
+        None => Ok(Cache::Miss),
+    }
+}
+
+#[async_trait(?Send)]
+impl Storage for EncryptedStorage {
+    async fn get(&self, key: &str) -> Result<Cache> {
+        match self.inner.get(key).await? {
+            Cache::Hit(entry) => {
+                let (keys, key) = (self.keys.clone(), key.to_owned());
+                self.pool
+                    .spawn_fn(move || decrypt(&keys, &key, entry))
+                    .await
+            }
+            cache => Ok(cache),
+        }
+    }
+
+    async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration> {
+        let sealed = self
+            .pool
+            .spawn_fn({
+                let (keys, key) = (self.keys.clone(), key.to_owned());
+                move || keys.seal(&key, entry.finish()?)
+            })
+            .await?;
+        self.inner.put(key, CacheWrite::encrypted(sealed)).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.inner.delete(key).await
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+
+    async fn clear(&self, scope: ClearScope) -> Result<()> {
+        self.inner.clear(scope).await
+    }
+
+    fn local(&self) -> Option<Arc<dyn Storage>> {
+        // Whose entries are encrypted as well
+        let local = self.inner.local()?;
+        Some(Arc::new(EncryptedStorage {
+            inner: local,
+            keys: self.keys.clone(),
+            pool: self.pool.clone(),
+        }))
+    }
+
+    fn location(&self) -> String {
+        format!("{} (encrypted)", self.inner.location())
+    }
+
+    fn kind(&self) -> &'static str {
+        self.inner.kind()
+    }
+
+    async fn current_size(&self) -> Result<Option<u64>> {
+        self.inner.current_size().await
+    }
+
+    async fn max_size(&self) -> Result<Option<u64>> {
+        self.inner.max_size().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::disk::DiskCache;
+    use crate::test::utils::ThreadPoolExt;
+    use futures::executor::block_on;
+    use std::collections::BTreeMap;
+
+    fn key_ring(active: &str, keys: &[(&str, u8)]) -> KeyRing {
+        let keys: BTreeMap<_, _> = keys
+            .iter()
+            .map(|&(id, byte)| (id.to_owned(), base64::encode(&[byte; 32])))
+            .collect();
+        KeyRing::from_config(&EncryptionConfig {
+            active: active.to_owned(),
+            keys,
+        })
+        .unwrap()
+    }
+
+    fn entry(stdout: &[u8]) -> CacheWrite {
+        let mut entry = CacheWrite::new();
+        entry.put_stdout(stdout).unwrap();
+        entry
+    }
+
+    fn stdout(cache: Cache) -> Option<Vec<u8>> {
+        match cache {
+            Cache::Hit(mut entry) => Some(entry.get_stdout()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_encrypted_storage() {
+        let dir = tempfile::Builder::new()
+            .prefix("sccache_encrypted")
+            .tempdir()
+            .unwrap();
+        let pool = ThreadPool::sized(1);
+        let disk: Arc<dyn Storage> = Arc::new(DiskCache::new(&dir.path(), 1 << 20, &pool));
+        let encrypted = |keys| EncryptedStorage::new(disk.clone(), keys, &pool);
+
+        let old = encrypted(key_ring("old", &[("old", 1)]));
+        block_on(old.put("aaaa", entry(b"hello"))).unwrap();
+        assert_eq!(
+            stdout(block_on(old.get("aaaa")).unwrap()).unwrap(),
+            b"hello"
+        );
+
+        // What's stored can't be read without the key
+        let mut stored = match block_on(disk.get("aaaa")).unwrap() {
+            Cache::Hit(entry) => entry,
+            _ => panic!("not stored"),
+        };
+        assert_eq!(stored.format(), ENCRYPTED_ENTRY_VERSION);
+        let mut out = vec![];
+        let err = stored.get_object("stdout", &mut out).unwrap_err();
+        assert!(err.to_string().contains("encrypted"), "{}", err);
+
+        // After rotating, entries encrypted with the old key are still read,
+        // and new ones are encrypted with the new key
+        let rotated = encrypted(key_ring("new", &[("new", 2), ("old", 1)]));
+        assert_eq!(
+            stdout(block_on(rotated.get("aaaa")).unwrap()).unwrap(),
+            b"hello"
+        );
+        block_on(rotated.put("bbbb", entry(b"world"))).unwrap();
+        assert!(stdout(block_on(old.get("bbbb")).unwrap()).is_none());
+        assert_eq!(
+            stdout(block_on(rotated.get("bbbb")).unwrap()).unwrap(),
+            b"world"
+        );
+
+        // Entries that aren't encrypted are misses
+        block_on(disk.put("cccc", entry(b"plain"))).unwrap();
+        assert!(stdout(block_on(rotated.get("cccc")).unwrap()).is_none());
+
+        // Those under the same ID with another key fail to decrypt
+        let wrong = encrypted(key_ring("old", &[("old", 3)]));
+        assert!(block_on(wrong.get("aaaa")).is_err());
+    }
+
+    #[test]
+    fn test_entries_are_bound_to_their_key() {
+        let keys = key_ring("a", &[("a", 1)]);
+        let bytes = entry(b"hello").finish().unwrap();
+        let sealed = keys.seal("aaaa", bytes.clone()).unwrap();
+        assert!(sealed.starts_with(ENTRY_MAGIC));
+        assert_eq!(keys.open("aaaa", sealed.clone()).unwrap().unwrap(), bytes);
+        assert!(keys.open("bbbb", sealed.clone()).is_err());
+
+        // Copies between layers keep them as they are
+        let copy = CacheWrite::from_bytes(sealed.clone()).unwrap();
+        assert_eq!(copy.finish().unwrap(), sealed);
+
+        let mut truncated = sealed;
+        truncated.truncate(ENTRY_MAGIC.len() + 4);
+        assert!(keys.open("aaaa", truncated).is_err());
+    }
+}
@@ -13,22 +13,20 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{cell::RefCell, fmt, io, rc::Rc, time};
+use std::{fmt, io, rc::Rc, time};
 
 use crate::{
-    cache::{Cache, CacheRead, CacheWrite, Storage},
+    cache::{ranged, Cache, CacheRead, CacheWrite, ReadOnly, Storage},
     errors::*,
-    util::HeadersExt,
-};
-use futures::{
-    future::{self, Shared},
-    Async, Future, Stream,
+    util::{http_client, HeadersExt},
 };
+use async_trait::async_trait;
 use hyper::Method;
 use hyperx::header::{Authorization, Bearer, ContentLength, ContentType};
 use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
-use reqwest::r#async::{Client, Request};
+use reqwest::{Client, Request};
 use serde::de;
+use tokio::sync::Mutex;
 use url::form_urlencoded;
 
 /// Lifted from the url crate
@@ -57,109 +55,128 @@ impl fmt::Display for Bucket {
 
 impl Bucket {
     pub fn new(name: String) -> Result<Bucket> {
-        let client = Client::new();
+        let client = http_client("GCS")?;
 
         Ok(Bucket { name, client })
     }
 
-    fn get(&self, key: &str, cred_provider: &Option<GCSCredentialProvider>) -> SFuture<Vec<u8>> {
+    async fn get(
+        &self,
+        key: &str,
+        cred_provider: &Option<GCSCredentialProvider>,
+    ) -> Result<Vec<u8>> {
         let url = format!(
             "https://www.googleapis.com/download/storage/v1/b/{}/o/{}?alt=media",
             utf8_percent_encode(&self.name, PATH_SEGMENT),
             utf8_percent_encode(key, PATH_SEGMENT)
         );
 
-        let client = self.client.clone();
-
-        let creds_opt_future = if let Some(ref cred_provider) = *cred_provider {
-            future::Either::A(
-                cred_provider
-                    .credentials(&self.client)
-                    .map_err(|err| {
-                        warn!("Error getting credentials: {:?}", err);
-                        err
-                    })
-                    .map(Some),
-            )
+        let creds_opt = if let Some(ref cred_provider) = *cred_provider {
+            let creds = cred_provider
+                .credentials(&self.client)
+                .await
+                .map_err(|err| {
+                    warn!("Error getting credentials: {:?}", err);
+                    err
+                })?;
+            Some(creds)
         } else {
-            future::Either::B(future::ok(None))
+            None
         };
 
-        Box::new(creds_opt_future.and_then(move |creds_opt| {
+        let request = || {
             let mut request = Request::new(Method::GET, url.parse().unwrap());
-            if let Some(creds) = creds_opt {
-                request
-                    .headers_mut()
-                    .set(Authorization(Bearer { token: creds.token }));
+            if let Some(ref creds) = creds_opt {
+                request.headers_mut().set(Authorization(Bearer {
+                    token: creds.token.clone(),
+                }));
             }
-            client
-                .execute(request)
-                .fwith_context(move || format!("failed GET: {}", url))
-                .and_then(|res| {
-                    if res.status().is_success() {
-                        Ok(res.into_body())
-                    } else {
-                        Err(BadHttpStatusError(res.status()).into())
-                    }
-                })
-                .and_then(|body| {
-                    body.fold(Vec::new(), |mut body, chunk| {
-                        body.extend_from_slice(&chunk);
-                        Ok::<_, reqwest::Error>(body)
-                    })
-                    .fcontext("failed to read HTTP body")
-                })
-        }))
+            request
+        };
+        ranged::download(&self.client, request).await
     }
 
-    fn put(
+    async fn put(
         &self,
         key: &str,
         content: Vec<u8>,
         cred_provider: &Option<GCSCredentialProvider>,
-    ) -> SFuture<()> {
+    ) -> Result<()> {
         let url = format!(
             "https://www.googleapis.com/upload/storage/v1/b/{}/o?name={}&uploadType=media",
             utf8_percent_encode(&self.name, PATH_SEGMENT),
             utf8_percent_encode(key, QUERY)
         );
 
-        let client = self.client.clone();
-
-        let creds_opt_future = if let Some(ref cred_provider) = cred_provider {
-            future::Either::A(cred_provider.credentials(&self.client).map(Some))
+        let creds_opt = if let Some(ref cred_provider) = cred_provider {
+            Some(cred_provider.credentials(&self.client).await?)
         } else {
-            future::Either::B(future::ok(None))
+            None
         };
 
-        Box::new(creds_opt_future.and_then(move |creds_opt| {
-            let mut request = Request::new(Method::POST, url.parse().unwrap());
-            {
-                let headers = request.headers_mut();
-                if let Some(creds) = creds_opt {
-                    headers.set(Authorization(Bearer { token: creds.token }));
-                }
-                headers.set(ContentType::octet_stream());
-                headers.set(ContentLength(content.len() as u64));
+        let mut request = Request::new(Method::POST, url.parse().unwrap());
+        {
+            let headers = request.headers_mut();
+            if let Some(creds) = creds_opt {
+                headers.set(Authorization(Bearer { token: creds.token }));
             }
-            *request.body_mut() = Some(content.into());
-
-            client.execute(request).then(|result| match result {
-                Ok(res) => {
-                    if res.status().is_success() {
-                        trace!("PUT succeeded");
-                        Ok(())
-                    } else {
-                        trace!("PUT failed with HTTP status: {}", res.status());
-                        Err(BadHttpStatusError(res.status()).into())
-                    }
+            headers.set(ContentType::octet_stream());
+            headers.set(ContentLength(content.len() as u64));
+        }
+        *request.body_mut() = Some(content.into());
+
+        match self.client.execute(request).await {
+            Ok(res) => {
+                if res.status().is_success() {
+                    trace!("PUT succeeded");
+                    Ok(())
+                } else {
+                    trace!("PUT failed with HTTP status: {}", res.status());
+                    Err(BadHttpStatusError(res.status()).into())
                 }
-                Err(e) => {
-                    trace!("PUT failed with error: {:?}", e);
-                    Err(e.into())
+            }
+            Err(e) => {
+                trace!("PUT failed with error: {:?}", e);
+                Err(e.into())
+            }
+        }
+    }
+
+    async fn delete(&self, key: &str, cred_provider: &Option<GCSCredentialProvider>) -> Result<()> {
+        let url = format!(
+            "https://www.googleapis.com/storage/v1/b/{}/o/{}",
+            utf8_percent_encode(&self.name, PATH_SEGMENT),
+            utf8_percent_encode(key, PATH_SEGMENT)
+        );
+
+        let creds_opt = if let Some(ref cred_provider) = cred_provider {
+            Some(cred_provider.credentials(&self.client).await?)
+        } else {
+            None
+        };
+
+        let mut request = Request::new(Method::DELETE, url.parse().unwrap());
+        if let Some(creds) = creds_opt {
+            request
+                .headers_mut()
+                .set(Authorization(Bearer { token: creds.token }));
+        }
+
+        match self.client.execute(request).await {
+            Ok(res) => {
+                if res.status().is_success() {
+                    trace!("DELETE succeeded");
+                    Ok(())
+                } else {
+                    trace!("DELETE failed with HTTP status: {}", res.status());
+                    Err(BadHttpStatusError(res.status()).into())
                 }
-            })
-        }))
+            }
+            Err(e) => {
+                trace!("DELETE failed with error: {:?}", e);
+                Err(e.into())
+            }
+        }
     }
 }
 
@@ -170,7 +187,7 @@ impl Bucket {
 pub struct GCSCredentialProvider {
     rw_mode: RWMode,
     sa_info: ServiceAccountInfo,
-    cached_credentials: RefCell<Option<Shared<SFuture<GCSCredential>>>>,
+    cached_credentials: Mutex<Option<GCSCredential>>,
 }
 
 /// ServiceAccountInfo either contains a URL to fetch the oauth token
@@ -334,7 +351,7 @@ impl GCSCredentialProvider {
         GCSCredentialProvider {
             rw_mode,
             sa_info,
-            cached_credentials: RefCell::new(None),
+            cached_credentials: Mutex::new(None),
         }
     }
 
@@ -365,122 +382,83 @@ impl GCSCredentialProvider {
         .unwrap())
     }
 
-    fn request_new_token(
+    async fn request_new_token(
         &self,
         sa_key: &ServiceAccountKey,
         client: &Client,
-    ) -> SFuture<GCSCredential> {
-        let client = client.clone();
+    ) -> Result<GCSCredential> {
         let expires_at = chrono::offset::Utc::now() + chrono::Duration::minutes(59);
-        let auth_jwt = self.auth_request_jwt(sa_key, &expires_at);
-        let url = sa_key.token_uri.clone();
+        let auth_jwt = self.auth_request_jwt(sa_key, &expires_at)?;
+        let url = &sa_key.token_uri;
 
         // Request credentials
-        Box::new(
-            future::result(auth_jwt)
-                .and_then(move |auth_jwt| {
-                    let params = form_urlencoded::Serializer::new(String::new())
-                        .append_pair("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer")
-                        .append_pair("assertion", &auth_jwt)
-                        .finish();
-
-                    let mut request = Request::new(Method::POST, url.parse().unwrap());
-                    {
-                        let headers = request.headers_mut();
-                        headers.set(ContentType::form_url_encoded());
-                        headers.set(ContentLength(params.len() as u64));
-                    }
-                    *request.body_mut() = Some(params.into());
-
-                    client.execute(request).map_err(Into::into)
-                })
-                .and_then(move |res| {
-                    if res.status().is_success() {
-                        Ok(res.into_body())
-                    } else {
-                        Err(BadHttpStatusError(res.status()).into())
-                    }
-                })
-                .and_then(move |body| {
-                    // Concatenate body chunks into a single Vec<u8>
-                    body.fold(Vec::new(), |mut body, chunk| {
-                        body.extend_from_slice(&chunk);
-                        Ok::<_, reqwest::Error>(body)
-                    })
-                    .fcontext("failed to read HTTP body")
-                })
-                .and_then(move |body| {
-                    // Convert body to string and parse the token out of the response
-                    let body_str = String::from_utf8(body)?;
-                    let token_msg: TokenMsg = serde_json::from_str(&body_str)?;
-
-                    Ok(GCSCredential {
-                        token: token_msg.access_token,
-                        expiration_time: expires_at,
-                    })
-                }),
-        )
-    }
+        let params = form_urlencoded::Serializer::new(String::new())
+            .append_pair("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer")
+            .append_pair("assertion", &auth_jwt)
+            .finish();
 
-    fn request_new_token_from_tcauth(&self, url: &str, client: &Client) -> SFuture<GCSCredential> {
-        Box::new(
-            client
-                .get(url)
-                .send()
-                .map_err(Into::into)
-                .and_then(move |res| {
-                    if res.status().is_success() {
-                        Ok(res.into_body())
-                    } else {
-                        Err(BadHttpStatusError(res.status()).into())
-                    }
-                })
-                .and_then(move |body| {
-                    body.fold(Vec::new(), |mut body, chunk| {
-                        body.extend_from_slice(&chunk);
-                        Ok::<_, reqwest::Error>(body)
-                    })
-                    .fcontext("failed to read HTTP body")
-                })
-                .and_then(move |body| {
-                    let body_str = String::from_utf8(body)?;
-                    let resp: AuthResponse = serde_json::from_str(&body_str)?;
-                    Ok(GCSCredential {
-                        token: resp.access_token,
-                        expiration_time: resp.expire_time.parse()?,
-                    })
-                }),
-        )
+        let mut request = Request::new(Method::POST, url.parse().unwrap());
+        {
+            let headers = request.headers_mut();
+            headers.set(ContentType::form_url_encoded());
+            headers.set(ContentLength(params.len() as u64));
+        }
+        *request.body_mut() = Some(params.into());
+
+        let res = client.execute(request).await?;
+        if !res.status().is_success() {
+            return Err(BadHttpStatusError(res.status()).into());
+        }
+        let body = res.bytes().await.context("failed to read HTTP body")?;
+
+        // Convert body to string and parse the token out of the response
+        let body_str = String::from_utf8(body.to_vec())?;
+        let token_msg: TokenMsg = serde_json::from_str(&body_str)?;
+
+        Ok(GCSCredential {
+            token: token_msg.access_token,
+            expiration_time: expires_at,
+        })
     }
 
-    pub fn credentials(&self, client: &Client) -> SFuture<GCSCredential> {
-        let mut future_opt = self.cached_credentials.borrow_mut();
+    async fn request_new_token_from_tcauth(
+        &self,
+        url: &str,
+        client: &Client,
+    ) -> Result<GCSCredential> {
+        let res = client.get(url).send().await?;
+        if !res.status().is_success() {
+            return Err(BadHttpStatusError(res.status()).into());
+        }
+        let body = res.bytes().await.context("failed to read HTTP body")?;
+        let body_str = String::from_utf8(body.to_vec())?;
+        let resp: AuthResponse = serde_json::from_str(&body_str)?;
+        Ok(GCSCredential {
+            token: resp.access_token,
+            expiration_time: resp.expire_time.parse()?,
+        })
+    }
 
-        let needs_refresh = match Option::as_mut(&mut future_opt).map(|f| f.poll()) {
-            None => true,
-            Some(Ok(Async::Ready(ref creds))) => creds.expiration_time < chrono::offset::Utc::now(),
-            _ => false,
-        };
+    pub async fn credentials(&self, client: &Client) -> Result<GCSCredential> {
+        // Held while they're requested, so that lookups at once share them
+        let mut cached = self.cached_credentials.lock().await;
+        match *cached {
+            Some(ref creds) if creds.expiration_time >= chrono::offset::Utc::now() => {
+                return Ok(creds.clone())
+            }
+            _ => {}
+        }
 
-        if needs_refresh {
-            let credentials = match self.sa_info {
-                ServiceAccountInfo::AccountKey(ref sa_key) => {
-                    self.request_new_token(sa_key, client)
-                }
-                ServiceAccountInfo::URL(ref url) => self.request_new_token_from_tcauth(url, client),
-            };
-            *future_opt = Some(credentials.shared());
+        let credentials = match self.sa_info {
+            ServiceAccountInfo::AccountKey(ref sa_key) => {
+                self.request_new_token(sa_key, client).await?
+            }
+            ServiceAccountInfo::URL(ref url) => {
+                self.request_new_token_from_tcauth(url, client).await?
+            }
         };
-
-        Box::new(
-            Option::as_mut(&mut future_opt)
-                .unwrap()
-                .clone()
-                .then(|result| match result {
-                    Ok(e) => Ok((*e).clone()),
-                    Err(e) => Err(anyhow!(e.to_string())),
-                }),
-        )
+        *cached = Some(credentials.clone());
+        Ok(credentials)
     }
 }
 
@@ -509,89 +487,102 @@ impl GCSCache {
     }
 }
 
+#[async_trait(?Send)]
 impl Storage for GCSCache {
-    fn get(&self, key: &str) -> SFuture<Cache> {
-        Box::new(
-            self.bucket
-                .get(&key, &self.credential_provider)
-                .then(|result| match result {
-                    Ok(data) => {
-                        let hit = CacheRead::from(io::Cursor::new(data))?;
-                        Ok(Cache::Hit(hit))
-                    }
-                    Err(e) => {
-                        warn!("Got GCS error: {:?}", e);
-                        Ok(Cache::Miss)
-                    }
-                }),
-        )
+    async fn get(&self, key: &str) -> Result<Cache> {
+        match self.bucket.get(&key, &self.credential_provider).await {
+            Ok(data) => {
+                let hit = CacheRead::from(io::Cursor::new(data))?;
+                Ok(Cache::Hit(hit))
+            }
+            Err(e) => {
+                warn!("Got GCS error: {:?}", e);
+                Ok(Cache::Miss)
+            }
+        }
     }
 
-    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<time::Duration> {
+    async fn put(&self, key: &str, entry: CacheWrite) -> Result<time::Duration> {
         if let RWMode::ReadOnly = self.rw_mode {
-            return Box::new(future::ok(time::Duration::new(0, 0)));
+            return Err(ReadOnly.into());
         }
 
         let start = time::Instant::now();
-        let data = match entry.finish() {
-            Ok(data) => data,
-            Err(e) => return Box::new(future::err(e)),
-        };
-        let bucket = self.bucket.clone();
-        let response = bucket
+        let data = entry.finish()?;
+        self.bucket
             .put(&key, data, &self.credential_provider)
-            .fcontext("failed to put cache entry in GCS");
+            .await
+            .context("failed to put cache entry in GCS")?;
+
+        Ok(start.elapsed())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        if let RWMode::ReadOnly = self.rw_mode {
+            return Ok(());
+        }
+
+        self.bucket
+            .delete(&key, &self.credential_provider)
+            .await
+            .context("failed to delete cache entry from GCS")
+    }
 
-        Box::new(response.map(move |_| start.elapsed()))
+    fn is_read_only(&self) -> bool {
+        match self.rw_mode {
+            RWMode::ReadOnly => true,
+            RWMode::ReadWrite => false,
+        }
     }
 
     fn location(&self) -> String {
         format!("GCS, bucket: {}", self.bucket)
     }
 
-    fn current_size(&self) -> SFuture<Option<u64>> {
-        Box::new(future::ok(None))
+    fn kind(&self) -> &'static str {
+        "gcs"
+    }
+
+    async fn current_size(&self) -> Result<Option<u64>> {
+        Ok(None)
     }
-    fn max_size(&self) -> SFuture<Option<u64>> {
-        Box::new(future::ok(None))
+    async fn max_size(&self) -> Result<Option<u64>> {
+        Ok(None)
     }
 }
 
 #[test]
 fn test_gcs_credential_provider() {
     const EXPIRE_TIME: &str = "3000-01-01T00:00:00.0Z";
-    let addr = ([127, 0, 0, 1], 3000).into();
-    let make_service = || {
-        hyper::service::service_fn_ok(|_| {
-            let token = serde_json::json!({
-                "accessToken": "1234567890",
-                "expireTime": EXPIRE_TIME,
-            });
-            hyper::Response::new(hyper::Body::from(token.to_string()))
-        })
-    };
-
-    let server = hyper::Server::bind(&addr).serve(make_service);
-
-    let credential_provider = GCSCredentialProvider::new(
-        RWMode::ReadWrite,
-        ServiceAccountInfo::URL("http://127.0.0.1:3000/".to_string()),
-    );
-
-    let client = Client::new();
-    let cred_fut = credential_provider
-        .credentials(&client)
-        .map(move |credential| {
-            assert_eq!(credential.token, "1234567890");
-            assert_eq!(
-                credential.expiration_time.timestamp(),
-                EXPIRE_TIME
-                    .parse::<chrono::DateTime<chrono::offset::Utc>>()
-                    .unwrap()
-                    .timestamp(),
-            );
-        })
-        .map_err(move |err| panic!(err.to_string()));
+    let runtime = crate::util::runtime().unwrap();
+    runtime.block_on(async {
+        let addr = ([127, 0, 0, 1], 0).into();
+        let make_service = hyper::service::make_service_fn(|_| async {
+            Ok::<_, hyper::Error>(hyper::service::service_fn(|_| async {
+                let token = serde_json::json!({
+                    "accessToken": "1234567890",
+                    "expireTime": EXPIRE_TIME,
+                });
+                Ok::<_, hyper::Error>(hyper::Response::new(hyper::Body::from(token.to_string())))
+            }))
+        });
+
+        let server = hyper::Server::bind(&addr).serve(make_service);
+        let url = format!("http://{}/", server.local_addr());
+        tokio::spawn(server);
+
+        let credential_provider =
+            GCSCredentialProvider::new(RWMode::ReadWrite, ServiceAccountInfo::URL(url));
 
-    server.with_graceful_shutdown(cred_fut);
+        let client = Client::new();
+        let credential = credential_provider.credentials(&client).await.unwrap();
+        assert_eq!(credential.token, "1234567890");
+        assert_eq!(
+            credential.expiration_time.timestamp(),
+            EXPIRE_TIME
+                .parse::<chrono::DateTime<chrono::offset::Utc>>()
+                .unwrap()
+                .timestamp(),
+        );
+    });
 }
@@ -0,0 +1,372 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Storage made of layers of other storage, looked up in order, each used
+//! as its role in the config says. That's also how a local disk cache is
+//! kept in front of remote storage with `SCCACHE_LOCAL_CACHE`.
+
+use crate::cache::disk::DiskCache;
+use crate::cache::metered::{BackendMeters, MeteredStorage};
+use crate::cache::{Cache, CacheRead, CacheWrite, ClearScope, ReadOnly, Storage};
+use crate::config::{CacheRole, Config};
+use crate::util::SpawnExt;
+use async_trait::async_trait;
+use futures::executor::ThreadPool;
+use futures::future;
+use std::env;
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::errors::*;
+
+type Layers = Arc<Vec<(Arc<dyn Storage>, CacheRole)>>;
+
+/// A `Storage` that looks up entries in each of its layers but mirrors in
+/// turn, copying those found into the read-through layers before, and
+/// writes them to all of them, failing the write only if a write-through
+/// layer fails it.
+pub struct LayeredStorage {
+    layers: Layers,
+    pool: ThreadPool,
+}
+
+impl LayeredStorage {
+    pub fn new(layers: Vec<(Arc<dyn Storage>, CacheRole)>, pool: &ThreadPool) -> LayeredStorage {
+        LayeredStorage {
+            layers: Arc::new(layers),
+            pool: pool.clone(),
+        }
+    }
+
+    /// Put the local disk cache configured in `config` in front of `remote`,
+    /// as a read-through layer, if `SCCACHE_LOCAL_CACHE` is `1`, unless it's
+    /// local disk storage already. Its lookups and writes are counted in
+    /// `meters`.
+    pub fn local_cache_from_env(
+        remote: Arc<dyn Storage>,
+        config: &Config,
+        pool: &ThreadPool,
+        meters: &BackendMeters,
+    ) -> Arc<dyn Storage> {
+        match env::var("SCCACHE_LOCAL_CACHE") {
+            Ok(ref val) if val == "1" && remote.kind() != "disk" => {}
+            _ => return remote,
+        }
+        let (dir, size) = (&config.fallback_cache.dir, config.fallback_cache.size);
+        trace!(
+            "Using DiskCache({:?}, {}) in front of the storage",
+            dir,
+            size
+        );
+        let local = MeteredStorage::wrap(Arc::new(DiskCache::new(&dir, size, pool)), meters);
+        let layers = vec![
+            (local, CacheRole::ReadThrough),
+            (remote, CacheRole::WriteThrough),
+        ];
+        Arc::new(LayeredStorage::new(layers, pool))
+    }
+}
+
+impl LayeredStorage {
+    /// Copy `entry`, found in the `i`th layer, into the read-through layers
+    /// before it.
+    async fn copy_to(&self, i: usize, key: &str, entry: CacheRead) -> Result<Cache> {
+        let bytes = self.pool.spawn_fn(move || entry.into_bytes()).await?;
+        let copies = self.layers[..i]
+            .iter()
+            .filter(|&&(_, role)| role == CacheRole::ReadThrough)
+            .map(|(storage, _)| {
+                let bytes = bytes.clone();
+                async move {
+                    let res = match CacheWrite::from_bytes(bytes) {
+                        Ok(entry) => storage.put(key, entry).await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = res {
+                        warn!("Failed to copy {} to {}: {}", key, storage.location(), e);
+                    }
+                }
+            });
+        future::join_all(copies).await;
+        Ok(Cache::Hit(CacheRead::from(Cursor::new(bytes))?))
+    }
+}
+
+#[async_trait(?Send)]
+impl Storage for LayeredStorage {
+    /// Look up `key` in each layer in turn. A miss is the error of the
+    /// first write-through layer that failed, if one did.
+    async fn get(&self, key: &str) -> Result<Cache> {
+        let mut failed = None;
+        for (i, &(ref storage, role)) in self.layers.iter().enumerate() {
+            if role == CacheRole::Mirror {
+                continue;
+            }
+            match storage.get(key).await {
+                Ok(Cache::Hit(entry)) => return self.copy_to(i, key, entry).await,
+                Ok(_) => {}
+                Err(e) => {
+                    debug!(
+                        "LayeredStorage::get({}): lookup in {} failed: {}",
+                        key,
+                        storage.location(),
+                        e
+                    );
+                    if failed.is_none() && role == CacheRole::WriteThrough {
+                        failed = Some(e);
+                    }
+                }
+            }
+        }
+        match failed {
+            Some(e) => Err(e),
+            None => Ok(Cache::Miss),
+        }
+    }
+
+    async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration> {
+        if self.is_read_only() {
+            return Err(ReadOnly.into());
+        }
+        // A write-through layer refusing writes is left out, but then the
+        // write counts as refused once the others are written
+        let refused = self
+            .layers
+            .iter()
+            .any(|&(ref storage, role)| role == CacheRole::WriteThrough && storage.is_read_only());
+        let bytes = self.pool.spawn_fn(move || entry.finish()).await?;
+        let puts = self
+            .layers
+            .iter()
+            .filter(|(storage, _)| !storage.is_read_only())
+            .map(|&(ref storage, role)| {
+                let bytes = bytes.clone();
+                async move {
+                    let res = match CacheWrite::from_bytes(bytes) {
+                        Ok(entry) => storage.put(key, entry).await,
+                        Err(e) => Err(e),
+                    };
+                    if role == CacheRole::WriteThrough {
+                        return res.map(Some);
+                    }
+                    if let Err(e) = res {
+                        warn!("Failed to write {} to {}: {}", key, storage.location(), e);
+                    }
+                    Ok(None)
+                }
+            });
+        // As long as the slowest write-through layer took
+        let durations = future::try_join_all(puts).await?;
+        if refused {
+            return Err(ReadOnly.into());
+        }
+        Ok(durations.into_iter().flatten().max().unwrap_or_default())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let deletes = self.layers.iter().map(|(storage, _)| storage.delete(key));
+        future::try_join_all(deletes).await?;
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.layers
+            .iter()
+            .all(|(storage, _)| storage.is_read_only())
+    }
+
+    async fn clear(&self, scope: ClearScope) -> Result<()> {
+        // In order, stopping at the first layer that can't be cleared
+        for (storage, _) in self.layers.iter() {
+            storage.clear(scope).await?;
+        }
+        Ok(())
+    }
+
+    fn local(&self) -> Option<Arc<dyn Storage>> {
+        self.layers
+            .iter()
+            .map(|(storage, _)| storage)
+            .find(|storage| storage.kind() == "disk")
+            .cloned()
+    }
+
+    fn location(&self) -> String {
+        let layers: Vec<_> = self
+            .layers
+            .iter()
+            .map(|(storage, role)| format!("{} ({})", storage.location(), role.name()))
+            .collect();
+        layers.join(", then ")
+    }
+
+    fn kind(&self) -> &'static str {
+        // That of the storage entries are kept in
+        self.layers
+            .iter()
+            .find(|&&(_, role)| role == CacheRole::WriteThrough)
+            .unwrap_or(&self.layers[0])
+            .0
+            .kind()
+    }
+
+    async fn current_size(&self) -> Result<Option<u64>> {
+        self.layers[0].0.current_size().await
+    }
+
+    async fn max_size(&self) -> Result<Option<u64>> {
+        self.layers[0].0.max_size().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::ReadOnlyStorage;
+    use crate::test::mock_storage::MockStorage;
+    use crate::test::utils::ThreadPoolExt;
+    use futures::executor::block_on;
+
+    fn hit(stdout: &[u8]) -> Cache {
+        let mut entry = CacheWrite::new();
+        entry.put_stdout(stdout).unwrap();
+        let bytes = entry.finish().unwrap();
+        Cache::Hit(CacheRead::from(Cursor::new(bytes)).unwrap())
+    }
+
+    #[test]
+    fn test_layered_storage() {
+        let pool = ThreadPool::sized(1);
+        let (near, far) = (Arc::new(MockStorage::new()), Arc::new(MockStorage::new()));
+        let mirror = Arc::new(MockStorage::new());
+        let layers: Vec<(Arc<dyn Storage>, CacheRole)> = vec![
+            (near.clone(), CacheRole::ReadThrough),
+            (mirror, CacheRole::Mirror),
+            (far.clone(), CacheRole::WriteThrough),
+        ];
+        let storage = LayeredStorage::new(layers, &pool);
+
+        // Found in the write-through layer, the mirror isn't looked up
+        near.next_get(Ok(Cache::Miss));
+        far.next_get(Ok(hit(b"far")));
+        match block_on(storage.get("aaaa")).unwrap() {
+            Cache::Hit(mut entry) => assert_eq!(entry.get_stdout(), b"far"),
+            cache => panic!("Unexpected {:?}", cache),
+        }
+
+        // The read-through layer failing is a miss, the write-through one an
+        // error
+        near.next_get(Err(anyhow!("near")));
+        far.next_get(Ok(Cache::Miss));
+        assert!(matches!(block_on(storage.get("bbbb")), Ok(Cache::Miss)));
+        near.next_get(Ok(Cache::Miss));
+        far.next_get(Err(anyhow!("far")));
+        assert!(block_on(storage.get("cccc")).is_err());
+
+        block_on(storage.put("dddd", CacheWrite::new())).unwrap();
+        assert!(storage.location().contains("(mirror)"));
+    }
+
+    fn entry(stdout: &[u8]) -> CacheWrite {
+        let mut entry = CacheWrite::new();
+        entry.put_stdout(stdout).unwrap();
+        entry
+    }
+
+    fn stdout(cache: Cache) -> Vec<u8> {
+        match cache {
+            Cache::Hit(mut entry) => entry.get_stdout(),
+            cache => panic!("Unexpected {:?}", cache),
+        }
+    }
+
+    fn local_cache(
+        dir: &std::path::Path,
+        remote: Arc<dyn Storage>,
+        pool: &ThreadPool,
+    ) -> LayeredStorage {
+        let local = Arc::new(DiskCache::new(&dir, u64::MAX, pool));
+        let layers: Vec<(Arc<dyn Storage>, CacheRole)> = vec![
+            (local, CacheRole::ReadThrough),
+            (remote, CacheRole::WriteThrough),
+        ];
+        LayeredStorage::new(layers, pool)
+    }
+
+    #[test]
+    fn test_local_cache() {
+        let tempdir = tempfile::Builder::new()
+            .prefix("sccache_test_layered")
+            .tempdir()
+            .unwrap();
+        let pool = ThreadPool::sized(1);
+        let remote = Arc::new(MockStorage::new());
+        remote.next_get(Ok(hit(b"remote")));
+        remote.next_get(Ok(Cache::Miss));
+        let storage = local_cache(tempdir.path(), remote, &pool);
+
+        // Found remotely once, and locally after that
+        assert_eq!(stdout(block_on(storage.get("aaaa")).unwrap()), b"remote");
+        assert_eq!(stdout(block_on(storage.get("aaaa")).unwrap()), b"remote");
+        assert!(matches!(block_on(storage.get("bbbb")), Ok(Cache::Miss)));
+        // Writes are found locally
+        block_on(storage.put("cccc", entry(b"written"))).unwrap();
+        assert_eq!(stdout(block_on(storage.get("cccc")).unwrap()), b"written");
+        assert_eq!(storage.kind(), "mock");
+    }
+
+    #[test]
+    fn test_local_cache_clear() {
+        let tempdir = tempfile::Builder::new()
+            .prefix("sccache_test_layered")
+            .tempdir()
+            .unwrap();
+        let pool = ThreadPool::sized(1);
+        let remote = Arc::new(MockStorage::new());
+        let storage = local_cache(tempdir.path(), remote.clone(), &pool);
+        block_on(storage.put("aaaa", entry(b"written"))).unwrap();
+
+        // Clearing the local disk leaves the remote storage alone
+        block_on(storage.clear(ClearScope::Local)).unwrap();
+        remote.next_get(Ok(Cache::Miss));
+        assert!(matches!(block_on(storage.get("aaaa")), Ok(Cache::Miss)));
+        // Which can't be cleared
+        assert!(block_on(storage.clear(ClearScope::Remote)).is_err());
+    }
+
+    #[test]
+    fn test_local_cache_of_read_only_storage() {
+        let tempdir = tempfile::Builder::new()
+            .prefix("sccache_test_layered")
+            .tempdir()
+            .unwrap();
+        let pool = ThreadPool::sized(1);
+        let remote = Arc::new(ReadOnlyStorage::new(Arc::new(MockStorage::new())));
+        let storage = local_cache(tempdir.path(), remote, &pool);
+
+        // Written locally, but refused as a whole
+        let res = block_on(storage.put("aaaa", entry(b"written")));
+        assert!(res.unwrap_err().is::<ReadOnly>());
+        assert_eq!(stdout(block_on(storage.get("aaaa")).unwrap()), b"written");
+    }
+
+    #[test]
+    fn test_cache_write_from_bytes() {
+        let bytes = entry(b"hello").finish().unwrap();
+        let copy = CacheWrite::from_bytes(bytes).unwrap().finish().unwrap();
+        let mut entry = CacheRead::from(Cursor::new(copy)).unwrap();
+        assert_eq!(entry.get_stdout(), b"hello");
+    }
+}
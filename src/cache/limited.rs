@@ -0,0 +1,279 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bounding how many cache operations are in progress, so that a slow
+//! storage backend doesn't pile up lookups and the entries waiting to be
+//! written. Once too many are waiting, new ones are shed: lookups miss and
+//! the compile runs locally, and writes are skipped.
+
+use crate::cache::{Cache, CacheWrite, ClearScope, Storage};
+use async_trait::async_trait;
+use futures::channel::oneshot;
+use std::collections::VecDeque;
+use std::env;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::errors::*;
+
+/// The error of an operation that was shed.
+#[derive(Debug)]
+pub struct Overloaded;
+
+impl fmt::Display for Overloaded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "too many cache operations are waiting for the storage")
+    }
+}
+
+impl std::error::Error for Overloaded {}
+
+/// Which operations may be shed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shed {
+    /// Lookups and writes.
+    All,
+    /// Only writes, while lookups keep waiting for the storage.
+    Writes,
+}
+
+/// A `Storage` that runs at most `concurrency` lookups and writes of the
+/// storage it wraps at once, queueing the others.
+pub struct LimitedStorage {
+    inner: Arc<dyn Storage>,
+    limiter: Arc<Limiter>,
+    queue_limit: Option<usize>,
+    shed: Shed,
+}
+
+impl LimitedStorage {
+    /// Limit `inner` to `concurrency` operations at once, shedding those
+    /// that `shed` allows once `queue_limit` are waiting.
+    pub fn new(
+        inner: Arc<dyn Storage>,
+        concurrency: usize,
+        queue_limit: Option<usize>,
+        shed: Shed,
+    ) -> LimitedStorage {
+        LimitedStorage {
+            inner,
+            limiter: Arc::new(Limiter {
+                concurrency,
+                state: Mutex::new(State {
+                    active: 0,
+                    waiting: VecDeque::new(),
+                }),
+            }),
+            queue_limit,
+            shed,
+        }
+    }
+
+    /// Limit `inner` as set by `SCCACHE_STORAGE_CONCURRENCY`,
+    /// `SCCACHE_STORAGE_QUEUE_LIMIT` and `SCCACHE_STORAGE_SHED`, if the first
+    /// is set.
+    pub fn from_env(inner: Arc<dyn Storage>) -> Arc<dyn Storage> {
+        fn var(name: &str) -> Option<usize> {
+            let value = env::var(name).ok()?;
+            match value.parse() {
+                Ok(n) if n > 0 => Some(n),
+                _ => {
+                    warn!("Invalid {} {:?}, ignoring it", name, value);
+                    None
+                }
+            }
+        }
+
+        let concurrency = match var("SCCACHE_STORAGE_CONCURRENCY") {
+            Some(concurrency) => concurrency,
+            None => return inner,
+        };
+        let shed = match env::var("SCCACHE_STORAGE_SHED").as_deref() {
+            Err(_) | Ok("all") => Shed::All,
+            Ok("writes") => Shed::Writes,
+            Ok(shed) => {
+                warn!("Invalid SCCACHE_STORAGE_SHED {:?}, shedding all", shed);
+                Shed::All
+            }
+        };
+        Arc::new(LimitedStorage::new(
+            inner,
+            concurrency,
+            var("SCCACHE_STORAGE_QUEUE_LIMIT"),
+            shed,
+        ))
+    }
+
+    /// Wait for a turn to use the storage, failing with `Overloaded` if the
+    /// operation is shed.
+    async fn acquire(&self, is_write: bool) -> Result<Permit> {
+        let rx = {
+            let mut state = self.limiter.state.lock().unwrap();
+            if state.active < self.limiter.concurrency {
+                state.active += 1;
+                return Ok(Permit(Some(self.limiter.clone())));
+            }
+            let may_shed = is_write || self.shed == Shed::All;
+            if may_shed
+                && self
+                    .queue_limit
+                    .map_or(false, |limit| state.waiting.len() >= limit)
+            {
+                return Err(Overloaded.into());
+            }
+            let (tx, rx) = oneshot::channel();
+            state.waiting.push_back(tx);
+            rx
+        };
+        rx.await
+            .map_err(|_| anyhow!("the storage limiter was dropped"))
+    }
+}
+
+struct Limiter {
+    concurrency: usize,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// How many operations are using the storage.
+    active: usize,
+    /// The operations waiting for a turn, including any since dropped.
+    waiting: VecDeque<oneshot::Sender<Permit>>,
+}
+
+/// A turn to use the storage, passed on to the next operation waiting when
+/// it's dropped.
+struct Permit(Option<Arc<Limiter>>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let limiter = match self.0.take() {
+            Some(limiter) => limiter,
+            None => return,
+        };
+        let mut state = limiter.state.lock().unwrap();
+        while let Some(tx) = state.waiting.pop_front() {
+            match tx.send(Permit(Some(limiter.clone()))) {
+                Ok(()) => return,
+                // The operation was dropped while it waited
+                Err(mut permit) => permit.0 = None,
+            }
+        }
+        state.active -= 1;
+    }
+}
+
+#[async_trait(?Send)]
+impl Storage for LimitedStorage {
+    async fn get(&self, key: &str) -> Result<Cache> {
+        let _permit = self.acquire(false).await?;
+        self.inner.get(key).await
+    }
+
+    async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration> {
+        let _permit = self.acquire(true).await?;
+        self.inner.put(key, entry).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.inner.delete(key).await
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+
+    async fn clear(&self, scope: ClearScope) -> Result<()> {
+        self.inner.clear(scope).await
+    }
+
+    fn local(&self) -> Option<Arc<dyn Storage>> {
+        self.inner.local()
+    }
+
+    fn location(&self) -> String {
+        self.inner.location()
+    }
+
+    fn kind(&self) -> &'static str {
+        self.inner.kind()
+    }
+
+    async fn current_size(&self) -> Result<Option<u64>> {
+        self.inner.current_size().await
+    }
+
+    async fn max_size(&self) -> Result<Option<u64>> {
+        self.inner.max_size().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::mock_storage::MockStorage;
+    use futures::executor::block_on;
+    use futures::{poll, FutureExt};
+
+    #[test]
+    fn test_limited_storage() {
+        let mock = Arc::new(MockStorage::new());
+        let (tx, rx) = oneshot::channel();
+        mock.next_get_future(rx.map(|res| res.map(|()| Cache::Miss).map_err(Error::from)));
+        mock.next_get(Ok(Cache::Miss));
+        let storage = LimitedStorage::new(mock, 1, Some(2), Shed::All);
+
+        block_on(async {
+            let mut first = storage.get("a");
+            assert!(poll!(&mut first).is_pending());
+            // Dropped while it waits, so its turn goes to the next
+            let mut dropped = storage.get("b");
+            assert!(poll!(&mut dropped).is_pending());
+            drop(dropped);
+            let mut second = storage.get("c");
+            assert!(poll!(&mut second).is_pending());
+            let shed = storage.get("d").await.unwrap_err();
+            assert!(shed.is::<Overloaded>());
+
+            tx.send(()).unwrap();
+            assert!(matches!(first.await, Ok(Cache::Miss)));
+            assert!(matches!(second.await, Ok(Cache::Miss)));
+            storage.put("e", CacheWrite::new()).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_limited_storage_sheds_writes() {
+        let mock = Arc::new(MockStorage::new());
+        let (tx, rx) = oneshot::channel();
+        mock.next_get_future(rx.map(|res| res.map(|()| Cache::Miss).map_err(Error::from)));
+        mock.next_get(Ok(Cache::Miss));
+        let storage = LimitedStorage::new(mock, 1, Some(0), Shed::Writes);
+
+        block_on(async {
+            let mut first = storage.get("a");
+            assert!(poll!(&mut first).is_pending());
+            let mut lookup = storage.get("b");
+            assert!(poll!(&mut lookup).is_pending());
+            let shed = storage.put("c", CacheWrite::new()).await.unwrap_err();
+            assert!(shed.is::<Overloaded>());
+
+            tx.send(()).unwrap();
+            assert!(matches!(first.await, Ok(Cache::Miss)));
+            assert!(matches!(lookup.await, Ok(Cache::Miss)));
+        });
+    }
+}
@@ -14,21 +14,41 @@
 // limitations under the License.
 
 use crate::cache::{Cache, CacheRead, CacheWrite, Storage};
+use crate::dns;
 use crate::errors::*;
 use crate::util::SpawnExt;
-use futures_03::executor::ThreadPool;
+use async_trait::async_trait;
+use futures::executor::ThreadPool;
 use memcached::client::Client;
 use memcached::proto::NoReplyOperation;
 use memcached::proto::Operation;
 use memcached::proto::ProtoType::Binary;
 use std::cell::RefCell;
 use std::io::Cursor;
+use std::net::SocketAddr;
 use std::time::{Duration, Instant};
 
 thread_local! {
     static CLIENT: RefCell<Option<Client>> = RefCell::default();
 }
 
+/// The `tcp://host:port` URL of a server with the host resolved as the
+/// `SCCACHE_DNS_*` variables say, if they do.
+fn resolve_server(url: &str) -> String {
+    let resolved = url.strip_prefix("tcp://").and_then(|addr| {
+        let colon = addr.rfind(':')?;
+        let port = addr[colon + 1..].parse().ok()?;
+        match dns::resolve_host(&addr[..colon]) {
+            Ok(ip) => ip.map(|ip| format!("tcp://{}", SocketAddr::new(ip, port))),
+            Err(e) => {
+                warn!("{:#}", e);
+                None
+            }
+        }
+    });
+    resolved.unwrap_or_else(|| url.to_owned())
+}
+
 #[derive(Clone)]
 pub struct MemcachedCache {
     url: String,
@@ -43,8 +63,11 @@ impl MemcachedCache {
         })
     }
 
-    fn parse(&self) -> Vec<(&str, usize)> {
-        self.url.split_whitespace().map(|w| (w, 1usize)).collect()
+    fn parse(&self) -> Vec<(String, usize)> {
+        self.url
+            .split_whitespace()
+            .map(|w| (resolve_server(w), 1usize))
+            .collect()
     }
 
     fn exec<U, F>(&self, f: F) -> U
@@ -55,7 +78,9 @@ impl MemcachedCache {
             match *rc.borrow_mut() {
                 ref mut opt @ Some(_) => opt,
                 ref mut opt @ None => {
-                    *opt = Some(Client::connect(&self.parse(), Binary).unwrap());
+                    let servers = self.parse();
+                    let servers: Vec<_> = servers.iter().map(|(s, w)| (s.as_str(), *w)).collect();
+                    *opt = Some(Client::connect(&servers, Binary).unwrap());
                     opt
                 }
             }
@@ -66,36 +91,56 @@ impl MemcachedCache {
     }
 }
 
+#[async_trait(?Send)]
 impl Storage for MemcachedCache {
-    fn get(&self, key: &str) -> SFuture<Cache> {
+    async fn get(&self, key: &str) -> Result<Cache> {
         let key = key.to_owned();
         let me = self.clone();
-        Box::new(self.pool.spawn_fn(move || {
-            me.exec(|c| c.get(&key.as_bytes()))
-                .map(|(d, _)| CacheRead::from(Cursor::new(d)).map(Cache::Hit))
-                .unwrap_or(Ok(Cache::Miss))
-        }))
+        self.pool
+            .spawn_fn(move || {
+                me.exec(|c| c.get(&key.as_bytes()))
+                    .map(|(d, _)| CacheRead::from(Cursor::new(d)).map(Cache::Hit))
+                    .unwrap_or(Ok(Cache::Miss))
+            })
+            .await
     }
 
-    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+    async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration> {
         let key = key.to_owned();
         let me = self.clone();
-        Box::new(self.pool.spawn_fn(move || {
-            let start = Instant::now();
-            let d = entry.finish()?;
-            me.exec(|c| c.set_noreply(&key.as_bytes(), &d, 0, 0))?;
-            Ok(start.elapsed())
-        }))
+        self.pool
+            .spawn_fn(move || {
+                let start = Instant::now();
+                let d = entry.finish()?;
+                me.exec(|c| c.set_noreply(&key.as_bytes(), &d, 0, 0))?;
+                Ok(start.elapsed())
+            })
+            .await
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let key = key.to_owned();
+        let me = self.clone();
+        self.pool
+            .spawn_fn(move || {
+                me.exec(|c| c.delete(&key.as_bytes()))?;
+                Ok(())
+            })
+            .await
     }
 
     fn location(&self) -> String {
         format!("Memcached: {}", self.url)
     }
 
-    fn current_size(&self) -> SFuture<Option<u64>> {
-        f_ok(None)
+    fn kind(&self) -> &'static str {
+        "memcached"
+    }
+
+    async fn current_size(&self) -> Result<Option<u64>> {
+        Ok(None)
     }
-    fn max_size(&self) -> SFuture<Option<u64>> {
-        f_ok(None)
+    async fn max_size(&self) -> Result<Option<u64>> {
+        Ok(None)
     }
 }
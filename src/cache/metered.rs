@@ -0,0 +1,303 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Counting the lookups and writes of each storage backend, and how long
+//! they took, so that the stats show which tier of layered storage is
+//! failing or slow.
+
+use crate::cache::{Cache, CacheWrite, ClearScope, Storage};
+use async_trait::async_trait;
+use futures::Future;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::errors::*;
+
+/// The outcomes of the operations of one storage backend.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct BackendStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// The count of lookups that failed.
+    pub errors: u64,
+    pub writes: u64,
+    pub write_errors: u64,
+    /// The total time lookups took, including those that failed.
+    pub read_duration: Duration,
+    /// The total time writes took, including those that failed.
+    pub write_duration: Duration,
+}
+
+impl BackendStats {
+    pub fn add(&mut self, other: &BackendStats) {
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.errors += other.errors;
+        self.writes += other.writes;
+        self.write_errors += other.write_errors;
+        self.read_duration += other.read_duration;
+        self.write_duration += other.write_duration;
+    }
+
+    /// The mean time a lookup took.
+    pub fn read_mean(&self) -> Option<Duration> {
+        mean(self.read_duration, self.hits + self.misses + self.errors)
+    }
+
+    /// The mean time a write took.
+    pub fn write_mean(&self) -> Option<Duration> {
+        mean(self.write_duration, self.writes + self.write_errors)
+    }
+}
+
+fn mean(total: Duration, count: u64) -> Option<Duration> {
+    if count == 0 {
+        None
+    } else {
+        Some(Duration::from_secs_f64(total.as_secs_f64() / count as f64))
+    }
+}
+
+/// The stats of one storage backend, counted without taking a lock, as its
+/// operations finish on any thread.
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    errors: AtomicU64,
+    writes: AtomicU64,
+    write_errors: AtomicU64,
+    /// In nanoseconds.
+    read_duration: AtomicU64,
+    /// In nanoseconds.
+    write_duration: AtomicU64,
+}
+
+impl Counters {
+    fn load(&self) -> BackendStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        BackendStats {
+            hits: load(&self.hits),
+            misses: load(&self.misses),
+            errors: load(&self.errors),
+            writes: load(&self.writes),
+            write_errors: load(&self.write_errors),
+            read_duration: Duration::from_nanos(load(&self.read_duration)),
+            write_duration: Duration::from_nanos(load(&self.write_duration)),
+        }
+    }
+
+    fn store(&self, stats: &BackendStats) {
+        let store = |counter: &AtomicU64, value| counter.store(value, Ordering::Relaxed);
+        store(&self.hits, stats.hits);
+        store(&self.misses, stats.misses);
+        store(&self.errors, stats.errors);
+        store(&self.writes, stats.writes);
+        store(&self.write_errors, stats.write_errors);
+        store(&self.read_duration, nanos(stats.read_duration));
+        store(&self.write_duration, nanos(stats.write_duration));
+    }
+}
+
+fn nanos(duration: Duration) -> u64 {
+    duration.as_nanos() as u64
+}
+
+fn add(counter: &AtomicU64, value: u64) {
+    counter.fetch_add(value, Ordering::Relaxed);
+}
+
+/// The stats of the storage backends, by their location, shared by the
+/// `Meter`s that count them. The lock is only taken to add a backend or
+/// read or reset them all, not to count an operation.
+#[derive(Clone, Default)]
+pub struct BackendMeters {
+    backends: Arc<Mutex<HashMap<String, Arc<Counters>>>>,
+}
+
+impl BackendMeters {
+    /// A meter for the backend at `location`.
+    pub fn meter(&self, location: String) -> Meter {
+        let mut backends = self.backends.lock().unwrap();
+        Meter {
+            counters: backends.entry(location).or_default().clone(),
+        }
+    }
+
+    /// The stats of each backend that has counted anything so far.
+    pub fn snapshot(&self) -> HashMap<String, BackendStats> {
+        let backends = self.backends.lock().unwrap();
+        backends
+            .iter()
+            .map(|(location, counters)| (location.clone(), counters.load()))
+            .filter(|(_, stats)| *stats != BackendStats::default())
+            .collect()
+    }
+
+    /// Start counting again from `backends`.
+    pub fn reset(&self, mut backends: HashMap<String, BackendStats>) {
+        let mut counters = self.backends.lock().unwrap();
+        // The meters counting them keep the counters of backends in use
+        for (location, counters) in counters.iter() {
+            counters.store(&backends.remove(location).unwrap_or_default());
+        }
+        for (location, stats) in backends {
+            let new = Counters::default();
+            new.store(&stats);
+            counters.insert(location, Arc::new(new));
+        }
+    }
+}
+
+/// Counts the operations of one storage backend.
+#[derive(Clone)]
+pub struct Meter {
+    counters: Arc<Counters>,
+}
+
+impl Meter {
+    /// Count the lookup `get`, as it finishes.
+    pub async fn get(&self, get: impl Future<Output = Result<Cache>>) -> Result<Cache> {
+        let (counters, start) = (&self.counters, Instant::now());
+        let res = get.await;
+        match res {
+            Ok(Cache::Hit(_)) => add(&counters.hits, 1),
+            Ok(_) => add(&counters.misses, 1),
+            Err(_) => add(&counters.errors, 1),
+        }
+        add(&counters.read_duration, nanos(start.elapsed()));
+        res
+    }
+
+    /// Count the write `put`, as it finishes.
+    pub async fn put<T>(&self, put: impl Future<Output = Result<T>>) -> Result<T> {
+        let (counters, start) = (&self.counters, Instant::now());
+        let res = put.await;
+        match res {
+            Ok(_) => add(&counters.writes, 1),
+            Err(_) => add(&counters.write_errors, 1),
+        }
+        add(&counters.write_duration, nanos(start.elapsed()));
+        res
+    }
+}
+
+/// A `Storage` that counts the lookups and writes of the storage it wraps.
+pub struct MeteredStorage {
+    inner: Arc<dyn Storage>,
+    meter: Meter,
+}
+
+impl MeteredStorage {
+    /// Count the operations of `inner` in `meters`, by its location.
+    pub fn wrap(inner: Arc<dyn Storage>, meters: &BackendMeters) -> Arc<dyn Storage> {
+        let meter = meters.meter(inner.location());
+        Arc::new(MeteredStorage { inner, meter })
+    }
+}
+
+#[async_trait(?Send)]
+impl Storage for MeteredStorage {
+    async fn get(&self, key: &str) -> Result<Cache> {
+        self.meter.get(self.inner.get(key)).await
+    }
+
+    async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration> {
+        // Writes refused by read-only storage never reach the backend
+        if self.inner.is_read_only() {
+            return self.inner.put(key, entry).await;
+        }
+        self.meter.put(self.inner.put(key, entry)).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.inner.delete(key).await
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+
+    async fn clear(&self, scope: ClearScope) -> Result<()> {
+        self.inner.clear(scope).await
+    }
+
+    fn local(&self) -> Option<Arc<dyn Storage>> {
+        self.inner.local()
+    }
+
+    fn location(&self) -> String {
+        self.inner.location()
+    }
+
+    fn kind(&self) -> &'static str {
+        self.inner.kind()
+    }
+
+    async fn current_size(&self) -> Result<Option<u64>> {
+        self.inner.current_size().await
+    }
+
+    async fn max_size(&self) -> Result<Option<u64>> {
+        self.inner.max_size().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::mock_storage::MockStorage;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_metered_storage() {
+        let meters = BackendMeters::default();
+        let inner = Arc::new(MockStorage::new());
+        let storage = MeteredStorage::wrap(inner.clone(), &meters);
+        let location = storage.location();
+        inner.next_get(Ok(Cache::Miss));
+        inner.next_get(Err(anyhow!("broken")));
+        assert!(matches!(block_on(storage.get("aaaa")), Ok(Cache::Miss)));
+        assert!(block_on(storage.get("bbbb")).is_err());
+        block_on(storage.put("cccc", CacheWrite::new())).unwrap();
+
+        let backends = meters.snapshot();
+        let stats = &backends[&location];
+        assert_eq!(
+            (stats.hits, stats.misses, stats.errors, stats.writes),
+            (0, 1, 1, 1)
+        );
+        assert!(stats.read_mean().is_some());
+        meters.reset(HashMap::new());
+        assert!(meters.snapshot().is_empty());
+
+        // Restored stats are counted on from
+        let mut saved = HashMap::new();
+        saved.insert(
+            location.clone(),
+            BackendStats {
+                hits: 2,
+                ..Default::default()
+            },
+        );
+        meters.reset(saved);
+        inner.next_get(Ok(Cache::Miss));
+        block_on(storage.get("dddd")).unwrap();
+        let stats = &meters.snapshot()[&location];
+        assert_eq!((stats.hits, stats.misses), (2, 1));
+    }
+}
@@ -0,0 +1,237 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `sccache --migrate-cache`: bringing the entries of the local disk cache
+//! up to date with this version of sccache.
+//!
+//! Entries recording an older version of the hashing of their kind of
+//! compiler than the current one can't be looked up any more, and are
+//! removed, as are those that can't be read. Those in the zip format are
+//! rewritten in the current one. Entries written before the version of the
+//! hashing was recorded are kept, as there's no telling whether their keys
+//! are still computed the same.
+
+use crate::cache::{is_newer_format, CacheRead, CacheWrite, ZIP_ENTRY_VERSION};
+use crate::compiler::cache_schemas;
+use filetime::FileTime;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+use walkdir::WalkDir;
+
+use crate::errors::*;
+
+/// What migrating the entries of a cache did.
+#[derive(Debug, Default, PartialEq)]
+pub struct Migration {
+    /// The count of entries by their version, as `CacheRead::version`
+    /// describes it, before they were migrated.
+    pub versions: BTreeMap<String, u64>,
+    /// The count of entries left as they were.
+    pub kept: u64,
+    /// The count of entries rewritten in the current format.
+    pub rewritten: u64,
+    /// The count of entries removed.
+    pub pruned: u64,
+}
+
+/// What is done to an entry.
+#[derive(Debug, PartialEq)]
+enum Action {
+    Keep,
+    Rewrite,
+    Prune,
+}
+
+/// The kind of compiler and the version of a schema, as `cache_schema`
+/// names it.
+fn parse_schema(schema: &str) -> Option<(&str, u64)> {
+    let mut parts = schema.rsplitn(2, '-');
+    let version = parts.next()?.parse().ok()?;
+    Some((parts.next()?, version))
+}
+
+/// What is done to an entry recording `schema`, if any, in format `format`,
+/// when the current schemas are `schemas`. Entries of later versions of
+/// sccache are left for them.
+fn action(schema: Option<&str>, format: u8, schemas: &[String]) -> Action {
+    if let Some((kind, version)) = schema.and_then(parse_schema) {
+        let outdated = schemas
+            .iter()
+            .filter_map(|current| parse_schema(current))
+            .any(|(current_kind, current)| current_kind == kind && version < current);
+        if outdated {
+            return Action::Prune;
+        }
+    }
+    if format == ZIP_ENTRY_VERSION {
+        Action::Rewrite
+    } else {
+        Action::Keep
+    }
+}
+
+/// Replace the entry at `path` with `bytes`, keeping its modification time
+/// so that it keeps its place in the order entries are evicted in.
+fn replace_entry(path: &Path, bytes: &[u8]) -> Result<()> {
+    let mtime = FileTime::from_last_modification_time(&fs::metadata(path)?);
+    let tmp = path.with_extension("migrating");
+    fs::write(&tmp, bytes)?;
+    filetime::set_file_mtime(&tmp, mtime)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Migrate the entry at `path`, counting it in `migration`.
+fn migrate_entry(path: &Path, schemas: &[String], migration: &mut Migration) -> Result<()> {
+    let bytes = fs::read(path)?;
+    if is_newer_format(&bytes) {
+        *migration
+            .versions
+            .entry("newer format".to_owned())
+            .or_insert(0) += 1;
+        migration.kept += 1;
+        return Ok(());
+    }
+    let mut entry = match CacheRead::from(Cursor::new(bytes)) {
+        Ok(entry) => entry,
+        Err(e) => {
+            debug!(
+                "Removing unreadable cache entry {}: {:#}",
+                path.display(),
+                e
+            );
+            *migration
+                .versions
+                .entry("unreadable".to_owned())
+                .or_insert(0) += 1;
+            migration.pruned += 1;
+            return Ok(fs::remove_file(path)?);
+        }
+    };
+    *migration.versions.entry(entry.version()).or_insert(0) += 1;
+    match action(entry.schema().as_deref(), entry.format(), schemas) {
+        Action::Keep => migration.kept += 1,
+        Action::Rewrite => {
+            let bytes = CacheWrite::from_bytes(entry.into_bytes()?)?.finish()?;
+            replace_entry(path, &bytes)?;
+            migration.rewritten += 1;
+        }
+        Action::Prune => {
+            fs::remove_file(path)?;
+            migration.pruned += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Migrate the entries of the disk cache in `dir`, which the server must
+/// not be using.
+pub fn migrate_disk_cache(dir: &Path) -> Result<Migration> {
+    let schemas = cache_schemas();
+    let mut migration = Migration::default();
+    // Entries are stored two directories down, as `DiskCache` puts them
+    for file in WalkDir::new(dir).min_depth(3).max_depth(3) {
+        let file = file.with_context(|| format!("failed to read {}", dir.display()))?;
+        if !file.file_type().is_file() {
+            continue;
+        }
+        migrate_entry(file.path(), &schemas, &mut migration)
+            .with_context(|| format!("failed to migrate {}", file.path().display()))?;
+    }
+    Ok(migration)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+    use zip::write::FileOptions;
+    use zip::{CompressionMethod, ZipWriter};
+
+    #[test]
+    fn test_action() {
+        let schemas = vec!["c-11".to_owned(), "rust-7".to_owned()];
+        assert_eq!(action(Some("c-11"), 1, &schemas), Action::Keep);
+        assert_eq!(action(Some("c-10"), 1, &schemas), Action::Prune);
+        assert_eq!(action(Some("rust-6"), 0, &schemas), Action::Prune);
+        assert_eq!(action(Some("c-12"), 2, &schemas), Action::Keep);
+        assert_eq!(action(Some("other-1"), 1, &schemas), Action::Keep);
+        assert_eq!(action(None, 1, &schemas), Action::Keep);
+        assert_eq!(action(None, 0, &schemas), Action::Rewrite);
+        assert_eq!(action(Some("c-11"), 0, &schemas), Action::Rewrite);
+    }
+
+    #[test]
+    fn test_migrate_disk_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let put = |key: &str, bytes: &[u8]| {
+            let path = dir.path().join(&key[..1]).join(&key[1..2]).join(key);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, bytes).unwrap();
+            path
+        };
+        let entry = |schema: Option<&str>| {
+            let mut entry = CacheWrite::new();
+            entry.put_stdout(b"out").unwrap();
+            if let Some(schema) = schema {
+                entry.put_schema(schema).unwrap();
+            }
+            entry.finish().unwrap()
+        };
+        let current = cache_schemas().remove(0);
+        let (kind, version) = parse_schema(&current).unwrap();
+        let old = format!("{}-{}", kind, version - 1);
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        let opts = FileOptions::default().compression_method(CompressionMethod::Stored);
+        zip.start_file("stdout", opts).unwrap();
+        zip.write_all(b"zipped").unwrap();
+        let zipped = zip.finish().unwrap().into_inner();
+
+        let current_path = put("aa01", &entry(Some(&current)));
+        let old_path = put("ab02", &entry(Some(&old)));
+        let unversioned_path = put("ba03", &entry(None));
+        let zip_path = put("bb04", &zipped);
+        let garbage_path = put("cc05", b"garbage");
+        let mtime = FileTime::from_unix_time(1_000_000, 0);
+        filetime::set_file_mtime(&zip_path, mtime).unwrap();
+
+        let migration = migrate_disk_cache(dir.path()).unwrap();
+        assert_eq!(
+            (migration.kept, migration.rewritten, migration.pruned),
+            (2, 1, 2)
+        );
+        let versions: Vec<_> = migration.versions.into_iter().collect();
+        let mut expected = vec![
+            (format!("{}, format 1", current), 1),
+            (format!("{}, format 1", old), 1),
+            ("unreadable".to_owned(), 1),
+            ("unversioned, format 0".to_owned(), 1),
+            ("unversioned, format 1".to_owned(), 1),
+        ];
+        expected.sort();
+        assert_eq!(versions, expected);
+
+        assert!(current_path.exists());
+        assert!(unversioned_path.exists());
+        assert!(!old_path.exists());
+        assert!(!garbage_path.exists());
+        let mut rewritten = CacheRead::from(fs::File::open(&zip_path).unwrap()).unwrap();
+        assert_eq!(rewritten.format(), 1);
+        assert_eq!(rewritten.get_stdout(), b"zipped");
+        let metadata = fs::metadata(&zip_path).unwrap();
+        assert_eq!(FileTime::from_last_modification_time(&metadata), mtime);
+    }
+}
@@ -12,18 +12,27 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod audit;
 #[cfg(feature = "azure")]
 pub mod azure;
 #[allow(clippy::module_inception)]
 pub mod cache;
 pub mod disk;
+pub mod encrypted;
 #[cfg(feature = "gcs")]
 pub mod gcs;
+pub mod layered;
+pub mod limited;
 #[cfg(feature = "memcached")]
 pub mod memcached;
+pub mod metered;
+pub mod migrate;
+#[cfg(any(feature = "s3", feature = "gcs"))]
+pub mod ranged;
 #[cfg(feature = "redis")]
 pub mod redis;
 #[cfg(feature = "s3")]
 pub mod s3;
+pub mod spill;
 
 pub use crate::cache::cache::*;
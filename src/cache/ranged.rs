@@ -0,0 +1,211 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Downloading large cache entries in ranges over several connections at
+//! once, rather than one, whose throughput is often limited by the storage.
+
+use crate::config::parse_size;
+use futures::{stream, StreamExt, TryStreamExt};
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_RANGE, RANGE};
+use hyper::StatusCode;
+use reqwest::{Client, Request};
+use std::cmp;
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::errors::*;
+
+/// Entries are downloaded in ranges of this size, unless
+/// `SCCACHE_DOWNLOAD_CHUNK_SIZE` is set.
+const DEFAULT_CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// How many ranges of an entry are downloaded at once, unless
+/// `SCCACHE_DOWNLOAD_CONNECTIONS` is set.
+const DEFAULT_CONNECTIONS: usize = 4;
+
+struct Settings {
+    chunk_size: u64,
+    connections: usize,
+    /// The bytes per second all downloads share, if limited.
+    bandwidth: Option<u64>,
+}
+
+impl Settings {
+    fn from_env() -> Settings {
+        fn size(name: &str) -> Option<u64> {
+            let value = env::var(name).ok()?;
+            match parse_size(&value) {
+                Some(size) if size > 0 => Some(size),
+                _ => {
+                    warn!("Invalid {} {:?}, ignoring it", name, value);
+                    None
+                }
+            }
+        }
+
+        let connections =
+            env::var("SCCACHE_DOWNLOAD_CONNECTIONS")
+                .ok()
+                .and_then(|value| match value.parse() {
+                    Ok(n) if n > 0 => Some(n),
+                    _ => {
+                        warn!(
+                            "Invalid SCCACHE_DOWNLOAD_CONNECTIONS {:?}, ignoring it",
+                            value
+                        );
+                        None
+                    }
+                });
+        Settings {
+            chunk_size: size("SCCACHE_DOWNLOAD_CHUNK_SIZE").unwrap_or(DEFAULT_CHUNK_SIZE),
+            connections: connections.unwrap_or(DEFAULT_CONNECTIONS),
+            bandwidth: size("SCCACHE_DOWNLOAD_BANDWIDTH"),
+        }
+    }
+}
+
+lazy_static! {
+    static ref SETTINGS: Settings = Settings::from_env();
+    /// When the next range may be requested, to keep all downloads within
+    /// the bandwidth.
+    static ref NEXT_REQUEST: Mutex<Instant> = Mutex::new(Instant::now());
+}
+
+/// Download the object `request` gets, in ranges over several connections
+/// if it's bigger than one. `request` makes a new request for it each time
+/// it's called.
+pub async fn download<F>(client: &Client, request: F) -> Result<Vec<u8>>
+where
+    F: Fn() -> Request,
+{
+    let chunk_size = SETTINGS.chunk_size;
+    let (mut bytes, len) = fetch(client, request(), (0, chunk_size)).await?;
+    let ranges = match len {
+        Some(len) => ranges(len, chunk_size),
+        // The storage sent all of it
+        None => return Ok(bytes),
+    };
+    let mut chunks = stream::iter(ranges)
+        .map(|range| fetch(client, request(), range))
+        .buffered(SETTINGS.connections);
+    while let Some((chunk, _)) = chunks.try_next().await? {
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+/// The ranges of an object of `len` bytes left to download after the
+/// first.
+fn ranges(len: u64, chunk_size: u64) -> Vec<(u64, u64)> {
+    (chunk_size..len)
+        .step_by(chunk_size as usize)
+        .map(|start| (start, cmp::min(start + chunk_size, len)))
+        .collect()
+}
+
+/// Send `request` for the range `start..end` of its object, and read the
+/// bytes of it sent, with the length of the object if they're only that
+/// range of it.
+async fn fetch(
+    client: &Client,
+    mut request: Request,
+    (start, end): (u64, u64),
+) -> Result<(Vec<u8>, Option<u64>)> {
+    let range = format!("bytes={}-{}", start, end - 1);
+    request
+        .headers_mut()
+        .insert(RANGE, HeaderValue::from_str(&range).unwrap());
+    let url = request.url().to_string();
+    throttle(end - start).await;
+    let res = client
+        .execute(request)
+        .await
+        .with_context(|| format!("failed GET: {}", url))?;
+    let len = match res.status() {
+        StatusCode::PARTIAL_CONTENT => Some(
+            object_len(res.headers()).with_context(|| format!("Bad Content-Range from {}", url))?,
+        ),
+        status if status.is_success() => None,
+        status => return Err(BadHttpStatusError(status).into()),
+    };
+    let bytes = res.bytes().await.context("failed to read HTTP body")?;
+    if let Some(len) = len {
+        let expected = cmp::min(end, len).saturating_sub(start);
+        if bytes.len() as u64 != expected {
+            bail!(
+                "Bad HTTP body size read from {}: {}, expected {}",
+                url,
+                bytes.len(),
+                expected
+            );
+        }
+    }
+    Ok((bytes.to_vec(), len))
+}
+
+/// The length of the whole object in the `Content-Range` of a response
+/// with part of it, such as `bytes 0-1023/4096`.
+fn object_len(headers: &HeaderMap) -> Result<u64> {
+    let value = headers
+        .get(CONTENT_RANGE)
+        .ok_or_else(|| anyhow!("missing header"))?
+        .to_str()?;
+    let len = value
+        .strip_prefix("bytes ")
+        .and_then(|range| range.rsplit('/').next())
+        .and_then(|len| len.parse().ok());
+    len.ok_or_else(|| anyhow!("can't parse {:?}", value))
+}
+
+/// Wait until `len` more bytes can be requested within the bandwidth, if
+/// it's limited.
+async fn throttle(len: u64) {
+    let bandwidth = match SETTINGS.bandwidth {
+        Some(bandwidth) => bandwidth,
+        None => return,
+    };
+    let now = Instant::now();
+    let start = {
+        let mut next = NEXT_REQUEST.lock().unwrap();
+        let start = cmp::max(*next, now);
+        *next = start + Duration::from_secs_f64(len as f64 / bandwidth as f64);
+        start
+    };
+    if start > now {
+        tokio::time::sleep_until(start.into()).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ranges() {
+        assert_eq!(ranges(10, 10), Vec::<(u64, u64)>::new());
+        assert_eq!(ranges(25, 10), vec![(10, 20), (20, 25)]);
+        assert_eq!(ranges(30, 10), vec![(10, 20), (20, 30)]);
+    }
+
+    #[test]
+    fn test_object_len() {
+        let mut headers = HeaderMap::new();
+        assert!(object_len(&headers).is_err());
+        headers.insert(CONTENT_RANGE, HeaderValue::from_static("bytes 0-1023/4096"));
+        assert_eq!(object_len(&headers).unwrap(), 4096);
+        headers.insert(CONTENT_RANGE, HeaderValue::from_static("bytes 0-1023/*"));
+        assert!(object_len(&headers).is_err());
+    }
+}
@@ -13,11 +13,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::cache::{Cache, CacheRead, CacheWrite, Storage};
+use crate::cache::{Cache, CacheRead, CacheWrite, ClearScope, Storage};
+use crate::dns;
 use crate::errors::*;
-use futures_03::prelude::*;
+use async_trait::async_trait;
 use redis::aio::Connection;
-use redis::{cmd, Client, InfoDict};
+use redis::{cmd, Client, ConnectionAddr, ConnectionInfo, InfoDict, IntoConnectionInfo};
 use std::collections::HashMap;
 use std::io::Cursor;
 use std::time::{Duration, Instant};
@@ -26,57 +27,78 @@ use std::time::{Duration, Instant};
 #[derive(Clone)]
 pub struct RedisCache {
     url: String,
-    client: Client,
+    info: ConnectionInfo,
 }
 
 impl RedisCache {
-    /// Create a new `RedisCache`.
-    pub fn new(url: &str) -> Result<RedisCache> {
+    /// Create a new `RedisCache`, connecting with `password` if it's given.
+    pub fn new(url: &str, password: Option<&str>) -> Result<RedisCache> {
+        let mut info = url.into_connection_info()?;
+        if let Some(password) = password {
+            info.redis.password = Some(password.to_owned());
+        }
+        // Check the URL now rather than when connecting
+        Client::open(info.clone())?;
         Ok(RedisCache {
             url: url.to_owned(),
-            client: Client::open(url)?,
+            info,
         })
     }
 
     /// Returns a connection with configured read and write timeouts.
-    async fn connect(self) -> Result<Connection> {
-        Ok(self.client.get_async_connection().await?)
+    async fn connect(&self) -> Result<Connection> {
+        let mut info = self.info.clone();
+        // Connections with TLS need the host name to check the certificate
+        if let ConnectionAddr::Tcp(ref mut host, _) = info.addr {
+            if let Some(ip) = dns::resolve_host(host)? {
+                *host = ip.to_string();
+            }
+        }
+        Ok(Client::open(info)?.get_async_connection().await?)
     }
 }
 
+#[async_trait(?Send)]
 impl Storage for RedisCache {
     /// Open a connection and query for a key.
-    fn get(&self, key: &str) -> SFuture<Cache> {
-        let key = key.to_owned();
-        let me = self.clone();
-        Box::new(
-            Box::pin(async move {
-                let mut c = me.connect().await?;
-                let d: Vec<u8> = cmd("GET").arg(key).query_async(&mut c).await?;
-                if d.is_empty() {
-                    Ok(Cache::Miss)
-                } else {
-                    CacheRead::from(Cursor::new(d)).map(Cache::Hit)
-                }
-            })
-            .compat(),
-        )
+    async fn get(&self, key: &str) -> Result<Cache> {
+        let mut c = self.connect().await?;
+        let d: Vec<u8> = cmd("GET").arg(key).query_async(&mut c).await?;
+        if d.is_empty() {
+            Ok(Cache::Miss)
+        } else {
+            CacheRead::from(Cursor::new(d)).map(Cache::Hit)
+        }
     }
 
     /// Open a connection and store a object in the cache.
-    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
-        let key = key.to_owned();
-        let me = self.clone();
+    async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration> {
         let start = Instant::now();
-        Box::new(
-            Box::pin(async move {
-                let mut c = me.connect().await?;
-                let d = entry.finish()?;
-                cmd("SET").arg(key).arg(d).query_async(&mut c).await?;
-                Ok(start.elapsed())
-            })
-            .compat(),
-        )
+        let mut c = self.connect().await?;
+        let d = entry.finish()?;
+        cmd("SET")
+            .arg(key)
+            .arg(d)
+            .query_async::<_, ()>(&mut c)
+            .await?;
+        Ok(start.elapsed())
+    }
+
+    /// Open a connection and remove a object from the cache.
+    async fn delete(&self, key: &str) -> Result<()> {
+        let mut c = self.connect().await?;
+        cmd("DEL").arg(key).query_async::<_, ()>(&mut c).await?;
+        Ok(())
+    }
+
+    /// Open a connection and remove every key of the database in the URL.
+    async fn clear(&self, scope: ClearScope) -> Result<()> {
+        if !scope.remote() {
+            return Ok(());
+        }
+        let mut c = self.connect().await?;
+        cmd("FLUSHDB").query_async::<_, ()>(&mut c).await?;
+        Ok(())
     }
 
     /// Returns the cache location.
@@ -84,42 +106,33 @@ impl Storage for RedisCache {
         format!("Redis: {}", self.url)
     }
 
+    fn kind(&self) -> &'static str {
+        "redis"
+    }
+
     /// Returns the current cache size. This value is aquired via
     /// the Redis INFO command (used_memory).
-    fn current_size(&self) -> SFuture<Option<u64>> {
-        let me = self.clone(); // TODO Remove clone
-        Box::new(
-            Box::pin(async move {
-                let mut c = me.connect().await?;
-                let v: InfoDict = cmd("INFO").query_async(&mut c).await?;
-                Ok(v.get("used_memory"))
-            })
-            .compat(),
-        )
+    async fn current_size(&self) -> Result<Option<u64>> {
+        let mut c = self.connect().await?;
+        let v: InfoDict = cmd("INFO").query_async(&mut c).await?;
+        Ok(v.get("used_memory"))
     }
 
     /// Returns the maximum cache size. This value is read via
     /// the Redis CONFIG command (maxmemory). If the server has no
     /// configured limit, the result is None.
-    fn max_size(&self) -> SFuture<Option<u64>> {
-        let me = self.clone(); // TODO Remove clone
-        Box::new(
-            Box::pin(async move {
-                let mut c = me.connect().await?;
-                let result: redis::RedisResult<HashMap<String, usize>> = cmd("CONFIG")
-                    .arg("GET")
-                    .arg("maxmemory")
-                    .query_async(&mut c)
-                    .await;
-                match result {
-                    Ok(h) => {
-                        Ok(h.get("maxmemory")
-                            .and_then(|&s| if s != 0 { Some(s as u64) } else { None }))
-                    }
-                    Err(_) => Ok(None),
-                }
-            })
-            .compat(),
-        )
+    async fn max_size(&self) -> Result<Option<u64>> {
+        let mut c = self.connect().await?;
+        let result: redis::RedisResult<HashMap<String, usize>> = cmd("CONFIG")
+            .arg("GET")
+            .arg("maxmemory")
+            .query_async(&mut c)
+            .await;
+        match result {
+            Ok(h) => Ok(h
+                .get("maxmemory")
+                .and_then(|&s| if s != 0 { Some(s as u64) } else { None })),
+            Err(_) => Ok(None),
+        }
     }
 }
@@ -12,13 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::cache::{Cache, CacheRead, CacheWrite, Storage};
+use crate::cache::{Cache, CacheRead, CacheWrite, ClearScope, Storage};
 use crate::simples3::{
     AutoRefreshingProvider, Bucket, ChainProvider, ProfileProvider, ProvideAwsCredentials, Ssl,
 };
+use async_trait::async_trait;
 use directories::UserDirs;
-use futures::future;
-use futures::future::Future;
+use futures::stream::{self, StreamExt, TryStreamExt};
 use std::io;
 use std::rc::Rc;
 use std::time::{Duration, Instant};
@@ -71,11 +71,19 @@ impl S3Cache {
     }
 }
 
+#[async_trait(?Send)]
 impl Storage for S3Cache {
-    fn get(&self, key: &str) -> SFuture<Cache> {
+    async fn get(&self, key: &str) -> Result<Cache> {
         let key = self.normalize_key(key);
 
-        let result_cb = |result| match result {
+        let result = match self.provider.credentials().await {
+            Ok(creds) => self.bucket.get(&key, Some(&creds)).await,
+            Err(e) => {
+                debug!("Could not load AWS creds: {}", e);
+                self.bucket.get(&key, None).await
+            }
+        };
+        match result {
             Ok(data) => {
                 let hit = CacheRead::from(io::Cursor::new(data))?;
                 Ok(Cache::Hit(hit))
@@ -84,53 +92,99 @@ impl Storage for S3Cache {
                 warn!("Got AWS error: {:?}", e);
                 Ok(Cache::Miss)
             }
-        };
+        }
+    }
 
-        let bucket = self.bucket.clone();
-        let response = self
+    async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration> {
+        let key = self.normalize_key(&key);
+        let start = Instant::now();
+        let data = entry.finish()?;
+        let credentials = self
             .provider
             .credentials()
-            .then(move |credentials| match credentials {
-                Ok(creds) => bucket.get(&key, Some(&creds)),
-                Err(e) => {
-                    debug!("Could not load AWS creds: {}", e);
-                    bucket.get(&key, None)
-                }
-            })
-            .then(result_cb);
-        Box::new(response)
+            .await
+            .context("failed to get AWS credentials")?;
+
+        self.bucket
+            .put(&key, data, &credentials)
+            .await
+            .context("failed to put cache entry in s3")?;
+
+        Ok(start.elapsed())
     }
 
-    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+    async fn delete(&self, key: &str) -> Result<()> {
         let key = self.normalize_key(&key);
-        let start = Instant::now();
-        let data = match entry.finish() {
-            Ok(data) => data,
-            Err(e) => return f_err(e),
-        };
         let credentials = self
             .provider
             .credentials()
-            .fcontext("failed to get AWS credentials");
+            .await
+            .context("failed to get AWS credentials")?;
 
-        let bucket = self.bucket.clone();
-        let response = credentials.and_then(move |credentials| {
-            bucket
-                .put(&key, data, &credentials)
-                .fcontext("failed to put cache entry in s3")
-        });
+        self.bucket
+            .delete(&key, &credentials)
+            .await
+            .context("failed to delete cache entry from s3")
+    }
 
-        Box::new(response.map(move |_| start.elapsed()))
+    /// Delete every entry under the key prefix, a page of keys at a time.
+    /// Without a prefix, the entries can't be told apart from other objects
+    /// in the bucket, so it fails.
+    async fn clear(&self, scope: ClearScope) -> Result<()> {
+        if !scope.remote() {
+            return Ok(());
+        }
+        if self.key_prefix.is_empty() {
+            bail!(
+                "{} can only be cleared with a key prefix, set with SCCACHE_S3_KEY_PREFIX",
+                self.location()
+            );
+        }
+        let credentials = self
+            .provider
+            .credentials()
+            .await
+            .context("failed to get AWS credentials")?;
+        let mut marker: Option<String> = None;
+        loop {
+            let (keys, more) = self
+                .bucket
+                .list(&self.key_prefix, marker.as_deref(), &credentials)
+                .await
+                .context("failed to list cache entries in s3")?;
+            let next = keys.last().cloned();
+            stream::iter(keys)
+                .map(|key| {
+                    let (bucket, credentials) = (&self.bucket, &credentials);
+                    async move {
+                        bucket
+                            .delete(&key, credentials)
+                            .await
+                            .context("failed to delete cache entry from s3")
+                    }
+                })
+                .buffer_unordered(16)
+                .try_collect::<()>()
+                .await?;
+            match next {
+                Some(next) if more => marker = Some(next),
+                _ => return Ok(()),
+            }
+        }
     }
 
     fn location(&self) -> String {
         format!("S3, bucket: {}", self.bucket)
     }
 
-    fn current_size(&self) -> SFuture<Option<u64>> {
-        Box::new(future::ok(None))
+    fn kind(&self) -> &'static str {
+        "s3"
+    }
+
+    async fn current_size(&self) -> Result<Option<u64>> {
+        Ok(None)
     }
-    fn max_size(&self) -> SFuture<Option<u64>> {
-        Box::new(future::ok(None))
+    async fn max_size(&self) -> Result<Option<u64>> {
+        Ok(None)
     }
 }
@@ -0,0 +1,242 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Buffers for cache entries being written, kept in memory as long as all of
+//! them fit in `SCCACHE_ENTRY_MEMORY_LIMIT` and in temporary files beyond it.
+
+use crate::config::parse_size;
+use std::env;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+lazy_static! {
+    static ref ENTRY_BUDGET: Arc<Budget> = Arc::new(Budget::new(entry_memory_limit()));
+}
+
+fn entry_memory_limit() -> Option<u64> {
+    let value = env::var("SCCACHE_ENTRY_MEMORY_LIMIT").ok()?;
+    let limit = parse_size(&value);
+    if limit.is_none() {
+        warn!(
+            "Invalid SCCACHE_ENTRY_MEMORY_LIMIT {:?}, ignoring it",
+            value
+        );
+    }
+    limit
+}
+
+/// How many bytes the buffers sharing it may hold in memory, if limited.
+pub struct Budget {
+    limit: Option<u64>,
+    used: AtomicU64,
+}
+
+impl Budget {
+    pub fn new(limit: Option<u64>) -> Budget {
+        Budget {
+            limit,
+            used: AtomicU64::new(0),
+        }
+    }
+
+    /// Take `len` more bytes, unless that would go over the limit.
+    fn reserve(&self, len: u64) -> bool {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return true,
+        };
+        let mut used = self.used.load(Ordering::SeqCst);
+        loop {
+            if used + len > limit {
+                return false;
+            }
+            match self
+                .used
+                .compare_exchange(used, used + len, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => return true,
+                Err(actual) => used = actual,
+            }
+        }
+    }
+
+    fn release(&self, len: u64) {
+        if self.limit.is_some() {
+            self.used.fetch_sub(len, Ordering::SeqCst);
+        }
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::SeqCst)
+    }
+}
+
+enum Contents {
+    Memory(Cursor<Vec<u8>>),
+    File(File),
+}
+
+/// A buffer kept in memory until it would take more than is left of its
+/// budget, then moved to an anonymous temporary file.
+pub struct SpillBuffer {
+    contents: Contents,
+    budget: Arc<Budget>,
+    /// The bytes taken from the budget while in memory.
+    reserved: u64,
+}
+
+impl SpillBuffer {
+    /// A buffer within the budget shared by all cache entries being written.
+    pub fn new() -> SpillBuffer {
+        SpillBuffer::with_budget(ENTRY_BUDGET.clone())
+    }
+
+    pub fn with_budget(budget: Arc<Budget>) -> SpillBuffer {
+        SpillBuffer {
+            contents: Contents::Memory(Cursor::new(vec![])),
+            budget,
+            reserved: 0,
+        }
+    }
+
+    pub fn is_spilled(&self) -> bool {
+        matches!(self.contents, Contents::File(_))
+    }
+
+    /// Make room for the buffer to be `len` bytes long, moving it to a file
+    /// if the budget doesn't have it.
+    fn grow(&mut self, len: u64) -> io::Result<()> {
+        let cursor = match self.contents {
+            Contents::Memory(ref mut cursor) if len > self.reserved => cursor,
+            _ => return Ok(()),
+        };
+        if self.budget.reserve(len - self.reserved) {
+            self.reserved = len;
+            return Ok(());
+        }
+        debug!(
+            "Cache entry of {} bytes is over the memory limit, moving it to a file",
+            len
+        );
+        let mut file = tempfile::tempfile()?;
+        file.write_all(cursor.get_ref())?;
+        file.seek(SeekFrom::Start(cursor.position()))?;
+        self.contents = Contents::File(file);
+        self.budget.release(self.reserved);
+        self.reserved = 0;
+        Ok(())
+    }
+
+    /// The contents of the buffer, read back from its file if it has one.
+    pub fn into_bytes(mut self) -> io::Result<Vec<u8>> {
+        match self.contents {
+            Contents::Memory(ref mut cursor) => Ok(std::mem::take(cursor.get_mut())),
+            Contents::File(ref mut file) => {
+                let mut bytes = vec![];
+                file.seek(SeekFrom::Start(0))?;
+                file.read_to_end(&mut bytes)?;
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+impl Default for SpillBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for SpillBuffer {
+    fn drop(&mut self) {
+        self.budget.release(self.reserved);
+    }
+}
+
+impl Write for SpillBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Contents::Memory(ref cursor) = self.contents {
+            let end = cursor.position() + buf.len() as u64;
+            let len = end.max(cursor.get_ref().len() as u64);
+            self.grow(len)?;
+        }
+        match self.contents {
+            Contents::Memory(ref mut cursor) => cursor.write(buf),
+            Contents::File(ref mut file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.contents {
+            Contents::Memory(_) => Ok(()),
+            Contents::File(ref mut file) => file.flush(),
+        }
+    }
+}
+
+impl Read for SpillBuffer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.contents {
+            Contents::Memory(ref mut cursor) => cursor.read(buf),
+            Contents::File(ref mut file) => file.read(buf),
+        }
+    }
+}
+
+impl Seek for SpillBuffer {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self.contents {
+            Contents::Memory(ref mut cursor) => cursor.seek(pos),
+            Contents::File(ref mut file) => file.seek(pos),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_spill_buffer() {
+        let budget = Arc::new(Budget::new(Some(10)));
+        let mut small = SpillBuffer::with_budget(budget.clone());
+        small.write_all(b"hello").unwrap();
+        assert!(!small.is_spilled());
+        assert_eq!(budget.used(), 5);
+
+        // Only what's left of the budget can be kept in memory
+        let mut large = SpillBuffer::with_budget(budget.clone());
+        large.write_all(b"hello").unwrap();
+        large.write_all(b" world").unwrap();
+        assert!(large.is_spilled());
+        assert_eq!(budget.used(), 5);
+
+        // Overwriting doesn't take more of the budget, and seeks are kept
+        // when the buffer is moved to a file
+        small.seek(SeekFrom::Start(0)).unwrap();
+        small.write_all(b"J").unwrap();
+        assert_eq!(budget.used(), 5);
+        large.seek(SeekFrom::Start(0)).unwrap();
+        large.write_all(b"J").unwrap();
+        let mut read = vec![];
+        large.seek(SeekFrom::Start(6)).unwrap();
+        large.read_to_end(&mut read).unwrap();
+        assert_eq!(read, b"world");
+        assert_eq!(small.into_bytes().unwrap(), b"Jello");
+        assert_eq!(large.into_bytes().unwrap(), b"Jello world");
+        assert_eq!(budget.used(), 0);
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Opt-in support for the `CCACHE_*` environment variables build systems
+//! set for ccache. With `SCCACHE_CCACHE_COMPAT=1`, each one sccache has an
+//! equivalent for sets that `SCCACHE_*` variable, unless it's set already,
+//! so it applies to the client, to the server it starts, and to each
+//! compile the client sends.
+
+use crate::config::{CompilerCheck, Sloppiness};
+use std::env;
+use std::ffi::{OsStr, OsString};
+
+/// Set the `SCCACHE_*` variables the `CCACHE_*` ones map onto, if
+/// `SCCACHE_CCACHE_COMPAT` is `1`.
+pub fn apply() {
+    if env::var("SCCACHE_CCACHE_COMPAT").map_or(true, |v| v != "1") {
+        return;
+    }
+    for (name, value) in translate(|name| env::var_os(name)) {
+        if env::var_os(name).is_none() {
+            trace!("Setting {}={:?} for ccache compatibility", name, value);
+            env::set_var(name, value);
+        }
+    }
+}
+
+/// The `SCCACHE_*` variables for the `CCACHE_*` variables read with `var`.
+fn translate<F>(var: F) -> Vec<(&'static str, OsString)>
+where
+    F: Fn(&str) -> Option<OsString>,
+{
+    let mut vars = vec![];
+    let flag = |name| var(name).map_or(false, |value| is_true(&value));
+    if flag("CCACHE_DISABLE") {
+        vars.push(("SCCACHE_NO_CACHE", "1".into()));
+    }
+    if flag("CCACHE_RECACHE") {
+        vars.push(("SCCACHE_RECACHE", "1".into()));
+    }
+    if flag("CCACHE_READONLY") {
+        vars.push(("SCCACHE_READONLY", "1".into()));
+    }
+    if let Some(dir) = var("CCACHE_DIR") {
+        vars.push(("SCCACHE_DIR", dir));
+    }
+    if let Some(dir) = var("CCACHE_BASEDIR") {
+        vars.push(("SCCACHE_BASEDIR", dir));
+    }
+    let string = |name| var(name).and_then(|value| value.into_string().ok());
+    if let Some(value) = string("CCACHE_MAXSIZE") {
+        match parse_size(&value) {
+            // SCCACHE_CACHE_SIZE only takes whole binary units
+            Some(size) => vars.push(("SCCACHE_CACHE_SIZE", format!("{}K", size / 1024).into())),
+            None => warn!("Ignoring unknown CCACHE_MAXSIZE value: {}", value),
+        }
+    }
+    if let Some(value) = string("CCACHE_SLOPPINESS") {
+        let names: Vec<_> = value
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|name| !name.is_empty())
+            .filter(|name| {
+                let known = Sloppiness::from_name(name).is_some();
+                if !known {
+                    debug!("sccache has no equivalent of ccache sloppiness {}", name);
+                }
+                known
+            })
+            .collect();
+        if !names.is_empty() {
+            vars.push(("SCCACHE_SLOPPINESS", names.join(",").into()));
+        }
+    }
+    if let Some(value) = string("CCACHE_COMPILERCHECK") {
+        if CompilerCheck::from_name(&value).is_some() {
+            vars.push(("SCCACHE_COMPILER_CHECK", value.into()));
+        } else {
+            debug!(
+                "sccache has no equivalent of ccache compiler check {}",
+                value
+            );
+        }
+    }
+    vars
+}
+
+/// Whether a ccache boolean variable is set to true: ccache takes any value
+/// but these as true.
+fn is_true(value: &OsStr) -> bool {
+    let value = value.to_string_lossy().to_lowercase();
+    !["0", "false", "disable", "no"].contains(&value.as_str())
+}
+
+/// Parse a ccache size, in gigabytes if it has no suffix, where `k`, `M`, `G`
+/// and `T` are decimal units and `Ki`, `Mi`, `Gi` and `Ti` binary ones.
+fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, suffix) = value.split_at(split);
+    let number: f64 = number.parse().ok()?;
+    let (base, suffix): (f64, _) = match suffix.strip_suffix('i') {
+        Some(suffix) => (1024.0, suffix),
+        None => (1000.0, suffix),
+    };
+    let exponent = match suffix {
+        "k" | "K" => 1,
+        "M" => 2,
+        "" | "G" => 3,
+        "T" => 4,
+        _ => return None,
+    };
+    Some((number * base.powi(exponent)) as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn translated(vars: &[(&str, &str)]) -> Vec<(&'static str, String)> {
+        let vars: HashMap<_, _> = vars
+            .iter()
+            .map(|&(k, v)| (k.to_owned(), OsString::from(v)))
+            .collect();
+        translate(|name| vars.get(name).cloned())
+            .into_iter()
+            .map(|(name, value)| (name, value.into_string().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_translate() {
+        assert!(translated(&[("SCCACHE_DIR", "/cache")]).is_empty());
+        assert_eq!(
+            translated(&[
+                ("CCACHE_DISABLE", "1"),
+                ("CCACHE_RECACHE", ""),
+                ("CCACHE_READONLY", "false"),
+                ("CCACHE_DIR", "/cache"),
+                ("CCACHE_BASEDIR", "/src"),
+                ("CCACHE_MAXSIZE", "2Gi"),
+                ("CCACHE_SLOPPINESS", "include_file_mtime, time_macros"),
+                ("CCACHE_COMPILERCHECK", "content"),
+            ]),
+            vec![
+                ("SCCACHE_NO_CACHE", "1".to_owned()),
+                ("SCCACHE_RECACHE", "1".to_owned()),
+                ("SCCACHE_DIR", "/cache".to_owned()),
+                ("SCCACHE_BASEDIR", "/src".to_owned()),
+                ("SCCACHE_CACHE_SIZE", "2097152K".to_owned()),
+                ("SCCACHE_SLOPPINESS", "time_macros".to_owned()),
+                ("SCCACHE_COMPILER_CHECK", "content".to_owned()),
+            ]
+        );
+        // Settings sccache has no equivalent for are left out
+        assert!(translated(&[
+            ("CCACHE_SLOPPINESS", "locale"),
+            ("CCACHE_COMPILERCHECK", "none"),
+            ("CCACHE_MAXSIZE", "lots"),
+        ])
+        .is_empty());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("5"), Some(5_000_000_000));
+        assert_eq!(parse_size("1.5G"), Some(1_500_000_000));
+        assert_eq!(parse_size("500M"), Some(500_000_000));
+        assert_eq!(parse_size("64Ki"), Some(65536));
+        assert_eq!(parse_size("1Ti"), Some(1u64 << 40));
+        assert_eq!(parse_size("10X"), None);
+        assert_eq!(parse_size(""), None);
+    }
+}
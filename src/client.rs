@@ -13,29 +13,64 @@
 // limitations under the License.
 
 use crate::errors::*;
-use crate::protocol::{Request, Response};
+use crate::protocol::{Hello, Request, Response};
+#[cfg(any(unix, windows))]
+use crate::server;
 use crate::util;
 use byteorder::{BigEndian, ByteOrder};
 use retry::{delay::Fixed, retry};
-use std::io::{self, BufReader, BufWriter, Read};
+#[cfg(windows)]
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
 use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::Path;
 
 /// A connection to an sccache server.
 pub struct ServerConnection {
     /// A reader for the socket connected to the server.
-    reader: BufReader<TcpStream>,
+    reader: BufReader<Box<dyn Read>>,
     /// A writer for the socket connected to the server.
-    writer: BufWriter<TcpStream>,
+    writer: BufWriter<Box<dyn Write>>,
 }
 
 impl ServerConnection {
     /// Create a new connection using `stream`.
     pub fn new(stream: TcpStream) -> io::Result<ServerConnection> {
         let writer = stream.try_clone()?;
-        Ok(ServerConnection {
-            reader: BufReader::new(stream),
+        Ok(ServerConnection::from_parts(
+            Box::new(stream),
+            Box::new(writer),
+        ))
+    }
+
+    /// Create a new connection using the Unix socket `stream`.
+    #[cfg(unix)]
+    pub fn new_unix(stream: UnixStream) -> io::Result<ServerConnection> {
+        let writer = stream.try_clone()?;
+        Ok(ServerConnection::from_parts(
+            Box::new(stream),
+            Box::new(writer),
+        ))
+    }
+
+    /// Create a new connection using the named pipe `pipe`.
+    #[cfg(windows)]
+    pub fn new_pipe(pipe: File) -> io::Result<ServerConnection> {
+        let writer = pipe.try_clone()?;
+        Ok(ServerConnection::from_parts(
+            Box::new(pipe),
+            Box::new(writer),
+        ))
+    }
+
+    fn from_parts(reader: Box<dyn Read>, writer: Box<dyn Write>) -> ServerConnection {
+        ServerConnection {
+            reader: BufReader::new(reader),
             writer: BufWriter::new(writer),
-        })
+        }
     }
 
     /// Send `request` to the server, read and return a `Response`.
@@ -46,6 +81,20 @@ impl ServerConnection {
         self.read_one_response()
     }
 
+    /// Tell the server which version this client is, and return which it is,
+    /// or `None` if it's from before the handshake was added. Such a server
+    /// closes the connection.
+    pub fn hello(&mut self) -> Result<Option<Hello>> {
+        match self.request(Request::Hello(Hello::current())) {
+            Ok(Response::Hello(hello)) => Ok(Some(hello)),
+            Ok(_) => bail!("Unexpected server response!"),
+            Err(e) => {
+                debug!("The server didn't answer the handshake: {:?}", e);
+                Ok(None)
+            }
+        }
+    }
+
     /// Read a single `Response` from the server.
     pub fn read_one_response(&mut self) -> Result<Response> {
         trace!("ServerConnection::read_one_response");
@@ -62,14 +111,61 @@ impl ServerConnection {
     }
 }
 
-/// Establish a TCP connection to an sccache server listening on `port`.
+/// Establish a connection to an sccache server listening on `port`, over its
+/// Unix socket if it has one and over TCP otherwise.
 pub fn connect_to_server(port: u16) -> io::Result<ServerConnection> {
     trace!("connect_to_server({})", port);
+    #[cfg(unix)]
+    {
+        if let Some(path) = server::get_socket_path(port) {
+            match connect_to_socket(&path) {
+                Ok(conn) => return Ok(conn),
+                Err(e) => debug!("couldn't connect to {}: {}", path.display(), e),
+            }
+        }
+    }
+    #[cfg(windows)]
+    {
+        if let Some(name) = server::get_pipe_name(port) {
+            match connect_to_pipe(&name) {
+                Ok(conn) => return Ok(conn),
+                Err(e) => debug!("couldn't connect to {}: {}", name, e),
+            }
+        }
+    }
     let stream = TcpStream::connect(("127.0.0.1", port))?;
     ServerConnection::new(stream)
 }
 
-/// Attempt to establish a TCP connection to an sccache server listening on `port`.
+/// Establish a connection to an sccache server listening on the Unix socket
+/// at `path`.
+#[cfg(unix)]
+pub fn connect_to_socket(path: &Path) -> io::Result<ServerConnection> {
+    trace!("connect_to_socket({})", path.display());
+    ServerConnection::new_unix(UnixStream::connect(path)?)
+}
+
+/// Establish a connection to an sccache server listening on the named pipe
+/// `name`.
+#[cfg(windows)]
+pub fn connect_to_pipe(name: &str) -> io::Result<ServerConnection> {
+    use std::time::Duration;
+    use winapi::shared::winerror::ERROR_PIPE_BUSY;
+
+    trace!("connect_to_pipe({})", name);
+    let open = || OpenOptions::new().read(true).write(true).open(name);
+    let pipe = match open() {
+        // Every instance is taken until the server creates another
+        Err(ref e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY as i32) => {
+            miow::pipe::NamedPipe::wait(name, Some(Duration::from_secs(1)))?;
+            open()?
+        }
+        res => res?,
+    };
+    ServerConnection::new_pipe(pipe)
+}
+
+/// Attempt to establish a connection to an sccache server listening on `port`.
 ///
 /// If the connection fails, retry a few times.
 pub fn connect_with_retry(port: u16) -> io::Result<ServerConnection> {
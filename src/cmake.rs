@@ -0,0 +1,214 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `sccache --generate-cmake-launcher`: writing a CMake file that makes the
+//! compilers it finds use sccache as their launcher.
+
+use crate::compiler::{get_compiler_info, CCompilerKind, CompilerKind};
+use crate::jobserver::Client;
+use crate::mock_command::{CommandCreatorSync, ProcessCommandCreator};
+use crate::util;
+use futures::executor::ThreadPool;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::errors::*;
+
+/// The CMake languages sccache caches, with the variable CMake reads their
+/// compiler from and the compilers it looks for on `PATH` otherwise.
+const LANGUAGES: &[(&str, &str, &[&str])] = &[
+    ("C", "CC", &["cc", "gcc", "clang", "cl"]),
+    ("CXX", "CXX", &["c++", "g++", "clang++", "cl"]),
+    ("CUDA", "CUDACXX", &["nvcc"]),
+];
+
+/// The compiler found for a language, and what sccache made of it.
+struct Detected {
+    lang: &'static str,
+    path: PathBuf,
+    /// The kind of compiler, or why sccache doesn't support it.
+    kind: ::std::result::Result<CompilerKind, String>,
+}
+
+/// Find the compiler of each language and write the CMake file setting
+/// their launchers to `out`.
+pub fn generate_launcher(out: &Path) -> Result<()> {
+    let runtime = util::runtime()?;
+    let pool = ThreadPool::builder().pool_size(1).create()?;
+    let jobserver = unsafe { Client::new() };
+    let creator = ProcessCommandCreator::new(&jobserver);
+    let cwd = env::current_dir()?;
+    let env: Vec<_> = env::vars_os().collect();
+
+    let mut detected = vec![];
+    for &(lang, var, names) in LANGUAGES {
+        let path = match env::var_os(var) {
+            Some(compiler) => which::which(&compiler).ok(),
+            None => names.iter().find_map(|name| which::which(name).ok()),
+        };
+        let path = match path {
+            Some(path) => path,
+            None => continue,
+        };
+        let info = get_compiler_info(creator.clone(), &path, &cwd, &env, &pool, None);
+        let kind = runtime
+            .block_on(info)
+            .map(|(compiler, _)| compiler.kind())
+            .map_err(|e| e.to_string());
+        detected.push(Detected { lang, path, kind });
+    }
+    if detected.iter().all(|d| d.kind.is_err()) {
+        bail!("No supported C, C++ or CUDA compiler was found, set CC, CXX or CUDACXX to the compilers to use");
+    }
+
+    let sccache = env::current_exe().context("couldn't find the sccache executable")?;
+    fs::write(out, render(&sccache, &detected))
+        .with_context(|| format!("couldn't write {}", out.display()))?;
+    for d in &detected {
+        match d.kind {
+            Ok(ref kind) => println!("{}: {} ({})", d.lang, d.path.display(), describe(kind)),
+            Err(_) => println!("{}: {} isn't supported", d.lang, d.path.display()),
+        }
+    }
+    println!(
+        "Wrote {}, use it with `cmake -DCMAKE_PROJECT_INCLUDE={} ...`",
+        out.display(),
+        out.display()
+    );
+    Ok(())
+}
+
+fn describe(kind: &CompilerKind) -> String {
+    match kind {
+        CompilerKind::C(kind) => format!("{:?}", kind),
+        kind => kind.lang_kind(),
+    }
+}
+
+/// Quote a path for CMake.
+fn quote(path: &Path) -> String {
+    // CMake takes forward slashes on Windows too, and they need no escaping
+    let path = path.to_string_lossy().replace('\\', "/");
+    format!("\"{}\"", path.replace('"', "\\\""))
+}
+
+/// The CMake file making the supported `compilers` use `sccache`.
+fn render(sccache: &Path, compilers: &[Detected]) -> String {
+    let mut cmake = String::new();
+    let mut line = |text: &str| {
+        cmake.push_str(text);
+        cmake.push('\n');
+    };
+    line("# Compiler launchers for sccache, generated by `sccache --generate-cmake-launcher`.");
+    line("# Include it in a project with `cmake -DCMAKE_PROJECT_INCLUDE=<this file> ...`,");
+    line("# or with include() after project() in CMakeLists.txt, before any targets.");
+    line("");
+    line(&format!("set(SCCACHE_PROGRAM {})", quote(sccache)));
+    let mut msvc = vec![];
+    for d in compilers {
+        line("");
+        match d.kind {
+            Ok(ref kind) => {
+                line(&format!(
+                    "# {}: {} ({})",
+                    d.lang,
+                    d.path.display(),
+                    describe(kind)
+                ));
+                line(&format!(
+                    "set(CMAKE_{}_COMPILER_LAUNCHER \"${{SCCACHE_PROGRAM}}\")",
+                    d.lang
+                ));
+                if *kind == CompilerKind::C(CCompilerKind::MSVC) {
+                    msvc.push(d.lang);
+                }
+            }
+            Err(ref e) => line(&format!(
+                "# {}: {} isn't supported by sccache, so it has no launcher: {}",
+                d.lang,
+                d.path.display(),
+                e.lines().next().unwrap_or("")
+            )),
+        }
+    }
+    if !msvc.is_empty() {
+        line("");
+        line("if(MSVC)");
+        line("  # The Visual Studio generators ignore compiler launchers");
+        line("  if(CMAKE_GENERATOR MATCHES \"Visual Studio\")");
+        line("    message(WARNING \"The ${CMAKE_GENERATOR} generator doesn't use compiler launchers, so sccache doesn't cache its compiles. Use the Ninja generator instead.\")");
+        line("  endif()");
+        line("  # Debug info written to a PDB shared by the compiles (/Zi) can't be");
+        line("  # cached, so embed it in the objects (/Z7) instead");
+        line("  set(CMAKE_MSVC_DEBUG_INFORMATION_FORMAT \"$<$<CONFIG:Debug,RelWithDebInfo>:Embedded>\")");
+        line(&format!("  foreach(lang {})", msvc.join(" ")));
+        line("    foreach(config DEBUG RELWITHDEBINFO)");
+        line("      string(REPLACE \"/Zi\" \"/Z7\" CMAKE_${lang}_FLAGS_${config} \"${CMAKE_${lang}_FLAGS_${config}}\")");
+        line("    endforeach()");
+        line("  endforeach()");
+        line("endif()");
+    }
+    cmake
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn detected(lang: &'static str, path: &str, kind: CCompilerKind) -> Detected {
+        Detected {
+            lang,
+            path: path.into(),
+            kind: Ok(CompilerKind::C(kind)),
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let cmake = render(
+            Path::new("/usr/bin/sccache"),
+            &[
+                detected("C", "/usr/bin/gcc", CCompilerKind::GCC),
+                detected("CXX", "/usr/bin/g++", CCompilerKind::GCC),
+                Detected {
+                    lang: "CUDA",
+                    path: "/opt/cuda/bin/nvcc".into(),
+                    kind: Err("nvcc failed\nwith more".to_owned()),
+                },
+            ],
+        );
+        assert!(cmake.contains("set(SCCACHE_PROGRAM \"/usr/bin/sccache\")\n"));
+        assert!(cmake.contains("set(CMAKE_C_COMPILER_LAUNCHER \"${SCCACHE_PROGRAM}\")\n"));
+        assert!(cmake.contains("set(CMAKE_CXX_COMPILER_LAUNCHER \"${SCCACHE_PROGRAM}\")\n"));
+        assert!(!cmake.contains("CMAKE_CUDA_COMPILER_LAUNCHER"));
+        assert!(cmake.contains("isn't supported by sccache, so it has no launcher: nvcc failed\n"));
+        assert!(!cmake.contains("if(MSVC)"));
+    }
+
+    #[test]
+    fn test_render_msvc() {
+        let cmake = render(
+            Path::new("C:\\Program Files\\sccache\\sccache.exe"),
+            &[
+                detected("C", "C:\\VC\\bin\\cl.exe", CCompilerKind::MSVC),
+                detected("CXX", "C:\\VC\\bin\\cl.exe", CCompilerKind::MSVC),
+            ],
+        );
+        assert!(cmake.contains("set(SCCACHE_PROGRAM \"C:/Program Files/sccache/sccache.exe\")\n"));
+        assert!(cmake.contains("if(MSVC)\n"));
+        assert!(cmake.contains("  foreach(lang C CXX)\n"));
+        assert!(cmake.contains("string(REPLACE \"/Zi\" \"/Z7\""));
+    }
+}
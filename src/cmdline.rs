@@ -12,8 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::cache::ClearScope;
+use crate::config;
 use crate::errors::*;
-use clap::{App, AppSettings, Arg};
+use crate::protocol::AdminRequest;
+use clap::{App, AppSettings, Arg, Shell, SubCommand};
 use std::env;
 use std::ffi::OsString;
 use std::path::PathBuf;
@@ -24,26 +27,87 @@ arg_enum! {
     #[allow(non_camel_case_types)]
     pub enum StatsFormat {
         text,
-        json
+        json,
+        csv
     }
 }
 
+/// Which of the statistics `--show-stats` shows.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StatsView {
+    /// All of the counts and durations.
+    Summary,
+    /// The slowest compiles that missed the cache.
+    Slowest,
+    /// The outcomes of the compiles of each Rust crate.
+    Rust,
+}
+
 /// A specific command to run.
 pub enum Command {
-    /// Show cache statistics and exit.
-    ShowStats(StatsFormat),
+    /// Show cache statistics, of all compiles or those of a project, and exit.
+    ShowStats(StatsFormat, Option<String>, StatsView),
+    /// Show the stats as they change, until the server shuts down.
+    WatchStats(StatsFormat),
+    /// Show the compiles in progress and the results of recent ones,
+    /// redrawn every second until the server shuts down.
+    Top,
     /// Run background server.
     InternalStartServer,
     /// Start background server as a subprocess.
     StartServer,
     /// Stop background server.
     StopServer,
-    /// Zero cache statistics and exit.
-    ZeroStats,
-    /// Show the status of the distributed client.
-    DistStatus,
+    /// Zero cache statistics, or those of a project, and exit.
+    ZeroStats(Option<String>),
+    /// Append a summary of the stats to the GitHub Actions job summary.
+    GhaSummary,
+    /// Show the status of the distributed client and the scheduler's build
+    /// servers.
+    DistStatus(StatsFormat),
+    /// Remove the toolchains packaged for distributed compilation.
+    ClearToolchainCache,
+    /// Make the server re-read its config.
+    ReloadConfig,
+    /// Show the config in effect, and where each setting comes from.
+    ShowConfig,
+    /// Check the config file, or the one at the path, for problems.
+    ValidateConfig(Option<PathBuf>),
+    /// Check that the configured storage can be written, read and deleted.
+    CheckStorage,
+    /// Remove the entries of the local disk cache of older versions of
+    /// sccache, and rewrite those in an older format.
+    MigrateCache,
+    /// Show the writes recorded in the audit log, or those of a key.
+    AuditLog(Option<String>, StatsFormat),
+    /// Check for common problems with the config, server, storage and
+    /// compilers, and suggest fixes.
+    Doctor,
+    /// Look up the compiles of a `compile_commands.json` without compiling
+    /// them, this many at once.
+    Prewarm(PathBuf, usize),
+    /// Look up the compiles the next build of a Ninja build directory runs
+    /// without compiling them, this many at once.
+    PrefetchNinja(PathBuf, usize),
+    /// Keep the server running until the named build session ends.
+    StartSession(String),
+    /// End the named build session.
+    EndSession(String),
+    /// Send an admin request to the server.
+    Admin(AdminRequest),
     /// Perform a login to authenticate for distributed compilation.
     DistAuth,
+    /// Write the completions of the commandline for a shell.
+    Completions(Shell),
+    /// Write a CMake file setting the compiler launchers of the compilers
+    /// found to sccache.
+    GenerateCmakeLauncher(PathBuf),
+    /// Write the MSBuild files making the projects under a directory run
+    /// their compiles through sccache.
+    GenerateMsbuildProps(PathBuf),
+    /// Write the xcconfig and compiler shims making Xcode run its compiles
+    /// through sccache.
+    GenerateXcodeConfig(PathBuf),
     /// Package a toolchain for distributed compilation (executable, out)
     PackageToolchain(PathBuf, PathBuf),
     /// Run a compiler command.
@@ -57,6 +121,33 @@ pub enum Command {
         /// The environment variables to use for execution.
         env_vars: Vec<(OsString, OsString)>,
     },
+    /// Show whether a compiler command could be cached, and its key,
+    /// without compiling.
+    DryRun {
+        /// The binary to execute.
+        exe: OsString,
+        /// The commandline arguments to pass to `exe`.
+        cmdline: Vec<OsString>,
+        /// The directory in which to execute the command.
+        cwd: PathBuf,
+        /// The environment variables to use for execution.
+        env_vars: Vec<(OsString, OsString)>,
+    },
+    /// Run a command that isn't a compiler, caching its declared outputs.
+    Run {
+        /// The binary to execute.
+        exe: OsString,
+        /// The commandline arguments to pass to `exe`.
+        cmdline: Vec<OsString>,
+        /// The directory in which to execute the command.
+        cwd: PathBuf,
+        /// The environment variables to use for execution.
+        env_vars: Vec<(OsString, OsString)>,
+        /// The files the command reads.
+        inputs: Vec<PathBuf>,
+        /// The files the command writes.
+        outputs: Vec<PathBuf>,
+    },
 }
 
 /// Get the `App` used for argument parsing.
@@ -74,14 +165,70 @@ pub fn get_app<'a, 'b>() -> App<'a, 'b> {
                 )
         .args_from_usage(
             "-s --show-stats  'show cache statistics'
+             --watch-stats    'show cache statistics as they change'
+             --top            'show the compiles in progress and recent results, like top'
              --start-server   'start background server'
              --stop-server    'stop background server'
              -z, --zero-stats 'zero statistics counters'
              --dist-auth      'authenticate for distributed compilation'
-             --dist-status    'show status of the distributed client'"
+             --dist-status    'show status of the distributed client and build servers'
+             --clear-toolchain-cache 'remove toolchains packaged for distributed compilation'
+             --reload-config  'reload the config file in the running server'
+             --show-config    'show the config in effect and where each setting comes from'
+             --check-storage  'check that the configured cache storage works'
+             --migrate-cache  'remove or rewrite the entries of older versions of sccache in the local disk cache'
+             --doctor         'check for common problems with the setup of sccache'
+             --gha-summary    'write a summary of the statistics to the GitHub Actions job summary'
+             --dry-run        'show whether the compiler command would be cached, without compiling'"
                 )
         .arg(Arg::from_usage("--package-toolchain <executable> <out> 'package toolchain for distributed compilation'")
              .required(false))
+        .arg(Arg::from_usage("--completions <shell> 'write the completions of sccache's arguments for a shell'")
+             .required(false)
+             .possible_values(&["bash", "zsh", "fish", "powershell"]))
+        .arg(Arg::from_usage("--generate-cmake-launcher <file> 'write a CMake file making the compilers found use sccache as their launcher'")
+             .required(false))
+        .arg(Arg::from_usage("--generate-msbuild-props <dir> 'write the MSBuild files making the projects under a directory compile with sccache'")
+             .required(false))
+        .arg(Arg::from_usage("--generate-xcode-config <dir> 'write an xcconfig and compiler shims making Xcode compile with sccache'")
+             .required(false))
+        .arg(Arg::from_usage("--validate-config [path] 'check the config file, or the one at the path, for problems'")
+             .required(false))
+        .arg(Arg::from_usage("--audit-log [key] 'show the cache writes recorded in the audit log, or those of a key'")
+             .required(false))
+        .arg(Arg::from_usage("--profile <name> 'use the server, cache and config of a profile'")
+             .required(false))
+        .arg(Arg::from_usage("--start-session <name> 'keep the server running until the named build session ends'")
+             .required(false))
+        .arg(Arg::from_usage("--end-session <name> 'end a build session'")
+             .required(false))
+        .arg(Arg::from_usage("--project <name> 'show or zero the statistics of a project'")
+             .required(false))
+        .arg(Arg::from_usage("--prewarm <compile_commands> 'fetch the cached results of the compiles in a compile_commands.json'")
+             .required(false))
+        .arg(Arg::from_usage("--prefetch-ninja <builddir> 'fetch the cached results of the compiles the next ninja build in a directory runs'")
+             .required(false))
+        .arg(Arg::from_usage("-j, --jobs <N> 'how many compiles --prewarm or --prefetch-ninja looks up at once'")
+             .required(false))
+        .arg(Arg::from_usage("--flush-cache 'make the server forget its compilers and empty its local disk cache'"))
+        .arg(Arg::from_usage("--clear-cache 'empty the cache, by default only on the local disk'"))
+        .arg(Arg::from_usage("--local 'with --clear-cache, empty the cache on the local disk'")
+             .conflicts_with_all(&["remote", "all"]))
+        .arg(Arg::from_usage("--remote 'with --clear-cache, empty the remote storage'")
+             .conflicts_with("all"))
+        .arg(Arg::from_usage("--all 'with --clear-cache, empty both'"))
+        .arg(Arg::from_usage("--yes 'confirm emptying the remote storage with --clear-cache'"))
+        .arg(Arg::from_usage("--query-key <key> 'look up a cache key in the server's storage'")
+             .required(false))
+        .arg(Arg::from_usage("--read-only <mode> 'stop the server writing to the cache, or start again'")
+             .required(false)
+             .possible_values(&["on", "off"]))
+        .arg(Arg::from_usage("--offline <mode> 'make the server use only the local disk cache, or remote storage again'")
+             .required(false)
+             .possible_values(&["on", "off"]))
+        .arg(Arg::from_usage("--slowest 'with --show-stats, show the slowest compiles that missed the cache'")
+             .conflicts_with("rust"))
+        .arg(Arg::from_usage("--rust 'with --show-stats, show the hits and misses of each Rust crate'"))
         .arg(Arg::from_usage("--stats-format  'set output format of statistics'")
              .possible_values(&StatsFormat::variants())
              .default_value("text"))
@@ -90,6 +237,23 @@ pub fn get_app<'a, 'b>() -> App<'a, 'b> {
                 .multiple(true)
                 .use_delimiter(false)
                 )
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("run a command, caching the outputs it declares")
+                .setting(AppSettings::TrailingVarArg)
+                .arg(Arg::from_usage("--input [file] 'a file the command reads'")
+                     .multiple(true)
+                     .number_of_values(1))
+                .arg(Arg::from_usage("--output <file> 'a file the command writes'")
+                     .multiple(true)
+                     .number_of_values(1))
+                .arg(
+                    Arg::with_name("cmd")
+                        .multiple(true)
+                        .required(true)
+                        .use_delimiter(false)
+                        )
+                )
 }
 
 /// Parse the commandline into a `Command` to execute.
@@ -143,14 +307,55 @@ pub fn parse() -> Result<Command> {
     }
     let matches = get_app().get_matches_from(args);
 
+    if let Some(profile) = matches.value_of("profile") {
+        // Everything else reads it from the environment, as does a server
+        // started for it
+        env::set_var("SCCACHE_PROFILE", profile);
+    }
+    if let Some(profile) = config::profile() {
+        if !config::is_valid_profile(&profile) {
+            bail!(
+                "Invalid profile {:?}, it can only have letters, digits, '-' and '_'",
+                profile
+            );
+        }
+    }
+
     let show_stats = matches.is_present("show-stats");
+    let watch_stats = matches.is_present("watch-stats");
+    let top = matches.is_present("top");
     let start_server = matches.is_present("start-server");
     let stop_server = matches.is_present("stop-server");
     let zero_stats = matches.is_present("zero-stats");
     let dist_auth = matches.is_present("dist-auth");
     let dist_status = matches.is_present("dist-status");
+    let clear_toolchain_cache = matches.is_present("clear-toolchain-cache");
+    let reload_config = matches.is_present("reload-config");
+    let show_config = matches.is_present("show-config");
+    let validate_config = matches.is_present("validate-config");
+    let check_storage = matches.is_present("check-storage");
+    let migrate_cache = matches.is_present("migrate-cache");
+    let audit_log = matches.is_present("audit-log");
+    let doctor = matches.is_present("doctor");
+    let gha_summary = matches.is_present("gha-summary");
+    let start_session = matches.value_of("start-session");
+    let end_session = matches.value_of("end-session");
+    let prewarm = matches.value_of_os("prewarm");
+    let prefetch_ninja = matches.value_of_os("prefetch-ninja");
+    let project = matches.value_of("project").map(str::to_owned);
+    let flush_cache = matches.is_present("flush-cache");
+    let clear_cache = matches.is_present("clear-cache");
+    let query_key = matches.value_of("query-key");
+    let read_only = matches.value_of("read-only");
+    let offline = matches.value_of("offline");
     let package_toolchain = matches.is_present("package-toolchain");
+    let completions = matches.value_of("completions");
+    let cmake_launcher = matches.value_of_os("generate-cmake-launcher");
+    let msbuild_props = matches.value_of_os("generate-msbuild-props");
+    let xcode_config = matches.value_of_os("generate-xcode-config");
+    let dry_run = matches.is_present("dry-run");
     let cmd = matches.values_of_os("cmd");
+    let run = matches.subcommand_matches("run");
     // Ensure that we've only received one command to run.
     fn is_some<T>(x: &Option<T>) -> bool {
         x.is_some()
@@ -158,11 +363,37 @@ pub fn parse() -> Result<Command> {
     if [
         internal_start_server,
         show_stats,
+        watch_stats,
+        top,
         start_server,
         stop_server,
         zero_stats,
+        clear_toolchain_cache,
+        reload_config,
+        show_config,
+        validate_config,
+        check_storage,
+        migrate_cache,
+        audit_log,
+        doctor,
+        gha_summary,
+        is_some(&start_session),
+        is_some(&end_session),
+        is_some(&prewarm),
+        is_some(&prefetch_ninja),
+        flush_cache,
+        clear_cache,
+        is_some(&query_key),
+        is_some(&read_only),
+        is_some(&offline),
         package_toolchain,
-        is_some(&cmd),
+        is_some(&completions),
+        is_some(&cmake_launcher),
+        is_some(&msbuild_props),
+        is_some(&xcode_config),
+        // The command is the compile to dry-run
+        is_some(&cmd) && !dry_run,
+        is_some(&run),
     ]
     .iter()
     .filter(|&&x| x)
@@ -171,22 +402,125 @@ pub fn parse() -> Result<Command> {
     {
         bail!("Too many commands specified");
     }
+    if project.is_some() && !show_stats && !zero_stats {
+        bail!("--project can only be used with --show-stats or --zero-stats");
+    }
+    let view = if matches.is_present("slowest") {
+        StatsView::Slowest
+    } else if matches.is_present("rust") {
+        StatsView::Rust
+    } else {
+        StatsView::Summary
+    };
+    if view != StatsView::Summary && !show_stats {
+        bail!("--slowest and --rust can only be used with --show-stats");
+    }
+    let clear_scope = if matches.is_present("all") {
+        ClearScope::All
+    } else if matches.is_present("remote") {
+        ClearScope::Remote
+    } else {
+        ClearScope::Local
+    };
+    let clear_options = ["local", "remote", "all", "yes"];
+    if !clear_cache && clear_options.iter().any(|&name| matches.is_present(name)) {
+        bail!("--local, --remote, --all and --yes can only be used with --clear-cache");
+    }
+    if clear_cache && clear_scope.remote() && !matches.is_present("yes") {
+        bail!("--clear-cache would delete every entry in the remote storage, add --yes to do so");
+    }
+    if dry_run && cmd.is_none() {
+        bail!("--dry-run needs a compiler command");
+    }
+    if matches.is_present("jobs") && prewarm.is_none() && prefetch_ninja.is_none() {
+        bail!("--jobs can only be used with --prewarm or --prefetch-ninja");
+    }
+    let jobs = || {
+        if matches.is_present("jobs") {
+            value_t!(matches.value_of("jobs"), usize).unwrap_or_else(|e| e.exit())
+        } else {
+            num_cpus::get()
+        }
+    };
     if internal_start_server {
         Ok(Command::InternalStartServer)
     } else if show_stats {
         let fmt =
             value_t!(matches.value_of("stats-format"), StatsFormat).unwrap_or_else(|e| e.exit());
-        Ok(Command::ShowStats(fmt))
+        Ok(Command::ShowStats(fmt, project, view))
+    } else if watch_stats {
+        let fmt =
+            value_t!(matches.value_of("stats-format"), StatsFormat).unwrap_or_else(|e| e.exit());
+        Ok(Command::WatchStats(fmt))
+    } else if top {
+        Ok(Command::Top)
     } else if start_server {
         Ok(Command::StartServer)
     } else if stop_server {
         Ok(Command::StopServer)
     } else if zero_stats {
-        Ok(Command::ZeroStats)
+        Ok(Command::ZeroStats(project))
     } else if dist_auth {
         Ok(Command::DistAuth)
     } else if dist_status {
-        Ok(Command::DistStatus)
+        let fmt =
+            value_t!(matches.value_of("stats-format"), StatsFormat).unwrap_or_else(|e| e.exit());
+        if let StatsFormat::csv = fmt {
+            bail!("--dist-status can't be shown as csv");
+        }
+        Ok(Command::DistStatus(fmt))
+    } else if clear_toolchain_cache {
+        Ok(Command::ClearToolchainCache)
+    } else if reload_config {
+        Ok(Command::ReloadConfig)
+    } else if show_config {
+        Ok(Command::ShowConfig)
+    } else if validate_config {
+        let path = matches.value_of_os("validate-config").map(PathBuf::from);
+        Ok(Command::ValidateConfig(path))
+    } else if check_storage {
+        Ok(Command::CheckStorage)
+    } else if migrate_cache {
+        Ok(Command::MigrateCache)
+    } else if audit_log {
+        let key = matches.value_of("audit-log").map(str::to_owned);
+        let fmt =
+            value_t!(matches.value_of("stats-format"), StatsFormat).unwrap_or_else(|e| e.exit());
+        if let StatsFormat::csv = fmt {
+            bail!("--audit-log can't be shown as csv");
+        }
+        Ok(Command::AuditLog(key, fmt))
+    } else if doctor {
+        Ok(Command::Doctor)
+    } else if gha_summary {
+        Ok(Command::GhaSummary)
+    } else if let Some(path) = prewarm {
+        Ok(Command::Prewarm(path.into(), jobs()))
+    } else if let Some(dir) = prefetch_ninja {
+        Ok(Command::PrefetchNinja(dir.into(), jobs()))
+    } else if let Some(name) = start_session {
+        Ok(Command::StartSession(name.to_owned()))
+    } else if let Some(name) = end_session {
+        Ok(Command::EndSession(name.to_owned()))
+    } else if flush_cache {
+        Ok(Command::Admin(AdminRequest::Flush))
+    } else if clear_cache {
+        Ok(Command::Admin(AdminRequest::Clear(clear_scope)))
+    } else if let Some(key) = query_key {
+        Ok(Command::Admin(AdminRequest::QueryKey(key.to_owned())))
+    } else if let Some(mode) = read_only {
+        Ok(Command::Admin(AdminRequest::SetReadOnly(mode == "on")))
+    } else if let Some(mode) = offline {
+        Ok(Command::Admin(AdminRequest::SetOffline(mode == "on")))
+    } else if completions.is_some() {
+        let shell = value_t!(matches.value_of("completions"), Shell).unwrap_or_else(|e| e.exit());
+        Ok(Command::Completions(shell))
+    } else if let Some(path) = cmake_launcher {
+        Ok(Command::GenerateCmakeLauncher(path.into()))
+    } else if let Some(dir) = msbuild_props {
+        Ok(Command::GenerateMsbuildProps(dir.into()))
+    } else if let Some(dir) = xcode_config {
+        Ok(Command::GenerateXcodeConfig(dir.into()))
     } else if package_toolchain {
         let mut values = matches
             .values_of_os("package-toolchain")
@@ -197,6 +531,22 @@ pub fn parse() -> Result<Command> {
             values.next().expect("package-toolchain missing value 2"),
         );
         Ok(Command::PackageToolchain(executable.into(), out.into()))
+    } else if let Some(run) = run {
+        let mut args = run.values_of_os("cmd").expect("Parsed run but no command");
+        let exe = args.next().expect("run missing command");
+        let files = |name| {
+            run.values_of_os(name)
+                .map(|values| values.map(PathBuf::from).collect())
+                .unwrap_or_default()
+        };
+        Ok(Command::Run {
+            exe: exe.to_owned(),
+            cmdline: args.map(|s| s.to_owned()).collect(),
+            cwd,
+            env_vars: env::vars_os().collect(),
+            inputs: files("input"),
+            outputs: files("output"),
+        })
     } else if let Some(mut args) = cmd {
         if let Some(exe) = args.next() {
             let cmdline = args.map(|s| s.to_owned()).collect::<Vec<_>>();
@@ -211,12 +561,21 @@ pub fn parse() -> Result<Command> {
                 env_vars.retain(|(k, _v)| k != "LD_PRELOAD" && k != "RUNNING_UNDER_RR");
             }
 
-            Ok(Command::Compile {
-                exe: exe.to_owned(),
-                cmdline,
-                cwd,
-                env_vars,
-            })
+            if dry_run {
+                Ok(Command::DryRun {
+                    exe: exe.to_owned(),
+                    cmdline,
+                    cwd,
+                    env_vars,
+                })
+            } else {
+                Ok(Command::Compile {
+                    exe: exe.to_owned(),
+                    cmdline,
+                    cwd,
+                    env_vars,
+                })
+            }
         } else {
             bail!("No compile command");
         }
@@ -13,31 +13,43 @@
 // limitations under the License.
 
 use crate::client::{connect_to_server, connect_with_retry, ServerConnection};
-use crate::cmdline::{Command, StatsFormat};
+use crate::cmake;
+use crate::cmdline::{self, Command, StatsFormat, StatsView};
 use crate::compiler::ColorMode;
-use crate::config::Config;
+use crate::config::{self, Config};
+use crate::doctor;
 use crate::jobserver::Client;
+use crate::logging;
 use crate::mock_command::{CommandChild, CommandCreatorSync, ProcessCommandCreator, RunCommand};
-use crate::protocol::{Compile, CompileFinished, CompileResponse, Request, Response};
-use crate::server::{self, DistInfo, ServerInfo, ServerStartup};
-use crate::util::daemonize;
+use crate::msbuild;
+use crate::prewarm;
+use crate::protocol::{
+    AdminRequest, AdminResponse, Compile, CompileFinished, CompileResponse, DryRunResult, Hello,
+    PrewarmResult, Request, Response, Run, PROTOCOL_VERSION,
+};
+use crate::secrets;
+use crate::server::{
+    self, DistStatus, LiveCounts, ServerInfo, ServerStartup, StatsDelta, TopSnapshot,
+};
+use crate::top;
+use crate::util::{self, daemonize};
+use crate::xcode;
 use atty::Stream;
 use byteorder::{BigEndian, ByteOrder};
-use futures::Future;
 use log::Level::Trace;
+use number_prefix::NumberPrefix;
 use std::env;
 use std::ffi::{OsStr, OsString};
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File};
 use std::io::{self, Write};
 #[cfg(unix)]
 use std::os::unix::process::ExitStatusExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::time::Duration;
 use strip_ansi_escapes::Writer;
-use tokio_compat::runtime::current_thread::Runtime;
-use tokio_io::io::read_exact;
-use tokio_io::AsyncRead;
-use tokio_timer::Timeout;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::runtime::Runtime;
 use which::which_in;
 
 use crate::errors::*;
@@ -53,36 +65,58 @@ fn get_port() -> u16 {
     env::var("SCCACHE_SERVER_PORT")
         .ok()
         .and_then(|s| s.parse().ok())
-        .unwrap_or(DEFAULT_PORT)
+        .unwrap_or_else(|| match config::profile() {
+            Some(profile) => profile_port(&profile),
+            None => DEFAULT_PORT,
+        })
 }
 
-fn read_server_startup_status<R: AsyncRead>(
-    server: R,
-) -> impl Future<Item = ServerStartup, Error = Error> {
+/// The port of the server for `profile`, one of the 1000 after the default.
+fn profile_port(profile: &str) -> u16 {
+    // FNV-1a, as every build of sccache has to agree on it
+    let hash = profile.bytes().fold(0x811c_9dc5u32, |hash, b| {
+        (hash ^ u32::from(b)).wrapping_mul(0x0100_0193)
+    });
+    DEFAULT_PORT + 1 + (hash % 1000) as u16
+}
+
+async fn read_server_startup_status<R: AsyncRead + Unpin>(mut server: R) -> Result<ServerStartup> {
     // This is an async equivalent of ServerConnection::read_one_response
-    read_exact(server, [0u8; 4])
-        .map_err(Error::from)
-        .and_then(|(server, bytes)| {
-            let len = BigEndian::read_u32(&bytes);
-            let data = vec![0; len as usize];
-            read_exact(server, data)
-                .map_err(Error::from)
-                .and_then(|(_server, data)| Ok(bincode::deserialize(&data)?))
-        })
+    let mut bytes = [0u8; 4];
+    server.read_exact(&mut bytes).await?;
+    let len = BigEndian::read_u32(&bytes);
+    let mut data = vec![0; len as usize];
+    server.read_exact(&mut data).await?;
+    Ok(bincode::deserialize(&data)?)
+}
+
+/// Wait for the server to start up with `startup`, for at most
+/// `SERVER_STARTUP_TIMEOUT_MS`.
+fn wait_for_startup<F>(runtime: &Runtime, startup: F) -> Result<ServerStartup>
+where
+    F: std::future::Future<Output = Result<ServerStartup>>,
+{
+    let timeout = Duration::from_millis(SERVER_STARTUP_TIMEOUT_MS.into());
+    runtime.block_on(async {
+        match tokio::time::timeout(timeout, startup).await {
+            Ok(startup) => startup,
+            Err(_) => Ok(ServerStartup::TimedOut),
+        }
+    })
 }
 
 /// Re-execute the current executable as a background server, and wait
 /// for it to start up.
 #[cfg(not(windows))]
 fn run_server_process() -> Result<ServerStartup> {
-    use futures::Stream;
-    use std::time::Duration;
-
     trace!("run_server_process");
     let tempdir = tempfile::Builder::new().prefix("sccache").tempdir()?;
     let socket_path = tempdir.path().join("sock");
-    let mut runtime = Runtime::new()?;
-    let listener = tokio_uds::UnixListener::bind(&socket_path)?;
+    let runtime = util::runtime()?;
+    let listener = {
+        let _guard = runtime.enter();
+        tokio::net::UnixListener::bind(&socket_path)?
+    };
     let exe_path = env::current_exe()?;
     let workdir = exe_path.parent().expect("executable path has no parent?!");
     let _child = process::Command::new(&exe_path)
@@ -92,23 +126,11 @@ fn run_server_process() -> Result<ServerStartup> {
         .env("RUST_BACKTRACE", "1")
         .spawn()?;
 
-    let startup = listener.incoming().into_future().map_err(|e| e.0);
-    let startup = startup.map_err(Error::from).and_then(|(socket, _rest)| {
-        let socket = socket.unwrap(); // incoming() never returns None
-        read_server_startup_status(socket)
-    });
-
-    let timeout = Duration::from_millis(SERVER_STARTUP_TIMEOUT_MS.into());
-    let timeout = Timeout::new(startup, timeout).or_else(|err| {
-        if err.is_elapsed() {
-            Ok(ServerStartup::TimedOut)
-        } else if err.is_inner() {
-            Err(err.into_inner().unwrap())
-        } else {
-            Err(err.into_timer().unwrap().into())
-        }
-    });
-    runtime.block_on(timeout)
+    let startup = async move {
+        let (socket, _) = listener.accept().await?;
+        read_server_startup_status(socket).await
+    };
+    wait_for_startup(&runtime, startup)
 }
 
 #[cfg(not(windows))]
@@ -134,26 +156,27 @@ fn redirect_stderr(f: File) -> Result<()> {
     Ok(())
 }
 
-/// If `SCCACHE_ERROR_LOG` is set, redirect stderr to it.
+/// If `SCCACHE_ERROR_LOG` is set, redirect stderr to it, and rotate it if
+/// that's configured.
 fn redirect_error_log() -> Result<()> {
-    let name = match env::var("SCCACHE_ERROR_LOG") {
-        Ok(filename) if !filename.is_empty() => filename,
+    let name = match env::var_os("SCCACHE_ERROR_LOG") {
+        Some(filename) if !filename.is_empty() => PathBuf::from(filename),
         _ => return Ok(()),
     };
-    let f = OpenOptions::new().create(true).append(true).open(name)?;
+    let f = logging::open_log(&name)?;
+    if let Some(rotation) = logging::Rotation::from_env()? {
+        rotation.start(name, f.try_clone()?, |f| drop(redirect_stderr(f)));
+    }
     redirect_stderr(f)
 }
 
 /// Re-execute the current executable as a background server.
 #[cfg(windows)]
 fn run_server_process() -> Result<ServerStartup> {
-    use futures::future;
     use std::mem;
     use std::os::windows::ffi::OsStrExt;
     use std::ptr;
-    use std::time::Duration;
-    use tokio_named_pipes::NamedPipe;
-    use tokio_reactor::Handle;
+    use tokio::net::windows::named_pipe::ServerOptions;
     use uuid::Uuid;
     use winapi::shared::minwindef::{DWORD, FALSE, LPVOID, TRUE};
     use winapi::um::handleapi::CloseHandle;
@@ -165,24 +188,14 @@ fn run_server_process() -> Result<ServerStartup> {
     trace!("run_server_process");
 
     // Create a mini event loop and register our named pipe server
-    let mut runtime = Runtime::new()?;
+    let runtime = util::runtime()?;
     let pipe_name = format!(r"\\.\pipe\{}", Uuid::new_v4().to_simple_ref());
-    let server = runtime.block_on(future::lazy(|| {
-        NamedPipe::new(
-            &pipe_name,
-            #[allow(deprecated)]
-            &Handle::current(),
-        )
-    }))?;
-
-    // Connect a client to our server, and we'll wait below if it's still in
-    // progress.
-
-    match server.connect() {
-        Ok(()) => {}
-        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
-        Err(e) => return Err(e.into()),
-    }
+    let server = {
+        let _guard = runtime.enter();
+        ServerOptions::new()
+            .first_pipe_instance(true)
+            .create(&pipe_name)?
+    };
 
     // Spawn a server which should come back and connect to us
     let exe_path = env::current_exe()?;
@@ -251,19 +264,12 @@ fn run_server_process() -> Result<ServerStartup> {
         return Err(io::Error::last_os_error().into());
     }
 
-    let result = read_server_startup_status(server);
-
-    let timeout = Duration::from_millis(SERVER_STARTUP_TIMEOUT_MS.into());
-    let timeout = Timeout::new(result, timeout).or_else(|err| {
-        if err.is_elapsed() {
-            Ok(ServerStartup::TimedOut)
-        } else if err.is_inner() {
-            Err(err.into_inner().unwrap())
-        } else {
-            Err(err.into_timer().unwrap().into())
-        }
-    });
-    runtime.block_on(timeout)
+    // Wait for the server to connect back to our pipe
+    let startup = async move {
+        server.connect().await?;
+        read_server_startup_status(server).await
+    };
+    wait_for_startup(&runtime, startup)
 }
 
 /// Attempt to connect to an sccache server listening on `port`, or start one if no server is running.
@@ -301,6 +307,27 @@ fn connect_or_start_server(port: u16) -> Result<ServerConnection> {
     }
 }
 
+/// Check that the server on `conn` speaks the protocol of this client, as
+/// every request but compiles needs.
+fn check_server_version(mut conn: ServerConnection) -> Result<ServerConnection> {
+    match conn.hello()? {
+        Some(ref hello) if hello.protocol == PROTOCOL_VERSION => Ok(conn),
+        hello => bail!(
+            "The sccache server is {}, but this client is version {}. \
+             Run `sccache --stop-server` to restart it.",
+            describe_server(hello.as_ref()),
+            env!("CARGO_PKG_VERSION")
+        ),
+    }
+}
+
+pub fn describe_server(hello: Option<&Hello>) -> String {
+    match hello {
+        Some(hello) => format!("version {} (protocol {})", hello.version, hello.protocol),
+        None => "an older version".to_owned(),
+    }
+}
+
 /// Send a `ZeroStats` request to the server, and return the `ServerInfo` request if successful.
 pub fn request_zero_stats(mut conn: ServerConnection) -> Result<ServerInfo> {
     debug!("request_stats");
@@ -314,6 +341,34 @@ pub fn request_zero_stats(mut conn: ServerConnection) -> Result<ServerInfo> {
     }
 }
 
+/// Send a `ZeroProjectStats` request to the server, and return the `ServerInfo` of the project
+/// if successful.
+pub fn request_zero_project_stats(mut conn: ServerConnection, project: &str) -> Result<ServerInfo> {
+    debug!("request_zero_project_stats");
+    let response = conn
+        .request(Request::ZeroProjectStats(project.to_owned()))
+        .context("failed to send zero statistics command to server or failed to receive respone")?;
+    if let Response::Stats(stats) = response {
+        Ok(*stats)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
+/// Send a `GetProjectStats` request to the server, and return the `ServerInfo` of the project if
+/// successful.
+pub fn request_project_stats(mut conn: ServerConnection, project: &str) -> Result<ServerInfo> {
+    debug!("request_project_stats");
+    let response = conn
+        .request(Request::GetProjectStats(project.to_owned()))
+        .context("Failed to send data to or receive data from server")?;
+    if let Response::Stats(stats) = response {
+        Ok(*stats)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
 /// Send a `GetStats` request to the server, and return the `ServerInfo` request if successful.
 pub fn request_stats(mut conn: ServerConnection) -> Result<ServerInfo> {
     debug!("request_stats");
@@ -327,8 +382,67 @@ pub fn request_stats(mut conn: ServerConnection) -> Result<ServerInfo> {
     }
 }
 
+/// Send a `Prewarm` request for `compile` to the server, and return what it found.
+pub fn request_prewarm(mut conn: ServerConnection, compile: Compile) -> Result<PrewarmResult> {
+    debug!("request_prewarm");
+    let response = conn
+        .request(Request::Prewarm(compile))
+        .context("Failed to send data to or receive data from server")?;
+    if let Response::Prewarmed(result) = response {
+        Ok(result)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
+/// Send a `DryRun` request for `compile` to the server, and return whether
+/// it could be cached.
+pub fn request_dry_run(mut conn: ServerConnection, compile: Compile) -> Result<DryRunResult> {
+    debug!("request_dry_run");
+    let response = conn
+        .request(Request::DryRun(compile))
+        .context("Failed to send data to or receive data from server")?;
+    if let Response::DryRun(result) = response {
+        Ok(result)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
+/// Write what a dry run found to `w`, and return the exit status: 0 if the
+/// compile could be cached, and 1 if not.
+fn print_dry_run(result: &DryRunResult, mut w: impl Write) -> Result<i32> {
+    match result {
+        DryRunResult::Cacheable {
+            compiler,
+            key,
+            pinned,
+        } => {
+            writeln!(w, "Compiler:  {}", compiler)?;
+            writeln!(w, "Cacheable: yes")?;
+            if *pinned {
+                writeln!(w, "Key:       {}", key)?;
+            } else {
+                writeln!(w, "Key:       {} (of an unpinned toolchain)", key)?;
+            }
+            Ok(0)
+        }
+        DryRunResult::NotCacheable { reason, detail } => {
+            match detail {
+                Some(detail) => writeln!(w, "Cacheable: no, {} ({})", reason, detail)?,
+                None => writeln!(w, "Cacheable: no, {}", reason)?,
+            }
+            Ok(1)
+        }
+        DryRunResult::Failed(e) => {
+            writeln!(w, "Cacheable: no, {}", e)?;
+            Ok(1)
+        }
+    }
+}
+
 /// Send a `DistStatus` request to the server, and return `DistStatus` if successful.
-pub fn request_dist_status(mut conn: ServerConnection) -> Result<DistInfo> {
+pub fn request_dist_status(mut conn: ServerConnection) -> Result<DistStatus> {
     debug!("request_dist_status");
     let response = conn
         .request(Request::DistStatus)
@@ -340,6 +454,146 @@ pub fn request_dist_status(mut conn: ServerConnection) -> Result<DistInfo> {
     }
 }
 
+/// Send a `ClearToolchainCache` request to the server, and return the bytes freed if successful.
+pub fn request_clear_toolchain_cache(mut conn: ServerConnection) -> Result<u64> {
+    debug!("request_clear_toolchain_cache");
+    let response = conn
+        .request(Request::ClearToolchainCache)
+        .context("Failed to send data to or receive data from server")?;
+    if let Response::ToolchainCacheCleared(freed) = response {
+        Ok(freed)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
+/// Send a `ReloadConfig` request to the server, and return the `ServerInfo` after reloading if successful.
+pub fn request_reload_config(mut conn: ServerConnection) -> Result<ServerInfo> {
+    debug!("request_reload_config");
+    let response = conn
+        .request(Request::ReloadConfig)
+        .context("Failed to send data to or receive data from server")?;
+    match response {
+        Response::ConfigReloaded(info) => Ok(*info),
+        Response::ConfigReloadFailed(e) => bail!("the server couldn't reload its config: {}", e),
+        _ => bail!("Unexpected server response!"),
+    }
+}
+
+/// Send a `StartSession` request to the server, and return the sessions open if successful.
+pub fn request_start_session(mut conn: ServerConnection, name: &str) -> Result<Vec<String>> {
+    debug!("request_start_session");
+    let response = conn
+        .request(Request::StartSession(name.to_owned()))
+        .context("Failed to send data to or receive data from server")?;
+    if let Response::Sessions(sessions) = response {
+        Ok(sessions)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
+/// Send an `EndSession` request to the server, and return the sessions still open if successful.
+pub fn request_end_session(mut conn: ServerConnection, name: &str) -> Result<Vec<String>> {
+    debug!("request_end_session");
+    let response = conn
+        .request(Request::EndSession(name.to_owned()))
+        .context("Failed to send data to or receive data from server")?;
+    if let Response::Sessions(sessions) = response {
+        Ok(sessions)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
+/// Send a `WatchStats` request to the server, and call `f` with each update of the stats until
+/// the server shuts down.
+pub fn request_watch_stats<F>(mut conn: ServerConnection, mut f: F) -> Result<()>
+where
+    F: FnMut(&StatsDelta) -> Result<()>,
+{
+    debug!("request_watch_stats");
+    let mut response = conn
+        .request(Request::WatchStats)
+        .context("Failed to send data to or receive data from server")?;
+    loop {
+        match response {
+            Response::StatsDelta(delta) => f(&delta)?,
+            Response::StatsWatchEnded => return Ok(()),
+            _ => bail!("Unexpected server response!"),
+        }
+        response = conn
+            .read_one_response()
+            .context("Failed to receive data from server")?;
+    }
+}
+
+/// A line of `--watch-stats`, of the `counts` so far and the compiles in progress in `delta`.
+pub fn live_stats_line(counts: &LiveCounts, delta: &StatsDelta) -> String {
+    let lookups = counts.cache_hits + counts.cache_misses;
+    let hit_rate = if lookups > 0 {
+        format!(
+            " ({:.1}% hits)",
+            counts.cache_hits as f64 * 100.0 / lookups as f64
+        )
+    } else {
+        String::new()
+    };
+    format!(
+        "{} requests: {} hits, {} misses{}, {} errors, {} failed, {} written; \
+         {} compiling, {} writing",
+        counts.compile_requests,
+        counts.cache_hits,
+        counts.cache_misses,
+        hit_rate,
+        counts.cache_errors,
+        counts.compile_fails,
+        counts.cache_writes,
+        delta.compiles_active,
+        delta.cache_writes_active
+    )
+}
+
+/// Send a `Top` request to the server, and call `f` with each snapshot of what it's doing until
+/// it shuts down.
+pub fn request_top<F>(mut conn: ServerConnection, mut f: F) -> Result<()>
+where
+    F: FnMut(&TopSnapshot) -> Result<()>,
+{
+    debug!("request_top");
+    let mut response = conn
+        .request(Request::Top)
+        .context("Failed to send data to or receive data from server")?;
+    loop {
+        match response {
+            Response::TopSnapshot(snapshot) => f(&snapshot)?,
+            Response::StatsWatchEnded => return Ok(()),
+            _ => bail!("Unexpected server response!"),
+        }
+        response = conn
+            .read_one_response()
+            .context("Failed to receive data from server")?;
+    }
+}
+
+/// Send an `Admin` request to the server with its admin `token`, and return its result if
+/// successful.
+pub fn request_admin(
+    mut conn: ServerConnection,
+    token: &str,
+    request: AdminRequest,
+) -> Result<AdminResponse> {
+    debug!("request_admin");
+    let response = conn
+        .request(Request::Admin(token.to_owned(), request))
+        .context("Failed to send data to or receive data from server")?;
+    if let Response::Admin(response) = response {
+        Ok(response)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
 /// Send a `Shutdown` request to the server, and return the `ServerInfo` contained within the response if successful.
 pub fn request_shutdown(mut conn: ServerConnection) -> Result<ServerInfo> {
     debug!("request_shutdown");
@@ -385,6 +639,20 @@ where
     }
 }
 
+/// Send a `Run` request to the server, and return the server response if successful.
+fn request_run(conn: &mut ServerConnection, run: Run) -> Result<CompileResponse> {
+    let req = Request::Run(run);
+    trace!("request_run: {:?}", req);
+    let response = conn
+        .request(req)
+        .context("Failed to send data to or receive data from server")?;
+    if let Response::Compile(response) = response {
+        Ok(response)
+    } else {
+        bail!("Unexpected response from server")
+    }
+}
+
 /// Return the signal that caused a process to exit from `status`.
 #[cfg(unix)]
 #[allow(dead_code)]
@@ -468,12 +736,13 @@ fn handle_compile_finished(
 #[allow(clippy::too_many_arguments)]
 fn handle_compile_response<T>(
     mut creator: T,
-    runtime: &mut Runtime,
+    runtime: &Runtime,
     conn: &mut ServerConnection,
     response: CompileResponse,
     exe: &Path,
     cmdline: Vec<OsString>,
     cwd: &Path,
+    next_wrapper: Option<&Path>,
     stdout: &mut dyn Write,
     stderr: &mut dyn Write,
 ) -> Result<i32>
@@ -514,15 +783,22 @@ where
         }
     };
 
-    let mut cmd = creator.new_command_sync(exe);
+    let mut cmd = match next_wrapper {
+        Some(wrapper) => {
+            let mut cmd = creator.new_command_sync(wrapper);
+            cmd.arg(exe);
+            cmd
+        }
+        None => creator.new_command_sync(exe),
+    };
     cmd.args(&cmdline).current_dir(cwd);
     if log_enabled!(Trace) {
         trace!("running command: {:?}", cmd);
     }
-    let status = runtime.block_on(
-        cmd.spawn()
-            .and_then(|c| c.wait().fcontext("failed to wait for child")),
-    )?;
+    let status = runtime.block_on(async {
+        let child = cmd.spawn().await?;
+        child.wait().await.context("failed to wait for child")
+    })?;
 
     Ok(status.code().unwrap_or_else(|| {
         if let Some(sig) = status_signal(status) {
@@ -533,6 +809,25 @@ where
     }))
 }
 
+/// Drop the compiler wrapper `wrapper` from ahead of the compiler, for
+/// sccache run as `sccache <wrapper> <compiler> ...`, since the server runs
+/// compiles through the wrapper itself.
+fn skip_next_wrapper(
+    exe: OsString,
+    mut cmdline: Vec<OsString>,
+    wrapper: Option<&Path>,
+) -> (OsString, Vec<OsString>) {
+    let is_wrapper = match wrapper {
+        Some(wrapper) => !cmdline.is_empty() && Path::new(&exe).file_stem() == wrapper.file_stem(),
+        None => false,
+    };
+    if !is_wrapper {
+        return (exe, cmdline);
+    }
+    let compiler = cmdline.remove(0);
+    (compiler, cmdline)
+}
+
 /// Send a `Compile` request to the sccache server `conn`, and handle the response.
 ///
 /// The first entry in `cmdline` will be looked up in `path` if it is not
@@ -541,13 +836,14 @@ where
 #[allow(clippy::too_many_arguments)]
 pub fn do_compile<T>(
     creator: T,
-    runtime: &mut Runtime,
+    runtime: &Runtime,
     mut conn: ServerConnection,
     exe: &Path,
     cmdline: Vec<OsString>,
     cwd: &Path,
     path: Option<OsString>,
     env_vars: Vec<(OsString, OsString)>,
+    next_wrapper: Option<&Path>,
     stdout: &mut dyn Write,
     stderr: &mut dyn Write,
 ) -> Result<i32>
@@ -558,26 +854,188 @@ where
     let exe_path = which_in(exe, path, &cwd)?;
     let res = request_compile(&mut conn, &exe_path, &cmdline, &cwd, env_vars)?;
     handle_compile_response(
-        creator, runtime, &mut conn, res, &exe_path, cmdline, cwd, stdout, stderr,
+        creator,
+        runtime,
+        &mut conn,
+        res,
+        &exe_path,
+        cmdline,
+        cwd,
+        next_wrapper,
+        stdout,
+        stderr,
+    )
+}
+
+/// Send a `Run` request for the command `exe` with `cmdline`, declaring that it
+/// reads `inputs` and writes `outputs`, to the sccache server `conn`, and
+/// handle the response like `do_compile` does.
+#[allow(clippy::too_many_arguments)]
+pub fn do_run<T>(
+    creator: T,
+    runtime: &Runtime,
+    mut conn: ServerConnection,
+    exe: &Path,
+    cmdline: Vec<OsString>,
+    cwd: &Path,
+    path: Option<OsString>,
+    env_vars: Vec<(OsString, OsString)>,
+    inputs: Vec<PathBuf>,
+    outputs: Vec<PathBuf>,
+    stdout: &mut dyn Write,
+    stderr: &mut dyn Write,
+) -> Result<i32>
+where
+    T: CommandCreatorSync,
+{
+    trace!("do_run");
+    let exe_path = which_in(exe, path, &cwd)?;
+    let res = request_run(
+        &mut conn,
+        Run {
+            exe: exe_path.clone().into(),
+            cwd: cwd.to_owned().into(),
+            args: cmdline.clone(),
+            env_vars,
+            inputs: inputs.into_iter().map(Into::into).collect(),
+            outputs: outputs.into_iter().map(Into::into).collect(),
+        },
+    )?;
+    handle_compile_response(
+        creator, runtime, &mut conn, res, &exe_path, cmdline, cwd, None, stdout, stderr,
     )
 }
 
 /// Run `cmd` and return the process exit status.
+/// Append the stats in `info` to the job summary of GitHub Actions, the file
+/// at `GITHUB_STEP_SUMMARY`, if it's set. Otherwise they're written to stdout
+/// if `or_stdout`.
+fn write_gha_summary(info: &ServerInfo, or_stdout: bool) -> Result<()> {
+    match env::var_os("GITHUB_STEP_SUMMARY").filter(|path| !path.is_empty()) {
+        Some(path) => {
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .with_context(|| format!("failed to open {:?}", path))?;
+            info.write_markdown(io::BufWriter::new(file))
+        }
+        None if or_stdout => info.write_markdown(io::stdout()),
+        None => Ok(()),
+    }
+}
+
 pub fn run_command(cmd: Command) -> Result<i32> {
+    // Diagnostics report a broken config rather than failing to load it
+    if let Command::Doctor = cmd {
+        trace!("Command::Doctor");
+        return doctor::run(get_port());
+    }
+    // Nor does validating it, which reports what's wrong with it
+    if let Command::ValidateConfig(path) = cmd {
+        trace!("Command::ValidateConfig({:?})", path);
+        let path = path.unwrap_or_else(config::config_file_path);
+        let problems = config::validate_config_file(&path)?;
+        if problems.is_empty() {
+            println!("{} is valid", path.display());
+            return Ok(0);
+        }
+        for problem in problems {
+            eprintln!("{}: {}", path.display(), problem);
+        }
+        return Ok(1);
+    }
+    // Nor do completions, which are written from the commandline definition
+    if let Command::Completions(shell) = cmd {
+        trace!("Command::Completions({})", shell);
+        cmdline::get_app().gen_completions_to(env!("CARGO_PKG_NAME"), shell, &mut io::stdout());
+        return Ok(0);
+    }
+    // The server's credentials are fetched before its config is loaded, which
+    // they may be part of
+    if let Command::InternalStartServer = cmd {
+        secrets::init()?;
+    }
     // Config isn't required for all commands, but if it's broken then we should flag
     // it early and loudly.
     let config = &Config::load()?;
 
     match cmd {
-        Command::ShowStats(fmt) => {
-            trace!("Command::ShowStats({:?})", fmt);
-            let srv = connect_or_start_server(get_port())?;
-            let stats = request_stats(srv).context("failed to get stats from server")?;
-            match fmt {
-                StatsFormat::text => stats.print(),
-                StatsFormat::json => serde_json::to_writer(&mut io::stdout(), &stats)?,
+        Command::ShowStats(fmt, project, view) => {
+            trace!("Command::ShowStats({:?}, {:?}, {:?})", fmt, project, view);
+            let srv = check_server_version(connect_or_start_server(get_port())?)?;
+            let stats = match project {
+                Some(project) => request_project_stats(srv, &project),
+                None => request_stats(srv),
+            }
+            .context("failed to get stats from server")?;
+            match (view, fmt) {
+                (StatsView::Summary, StatsFormat::text) => stats.print(),
+                (StatsView::Summary, StatsFormat::json) => stats.write_json(io::stdout())?,
+                (StatsView::Summary, StatsFormat::csv) => stats.write_csv(io::stdout())?,
+                (StatsView::Slowest, StatsFormat::text) => stats.print_slowest(),
+                (StatsView::Slowest, StatsFormat::json) => stats.write_slowest_json(io::stdout())?,
+                (StatsView::Slowest, StatsFormat::csv) => stats.write_slowest_csv(io::stdout())?,
+                (StatsView::Rust, StatsFormat::text) => stats.print_crates(),
+                (StatsView::Rust, StatsFormat::json) => stats.write_crates_json(io::stdout())?,
+                (StatsView::Rust, StatsFormat::csv) => stats.write_crates_csv(io::stdout())?,
+            }
+        }
+        Command::WatchStats(fmt) => {
+            trace!("Command::WatchStats({:?})", fmt);
+            let srv = check_server_version(connect_or_start_server(get_port())?)?;
+            // Redraw the line in a terminal, and print a line per update otherwise
+            let redraw = atty::is(Stream::Stdout);
+            let mut counts = LiveCounts::default();
+            if let StatsFormat::csv = fmt {
+                println!("compile_requests,cache_hits,cache_misses,cache_errors,compile_fails,cache_writes,compiles_active,cache_writes_active");
+            }
+            request_watch_stats(srv, |delta| {
+                delta.apply(&mut counts);
+                let stdout = io::stdout();
+                let mut stdout = stdout.lock();
+                match fmt {
+                    StatsFormat::text if redraw => {
+                        write!(stdout, "\r\x1b[K{}", live_stats_line(&counts, delta))?
+                    }
+                    StatsFormat::text => writeln!(stdout, "{}", live_stats_line(&counts, delta))?,
+                    StatsFormat::json => {
+                        let line = serde_json::json!({
+                            "counts": counts,
+                            "compiles_active": delta.compiles_active,
+                            "cache_writes_active": delta.cache_writes_active,
+                        });
+                        writeln!(stdout, "{}", line)?
+                    }
+                    StatsFormat::csv => writeln!(
+                        stdout,
+                        "{},{},{},{},{},{},{},{}",
+                        counts.compile_requests,
+                        counts.cache_hits,
+                        counts.cache_misses,
+                        counts.cache_errors,
+                        counts.compile_fails,
+                        counts.cache_writes,
+                        delta.compiles_active,
+                        delta.cache_writes_active
+                    )?,
+                }
+                stdout.flush()?;
+                Ok(())
+            })
+            .context("failed to watch stats from server")?;
+            if let StatsFormat::text = fmt {
+                if redraw {
+                    println!();
+                }
+                println!("The server shut down");
             }
         }
+        Command::Top => {
+            trace!("Command::Top");
+            let srv = check_server_version(connect_or_start_server(get_port())?)?;
+            top::run(srv)?;
+        }
         Command::InternalStartServer => {
             trace!("Command::InternalStartServer");
             // Can't report failure here, we're already daemonized.
@@ -603,19 +1061,53 @@ pub fn run_command(cmd: Command) -> Result<i32> {
         Command::StopServer => {
             trace!("Command::StopServer");
             println!("Stopping sccache server...");
-            let server = connect_to_server(get_port()).context("couldn't connect to server")?;
-            let stats = request_shutdown(server)?;
-            stats.print();
+            let port = get_port();
+            let mut server = connect_to_server(port).context("couldn't connect to server")?;
+            match server.hello()? {
+                Some(ref hello) if hello.protocol == PROTOCOL_VERSION => {
+                    let info = request_shutdown(server)?;
+                    info.print();
+                    // The last stats of a job go in its summary on GitHub Actions
+                    if env::var("SCCACHE_GHA_SUMMARY").ok().as_deref() != Some("0") {
+                        if let Err(e) = write_gha_summary(&info, false) {
+                            warn!("Failed to write the GitHub Actions job summary: {:?}", e);
+                        }
+                    }
+                }
+                hello => {
+                    // Any version shuts down, but its stats can't be read.
+                    // Servers without the handshake closed the connection.
+                    if hello.is_none() {
+                        server = connect_to_server(port).context("couldn't connect to server")?;
+                    }
+                    match server.request(Request::Shutdown) {
+                        Ok(_) => {}
+                        Err(e) if e.is::<bincode::Error>() => {}
+                        Err(e) => {
+                            return Err(
+                                e.context("Failed to send data to or receive data from server")
+                            )
+                        }
+                    }
+                    println!(
+                        "The server was {}, so its statistics can't be shown",
+                        describe_server(hello.as_ref())
+                    );
+                }
+            }
         }
-        Command::ZeroStats => {
-            trace!("Command::ZeroStats");
-            let conn = connect_or_start_server(get_port())?;
-            let stats = request_zero_stats(conn).context("couldn't zero stats on server")?;
+        Command::ZeroStats(project) => {
+            trace!("Command::ZeroStats({:?})", project);
+            let conn = check_server_version(connect_or_start_server(get_port())?)?;
+            let stats = match project {
+                Some(project) => request_zero_project_stats(conn, &project),
+                None => request_zero_stats(conn),
+            }
+            .context("couldn't zero stats on server")?;
             stats.print();
         }
         #[cfg(feature = "dist-client")]
         Command::DistAuth => {
-            use crate::config;
             use crate::dist;
             use url::Url;
 
@@ -663,26 +1155,258 @@ pub fn run_command(cmd: Command) -> Result<i32> {
                         .context("Unable to save auth token")?;
                     println!("Saved token")
                 }
+                config::DistAuth::Oauth2DeviceCode {
+                    client_id,
+                    device_auth_url,
+                    token_url,
+                    scope,
+                    audience,
+                } => {
+                    let cached_config = config::CachedConfig::load()?;
+
+                    let (token, refresh_token) = dist::client_auth::get_token_oauth2_device_code(
+                        client_id,
+                        device_auth_url,
+                        token_url,
+                        scope.as_deref(),
+                        audience.as_deref(),
+                    )?;
+
+                    cached_config
+                        .with_mut(|c| {
+                            c.dist.auth_tokens.insert(token_url.to_owned(), token);
+                            match refresh_token {
+                                Some(refresh_token) => {
+                                    c.dist
+                                        .refresh_tokens
+                                        .insert(token_url.to_owned(), refresh_token);
+                                }
+                                None => {
+                                    c.dist.refresh_tokens.remove(token_url);
+                                }
+                            }
+                        })
+                        .context("Unable to save auth token")?;
+                    println!("Saved token")
+                }
+                config::DistAuth::Oauth2ClientCredentials { .. } => {
+                    info!("No authentication needed for type 'oauth2_client_credentials'")
+                }
             };
         }
         #[cfg(not(feature = "dist-client"))]
         Command::DistAuth => bail!(
             "Distributed compilation not compiled in, please rebuild with the dist-client feature"
         ),
-        Command::DistStatus => {
-            trace!("Command::DistStatus");
-            let srv = connect_or_start_server(get_port())?;
+        Command::DistStatus(fmt) => {
+            trace!("Command::DistStatus({:?})", fmt);
+            let srv = check_server_version(connect_or_start_server(get_port())?)?;
             let status =
                 request_dist_status(srv).context("failed to get dist-status from server")?;
-            serde_json::to_writer(&mut io::stdout(), &status)?;
+            match fmt {
+                StatsFormat::json => serde_json::to_writer(&mut io::stdout(), &status)?,
+                _ => status.write_text(io::stdout())?,
+            }
+        }
+        Command::ClearToolchainCache => {
+            trace!("Command::ClearToolchainCache");
+            let conn = check_server_version(connect_or_start_server(get_port())?)?;
+            let freed = request_clear_toolchain_cache(conn)
+                .context("couldn't clear the toolchain cache")?;
+            let freed = match NumberPrefix::binary(freed as f64) {
+                NumberPrefix::Standalone(bytes) => format!("{} bytes", bytes),
+                NumberPrefix::Prefixed(prefix, n) => format!("{:.0} {}B", n, prefix),
+            };
+            println!("Cleared the toolchain cache, freeing {}", freed);
+        }
+        Command::GhaSummary => {
+            trace!("Command::GhaSummary");
+            let srv = check_server_version(connect_or_start_server(get_port())?)?;
+            let info = request_stats(srv).context("failed to get stats from server")?;
+            write_gha_summary(&info, true)?;
+        }
+        Command::ReloadConfig => {
+            trace!("Command::ReloadConfig");
+            let conn = connect_to_server(get_port()).context("couldn't connect to server")?;
+            let info = request_reload_config(check_server_version(conn)?)?;
+            println!("Reloaded the config, caching in {}", info.cache_location);
+        }
+        Command::ShowConfig => {
+            trace!("Command::ShowConfig");
+            print!("{}", config::describe_config()?);
+        }
+        Command::AuditLog(key, fmt) => {
+            use crate::cache::audit::{audit_log_path, read_audit_log};
+
+            trace!("Command::AuditLog({:?})", key);
+            let path = audit_log_path(config);
+            if !path.exists() {
+                bail!(
+                    "There is no audit log at {}, the server writes it if SCCACHE_AUDIT_LOG=1",
+                    path.display()
+                );
+            }
+            let records = read_audit_log(&path, key.as_deref())?;
+            match fmt {
+                StatsFormat::json => serde_json::to_writer(&mut io::stdout(), &records)?,
+                _ => {
+                    for record in records {
+                        let who = format!(
+                            "{}@{}",
+                            record.provenance.user.as_deref().unwrap_or("?"),
+                            record.provenance.host.as_deref().unwrap_or("?")
+                        );
+                        println!(
+                            "{} {} {} {} {}",
+                            record.time,
+                            record.key,
+                            who,
+                            record.provenance.ci_job.as_deref().unwrap_or("-"),
+                            record.storage
+                        );
+                    }
+                }
+            }
+        }
+        Command::CheckStorage => {
+            use crate::cache::{check_storage, storage_from_config};
+            use futures::executor::ThreadPool;
+
+            trace!("Command::CheckStorage");
+            let runtime = util::runtime()?;
+            let pool = ThreadPool::builder().pool_size(1).create()?;
+            let storage = storage_from_config(config, &pool);
+            let location = storage.location();
+            let read_only = storage.is_read_only();
+            runtime.block_on(check_storage(storage))?;
+            if read_only {
+                println!("{} can be read from, and is read-only", location);
+            } else {
+                println!("{} can be written to, read from and deleted from", location);
+            }
+        }
+        Command::MigrateCache => {
+            use crate::cache::migrate::migrate_disk_cache;
+
+            trace!("Command::MigrateCache");
+            if connect_to_server(get_port()).is_ok() {
+                bail!(
+                    "The server is using the cache, stop it with `sccache --stop-server` \
+                     before migrating it"
+                );
+            }
+            let dir = &config.fallback_cache.dir;
+            let migration = migrate_disk_cache(dir)?;
+            println!("Entries in {} by version:", dir.display());
+            for (version, count) in &migration.versions {
+                println!("  {}: {}", version, count);
+            }
+            println!(
+                "Kept {} entries, rewrote {} in the current format and removed {}",
+                migration.kept, migration.rewritten, migration.pruned
+            );
+        }
+        Command::Doctor | Command::ValidateConfig(_) | Command::Completions(_) => {
+            unreachable!("handled before loading the config")
+        }
+        Command::GenerateCmakeLauncher(path) => {
+            trace!("Command::GenerateCmakeLauncher({:?})", path);
+            cmake::generate_launcher(&path)?;
+        }
+        Command::GenerateMsbuildProps(dir) => {
+            trace!("Command::GenerateMsbuildProps({:?})", dir);
+            msbuild::generate_props(&dir)?;
+        }
+        Command::GenerateXcodeConfig(dir) => {
+            trace!("Command::GenerateXcodeConfig({:?})", dir);
+            xcode::generate_config(&dir)?;
+        }
+        Command::Prewarm(path, jobs) => {
+            trace!("Command::Prewarm");
+            check_server_version(connect_or_start_server(get_port())?)?;
+            prewarm::run(get_port(), &path, jobs)?;
+        }
+        Command::PrefetchNinja(dir, jobs) => {
+            trace!("Command::PrefetchNinja");
+            check_server_version(connect_or_start_server(get_port())?)?;
+            prewarm::run_ninja(get_port(), &dir, jobs)?;
+        }
+        Command::StartSession(name) => {
+            trace!("Command::StartSession");
+            let conn = check_server_version(connect_or_start_server(get_port())?)?;
+            request_start_session(conn, &name).context("couldn't start the build session")?;
+            println!("Started build session {:?}", name);
+        }
+        Command::EndSession(name) => {
+            trace!("Command::EndSession");
+            let conn = connect_to_server(get_port()).context("couldn't connect to server")?;
+            let sessions = request_end_session(check_server_version(conn)?, &name)
+                .context("couldn't end the build session")?;
+            if sessions.is_empty() {
+                println!("Ended build session {:?}", name);
+            } else {
+                println!(
+                    "Ended build session {:?}, still open: {}",
+                    name,
+                    sessions.join(", ")
+                );
+            }
+        }
+        Command::Admin(request) => {
+            trace!("Command::Admin");
+            let port = get_port();
+            let path = server::get_admin_token_path(port).ok_or_else(|| {
+                anyhow!("admin requests are disabled by SCCACHE_ADMIN_TOKEN_PATH")
+            })?;
+            let token = fs::read_to_string(&path).with_context(|| {
+                format!(
+                    "couldn't read the server's admin token from {}",
+                    path.display()
+                )
+            })?;
+            let conn = connect_to_server(port).context("couldn't connect to server")?;
+            match request_admin(check_server_version(conn)?, token.trim(), request)? {
+                AdminResponse::Denied => bail!(
+                    "The server denied the request, as its admin token isn't the one in {}",
+                    path.display()
+                ),
+                AdminResponse::Flushed(Ok(())) => {
+                    println!("Forgot the detected compilers and emptied the cache")
+                }
+                AdminResponse::Flushed(Err(e)) => {
+                    bail!(
+                        "Forgot the detected compilers, but couldn't empty the cache: {}",
+                        e
+                    )
+                }
+                AdminResponse::KeyStatus(found) => {
+                    for (location, found) in found {
+                        match found {
+                            Ok(true) => println!("{}: found", location),
+                            Ok(false) => println!("{}: not found", location),
+                            Err(e) => println!("{}: couldn't look it up: {}", location, e),
+                        }
+                    }
+                }
+                AdminResponse::ReadOnly(true) => {
+                    println!("The server no longer writes to the cache")
+                }
+                AdminResponse::ReadOnly(false) => println!("The server writes to the cache again"),
+                AdminResponse::Offline(true) => println!(
+                    "The server only uses the local disk cache until `sccache --offline off`"
+                ),
+                AdminResponse::Offline(false) => println!("The server uses remote storage again"),
+                AdminResponse::Cleared(Ok(())) => println!("Emptied the cache"),
+                AdminResponse::Cleared(Err(e)) => bail!("Couldn't empty the cache: {}", e),
+            }
         }
         #[cfg(feature = "dist-client")]
         Command::PackageToolchain(executable, out) => {
             use crate::compiler;
-            use futures_03::executor::ThreadPool;
+            use futures::executor::ThreadPool;
 
             trace!("Command::PackageToolchain({})", executable.display());
-            let mut runtime = Runtime::new()?;
+            let runtime = util::runtime()?;
             let jobserver = unsafe { Client::new() };
             let creator = ProcessCommandCreator::new(&jobserver);
             let env: Vec<_> = env::vars_os().collect();
@@ -690,11 +1414,15 @@ pub fn run_command(cmd: Command) -> Result<i32> {
             let out_file = File::create(out)?;
             let cwd = env::current_dir().expect("A current working dir should exist");
 
-            let compiler =
-                compiler::get_compiler_info(creator, &executable, &cwd, &env, &pool, None);
-            let packager = compiler.map(|c| c.0.get_toolchain_packager());
-            let res = packager.and_then(|p| p.write_pkg(out_file));
-            runtime.block_on(res)?
+            let (compiler, _) = runtime.block_on(compiler::get_compiler_info(
+                creator,
+                &executable,
+                &cwd,
+                &env,
+                &pool,
+                None,
+            ))?;
+            compiler.get_toolchain_packager().write_pkg(out_file)?
         }
         #[cfg(not(feature = "dist-client"))]
         Command::PackageToolchain(_executable, _out) => bail!(
@@ -707,23 +1435,72 @@ pub fn run_command(cmd: Command) -> Result<i32> {
             env_vars,
         } => {
             trace!("Command::Compile {{ {:?}, {:?}, {:?} }}", exe, cmdline, cwd);
+            let next_wrapper = config.hash.next_wrapper.as_deref();
+            let (exe, cmdline) = skip_next_wrapper(exe, cmdline, next_wrapper);
             let jobserver = unsafe { Client::new() };
             let conn = connect_or_start_server(get_port())?;
-            let mut runtime = Runtime::new()?;
+            let runtime = util::runtime()?;
             let res = do_compile(
                 ProcessCommandCreator::new(&jobserver),
-                &mut runtime,
+                &runtime,
                 conn,
                 exe.as_ref(),
                 cmdline,
                 &cwd,
                 env::var_os("PATH"),
                 env_vars,
+                next_wrapper,
                 &mut io::stdout(),
                 &mut io::stderr(),
             );
             return res.context("failed to execute compile");
         }
+        Command::DryRun {
+            exe,
+            cmdline,
+            cwd,
+            env_vars,
+        } => {
+            trace!("Command::DryRun {{ {:?}, {:?}, {:?} }}", exe, cmdline, cwd);
+            let conn = check_server_version(connect_or_start_server(get_port())?)?;
+            let exe = which_in(&exe, env::var_os("PATH"), &cwd)?;
+            let compile = Compile {
+                exe: exe.into(),
+                cwd: cwd.into(),
+                args: cmdline,
+                env_vars,
+            };
+            let result = request_dry_run(conn, compile).context("failed to dry-run compile")?;
+            return print_dry_run(&result, io::stdout());
+        }
+        Command::Run {
+            exe,
+            cmdline,
+            cwd,
+            env_vars,
+            inputs,
+            outputs,
+        } => {
+            trace!("Command::Run {{ {:?}, {:?}, {:?} }}", exe, cmdline, cwd);
+            let jobserver = unsafe { Client::new() };
+            let conn = connect_or_start_server(get_port())?;
+            let runtime = util::runtime()?;
+            let res = do_run(
+                ProcessCommandCreator::new(&jobserver),
+                &runtime,
+                conn,
+                exe.as_ref(),
+                cmdline,
+                &cwd,
+                env::var_os("PATH"),
+                env_vars,
+                inputs,
+                outputs,
+                &mut io::stdout(),
+                &mut io::stderr(),
+            );
+            return res.context("failed to execute command");
+        }
     }
 
     Ok(0)
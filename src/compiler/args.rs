@@ -528,6 +528,22 @@ pub trait SearchableArgInfo<T> {
 /// data.
 impl<T: ArgumentValue> SearchableArgInfo<T> for &'static [ArgInfo<T>] {
     fn search(&self, key: &str) -> Option<&ArgInfo<T>> {
+        // "-arg=value" sorts after the arguments "-arg" is a prefix of, such
+        // as "-arg-other", so an argument taking its value that way is looked
+        // up by its name first
+        if let Some(eq) = key.find('=') {
+            let by_name = bsearch(&key[..eq], self, |i, k| i.cmp(k)).filter(|info| match info {
+                ArgInfo::TakeArg(s, _, ArgDisposition::CanBeSeparated(d))
+                | ArgInfo::TakeArg(s, _, ArgDisposition::Concatenated(d))
+                | ArgInfo::TakeArg(s, _, ArgDisposition::CanBeConcatenated(d)) => {
+                    s.len() == eq && *d == Some(b'=')
+                }
+                _ => false,
+            });
+            if by_name.is_some() {
+                return by_name;
+            }
+        }
         bsearch(key, self, |i, k| i.cmp(k))
     }
 
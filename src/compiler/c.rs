@@ -12,19 +12,26 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::compiler::digest_cache::{compiler_digest, input_digests};
+use crate::compiler::icecc;
 use crate::compiler::{
-    Cacheable, ColorMode, Compilation, CompileCommand, Compiler, CompilerArguments, CompilerHasher,
-    CompilerKind, HashResult,
+    cache_schema, debug_dir, Cacheable, ColorMode, Compilation, CompileCommand, Compiler,
+    CompilerArguments, CompilerHasher, CompilerKind, HashResult,
 };
 #[cfg(feature = "dist-client")]
 use crate::compiler::{DistPackagers, NoopOutputsRewriter};
+use crate::config::{HashConfig, Sloppiness};
 use crate::dist;
 #[cfg(feature = "dist-client")]
 use crate::dist::pkg;
 use crate::mock_command::CommandCreatorSync;
-use crate::util::{hash_all, Digest, HashToDigest};
-use futures::Future;
-use futures_03::executor::ThreadPool;
+use crate::trace::Span;
+use crate::util::{
+    glob_match, relativize_base_dir, Digest, HashInput, HashToDigest, RecordingDigest, SpawnExt,
+};
+use async_trait::async_trait;
+use futures::executor::ThreadPool;
+use futures::future;
 use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
 use std::ffi::{OsStr, OsString};
@@ -46,6 +53,7 @@ where
 {
     executable: PathBuf,
     executable_digest: String,
+    version: Option<String>,
     compiler: I,
 }
 
@@ -68,6 +76,13 @@ pub enum Language {
     ObjectiveC,
     ObjectiveCxx,
     Cuda,
+    /// Assembly that is not preprocessed (`.s`).
+    Assembler,
+    /// Assembly that goes through the C preprocessor (`.S`).
+    AssemblerWithCpp,
+    /// Windows resource scripts (`.rc`), which only resource compilers
+    /// compile.
+    Resource,
 }
 
 /// The results of parsing a compiler commandline.
@@ -98,6 +113,14 @@ pub struct ParsedArguments {
     pub profile_generate: bool,
     /// The color mode.
     pub color_mode: ColorMode,
+    /// Target architectures given with `-arch`, in command line order.
+    pub arch_args: Vec<OsString>,
+    /// The compilation database fragment to write, as requested by `-MJ`.
+    pub compilation_db_fragment: Option<PathBuf>,
+    /// Arguments telling clang where to write index data for IDEs, such as
+    /// Xcode's `-index-store-path`. They're passed to local compiles but not
+    /// hashed, as the index isn't cached.
+    pub index_args: Vec<OsString>,
 }
 
 impl ParsedArguments {
@@ -118,6 +141,8 @@ impl Language {
             Some("m") => Some(Language::ObjectiveC),
             Some("mm") => Some(Language::ObjectiveCxx),
             Some("cu") => Some(Language::Cuda),
+            Some("s") => Some(Language::Assembler),
+            Some("S") | Some("sx") => Some(Language::AssemblerWithCpp),
             e => {
                 trace!("Unknown source extension: {}", e.unwrap_or("(None)"));
                 None
@@ -132,8 +157,30 @@ impl Language {
             Language::ObjectiveC => "objc",
             Language::ObjectiveCxx => "objc++",
             Language::Cuda => "cuda",
+            Language::Assembler => "assembler",
+            Language::AssemblerWithCpp => "assembler-with-cpp",
+            Language::Resource => "resource",
+        }
+    }
+
+    /// The name of the language, as the stats are broken down by.
+    pub fn name(self) -> &'static str {
+        match self {
+            Language::C => "C",
+            Language::Cxx => "C++",
+            Language::ObjectiveC => "Objective-C",
+            Language::ObjectiveCxx => "Objective-C++",
+            Language::Cuda => "CUDA",
+            Language::Assembler | Language::AssemblerWithCpp => "Assembler",
+            Language::Resource => "Resource",
         }
     }
+
+    /// Whether this is an assembly language, which only the GCC-like
+    /// compilers know how to handle.
+    pub fn is_assembler(self) -> bool {
+        matches!(self, Language::Assembler | Language::AssemblerWithCpp)
+    }
 }
 
 /// A generic implementation of the `Compilation` trait for C/C++ compilers.
@@ -145,6 +192,7 @@ struct CCompilation<I: CCompilerImpl> {
     compiler: I,
     cwd: PathBuf,
     env_vars: Vec<(OsString, OsString)>,
+    not_cacheable: Option<&'static str>,
 }
 
 /// Supported C compilers.
@@ -160,9 +208,16 @@ pub enum CCompilerKind {
     MSVC,
     /// NVIDIA cuda compiler
     NVCC,
+    /// TI Code Generation Tools
+    TI,
+    /// The Windows SDK resource compiler, rc.exe
+    RC,
+    /// GNU windres
+    Windres,
 }
 
 /// An interface to a specific C compiler.
+#[async_trait(?Send)]
 pub trait CCompilerImpl: Clone + fmt::Debug + Send + 'static {
     /// Return the kind of compiler.
     fn kind(&self) -> CCompilerKind;
@@ -176,7 +231,7 @@ pub trait CCompilerImpl: Clone + fmt::Debug + Send + 'static {
     ) -> CompilerArguments<ParsedArguments>;
     /// Run the C preprocessor with the specified set of arguments.
     #[allow(clippy::too_many_arguments)]
-    fn preprocess<T>(
+    async fn preprocess<T>(
         &self,
         creator: &T,
         executable: &Path,
@@ -185,9 +240,38 @@ pub trait CCompilerImpl: Clone + fmt::Debug + Send + 'static {
         env_vars: &[(OsString, OsString)],
         may_dist: bool,
         rewrite_includes_only: bool,
-    ) -> SFuture<process::Output>
+    ) -> Result<process::Output>
     where
         T: CommandCreatorSync;
+    /// Run the C preprocessor like `preprocess`, also returning the digest
+    /// of its output if it was hashed as the preprocessor wrote it.
+    #[allow(clippy::too_many_arguments)]
+    async fn preprocess_hashed<T>(
+        &self,
+        creator: &T,
+        executable: &Path,
+        parsed_args: &ParsedArguments,
+        cwd: &Path,
+        env_vars: &[(OsString, OsString)],
+        may_dist: bool,
+        rewrite_includes_only: bool,
+    ) -> Result<(process::Output, Option<String>)>
+    where
+        T: CommandCreatorSync,
+    {
+        let output = self
+            .preprocess(
+                creator,
+                executable,
+                parsed_args,
+                cwd,
+                env_vars,
+                may_dist,
+                rewrite_includes_only,
+            )
+            .await?;
+        Ok((output, None))
+    }
     /// Generate a command that can be used to invoke the C compiler to perform
     /// the compilation.
     fn generate_compile_commands(
@@ -205,28 +289,28 @@ impl<I> CCompiler<I>
 where
     I: CCompilerImpl,
 {
-    pub fn new(
+    pub async fn new(
         compiler: I,
         executable: PathBuf,
         version: Option<String>,
         pool: &ThreadPool,
-    ) -> SFuture<CCompiler<I>> {
-        Box::new(
-            Digest::file(executable.clone(), &pool).map(move |digest| CCompiler {
-                executable,
-                executable_digest: {
-                    if let Some(version) = version {
-                        let mut m = Digest::new();
-                        m.update(digest.as_bytes());
-                        m.update(version.as_bytes());
-                        m.finish()
-                    } else {
-                        digest
-                    }
-                },
-                compiler,
-            }),
-        )
+    ) -> Result<CCompiler<I>> {
+        let digest = compiler_digest(&executable, pool).await?;
+        Ok(CCompiler {
+            executable,
+            executable_digest: {
+                if let Some(ref version) = version {
+                    let mut m = Digest::new();
+                    m.update(digest.as_bytes());
+                    m.update(version.as_bytes());
+                    m.finish()
+                } else {
+                    digest
+                }
+            },
+            version,
+            compiler,
+        })
     }
 }
 
@@ -260,17 +344,22 @@ impl<T: CommandCreatorSync, I: CCompilerImpl> Compiler<T> for CCompiler<I> {
         }
     }
 
+    fn identity(&self) -> (String, Option<String>) {
+        (self.executable_digest.clone(), self.version.clone())
+    }
+
     fn box_clone(&self) -> Box<dyn Compiler<T>> {
         Box::new((*self).clone())
     }
 }
 
+#[async_trait(?Send)]
 impl<T, I> CompilerHasher<T> for CCompilerHasher<I>
 where
     T: CommandCreatorSync,
     I: CCompilerImpl,
 {
-    fn generate_hash_key(
+    async fn generate_hash_key(
         self: Box<Self>,
         creator: &T,
         cwd: PathBuf,
@@ -278,7 +367,9 @@ where
         may_dist: bool,
         pool: &ThreadPool,
         rewrite_includes_only: bool,
-    ) -> SFuture<HashResult> {
+        hash_config: &HashConfig,
+        span: &Span,
+    ) -> Result<HashResult> {
         let me = *self;
         let CCompilerHasher {
             parsed_args,
@@ -286,104 +377,154 @@ where
             executable_digest,
             compiler,
         } = me;
-        let result = compiler.preprocess(
-            creator,
-            &executable,
-            &parsed_args,
-            &cwd,
-            &env_vars,
-            may_dist,
-            rewrite_includes_only,
-        );
         let out_pretty = parsed_args.output_pretty().into_owned();
-        let result = result.map_err(move |e| {
-            debug!("[{}]: preprocessor failed: {:?}", out_pretty, e);
-            e
-        });
-        let out_pretty = parsed_args.output_pretty().into_owned();
-        let extra_hashes = hash_all(&parsed_args.extra_hash_files, &pool.clone());
-        let outputs = parsed_args.outputs.clone();
-        let args_cwd = cwd.clone();
-
-        Box::new(
-            result
-                .or_else(move |err| {
-                    // Errors remove all traces of potential output.
-                    debug!("removing files {:?}", &outputs);
-
-                    let v: std::result::Result<(), std::io::Error> =
-                        outputs.values().fold(Ok(()), |r, f| {
-                            r.and_then(|_| {
-                                let mut path = (&args_cwd).clone();
-                                path.push(&f);
-                                match fs::metadata(&path) {
-                                    // File exists, remove it.
-                                    Ok(_) => fs::remove_file(&path),
-                                    _ => Ok(()),
-                                }
-                            })
-                        });
-                    if v.is_err() {
-                        warn!("Could not remove files after preprocessing failed!\n");
-                    }
+        let preprocess = async {
+            let preprocess_span = span.child("preprocess");
+            let result = compiler
+                .preprocess_hashed(
+                    creator,
+                    &executable,
+                    &parsed_args,
+                    &cwd,
+                    &env_vars,
+                    may_dist,
+                    rewrite_includes_only,
+                )
+                .await;
+            preprocess_span.end();
+            result.map_err(|e| {
+                debug!("[{}]: preprocessor failed: {:?}", out_pretty, e);
+                e
+            })
+        };
+        let extra_hashes = input_digests(&parsed_args.extra_hash_files, pool);
+        let (result, extra_hashes) = future::join(preprocess, extra_hashes).await;
+        // Profiling and coverage builds embed the absolute path of the object
+        // (for the `.gcda` file) and the working directory (in the `.gcno` file)
+        // into their outputs, so they are only reusable from the same directory.
+        // Likewise, split DWARF objects locate their `.dwo` relative to the
+        // compilation directory recorded in the object.
+        let cwd_hash = if parsed_args.profile_generate || parsed_args.outputs.contains_key("dwo") {
+            Some(cwd.to_string_lossy().into_owned())
+        } else {
+            None
+        };
+        let hashed_args = hashed_args(&parsed_args.common_args, hash_config, &cwd);
+        let time_macros_sloppy = hash_config.is_sloppy(Sloppiness::TimeMacros);
+
+        let (preprocessor_result, stdout_digest) = match result {
+            Ok(result) => result,
+            Err(err) => {
+                // Errors remove all traces of potential output.
+                debug!("removing files {:?}", &parsed_args.outputs);
+
+                let v: std::result::Result<(), std::io::Error> =
+                    parsed_args.outputs.values().fold(Ok(()), |r, f| {
+                        r.and_then(|_| {
+                            let mut path = cwd.clone();
+                            path.push(&f);
+                            match fs::metadata(&path) {
+                                // File exists, remove it.
+                                Ok(_) => fs::remove_file(&path),
+                                _ => Ok(()),
+                            }
+                        })
+                    });
+                if v.is_err() {
+                    warn!("Could not remove files after preprocessing failed!\n");
+                }
 
-                    match err.downcast::<ProcessError>() {
-                        Ok(ProcessError(output)) => {
-                            debug!(
-                                "[{}]: preprocessor returned error status {:?}",
-                                out_pretty,
-                                output.status.code()
-                            );
-                            // Drop the stdout since it's the preprocessor output,
-                            // just hand back stderr and the exit status.
-                            bail!(ProcessError(process::Output {
-                                stdout: vec!(),
-                                ..output
-                            }))
-                        }
-                        Err(err) => Err(err),
+                return match err.downcast::<ProcessError>() {
+                    Ok(ProcessError(output)) => {
+                        debug!(
+                            "[{}]: preprocessor returned error status {:?}",
+                            out_pretty,
+                            output.status.code()
+                        );
+                        // Drop the stdout since it's the preprocessor output,
+                        // just hand back stderr and the exit status.
+                        bail!(ProcessError(process::Output {
+                            stdout: vec!(),
+                            ..output
+                        }))
                     }
-                })
-                .and_then(move |preprocessor_result| {
-                    trace!(
-                        "[{}]: Preprocessor output is {} bytes",
-                        parsed_args.output_pretty(),
-                        preprocessor_result.stdout.len()
-                    );
-
-                    Box::new(extra_hashes.and_then(move |extra_hashes| {
-                        let key = {
-                            hash_key(
-                                &executable_digest,
-                                parsed_args.language,
-                                &parsed_args.common_args,
-                                &extra_hashes,
-                                &env_vars,
-                                &preprocessor_result.stdout,
-                                compiler.plusplus(),
-                            )
-                        };
-                        // A compiler binary may be a symlink to another and so has the same digest, but that means
-                        // the toolchain will not contain the correct path to invoke the compiler! Add the compiler
-                        // executable path to try and prevent this
-                        let weak_toolchain_key =
-                            format!("{}-{}", executable.to_string_lossy(), executable_digest);
-                        Ok(HashResult {
-                            key,
-                            compilation: Box::new(CCompilation {
-                                parsed_args,
-                                #[cfg(feature = "dist-client")]
-                                preprocessed_input: preprocessor_result.stdout,
-                                executable,
-                                compiler,
-                                cwd,
-                                env_vars,
-                            }),
-                            weak_toolchain_key,
-                        })
-                    }))
-                }),
-        )
+                    Err(err) => Err(err),
+                };
+            }
+        };
+        trace!(
+            "[{}]: Preprocessor output is {} bytes",
+            parsed_args.output_pretty(),
+            preprocessor_result.stdout.len()
+        );
+
+        // Scanned off the event loop, as the output may be large.
+        // The preprocessor expands `__DATE__` and friends into what's
+        // hashed, unless it merely rewrote the includes, leaving them
+        // to the compiler.
+        let language = parsed_args.language;
+        let (preprocessor_result, not_cacheable) = pool
+            .spawn_fn(move || {
+                let stdout = &preprocessor_result.stdout;
+                let not_cacheable = if !time_macros_sloppy && uses_time_macros(stdout) {
+                    Some("time macros")
+                } else if language.is_assembler() && uses_asm_includes(stdout) {
+                    Some("assembler includes")
+                } else {
+                    None
+                };
+                Ok((preprocessor_result, not_cacheable))
+            })
+            .await?;
+        let mut extra_hashes = extra_hashes?;
+        extra_hashes.extend(cwd_hash);
+        // With a base directory, paths under it are hashed relative to
+        // the working directory so that other checkouts can share hits.
+        // Otherwise the output was hashed as the preprocessor wrote it,
+        // if it could be.
+        let stdout = &preprocessor_result.stdout;
+        let (preprocessed_digest, preprocessed_len) = match (&hash_config.base_dir, stdout_digest) {
+            (None, Some(digest)) => (digest, stdout.len()),
+            (base_dir, _) => {
+                let preprocessed = match base_dir {
+                    Some(ref base_dir) => relativize_base_dir(stdout, base_dir, &cwd),
+                    None => Cow::Borrowed(&stdout[..]),
+                };
+                let mut m = Digest::new();
+                m.update(&preprocessed);
+                (m.finish(), preprocessed.len())
+            }
+        };
+        let (key, hash_inputs) = hash_key_with_inputs(
+            &executable_digest,
+            parsed_args.language,
+            &hashed_args,
+            &extra_hashes,
+            &env_vars,
+            &preprocessed_digest,
+            preprocessed_len,
+            compiler.plusplus(),
+            debug_dir().is_some(),
+        );
+        // A compiler binary may be a symlink to another and so has the same digest, but that means
+        // the toolchain will not contain the correct path to invoke the compiler! Add the compiler
+        // executable path to try and prevent this
+        let weak_toolchain_key = format!("{}-{}", executable.to_string_lossy(), executable_digest);
+        Ok(HashResult {
+            key,
+            compilation: Box::new(CCompilation {
+                parsed_args,
+                #[cfg(feature = "dist-client")]
+                preprocessed_input: preprocessor_result.stdout,
+                executable,
+                compiler,
+                cwd,
+                env_vars,
+                not_cacheable,
+            }),
+            weak_toolchain_key,
+            hash_inputs,
+        })
     }
 
     fn color_mode(&self) -> ColorMode {
@@ -394,6 +535,18 @@ where
         self.parsed_args.output_pretty()
     }
 
+    fn input_pretty(&self) -> Cow<'_, str> {
+        self.parsed_args.input.to_string_lossy()
+    }
+
+    fn language(&self) -> Option<&'static str> {
+        Some(self.parsed_args.language.name())
+    }
+
+    fn cache_schema(&self) -> String {
+        cache_schema("c", CACHE_VERSION)
+    }
+
     fn box_clone(&self) -> Box<dyn CompilerHasher<T>> {
         Box::new((*self).clone())
     }
@@ -413,14 +566,16 @@ impl<I: CCompilerImpl> Compilation for CCompilation<I> {
             ref env_vars,
             ..
         } = *self;
-        compiler.generate_compile_commands(
+        let (compile_cmd, dist_compile_cmd, cacheable) = compiler.generate_compile_commands(
             path_transformer,
             executable,
             parsed_args,
             cwd,
             env_vars,
             rewrite_includes_only,
-        )
+        )?;
+        let compile_cmd = icecc::wrap(compiler.kind(), compile_cmd);
+        Ok((compile_cmd, dist_compile_cmd, cacheable))
     }
 
     #[cfg(feature = "dist-client")]
@@ -456,6 +611,105 @@ impl<I: CCompilerImpl> Compilation for CCompilation<I> {
     fn outputs<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a str, &'a Path)> + 'a> {
         Box::new(self.parsed_args.outputs.iter().map(|(k, v)| (*k, &**v)))
     }
+
+    fn write_local_outputs(&self) -> Result<()> {
+        let fragment = match self.parsed_args.compilation_db_fragment {
+            Some(ref fragment) => self.cwd.join(fragment),
+            None => return Ok(()),
+        };
+        let entry = compilation_db_entry(&self.executable, &self.parsed_args, &self.cwd);
+        fs::write(&fragment, format!("{},\n", entry))
+            .with_context(|| format!("failed to write {}", fragment.display()))
+    }
+    fn not_cacheable_reason(&self) -> Option<&'static str> {
+        self.not_cacheable
+    }
+}
+
+/// The arguments that go into the hash: `args` without those matching one of
+/// the configured ignore patterns, with paths under the base directory made
+/// relative to `cwd`.
+fn hashed_args(args: &[OsString], hash_config: &HashConfig, cwd: &Path) -> Vec<OsString> {
+    args.iter()
+        .filter(|arg| {
+            let arg = arg.to_string_lossy();
+            !hash_config
+                .ignore_args
+                .iter()
+                .any(|pattern| glob_match(pattern, &arg))
+        })
+        .map(|arg| match hash_config.base_dir {
+            Some(ref base_dir) => {
+                let arg = arg.to_string_lossy();
+                let arg = relativize_base_dir(arg.as_bytes(), base_dir, cwd);
+                String::from_utf8_lossy(&arg).into_owned().into()
+            }
+            None => arg.clone(),
+        })
+        .collect()
+}
+
+/// Returns true if `source` uses `__DATE__`, `__TIME__` or `__TIMESTAMP__`,
+/// whose expansions differ between builds of otherwise identical input.
+fn uses_time_macros(source: &[u8]) -> bool {
+    let is_ident = |b: u8| b.is_ascii_alphanumeric() || b == b'_';
+    [&b"__DATE__"[..], b"__TIME__", b"__TIMESTAMP__"]
+        .iter()
+        .any(|name| {
+            source.windows(name.len()).enumerate().any(|(i, window)| {
+                window == *name
+                    && (i == 0 || !is_ident(source[i - 1]))
+                    && source.get(i + name.len()).map_or(true, |&b| !is_ident(b))
+            })
+        })
+}
+
+/// Returns true if the assembly `source` may use `.include` or `.incbin`,
+/// which read files the assembler finds itself, so that they aren't part of
+/// the preprocessor output nor hashed. Comments and strings aren't told apart
+/// from directives, so this errs on the side of not caching.
+fn uses_asm_includes(source: &[u8]) -> bool {
+    let is_ident = |b: u8| b.is_ascii_alphanumeric() || b == b'_' || b == b'.' || b == b'$';
+    [&b".include"[..], b".incbin"].iter().any(|name| {
+        source.windows(name.len()).enumerate().any(|(i, window)| {
+            window.eq_ignore_ascii_case(name)
+                && (i == 0 || !is_ident(source[i - 1]))
+                && source.get(i + name.len()).map_or(true, |&b| !is_ident(b))
+        })
+    })
+}
+
+/// Serialize the compilation into a JSON compilation database entry, in the
+/// same shape clang writes for `-MJ`.
+fn compilation_db_entry(
+    executable: &Path,
+    parsed_args: &ParsedArguments,
+    cwd: &Path,
+) -> serde_json::Value {
+    let lossy = |s: &OsStr| s.to_string_lossy().into_owned();
+    let mut arguments = vec![
+        lossy(executable.as_os_str()),
+        lossy(parsed_args.input.as_os_str()),
+        lossy(&parsed_args.compilation_flag),
+    ];
+    let output = parsed_args.outputs.get("obj");
+    if let Some(output) = output {
+        arguments.push("-o".to_owned());
+        arguments.push(lossy(output.as_os_str()));
+    }
+    arguments.extend(
+        parsed_args
+            .preprocessor_args
+            .iter()
+            .chain(&parsed_args.common_args)
+            .map(|arg| lossy(arg)),
+    );
+    serde_json::json!({
+        "directory": lossy(cwd.as_os_str()),
+        "file": lossy(parsed_args.input.as_os_str()),
+        "output": output.map(|o| lossy(o.as_os_str())),
+        "arguments": arguments,
+    })
 }
 
 #[cfg(feature = "dist-client")]
@@ -637,8 +891,109 @@ impl pkg::ToolchainPackager for CToolchainPackager {
     }
 }
 
+#[cfg(feature = "dist-client")]
+#[cfg(target_os = "macos")]
+impl pkg::ToolchainPackager for CToolchainPackager {
+    fn write_pkg(self: Box<Self>, f: fs::File) -> Result<()> {
+        info!("Generating toolchain {}", self.executable.display());
+        if self.kind != CCompilerKind::Clang {
+            bail!(
+                "Automatic packaging of {:?} is not supported on macOS",
+                self.kind
+            )
+        }
+        let mut package_builder = pkg::ToolchainPackageBuilder::new();
+        package_builder.add_common()?;
+
+        // The compilers in /usr/bin are shims that run the clang of the
+        // selected Xcode or Command Line Tools, which is packaged in their
+        // place.
+        let real_executable = if self.executable.starts_with("/usr/bin") {
+            let name = self
+                .executable
+                .file_name()
+                .context("Compiler executable has no file name")?;
+            let output = process::Command::new("xcrun")
+                .arg("--find")
+                .arg(name)
+                .output()
+                .context("Failed to run xcrun")?;
+            if !output.status.success() {
+                bail!("xcrun could not find {:?}", name)
+            }
+            let path = String::from_utf8(output.stdout).context("xcrun output not utf8")?;
+            PathBuf::from(path.trim())
+        } else {
+            self.executable.clone()
+        };
+        package_builder.add_executable_and_deps_as(real_executable.clone(), &self.executable)?;
+
+        // Clang looks for its internal headers relative to the path it runs
+        // from, so add them relative to the executable the build server runs.
+        let output = process::Command::new(&real_executable)
+            .arg("-print-resource-dir")
+            .output()
+            .context("Failed to find the clang resource directory")?;
+        if output.status.success() {
+            let resource_dir = PathBuf::from(
+                String::from_utf8(output.stdout)
+                    .context("clang output not utf8")?
+                    .trim(),
+            );
+            let usr_dir = |exe: &Path| exe.parent().and_then(Path::parent).map(Path::to_owned);
+            if let (Some(real_usr), Some(run_usr)) =
+                (usr_dir(&real_executable), usr_dir(&self.executable))
+            {
+                if let Ok(relative) = resource_dir.strip_prefix(&real_usr) {
+                    package_builder.add_dir_contents_as(
+                        &resource_dir.join("include"),
+                        &run_usr.join(relative).join("include"),
+                    )?;
+                }
+            }
+        }
+
+        // Bundle into a compressed tarfile.
+        package_builder.into_compressed_tar(f)
+    }
+}
+
+#[cfg(feature = "dist-client")]
+#[cfg(windows)]
+impl pkg::ToolchainPackager for CToolchainPackager {
+    fn write_pkg(self: Box<Self>, f: fs::File) -> Result<()> {
+        info!("Generating toolchain {}", self.executable.display());
+        if self.kind != CCompilerKind::MSVC {
+            bail!(
+                "Automatic packaging of {:?} is not supported on Windows",
+                self.kind
+            )
+        }
+        let mut package_builder = pkg::ToolchainPackageBuilder::new();
+        let is_clang = self.executable.file_stem().map_or(false, |stem| {
+            stem.to_string_lossy().to_lowercase().starts_with("clang")
+        });
+        if is_clang {
+            // clang-cl only needs system DLLs, and shares its directory with
+            // the whole of LLVM.
+            package_builder.add_file(self.executable.clone())?;
+        } else {
+            // cl.exe loads the compiler passes, mspdb and its localized
+            // messages from its own directory.
+            let bin_dir = self
+                .executable
+                .parent()
+                .context("MSVC executable has no parent directory")?;
+            package_builder.add_dir_contents(bin_dir)?;
+        }
+
+        // Bundle into a compressed tarfile.
+        package_builder.into_compressed_tar(f)
+    }
+}
+
 /// The cache is versioned by the inputs to `hash_key`.
-pub const CACHE_VERSION: &[u8] = b"10";
+pub const CACHE_VERSION: &[u8] = b"12";
 
 lazy_static! {
     /// Environment variables that are factored into the cache key.
@@ -649,6 +1004,7 @@ lazy_static! {
 }
 
 /// Compute the hash key of `compiler` compiling `preprocessor_output` with `args`.
+#[cfg(test)]
 pub fn hash_key(
     compiler_digest: &str,
     language: Language,
@@ -658,29 +1014,62 @@ pub fn hash_key(
     preprocessor_output: &[u8],
     plusplus: bool,
 ) -> String {
-    // If you change any of the inputs to the hash, you should change `CACHE_VERSION`.
     let mut m = Digest::new();
-    m.update(compiler_digest.as_bytes());
+    m.update(preprocessor_output);
+    hash_key_with_inputs(
+        compiler_digest,
+        language,
+        arguments,
+        extra_hashes,
+        env_vars,
+        &m.finish(),
+        preprocessor_output.len(),
+        plusplus,
+        false,
+    )
+    .0
+}
+
+/// Compute the hash key like `hash_key`, from the digest of the
+/// preprocessor output and its length, and each input of it if `record` is
+/// set.
+#[allow(clippy::too_many_arguments)]
+pub fn hash_key_with_inputs(
+    compiler_digest: &str,
+    language: Language,
+    arguments: &[OsString],
+    extra_hashes: &[String],
+    env_vars: &[(OsString, OsString)],
+    preprocessor_digest: &str,
+    preprocessor_len: usize,
+    plusplus: bool,
+    record: bool,
+) -> (String, Vec<HashInput>) {
+    // If you change any of the inputs to the hash, you should change `CACHE_VERSION`.
+    let mut m = RecordingDigest::new(record);
+    m.update_str("compiler digest", compiler_digest);
     // clang and clang++ have different behavior despite being byte-for-byte identical binaries, so
     // we have to incorporate that into the hash as well.
-    m.update(&[plusplus as u8]);
-    m.update(CACHE_VERSION);
-    m.update(language.as_str().as_bytes());
+    m.hash("plusplus", &plusplus);
+    m.update_str("cache version", &String::from_utf8_lossy(CACHE_VERSION));
+    m.update_str("language", language.as_str());
     for arg in arguments {
-        arg.hash(&mut HashToDigest { digest: &mut m });
+        m.hash("argument", arg);
     }
     for hash in extra_hashes {
-        m.update(hash.as_bytes());
+        m.update_str("extra hash", hash);
     }
 
     for &(ref var, ref val) in env_vars.iter() {
         if CACHED_ENV_VARS.contains(var.as_os_str()) {
-            var.hash(&mut HashToDigest { digest: &mut m });
-            m.update(&b"="[..]);
-            val.hash(&mut HashToDigest { digest: &mut m });
+            let digest = m.digest_mut();
+            var.hash(&mut HashToDigest { digest });
+            digest.update(&b"="[..]);
+            val.hash(&mut HashToDigest { digest });
+            m.record("env var", || format!("{:?}={:?}", var, val));
         }
     }
-    m.update(preprocessor_output);
+    m.update_digest("preprocessor output", preprocessor_digest, preprocessor_len);
     m.finish()
 }
 
@@ -795,4 +1184,98 @@ mod test {
             hash_key(digest, Language::C, &args, &[], &[], &PREPROCESSED, false)
         );
     }
+
+    #[test]
+    fn test_uses_time_macros() {
+        assert!(uses_time_macros(b"const char *d = __DATE__;"));
+        assert!(uses_time_macros(b"__TIME__"));
+        assert!(uses_time_macros(b"puts(__TIMESTAMP__);"));
+        assert!(!uses_time_macros(b"int x__DATE__ = 1;"));
+        assert!(!uses_time_macros(b"int __DATE__S;"));
+        assert!(!uses_time_macros(b"int __TIME_;"));
+    }
+
+    #[test]
+    fn test_uses_asm_includes() {
+        assert!(uses_asm_includes(b"\t.include \"macros.inc\"\n"));
+        assert!(uses_asm_includes(b"blob:\n.incbin \"data.bin\"\n"));
+        assert!(uses_asm_includes(b"table: .INCBIN \"t\", 0, 16"));
+        assert!(!uses_asm_includes(b"\t.globl f\nf:\n\tret\n"));
+        assert!(!uses_asm_includes(b"\t.includes_not\n"));
+        assert!(!uses_asm_includes(b"\tcall my.include\n"));
+    }
+
+    #[test]
+    fn test_hashed_args_ignore_patterns() {
+        let args = ovec![
+            "-O2",
+            "-fmessage-length=80",
+            "-DBUILD_TIMESTAMP=1234",
+            "-Wall"
+        ];
+        let hash_config = HashConfig {
+            ignore_args: vec!["-fmessage-length=*".to_owned(), "-D*TIMESTAMP=*".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(
+            ovec!["-O2", "-Wall"],
+            hashed_args(&args, &hash_config, Path::new("/"))
+        );
+        assert_eq!(
+            args,
+            hashed_args(&args, &HashConfig::default(), Path::new("/"))
+        );
+    }
+
+    #[test]
+    fn test_write_compilation_db_fragment() {
+        let td = tempfile::Builder::new()
+            .prefix("sccache")
+            .tempdir()
+            .unwrap();
+        let mut outputs = HashMap::new();
+        outputs.insert("obj", PathBuf::from("foo.o"));
+        let compilation = CCompilation {
+            parsed_args: ParsedArguments {
+                input: "foo.c".into(),
+                language: Language::C,
+                compilation_flag: "-c".into(),
+                depfile: None,
+                outputs,
+                dependency_args: vec![],
+                preprocessor_args: ovec!["-Iinclude"],
+                common_args: ovec!["-O2"],
+                extra_hash_files: vec![],
+                msvc_show_includes: false,
+                profile_generate: false,
+                color_mode: ColorMode::Auto,
+                arch_args: vec![],
+                compilation_db_fragment: Some("foo.o.json".into()),
+                index_args: vec![],
+            },
+            #[cfg(feature = "dist-client")]
+            preprocessed_input: vec![],
+            executable: "/usr/bin/clang".into(),
+            compiler: crate::compiler::clang::Clang {
+                clangplusplus: false,
+            },
+            cwd: td.path().to_owned(),
+            env_vars: vec![],
+            not_cacheable: None,
+        };
+        compilation.write_local_outputs().unwrap();
+        let fragment = fs::read_to_string(td.path().join("foo.o.json")).unwrap();
+        assert!(fragment.ends_with("},\n"));
+        let entry: serde_json::Value =
+            serde_json::from_str(fragment.trim_end().trim_end_matches(',')).unwrap();
+        assert_eq!(
+            serde_json::json!({
+                "directory": td.path().to_string_lossy(),
+                "file": "foo.c",
+                "output": "foo.o",
+                "arguments": ["/usr/bin/clang", "foo.c", "-c", "-o", "foo.o", "-Iinclude", "-O2"],
+            }),
+            entry
+        );
+    }
 }
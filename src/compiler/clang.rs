@@ -21,7 +21,7 @@ use crate::compiler::{gcc, write_temp_file, Cacheable, CompileCommand, CompilerA
 use crate::dist;
 use crate::mock_command::{CommandCreator, CommandCreatorSync, RunCommand};
 use crate::util::{run_input_output, OsStrExt};
-use futures::future::{self, Future};
+use async_trait::async_trait;
 use std::ffi::OsString;
 use std::fs::File;
 use std::io::{self, Write};
@@ -37,6 +37,7 @@ pub struct Clang {
     pub clangplusplus: bool,
 }
 
+#[async_trait(?Send)]
 impl CCompilerImpl for Clang {
     fn kind(&self) -> CCompilerKind {
         CCompilerKind::Clang
@@ -57,7 +58,7 @@ impl CCompilerImpl for Clang {
         )
     }
 
-    fn preprocess<T>(
+    async fn preprocess<T>(
         &self,
         creator: &T,
         executable: &Path,
@@ -66,7 +67,7 @@ impl CCompilerImpl for Clang {
         env_vars: &[(OsString, OsString)],
         may_dist: bool,
         rewrite_includes_only: bool,
-    ) -> SFuture<process::Output>
+    ) -> Result<process::Output>
     where
         T: CommandCreatorSync,
     {
@@ -80,6 +81,33 @@ impl CCompilerImpl for Clang {
             self.kind(),
             rewrite_includes_only,
         )
+        .await
+    }
+
+    async fn preprocess_hashed<T>(
+        &self,
+        creator: &T,
+        executable: &Path,
+        parsed_args: &ParsedArguments,
+        cwd: &Path,
+        env_vars: &[(OsString, OsString)],
+        may_dist: bool,
+        rewrite_includes_only: bool,
+    ) -> Result<(process::Output, Option<String>)>
+    where
+        T: CommandCreatorSync,
+    {
+        gcc::preprocess_hashed(
+            creator,
+            executable,
+            parsed_args,
+            cwd,
+            env_vars,
+            may_dist,
+            self.kind(),
+            rewrite_includes_only,
+        )
+        .await
     }
 
     fn generate_compile_commands(
@@ -104,8 +132,10 @@ impl CCompilerImpl for Clang {
 }
 
 counted_array!(pub static ARGS: [ArgInfo<gcc::ArgData>; _] = [
+    take_arg!("--save-temps", OsString, Concatenated('='), SaveTempsWithBitcode),
     take_arg!("--serialize-diagnostics", OsString, Separated, PassThrough),
     take_arg!("--target", OsString, Separated, PassThrough),
+    take_arg!("-MJ", PathBuf, CanBeSeparated, CompilationDbFragment),
     take_arg!("-Xclang", OsString, Separated, XClang),
     take_arg!("-add-plugin", OsString, Separated, PassThrough),
     take_arg!("-debug-info-kind", OsString, Concatenated('='), PassThrough),
@@ -115,16 +145,23 @@ counted_array!(pub static ARGS: [ArgInfo<gcc::ArgData>; _] = [
     take_arg!("-fdebug-compilation-dir", OsString, Separated, PassThrough),
     flag!("-fmodules", TooHardFlag),
     flag!("-fno-color-diagnostics", NoDiagnosticsColorFlag),
+    take_arg!("-foptimization-record-file", PathBuf, Concatenated('='), OptimizationRecordFile),
     take_arg!("-fplugin", PathBuf, CanBeConcatenated('='), ExtraHashFile),
     flag!("-fprofile-instr-generate", ProfileGenerate),
     // Can be either -fprofile-instr-use or -fprofile-instr-use=path
-    take_arg!("-fprofile-instr-use", OsString, Concatenated, TooHard),
+    take_arg!("-fprofile-instr-use", OsString, Concatenated('='), ProfileUse),
+    take_arg!("-fprofile-sample-use", PathBuf, Concatenated('='), ExtraHashFile),
     take_arg!("-fsanitize-blacklist", PathBuf, Concatenated('='), ExtraHashFile),
+    take_arg!("-fsave-optimization-record", OsString, Concatenated('='), OptimizationRecord),
+    take_arg!("-ftime-trace", OsString, Concatenated('='), TimeTrace),
     take_arg!("-gcc-toolchain", OsString, Separated, PassThrough),
     take_arg!("-include-pch", PathBuf, CanBeSeparated, PreprocessorArgumentPath),
+    take_arg!("-index-store-path", PathBuf, Separated, IndexArgument),
+    take_arg!("-index-unit-output-path", PathBuf, Separated, IndexArgument),
     take_arg!("-load", PathBuf, Separated, ExtraHashFile),
     take_arg!("-mllvm", OsString, Separated, PassThrough),
     take_arg!("-plugin-arg", OsString, Concatenated('-'), PassThrough),
+    take_arg!("-save-temps", OsString, Concatenated('='), SaveTempsWithBitcode),
     take_arg!("-target", OsString, Separated, PassThrough),
     flag!("-verify", PreprocessorArgumentFlag),
 ]);
@@ -136,7 +173,7 @@ mod test {
     use crate::compiler::*;
     use crate::mock_command::*;
     use crate::test::utils::*;
-    use futures::Future;
+    use futures::executor::block_on;
     use std::collections::HashMap;
     use std::path::PathBuf;
 
@@ -209,13 +246,19 @@ mod test {
     #[test]
     fn test_parse_xclang_invalid() {
         assert_eq!(
-            CompilerArguments::CannotCache("Can't handle Raw arguments with -Xclang", None),
+            CompilerArguments::CannotCache(
+                "Can't handle Raw arguments with -Xclang",
+                Some("broken".to_owned())
+            ),
             parse_arguments_(stringvec![
                 "-c", "foo.c", "-o", "foo.o", "-Xclang", "broken"
             ])
         );
         assert_eq!(
-            CompilerArguments::CannotCache("Can't handle UnknownFlag arguments with -Xclang", None),
+            CompilerArguments::CannotCache(
+                "Can't handle UnknownFlag arguments with -Xclang",
+                Some("-broken".to_owned())
+            ),
             parse_arguments_(stringvec![
                 "-c", "foo.c", "-o", "foo.o", "-Xclang", "-broken"
             ])
@@ -392,6 +435,140 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_parse_time_trace() {
+        let a = parses!("-c", "foo.c", "-o", "out/foo.o", "-ftime-trace");
+        assert_eq!(ovec!["-ftime-trace"], a.common_args);
+        assert_map_contains!(
+            a.outputs,
+            ("obj", PathBuf::from("out/foo.o")),
+            ("time-trace", PathBuf::from("out/foo.json"))
+        );
+        let a = parses!("-c", "foo.c", "-o", "out/foo.o", "-ftime-trace=traces/");
+        assert_map_contains!(
+            a.outputs,
+            ("obj", PathBuf::from("out/foo.o")),
+            ("time-trace", PathBuf::from("traces/foo.json"))
+        );
+        let a = parses!("-c", "foo.c", "-o", "out/foo.o", "-ftime-trace=trace.json");
+        assert_map_contains!(
+            a.outputs,
+            ("obj", PathBuf::from("out/foo.o")),
+            ("time-trace", PathBuf::from("trace.json"))
+        );
+        let a = parses!(
+            "-c",
+            "foo.c",
+            "-o",
+            "foo.o",
+            "-ftime-trace",
+            "-ftime-trace-granularity=100"
+        );
+        assert_eq!(
+            ovec!["-ftime-trace", "-ftime-trace-granularity=100"],
+            a.common_args
+        );
+    }
+
+    #[test]
+    fn test_parse_optimization_record() {
+        let a = parses!("-c", "foo.c", "-o", "foo.o", "-fsave-optimization-record");
+        assert_eq!(ovec!["-fsave-optimization-record"], a.common_args);
+        assert_map_contains!(
+            a.outputs,
+            ("obj", PathBuf::from("foo.o")),
+            ("opt-record", PathBuf::from("foo.opt.yaml"))
+        );
+        let a = parses!(
+            "-c",
+            "foo.c",
+            "-o",
+            "foo.o",
+            "-fsave-optimization-record=bitstream"
+        );
+        assert_map_contains!(
+            a.outputs,
+            ("obj", PathBuf::from("foo.o")),
+            ("opt-record", PathBuf::from("foo.opt.bitstream"))
+        );
+        let a = parses!(
+            "-c",
+            "foo.c",
+            "-o",
+            "foo.o",
+            "-foptimization-record-file=remarks/foo.yaml"
+        );
+        assert_map_contains!(
+            a.outputs,
+            ("obj", PathBuf::from("foo.o")),
+            ("opt-record", PathBuf::from("remarks/foo.yaml"))
+        );
+        assert_eq!(
+            CompilerArguments::CannotCache("optimization records with multiple -arch", None),
+            parse_arguments_(stringvec![
+                "-c",
+                "foo.c",
+                "-arch",
+                "arm64",
+                "-arch",
+                "x86_64",
+                "-fsave-optimization-record"
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_save_temps() {
+        let a = parses!("-c", "foo.c", "-o", "out/foo.o", "-save-temps=obj");
+        assert_eq!(ovec!["-save-temps=obj"], a.common_args);
+        assert_map_contains!(
+            a.outputs,
+            ("obj", PathBuf::from("out/foo.o")),
+            ("temp-preprocessed", PathBuf::from("out/foo.i")),
+            ("temp-assembly", PathBuf::from("out/foo.s")),
+            ("temp-bitcode", PathBuf::from("out/foo.bc"))
+        );
+    }
+
+    #[test]
+    fn test_parse_compilation_db_fragment() {
+        let a = parses!("-c", "foo.c", "-o", "foo.o", "-MJ", "foo.o.json", "-O2");
+        assert_eq!(Some(PathBuf::from("foo.o.json")), a.compilation_db_fragment);
+        assert_eq!(ovec!["-O2"], a.common_args);
+        assert_map_contains!(a.outputs, ("obj", PathBuf::from("foo.o")));
+        let a = parses!("-c", "foo.c", "-o", "foo.o", "-MJfoo.o.json");
+        assert_eq!(Some(PathBuf::from("foo.o.json")), a.compilation_db_fragment);
+        assert!(a.common_args.is_empty());
+    }
+
+    #[test]
+    fn test_parse_index_args() {
+        // As Xcode passes them
+        let a = parses!(
+            "-c",
+            "foo.m",
+            "-o",
+            "foo.o",
+            "-index-store-path",
+            "/DerivedData/Index/DataStore",
+            "-index-unit-output-path",
+            "/foo.o",
+            "-O2"
+        );
+        assert_eq!(
+            ovec![
+                "-index-store-path",
+                "/DerivedData/Index/DataStore",
+                "-index-unit-output-path",
+                "/foo.o"
+            ],
+            a.index_args
+        );
+        // Not hashed
+        assert_eq!(ovec!["-O2"], a.common_args);
+        assert_eq!(Some("foo.m"), a.input.to_str());
+    }
+
     #[test]
     fn test_parse_color_diags() {
         let a = parses!("-c", "foo.c", "-o", "foo.o", "-fcolor-diagnostics");
@@ -0,0 +1,294 @@
+// Copyright 2017 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::compiler::digest_cache::input_digests;
+#[cfg(feature = "dist-client")]
+use crate::compiler::DistPackagers;
+use crate::compiler::{
+    cache_schema, debug_dir, Cacheable, ColorMode, Compilation, CompileCommand, CompilerHasher,
+    HashResult,
+};
+use crate::config::HashConfig;
+use crate::dist;
+use crate::mock_command::CommandCreatorSync;
+use crate::trace::Span;
+use crate::util::RecordingDigest;
+use async_trait::async_trait;
+use futures::executor::ThreadPool;
+use std::borrow::Cow;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use crate::errors::*;
+
+/// Version number for cache key.
+pub const CACHE_VERSION: &[u8] = b"3";
+
+/// A command that isn't a compiler, cached from the inputs and outputs
+/// declared for it on the sccache commandline.
+#[derive(Debug, Clone)]
+pub struct CommandHasher {
+    /// The path to the executable.
+    executable: PathBuf,
+    /// The digest of the executable.
+    executable_digest: String,
+    /// The commandline arguments to pass to the executable.
+    arguments: Vec<OsString>,
+    /// The files the command reads.
+    inputs: Vec<PathBuf>,
+    /// The files the command writes.
+    outputs: Vec<PathBuf>,
+}
+
+/// A struct on which to hang a `Compilation` impl.
+#[derive(Debug, Clone)]
+struct CommandCompilation {
+    executable: PathBuf,
+    arguments: Vec<OsString>,
+    /// The outputs, keyed by the order they're declared in, as their paths
+    /// may be absolute or outside of `cwd`, which objects can't be named.
+    outputs: Vec<(String, PathBuf)>,
+    cwd: PathBuf,
+    env_vars: Vec<(OsString, OsString)>,
+}
+
+impl CommandHasher {
+    pub fn new(
+        executable: PathBuf,
+        executable_digest: String,
+        arguments: Vec<OsString>,
+        inputs: Vec<PathBuf>,
+        outputs: Vec<PathBuf>,
+    ) -> CommandHasher {
+        CommandHasher {
+            executable,
+            executable_digest,
+            arguments,
+            inputs,
+            outputs,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<T> CompilerHasher<T> for CommandHasher
+where
+    T: CommandCreatorSync,
+{
+    async fn generate_hash_key(
+        self: Box<Self>,
+        _creator: &T,
+        cwd: PathBuf,
+        env_vars: Vec<(OsString, OsString)>,
+        _may_dist: bool,
+        pool: &ThreadPool,
+        _rewrite_includes_only: bool,
+        _hash_config: &HashConfig,
+        _span: &Span,
+    ) -> Result<HashResult> {
+        let CommandHasher {
+            executable,
+            executable_digest,
+            arguments,
+            inputs,
+            outputs,
+        } = *self;
+        let abs_inputs = inputs.iter().map(|i| cwd.join(i)).collect::<Vec<_>>();
+        let input_hashes = input_digests(&abs_inputs, pool).await?;
+        // If you change any of the inputs to the hash, you should change `CACHE_VERSION`.
+        let mut m = RecordingDigest::new(debug_dir().is_some());
+        // Hash inputs:
+        // 1. A version
+        m.update_str("cache version", &String::from_utf8_lossy(CACHE_VERSION));
+        // 2. The digest of the executable
+        m.update_str("executable digest", &executable_digest);
+        // 3. The commandline
+        for arg in &arguments {
+            m.hash("argument", arg);
+        }
+        // 4. The declared inputs, and the digests of their contents
+        for (input, hash) in inputs.iter().zip(input_hashes) {
+            m.hash("input", input);
+            m.update_str("input digest", &hash);
+        }
+        // 5. The declared outputs, as the cached files are only named by
+        //    their order
+        for output in &outputs {
+            m.hash("output", output);
+        }
+        let weak_toolchain_key = executable_digest;
+        let (key, hash_inputs) = m.finish();
+        Ok(HashResult {
+            key,
+            compilation: Box::new(CommandCompilation {
+                executable,
+                arguments,
+                outputs: outputs
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, o)| (format!("output{}", i), o))
+                    .collect(),
+                cwd,
+                env_vars,
+            }),
+            weak_toolchain_key,
+            hash_inputs,
+        })
+    }
+
+    fn color_mode(&self) -> ColorMode {
+        ColorMode::Auto
+    }
+
+    fn output_pretty(&self) -> Cow<'_, str> {
+        self.outputs
+            .first()
+            .map(|o| o.to_string_lossy())
+            .unwrap_or_else(|| self.executable.to_string_lossy())
+    }
+
+    fn cache_schema(&self) -> String {
+        cache_schema("command", CACHE_VERSION)
+    }
+
+    fn box_clone(&self) -> Box<dyn CompilerHasher<T>> {
+        Box::new((*self).clone())
+    }
+}
+
+impl Compilation for CommandCompilation {
+    fn generate_compile_commands(
+        &self,
+        _path_transformer: &mut dist::PathTransformer,
+        _rewrite_includes_only: bool,
+    ) -> Result<(CompileCommand, Option<dist::CompileCommand>, Cacheable)> {
+        let command = CompileCommand {
+            executable: self.executable.clone(),
+            arguments: self.arguments.clone(),
+            env_vars: self.env_vars.clone(),
+            cwd: self.cwd.clone(),
+        };
+        Ok((command, None, Cacheable::Yes))
+    }
+
+    #[cfg(feature = "dist-client")]
+    fn into_dist_packagers(
+        self: Box<Self>,
+        _path_transformer: dist::PathTransformer,
+    ) -> Result<DistPackagers> {
+        bail!("Commands cannot be distributed")
+    }
+
+    fn outputs<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a str, &'a Path)> + 'a> {
+        Box::new(self.outputs.iter().map(|(k, v)| (k.as_str(), v.as_path())))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::{CacheRead, CacheWrite};
+    use crate::test::utils::*;
+    use futures::executor::block_on;
+    use std::fs;
+    use std::io::{Cursor, Write};
+
+    fn hash(
+        f: &TestFixture,
+        cwd: &Path,
+        args: &[&str],
+        input: &[u8],
+        outputs: &[&str],
+    ) -> HashResult {
+        create_file(f.tempdir.path(), "a.proto", |mut f| f.write_all(input)).unwrap();
+        let hasher = Box::new(CommandHasher::new(
+            "protoc".into(),
+            "abcd".to_owned(),
+            args.iter().map(OsString::from).collect(),
+            vec![f.tempdir.path().join("a.proto")],
+            outputs.iter().map(PathBuf::from).collect(),
+        ));
+        let creator = new_creator();
+        let pool = ThreadPool::sized(1);
+        block_on(hasher.generate_hash_key(
+            &creator,
+            cwd.to_owned(),
+            vec![],
+            false,
+            &pool,
+            false,
+            &HashConfig::default(),
+            &Span::default(),
+        ))
+        .unwrap()
+    }
+
+    fn hash_key(f: &TestFixture, args: &[&str], input: &[u8], outputs: &[&str]) -> String {
+        hash(f, f.tempdir.path(), args, input, outputs).key
+    }
+
+    #[test]
+    fn test_hash_key_declared_files() {
+        let f = TestFixture::new();
+        let key = hash_key(&f, &["a.proto"], b"message A {}", &["a.pb.rs"]);
+        assert_eq!(
+            key,
+            hash_key(&f, &["a.proto"], b"message A {}", &["a.pb.rs"])
+        );
+        assert_neq!(
+            key,
+            hash_key(&f, &["-v", "a.proto"], b"message A {}", &["a.pb.rs"])
+        );
+        assert_neq!(
+            key,
+            hash_key(&f, &["a.proto"], b"message B {}", &["a.pb.rs"])
+        );
+        assert_neq!(
+            key,
+            hash_key(&f, &["a.proto"], b"message A {}", &["b.pb.rs"])
+        );
+    }
+
+    #[test]
+    fn test_outputs_outside_cwd() {
+        let f = TestFixture::new();
+        let cwd = f.tempdir.path().join("build");
+        fs::create_dir(&cwd).unwrap();
+        let absolute = f.tempdir.path().join("abs.pb.rs");
+        fs::write(&absolute, b"absolute").unwrap();
+        fs::write(f.tempdir.path().join("up.pb.rs"), b"up").unwrap();
+        let outputs = [absolute.to_str().unwrap(), "../up.pb.rs"];
+        let result = hash(&f, &cwd, &["a.proto"], b"message A {}", &outputs);
+        let objects: Vec<_> = result
+            .compilation
+            .outputs()
+            .map(|(key, path)| (key.to_owned(), cwd.join(path)))
+            .collect();
+        assert_eq!(
+            objects
+                .iter()
+                .map(|(key, _)| key.as_str())
+                .collect::<Vec<_>>(),
+            vec!["output0", "output1"]
+        );
+
+        // Both can be stored, and restored
+        let pool = ThreadPool::sized(1);
+        let entry = block_on(CacheWrite::from_objects(objects, &pool)).unwrap();
+        let mut entry = CacheRead::from(Cursor::new(entry.finish().unwrap())).unwrap();
+        let mut up = vec![];
+        entry.get_object("output1", &mut up).unwrap();
+        assert_eq!(up, b"up");
+    }
+}
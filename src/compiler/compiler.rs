@@ -12,29 +12,43 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::cache::limited::Overloaded;
 use crate::cache::{Cache, CacheWrite, DecompressionFailure, Storage};
-use crate::compiler::c::{CCompiler, CCompilerKind};
+use crate::compiler::c::{self, CCompiler, CCompilerKind};
 use crate::compiler::clang::Clang;
+use crate::compiler::command;
 use crate::compiler::diab::Diab;
 use crate::compiler::gcc::GCC;
 use crate::compiler::msvc;
 use crate::compiler::msvc::MSVC;
 use crate::compiler::nvcc::NVCC;
-use crate::compiler::rust::{Rust, RustupProxy};
+use crate::compiler::resource::{Windres, RC};
+use crate::compiler::rust::{self, Rust, RustupProxy};
+use crate::compiler::ti::TI;
+use crate::compiler::verify;
+use crate::config::HashConfig;
 use crate::dist;
 #[cfg(feature = "dist-client")]
 use crate::dist::pkg;
 use crate::lru_disk_cache;
 use crate::mock_command::{exit_status, CommandChild, CommandCreatorSync, RunCommand};
-use crate::util::{fmt_duration_as_secs, ref_env, run_input_output, SpawnExt};
+use crate::protocol::PrewarmResult;
+use crate::trace::Span;
+#[cfg(feature = "dist-client")]
+use crate::util::long_path;
+use crate::util::{
+    fmt_duration_as_secs, ref_env, relativize_base_dir, run_input_output, Digest, HashInput,
+    SpawnExt,
+};
+use async_trait::async_trait;
 use filetime::FileTime;
-use futures::Future;
-use futures_03::executor::ThreadPool;
+use futures::executor::ThreadPool;
+use futures::future::{self, LocalBoxFuture};
 use std::borrow::Cow;
 use std::collections::HashMap;
+use std::env;
 use std::ffi::OsString;
 use std::fmt;
-#[cfg(feature = "dist-client")]
 use std::fs;
 use std::fs::File;
 use std::io::prelude::*;
@@ -42,9 +56,8 @@ use std::path::{Path, PathBuf};
 use std::process::{self, Stdio};
 use std::str;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tempfile::TempDir;
-use tokio_timer::Timeout;
 
 use crate::errors::*;
 
@@ -66,7 +79,7 @@ pub struct CompileCommand {
 }
 
 impl CompileCommand {
-    pub fn execute<T>(self, creator: &T) -> SFuture<process::Output>
+    pub async fn execute<T>(self, creator: &T) -> Result<process::Output>
     where
         T: CommandCreatorSync,
     {
@@ -75,7 +88,23 @@ impl CompileCommand {
             .env_clear()
             .envs(self.env_vars)
             .current_dir(self.cwd);
-        Box::new(run_input_output(cmd, None))
+        run_input_output(cmd, None).await
+    }
+
+    /// This command run through `wrapper`, if any, which is passed the
+    /// executable ahead of its arguments.
+    pub fn wrapped(self, wrapper: Option<&Path>) -> CompileCommand {
+        let wrapper = match wrapper {
+            Some(wrapper) => wrapper,
+            None => return self,
+        };
+        let mut arguments = vec![self.executable.into_os_string()];
+        arguments.extend(self.arguments);
+        CompileCommand {
+            executable: wrapper.to_owned(),
+            arguments,
+            ..self
+        }
     }
 }
 
@@ -86,6 +115,8 @@ pub enum CompilerKind {
     C(CCompilerKind),
     /// A Rust compiler.
     Rust,
+    /// Any other command, with declared inputs and outputs.
+    Command,
 }
 
 impl CompilerKind {
@@ -94,9 +125,39 @@ impl CompilerKind {
             CompilerKind::C(CCompilerKind::NVCC) => "CUDA",
             CompilerKind::C(_) => "C/C++",
             CompilerKind::Rust => "Rust",
+            CompilerKind::Command => "Command",
         }
         .to_string()
     }
+
+    /// The compiler and the language it compiles, as `sccache --show-stats`
+    /// breaks compiles down by, with the `language` of the source if it's
+    /// known.
+    pub fn stats_bucket(&self, language: Option<&str>) -> String {
+        match self {
+            CompilerKind::C(kind) => match language {
+                Some(language) => format!("{} ({:?})", language, kind),
+                None => format!("{} ({:?})", self.lang_kind(), kind),
+            },
+            _ => self.lang_kind(),
+        }
+    }
+}
+
+/// The name of version `version` of the hashing of the compiles of a kind
+/// of compiler, the `CACHE_VERSION` of its module, as the entries of their
+/// outputs record it.
+pub fn cache_schema(kind: &str, version: &[u8]) -> String {
+    format!("{}-{}", kind, String::from_utf8_lossy(version))
+}
+
+/// The current version of the hashing of each kind of compiler.
+pub fn cache_schemas() -> Vec<String> {
+    vec![
+        cache_schema("c", c::CACHE_VERSION),
+        cache_schema("rust", rust::CACHE_VERSION),
+        cache_schema("command", command::CACHE_VERSION),
+    ]
 }
 
 #[cfg(feature = "dist-client")]
@@ -127,6 +188,9 @@ where
         arguments: &[OsString],
         cwd: &Path,
     ) -> CompilerArguments<Box<dyn CompilerHasher<T> + 'static>>;
+    /// Return the digest and, if known, the version string that identify this
+    /// compiler for toolchain pinning.
+    fn identity(&self) -> (String, Option<String>);
     fn box_clone(&self) -> Box<dyn Compiler<T>>;
 }
 
@@ -136,6 +200,7 @@ impl<T: CommandCreatorSync> Clone for Box<dyn Compiler<T>> {
     }
 }
 
+#[async_trait(?Send)]
 pub trait CompilerProxy<T>: Send + 'static
 where
     T: CommandCreatorSync + Sized,
@@ -145,12 +210,12 @@ where
     /// Returns the absolute path to the true compiler and the timestamp of
     /// timestamp of the true compiler. Iff the resolution fails,
     /// the returned future resolves to an error with more information.
-    fn resolve_proxied_executable(
+    async fn resolve_proxied_executable(
         &self,
         creator: T,
         cwd: PathBuf,
         env_vars: &[(OsString, OsString)],
-    ) -> SFuture<(PathBuf, FileTime)>;
+    ) -> Result<(PathBuf, FileTime)>;
 
     /// Create a clone of `Self` and puts it in a `Box`
     fn box_clone(&self) -> Box<dyn CompilerProxy<T>>;
@@ -158,6 +223,7 @@ where
 
 /// An interface to a compiler for hash key generation, the result of
 /// argument parsing.
+#[async_trait(?Send)]
 pub trait CompilerHasher<T>: fmt::Debug + Send + 'static
 where
     T: CommandCreatorSync,
@@ -165,7 +231,9 @@ where
     /// Given information about a compiler command, generate a hash key
     /// that can be used for cache lookups, as well as any additional
     /// information that can be reused for compilation if necessary.
-    fn generate_hash_key(
+    /// Steps of it, like preprocessing, are traced as children of `span`.
+    #[allow(clippy::too_many_arguments)]
+    async fn generate_hash_key(
         self: Box<Self>,
         creator: &T,
         cwd: PathBuf,
@@ -173,7 +241,48 @@ where
         may_dist: bool,
         pool: &ThreadPool,
         rewrite_includes_only: bool,
-    ) -> SFuture<HashResult>;
+        hash_config: &HashConfig,
+        span: &Span,
+    ) -> Result<HashResult>;
+
+    /// Hash this compile and look up its entry in `storage` without
+    /// compiling it, as `sccache --prewarm` does ahead of a build.
+    async fn prewarm(
+        self: Box<Self>,
+        creator: T,
+        storage: Arc<dyn Storage>,
+        cwd: PathBuf,
+        env_vars: Vec<(OsString, OsString)>,
+        hash_config: Arc<HashConfig>,
+        pool: ThreadPool,
+    ) -> Result<PrewarmResult> {
+        let result = self
+            .generate_hash_key(
+                &creator,
+                cwd,
+                env_vars,
+                false,
+                &pool,
+                false,
+                &hash_config,
+                &Span::default(),
+            )
+            .await;
+        let HashResult {
+            key, compilation, ..
+        } = match result {
+            Ok(res) => res,
+            Err(e) => return Ok(PrewarmResult::Failed(format!("failed to hash: {}", e))),
+        };
+        if let Some(why) = compilation.not_cacheable_reason() {
+            return Ok(PrewarmResult::NotCacheable(why.to_owned()));
+        }
+        Ok(match storage.get(&key).await {
+            Ok(Cache::Hit(_)) => PrewarmResult::Hit,
+            Ok(_) => PrewarmResult::Miss,
+            Err(e) => PrewarmResult::Failed(format!("failed to look up: {}", e)),
+        })
+    }
 
     /// Return the state of any `--color` option passed to the compiler.
     fn color_mode(&self) -> ColorMode;
@@ -181,7 +290,7 @@ where
     /// Look up a cached compile result in `storage`. If not found, run the
     /// compile and store the result.
     #[allow(clippy::too_many_arguments)]
-    fn get_cached_or_compile(
+    async fn get_cached_or_compile(
         self: Box<Self>,
         dist_client: Result<Option<Arc<dyn dist::Client>>>,
         creator: T,
@@ -190,9 +299,12 @@ where
         cwd: PathBuf,
         env_vars: Vec<(OsString, OsString)>,
         cache_control: CacheControl,
+        hash_config: Arc<HashConfig>,
         pool: ThreadPool,
-    ) -> SFuture<(CompileResult, process::Output)> {
+        span: Span,
+    ) -> Result<(CompileResult, process::Output, CompileTimings)> {
         let out_pretty = self.output_pretty().into_owned();
+        let schema = self.cache_schema();
         debug!("[{}]: get_cached_or_compile: {:?}", out_pretty, arguments);
         let start = Instant::now();
         let may_dist = matches!(dist_client, Ok(Some(_)));
@@ -200,6 +312,19 @@ where
             Ok(Some(ref client)) => client.rewrite_includes_only(),
             _ => false,
         };
+        let mut debug = debug_dir().map(|dir| DebugRecord {
+            dir,
+            output: out_pretty.clone(),
+            cwd: cwd.clone(),
+            arguments: arguments.clone(),
+            parsed: format!("{:#?}", self),
+            hash_inputs: vec![],
+        });
+        let next_wrapper = match hash_config.next_wrapper {
+            Some(ref wrapper) => Some(find_next_wrapper(wrapper, &env_vars, &cwd)?),
+            None => None,
+        };
+        let hash_span = span.child("hash");
         let result = self.generate_hash_key(
             &creator,
             cwd.clone(),
@@ -207,202 +332,298 @@ where
             may_dist,
             &pool,
             rewrite_includes_only,
+            &hash_config,
+            &hash_span,
         );
-        Box::new(result.then(move |res| -> SFuture<_> {
-            debug!(
-                "[{}]: generate_hash_key took {}",
-                out_pretty,
-                fmt_duration_as_secs(&start.elapsed())
-            );
-            let (key, compilation, weak_toolchain_key) = match res {
-                Err(e) => {
-                    return match e.downcast::<ProcessError>() {
-                        Ok(ProcessError(output)) => f_ok((CompileResult::Error, output)),
-                        Err(e) => f_err(e),
-                    };
+        // What the wrapper does to compiles is down to its version, so that's
+        // hashed like the compiler's
+        let result = match next_wrapper {
+            Some(ref wrapper) => future::try_join(result, Digest::file(wrapper, &pool))
+                .await
+                .map(|(mut res, digest)| {
+                    let mut m = Digest::new();
+                    m.update(res.key.as_bytes());
+                    m.update(digest.as_bytes());
+                    res.key = m.finish();
+                    if debug.is_some() {
+                        res.hash_inputs.push(("next wrapper".to_owned(), digest));
+                    }
+                    res
+                }),
+            None => result.await,
+        };
+        hash_span.end();
+        let timings = CompileTimings {
+            hash: Some(start.elapsed()),
+            ..Default::default()
+        };
+        debug!(
+            "[{}]: generate_hash_key took {}",
+            out_pretty,
+            fmt_duration_as_secs(&start.elapsed())
+        );
+        let (key, compilation, weak_toolchain_key) = match result {
+            Err(e) => {
+                if let Some(ref debug) = debug {
+                    debug.write(None, &format!("not hashed: {}", e));
                 }
-                Ok(HashResult {
-                    key,
-                    compilation,
-                    weak_toolchain_key,
-                }) => (key, compilation, weak_toolchain_key),
-            };
-            trace!("[{}]: Hash key: {}", out_pretty, key);
-            // If `ForceRecache` is enabled, we won't check the cache.
-            let start = Instant::now();
-            let cache_status = if cache_control == CacheControl::ForceRecache {
-                f_ok(Cache::Recache)
+                return match e.downcast::<ProcessError>() {
+                    Ok(ProcessError(output)) => Ok((CompileResult::Error, output, timings)),
+                    Err(e) => Err(e),
+                };
+            }
+            Ok(HashResult {
+                key,
+                compilation,
+                weak_toolchain_key,
+                hash_inputs,
+            }) => {
+                if let Some(ref mut debug) = debug {
+                    debug.hash_inputs = hash_inputs;
+                }
+                (key, compilation, weak_toolchain_key)
+            }
+        };
+        log_event!(log::Level::Debug, "hash key";
+            "output" => out_pretty,
+            "key" => key,
+            "duration_ms" => start.elapsed().as_millis() as u64,
+        );
+        compilation.write_local_outputs()?;
+        // If `ForceRecache` is enabled, we won't check the cache.
+        let start = Instant::now();
+        let get_span = span.child("cache get");
+        let not_cacheable = compilation.not_cacheable_reason();
+        let looked_up = not_cacheable.is_none() && cache_control == CacheControl::Default;
+        let cache_status = async {
+            if let Some(why) = not_cacheable {
+                debug!("[{}]: Not cacheable: {}", out_pretty, why);
+                Ok(Cache::Miss)
+            } else if cache_control == CacheControl::ForceRecache {
+                Ok(Cache::Recache)
             } else {
-                storage.get(&key)
-            };
+                storage.get(&key).await
+            }
+        };
 
-            // Set a maximum time limit for the cache to respond before we forge
-            // ahead ourselves with a compilation.
-            let timeout = Duration::new(60, 0);
-            let cache_status = Timeout::new(cache_status, timeout);
-
-            // Check the result of the cache lookup.
-            Box::new(cache_status.then(move |result| {
-                let out_pretty2 = out_pretty.clone();
-                let duration = start.elapsed();
-                let outputs = compilation
-                    .outputs()
-                    .map(|(key, path)| (key.to_string(), cwd.join(path)))
-                    .collect::<HashMap<_, _>>();
-
-                let miss_type = Box::new(match result {
-                    Ok(Cache::Hit(mut entry)) => {
-                        debug!(
-                            "[{}]: Cache hit in {}",
-                            out_pretty,
-                            fmt_duration_as_secs(&duration)
-                        );
-                        let stdout = entry.get_stdout();
-                        let stderr = entry.get_stderr();
-                        let write = entry.extract_objects(outputs.clone(), &pool);
-                        let output = process::Output {
-                            status: exit_status(0),
-                            stdout,
-                            stderr,
-                        };
-                        let hit = CompileResult::CacheHit(duration);
-                        Box::new(write.then(move |result| match result {
-                            Ok(()) => f_ok(CacheLookupResult::Success(hit, output)),
-                            Err(e) => {
-                                if e.downcast_ref::<DecompressionFailure>().is_some() {
-                                    debug!("[{}]: Failed to decompress object", out_pretty);
-                                    f_ok(CacheLookupResult::Miss(MissType::CacheReadError))
-                                } else {
-                                    f_err(e)
-                                }
-                            }
-                        }))
-                    }
-                    Ok(Cache::Miss) => {
-                        debug!(
-                            "[{}]: Cache miss in {}",
-                            out_pretty,
-                            fmt_duration_as_secs(&duration)
-                        );
-                        f_ok(CacheLookupResult::Miss(MissType::Normal))
-                    }
-                    Ok(Cache::Recache) => {
-                        debug!(
-                            "[{}]: Cache recache in {}",
-                            out_pretty,
-                            fmt_duration_as_secs(&duration)
-                        );
-                        f_ok(CacheLookupResult::Miss(MissType::ForcedRecache))
-                    }
-                    Err(err) => {
-                        if err.is_elapsed() {
-                            debug!(
-                                "[{}]: Cache timed out {}",
-                                out_pretty,
-                                fmt_duration_as_secs(&duration)
-                            );
-                            f_ok(CacheLookupResult::Miss(MissType::TimedOut))
+        // Set a maximum time limit for the cache to respond before we forge
+        // ahead ourselves with a compilation.
+        let timeout = Duration::new(60, 0);
+        let result = tokio::time::timeout(timeout, cache_status).await;
+
+        // Check the result of the cache lookup.
+        get_span.end();
+        let duration = start.elapsed();
+        let timings = CompileTimings {
+            cache_get: if looked_up { Some(duration) } else { None },
+            ..timings
+        };
+        let outputs = compilation
+            .outputs()
+            .map(|(key, path)| (key.to_string(), cwd.join(path)))
+            .collect::<HashMap<_, _>>();
+
+        let lookup = match result {
+            Ok(Ok(Cache::Hit(mut entry))) => {
+                debug!(
+                    "[{}]: Cache hit in {}",
+                    out_pretty,
+                    fmt_duration_as_secs(&duration)
+                );
+                let size = entry.size();
+                let version = entry.version();
+                let stdout = entry.get_stdout();
+                let stderr = entry.get_stderr();
+                let output = process::Output {
+                    status: exit_status(0),
+                    stdout,
+                    stderr,
+                };
+                let hit = CompileResult::CacheHit(duration, size, HitCheck::Unchecked, version);
+                match entry.extract_objects(outputs.clone(), &pool).await {
+                    Ok(()) => CacheLookupResult::Success(hit, output),
+                    Err(e) => {
+                        if e.downcast_ref::<DecompressionFailure>().is_some() {
+                            debug!("[{}]: Failed to decompress object", out_pretty);
+                            CacheLookupResult::Miss(MissType::CacheReadError)
                         } else {
-                            error!("[{}]: Cache read error: {}", out_pretty, err);
-                            if err.is_inner() {
-                                let err = err.into_inner().unwrap();
-                                for e in err.chain().skip(1) {
-                                    error!("[{}] \t{}", out_pretty, e);
-                                }
-                            }
-                            f_ok(CacheLookupResult::Miss(MissType::CacheReadError))
+                            return Err(e);
                         }
                     }
-                });
-
-                Box::new(miss_type.and_then(move |result| {
-                    match result {
-                        CacheLookupResult::Success(compile_result, output) => {
-                            f_ok((compile_result, output))
-                        }
-                        CacheLookupResult::Miss(miss_type) => {
-                            // Cache miss, so compile it.
-                            let start = Instant::now();
-                            let compile = dist_or_local_compile(
-                                dist_client,
-                                creator,
-                                cwd,
-                                compilation,
-                                weak_toolchain_key,
-                                out_pretty2.clone(),
-                            );
-
-                            Box::new(compile.and_then(
-                                move |(cacheable, dist_type, compiler_result)| {
-                                    let duration = start.elapsed();
-                                    if !compiler_result.status.success() {
-                                        debug!(
-                                            "[{}]: Compiled but failed, not storing in cache",
-                                            out_pretty2
-                                        );
-                                        return f_ok((CompileResult::CompileFailed, compiler_result))
-                                            as SFuture<_>;
-                                    }
-                                    if cacheable != Cacheable::Yes {
-                                        // Not cacheable
-                                        debug!("[{}]: Compiled but not cacheable", out_pretty2);
-                                        return f_ok((
-                                            CompileResult::NotCacheable,
-                                            compiler_result,
-                                        ));
-                                    }
-                                    debug!(
-                                        "[{}]: Compiled in {}, storing in cache",
-                                        out_pretty2,
-                                        fmt_duration_as_secs(&duration)
-                                    );
-                                    let write = CacheWrite::from_objects(outputs, &pool);
-                                    let write = write.fcontext("failed to zip up compiler outputs");
-                                    let o = out_pretty2.clone();
-                                    Box::new(
-                                        write
-                                            .and_then(move |mut entry| {
-                                                entry.put_stdout(&compiler_result.stdout)?;
-                                                entry.put_stderr(&compiler_result.stderr)?;
-
-                                                // Try to finish storing the newly-written cache
-                                                // entry. We'll get the result back elsewhere.
-                                                let future =
-                                                    storage.put(&key, entry).then(move |res| {
-                                                        match res {
-                                                            Ok(_) => debug!(
-							    "[{}]: Stored in cache successfully!",
-							    out_pretty2
-							),
-                                                            Err(ref e) => debug!(
-                                                                "[{}]: Cache write error: {:?}",
-                                                                out_pretty2, e
-                                                            ),
-                                                        }
-                                                        res.map(|duration| CacheWriteInfo {
-                                                            object_file_pretty: out_pretty2,
-                                                            duration,
-                                                        })
-                                                    });
-                                                let future = Box::new(future);
-                                                Ok((
-                                                    CompileResult::CacheMiss(
-                                                        miss_type, dist_type, duration, future,
-                                                    ),
-                                                    compiler_result,
-                                                ))
-                                            })
-                                            .fwith_context(move || {
-                                                format!("failed to store `{}` to cache", o)
-                                            }),
-                                    )
-                                },
-                            ))
-                        }
+                }
+            }
+            Ok(Ok(Cache::Miss)) => {
+                debug!(
+                    "[{}]: Cache miss in {}",
+                    out_pretty,
+                    fmt_duration_as_secs(&duration)
+                );
+                CacheLookupResult::Miss(MissType::Normal)
+            }
+            Ok(Ok(Cache::Recache)) => {
+                debug!(
+                    "[{}]: Cache recache in {}",
+                    out_pretty,
+                    fmt_duration_as_secs(&duration)
+                );
+                CacheLookupResult::Miss(MissType::ForcedRecache)
+            }
+            Ok(Err(err)) => {
+                if err.is::<Overloaded>() {
+                    debug!("[{}]: Cache lookup shed: {}", out_pretty, err);
+                    CacheLookupResult::Miss(MissType::Shed)
+                } else {
+                    error!("[{}]: Cache read error: {}", out_pretty, err);
+                    for e in err.chain().skip(1) {
+                        error!("[{}] \t{}", out_pretty, e);
                     }
-                }))
-            }))
-        }))
+                    CacheLookupResult::Miss(MissType::CacheReadError)
+                }
+            }
+            Err(_) => {
+                debug!(
+                    "[{}]: Cache timed out {}",
+                    out_pretty,
+                    fmt_duration_as_secs(&duration)
+                );
+                CacheLookupResult::Miss(MissType::TimedOut)
+            }
+        };
+
+        if let Some(ref debug) = debug {
+            let decision = match (&lookup, not_cacheable) {
+                (CacheLookupResult::Success(..), _) => "hit".to_owned(),
+                (CacheLookupResult::Miss(_), Some(why)) => format!("not cacheable: {}", why),
+                (CacheLookupResult::Miss(miss_type), None) => format!("miss ({})", miss_type),
+            };
+            debug.write(Some(&key), &decision);
+        }
+        let miss_type = match lookup {
+            CacheLookupResult::Success(
+                CompileResult::CacheHit(duration, size, _, version),
+                output,
+            ) if verify::sample() => {
+                let check = check_hit(
+                    creator,
+                    storage,
+                    compilation,
+                    outputs,
+                    key,
+                    HitContext {
+                        output: out_pretty,
+                        cwd,
+                        arguments,
+                        weak_toolchain_key,
+                    },
+                    &pool,
+                )
+                .await;
+                return Ok((
+                    CompileResult::CacheHit(duration, size, check, version),
+                    output,
+                    timings,
+                ));
+            }
+            CacheLookupResult::Success(compile_result, output) => {
+                return Ok((compile_result, output, timings));
+            }
+            CacheLookupResult::Miss(miss_type) => miss_type,
+        };
+
+        // Cache miss, so compile it.
+        let start = Instant::now();
+        let compile_span = span.child("compile");
+        let compiled = dist_or_local_compile(
+            dist_client,
+            creator,
+            cwd.clone(),
+            compilation,
+            weak_toolchain_key,
+            out_pretty.clone(),
+            next_wrapper,
+        )
+        .await;
+        compile_span.end();
+        let (cacheable, dist_type, mut compiler_result) = compiled?;
+        let duration = start.elapsed();
+        let timings = CompileTimings {
+            compile: Some(duration),
+            ..timings
+        };
+        // Warnings about falling back to a local compile aren't stored in the
+        // cache, to be replayed
+        let dist_warning = match dist_type {
+            DistType::Error(DistFailure {
+                warning: Some(ref warning),
+                ..
+            }) => Some(warning.clone()),
+            _ => None,
+        };
+        if !compiler_result.status.success() {
+            debug!(
+                "[{}]: Compiled but failed, not storing in cache",
+                out_pretty
+            );
+            add_dist_warning(&mut compiler_result, dist_warning);
+            return Ok((CompileResult::CompileFailed, compiler_result, timings));
+        }
+        if cacheable != Cacheable::Yes || not_cacheable.is_some() {
+            // Not cacheable
+            debug!("[{}]: Compiled but not cacheable", out_pretty);
+            add_dist_warning(&mut compiler_result, dist_warning);
+            return Ok((
+                CompileResult::NotCacheable(not_cacheable),
+                compiler_result,
+                timings,
+            ));
+        }
+        debug!(
+            "[{}]: Compiled in {}, storing in cache",
+            out_pretty,
+            fmt_duration_as_secs(&duration)
+        );
+        let entry = async {
+            let mut entry = CacheWrite::from_objects(outputs, &pool)
+                .await
+                .context("failed to zip up compiler outputs")?;
+            // Diagnostics replayed from the cache refer to files under the
+            // base directory relative to the working directory, like the hash
+            // does.
+            let relativize = |bytes| match hash_config.base_dir {
+                Some(ref base_dir) => relativize_base_dir(bytes, base_dir, &cwd),
+                None => Cow::Borrowed(bytes),
+            };
+            entry.put_stdout(&relativize(&compiler_result.stdout))?;
+            entry.put_stderr(&relativize(&compiler_result.stderr))?;
+            entry.put_schema(&schema)?;
+            Ok::<_, Error>(entry)
+        };
+        let entry = entry
+            .await
+            .with_context(|| format!("failed to store `{}` to cache", out_pretty))?;
+        add_dist_warning(&mut compiler_result, dist_warning);
+
+        // Try to finish storing the newly-written cache entry. We'll get the
+        // result back elsewhere.
+        let put_span = span.child("cache put");
+        let put_key = key.clone();
+        let future = async move {
+            let res = storage.put(&put_key, entry).await;
+            put_span.end();
+            match res {
+                Ok(_) => debug!("[{}]: Stored in cache successfully!", out_pretty),
+                Err(ref e) => debug!("[{}]: Cache write error: {:?}", out_pretty, e),
+            }
+            res.map(|duration| CacheWriteInfo {
+                object_file_pretty: out_pretty,
+                duration,
+            })
+        };
+        Ok((
+            CompileResult::CacheMiss(miss_type, dist_type, duration, key, Box::pin(future)),
+            compiler_result,
+            timings,
+        ))
     }
 
     /// A descriptive string about the file that we're going to be producing.
@@ -411,51 +632,241 @@ where
     /// artifact generation.
     fn output_pretty(&self) -> Cow<'_, str>;
 
+    /// A descriptive string about the file being compiled, as shown by
+    /// `sccache --top`. It's the output, unless the compiler knows better.
+    fn input_pretty(&self) -> Cow<'_, str> {
+        self.output_pretty()
+    }
+
+    /// The language of the source being compiled, for compilers of more
+    /// than one.
+    fn language(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// The name of the Rust crate being compiled, for rustc.
+    fn crate_name(&self) -> Option<&str> {
+        None
+    }
+
+    /// The version of the hashing of this compile, as `cache_schema` names
+    /// it, recorded in the entry of its outputs.
+    fn cache_schema(&self) -> String;
+
     fn box_clone(&self) -> Box<dyn CompilerHasher<T>>;
 }
 
+/// The directory that `SCCACHE_DEBUG_DIR` sets, to write a file to about
+/// the hashing and lookup of each compile.
+pub fn debug_dir() -> Option<PathBuf> {
+    env::var_os("SCCACHE_DEBUG_DIR")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+}
+
+/// What's written to the `debug_dir` about a compile.
+struct DebugRecord {
+    dir: PathBuf,
+    output: String,
+    cwd: PathBuf,
+    arguments: Vec<OsString>,
+    /// The compiler's parsed arguments, as `Debug` shows them.
+    parsed: String,
+    hash_inputs: Vec<HashInput>,
+}
+
+impl DebugRecord {
+    /// Write the record with the key, if the compile was hashed, and what was
+    /// decided, to a file named after the output and the time.
+    fn write(&self, key: Option<&str>, decision: &str) {
+        let mut text = format!(
+            "output: {}\ncwd: {:?}\narguments: {:?}\nkey: {}\nresult: {}\n\nhash inputs:\n",
+            self.output,
+            self.cwd,
+            self.arguments,
+            key.unwrap_or("-"),
+            decision
+        );
+        for (name, value) in &self.hash_inputs {
+            text.push_str(&format!("{}: {}\n", name, value));
+        }
+        text.push_str(&format!("\nparsed arguments:\n{}\n", self.parsed));
+
+        let name: String = self
+            .output
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+                _ => '_',
+            })
+            .collect();
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let path = self.dir.join(format!("{}.{}.txt", name, millis));
+        if let Err(e) = fs::create_dir_all(&self.dir).and_then(|()| fs::write(&path, text)) {
+            warn!("Failed to write {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// What's reported about a cache hit whose outputs don't match compiling it.
+struct HitContext {
+    output: String,
+    cwd: PathBuf,
+    arguments: Vec<OsString>,
+    weak_toolchain_key: String,
+}
+
+/// Compile a cache hit sampled by `SCCACHE_VERIFY_HITS` locally, over the
+/// outputs extracted from its entry, and compare what's compiled with them.
+async fn check_hit<T>(
+    creator: T,
+    storage: Arc<dyn Storage>,
+    compilation: Box<dyn Compilation>,
+    outputs: HashMap<String, PathBuf>,
+    key: String,
+    context: HitContext,
+    pool: &ThreadPool,
+) -> HitCheck
+where
+    T: CommandCreatorSync,
+{
+    debug!("[{}]: Checking cache hit by compiling it", context.output);
+    let outputs = Arc::new(outputs);
+    let check = async {
+        let cached = {
+            let outputs = outputs.clone();
+            pool.spawn_fn(move || Ok(verify::read_outputs(&outputs)?))
+                .await?
+        };
+        let (_, _, compiled) = dist_or_local_compile(
+            Ok(None),
+            creator,
+            context.cwd.clone(),
+            compilation,
+            context.weak_toolchain_key.clone(),
+            context.output.clone(),
+            None,
+        )
+        .await?;
+        let success = compiled.status.success();
+        let mismatches = pool
+            .spawn_fn(move || {
+                Ok(if success {
+                    verify::compare(&cached, &verify::read_outputs(&outputs)?)
+                } else {
+                    vec![]
+                })
+            })
+            .await?;
+        Ok::<_, Error>((compiled, mismatches))
+    };
+    let (compiled, mismatches) = match check.await {
+        Ok(checked) => checked,
+        Err(e) => {
+            warn!("Failed to check cache hit: {:?}", e);
+            return HitCheck::Unchecked;
+        }
+    };
+    if compiled.status.success() && mismatches.is_empty() {
+        debug!("[{}]: Cache hit matches compiling it", context.output);
+        return HitCheck::Matched;
+    }
+    error!(
+        "[{}]: Cache hit doesn't match compiling it: key {}, cwd {:?}, arguments {:?}",
+        context.output, key, context.cwd, context.arguments
+    );
+    if !compiled.status.success() {
+        error!(
+            "[{}] \tcompiling it failed with {}: {}",
+            context.output,
+            compiled.status,
+            String::from_utf8_lossy(&compiled.stderr)
+        );
+    }
+    for mismatch in &mismatches {
+        error!("[{}] \t{}", context.output, mismatch);
+    }
+    if !verify::quarantine() {
+        return HitCheck::Mismatched;
+    }
+    warn!(
+        "[{}]: Removing entry {} from the cache",
+        context.output, key
+    );
+    if let Err(e) = storage.delete(&key).await {
+        warn!("[{}]: Failed to remove the entry: {}", context.output, e);
+    }
+    HitCheck::Mismatched
+}
+
+/// Find the compiler wrapper `wrapper` in the `PATH` of `env_vars`, relative
+/// to `cwd`.
+fn find_next_wrapper(
+    wrapper: &Path,
+    env_vars: &[(OsString, OsString)],
+    cwd: &Path,
+) -> Result<PathBuf> {
+    let path = env_vars
+        .iter()
+        .find(|&&(ref k, _)| k == "PATH")
+        .map(|&(_, ref v)| v.clone());
+    which::which_in(wrapper, path, cwd)
+        .with_context(|| format!("failed to find the next wrapper {:?}", wrapper))
+}
+
+// Put a warning about a distributed compile falling back to a local one
+// ahead of the output of the local compile.
+fn add_dist_warning(output: &mut process::Output, warning: Option<String>) {
+    if let Some(warning) = warning {
+        let mut stderr = warning.into_bytes();
+        stderr.append(&mut output.stderr);
+        output.stderr = stderr;
+    }
+}
+
 #[cfg(not(feature = "dist-client"))]
-fn dist_or_local_compile<T>(
+async fn dist_or_local_compile<T>(
     _dist_client: Result<Option<Arc<dyn dist::Client>>>,
     creator: T,
     _cwd: PathBuf,
     compilation: Box<dyn Compilation>,
     _weak_toolchain_key: String,
     out_pretty: String,
-) -> SFuture<(Cacheable, DistType, process::Output)>
+    next_wrapper: Option<PathBuf>,
+) -> Result<(Cacheable, DistType, process::Output)>
 where
     T: CommandCreatorSync,
 {
     let mut path_transformer = dist::PathTransformer::default();
-    let compile_commands = compilation
+    let (compile_cmd, _dist_compile_cmd, cacheable) = compilation
         .generate_compile_commands(&mut path_transformer, true)
-        .context("Failed to generate compile commands");
-    let (compile_cmd, _dist_compile_cmd, cacheable) = match compile_commands {
-        Ok(cmds) => cmds,
-        Err(e) => return f_err(e),
-    };
+        .context("Failed to generate compile commands")?;
 
     debug!("[{}]: Compiling locally", out_pretty);
-    Box::new(
-        compile_cmd
-            .execute(&creator)
-            .map(move |o| (cacheable, DistType::NoDist, o)),
-    )
+    let output = compile_cmd
+        .wrapped(next_wrapper.as_deref())
+        .execute(&creator)
+        .await?;
+    Ok((cacheable, DistType::NoDist, output))
 }
 
 #[cfg(feature = "dist-client")]
-fn dist_or_local_compile<T>(
+async fn dist_or_local_compile<T>(
     dist_client: Result<Option<Arc<dyn dist::Client>>>,
     creator: T,
     cwd: PathBuf,
     compilation: Box<dyn Compilation>,
     weak_toolchain_key: String,
     out_pretty: String,
-) -> SFuture<(Cacheable, DistType, process::Output)>
+    next_wrapper: Option<PathBuf>,
+) -> Result<(Cacheable, DistType, process::Output)>
 where
     T: CommandCreatorSync,
 {
-    use futures::future;
+    use crate::config::DistFallback;
     use std::io;
 
     let rewrite_includes_only = match dist_client {
@@ -463,162 +874,247 @@ where
         _ => false,
     };
     let mut path_transformer = dist::PathTransformer::default();
-    let compile_commands = compilation
+    let (compile_cmd, dist_compile_cmd, cacheable) = compilation
         .generate_compile_commands(&mut path_transformer, rewrite_includes_only)
-        .context("Failed to generate compile commands");
-    let (compile_cmd, dist_compile_cmd, cacheable) = match compile_commands {
-        Ok(cmds) => cmds,
-        Err(e) => return f_err(e),
-    };
+        .context("Failed to generate compile commands")?;
 
-    let dist_client = match dist_client {
-        Ok(Some(dc)) => dc,
-        Ok(None) => {
+    let dist_client = match dist_client? {
+        Some(dc) => dc,
+        None => {
             debug!("[{}]: Compiling locally", out_pretty);
-            return Box::new(
-                compile_cmd
-                    .execute(&creator)
-                    .map(move |o| (cacheable, DistType::NoDist, o)),
-            );
-        }
-        Err(e) => {
-            return f_err(e);
+            let output = compile_cmd
+                .wrapped(next_wrapper.as_deref())
+                .execute(&creator)
+                .await?;
+            return Ok((cacheable, DistType::NoDist, output));
         }
     };
 
     debug!("[{}]: Attempting distributed compilation", out_pretty);
-    let compile_out_pretty = out_pretty.clone();
-    let compile_out_pretty2 = out_pretty.clone();
-    let compile_out_pretty3 = out_pretty.clone();
-    let compile_out_pretty4 = out_pretty;
     let local_executable = compile_cmd.executable.clone();
-    let local_executable2 = local_executable.clone();
-    // TODO: the number of map_errs is subideal, but there's no futures-based carrier trait AFAIK
-    Box::new(future::result(dist_compile_cmd.context("Could not create distributed compile command"))
-        .and_then(move |dist_compile_cmd| {
-            debug!("[{}]: Creating distributed compile request", compile_out_pretty);
-            let dist_output_paths = compilation.outputs()
+    let fallback = dist_client.fallback();
+    let dist_compile = async {
+        let packaged = (|| -> Result<_> {
+            let dist_compile_cmd =
+                dist_compile_cmd.context("Could not create distributed compile command")?;
+            debug!("[{}]: Creating distributed compile request", out_pretty);
+            let dist_output_paths = compilation
+                .outputs()
                 .map(|(_key, path)| path_transformer.as_dist_abs(&cwd.join(path)))
                 .collect::<Option<_>>()
                 .context("Failed to adapt an output path for distributed compile")?;
-            compilation.into_dist_packagers(path_transformer)
-                .map(|packagers| (dist_compile_cmd, packagers, dist_output_paths))
-        })
-        .and_then(move |(mut dist_compile_cmd, (inputs_packager, toolchain_packager, outputs_rewriter), dist_output_paths)| {
-            debug!("[{}]: Identifying dist toolchain for {:?}", compile_out_pretty2, local_executable);
-            dist_client.put_toolchain(&local_executable, &weak_toolchain_key, toolchain_packager)
-                .and_then(|(dist_toolchain, maybe_dist_compile_executable)| {
-                    let mut tc_archive = None;
-                    if let Some((dist_compile_executable, archive_path)) = maybe_dist_compile_executable {
-                        dist_compile_cmd.executable = dist_compile_executable;
-                        tc_archive = Some(archive_path);
-                    }
-                    Ok((dist_client, dist_compile_cmd, dist_toolchain, inputs_packager, outputs_rewriter, dist_output_paths, tc_archive))
-                })
-        })
-        .and_then(move |(dist_client, dist_compile_cmd, dist_toolchain, inputs_packager, outputs_rewriter, dist_output_paths, tc_archive)| {
-            debug!("[{}]: Requesting allocation", compile_out_pretty3);
-            dist_client.do_alloc_job(dist_toolchain.clone())
-                .and_then(move |jares| {
-                    let alloc = match jares {
-                        dist::AllocJobResult::Success { job_alloc, need_toolchain: true } => {
-                            debug!("[{}]: Sending toolchain {} for job {}",
-                                compile_out_pretty3, dist_toolchain.archive_id, job_alloc.job_id);
-                            Box::new(dist_client.do_submit_toolchain(job_alloc.clone(), dist_toolchain)
-                                .and_then(move |res| {
-                                    match res {
-                                        dist::SubmitToolchainResult::Success => Ok(job_alloc),
-                                        dist::SubmitToolchainResult::JobNotFound =>
-                                            bail!("Job {} not found on server", job_alloc.job_id),
-                                        dist::SubmitToolchainResult::CannotCache =>
-                                            bail!("Toolchain for job {} could not be cached by server", job_alloc.job_id),
-                                    }
-                                })
-                                .fcontext("Could not submit toolchain"))
-                        },
-                        dist::AllocJobResult::Success { job_alloc, need_toolchain: false } =>
-                            f_ok(job_alloc),
-                        dist::AllocJobResult::Fail { msg } =>
-                            f_err(anyhow!("Failed to allocate job").context(msg)),
-                    };
-                    alloc
-                        .and_then(move |job_alloc| {
-                            let job_id = job_alloc.job_id;
-                            let server_id = job_alloc.server_id;
-                            debug!("[{}]: Running job", compile_out_pretty3);
-                            dist_client.do_run_job(job_alloc, dist_compile_cmd, dist_output_paths, inputs_packager)
-                                .map(move |res| ((job_id, server_id), res))
-                                .fwith_context(move || format!("could not run distributed compilation job on {:?}", server_id))
-                        })
-                })
-                .and_then(move |((job_id, server_id), (jres, path_transformer))| {
-                    let jc = match jres {
-                        dist::RunJobResult::Complete(jc) => jc,
-                        dist::RunJobResult::JobNotFound => bail!("Job {} not found on server", job_id),
-                    };
-                    info!("fetched {:?}", jc.outputs.iter().map(|&(ref p, ref bs)| (p, bs.lens().to_string())).collect::<Vec<_>>());
-                    let mut output_paths: Vec<PathBuf> = vec![];
-                    macro_rules! try_or_cleanup {
-                        ($v:expr) => {{
-                            match $v {
-                                Ok(v) => v,
-                                Err(e) => {
-                                    // Do our best to clear up. We may end up deleting a file that we just wrote over
-                                    // the top of, but it's better to clear up too much than too little
-                                    for local_path in output_paths.iter() {
-                                        if let Err(e) = fs::remove_file(local_path) {
-                                            if e.kind() != io::ErrorKind::NotFound {
-                                                warn!("{} while attempting to clear up {}", e, local_path.display())
-                                            }
-                                        }
-                                    }
-                                    return Err(e)
-                                },
+            let packagers = compilation.into_dist_packagers(path_transformer)?;
+            Ok((dist_compile_cmd, packagers, dist_output_paths))
+        })();
+        let (
+            mut dist_compile_cmd,
+            (inputs_packager, toolchain_packager, outputs_rewriter),
+            dist_output_paths,
+        ) = packaged.map_err(|e| e.context(DistFailureCause::Packaging))?;
+
+        debug!(
+            "[{}]: Identifying dist toolchain for {:?}",
+            out_pretty, local_executable
+        );
+        let (dist_toolchain, maybe_dist_compile_executable) = dist_client
+            .put_toolchain(&local_executable, &weak_toolchain_key, toolchain_packager)
+            .await
+            .context(DistFailureCause::Toolchain)?;
+        let mut tc_archive = None;
+        if let Some((dist_compile_executable, archive_path)) = maybe_dist_compile_executable {
+            dist_compile_cmd.executable = dist_compile_executable;
+            tc_archive = Some(archive_path);
+        }
+
+        debug!("[{}]: Requesting allocation", out_pretty);
+        let jares = dist_client
+            .do_alloc_job(dist_toolchain.clone())
+            .await
+            .context(DistFailureCause::Scheduler)?;
+        let job_alloc = match jares {
+            dist::AllocJobResult::Success {
+                job_alloc,
+                need_toolchain: true,
+            } => {
+                debug!(
+                    "[{}]: Sending toolchain {} for job {}",
+                    out_pretty, dist_toolchain.archive_id, job_alloc.job_id
+                );
+                dist_client
+                    .do_submit_toolchain(job_alloc.clone(), dist_toolchain)
+                    .await
+                    .and_then(|res| match res {
+                        dist::SubmitToolchainResult::Success => Ok(()),
+                        dist::SubmitToolchainResult::JobNotFound => {
+                            bail!("Job {} not found on server", job_alloc.job_id)
+                        }
+                        dist::SubmitToolchainResult::CannotCache => bail!(
+                            "Toolchain for job {} could not be cached by server",
+                            job_alloc.job_id
+                        ),
+                        dist::SubmitToolchainResult::Untrusted(msg) => bail!(
+                            "Toolchain for job {} was refused by server: {}",
+                            job_alloc.job_id,
+                            msg
+                        ),
+                    })
+                    .context("Could not submit toolchain")
+                    .context(DistFailureCause::Server)?;
+                job_alloc
+            }
+            dist::AllocJobResult::Success {
+                job_alloc,
+                need_toolchain: false,
+            } => job_alloc,
+            dist::AllocJobResult::Fail { msg } => {
+                return Err(anyhow!("Failed to allocate job")
+                    .context(msg)
+                    .context(DistFailureCause::Refused))
+            }
+        };
+
+        let job_id = job_alloc.job_id;
+        let server_id = job_alloc.server_id;
+        debug!("[{}]: Running job", out_pretty);
+        let (jres, path_transformer) = dist_client
+            .do_run_job(
+                job_alloc,
+                dist_compile_cmd,
+                dist_output_paths,
+                inputs_packager,
+            )
+            .await
+            .with_context(|| {
+                format!(
+                    "could not run distributed compilation job on {:?}",
+                    server_id
+                )
+            })
+            .context(DistFailureCause::Server)?;
+        let jc = match jres {
+            dist::RunJobResult::Complete(jc) => jc,
+            dist::RunJobResult::JobNotFound => {
+                return Err(
+                    anyhow!("Job {} not found on server", job_id).context(DistFailureCause::Server)
+                )
+            }
+            dist::RunJobResult::OutOfMemory => {
+                return Err(
+                    anyhow!("Job {} ran out of memory on server {:?}", job_id, server_id)
+                        .context(DistFailureCause::OutOfMemory),
+                )
+            }
+            dist::RunJobResult::TimedOut => {
+                return Err(
+                    anyhow!("Job {} timed out on server {:?}", job_id, server_id)
+                        .context(DistFailureCause::TimedOut),
+                )
+            }
+        };
+        info!(
+            "fetched {:?}",
+            jc.outputs
+                .iter()
+                .map(|&(ref p, ref bs)| (p, bs.lens().to_string()))
+                .collect::<Vec<_>>()
+        );
+        let mut output_paths: Vec<PathBuf> = vec![];
+        macro_rules! try_or_cleanup {
+            ($v:expr) => {{
+                match $v {
+                    Ok(v) => v,
+                    Err(e) => {
+                        // Do our best to clear up. We may end up deleting a file that we just wrote over
+                        // the top of, but it's better to clear up too much than too little
+                        for local_path in output_paths.iter() {
+                            if let Err(e) = fs::remove_file(long_path(local_path)) {
+                                if e.kind() != io::ErrorKind::NotFound {
+                                    warn!(
+                                        "{} while attempting to clear up {}",
+                                        e,
+                                        local_path.display()
+                                    )
+                                }
                             }
-                        }};
+                        }
+                        return Err(e.context(DistFailureCause::Outputs));
                     }
+                }
+            }};
+        }
 
-                    for (path, output_data) in jc.outputs {
-                        let len = output_data.lens().actual;
-                        let local_path = try_or_cleanup!(path_transformer.to_local(&path)
-                            .with_context(|| format!("unable to transform output path {}", path)));
-                        output_paths.push(local_path);
-                        // Do this first so cleanup works correctly
-                        let local_path = output_paths.last().expect("nothing in vec after push");
-
-                        let mut file = try_or_cleanup!(File::create(&local_path)
-                            .with_context(|| format!("Failed to create output file {}", local_path.display())));
-                        let count = try_or_cleanup!(io::copy(&mut output_data.into_reader(), &mut file)
-                            .with_context(|| format!("Failed to write output to {}", local_path.display())));
+        for (path, output_data) in jc.outputs {
+            let len = output_data.lens().actual;
+            let local_path = try_or_cleanup!(path_transformer
+                .to_local(&path)
+                .with_context(|| format!("unable to transform output path {}", path)));
+            output_paths.push(local_path);
+            // Do this first so cleanup works correctly
+            let local_path = output_paths.last().expect("nothing in vec after push");
+
+            let mut file = try_or_cleanup!(File::create(long_path(local_path))
+                .with_context(|| format!("Failed to create output file {}", local_path.display())));
+            let count = try_or_cleanup!(output_data
+                .into_reader()
+                .and_then(|mut rdr| io::copy(&mut rdr, &mut file))
+                .with_context(|| format!("Failed to write output to {}", local_path.display())));
+
+            assert!(count == len);
+        }
+        let extra_inputs = match tc_archive {
+            Some(p) => vec![p],
+            None => vec![],
+        };
+        try_or_cleanup!(outputs_rewriter
+            .handle_outputs(&path_transformer, &output_paths, &extra_inputs)
+            .with_context(|| "failed to rewrite outputs from compile"));
+        Ok::<_, Error>((DistType::Ok(server_id), process::Output::from(jc.output)))
+    };
 
-                        assert!(count == len);
-                    }
-                    let extra_inputs = match tc_archive {
-                        Some(p) => vec![p],
-                        None => vec![],
-                    };
-                    try_or_cleanup!(outputs_rewriter.handle_outputs(&path_transformer, &output_paths, &extra_inputs)
-                        .with_context(|| "failed to rewrite outputs from compile"));
-                    Ok((DistType::Ok(server_id), jc.output.into()))
-                })
-        })
-        .or_else(move |e| {
-            if let Some(HttpClientError(_)) = e.downcast_ref::<HttpClientError>() {
-                f_err(e)
-            } else if let Some(lru_disk_cache::Error::FileTooLarge) = e.downcast_ref::<lru_disk_cache::Error>() {
-                f_err(anyhow!(
-                    "Could not cache dist toolchain for {:?} locally.
-                     Increase `toolchain_cache_size` or decrease the toolchain archive size.",
-                    local_executable2))
-            } else {
-                // `{:#}` prints the error and the causes in a single line.
-                let errmsg = format!("{:#}", e);
-                warn!("[{}]: Could not perform distributed compile, falling back to local: {}", compile_out_pretty4, errmsg);
-                Box::new(compile_cmd.execute(&creator).map(|o| (DistType::Error, o)))
-            }
-        })
-        .map(move |(dt, o)| (cacheable, dt, o))
-    )
+    let e = match dist_compile.await {
+        Ok((dist_type, output)) => return Ok((cacheable, dist_type, output)),
+        Err(e) => e,
+    };
+    if let Some(HttpClientError(_)) = e.downcast_ref::<HttpClientError>() {
+        return Err(e);
+    }
+    if let Some(lru_disk_cache::Error::FileTooLarge) = e.downcast_ref::<lru_disk_cache::Error>() {
+        bail!(
+            "Could not cache dist toolchain for {:?} locally.
+             Increase `toolchain_cache_size` or decrease the toolchain archive size.",
+            local_executable
+        );
+    }
+    let cause = e
+        .downcast_ref::<DistFailureCause>()
+        .copied()
+        .unwrap_or(DistFailureCause::Server);
+    // Compiles that can't be distributed at all, or that were turned away
+    // because the servers are busy, say nothing about whether the build farm
+    // is working
+    let expected = matches!(
+        cause,
+        DistFailureCause::Packaging | DistFailureCause::Refused
+    );
+    if fallback == DistFallback::FailFast && !expected {
+        return Err(e.context("Distributed compilation failed, and `dist.fallback` is `fail_fast`"));
+    }
+    // `{:#}` prints the error and the causes in a single line.
+    let errmsg = format!("{:#}", e);
+    warn!(
+        "[{}]: Could not perform distributed compile, falling back to local: {}",
+        out_pretty, errmsg
+    );
+    let warning = match fallback {
+        DistFallback::FallbackWithWarning => Some(format!(
+            "sccache: warning: Distributed compilation failed, compiled locally instead: {}\n",
+            errmsg
+        )),
+        _ => None,
+    };
+    let failure = DistFailure { cause, warning };
+    let output = compile_cmd.execute(&creator).await?;
+    Ok((cacheable, DistType::Error(failure), output))
 }
 
 impl<T: CommandCreatorSync> Clone for Box<dyn CompilerHasher<T>> {
@@ -649,6 +1145,18 @@ pub trait Compilation {
     /// Each item is a descriptive (and unique) name of the output paired with
     /// the path where it'll show up.
     fn outputs<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a str, &'a Path)> + 'a>;
+
+    /// Write any outputs that only depend on the command line, such as
+    /// compilation database fragments. These are generated locally for every
+    /// compile instead of being stored in the cache.
+    fn write_local_outputs(&self) -> Result<()> {
+        Ok(())
+    }
+    /// Returns why this compilation must not be cached, for reasons that only
+    /// show up once its input has been examined during hashing.
+    fn not_cacheable_reason(&self) -> Option<&'static str> {
+        None
+    }
 }
 
 #[cfg(feature = "dist-client")]
@@ -684,6 +1192,8 @@ pub struct HashResult {
     pub compilation: Box<dyn Compilation + 'static>,
     /// A weak key that may be used to identify the toolchain
     pub weak_toolchain_key: String,
+    /// The inputs of the key, if `debug_dir` is set.
+    pub hash_inputs: Vec<HashInput>,
 }
 
 /// Possible results of parsing compiler arguments.
@@ -722,8 +1232,69 @@ pub enum DistType {
     NoDist,
     /// Distributed compile success.
     Ok(dist::ServerId),
-    /// Distributed compile failed.
-    Error,
+    /// Distributed compile failed, and was compiled locally instead.
+    Error(DistFailure),
+}
+
+/// Why a distributed compile fell back to a local one.
+#[derive(Debug, PartialEq)]
+pub struct DistFailure {
+    pub cause: DistFailureCause,
+    /// A warning for the output of the local compile, if `dist.fallback`
+    /// asks for one.
+    pub warning: Option<String>,
+}
+
+/// The part of a distributed compile that failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DistFailureCause {
+    /// The compile couldn't be packaged up to run on a server.
+    Packaging,
+    /// The toolchain couldn't be packaged up.
+    Toolchain,
+    /// The scheduler couldn't be asked for a server.
+    Scheduler,
+    /// The scheduler had no server free to take the job.
+    Refused,
+    /// The server failed to take the toolchain or run the job.
+    Server,
+    /// The job ran out of memory on the server.
+    OutOfMemory,
+    /// The job ran for longer than the server allows.
+    TimedOut,
+    /// The outputs of the job couldn't be written.
+    Outputs,
+}
+
+impl DistFailureCause {
+    /// The name of the cause in stats.
+    pub fn name(self) -> &'static str {
+        match self {
+            DistFailureCause::Packaging => "packaging",
+            DistFailureCause::Toolchain => "toolchain",
+            DistFailureCause::Scheduler => "scheduler",
+            DistFailureCause::Refused => "refused",
+            DistFailureCause::Server => "server",
+            DistFailureCause::OutOfMemory => "out_of_memory",
+            DistFailureCause::TimedOut => "timed_out",
+            DistFailureCause::Outputs => "outputs",
+        }
+    }
+}
+
+impl fmt::Display for DistFailureCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DistFailureCause::Packaging => "Could not package the compile to distribute",
+            DistFailureCause::Toolchain => "Could not package the toolchain",
+            DistFailureCause::Scheduler => "Could not get a server from the scheduler",
+            DistFailureCause::Refused => "The scheduler refused the job",
+            DistFailureCause::Server => "The job failed on the server",
+            DistFailureCause::OutOfMemory => "The job ran out of memory on the server",
+            DistFailureCause::TimedOut => "The job timed out on the server",
+            DistFailureCause::Outputs => "Could not write the outputs of the job",
+        })
+    }
 }
 
 /// Specifics about cache misses.
@@ -737,6 +1308,31 @@ pub enum MissType {
     TimedOut,
     /// Error reading from cache
     CacheReadError,
+    /// Cache lookup was skipped, too many were waiting for the storage.
+    Shed,
+}
+
+impl fmt::Display for MissType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MissType::Normal => "not in the cache",
+            MissType::ForcedRecache => "forced recache",
+            MissType::TimedOut => "the cache timed out",
+            MissType::CacheReadError => "cache read error",
+            MissType::Shed => "lookup shed",
+        })
+    }
+}
+
+/// Whether a cache hit was checked against compiling it, as
+/// `SCCACHE_VERIFY_HITS` samples them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HitCheck {
+    Unchecked,
+    /// The compiled outputs were the same as those in the cache.
+    Matched,
+    /// They differed, or compiling failed.
+    Mismatched,
 }
 
 /// Information about a successful cache write.
@@ -745,19 +1341,40 @@ pub struct CacheWriteInfo {
     pub duration: Duration,
 }
 
+/// How long the steps of a compile took, for those it got to.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CompileTimings {
+    /// Hashing the compile, which includes preprocessing C and C++.
+    pub hash: Option<Duration>,
+    /// Looking up its entry in the cache.
+    pub cache_get: Option<Duration>,
+    /// Running the compiler, locally or distributed.
+    pub compile: Option<Duration>,
+}
+
 /// The result of a compilation or cache retrieval.
 pub enum CompileResult {
     /// An error made the compilation not possible.
     Error,
-    /// Result was found in cache.
-    CacheHit(Duration),
-    /// Result was not found in cache.
+    /// Result was found in cache, taking the duration to look up, with the
+    /// size of the entry as it's stored, whether it was checked and the
+    /// version of the entry, as `CacheRead::version` describes it.
+    CacheHit(Duration, u64, HitCheck, String),
+    /// Result was not found in cache, taking the duration to look up, under
+    /// the key it's stored with.
     ///
     /// The `CacheWriteFuture` will resolve when the result is finished
     /// being stored in the cache.
-    CacheMiss(MissType, DistType, Duration, SFuture<CacheWriteInfo>),
-    /// Not in cache, but the compilation result was determined to be not cacheable.
-    NotCacheable,
+    CacheMiss(
+        MissType,
+        DistType,
+        Duration,
+        String,
+        LocalBoxFuture<'static, Result<CacheWriteInfo>>,
+    ),
+    /// Not in cache, but the compilation result was determined to be not cacheable,
+    /// possibly with the reason why.
+    NotCacheable(Option<&'static str>),
     /// Not in cache, but compilation failed.
     CompileFailed,
 }
@@ -781,11 +1398,19 @@ impl fmt::Debug for CompileResult {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match *self {
             CompileResult::Error => write!(f, "CompileResult::Error"),
-            CompileResult::CacheHit(ref d) => write!(f, "CompileResult::CacheHit({:?})", d),
-            CompileResult::CacheMiss(ref m, ref dt, ref d, _) => {
-                write!(f, "CompileResult::CacheMiss({:?}, {:?}, {:?}, _)", d, m, dt)
+            CompileResult::CacheHit(ref d, size, check, ref version) => write!(
+                f,
+                "CompileResult::CacheHit({:?}, {}, {:?}, {:?})",
+                d, size, check, version
+            ),
+            CompileResult::CacheMiss(ref m, ref dt, ref d, ref key, _) => write!(
+                f,
+                "CompileResult::CacheMiss({:?}, {:?}, {:?}, {:?}, _)",
+                d, m, dt, key
+            ),
+            CompileResult::NotCacheable(ref why) => {
+                write!(f, "CompileResult::NotCacheable({:?})", why)
             }
-            CompileResult::NotCacheable => write!(f, "CompileResult::NotCacheable"),
             CompileResult::CompileFailed => write!(f, "CompileResult::CompileFailed"),
         }
     }
@@ -796,12 +1421,14 @@ impl PartialEq<CompileResult> for CompileResult {
     fn eq(&self, other: &CompileResult) -> bool {
         match (self, other) {
             (&CompileResult::Error, &CompileResult::Error) => true,
-            (&CompileResult::CacheHit(_), &CompileResult::CacheHit(_)) => true,
+            (&CompileResult::CacheHit(..), &CompileResult::CacheHit(..)) => true,
             (
-                &CompileResult::CacheMiss(ref m, ref dt, _, _),
-                &CompileResult::CacheMiss(ref n, ref dt2, _, _),
+                &CompileResult::CacheMiss(ref m, ref dt, _, _, _),
+                &CompileResult::CacheMiss(ref n, ref dt2, _, _, _),
             ) => m == n && dt == dt2,
-            (&CompileResult::NotCacheable, &CompileResult::NotCacheable) => true,
+            (&CompileResult::NotCacheable(ref why), &CompileResult::NotCacheable(ref why2)) => {
+                why == why2
+            }
             (&CompileResult::CompileFailed, &CompileResult::CompileFailed) => true,
             _ => false,
         }
@@ -824,20 +1451,19 @@ pub enum CacheControl {
     ForceRecache,
 }
 
-/// Creates a future that will write `contents` to `path` inside of a temporary
-/// directory.
+/// Writes `contents` to `path` inside of a temporary directory.
 ///
-/// The future will resolve to the temporary directory and an absolute path
-/// inside that temporary directory with a file that has the same filename as
-/// `path` contains the `contents` specified.
+/// Returns the temporary directory and an absolute path inside that
+/// temporary directory with a file that has the same filename as `path`
+/// contains the `contents` specified.
 ///
 /// Note that when the `TempDir` is dropped it will delete all of its contents
 /// including the path returned.
-pub fn write_temp_file(
+pub async fn write_temp_file(
     pool: &ThreadPool,
     path: &Path,
     contents: Vec<u8>,
-) -> SFuture<(TempDir, PathBuf)> {
+) -> Result<(TempDir, PathBuf)> {
     let path = path.to_owned();
     pool.spawn_fn(move || -> Result<_> {
         let dir = tempfile::Builder::new().prefix("sccache").tempdir()?;
@@ -846,18 +1472,19 @@ pub fn write_temp_file(
         file.write_all(&contents)?;
         Ok((dir, src))
     })
-    .fcontext("failed to write temporary file")
+    .await
+    .context("failed to write temporary file")
 }
 
 /// If `executable` is a known compiler, return `Some(Box<Compiler>)`.
-fn detect_compiler<T>(
+async fn detect_compiler<T>(
     creator: T,
     executable: &Path,
     cwd: &Path,
     env: &[(OsString, OsString)],
     pool: &ThreadPool,
     dist_archive: Option<PathBuf>,
-) -> SFuture<(Box<dyn Compiler<T>>, Option<Box<dyn CompilerProxy<T>>>)>
+) -> Result<(Box<dyn Compiler<T>>, Option<Box<dyn CompilerProxy<T>>>)>
 where
     T: CommandCreatorSync,
 {
@@ -865,132 +1492,126 @@ where
 
     // First, see if this looks like rustc.
     let filename = match executable.file_stem() {
-        None => return f_err(anyhow!("could not determine compiler kind")),
+        None => bail!("could not determine compiler kind"),
         Some(f) => f,
     };
     let filename = filename.to_string_lossy().to_lowercase();
 
     let rustc_vv = if filename == "rustc" || filename == "clippy-driver" {
         // Sanity check that it's really rustc.
-        let executable = executable.to_path_buf();
         let mut child = creator.clone().new_command_sync(executable);
         child.env_clear().envs(ref_env(env)).args(&["-vV"]);
 
-        Box::new(run_input_output(child, None).map(|output| {
-            if let Ok(stdout) = String::from_utf8(output.stdout.clone()) {
-                if stdout.starts_with("rustc ") {
-                    return Some(Ok(stdout));
-                }
-            }
-            Some(Err(ProcessError(output)))
-        }))
+        let output = run_input_output(child, None).await?;
+        match String::from_utf8(output.stdout.clone()) {
+            Ok(stdout) if stdout.starts_with("rustc ") => Some(Ok(stdout)),
+            _ => Some(Err(ProcessError(output))),
+        }
     } else {
-        f_ok(None)
+        None
     };
 
-    let creator1 = creator.clone();
-    let creator2 = creator.clone();
-    let executable = executable.to_owned();
-    let executable2 = executable.clone();
-    let env1 = env.to_owned();
-    let env2 = env.to_owned();
-    let env3 = env.to_owned();
-    let pool = pool.clone();
-    let cwd = cwd.to_owned();
-    Box::new(
-        rustc_vv
-            .and_then(move |rustc_vv| match rustc_vv {
-            Some(Ok(rustc_verbose_version)) => {
-                debug!("Found rustc");
-
-                Box::new(
-                    RustupProxy::find_proxy_executable::<T>(&executable2,"rustup", creator, &env1)
-                        .and_then(move |proxy : Result<Option<RustupProxy>>| -> SFuture<(Option<RustupProxy>, PathBuf)> {
-                            match proxy {
-                                Ok(Some(proxy)) => {
-                                    trace!("Found rustup proxy executable");
-                                    let fut =
-                                        proxy
-                                            .resolve_proxied_executable(creator1, cwd, &env2)
-                                            .then(move |res| {
-                                                // take the pathbuf for rustc as resolved by the proxy
-                                                match res {
-                                                    Ok((resolved_path, _time)) => {
-                                                        trace!("Resolved path with rustup proxy {:?}", &resolved_path);
-                                                        f_ok((Some(proxy), resolved_path))
-                                                    },
-                                                    Err(e) => {
-                                                        trace!("Could not resolve compiler with rustup proxy: {}", e);
-                                                        f_ok((None, executable))
-                                                    },
-                                                }
-                                            });
-                                    Box::new(fut)
-                                },
-                                Ok(None) => {
-                                    trace!("Did not find rustup");
-                                    f_ok((None, executable))
-                                },
-                                Err(e) => {
-                                    trace!("Did not find rustup due to {}", e);
-                                    f_ok((None, executable))
-                                },
-                            }
-                        })
-                        .then(move |res: Result<(Option<RustupProxy>, PathBuf)>| {
-                            let (proxy, resolved_rustc) : (_, PathBuf)
-                                = res
-                                    .map(|(proxy,resolved_compiler_executable)| {
-                                        (
-                                            proxy.map(Box::new).map(|x : Box<RustupProxy>| {
-                                                x as Box<dyn CompilerProxy<T>>
-                                            }),
-                                            resolved_compiler_executable
-                                        )
-                                    })
-                                    .unwrap_or_else(|_e| {
-                                        trace!("Compiling rust without proxy");
-                                        (None, executable2)
-                                    });
-
-                            Rust::new(
-                                creator2,
-                                resolved_rustc,
-                                &env3,
-                                &rustc_verbose_version,
-                                dist_archive,
-                                pool,
-                            )
-                            .map(|c| {
-                                (
-                                    Box::new(c) as Box<dyn Compiler<T> >,
-                                    proxy as Option<Box<dyn CompilerProxy<T>>>
-                                )
-                            })
+    let rustc_verbose_version = match rustc_vv {
+        Some(Ok(rustc_verbose_version)) => rustc_verbose_version,
+        Some(Err(e)) => return Err(e.into()),
+        None => {
+            let c = detect_c_compiler(creator, executable.to_owned(), env.to_vec(), pool).await?;
+            return Ok((c, None));
+        }
+    };
+
+    debug!("Found rustc");
+    let proxy =
+        match RustupProxy::find_proxy_executable::<T>(executable, "rustup", creator.clone(), env)
+            .await
+        {
+            Ok(Some(proxy)) => {
+                trace!("Found rustup proxy executable");
+                // take the pathbuf for rustc as resolved by the proxy
+                match proxy
+                    .resolve_proxied_executable(creator.clone(), cwd.to_owned(), env)
+                    .await
+                {
+                    Ok((resolved_path, _time)) => {
+                        trace!("Resolved path with rustup proxy {:?}", &resolved_path);
+                        Some((proxy, resolved_path))
                     }
-                )
-            )
+                    Err(e) => {
+                        trace!("Could not resolve compiler with rustup proxy: {}", e);
+                        None
+                    }
+                }
             }
-            Some(Err(e)) => f_err(e),
-            None => {
-                let cc = detect_c_compiler(creator, executable, env1.to_vec(), pool);
-                Box::new(cc.map(|c : Box<dyn Compiler<T>>| { (c, None ) }))
-            },
-        })
+            Ok(None) => {
+                trace!("Did not find rustup");
+                None
+            }
+            Err(e) => {
+                trace!("Did not find rustup due to {}", e);
+                None
+            }
+        };
+    let (proxy, resolved_rustc) = match proxy {
+        Some((proxy, resolved_rustc)) => (
+            Some(Box::new(proxy) as Box<dyn CompilerProxy<T>>),
+            resolved_rustc,
+        ),
+        None => {
+            trace!("Compiling rust without proxy");
+            (None, executable.to_owned())
+        }
+    };
+
+    let rust = Rust::new(
+        creator,
+        resolved_rustc,
+        env,
+        &rustc_verbose_version,
+        dist_archive,
+        pool.clone(),
     )
+    .await?;
+    Ok((Box::new(rust), proxy))
 }
 
-fn detect_c_compiler<T>(
+/// Executable names of the TI Code Generation Tools compiler drivers.
+const TI_COMPILERS: &[&str] = &["armcl", "cl2000", "cl430", "cl6x", "cl7x", "clpru"];
+
+async fn detect_c_compiler<T>(
     creator: T,
     executable: PathBuf,
     env: Vec<(OsString, OsString)>,
-    pool: ThreadPool,
-) -> SFuture<Box<dyn Compiler<T>>>
+    pool: &ThreadPool,
+) -> Result<Box<dyn Compiler<T>>>
 where
     T: CommandCreatorSync,
 {
     trace!("detect_c_compiler");
 
+    // The TI compilers don't accept `-E`, so recognize them by name instead
+    // of running them.
+    let name = executable
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .map(str::to_lowercase)
+        .unwrap_or_default();
+    if TI_COMPILERS.contains(&name.as_str()) {
+        debug!("Found TI compiler");
+        return Ok(Box::new(CCompiler::new(TI, executable, None, pool).await?));
+    }
+    // Nor do the resource compilers. llvm-windres can't decompile scripts,
+    // which is how they are hashed.
+    if name == "rc" {
+        debug!("Found rc");
+        return Ok(Box::new(CCompiler::new(RC, executable, None, pool).await?));
+    }
+    if name == "windres" || (name.ends_with("-windres") && name != "llvm-windres") {
+        debug!("Found windres");
+        return Ok(Box::new(
+            CCompiler::new(Windres, executable, None, pool).await?,
+        ));
+    }
+
     // NVCC needs to be first as msvc, clang, or gcc could
     // be the underlying host compiler for nvcc
     // Both clang and clang-cl define _MSC_VER on Windows, so we first
@@ -1018,140 +1639,112 @@ unknown
 __VERSION__
 "
     .to_vec();
-    let write = write_temp_file(&pool, "testfile.c".as_ref(), test);
+    let (tempdir, src) = write_temp_file(pool, "testfile.c".as_ref(), test).await?;
 
     let mut cmd = creator.clone().new_command_sync(&executable);
     cmd.stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .envs(env.iter().map(|s| (&s.0, &s.1)));
-    let output = write.and_then(move |(tempdir, src)| {
-        cmd.arg("-E").arg(src);
-        trace!("compiler {:?}", cmd);
-        cmd.spawn()
-            .and_then(|child| {
-                child
-                    .wait_with_output()
-                    .fcontext("failed to read child output")
-            })
-            .map(|e| {
-                drop(tempdir);
-                e
-            })
+    cmd.arg("-E").arg(src);
+    trace!("compiler {:?}", cmd);
+    let child = cmd.spawn().await?;
+    let output = child
+        .wait_with_output()
+        .await
+        .context("failed to read child output")?;
+    drop(tempdir);
+
+    let stdout = str::from_utf8(&output.stdout).context("Failed to parse output")?;
+    let mut lines = stdout.lines().filter_map(|line| {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            None
+        } else {
+            Some(line)
+        }
     });
-
-    Box::new(output.and_then(move |output| -> SFuture<_> {
-        let stdout = match str::from_utf8(&output.stdout) {
-            Ok(s) => s,
-            Err(_) => return f_err(anyhow!("Failed to parse output")),
-        };
-        let mut lines = stdout.lines().filter_map(|line| {
-            let line = line.trim();
-            if line.is_empty() || line.starts_with('#') {
-                None
-            } else {
-                Some(line)
+    if let Some(kind) = lines.next() {
+        let version = lines
+            .next()
+            // In case the compiler didn't expand the macro.
+            .filter(|&line| line != "__VERSION__")
+            .map(str::to_owned);
+        match kind {
+            "clang" | "clang++" => {
+                debug!("Found {}", kind);
+                let clang = Clang {
+                    clangplusplus: kind == "clang++",
+                };
+                return Ok(Box::new(
+                    CCompiler::new(clang, executable, version, pool).await?,
+                ));
             }
-        });
-        if let Some(kind) = lines.next() {
-            let version = lines
-                .next()
-                // In case the compiler didn't expand the macro.
-                .filter(|&line| line != "__VERSION__")
-                .map(str::to_owned);
-            match kind {
-                "clang" | "clang++" => {
-                    debug!("Found {}", kind);
-                    return Box::new(
-                        CCompiler::new(
-                            Clang {
-                                clangplusplus: kind == "clang++",
-                            },
-                            executable,
-                            version,
-                            &pool,
-                        )
-                        .map(|c| Box::new(c) as Box<dyn Compiler<T>>),
-                    );
-                }
-                "diab" => {
-                    debug!("Found diab");
-                    return Box::new(
-                        CCompiler::new(Diab, executable, version, &pool)
-                            .map(|c| Box::new(c) as Box<dyn Compiler<T>>),
-                    );
-                }
-                "gcc" | "g++" => {
-                    debug!("Found {}", kind);
-                    return Box::new(
-                        CCompiler::new(
-                            GCC {
-                                gplusplus: kind == "g++",
-                            },
-                            executable,
-                            version,
-                            &pool,
-                        )
-                        .map(|c| Box::new(c) as Box<dyn Compiler<T>>),
-                    );
-                }
-                "msvc" | "msvc-clang" => {
-                    let is_clang = kind == "msvc-clang";
-                    debug!("Found MSVC (is clang: {})", is_clang);
-                    let prefix = msvc::detect_showincludes_prefix(
-                        &creator,
-                        executable.as_ref(),
-                        is_clang,
-                        env,
-                        &pool,
-                    );
-                    return Box::new(prefix.and_then(move |prefix| {
-                        trace!("showIncludes prefix: '{}'", prefix);
-                        CCompiler::new(
-                            MSVC {
-                                includes_prefix: prefix,
-                                is_clang,
-                            },
-                            executable,
-                            version,
-                            &pool,
-                        )
-                        .map(|c| Box::new(c) as Box<dyn Compiler<T>>)
-                    }));
-                }
-                "nvcc" => {
-                    debug!("Found NVCC");
-                    return Box::new(
-                        CCompiler::new(NVCC, executable, version, &pool)
-                            .map(|c| Box::new(c) as Box<dyn Compiler<T>>),
-                    );
-                }
-                _ => (),
+            "diab" => {
+                debug!("Found diab");
+                return Ok(Box::new(
+                    CCompiler::new(Diab, executable, version, pool).await?,
+                ));
+            }
+            "gcc" | "g++" => {
+                debug!("Found {}", kind);
+                let gcc = GCC {
+                    gplusplus: kind == "g++",
+                };
+                return Ok(Box::new(
+                    CCompiler::new(gcc, executable, version, pool).await?,
+                ));
+            }
+            "msvc" | "msvc-clang" => {
+                let is_clang = kind == "msvc-clang";
+                debug!("Found MSVC (is clang: {})", is_clang);
+                let prefix = msvc::detect_showincludes_prefix(
+                    &creator,
+                    executable.as_ref(),
+                    is_clang,
+                    env,
+                    pool,
+                )
+                .await?;
+                trace!("showIncludes prefix: '{}'", prefix);
+                let msvc = MSVC {
+                    includes_prefix: prefix,
+                    is_clang,
+                };
+                return Ok(Box::new(
+                    CCompiler::new(msvc, executable, version, pool).await?,
+                ));
             }
+            "nvcc" => {
+                debug!("Found NVCC");
+                return Ok(Box::new(
+                    CCompiler::new(NVCC, executable, version, pool).await?,
+                ));
+            }
+            _ => (),
         }
+    }
 
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        debug!("nothing useful in detection output {:?}", stdout);
-        debug!("compiler status: {}", output.status);
-        debug!("compiler stderr:\n{}", stderr);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    debug!("nothing useful in detection output {:?}", stdout);
+    debug!("compiler status: {}", output.status);
+    debug!("compiler stderr:\n{}", stderr);
 
-        f_err(anyhow!(stderr.into_owned()))
-    }))
+    bail!(stderr.into_owned())
 }
 
 /// If `executable` is a known compiler, return a `Box<Compiler>` containing information about it.
-pub fn get_compiler_info<T>(
+pub async fn get_compiler_info<T>(
     creator: T,
     executable: &Path,
     cwd: &Path,
     env: &[(OsString, OsString)],
     pool: &ThreadPool,
     dist_archive: Option<PathBuf>,
-) -> SFuture<(Box<dyn Compiler<T>>, Option<Box<dyn CompilerProxy<T>>>)>
+) -> Result<(Box<dyn Compiler<T>>, Option<Box<dyn CompilerProxy<T>>>)>
 where
     T: CommandCreatorSync,
 {
-    let pool = pool.clone();
-    detect_compiler(creator, executable, cwd, env, &pool, dist_archive)
+    detect_compiler(creator, executable, cwd, env, pool, dist_archive).await
 }
 
 #[cfg(test)]
@@ -1159,17 +1752,16 @@ mod test {
     use super::*;
     use crate::cache::disk::DiskCache;
     use crate::cache::Storage;
+    use crate::config::Sloppiness;
     use crate::mock_command::*;
     use crate::test::mock_storage::MockStorage;
     use crate::test::utils::*;
-    use futures::{future, Future};
-    use futures_03::executor::ThreadPool;
+    use futures::executor::{block_on, ThreadPool};
     use std::fs::{self, File};
     use std::io::Write;
     use std::sync::Arc;
     use std::time::Duration;
     use std::u64;
-    use tokio_compat::runtime::current_thread::Runtime;
 
     #[test]
     fn test_detect_compiler_kind_gcc() {
@@ -1177,10 +1769,16 @@ mod test {
         let creator = new_creator();
         let pool = ThreadPool::sized(1);
         next_command(&creator, Ok(MockChild::new(exit_status(0), "\n\ngcc", "")));
-        let c = detect_compiler(creator, &f.bins[0], f.tempdir.path(), &[], &pool, None)
-            .wait()
-            .unwrap()
-            .0;
+        let c = block_on(detect_compiler(
+            creator,
+            &f.bins[0],
+            f.tempdir.path(),
+            &[],
+            &pool,
+            None,
+        ))
+        .unwrap()
+        .0;
         assert_eq!(CompilerKind::C(CCompilerKind::GCC), c.kind());
     }
 
@@ -1190,10 +1788,16 @@ mod test {
         let creator = new_creator();
         let pool = ThreadPool::sized(1);
         next_command(&creator, Ok(MockChild::new(exit_status(0), "clang\n", "")));
-        let c = detect_compiler(creator, &f.bins[0], f.tempdir.path(), &[], &pool, None)
-            .wait()
-            .unwrap()
-            .0;
+        let c = block_on(detect_compiler(
+            creator,
+            &f.bins[0],
+            f.tempdir.path(),
+            &[],
+            &pool,
+            None,
+        ))
+        .unwrap()
+        .0;
         assert_eq!(CompilerKind::C(CCompilerKind::Clang), c.kind());
     }
 
@@ -1217,10 +1821,16 @@ mod test {
             &creator,
             Ok(MockChild::new(exit_status(0), &stdout, &String::new())),
         );
-        let c = detect_compiler(creator, &f.bins[0], f.tempdir.path(), &[], &pool, None)
-            .wait()
-            .unwrap()
-            .0;
+        let c = block_on(detect_compiler(
+            creator,
+            &f.bins[0],
+            f.tempdir.path(),
+            &[],
+            &pool,
+            None,
+        ))
+        .unwrap()
+        .0;
         assert_eq!(CompilerKind::C(CCompilerKind::MSVC), c.kind());
     }
 
@@ -1230,10 +1840,16 @@ mod test {
         let creator = new_creator();
         let pool = ThreadPool::sized(1);
         next_command(&creator, Ok(MockChild::new(exit_status(0), "nvcc\n", "")));
-        let c = detect_compiler(creator, &f.bins[0], f.tempdir.path(), &[], &pool, None)
-            .wait()
-            .unwrap()
-            .0;
+        let c = block_on(detect_compiler(
+            creator,
+            &f.bins[0],
+            f.tempdir.path(),
+            &[],
+            &pool,
+            None,
+        ))
+        .unwrap()
+        .0;
         assert_eq!(CompilerKind::C(CCompilerKind::NVCC), c.kind());
     }
 
@@ -1267,10 +1883,16 @@ LLVM version: 6.0",
         next_command(&creator, Ok(MockChild::new(exit_status(0), &sysroot, "")));
         next_command(&creator, Ok(MockChild::new(exit_status(0), &sysroot, "")));
         next_command(&creator, Ok(MockChild::new(exit_status(0), &sysroot, "")));
-        let c = detect_compiler(creator, &rustc, f.tempdir.path(), &[], &pool, None)
-            .wait()
-            .unwrap()
-            .0;
+        let c = block_on(detect_compiler(
+            creator,
+            &rustc,
+            f.tempdir.path(),
+            &[],
+            &pool,
+            None,
+        ))
+        .unwrap()
+        .0;
         assert_eq!(CompilerKind::Rust, c.kind());
     }
 
@@ -1280,11 +1902,65 @@ LLVM version: 6.0",
         let creator = new_creator();
         let pool = ThreadPool::sized(1);
         next_command(&creator, Ok(MockChild::new(exit_status(0), "\ndiab\n", "")));
-        let c = detect_compiler(creator, &f.bins[0], f.tempdir.path(), &[], &pool, None)
-            .wait()
+        let c = block_on(detect_compiler(
+            creator,
+            &f.bins[0],
+            f.tempdir.path(),
+            &[],
+            &pool,
+            None,
+        ))
+        .unwrap()
+        .0;
+        assert_eq!(CompilerKind::C(CCompilerKind::Diab), c.kind());
+    }
+
+    #[test]
+    fn test_detect_compiler_kind_ti() {
+        let f = TestFixture::new();
+        let cl2000 = f.mk_bin("cl2000").unwrap();
+        let creator = new_creator();
+        let pool = ThreadPool::sized(1);
+        let c = block_on(detect_compiler(
+            creator.clone(),
+            &cl2000,
+            f.tempdir.path(),
+            &[],
+            &pool,
+            None,
+        ))
+        .unwrap()
+        .0;
+        assert_eq!(CompilerKind::C(CCompilerKind::TI), c.kind());
+        // The compiler is never run for detection.
+        assert_eq!(0, creator.lock().unwrap().children.len());
+    }
+
+    #[test]
+    fn test_detect_compiler_kind_resource() {
+        let f = TestFixture::new();
+        let creator = new_creator();
+        let pool = ThreadPool::sized(1);
+        for (name, kind) in &[
+            ("rc", CCompilerKind::RC),
+            ("windres", CCompilerKind::Windres),
+            ("x86_64-w64-mingw32-windres", CCompilerKind::Windres),
+        ] {
+            let bin = f.mk_bin(name).unwrap();
+            let c = block_on(detect_compiler(
+                creator.clone(),
+                &bin,
+                f.tempdir.path(),
+                &[],
+                &pool,
+                None,
+            ))
             .unwrap()
             .0;
-        assert_eq!(CompilerKind::C(CCompilerKind::Diab), c.kind());
+            assert_eq!(CompilerKind::C(kind.clone()), c.kind());
+        }
+        // The compilers are never run for detection.
+        assert_eq!(0, creator.lock().unwrap().children.len());
     }
 
     #[test]
@@ -1296,15 +1972,14 @@ LLVM version: 6.0",
             &creator,
             Ok(MockChild::new(exit_status(0), "something", "")),
         );
-        assert!(detect_compiler(
+        assert!(block_on(detect_compiler(
             creator,
             "/foo/bar".as_ref(),
             f.tempdir.path(),
             &[],
             &pool,
             None
-        )
-        .wait()
+        ))
         .is_err());
     }
 
@@ -1314,15 +1989,14 @@ LLVM version: 6.0",
         let creator = new_creator();
         let pool = ThreadPool::sized(1);
         next_command(&creator, Ok(MockChild::new(exit_status(1), "", "")));
-        assert!(detect_compiler(
+        assert!(block_on(detect_compiler(
             creator,
             "/foo/bar".as_ref(),
             f.tempdir.path(),
             &[],
             &pool,
             None
-        )
-        .wait()
+        ))
         .is_err());
     }
 
@@ -1339,15 +2013,14 @@ LLVM version: 6.0",
             .map(|version| {
                 let output = format!("clang\n\"{}.0.0\"", version);
                 next_command(&creator, Ok(MockChild::new(exit_status(0), &output, "")));
-                let c = detect_compiler(
+                let c = block_on(detect_compiler(
                     creator.clone(),
                     &f.bins[0],
                     f.tempdir.path(),
                     &[],
                     &pool,
                     None,
-                )
-                .wait()
+                ))
                 .unwrap()
                 .0;
                 next_command(
@@ -1358,16 +2031,138 @@ LLVM version: 6.0",
                     CompilerArguments::Ok(h) => h,
                     o => panic!("Bad result from parse_arguments: {:?}", o),
                 };
-                hasher
-                    .generate_hash_key(&creator, cwd.to_path_buf(), vec![], false, &pool, false)
-                    .wait()
-                    .unwrap()
+                block_on(hasher.generate_hash_key(
+                    &creator,
+                    cwd.to_path_buf(),
+                    vec![],
+                    false,
+                    &pool,
+                    false,
+                    &HashConfig::default(),
+                    &Span::default(),
+                ))
+                .unwrap()
             })
             .collect();
         assert_eq!(results.len(), 2);
         assert_ne!(results[0].key, results[1].key);
     }
 
+    #[test]
+    fn test_cwd_affects_hash_for_path_dependent_outputs() {
+        let f = TestFixture::new();
+        let creator = new_creator();
+        let pool = ThreadPool::sized(1);
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        let c = block_on(get_compiler_info(
+            creator.clone(),
+            &f.bins[0],
+            f.tempdir.path(),
+            &[],
+            &pool,
+            None,
+        ))
+        .unwrap()
+        .0;
+        let hash_in = |arguments: &[OsString], cwd: &Path| {
+            next_command(
+                &creator,
+                Ok(MockChild::new(exit_status(0), "preprocessor output", "")),
+            );
+            let hasher = match c.parse_arguments(arguments, ".".as_ref()) {
+                CompilerArguments::Ok(h) => h,
+                o => panic!("Bad result from parse_arguments: {:?}", o),
+            };
+            block_on(hasher.generate_hash_key(
+                &creator,
+                cwd.to_path_buf(),
+                vec![],
+                false,
+                &pool,
+                false,
+                &HashConfig::default(),
+                &Span::default(),
+            ))
+            .unwrap()
+            .key
+        };
+        let plain = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let coverage = ovec!["--coverage", "-c", "foo.c", "-o", "foo.o"];
+        let a = f.tempdir.path().join("a");
+        let b = f.tempdir.path().join("b");
+        assert_eq!(hash_in(&plain, &a), hash_in(&plain, &b));
+        assert_eq!(hash_in(&coverage, &a), hash_in(&coverage, &a));
+        assert_neq!(hash_in(&coverage, &a), hash_in(&coverage, &b));
+        let split_dwarf = ovec!["-gsplit-dwarf", "-c", "foo.c", "-o", "foo.o"];
+        assert_neq!(hash_in(&split_dwarf, &a), hash_in(&split_dwarf, &b));
+    }
+
+    #[test]
+    fn test_base_dir_shares_hash_across_checkouts() {
+        let f = TestFixture::new();
+        let creator = new_creator();
+        let pool = ThreadPool::sized(1);
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        let c = block_on(get_compiler_info(
+            creator.clone(),
+            &f.bins[0],
+            f.tempdir.path(),
+            &[],
+            &pool,
+            None,
+        ))
+        .unwrap()
+        .0;
+        let hash_in = |checkout: &Path, base_dir: Option<&Path>| {
+            let prefix_map = format!("-fdebug-prefix-map={}=.", checkout.display());
+            let preprocessed = format!("# 1 \"{}/foo.c\"", checkout.display());
+            next_command(
+                &creator,
+                Ok(MockChild::new(exit_status(0), preprocessed, "")),
+            );
+            let arguments = ovec!["-c", "foo.c", "-o", "foo.o", prefix_map];
+            let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
+                CompilerArguments::Ok(h) => h,
+                o => panic!("Bad result from parse_arguments: {:?}", o),
+            };
+            let hash_config = HashConfig {
+                base_dir: base_dir.map(Path::to_owned),
+                ..Default::default()
+            };
+            block_on(hasher.generate_hash_key(
+                &creator,
+                checkout.join("build"),
+                vec![],
+                false,
+                &pool,
+                false,
+                &hash_config,
+                &Span::default(),
+            ))
+            .unwrap()
+            .key
+        };
+        let a = f.tempdir.path().join("a");
+        let b = f.tempdir.path().join("b");
+        assert_neq!(hash_in(&a, None), hash_in(&b, None));
+        assert_eq!(hash_in(&a, Some(&a)), hash_in(&b, Some(&b)));
+    }
+
+    #[test]
+    fn test_compile_command_wrapped() {
+        let cmd = CompileCommand {
+            executable: "/usr/bin/gcc".into(),
+            arguments: ovec!["-c", "foo.c"],
+            env_vars: vec![],
+            cwd: "/src".into(),
+        };
+        let wrapped = cmd.clone().wrapped(Some(Path::new("/usr/bin/distcc")));
+        assert_eq!(wrapped.executable, PathBuf::from("/usr/bin/distcc"));
+        assert_eq!(wrapped.arguments, ovec!["/usr/bin/gcc", "-c", "foo.c"]);
+        assert_eq!(wrapped.cwd, cmd.cwd);
+        assert_eq!(cmd.clone().wrapped(None).arguments, cmd.arguments);
+    }
+
     #[test]
     fn test_get_compiler_info() {
         let creator = new_creator();
@@ -1375,10 +2170,16 @@ LLVM version: 6.0",
         let f = TestFixture::new();
         // Pretend to be GCC.
         next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
-        let c = get_compiler_info(creator, &f.bins[0], f.tempdir.path(), &[], &pool, None)
-            .wait()
-            .unwrap()
-            .0;
+        let c = block_on(get_compiler_info(
+            creator,
+            &f.bins[0],
+            f.tempdir.path(),
+            &[],
+            &pool,
+            None,
+        ))
+        .unwrap()
+        .0;
         // digest of an empty file.
         assert_eq!(CompilerKind::C(CCompilerKind::GCC), c.kind());
     }
@@ -1389,20 +2190,19 @@ LLVM version: 6.0",
         let creator = new_creator();
         let f = TestFixture::new();
         let pool = ThreadPool::sized(1);
-        let mut runtime = Runtime::new().unwrap();
+        let runtime = crate::util::runtime().unwrap();
         let storage = DiskCache::new(&f.tempdir.path().join("cache"), u64::MAX, &pool);
         let storage: Arc<dyn Storage> = Arc::new(storage);
         // Pretend to be GCC.
         next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
-        let c = get_compiler_info(
+        let c = block_on(get_compiler_info(
             creator.clone(),
             &f.bins[0],
             f.tempdir.path(),
             &[],
             &pool,
             None,
-        )
-        .wait()
+        ))
         .unwrap()
         .0;
         // The preprocessor invocation.
@@ -1432,26 +2232,26 @@ LLVM version: 6.0",
             o => panic!("Bad result from parse_arguments: {:?}", o),
         };
         let hasher2 = hasher.clone();
-        let (cached, res) = runtime
-            .block_on(future::lazy(|| {
-                hasher.get_cached_or_compile(
-                    Ok(None),
-                    creator.clone(),
-                    storage.clone(),
-                    arguments.clone(),
-                    cwd.to_path_buf(),
-                    vec![],
-                    CacheControl::Default,
-                    pool.clone(),
-                )
-            }))
+        let (cached, res, _) = runtime
+            .block_on(hasher.get_cached_or_compile(
+                Ok(None),
+                creator.clone(),
+                storage.clone(),
+                arguments.clone(),
+                cwd.to_path_buf(),
+                vec![],
+                CacheControl::Default,
+                Arc::new(HashConfig::default()),
+                pool.clone(),
+                Span::default(),
+            ))
             .unwrap();
         // Ensure that the object file was created.
         assert!(fs::metadata(&obj).map(|m| m.len() > 0).unwrap());
         match cached {
-            CompileResult::CacheMiss(MissType::Normal, DistType::NoDist, _, f) => {
+            CompileResult::CacheMiss(MissType::Normal, DistType::NoDist, _, _, f) => {
                 // wait on cache write future so we don't race with it!
-                f.wait().unwrap();
+                block_on(f).unwrap();
             }
             _ => assert!(false, "Unexpected compile result: {:?}", cached),
         }
@@ -1466,28 +2266,111 @@ LLVM version: 6.0",
             Ok(MockChild::new(exit_status(0), "preprocessor output", "")),
         );
         // There should be no actual compiler invocation.
-        let (cached, res) = runtime
-            .block_on(future::lazy(|| {
-                hasher2.get_cached_or_compile(
-                    Ok(None),
-                    creator,
-                    storage,
-                    arguments,
-                    cwd.to_path_buf(),
-                    vec![],
-                    CacheControl::Default,
-                    pool,
-                )
-            }))
+        let (cached, res, _) = runtime
+            .block_on(hasher2.get_cached_or_compile(
+                Ok(None),
+                creator,
+                storage,
+                arguments,
+                cwd.to_path_buf(),
+                vec![],
+                CacheControl::Default,
+                Arc::new(HashConfig::default()),
+                pool,
+                Span::default(),
+            ))
             .unwrap();
         // Ensure that the object file was created.
         assert!(fs::metadata(&obj).map(|m| m.len() > 0).unwrap());
-        assert_eq!(CompileResult::CacheHit(Duration::new(0, 0)), cached);
+        assert_eq!(
+            CompileResult::CacheHit(Duration::new(0, 0), 0, HitCheck::Unchecked, String::new()),
+            cached
+        );
+        if let CompileResult::CacheHit(_, _, _, ref version) = cached {
+            let schema = cache_schema("c", c::CACHE_VERSION);
+            assert_eq!(*version, format!("{}, format 1", schema));
+        }
         assert_eq!(exit_status(0), res.status);
         assert_eq!(COMPILER_STDOUT, res.stdout.as_slice());
         assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
     }
 
+    #[test]
+    fn test_compiler_get_cached_or_compile_time_macros() {
+        drop(env_logger::try_init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = ThreadPool::sized(1);
+        let runtime = crate::util::runtime().unwrap();
+        let storage = DiskCache::new(&f.tempdir.path().join("cache"), u64::MAX, &pool);
+        let storage: Arc<dyn Storage> = Arc::new(storage);
+        // Pretend to be GCC.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        let c = block_on(get_compiler_info(
+            creator.clone(),
+            &f.bins[0],
+            f.tempdir.path(),
+            &[],
+            &pool,
+            None,
+        ))
+        .unwrap()
+        .0;
+        let cwd = f.tempdir.path();
+        let obj = cwd.join("foo.o");
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
+            CompilerArguments::Ok(h) => h,
+            o => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        // They're cached unless sloppiness is set without them
+        let strict = HashConfig {
+            sloppiness: Some(vec![]),
+            ..Default::default()
+        };
+        let sloppy = HashConfig::default();
+        for hash_config in vec![strict, sloppy] {
+            // The preprocessor invocation, which left the macros unexpanded as
+            // when it only rewrites the includes.
+            next_command(
+                &creator,
+                Ok(MockChild::new(
+                    exit_status(0),
+                    "const char *built = __DATE__;",
+                    "",
+                )),
+            );
+            // The compiler invocation.
+            let o = obj.clone();
+            next_command_calls(&creator, move |_| {
+                fs::write(&o, "file contents")?;
+                Ok(MockChild::new(exit_status(0), "", ""))
+            });
+            let sloppy = hash_config.is_sloppy(Sloppiness::TimeMacros);
+            let (cached, _, _) = runtime
+                .block_on(hasher.clone().get_cached_or_compile(
+                    Ok(None),
+                    creator.clone(),
+                    storage.clone(),
+                    arguments.clone(),
+                    cwd.to_path_buf(),
+                    vec![],
+                    CacheControl::Default,
+                    Arc::new(hash_config),
+                    pool.clone(),
+                    Span::default(),
+                ))
+                .unwrap();
+            match cached {
+                CompileResult::CacheMiss(MissType::Normal, DistType::NoDist, _, _, f) if sloppy => {
+                    block_on(f).unwrap();
+                }
+                CompileResult::NotCacheable(Some("time macros")) if !sloppy => {}
+                _ => panic!("Unexpected compile result: {:?}", cached),
+            }
+        }
+    }
+
     #[test]
     #[cfg(feature = "dist-client")]
     fn test_compiler_get_cached_or_compile_dist() {
@@ -1495,20 +2378,19 @@ LLVM version: 6.0",
         let creator = new_creator();
         let f = TestFixture::new();
         let pool = ThreadPool::sized(1);
-        let mut runtime = Runtime::new().unwrap();
+        let runtime = crate::util::runtime().unwrap();
         let storage = DiskCache::new(&f.tempdir.path().join("cache"), u64::MAX, &pool);
         let storage: Arc<dyn Storage> = Arc::new(storage);
         // Pretend to be GCC.
         next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
-        let c = get_compiler_info(
+        let c = block_on(get_compiler_info(
             creator.clone(),
             &f.bins[0],
             f.tempdir.path(),
             &[],
             &pool,
             None,
-        )
-        .wait()
+        ))
         .unwrap()
         .0;
         // The preprocessor invocation.
@@ -1533,26 +2415,26 @@ LLVM version: 6.0",
             o => panic!("Bad result from parse_arguments: {:?}", o),
         };
         let hasher2 = hasher.clone();
-        let (cached, res) = runtime
-            .block_on(future::lazy(|| {
-                hasher.get_cached_or_compile(
-                    Ok(dist_client.clone()),
-                    creator.clone(),
-                    storage.clone(),
-                    arguments.clone(),
-                    cwd.to_path_buf(),
-                    vec![],
-                    CacheControl::Default,
-                    pool.clone(),
-                )
-            }))
+        let (cached, res, _) = runtime
+            .block_on(hasher.get_cached_or_compile(
+                Ok(dist_client.clone()),
+                creator.clone(),
+                storage.clone(),
+                arguments.clone(),
+                cwd.to_path_buf(),
+                vec![],
+                CacheControl::Default,
+                Arc::new(HashConfig::default()),
+                pool.clone(),
+                Span::default(),
+            ))
             .unwrap();
         // Ensure that the object file was created.
         assert!(fs::metadata(&obj).map(|m| m.len() > 0).unwrap());
         match cached {
-            CompileResult::CacheMiss(MissType::Normal, DistType::Ok(_), _, f) => {
+            CompileResult::CacheMiss(MissType::Normal, DistType::Ok(_), _, _, f) => {
                 // wait on cache write future so we don't race with it!
-                f.wait().unwrap();
+                block_on(f).unwrap();
             }
             _ => assert!(false, "Unexpected compile result: {:?}", cached),
         }
@@ -1567,23 +2449,26 @@ LLVM version: 6.0",
             Ok(MockChild::new(exit_status(0), "preprocessor output", "")),
         );
         // There should be no actual compiler invocation.
-        let (cached, res) = runtime
-            .block_on(future::lazy(|| {
-                hasher2.get_cached_or_compile(
-                    Ok(dist_client.clone()),
-                    creator,
-                    storage,
-                    arguments,
-                    cwd.to_path_buf(),
-                    vec![],
-                    CacheControl::Default,
-                    pool,
-                )
-            }))
+        let (cached, res, _) = runtime
+            .block_on(hasher2.get_cached_or_compile(
+                Ok(dist_client.clone()),
+                creator,
+                storage,
+                arguments,
+                cwd.to_path_buf(),
+                vec![],
+                CacheControl::Default,
+                Arc::new(HashConfig::default()),
+                pool,
+                Span::default(),
+            ))
             .unwrap();
         // Ensure that the object file was created.
         assert!(fs::metadata(&obj).map(|m| m.len() > 0).unwrap());
-        assert_eq!(CompileResult::CacheHit(Duration::new(0, 0)), cached);
+        assert_eq!(
+            CompileResult::CacheHit(Duration::new(0, 0), 0, HitCheck::Unchecked, String::new()),
+            cached
+        );
         assert_eq!(exit_status(0), res.status);
         assert_eq!(COMPILER_STDOUT, res.stdout.as_slice());
         assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
@@ -1597,20 +2482,19 @@ LLVM version: 6.0",
         let creator = new_creator();
         let f = TestFixture::new();
         let pool = ThreadPool::sized(1);
-        let mut runtime = Runtime::new().unwrap();
+        let runtime = crate::util::runtime().unwrap();
         let storage = MockStorage::new();
         let storage: Arc<MockStorage> = Arc::new(storage);
         // Pretend to be GCC.
         next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
-        let c = get_compiler_info(
+        let c = block_on(get_compiler_info(
             creator.clone(),
             &f.bins[0],
             f.tempdir.path(),
             &[],
             &pool,
             None,
-        )
-        .wait()
+        ))
         .unwrap()
         .0;
         // The preprocessor invocation.
@@ -1640,27 +2524,27 @@ LLVM version: 6.0",
             o => panic!("Bad result from parse_arguments: {:?}", o),
         };
         // The cache will return an error.
-        storage.next_get(f_err(anyhow!("Some Error")));
-        let (cached, res) = runtime
-            .block_on(future::lazy(|| {
-                hasher.get_cached_or_compile(
-                    Ok(None),
-                    creator,
-                    storage,
-                    arguments.clone(),
-                    cwd.to_path_buf(),
-                    vec![],
-                    CacheControl::Default,
-                    pool,
-                )
-            }))
+        storage.next_get(Err(anyhow!("Some Error")));
+        let (cached, res, _) = runtime
+            .block_on(hasher.get_cached_or_compile(
+                Ok(None),
+                creator,
+                storage,
+                arguments.clone(),
+                cwd.to_path_buf(),
+                vec![],
+                CacheControl::Default,
+                Arc::new(HashConfig::default()),
+                pool,
+                Span::default(),
+            ))
             .unwrap();
         // Ensure that the object file was created.
         assert!(fs::metadata(&obj).map(|m| m.len() > 0).unwrap());
         match cached {
-            CompileResult::CacheMiss(MissType::CacheReadError, DistType::NoDist, _, f) => {
+            CompileResult::CacheMiss(MissType::CacheReadError, DistType::NoDist, _, _, f) => {
                 // wait on cache write future so we don't race with it!
-                f.wait().unwrap();
+                block_on(f).unwrap();
             }
             _ => assert!(false, "Unexpected compile result: {:?}", cached),
         }
@@ -1676,20 +2560,19 @@ LLVM version: 6.0",
         let creator = new_creator();
         let f = TestFixture::new();
         let pool = ThreadPool::sized(1);
-        let mut runtime = Runtime::new().unwrap();
+        let runtime = crate::util::runtime().unwrap();
         let storage = DiskCache::new(&f.tempdir.path().join("cache"), u64::MAX, &pool);
         let storage: Arc<dyn Storage> = Arc::new(storage);
         // Pretend to be GCC.
         next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
-        let c = get_compiler_info(
+        let c = block_on(get_compiler_info(
             creator.clone(),
             &f.bins[0],
             f.tempdir.path(),
             &[],
             &pool,
             None,
-        )
-        .wait()
+        ))
         .unwrap()
         .0;
         const COMPILER_STDOUT: &[u8] = b"compiler stdout";
@@ -1723,26 +2606,26 @@ LLVM version: 6.0",
             o => panic!("Bad result from parse_arguments: {:?}", o),
         };
         let hasher2 = hasher.clone();
-        let (cached, res) = runtime
-            .block_on(future::lazy(|| {
-                hasher.get_cached_or_compile(
-                    Ok(None),
-                    creator.clone(),
-                    storage.clone(),
-                    arguments.clone(),
-                    cwd.to_path_buf(),
-                    vec![],
-                    CacheControl::Default,
-                    pool.clone(),
-                )
-            }))
+        let (cached, res, _) = runtime
+            .block_on(hasher.get_cached_or_compile(
+                Ok(None),
+                creator.clone(),
+                storage.clone(),
+                arguments.clone(),
+                cwd.to_path_buf(),
+                vec![],
+                CacheControl::Default,
+                Arc::new(HashConfig::default()),
+                pool.clone(),
+                Span::default(),
+            ))
             .unwrap();
         // Ensure that the object file was created.
         assert!(fs::metadata(&obj).map(|m| m.len() > 0).unwrap());
         match cached {
-            CompileResult::CacheMiss(MissType::Normal, DistType::NoDist, _, f) => {
+            CompileResult::CacheMiss(MissType::Normal, DistType::NoDist, _, _, f) => {
                 // wait on cache write future so we don't race with it!
-                f.wait().unwrap();
+                block_on(f).unwrap();
             }
             _ => assert!(false, "Unexpected compile result: {:?}", cached),
         }
@@ -1751,8 +2634,8 @@ LLVM version: 6.0",
         assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
         // Now compile again, but force recaching.
         fs::remove_file(&obj).unwrap();
-        let (cached, res) = hasher2
-            .get_cached_or_compile(
+        let (cached, res, _) = runtime
+            .block_on(hasher2.get_cached_or_compile(
                 Ok(None),
                 creator,
                 storage,
@@ -1760,16 +2643,17 @@ LLVM version: 6.0",
                 cwd.to_path_buf(),
                 vec![],
                 CacheControl::ForceRecache,
+                Arc::new(HashConfig::default()),
                 pool,
-            )
-            .wait()
+                Span::default(),
+            ))
             .unwrap();
         // Ensure that the object file was created.
         assert!(fs::metadata(&obj).map(|m| m.len() > 0).unwrap());
         match cached {
-            CompileResult::CacheMiss(MissType::ForcedRecache, DistType::NoDist, _, f) => {
+            CompileResult::CacheMiss(MissType::ForcedRecache, DistType::NoDist, _, _, f) => {
                 // wait on cache write future so we don't race with it!
-                f.wait().unwrap();
+                block_on(f).unwrap();
             }
             _ => assert!(false, "Unexpected compile result: {:?}", cached),
         }
@@ -1784,7 +2668,7 @@ LLVM version: 6.0",
         let creator = new_creator();
         let f = TestFixture::new();
         let pool = ThreadPool::sized(1);
-        let mut runtime = Runtime::new().unwrap();
+        let runtime = crate::util::runtime().unwrap();
         let storage = DiskCache::new(&f.tempdir.path().join("cache"), u64::MAX, &pool);
         let storage: Arc<dyn Storage> = Arc::new(storage);
         // Pretend to be GCC.  Also inject a fake object file that the subsequent
@@ -1796,15 +2680,14 @@ LLVM version: 6.0",
             f.write_all(b"file contents")?;
             Ok(MockChild::new(exit_status(0), "gcc", ""))
         });
-        let c = get_compiler_info(
+        let c = block_on(get_compiler_info(
             creator.clone(),
             &f.bins[0],
             f.tempdir.path(),
             &[],
             &pool,
             None,
-        )
-        .wait()
+        ))
         .unwrap()
         .0;
         // We should now have a fake object file.
@@ -1825,19 +2708,19 @@ LLVM version: 6.0",
             CompilerArguments::Ok(h) => h,
             o => panic!("Bad result from parse_arguments: {:?}", o),
         };
-        let (cached, res) = runtime
-            .block_on(future::lazy(|| {
-                hasher.get_cached_or_compile(
-                    Ok(None),
-                    creator,
-                    storage,
-                    arguments,
-                    cwd.to_path_buf(),
-                    vec![],
-                    CacheControl::Default,
-                    pool,
-                )
-            }))
+        let (cached, res, _) = runtime
+            .block_on(hasher.get_cached_or_compile(
+                Ok(None),
+                creator,
+                storage,
+                arguments,
+                cwd.to_path_buf(),
+                vec![],
+                CacheControl::Default,
+                Arc::new(HashConfig::default()),
+                pool,
+                Span::default(),
+            ))
             .unwrap();
         assert_eq!(cached, CompileResult::Error);
         assert_eq!(exit_status(1), res.status);
@@ -1855,6 +2738,7 @@ LLVM version: 6.0",
         let creator = new_creator();
         let f = TestFixture::new();
         let pool = ThreadPool::sized(1);
+        let runtime = crate::util::runtime().unwrap();
         let dist_clients = vec![
             test_dist::ErrorPutToolchainClient::new(),
             test_dist::ErrorAllocJobClient::new(),
@@ -1865,15 +2749,14 @@ LLVM version: 6.0",
         let storage: Arc<dyn Storage> = Arc::new(storage);
         // Pretend to be GCC.
         next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
-        let c = get_compiler_info(
+        let c = block_on(get_compiler_info(
             creator.clone(),
             &f.bins[0],
             f.tempdir.path(),
             &[],
             &pool,
             None,
-        )
-        .wait()
+        ))
         .unwrap()
         .0;
         const COMPILER_STDOUT: &[u8] = b"compiler stdout";
@@ -1906,14 +2789,20 @@ LLVM version: 6.0",
             CompilerArguments::Ok(h) => h,
             o => panic!("Bad result from parse_arguments: {:?}", o),
         };
+        let causes = [
+            DistFailureCause::Toolchain,
+            DistFailureCause::Scheduler,
+            DistFailureCause::Server,
+            DistFailureCause::Server,
+        ];
         // All these dist clients will fail, but should still result in successful compiles
-        for dist_client in dist_clients {
+        for (dist_client, &cause) in dist_clients.into_iter().zip(&causes) {
             if obj.is_file() {
                 fs::remove_file(&obj).unwrap();
             }
             let hasher = hasher.clone();
-            let (cached, res) = hasher
-                .get_cached_or_compile(
+            let (cached, res, _) = runtime
+                .block_on(hasher.get_cached_or_compile(
                     Ok(Some(dist_client.clone())),
                     creator.clone(),
                     storage.clone(),
@@ -1921,16 +2810,26 @@ LLVM version: 6.0",
                     cwd.to_path_buf(),
                     vec![],
                     CacheControl::ForceRecache,
+                    Arc::new(HashConfig::default()),
                     pool.clone(),
-                )
-                .wait()
+                    Span::default(),
+                ))
                 .unwrap();
             // Ensure that the object file was created.
             assert!(fs::metadata(&obj).map(|m| m.len() > 0).unwrap());
             match cached {
-                CompileResult::CacheMiss(MissType::ForcedRecache, DistType::Error, _, f) => {
+                CompileResult::CacheMiss(
+                    MissType::ForcedRecache,
+                    DistType::Error(DistFailure {
+                        cause: c,
+                        warning: None,
+                    }),
+                    _,
+                    _,
+                    f,
+                ) if c == cause => {
                     // wait on cache write future so we don't race with it!
-                    f.wait().unwrap();
+                    block_on(f).unwrap();
                 }
                 _ => assert!(false, "Unexpected compile result: {:?}", cached),
             }
@@ -1939,17 +2838,104 @@ LLVM version: 6.0",
             assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
         }
     }
+
+    #[test]
+    #[cfg(feature = "dist-client")]
+    fn test_compiler_get_cached_or_compile_dist_fallback_policy() {
+        use crate::config::DistFallback;
+
+        drop(env_logger::try_init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = ThreadPool::sized(1);
+        let runtime = crate::util::runtime().unwrap();
+        let storage = DiskCache::new(&f.tempdir.path().join("cache"), u64::MAX, &pool);
+        let storage: Arc<dyn Storage> = Arc::new(storage);
+        // Pretend to be GCC.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        let c = block_on(get_compiler_info(
+            creator.clone(),
+            &f.bins[0],
+            f.tempdir.path(),
+            &[],
+            &pool,
+            None,
+        ))
+        .unwrap()
+        .0;
+        const COMPILER_STDERR: &[u8] = b"compiler stderr";
+        let obj = f.tempdir.path().join("foo.o");
+        // The preprocessor and compiler invocations for the fallback, and the
+        // preprocessor invocation for the failing compile.
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "preprocessor output", "")),
+        );
+        next_command_calls(&creator, move |_| {
+            let mut f = File::create(&obj)?;
+            f.write_all(b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", COMPILER_STDERR))
+        });
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "preprocessor output", "")),
+        );
+        let cwd = f.tempdir.path();
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
+            CompilerArguments::Ok(h) => h,
+            o => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        let compile = |fallback| {
+            hasher.clone().get_cached_or_compile(
+                Ok(Some(test_dist::ErrorRunJobClient::with_fallback(fallback))),
+                creator.clone(),
+                storage.clone(),
+                arguments.clone(),
+                cwd.to_path_buf(),
+                vec![],
+                CacheControl::ForceRecache,
+                Arc::new(HashConfig::default()),
+                pool.clone(),
+                Span::default(),
+            )
+        };
+
+        let (cached, res, _) = runtime
+            .block_on(compile(DistFallback::FallbackWithWarning))
+            .unwrap();
+        let stderr = String::from_utf8(res.stderr).unwrap();
+        assert!(stderr.starts_with("sccache: warning: Distributed compilation failed"));
+        assert!(stderr.ends_with("compiler stderr"));
+        match cached {
+            CompileResult::CacheMiss(_, DistType::Error(failure), _, _, f) => {
+                assert_eq!(failure.cause, DistFailureCause::Server);
+                block_on(f).unwrap();
+            }
+            _ => panic!("Unexpected compile result: {:?}", cached),
+        }
+
+        let err = runtime
+            .block_on(compile(DistFallback::FailFast))
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<DistFailureCause>(),
+            Some(&DistFailureCause::Server)
+        );
+    }
 }
 
 #[cfg(test)]
 #[cfg(feature = "dist-client")]
 mod test_dist {
+    use crate::config::DistFallback;
     use crate::dist::pkg;
     use crate::dist::{
         self, AllocJobResult, CompileCommand, JobAlloc, JobComplete, JobId, OutputData,
-        PathTransformer, ProcessOutput, RunJobResult, SchedulerStatusResult, ServerId,
-        SubmitToolchainResult, Toolchain,
+        PathTransformer, ProcessOutput, RunJobResult, SchedulerDetailsResult,
+        SchedulerStatusResult, ServerId, SubmitToolchainResult, Toolchain,
     };
+    use async_trait::async_trait;
     use std::cell::Cell;
     use std::path::{Path, PathBuf};
     use std::sync::Arc;
@@ -1963,39 +2949,53 @@ mod test_dist {
             Arc::new(ErrorPutToolchainClient)
         }
     }
+    #[async_trait(?Send)]
     impl dist::Client for ErrorPutToolchainClient {
-        fn do_alloc_job(&self, _: Toolchain) -> SFuture<AllocJobResult> {
+        async fn do_alloc_job(&self, _: Toolchain) -> Result<AllocJobResult> {
+            unreachable!()
+        }
+        async fn do_get_status(&self) -> Result<SchedulerStatusResult> {
             unreachable!()
         }
-        fn do_get_status(&self) -> SFuture<SchedulerStatusResult> {
+        async fn do_get_details(&self) -> Result<SchedulerDetailsResult> {
             unreachable!()
         }
-        fn do_submit_toolchain(&self, _: JobAlloc, _: Toolchain) -> SFuture<SubmitToolchainResult> {
+        async fn do_submit_toolchain(
+            &self,
+            _: JobAlloc,
+            _: Toolchain,
+        ) -> Result<SubmitToolchainResult> {
             unreachable!()
         }
-        fn do_run_job(
+        async fn do_run_job(
             &self,
             _: JobAlloc,
             _: CompileCommand,
             _: Vec<String>,
             _: Box<dyn pkg::InputsPackager>,
-        ) -> SFuture<(RunJobResult, PathTransformer)> {
+        ) -> Result<(RunJobResult, PathTransformer)> {
             unreachable!()
         }
-        fn put_toolchain(
+        async fn put_toolchain(
             &self,
             _: &Path,
             _: &str,
             _: Box<dyn pkg::ToolchainPackager>,
-        ) -> SFuture<(Toolchain, Option<(String, PathBuf)>)> {
-            f_err(anyhow!("put toolchain failure"))
+        ) -> Result<(Toolchain, Option<(String, PathBuf)>)> {
+            Err(anyhow!("put toolchain failure"))
         }
         fn rewrite_includes_only(&self) -> bool {
             false
         }
+        fn fallback(&self) -> DistFallback {
+            DistFallback::AlwaysFallback
+        }
         fn get_custom_toolchain(&self, _exe: &PathBuf) -> Option<PathBuf> {
             None
         }
+        fn clear_toolchain_cache(&self) -> Result<u64> {
+            unreachable!()
+        }
     }
 
     pub struct ErrorAllocJobClient {
@@ -2011,40 +3011,54 @@ mod test_dist {
             })
         }
     }
+    #[async_trait(?Send)]
     impl dist::Client for ErrorAllocJobClient {
-        fn do_alloc_job(&self, tc: Toolchain) -> SFuture<AllocJobResult> {
+        async fn do_alloc_job(&self, tc: Toolchain) -> Result<AllocJobResult> {
             assert_eq!(self.tc, tc);
-            f_err(anyhow!("alloc job failure"))
+            Err(anyhow!("alloc job failure"))
+        }
+        async fn do_get_status(&self) -> Result<SchedulerStatusResult> {
+            unreachable!()
         }
-        fn do_get_status(&self) -> SFuture<SchedulerStatusResult> {
+        async fn do_get_details(&self) -> Result<SchedulerDetailsResult> {
             unreachable!()
         }
-        fn do_submit_toolchain(&self, _: JobAlloc, _: Toolchain) -> SFuture<SubmitToolchainResult> {
+        async fn do_submit_toolchain(
+            &self,
+            _: JobAlloc,
+            _: Toolchain,
+        ) -> Result<SubmitToolchainResult> {
             unreachable!()
         }
-        fn do_run_job(
+        async fn do_run_job(
             &self,
             _: JobAlloc,
             _: CompileCommand,
             _: Vec<String>,
             _: Box<dyn pkg::InputsPackager>,
-        ) -> SFuture<(RunJobResult, PathTransformer)> {
+        ) -> Result<(RunJobResult, PathTransformer)> {
             unreachable!()
         }
-        fn put_toolchain(
+        async fn put_toolchain(
             &self,
             _: &Path,
             _: &str,
             _: Box<dyn pkg::ToolchainPackager>,
-        ) -> SFuture<(Toolchain, Option<(String, PathBuf)>)> {
-            f_ok((self.tc.clone(), None))
+        ) -> Result<(Toolchain, Option<(String, PathBuf)>)> {
+            Ok((self.tc.clone(), None))
         }
         fn rewrite_includes_only(&self) -> bool {
             false
         }
+        fn fallback(&self) -> DistFallback {
+            DistFallback::AlwaysFallback
+        }
         fn get_custom_toolchain(&self, _exe: &PathBuf) -> Option<PathBuf> {
             None
         }
+        fn clear_toolchain_cache(&self) -> Result<u64> {
+            unreachable!()
+        }
     }
 
     pub struct ErrorSubmitToolchainClient {
@@ -2062,11 +3076,12 @@ mod test_dist {
             })
         }
     }
+    #[async_trait(?Send)]
     impl dist::Client for ErrorSubmitToolchainClient {
-        fn do_alloc_job(&self, tc: Toolchain) -> SFuture<AllocJobResult> {
+        async fn do_alloc_job(&self, tc: Toolchain) -> Result<AllocJobResult> {
             assert!(!self.has_started.replace(true));
             assert_eq!(self.tc, tc);
-            f_ok(AllocJobResult::Success {
+            Ok(AllocJobResult::Success {
                 job_alloc: JobAlloc {
                     auth: "abcd".to_owned(),
                     job_id: JobId(0),
@@ -2075,63 +3090,78 @@ mod test_dist {
                 need_toolchain: true,
             })
         }
-        fn do_get_status(&self) -> SFuture<SchedulerStatusResult> {
+        async fn do_get_status(&self) -> Result<SchedulerStatusResult> {
             unreachable!()
         }
-        fn do_submit_toolchain(
+        async fn do_get_details(&self) -> Result<SchedulerDetailsResult> {
+            unreachable!()
+        }
+        async fn do_submit_toolchain(
             &self,
             job_alloc: JobAlloc,
             tc: Toolchain,
-        ) -> SFuture<SubmitToolchainResult> {
+        ) -> Result<SubmitToolchainResult> {
             assert_eq!(job_alloc.job_id, JobId(0));
             assert_eq!(self.tc, tc);
-            f_err(anyhow!("submit toolchain failure"))
+            Err(anyhow!("submit toolchain failure"))
         }
-        fn do_run_job(
+        async fn do_run_job(
             &self,
             _: JobAlloc,
             _: CompileCommand,
             _: Vec<String>,
             _: Box<dyn pkg::InputsPackager>,
-        ) -> SFuture<(RunJobResult, PathTransformer)> {
+        ) -> Result<(RunJobResult, PathTransformer)> {
             unreachable!()
         }
-        fn put_toolchain(
+        async fn put_toolchain(
             &self,
             _: &Path,
             _: &str,
             _: Box<dyn pkg::ToolchainPackager>,
-        ) -> SFuture<(Toolchain, Option<(String, PathBuf)>)> {
-            f_ok((self.tc.clone(), None))
+        ) -> Result<(Toolchain, Option<(String, PathBuf)>)> {
+            Ok((self.tc.clone(), None))
         }
         fn rewrite_includes_only(&self) -> bool {
             false
         }
+        fn fallback(&self) -> DistFallback {
+            DistFallback::AlwaysFallback
+        }
         fn get_custom_toolchain(&self, _exe: &PathBuf) -> Option<PathBuf> {
             None
         }
+        fn clear_toolchain_cache(&self) -> Result<u64> {
+            unreachable!()
+        }
     }
 
     pub struct ErrorRunJobClient {
         has_started: Cell<bool>,
         tc: Toolchain,
+        fallback: DistFallback,
     }
     impl ErrorRunJobClient {
         #[allow(clippy::new_ret_no_self)]
         pub fn new() -> Arc<dyn dist::Client> {
+            Self::with_fallback(DistFallback::AlwaysFallback)
+        }
+        pub fn with_fallback(fallback: DistFallback) -> Arc<dyn dist::Client> {
             Arc::new(Self {
                 has_started: Cell::new(false),
                 tc: Toolchain {
                     archive_id: "somearchiveid".to_owned(),
                 },
+                fallback,
             })
         }
     }
+    #[async_trait(?Send)]
     impl dist::Client for ErrorRunJobClient {
-        fn do_alloc_job(&self, tc: Toolchain) -> SFuture<AllocJobResult> {
+        async fn do_alloc_job(&self, tc: Toolchain) -> Result<AllocJobResult> {
             assert!(!self.has_started.replace(true));
             assert_eq!(self.tc, tc);
-            f_ok(AllocJobResult::Success {
+            Ok(AllocJobResult::Success {
                 job_alloc: JobAlloc {
                     auth: "abcd".to_owned(),
                     job_id: JobId(0),
@@ -2140,36 +3170,39 @@ mod test_dist {
                 need_toolchain: true,
             })
         }
-        fn do_get_status(&self) -> SFuture<SchedulerStatusResult> {
+        async fn do_get_status(&self) -> Result<SchedulerStatusResult> {
+            unreachable!()
+        }
+        async fn do_get_details(&self) -> Result<SchedulerDetailsResult> {
             unreachable!()
         }
-        fn do_submit_toolchain(
+        async fn do_submit_toolchain(
             &self,
             job_alloc: JobAlloc,
             tc: Toolchain,
-        ) -> SFuture<SubmitToolchainResult> {
+        ) -> Result<SubmitToolchainResult> {
             assert_eq!(job_alloc.job_id, JobId(0));
             assert_eq!(self.tc, tc);
-            f_ok(SubmitToolchainResult::Success)
+            Ok(SubmitToolchainResult::Success)
         }
-        fn do_run_job(
+        async fn do_run_job(
             &self,
             job_alloc: JobAlloc,
             command: CompileCommand,
             _: Vec<String>,
             _: Box<dyn pkg::InputsPackager>,
-        ) -> SFuture<(RunJobResult, PathTransformer)> {
+        ) -> Result<(RunJobResult, PathTransformer)> {
             assert_eq!(job_alloc.job_id, JobId(0));
             assert_eq!(command.executable, "/overridden/compiler");
-            f_err(anyhow!("run job failure"))
+            Err(anyhow!("run job failure"))
         }
-        fn put_toolchain(
+        async fn put_toolchain(
             &self,
             _: &Path,
             _: &str,
             _: Box<dyn pkg::ToolchainPackager>,
-        ) -> SFuture<(Toolchain, Option<(String, PathBuf)>)> {
-            f_ok((
+        ) -> Result<(Toolchain, Option<(String, PathBuf)>)> {
+            Ok((
                 self.tc.clone(),
                 Some((
                     "/overridden/compiler".to_owned(),
@@ -2180,9 +3213,15 @@ mod test_dist {
         fn rewrite_includes_only(&self) -> bool {
             false
         }
+        fn fallback(&self) -> DistFallback {
+            self.fallback
+        }
         fn get_custom_toolchain(&self, _exe: &PathBuf) -> Option<PathBuf> {
             None
         }
+        fn clear_toolchain_cache(&self) -> Result<u64> {
+            unreachable!()
+        }
     }
 
     pub struct OneshotClient {
@@ -2204,12 +3243,13 @@ mod test_dist {
         }
     }
 
+    #[async_trait(?Send)]
     impl dist::Client for OneshotClient {
-        fn do_alloc_job(&self, tc: Toolchain) -> SFuture<AllocJobResult> {
+        async fn do_alloc_job(&self, tc: Toolchain) -> Result<AllocJobResult> {
             assert!(!self.has_started.replace(true));
             assert_eq!(self.tc, tc);
 
-            f_ok(AllocJobResult::Success {
+            Ok(AllocJobResult::Success {
                 job_alloc: JobAlloc {
                     auth: "abcd".to_owned(),
                     job_id: JobId(0),
@@ -2218,26 +3258,29 @@ mod test_dist {
                 need_toolchain: true,
             })
         }
-        fn do_get_status(&self) -> SFuture<SchedulerStatusResult> {
+        async fn do_get_status(&self) -> Result<SchedulerStatusResult> {
+            unreachable!()
+        }
+        async fn do_get_details(&self) -> Result<SchedulerDetailsResult> {
             unreachable!()
         }
-        fn do_submit_toolchain(
+        async fn do_submit_toolchain(
             &self,
             job_alloc: JobAlloc,
             tc: Toolchain,
-        ) -> SFuture<SubmitToolchainResult> {
+        ) -> Result<SubmitToolchainResult> {
             assert_eq!(job_alloc.job_id, JobId(0));
             assert_eq!(self.tc, tc);
 
-            f_ok(SubmitToolchainResult::Success)
+            Ok(SubmitToolchainResult::Success)
         }
-        fn do_run_job(
+        async fn do_run_job(
             &self,
             job_alloc: JobAlloc,
             command: CompileCommand,
             outputs: Vec<String>,
             inputs_packager: Box<dyn pkg::InputsPackager>,
-        ) -> SFuture<(RunJobResult, PathTransformer)> {
+        ) -> Result<(RunJobResult, PathTransformer)> {
             assert_eq!(job_alloc.job_id, JobId(0));
             assert_eq!(command.executable, "/overridden/compiler");
 
@@ -2247,7 +3290,9 @@ mod test_dist {
                 .into_iter()
                 .map(|name| {
                     let data = format!("some data in {}", name);
-                    let data = OutputData::try_from_reader(data.as_bytes()).unwrap();
+                    let data =
+                        OutputData::try_from_reader(data.as_bytes(), dist::Compression::Zstd(0))
+                            .unwrap();
                     (name, data)
                 })
                 .collect();
@@ -2255,15 +3300,15 @@ mod test_dist {
                 output: self.output.clone(),
                 outputs,
             });
-            f_ok((result, path_transformer))
+            Ok((result, path_transformer))
         }
-        fn put_toolchain(
+        async fn put_toolchain(
             &self,
             _: &Path,
             _: &str,
             _: Box<dyn pkg::ToolchainPackager>,
-        ) -> SFuture<(Toolchain, Option<(String, PathBuf)>)> {
-            f_ok((
+        ) -> Result<(Toolchain, Option<(String, PathBuf)>)> {
+            Ok((
                 self.tc.clone(),
                 Some((
                     "/overridden/compiler".to_owned(),
@@ -2274,8 +3319,14 @@ mod test_dist {
         fn rewrite_includes_only(&self) -> bool {
             false
         }
+        fn fallback(&self) -> DistFallback {
+            DistFallback::AlwaysFallback
+        }
         fn get_custom_toolchain(&self, _exe: &PathBuf) -> Option<PathBuf> {
             None
         }
+        fn clear_toolchain_cache(&self) -> Result<u64> {
+            unreachable!()
+        }
     }
 }
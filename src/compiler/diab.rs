@@ -23,6 +23,7 @@ use crate::dist;
 use crate::errors::*;
 use crate::mock_command::{CommandCreatorSync, RunCommand};
 use crate::util::{run_input_output, OsStrExt};
+use async_trait::async_trait;
 use log::Level::Trace;
 use std::collections::HashMap;
 use std::ffi::OsString;
@@ -34,6 +35,7 @@ use std::process;
 #[derive(Clone, Debug)]
 pub struct Diab;
 
+#[async_trait(?Send)]
 impl CCompilerImpl for Diab {
     fn kind(&self) -> CCompilerKind {
         CCompilerKind::Diab
@@ -49,7 +51,7 @@ impl CCompilerImpl for Diab {
         parse_arguments(arguments, cwd, &ARGS[..])
     }
 
-    fn preprocess<T>(
+    async fn preprocess<T>(
         &self,
         creator: &T,
         executable: &Path,
@@ -58,11 +60,11 @@ impl CCompilerImpl for Diab {
         env_vars: &[(OsString, OsString)],
         may_dist: bool,
         _rewrite_includes_only: bool,
-    ) -> SFuture<process::Output>
+    ) -> Result<process::Output>
     where
         T: CommandCreatorSync,
     {
-        preprocess(creator, executable, parsed_args, cwd, env_vars, may_dist)
+        preprocess(creator, executable, parsed_args, cwd, env_vars, may_dist).await
     }
 
     fn generate_compile_commands(
@@ -256,8 +258,8 @@ where
         None => cannot_cache!("no input file"),
     };
     let language = match Language::from_file_name(Path::new(&input)) {
-        Some(l) => l,
-        None => cannot_cache!("unknown source language"),
+        Some(l) if !l.is_assembler() => l,
+        _ => cannot_cache!("unknown source language"),
     };
 
     let output = output_arg
@@ -281,17 +283,20 @@ where
         profile_generate: false,
         // FIXME: Implement me.
         color_mode: ColorMode::Auto,
+        arch_args: vec![],
+        compilation_db_fragment: None,
+        index_args: vec![],
     })
 }
 
-pub fn preprocess<T>(
+pub async fn preprocess<T>(
     creator: &T,
     executable: &Path,
     parsed_args: &ParsedArguments,
     cwd: &Path,
     env_vars: &[(OsString, OsString)],
     _may_dist: bool,
-) -> SFuture<process::Output>
+) -> Result<process::Output>
 where
     T: CommandCreatorSync,
 {
@@ -308,7 +313,7 @@ where
     if log_enabled!(Trace) {
         trace!("preprocess: {:?}", cmd);
     }
-    Box::new(run_input_output(cmd, None))
+    run_input_output(cmd, None).await
 }
 
 pub fn generate_compile_commands(
@@ -422,7 +427,7 @@ mod test {
     use crate::compiler::*;
     use crate::mock_command::*;
     use crate::test::utils::*;
-    use futures::Future;
+    use futures::executor::block_on;
     use std::fs::File;
     use std::io::Write;
 
@@ -680,6 +685,9 @@ mod test {
             msvc_show_includes: false,
             profile_generate: false,
             color_mode: ColorMode::Auto,
+            arch_args: vec![],
+            compilation_db_fragment: None,
+            index_args: vec![],
         };
         let compiler = &f.bins[0];
         // Compiler invocation.
@@ -693,7 +701,7 @@ mod test {
             &[],
         )
         .unwrap();
-        let _ = command.execute(&creator).wait();
+        let _ = block_on(command.execute(&creator));
         assert_eq!(Cacheable::Yes, cacheable);
         // Ensure that we ran all processes.
         assert_eq!(0, creator.lock().unwrap().children.len());
@@ -0,0 +1,462 @@
+// Copyright 2017 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::util::{fmt_duration_as_secs, Digest, SpawnExt};
+use filetime::FileTime;
+use futures::executor::ThreadPool;
+use futures::future::{self, BoxFuture};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::errors::*;
+
+lazy_static! {
+    static ref COMPILER_DIGEST_CACHE: Mutex<Option<Arc<CompilerDigestCache>>> = Mutex::new(None);
+    static ref INPUT_DIGEST_CACHE: Mutex<Option<Arc<InputDigestCache>>> = Mutex::new(None);
+}
+
+/// Files modified this recently aren't trusted to keep their digest while
+/// their metadata stays the same, as on filesystems with coarse
+/// timestamps they could still change without their modification time
+/// changing.
+const RACY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Digests of compiler binaries, keyed by their path, size and modification
+/// time, and kept in a file so that they survive server restarts. Hashing a
+/// compiler on a network-mounted toolchain can take a long time.
+#[derive(Debug)]
+pub struct CompilerDigestCache {
+    path: PathBuf,
+    digests: Mutex<HashMap<String, String>>,
+}
+
+impl CompilerDigestCache {
+    /// Load the digests stored at `path`. A missing or unreadable file just
+    /// starts an empty cache.
+    pub fn load(path: PathBuf) -> CompilerDigestCache {
+        CompilerDigestCache {
+            digests: Mutex::new(load_digests(&path, "compiler")),
+            path,
+        }
+    }
+
+    /// Return the digest of the contents of `path`, only hashing it on a
+    /// background thread in `pool` if it isn't known for its current size and
+    /// modification time.
+    pub fn digest(
+        self: &Arc<Self>,
+        path: &Path,
+        pool: &ThreadPool,
+    ) -> BoxFuture<'static, Result<String>> {
+        let me = self.clone();
+        let path = path.to_owned();
+        pool.spawn_fn(move || -> Result<_> {
+            let key = fs::metadata(&path)
+                .map(|metadata| cache_key(&path, &metadata))
+                .with_context(|| format!("Failed to open file for hashing: {:?}", path))?;
+            if let Some(digest) = me.digests.lock().unwrap().get(&key) {
+                trace!("Reusing stored digest for {:?}", path);
+                return Ok(digest.clone());
+            }
+            let reader = File::open(&path)
+                .with_context(|| format!("Failed to open file for hashing: {:?}", path))?;
+            let digest = Digest::reader_sync(reader)?;
+            if let Err(e) = me.record(key, digest.clone()) {
+                warn!("Failed to store compiler digest: {:?}", e);
+            }
+            Ok(digest)
+        })
+    }
+
+    fn record(&self, key: String, digest: String) -> Result<()> {
+        let mut digests = self.digests.lock().unwrap();
+        digests.insert(key, digest);
+        save_digests(&self.path, &digests)
+    }
+}
+
+/// How many input files' digests are kept, the least recently used being
+/// dropped past that.
+const MAX_INPUT_DIGESTS: usize = 100_000;
+
+/// The digest of an input file, as long as its metadata doesn't change.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct InputDigest {
+    /// The inode, size and modification time of the file when hashed.
+    key: String,
+    digest: String,
+    /// When the digest was last used, as a count of uses of the cache.
+    used: u64,
+}
+
+/// Digests of input files, such as sources and libraries hashed into the
+/// keys of compiles, keyed by their path and checked against their size,
+/// modification time and inode. There are many more of them than of
+/// compilers, so they're kept in memory and only written to their file now
+/// and then by `save`. At most `MAX_INPUT_DIGESTS` are kept, and those of
+/// files that are gone are dropped.
+#[derive(Debug)]
+pub struct InputDigestCache {
+    path: PathBuf,
+    digests: Mutex<HashMap<String, InputDigest>>,
+    uses: AtomicU64,
+    changed: AtomicBool,
+}
+
+impl InputDigestCache {
+    /// Load the digests stored at `path`, dropping those of files that are
+    /// gone. A missing or unreadable file just starts an empty cache.
+    pub fn load(path: PathBuf) -> InputDigestCache {
+        let mut digests: HashMap<String, InputDigest> = load_digests(&path, "input");
+        let count = digests.len();
+        digests.retain(|file, _| Path::new(file).exists());
+        let uses = digests.values().map(|d| d.used).max().unwrap_or(0);
+        InputDigestCache {
+            changed: AtomicBool::new(digests.len() != count),
+            digests: Mutex::new(digests),
+            path,
+            uses: AtomicU64::new(uses),
+        }
+    }
+
+    /// Return the digest of the contents of `path`, only hashing it on a
+    /// background thread in `pool` if it isn't known for its current
+    /// metadata.
+    pub fn digest(
+        self: &Arc<Self>,
+        path: &Path,
+        pool: &ThreadPool,
+    ) -> BoxFuture<'static, Result<String>> {
+        let me = self.clone();
+        let path = path.to_owned();
+        pool.spawn_fn(move || -> Result<_> {
+            let file = path.display().to_string();
+            let metadata = match fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    if me.digests.lock().unwrap().remove(&file).is_some() {
+                        me.changed.store(true, Ordering::SeqCst);
+                    }
+                    return Err(Error::from(e))
+                        .with_context(|| format!("Failed to open file for hashing: {:?}", path));
+                }
+            };
+            let key = input_key(&metadata);
+            let used = me.uses.fetch_add(1, Ordering::SeqCst) + 1;
+            if let Some(stored) = me.digests.lock().unwrap().get_mut(&file) {
+                if stored.key == key {
+                    trace!("Reusing stored digest for {:?}", path);
+                    // Not worth writing the file for, only kept if it is
+                    stored.used = used;
+                    return Ok(stored.digest.clone());
+                }
+            }
+            let digest = Digest::file_sync(&path)?;
+            // Only if the file didn't change while it was hashed, and can't
+            // change without its metadata changing
+            let unchanged = fs::metadata(&path).map_or(false, |after| {
+                input_key(&after) == key && !recently_modified(&after)
+            });
+            let mut digests = me.digests.lock().unwrap();
+            if unchanged {
+                let stored = InputDigest {
+                    key,
+                    digest: digest.clone(),
+                    used,
+                };
+                digests.insert(file, stored);
+                evict_least_used(&mut digests, MAX_INPUT_DIGESTS);
+            } else if digests.remove(&file).is_none() {
+                return Ok(digest);
+            }
+            me.changed.store(true, Ordering::SeqCst);
+            Ok(digest)
+        })
+    }
+
+    /// Write the digests to their file if they changed since it was.
+    pub fn save(&self) -> Result<()> {
+        if !self.changed.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let digests = self.digests.lock().unwrap();
+        save_digests(&self.path, &digests)
+    }
+}
+
+/// Drop the least recently used of `digests` past `max`, down to 90% of it
+/// so that this doesn't happen on every insertion.
+fn evict_least_used(digests: &mut HashMap<String, InputDigest>, max: usize) {
+    if digests.len() <= max {
+        return;
+    }
+    let mut used: Vec<u64> = digests.values().map(|d| d.used).collect();
+    let drop = digests.len() - max * 9 / 10;
+    used.sort_unstable();
+    let threshold = used[drop - 1];
+    digests.retain(|_, d| d.used > threshold);
+}
+
+fn load_digests<T: DeserializeOwned>(path: &Path, kind: &str) -> HashMap<String, T> {
+    match File::open(path) {
+        Ok(f) => serde_json::from_reader(f).unwrap_or_else(|e| {
+            warn!("Ignoring corrupt {} digest cache {:?}: {}", kind, path, e);
+            HashMap::new()
+        }),
+        Err(_) => HashMap::new(),
+    }
+}
+
+fn save_digests<T: Serialize>(path: &Path, digests: &HashMap<String, T>) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Write to a temporary file first so that a concurrent load never
+    // sees a partially written cache.
+    let tmp = path.with_extension("tmp");
+    File::create(&tmp)
+        .map_err(Error::from)
+        .and_then(|f| serde_json::to_writer(f, digests).map_err(Error::from))
+        .and_then(|_| fs::rename(&tmp, path).map_err(Error::from))
+        .with_context(|| format!("failed to write {:?}", path))
+}
+
+fn cache_key(path: &Path, metadata: &Metadata) -> String {
+    let mtime = FileTime::from_last_modification_time(metadata);
+    format!(
+        "{}:{}.{:09}:{}",
+        metadata.len(),
+        mtime.unix_seconds(),
+        mtime.nanoseconds(),
+        path.display()
+    )
+}
+
+fn input_key(metadata: &Metadata) -> String {
+    #[cfg(unix)]
+    let inode = {
+        use std::os::unix::fs::MetadataExt;
+        metadata.ino()
+    };
+    #[cfg(not(unix))]
+    let inode = 0;
+    let mtime = FileTime::from_last_modification_time(metadata);
+    format!(
+        "{}:{}:{}.{:09}",
+        inode,
+        metadata.len(),
+        mtime.unix_seconds(),
+        mtime.nanoseconds()
+    )
+}
+
+fn recently_modified(metadata: &Metadata) -> bool {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|mtime| SystemTime::now().duration_since(mtime).ok())
+        .map_or(true, |age| age < RACY_INTERVAL)
+}
+
+/// Use `cache` for the digests of compilers detected from now on, or hash
+/// their full contents every time if it is `None`.
+pub fn set_compiler_digest_cache(cache: Option<CompilerDigestCache>) {
+    *COMPILER_DIGEST_CACHE.lock().unwrap() = cache.map(Arc::new);
+}
+
+/// Calculate the digest of the compiler file at `path`, reusing a stored
+/// digest when a compiler digest cache is in use.
+pub async fn compiler_digest(path: &Path, pool: &ThreadPool) -> Result<String> {
+    let cache = COMPILER_DIGEST_CACHE.lock().unwrap().clone();
+    match cache {
+        Some(cache) => cache.digest(path, pool).await,
+        None => Digest::file(path, pool).await,
+    }
+}
+
+/// Use `cache` for the digests of input files from now on, or hash their
+/// full contents every time if it is `None`. The cache used until now is
+/// saved.
+pub fn set_input_digest_cache(cache: Option<InputDigestCache>) {
+    let old = std::mem::replace(
+        &mut *INPUT_DIGEST_CACHE.lock().unwrap(),
+        cache.map(Arc::new),
+    );
+    if let Some(old) = old {
+        if let Err(e) = old.save() {
+            warn!("Failed to save input digests: {:?}", e);
+        }
+    }
+}
+
+/// Write the digests of input files to their file, if they're kept in one
+/// and changed.
+pub fn save_input_digests() {
+    let cache = INPUT_DIGEST_CACHE.lock().unwrap().clone();
+    if let Some(cache) = cache {
+        if let Err(e) = cache.save() {
+            warn!("Failed to save input digests: {:?}", e);
+        }
+    }
+}
+
+/// Calculate the digest of each of the input files in `files`, reusing
+/// stored digests when an input digest cache is in use.
+pub async fn input_digests(files: &[PathBuf], pool: &ThreadPool) -> Result<Vec<String>> {
+    let start = Instant::now();
+    let cache = INPUT_DIGEST_CACHE.lock().unwrap().clone();
+    let cache = &cache;
+    let digests = future::try_join_all(files.iter().map(|f| async move {
+        match cache {
+            Some(cache) => cache.digest(f, pool).await,
+            None => Digest::file(f, pool).await,
+        }
+    }))
+    .await?;
+    trace!(
+        "Hashed {} files in {}",
+        files.len(),
+        fmt_duration_as_secs(&start.elapsed())
+    );
+    Ok(digests)
+}
+
+/// Calculate the digest of each of the compiler files in `files`, as
+/// `compiler_digest` does.
+pub async fn compiler_digests(files: &[PathBuf], pool: &ThreadPool) -> Result<Vec<String>> {
+    future::try_join_all(files.iter().map(|f| compiler_digest(f, pool))).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::utils::*;
+    use futures::executor::block_on;
+    use std::io::Write;
+
+    #[test]
+    fn test_digests_persist_until_compiler_changes() {
+        let td = tempfile::Builder::new()
+            .prefix("sccache")
+            .tempdir()
+            .unwrap();
+        let pool = ThreadPool::sized(1);
+        let compiler = create_file(td.path(), "cc", |mut f| f.write_all(b"compiler")).unwrap();
+        let cache_path = td.path().join("cache").join("compiler_digests.json");
+        let expected = block_on(Digest::file(&compiler, &pool)).unwrap();
+
+        let cache = Arc::new(CompilerDigestCache::load(cache_path.clone()));
+        assert_eq!(expected, block_on(cache.digest(&compiler, &pool)).unwrap());
+        assert!(cache_path.exists());
+
+        // A fresh cache, as after a server restart, trusts the stored digest
+        // as long as the size and modification time match.
+        let key = cache_key(&compiler, &fs::metadata(&compiler).unwrap());
+        let mut stored: HashMap<String, String> =
+            serde_json::from_reader(File::open(&cache_path).unwrap()).unwrap();
+        stored.insert(key, "stored".to_owned());
+        serde_json::to_writer(File::create(&cache_path).unwrap(), &stored).unwrap();
+        let cache = Arc::new(CompilerDigestCache::load(cache_path.clone()));
+        assert_eq!("stored", block_on(cache.digest(&compiler, &pool)).unwrap());
+
+        // Changing the compiler invalidates it.
+        fs::write(&compiler, b"a new compiler").unwrap();
+        let expected = block_on(Digest::file(&compiler, &pool)).unwrap();
+        assert_eq!(expected, block_on(cache.digest(&compiler, &pool)).unwrap());
+    }
+
+    #[test]
+    fn test_input_digests_persist_until_input_changes() {
+        let td = tempfile::Builder::new()
+            .prefix("sccache")
+            .tempdir()
+            .unwrap();
+        let pool = ThreadPool::sized(1);
+        let source = create_file(td.path(), "lib.rs", |mut f| f.write_all(b"fn f() {}")).unwrap();
+        let cache_path = td.path().join("cache").join("input_digests.json");
+        let expected = block_on(Digest::file(&source, &pool)).unwrap();
+
+        // A file just written could still change without its metadata
+        // changing, so its digest isn't kept
+        let cache = Arc::new(InputDigestCache::load(cache_path.clone()));
+        assert_eq!(expected, block_on(cache.digest(&source, &pool)).unwrap());
+        cache.save().unwrap();
+        assert!(!cache_path.exists());
+
+        let old = FileTime::from_unix_time(FileTime::now().unix_seconds() - 60, 0);
+        filetime::set_file_mtime(&source, old).unwrap();
+        assert_eq!(expected, block_on(cache.digest(&source, &pool)).unwrap());
+        cache.save().unwrap();
+        assert!(cache_path.exists());
+
+        // A fresh cache trusts the stored digest as long as the metadata
+        // matches
+        let file = source.display().to_string();
+        let mut stored: HashMap<String, InputDigest> =
+            serde_json::from_reader(File::open(&cache_path).unwrap()).unwrap();
+        assert_eq!(
+            stored[&file].key,
+            input_key(&fs::metadata(&source).unwrap())
+        );
+        assert_eq!(stored[&file].digest, expected);
+        stored.get_mut(&file).unwrap().digest = "stored".to_owned();
+        serde_json::to_writer(File::create(&cache_path).unwrap(), &stored).unwrap();
+        let cache = Arc::new(InputDigestCache::load(cache_path.clone()));
+        assert_eq!("stored", block_on(cache.digest(&source, &pool)).unwrap());
+
+        // Changing the file invalidates it.
+        fs::write(&source, b"fn g() {}").unwrap();
+        let expected = block_on(Digest::file(&source, &pool)).unwrap();
+        assert_eq!(expected, block_on(cache.digest(&source, &pool)).unwrap());
+
+        // The digests of files that are gone are dropped
+        filetime::set_file_mtime(&source, old).unwrap();
+        block_on(cache.digest(&source, &pool)).unwrap();
+        cache.save().unwrap();
+        fs::remove_file(&source).unwrap();
+        assert!(block_on(cache.digest(&source, &pool)).is_err());
+        assert!(cache.digests.lock().unwrap().is_empty());
+        cache.save().unwrap();
+        let stored: HashMap<String, InputDigest> =
+            serde_json::from_reader(File::open(&cache_path).unwrap()).unwrap();
+        assert!(stored.is_empty());
+    }
+
+    #[test]
+    fn test_evict_least_used_input_digests() {
+        let mut digests: HashMap<_, _> = (0..20)
+            .map(|used| {
+                let digest = InputDigest {
+                    key: String::new(),
+                    digest: String::new(),
+                    used,
+                };
+                (used.to_string(), digest)
+            })
+            .collect();
+        evict_least_used(&mut digests, 20);
+        assert_eq!(digests.len(), 20);
+        digests.get_mut("3").unwrap().used = 100;
+        evict_least_used(&mut digests, 10);
+        let mut kept: Vec<u64> = digests.values().map(|d| d.used).collect();
+        kept.sort_unstable();
+        assert_eq!(kept, vec![12, 13, 14, 15, 16, 17, 18, 19, 100]);
+    }
+}
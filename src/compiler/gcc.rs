@@ -14,15 +14,17 @@
 
 use crate::compiler::args::*;
 use crate::compiler::c::{CCompilerImpl, CCompilerKind, Language, ParsedArguments};
+use crate::compiler::response_file::{ExpandResponseFiles, Quoting};
 use crate::compiler::{clang, Cacheable, ColorMode, CompileCommand, CompilerArguments};
 use crate::dist;
-use crate::mock_command::{CommandCreatorSync, RunCommand};
-use crate::util::{run_input_output, OsStrExt};
+use crate::mock_command::{exit_status, CommandCreatorSync, RunCommand};
+use crate::util::{run_input_output, run_input_output_hashed, OsStrExt};
+use async_trait::async_trait;
+use futures::future;
 use log::Level::Trace;
 use std::collections::HashMap;
-use std::ffi::OsString;
-use std::fs::File;
-use std::io::Read;
+use std::ffi::{OsStr, OsString};
+use std::fs;
 use std::path::{Path, PathBuf};
 use std::process;
 
@@ -34,6 +36,7 @@ pub struct GCC {
     pub gplusplus: bool,
 }
 
+#[async_trait(?Send)]
 impl CCompilerImpl for GCC {
     fn kind(&self) -> CCompilerKind {
         CCompilerKind::GCC
@@ -49,7 +52,7 @@ impl CCompilerImpl for GCC {
         parse_arguments(arguments, cwd, &ARGS[..], self.gplusplus)
     }
 
-    fn preprocess<T>(
+    async fn preprocess<T>(
         &self,
         creator: &T,
         executable: &Path,
@@ -58,7 +61,7 @@ impl CCompilerImpl for GCC {
         env_vars: &[(OsString, OsString)],
         may_dist: bool,
         rewrite_includes_only: bool,
-    ) -> SFuture<process::Output>
+    ) -> Result<process::Output>
     where
         T: CommandCreatorSync,
     {
@@ -72,6 +75,33 @@ impl CCompilerImpl for GCC {
             self.kind(),
             rewrite_includes_only,
         )
+        .await
+    }
+
+    async fn preprocess_hashed<T>(
+        &self,
+        creator: &T,
+        executable: &Path,
+        parsed_args: &ParsedArguments,
+        cwd: &Path,
+        env_vars: &[(OsString, OsString)],
+        may_dist: bool,
+        rewrite_includes_only: bool,
+    ) -> Result<(process::Output, Option<String>)>
+    where
+        T: CommandCreatorSync,
+    {
+        preprocess_hashed(
+            creator,
+            executable,
+            parsed_args,
+            cwd,
+            env_vars,
+            may_dist,
+            self.kind(),
+            rewrite_includes_only,
+        )
+        .await
     }
 
     fn generate_compile_commands(
@@ -117,14 +147,27 @@ ArgData! { pub
     DepTarget(OsString),
     DepArgumentPath(PathBuf),
     Language(OsString),
-    SplitDwarf,
+    SplitDwarf(OsString),
+    NoSplitDwarf,
     ProfileGenerate,
+    ProfileUse(OsString),
+    ProfileDir(PathBuf),
+    SaveTemps(OsString),
+    // Clang also keeps the LLVM bitcode around with -save-temps.
+    SaveTempsWithBitcode(OsString),
     TestCoverage,
     Coverage,
     ExtraHashFile(PathBuf),
     // Only valid for clang, but this needs to be here since clang shares gcc's arg parsing.
     XClang(OsString),
     Arch(OsString),
+    // Only valid for clang: compile-time side outputs written next to the object.
+    TimeTrace(OsString),
+    CompilationDbFragment(PathBuf),
+    // Only valid for clang: where to write index data for IDEs.
+    IndexArgument(PathBuf),
+    OptimizationRecord(OsString),
+    OptimizationRecordFile(PathBuf),
 }
 
 use self::ArgData::*;
@@ -134,7 +177,7 @@ counted_array!(pub static ARGS: [ArgInfo<ArgData>; _] = [
     flag!("-", TooHardFlag),
     flag!("--coverage", Coverage),
     take_arg!("--param", OsString, Separated, PassThrough),
-    flag!("--save-temps", TooHardFlag),
+    take_arg!("--save-temps", OsString, Concatenated('='), SaveTemps),
     take_arg!("--serialize-diagnostics", PathBuf, Separated, PassThroughPath),
     take_arg!("--sysroot", PathBuf, Separated, PassThroughPath),
     take_arg!("-A", OsString, Separated, PassThrough),
@@ -168,13 +211,16 @@ counted_array!(pub static ARGS: [ArgInfo<ArgData>; _] = [
     flag!("-fno-working-directory", PreprocessorArgumentFlag),
     flag!("-fplugin=libcc1plugin", TooHardFlag),
     flag!("-fprofile-arcs", ProfileGenerate),
+    take_arg!("-fprofile-dir", PathBuf, Concatenated('='), ProfileDir),
     flag!("-fprofile-generate", ProfileGenerate),
-    take_arg!("-fprofile-use", OsString, Concatenated, TooHard),
+    take_arg!("-fprofile-update", OsString, Concatenated('='), PassThrough),
+    take_arg!("-fprofile-use", OsString, Concatenated('='), ProfileUse),
     flag!("-frepo", TooHardFlag),
     flag!("-fsyntax-only", TooHardFlag),
     flag!("-ftest-coverage", TestCoverage),
     flag!("-fworking-directory", PreprocessorArgumentFlag),
-    flag!("-gsplit-dwarf", SplitDwarf),
+    flag!("-gno-split-dwarf", NoSplitDwarf),
+    take_arg!("-gsplit-dwarf", OsString, Concatenated('='), SplitDwarf),
     take_arg!("-idirafter", PathBuf, CanBeSeparated, PreprocessorArgumentPath),
     take_arg!("-iframework", PathBuf, CanBeSeparated, PreprocessorArgumentPath),
     take_arg!("-imacros", PathBuf, CanBeSeparated, PreprocessorArgumentPath),
@@ -191,7 +237,7 @@ counted_array!(pub static ARGS: [ArgInfo<ArgData>; _] = [
     flag!("-nostdinc++", PreprocessorArgumentFlag),
     take_arg!("-o", PathBuf, CanBeSeparated, Output),
     flag!("-remap", PreprocessorArgumentFlag),
-    flag!("-save-temps", TooHardFlag),
+    take_arg!("-save-temps", OsString, Concatenated('='), SaveTemps),
     take_arg!("-stdlib", OsString, Concatenated('='), PreprocessorArgument),
     flag!("-trigraphs", PreprocessorArgumentFlag),
     take_arg!("-u", OsString, CanBeSeparated, PassThrough),
@@ -225,9 +271,10 @@ where
     let mut common_args = vec![];
     let mut preprocessor_args = vec![];
     let mut dependency_args = vec![];
+    let mut index_args = vec![];
     let mut extra_hash_files = vec![];
     let mut compilation = false;
-    let mut multiple_input = false;
+    let mut extra_input = None;
     let mut split_dwarf = false;
     let mut need_explicit_dep_target = false;
     let mut language = None;
@@ -236,11 +283,18 @@ where
     let mut outputs_gcno = false;
     let mut xclangs: Vec<OsString> = vec![];
     let mut color_mode = ColorMode::Auto;
-    let mut seen_arch = false;
+    let mut arch_args = vec![];
+    let mut time_trace = None;
+    let mut optimization_record = None;
+    let mut optimization_record_file = None;
+    let mut profile_use = None;
+    let mut profile_dir = None;
+    let mut save_temps = None;
+    let mut compilation_db_fragment = None;
 
     // Custom iterator to expand `@` arguments which stand for reading a file
     // and interpreting it as a list of more arguments.
-    let it = ExpandIncludeFile::new(cwd, arguments);
+    let it = ExpandResponseFiles::new(cwd, arguments, Quoting::Gnu);
 
     for arg in ArgsIter::new(it, arg_info) {
         let arg = try_or_cannot_cache!(arg, "argument parse");
@@ -252,8 +306,9 @@ where
             Argument::WithValue(_, ref v, ArgDisposition::Separated)
             | Argument::WithValue(_, ref v, ArgDisposition::CanBeConcatenated(_))
             | Argument::WithValue(_, ref v, ArgDisposition::CanBeSeparated(_)) => {
-                if v.clone().into_arg_os_string().starts_with("@") {
-                    cannot_cache!("@");
+                let v = v.clone().into_arg_os_string();
+                if v.starts_with("@") {
+                    cannot_cache!("@", v.to_string_lossy().into_owned());
                 }
             }
             // Empirically, concatenated arguments appear not to interpret '@' as
@@ -268,13 +323,29 @@ where
             Some(TooHardFlag) | Some(TooHard(_)) => {
                 cannot_cache!(arg.flag_str().expect("Can't be Argument::Raw/UnknownFlag",))
             }
-            Some(SplitDwarf) => split_dwarf = true,
+            Some(SplitDwarf(mode)) => {
+                // `-gsplit-dwarf=single` keeps the split debug info in the
+                // object itself, so there is no separate `.dwo` to cache.
+                split_dwarf = match mode.to_str() {
+                    Some("") | Some("split") => true,
+                    Some("single") => false,
+                    _ => cannot_cache!(
+                        "-gsplit-dwarf",
+                        format!("-gsplit-dwarf={}", mode.to_string_lossy())
+                    ),
+                };
+            }
+            Some(NoSplitDwarf) => split_dwarf = false,
             Some(DoCompilation) => {
                 compilation = true;
                 compilation_flag =
                     OsString::from(arg.flag_str().expect("Compilation flag expected"));
             }
             Some(ProfileGenerate) => profile_generate = true,
+            Some(ProfileUse(path)) => profile_use = Some(PathBuf::from(path)),
+            Some(ProfileDir(path)) => profile_dir = Some(path.clone()),
+            Some(SaveTemps(mode)) => save_temps = Some((mode.clone(), false)),
+            Some(SaveTempsWithBitcode(mode)) => save_temps = Some((mode.clone(), true)),
             Some(TestCoverage) => outputs_gcno = true,
             Some(Coverage) => {
                 outputs_gcno = true;
@@ -301,7 +372,8 @@ where
             | Some(PreprocessorArgument(_))
             | Some(PreprocessorArgumentPath(_))
             | Some(PassThrough(_))
-            | Some(PassThroughPath(_)) => {}
+            | Some(PassThroughPath(_))
+            | Some(IndexArgument(_)) => {}
             Some(Language(lang)) => {
                 language = match lang.to_string_lossy().as_ref() {
                     "c" => Some(Language::C),
@@ -309,20 +381,21 @@ where
                     "objective-c" => Some(Language::ObjectiveC),
                     "objective-c++" => Some(Language::ObjectiveCxx),
                     "cu" => Some(Language::Cuda),
-                    _ => cannot_cache!("-x"),
+                    "assembler" => Some(Language::Assembler),
+                    "assembler-with-cpp" => Some(Language::AssemblerWithCpp),
+                    lang => cannot_cache!("-x", format!("-x {}", lang)),
                 };
             }
-            Some(Arch(_)) => {
-                if seen_arch {
-                    cannot_cache!("multiple -arch")
-                }
-                seen_arch = true;
-            }
+            Some(Arch(arch)) => arch_args.push(arch.clone()),
+            Some(TimeTrace(path)) => time_trace = Some(path.clone()),
+            Some(CompilationDbFragment(path)) => compilation_db_fragment = Some(path.clone()),
+            Some(OptimizationRecord(format)) => optimization_record = Some(format.clone()),
+            Some(OptimizationRecordFile(path)) => optimization_record_file = Some(path.clone()),
             Some(XClang(s)) => xclangs.push(s.clone()),
             None => match arg {
                 Argument::Raw(ref val) => {
-                    if input_arg.is_some() {
-                        multiple_input = true;
+                    if input_arg.is_some() && extra_input.is_none() {
+                        extra_input = Some(val.clone());
                     }
                     input_arg = Some(val.clone());
                 }
@@ -331,14 +404,22 @@ where
             },
         }
         let args = match arg.get_data() {
-            Some(SplitDwarf)
+            Some(SplitDwarf(_))
+            | Some(NoSplitDwarf)
             | Some(ProfileGenerate)
+            | Some(ProfileUse(_))
+            | Some(ProfileDir(_))
+            | Some(SaveTemps(_))
+            | Some(SaveTempsWithBitcode(_))
             | Some(TestCoverage)
             | Some(Coverage)
             | Some(DiagnosticsColor(_))
             | Some(DiagnosticsColorFlag)
             | Some(NoDiagnosticsColorFlag)
             | Some(Arch(_))
+            | Some(TimeTrace(_))
+            | Some(OptimizationRecord(_))
+            | Some(OptimizationRecordFile(_))
             | Some(PassThrough(_))
             | Some(PassThroughPath(_)) => &mut common_args,
             Some(ExtraHashFile(path)) => {
@@ -349,7 +430,12 @@ where
             | Some(PreprocessorArgument(_))
             | Some(PreprocessorArgumentPath(_)) => &mut preprocessor_args,
             Some(DepArgumentPath(_)) | Some(NeedDepTarget) => &mut dependency_args,
-            Some(DoCompilation) | Some(Language(_)) | Some(Output(_)) | Some(XClang(_))
+            Some(IndexArgument(_)) => &mut index_args,
+            Some(DoCompilation)
+            | Some(Language(_))
+            | Some(Output(_))
+            | Some(XClang(_))
+            | Some(CompilationDbFragment(_))
             | Some(DepTarget(_)) => continue,
             Some(TooHardFlag) | Some(TooHard(_)) => unreachable!(),
             None => match arg {
@@ -369,13 +455,18 @@ where
         args.extend(arg.normalize(norm).iter_os_strings());
     }
 
-    let xclang_it = ExpandIncludeFile::new(cwd, &xclangs);
+    let xclang_it = ExpandResponseFiles::new(cwd, &xclangs, Quoting::Gnu);
     let mut follows_plugin_arg = false;
     for arg in ArgsIter::new(xclang_it, (&ARGS[..], &clang::ARGS[..])) {
         let arg = try_or_cannot_cache!(arg, "argument parse");
         let args = match arg.get_data() {
-            Some(SplitDwarf)
+            Some(SplitDwarf(_))
+            | Some(NoSplitDwarf)
             | Some(ProfileGenerate)
+            | Some(ProfileUse(_))
+            | Some(ProfileDir(_))
+            | Some(SaveTemps(_))
+            | Some(SaveTempsWithBitcode(_))
             | Some(TestCoverage)
             | Some(Coverage)
             | Some(DoCompilation)
@@ -383,15 +474,24 @@ where
             | Some(Output(_))
             | Some(TooHardFlag)
             | Some(XClang(_))
+            | Some(TimeTrace(_))
+            | Some(OptimizationRecord(_))
+            | Some(OptimizationRecordFile(_))
+            | Some(CompilationDbFragment(_))
+            | Some(IndexArgument(_))
             | Some(TooHard(_)) => cannot_cache!(arg
                 .flag_str()
                 .unwrap_or("Can't handle complex arguments through clang",)),
             None => match arg {
                 Argument::Raw(_) if follows_plugin_arg => &mut common_args,
-                Argument::Raw(_) => cannot_cache!("Can't handle Raw arguments with -Xclang"),
-                Argument::UnknownFlag(_) => {
-                    cannot_cache!("Can't handle UnknownFlag arguments with -Xclang")
-                }
+                Argument::Raw(ref val) => cannot_cache!(
+                    "Can't handle Raw arguments with -Xclang",
+                    val.to_string_lossy().into_owned()
+                ),
+                Argument::UnknownFlag(ref flag) => cannot_cache!(
+                    "Can't handle UnknownFlag arguments with -Xclang",
+                    flag.to_string_lossy().into_owned()
+                ),
                 _ => unreachable!(),
             },
             Some(DiagnosticsColor(_))
@@ -434,8 +534,11 @@ where
         return CompilerArguments::NotCompilation;
     }
     // Can't cache compilations with multiple inputs.
-    if multiple_input {
-        cannot_cache!("multiple input files");
+    if let Some(extra_input) = extra_input {
+        cannot_cache!(
+            "multiple input files",
+            extra_input.to_string_lossy().into_owned()
+        );
     }
     let input = match input_arg {
         Some(i) => i,
@@ -454,7 +557,10 @@ where
     };
     let language = match language {
         Some(l) => l,
-        None => cannot_cache!("unknown source language"),
+        None => cannot_cache!(
+            "unknown source language",
+            input.to_string_lossy().into_owned()
+        ),
     };
     let mut outputs = HashMap::new();
     let output = match output_arg {
@@ -471,6 +577,75 @@ where
         outputs.insert("gcno", gcno);
         profile_generate = true;
     }
+    if let Some(path) = time_trace {
+        let path = PathBuf::from(path);
+        let file_name = output.with_extension("json");
+        let file_name = file_name.file_name().expect("Output file must have a name");
+        let trace = if path.as_os_str().is_empty() {
+            output.with_extension("json")
+        } else if path.to_string_lossy().ends_with(std::path::is_separator)
+            || cwd.join(&path).is_dir()
+        {
+            path.join(file_name)
+        } else {
+            path
+        };
+        outputs.insert("time-trace", trace);
+    }
+    if optimization_record.is_some() || optimization_record_file.is_some() {
+        // Clang writes one record per architecture, suffixed with its name.
+        if arch_args.len() > 1 {
+            cannot_cache!("optimization records with multiple -arch");
+        }
+        let record = optimization_record_file.unwrap_or_else(|| {
+            let format = optimization_record
+                .filter(|format| !format.is_empty())
+                .unwrap_or_else(|| "yaml".into());
+            let mut extension = OsString::from("opt.");
+            extension.push(format);
+            output.with_extension(extension)
+        });
+        outputs.insert("opt-record", record);
+    }
+    if let Some((mode, bitcode)) = save_temps {
+        // Temporaries go to the current directory, named after the input,
+        // or next to the object with `-save-temps=obj`.
+        let base = match mode.to_str() {
+            Some("") | Some("cwd") => PathBuf::from(Path::new(&input).file_name().unwrap()),
+            Some("obj") => output.clone(),
+            _ => cannot_cache!(
+                "-save-temps",
+                format!("-save-temps={}", mode.to_string_lossy())
+            ),
+        };
+        let preprocessed = match language {
+            Language::C => Some("i"),
+            Language::Cxx => Some("ii"),
+            Language::ObjectiveC => Some("mi"),
+            Language::ObjectiveCxx => Some("mii"),
+            Language::AssemblerWithCpp => Some("s"),
+            Language::Assembler => None,
+            Language::Cuda => cannot_cache!("-save-temps"),
+            Language::Resource => unreachable!(),
+        };
+        if let Some(ext) = preprocessed {
+            outputs.insert("temp-preprocessed", base.with_extension(ext));
+        }
+        if !language.is_assembler() {
+            outputs.insert("temp-assembly", base.with_extension("s"));
+            if bitcode {
+                outputs.insert("temp-bitcode", base.with_extension("bc"));
+            }
+        }
+    }
+    if let Some(path) = profile_use {
+        extra_hash_files.extend(profile_use_files(
+            cwd,
+            &output,
+            &path,
+            profile_dir.as_deref(),
+        ));
+    }
     if need_explicit_dep_target {
         dependency_args.push(dep_flag);
         dependency_args.push(dep_target.unwrap_or_else(|| output.clone().into_os_string()));
@@ -490,11 +665,14 @@ where
         msvc_show_includes: false,
         profile_generate,
         color_mode,
+        arch_args,
+        compilation_db_fragment,
+        index_args,
     })
 }
 
 #[allow(clippy::too_many_arguments)]
-pub fn preprocess<T>(
+pub async fn preprocess<T>(
     creator: &T,
     executable: &Path,
     parsed_args: &ParsedArguments,
@@ -503,7 +681,37 @@ pub fn preprocess<T>(
     may_dist: bool,
     kind: CCompilerKind,
     rewrite_includes_only: bool,
-) -> SFuture<process::Output>
+) -> Result<process::Output>
+where
+    T: CommandCreatorSync,
+{
+    let (output, _) = preprocess_hashed(
+        creator,
+        executable,
+        parsed_args,
+        cwd,
+        env_vars,
+        may_dist,
+        kind,
+        rewrite_includes_only,
+    )
+    .await?;
+    Ok(output)
+}
+
+/// Run the preprocessor like `preprocess`, hashing its output as it's
+/// written, unless it's for several architectures.
+#[allow(clippy::too_many_arguments)]
+pub async fn preprocess_hashed<T>(
+    creator: &T,
+    executable: &Path,
+    parsed_args: &ParsedArguments,
+    cwd: &Path,
+    env_vars: &[(OsString, OsString)],
+    may_dist: bool,
+    kind: CCompilerKind,
+    rewrite_includes_only: bool,
+) -> Result<(process::Output, Option<String>)>
 where
     T: CommandCreatorSync,
 {
@@ -514,39 +722,170 @@ where
         Language::ObjectiveC => "objective-c",
         Language::ObjectiveCxx => "objective-c++",
         Language::Cuda => "cu",
+        Language::Assembler => "assembler",
+        Language::AssemblerWithCpp => "assembler-with-cpp",
+        Language::Resource => unreachable!(),
     };
-    let mut cmd = creator.clone().new_command_sync(executable);
-    cmd.arg("-x").arg(language).arg("-E");
-    // When performing distributed compilation, line number info is important for error
-    // reporting and to not cause spurious compilation failure (e.g. no exceptions build
-    // fails due to exceptions transitively included in the stdlib).
-    // With -fprofile-generate line number information is important, so don't use -P.
-    if !may_dist && !parsed_args.profile_generate {
-        cmd.arg("-P");
+    // Assembly that doesn't need preprocessing is its own preprocessor output.
+    if parsed_args.language == Language::Assembler {
+        let input = cwd.join(&parsed_args.input);
+        let stdout =
+            fs::read(&input).with_context(|| format!("failed to read {}", input.display()))?;
+        let output = process::Output {
+            status: exit_status(0),
+            stdout,
+            stderr: vec![],
+        };
+        return Ok((output, None));
     }
-    if rewrite_includes_only {
-        match kind {
-            CCompilerKind::Clang => {
-                cmd.arg("-frewrite-includes");
-            }
-            CCompilerKind::GCC => {
-                cmd.arg("-fdirectives-only");
+
+    let multiple_archs = parsed_args.arch_args.len() > 1;
+    let mut common_args = parsed_args
+        .common_args
+        .iter()
+        .filter(|arg| !is_side_output_arg(arg))
+        .cloned()
+        .collect::<Vec<_>>();
+    if multiple_archs {
+        common_args = strip_arch_args(&common_args);
+    }
+    let new_cmd = || {
+        let mut cmd = creator.clone().new_command_sync(executable);
+        cmd.arg("-x").arg(language).arg("-E");
+        // When performing distributed compilation, line number info is important for error
+        // reporting and to not cause spurious compilation failure (e.g. no exceptions build
+        // fails due to exceptions transitively included in the stdlib).
+        // With -fprofile-generate line number information is important, so don't use -P.
+        if !may_dist && !parsed_args.profile_generate {
+            cmd.arg("-P");
+        }
+        if rewrite_includes_only {
+            match kind {
+                CCompilerKind::Clang => {
+                    cmd.arg("-frewrite-includes");
+                }
+                CCompilerKind::GCC => {
+                    cmd.arg("-fdirectives-only");
+                }
+                _ => {}
             }
-            _ => {}
         }
+        cmd.arg(&parsed_args.input)
+            .args(&parsed_args.preprocessor_args)
+            .args(&parsed_args.dependency_args)
+            .args(&common_args)
+            .env_clear()
+            .envs(env_vars.iter().map(|&(ref k, ref v)| (k, v)))
+            .current_dir(cwd);
+        cmd
+    };
+
+    if !multiple_archs {
+        let cmd = new_cmd();
+        if log_enabled!(Trace) {
+            trace!("preprocess: {:?}", cmd);
+        }
+        let (output, digest) = run_input_output_hashed(cmd).await?;
+        return Ok((output, Some(digest)));
     }
-    cmd.arg(&parsed_args.input)
-        .args(&parsed_args.preprocessor_args)
-        .args(&parsed_args.dependency_args)
-        .args(&parsed_args.common_args)
-        .env_clear()
-        .envs(env_vars.iter().map(|&(ref k, ref v)| (k, v)))
-        .current_dir(cwd);
-
-    if log_enabled!(Trace) {
-        trace!("preprocess: {:?}", cmd);
+
+    // The compiler refuses to preprocess for several architectures at once,
+    // so preprocess each of them separately and hash the combined output.
+    let outputs = parsed_args
+        .arch_args
+        .iter()
+        .map(|arch| {
+            let mut cmd = new_cmd();
+            cmd.arg("-arch").arg(arch);
+            if log_enabled!(Trace) {
+                trace!("preprocess: {:?}", cmd);
+            }
+            run_input_output(cmd, None)
+        })
+        .collect::<Vec<_>>();
+    let outputs = future::try_join_all(outputs).await?;
+    let mut combined = process::Output {
+        status: outputs[0].status,
+        stdout: vec![],
+        stderr: vec![],
+    };
+    for (arch, output) in parsed_args.arch_args.iter().zip(outputs) {
+        combined.stdout.extend_from_slice(b"# sccache-arch ");
+        combined
+            .stdout
+            .extend_from_slice(arch.to_string_lossy().as_bytes());
+        combined.stdout.push(b'\n');
+        combined.stdout.extend(output.stdout);
+        combined.stderr.extend(output.stderr);
     }
-    Box::new(run_input_output(cmd, None))
+    Ok((combined, None))
+}
+
+/// Find the profile data `-fprofile-use[=path]` will read when compiling to
+/// `output`, so that its contents are part of the hash key.
+///
+/// Clang reads `default.profdata` (or the named file), while GCC reads a
+/// `.gcda` named after the object, either next to it or, when given a
+/// profile directory, under the object's mangled absolute path. Only the
+/// files that exist are returned: the compilers carry on without missing
+/// profiles, and their absence changes the hash just as well.
+fn profile_use_files(
+    cwd: &Path,
+    output: &Path,
+    path: &Path,
+    profile_dir: Option<&Path>,
+) -> Vec<PathBuf> {
+    let object = cwd.join(output);
+    let dir = if path.as_os_str().is_empty() {
+        profile_dir.map(|dir| cwd.join(dir))
+    } else {
+        let path = cwd.join(path);
+        if !path.is_dir() {
+            return vec![path];
+        }
+        Some(path)
+    };
+    let candidates = match dir {
+        None => vec![cwd.join("default.profdata"), object.with_extension("gcda")],
+        Some(dir) => {
+            let mangled = object
+                .with_extension("gcda")
+                .to_string_lossy()
+                .replace(std::path::MAIN_SEPARATOR, "#");
+            let mut candidates = vec![dir.join("default.profdata"), dir.join(mangled)];
+            if let Some(name) = object.with_extension("gcda").file_name() {
+                candidates.push(dir.join(name));
+            }
+            candidates
+        }
+    };
+    candidates.into_iter().filter(|p| p.is_file()).collect()
+}
+
+/// Whether `arg` only requests a side output of compilation, which the
+/// preprocessor would otherwise write on its own (e.g. clang writes
+/// `-.json` for `-ftime-trace` when preprocessing to stdout).
+fn is_side_output_arg(arg: &OsStr) -> bool {
+    let arg = arg.to_string_lossy();
+    arg.starts_with("-ftime-trace")
+        || arg.starts_with("-save-temps")
+        || arg.starts_with("--save-temps")
+        || arg.starts_with("-fsave-optimization-record")
+        || arg.starts_with("-foptimization-record")
+}
+
+/// Remove all `-arch <value>` pairs from `args`.
+fn strip_arch_args(args: &[OsString]) -> Vec<OsString> {
+    let mut stripped = vec![];
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        if arg == "-arch" {
+            it.next();
+        } else {
+            stripped.push(arg.clone());
+        }
+    }
+    stripped
 }
 
 pub fn generate_compile_commands(
@@ -581,6 +920,9 @@ pub fn generate_compile_commands(
         Language::ObjectiveC => "objective-c",
         Language::ObjectiveCxx => "objective-c++",
         Language::Cuda => "cu",
+        Language::Assembler => "assembler",
+        Language::AssemblerWithCpp => "assembler-with-cpp",
+        Language::Resource => unreachable!(),
     };
     let mut arguments: Vec<OsString> = vec![
         "-x".into(),
@@ -592,6 +934,7 @@ pub fn generate_compile_commands(
     ];
     arguments.extend(parsed_args.preprocessor_args.clone());
     arguments.extend(parsed_args.common_args.clone());
+    arguments.extend(parsed_args.index_args.clone());
     let command = CompileCommand {
         executable: executable.to_owned(),
         arguments,
@@ -603,6 +946,11 @@ pub fn generate_compile_commands(
     let dist_command = None;
     #[cfg(feature = "dist-client")]
     let dist_command = (|| {
+        // The combined preprocessor output of a multi-arch build can't be
+        // compiled remotely.
+        if parsed_args.arch_args.len() > 1 {
+            return None;
+        }
         // https://gcc.gnu.org/onlinedocs/gcc-4.9.0/gcc/Overall-Options.html
         let mut language: String = match parsed_args.language {
             Language::C => "c",
@@ -610,11 +958,16 @@ pub fn generate_compile_commands(
             Language::ObjectiveC => "objective-c",
             Language::ObjectiveCxx => "objective-c++",
             Language::Cuda => "cu",
+            Language::Assembler => "assembler",
+            Language::AssemblerWithCpp => "assembler-with-cpp",
+            Language::Resource => unreachable!(),
         }
         .into();
         if !rewrite_includes_only {
             match parsed_args.language {
                 Language::C => language = "cpp-output".into(),
+                // Preprocessed assembly is plain assembly.
+                Language::Assembler | Language::AssemblerWithCpp => language = "assembler".into(),
                 _ => language.push_str("-cpp-output"),
             }
         }
@@ -626,7 +979,7 @@ pub fn generate_compile_commands(
             "-o".into(),
             path_transformer.as_dist(out_file)?,
         ];
-        if let CCompilerKind::GCC = kind {
+        if let (CCompilerKind::GCC, false) = (kind, parsed_args.language.is_assembler()) {
             // From https://gcc.gnu.org/onlinedocs/gcc/Preprocessor-Options.html:
             //
             // -fdirectives-only
@@ -657,75 +1010,6 @@ pub fn generate_compile_commands(
     Ok((command, dist_command, Cacheable::Yes))
 }
 
-pub struct ExpandIncludeFile<'a> {
-    cwd: &'a Path,
-    stack: Vec<OsString>,
-}
-
-impl<'a> ExpandIncludeFile<'a> {
-    pub fn new(cwd: &'a Path, args: &[OsString]) -> Self {
-        ExpandIncludeFile {
-            stack: args.iter().rev().map(|a| a.to_owned()).collect(),
-            cwd,
-        }
-    }
-}
-
-impl<'a> Iterator for ExpandIncludeFile<'a> {
-    type Item = OsString;
-
-    fn next(&mut self) -> Option<OsString> {
-        loop {
-            let arg = match self.stack.pop() {
-                Some(arg) => arg,
-                None => return None,
-            };
-            let file = match arg.split_prefix("@") {
-                Some(arg) => self.cwd.join(&arg),
-                None => return Some(arg),
-            };
-
-            // According to gcc [1], @file means:
-            //
-            //     Read command-line options from file. The options read are
-            //     inserted in place of the original @file option. If file does
-            //     not exist, or cannot be read, then the option will be
-            //     treated literally, and not removed.
-            //
-            //     Options in file are separated by whitespace. A
-            //     whitespace character may be included in an option by
-            //     surrounding the entire option in either single or double
-            //     quotes. Any character (including a backslash) may be
-            //     included by prefixing the character to be included with
-            //     a backslash. The file may itself contain additional
-            //     @file options; any such options will be processed
-            //     recursively.
-            //
-            // So here we interpret any I/O errors as "just return this
-            // argument". Currently we don't implement handling of arguments
-            // with quotes, so if those are encountered we just pass the option
-            // through literally anyway.
-            //
-            // At this time we interpret all `@` arguments above as non
-            // cacheable, so if we fail to interpret this we'll just call the
-            // compiler anyway.
-            //
-            // [1]: https://gcc.gnu.org/onlinedocs/gcc/Overall-Options.html#Overall-Options
-            let mut contents = String::new();
-            let res = File::open(&file).and_then(|mut f| f.read_to_string(&mut contents));
-            if let Err(e) = res {
-                debug!("failed to read @-file `{}`: {}", file.display(), e);
-                return Some(arg);
-            }
-            if contents.contains('"') || contents.contains('\'') {
-                return Some(arg);
-            }
-            let new_args = contents.split_whitespace().collect::<Vec<_>>();
-            self.stack.extend(new_args.iter().rev().map(|s| s.into()));
-        }
-    }
-}
-
 #[cfg(test)]
 mod test {
     use std::fs::File;
@@ -735,7 +1019,8 @@ mod test {
     use crate::compiler::*;
     use crate::mock_command::*;
     use crate::test::utils::*;
-    use futures::Future;
+    use crate::util::Digest;
+    use futures::executor::block_on;
 
     fn parse_arguments_(
         arguments: Vec<String>,
@@ -803,6 +1088,56 @@ mod test {
         assert_map_contains!(outputs, ("obj", PathBuf::from("foo.o")));
     }
 
+    #[test]
+    fn test_parse_arguments_assembler() {
+        let args = stringvec!["-c", "foo.S", "-o", "foo.o"];
+        match parse_arguments_(args, false) {
+            CompilerArguments::Ok(args) => {
+                assert_eq!(Language::AssemblerWithCpp, args.language)
+            }
+            o => panic!("Got unexpected parse result: {:?}", o),
+        }
+        let args = stringvec!["-c", "foo.s", "-o", "foo.o"];
+        match parse_arguments_(args, false) {
+            CompilerArguments::Ok(args) => assert_eq!(Language::Assembler, args.language),
+            o => panic!("Got unexpected parse result: {:?}", o),
+        }
+        let args = stringvec!["-c", "-x", "assembler-with-cpp", "foo.asm", "-o", "foo.o"];
+        match parse_arguments_(args, false) {
+            CompilerArguments::Ok(args) => {
+                assert_eq!(Language::AssemblerWithCpp, args.language)
+            }
+            o => panic!("Got unexpected parse result: {:?}", o),
+        }
+    }
+
+    #[test]
+    fn test_preprocess_assembler() {
+        let creator = new_creator();
+        let f = TestFixture::new();
+        File::create(f.tempdir.path().join("foo.s"))
+            .unwrap()
+            .write_all(b"nop\n")
+            .unwrap();
+        let parsed_args = match parse_arguments_(stringvec!["-c", "foo.s"], false) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        // The compiler is not run for assembly without preprocessing.
+        let output = block_on(preprocess(
+            &creator,
+            &f.bins[0],
+            &parsed_args,
+            f.tempdir.path(),
+            &[],
+            false,
+            CCompilerKind::GCC,
+            false,
+        ))
+        .unwrap();
+        assert_eq!(&b"nop\n"[..], &output.stdout[..]);
+    }
+
     #[test]
     fn test_parse_arguments_split_dwarf() {
         let args = stringvec!["-gsplit-dwarf", "-c", "foo.cpp", "-o", "foo.o"];
@@ -830,6 +1165,62 @@ mod test {
         assert!(!msvc_show_includes);
     }
 
+    #[test]
+    fn test_parse_arguments_split_dwarf_modes() {
+        fn outputs_of(args: Vec<String>) -> HashMap<&'static str, PathBuf> {
+            match parse_arguments_(args, false) {
+                CompilerArguments::Ok(args) => args.outputs,
+                o => panic!("Got unexpected parse result: {:?}", o),
+            }
+        }
+        let outputs = outputs_of(stringvec!["-gsplit-dwarf=split", "-c", "foo.c"]);
+        assert_map_contains!(
+            outputs,
+            ("obj", PathBuf::from("foo.o")),
+            ("dwo", PathBuf::from("foo.dwo"))
+        );
+        let outputs = outputs_of(stringvec!["-gsplit-dwarf=single", "-c", "foo.c"]);
+        assert!(!outputs.contains_key("dwo"));
+        let outputs = outputs_of(stringvec![
+            "-gsplit-dwarf",
+            "-gno-split-dwarf",
+            "-c",
+            "foo.c"
+        ]);
+        assert!(!outputs.contains_key("dwo"));
+        assert_eq!(
+            CompilerArguments::CannotCache("-gsplit-dwarf", Some("-gsplit-dwarf=bogus".to_owned())),
+            parse_arguments_(stringvec!["-gsplit-dwarf=bogus", "-c", "foo.c"], false)
+        );
+    }
+
+    #[test]
+    fn test_parse_arguments_split_dwarf_debug_types_section() {
+        let args = stringvec![
+            "-gsplit-dwarf",
+            "-fdebug-types-section",
+            "-c",
+            "foo.cpp",
+            "-o",
+            "out/foo.o"
+        ];
+        let ParsedArguments {
+            outputs,
+            common_args,
+            ..
+        } = match parse_arguments_(args, false) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        // Type units go to the `.dwo` alongside the rest of the split debug info.
+        assert_map_contains!(
+            outputs,
+            ("obj", PathBuf::from("out/foo.o")),
+            ("dwo", PathBuf::from("out/foo.dwo"))
+        );
+        assert_eq!(ovec!["-gsplit-dwarf", "-fdebug-types-section"], common_args);
+    }
+
     #[test]
     fn test_parse_arguments_linker_options() {
         let args = stringvec![
@@ -894,6 +1285,42 @@ mod test {
         assert!(profile_generate);
     }
 
+    #[test]
+    fn test_parse_arguments_coverage_profile_update() {
+        let args = stringvec![
+            "-fprofile-arcs",
+            "-ftest-coverage",
+            "-fprofile-update=atomic",
+            "-c",
+            "foo.cpp",
+            "-o",
+            "obj/foo.o"
+        ];
+        let ParsedArguments {
+            outputs,
+            common_args,
+            profile_generate,
+            ..
+        } = match parse_arguments_(args, false) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_map_contains!(
+            outputs,
+            ("obj", PathBuf::from("obj/foo.o")),
+            ("gcno", PathBuf::from("obj/foo.gcno"))
+        );
+        assert_eq!(
+            ovec![
+                "-fprofile-arcs",
+                "-ftest-coverage",
+                "-fprofile-update=atomic"
+            ],
+            common_args
+        );
+        assert!(profile_generate);
+    }
+
     #[test]
     fn test_parse_arguments_test_coverage_outputs_gcno() {
         let args = stringvec!["-ftest-coverage", "-c", "foo.cpp", "-o", "foo.o"];
@@ -1186,7 +1613,7 @@ mod test {
     #[test]
     fn test_parse_arguments_too_many_inputs() {
         assert_eq!(
-            CompilerArguments::CannotCache("multiple input files", None),
+            CompilerArguments::CannotCache("multiple input files", Some("bar.c".to_owned())),
             parse_arguments_(stringvec!["-c", "foo.c", "-o", "foo.o", "bar.c"], false)
         );
     }
@@ -1204,19 +1631,97 @@ mod test {
 
     #[test]
     fn test_parse_arguments_pgo() {
+        let f = TestFixture::new();
+        let parse = |args: Vec<String>| {
+            let args = args.iter().map(OsString::from).collect::<Vec<_>>();
+            match parse_arguments(&args, f.tempdir.path(), &ARGS[..], false) {
+                CompilerArguments::Ok(args) => args,
+                o => panic!("Got unexpected parse result: {:?}", o),
+            }
+        };
+        // No profile data yet: nothing to hash.
+        let a = parse(stringvec![
+            "-c",
+            "foo.c",
+            "-fprofile-use",
+            "-o",
+            "obj/foo.o"
+        ]);
+        assert_eq!(ovec!["-fprofile-use"], a.common_args);
+        assert!(a.extra_hash_files.is_empty());
+
+        let obj = f.tempdir.path().join("obj");
+        fs::create_dir(&obj).unwrap();
+        let gcda = f.touch("obj/foo.gcda").unwrap();
+        let a = parse(stringvec![
+            "-c",
+            "foo.c",
+            "-fprofile-use",
+            "-o",
+            "obj/foo.o"
+        ]);
+        assert_eq!(vec![gcda], a.extra_hash_files);
+
+        let profdata = f.touch("merged.profdata").unwrap();
+        let a = parse(stringvec!["-c", "foo.c", "-fprofile-use=merged.profdata"]);
+        assert_eq!(vec![profdata], a.extra_hash_files);
+
+        fs::create_dir(f.tempdir.path().join("prof")).unwrap();
+        let default = f.touch("prof/default.profdata").unwrap();
+        let a = parse(stringvec!["-c", "foo.c", "-fprofile-use=prof"]);
+        assert_eq!(vec![default], a.extra_hash_files);
+
+        let mangled = obj
+            .join("foo.gcda")
+            .to_string_lossy()
+            .replace(std::path::MAIN_SEPARATOR, "#");
+        let gcda = f.touch(&format!("prof/{}", mangled)).unwrap();
+        let a = parse(stringvec![
+            "-c",
+            "foo.c",
+            "-fprofile-use",
+            "-fprofile-dir=prof",
+            "-o",
+            "obj/foo.o"
+        ]);
+        assert_eq!(ovec!["-fprofile-use", "-fprofile-dir=prof"], a.common_args);
         assert_eq!(
-            CompilerArguments::CannotCache("-fprofile-use", None),
-            parse_arguments_(
-                stringvec!["-c", "foo.c", "-fprofile-use", "-o", "foo.o"],
-                false
-            )
+            vec![f.tempdir.path().join("prof/default.profdata"), gcda],
+            a.extra_hash_files
+        );
+    }
+
+    #[test]
+    fn test_parse_arguments_save_temps() {
+        let a = match parse_arguments_(
+            stringvec!["-save-temps", "-c", "src/foo.cpp", "-o", "obj/foo.o"],
+            false,
+        ) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(ovec!["-save-temps"], a.common_args);
+        assert_map_contains!(
+            a.outputs,
+            ("obj", PathBuf::from("obj/foo.o")),
+            ("temp-preprocessed", PathBuf::from("foo.ii")),
+            ("temp-assembly", PathBuf::from("foo.s"))
+        );
+        let a = match parse_arguments_(
+            stringvec!["--save-temps=obj", "-c", "src/foo.S", "-o", "obj/foo.o"],
+            false,
+        ) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_map_contains!(
+            a.outputs,
+            ("obj", PathBuf::from("obj/foo.o")),
+            ("temp-preprocessed", PathBuf::from("obj/foo.s"))
         );
         assert_eq!(
-            CompilerArguments::CannotCache("-fprofile-use", None),
-            parse_arguments_(
-                stringvec!["-c", "foo.c", "-fprofile-use=file", "-o", "foo.o"],
-                false
-            )
+            CompilerArguments::CannotCache("-save-temps", Some("-save-temps=elsewhere".to_owned())),
+            parse_arguments_(stringvec!["-save-temps=elsewhere", "-c", "foo.c"], false)
         );
     }
 
@@ -1227,7 +1732,7 @@ mod test {
             parse_arguments_(stringvec!["-c", "foo.c", "@foo", "-o", "foo.o"], false)
         );
         assert_eq!(
-            CompilerArguments::CannotCache("@", None),
+            CompilerArguments::CannotCache("@", Some("@foo".to_owned())),
             parse_arguments_(stringvec!["-c", "foo.c", "-o", "@foo"], false)
         );
     }
@@ -1238,19 +1743,112 @@ mod test {
             stringvec!["-arch", "arm64", "-o", "foo.o", "-c", "foo.cpp"],
             false,
         ) {
-            CompilerArguments::Ok(_) => {}
+            CompilerArguments::Ok(args) => assert_eq!(ovec!["arm64"], args.arch_args),
             o => panic!("Got unexpected parse result: {:?}", o),
         }
 
+        let ParsedArguments {
+            arch_args,
+            common_args,
+            ..
+        } = match parse_arguments_(
+            stringvec![
+                "-fPIC", "-arch", "arm64", "-arch", "x86_64", "-o", "foo.o", "-c", "foo.cpp"
+            ],
+            false,
+        ) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(ovec!["arm64", "x86_64"], arch_args);
         assert_eq!(
-            CompilerArguments::CannotCache("multiple -arch", None),
-            parse_arguments_(
-                stringvec![
-                    "-fPIC", "-arch", "arm64", "-arch", "i386", "-o", "foo.o", "-c", "foo.cpp"
-                ],
-                false
-            )
+            ovec!["-fPIC", "-arch", "arm64", "-arch", "x86_64"],
+            common_args
         );
+        assert_eq!(ovec!["-fPIC"], strip_arch_args(&common_args));
+    }
+
+    #[test]
+    fn test_preprocess_hashed() {
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let parsed_args = match parse_arguments_(stringvec!["-c", "foo.c"], false) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "preprocessed", "")),
+        );
+        let (output, digest) = block_on(preprocess_hashed(
+            &creator,
+            &f.bins[0],
+            &parsed_args,
+            f.tempdir.path(),
+            &[],
+            false,
+            CCompilerKind::GCC,
+            false,
+        ))
+        .unwrap();
+        // Hashed as it was read
+        let mut m = Digest::new();
+        m.update(b"preprocessed");
+        assert_eq!(&b"preprocessed"[..], &output.stdout[..]);
+        assert_eq!(digest, Some(m.finish()));
+    }
+
+    #[test]
+    fn test_preprocess_multiple_arch() {
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let parsed_args = match parse_arguments_(
+            stringvec!["-arch", "arm64", "-arch", "x86_64", "-c", "foo.c"],
+            false,
+        ) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "arm64 code", "")),
+        );
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "x86_64 code", "")),
+        );
+        let output = block_on(preprocess(
+            &creator,
+            &f.bins[0],
+            &parsed_args,
+            f.tempdir.path(),
+            &[],
+            false,
+            CCompilerKind::Clang,
+            false,
+        ))
+        .unwrap();
+        assert_eq!(
+            &b"# sccache-arch arm64\narm64 code# sccache-arch x86_64\nx86_64 code"[..],
+            &output.stdout[..]
+        );
+        // Both preprocessor runs happened.
+        assert_eq!(0, creator.lock().unwrap().children.len());
+    }
+
+    #[test]
+    fn test_side_output_args() {
+        assert!(is_side_output_arg("-ftime-trace".as_ref()));
+        assert!(is_side_output_arg("-ftime-trace-granularity=10".as_ref()));
+        assert!(is_side_output_arg(
+            "-fsave-optimization-record=yaml".as_ref()
+        ));
+        assert!(is_side_output_arg(
+            "-foptimization-record-passes=inline".as_ref()
+        ));
+        assert!(is_side_output_arg("-save-temps=obj".as_ref()));
+        assert!(!is_side_output_arg("-gsplit-dwarf".as_ref()));
+        assert!(!is_side_output_arg("-fPIC".as_ref()));
     }
 
     #[test]
@@ -1288,6 +1886,38 @@ mod test {
         assert!(!msvc_show_includes);
     }
 
+    #[test]
+    fn at_signs_quoted_and_nested() {
+        let td = tempfile::Builder::new()
+            .prefix("sccache")
+            .tempdir()
+            .unwrap();
+        let inner = td.path().join("inner");
+        std::fs::write(&inner, b"-DNAME='\"a b\"' -o 'my out.o'").unwrap();
+        std::fs::write(
+            td.path().join("outer"),
+            // Backslashes in the path are escaped as GCC reads them
+            format!(
+                "-c \"my file.c\" @{}",
+                inner.display().to_string().replace('\\', "\\\\")
+            ),
+        )
+        .unwrap();
+        let arg = format!("@{}", td.path().join("outer").display());
+        let ParsedArguments {
+            input,
+            outputs,
+            common_args,
+            ..
+        } = match parse_arguments_(vec![arg], false) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(Some("my file.c"), input.to_str());
+        assert_map_contains!(outputs, ("obj", PathBuf::from("my out.o")));
+        assert_eq!(ovec!["-DNAME=\"a b\""], common_args);
+    }
+
     #[test]
     fn test_compile_simple() {
         let creator = new_creator();
@@ -1305,6 +1935,9 @@ mod test {
             msvc_show_includes: false,
             profile_generate: false,
             color_mode: ColorMode::Auto,
+            arch_args: vec![],
+            compilation_db_fragment: None,
+            index_args: vec![],
         };
         let compiler = &f.bins[0];
         // Compiler invocation.
@@ -1324,7 +1957,7 @@ mod test {
         assert!(dist_command.is_some());
         #[cfg(not(feature = "dist-client"))]
         assert!(dist_command.is_none());
-        let _ = command.execute(&creator).wait();
+        let _ = block_on(command.execute(&creator));
         assert_eq!(Cacheable::Yes, cacheable);
         // Ensure that we ran all processes.
         assert_eq!(0, creator.lock().unwrap().children.len());
@@ -0,0 +1,128 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compiling C and C++ on an icecream farm: with `SCCACHE_ICECC` set to the
+//! `icecc` executable, local GCC and clang compiles, such as those of cache
+//! misses, are run through it, and the local icecream daemon sends them to
+//! the farm's scheduler. icecream packages the toolchain itself, or uses the
+//! one `ICECC_VERSION` names, which `SCCACHE_ICECC_VERSION` sets for
+//! compiles that don't set it.
+
+use crate::compiler::c::CCompilerKind;
+use crate::compiler::CompileCommand;
+use std::env;
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+lazy_static! {
+    static ref ICECC: Option<Icecc> = Icecc::from_env();
+}
+
+struct Icecc {
+    executable: PathBuf,
+    /// The toolchain archive for compiles that don't name one.
+    version: Option<OsString>,
+}
+
+impl Icecc {
+    fn from_env() -> Option<Icecc> {
+        let name = env::var_os("SCCACHE_ICECC").filter(|name| !name.is_empty())?;
+        let executable = match which::which(&name) {
+            Ok(executable) => executable,
+            Err(e) => {
+                warn!(
+                    "Can't find SCCACHE_ICECC {:?}, compiling locally: {}",
+                    name, e
+                );
+                return None;
+            }
+        };
+        info!("Compiling C and C++ through {}", executable.display());
+        Some(Icecc {
+            executable,
+            version: env::var_os("SCCACHE_ICECC_VERSION").filter(|version| !version.is_empty()),
+        })
+    }
+
+    /// `command` run through `icecc`, if icecream can compile it.
+    fn wrap(&self, kind: CCompilerKind, mut command: CompileCommand) -> CompileCommand {
+        match kind {
+            CCompilerKind::GCC | CCompilerKind::Clang => {}
+            // icecream only distributes GCC and clang
+            _ => return command,
+        }
+        let compiler = std::mem::replace(&mut command.executable, self.executable.clone());
+        command.arguments.insert(0, compiler.into_os_string());
+        if let Some(ref version) = self.version {
+            if !command.env_vars.iter().any(|(k, _)| k == "ICECC_VERSION") {
+                command
+                    .env_vars
+                    .push(("ICECC_VERSION".into(), version.clone()));
+            }
+        }
+        command
+    }
+}
+
+/// The local compile `command` of a compiler of `kind`, run through `icecc`
+/// if `SCCACHE_ICECC` is set.
+pub fn wrap(kind: CCompilerKind, command: CompileCommand) -> CompileCommand {
+    match *ICECC {
+        Some(ref icecc) => icecc.wrap(kind, command),
+        None => command,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wrap() {
+        let icecc = Icecc {
+            executable: "/usr/bin/icecc".into(),
+            version: Some("/opt/gcc.tar.gz".into()),
+        };
+        let command = || CompileCommand {
+            executable: "/usr/bin/gcc".into(),
+            arguments: vec!["-c".into(), "foo.c".into()],
+            env_vars: vec![],
+            cwd: "/src".into(),
+        };
+
+        let wrapped = icecc.wrap(CCompilerKind::GCC, command());
+        assert_eq!(wrapped.executable, PathBuf::from("/usr/bin/icecc"));
+        assert_eq!(wrapped.arguments, ovec!["/usr/bin/gcc", "-c", "foo.c"]);
+        assert_eq!(
+            wrapped.env_vars,
+            vec![("ICECC_VERSION".into(), "/opt/gcc.tar.gz".into())]
+        );
+
+        // A toolchain the compile names is kept
+        let mut named = command();
+        named
+            .env_vars
+            .push(("ICECC_VERSION".into(), "/opt/other.tar.gz".into()));
+        let wrapped = icecc.wrap(CCompilerKind::Clang, named);
+        assert_eq!(
+            wrapped.env_vars,
+            vec![("ICECC_VERSION".into(), "/opt/other.tar.gz".into())]
+        );
+
+        // Compilers icecream doesn't distribute are left alone
+        let wrapped = icecc.wrap(CCompilerKind::MSVC, command());
+        assert_eq!(wrapped.executable, PathBuf::from("/usr/bin/gcc"));
+        assert_eq!(wrapped.arguments, ovec!["-c", "foo.c"]);
+    }
+}
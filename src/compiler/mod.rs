@@ -16,13 +16,26 @@
 mod args;
 mod c;
 mod clang;
+mod command;
 #[macro_use]
 #[allow(clippy::module_inception)]
 mod compiler;
 mod diab;
+mod digest_cache;
 mod gcc;
+mod icecc;
 mod msvc;
 mod nvcc;
+mod resource;
+mod response_file;
 mod rust;
+mod ti;
+mod verify;
 
+pub use crate::compiler::c::CCompilerKind;
+pub use crate::compiler::command::CommandHasher;
 pub use crate::compiler::compiler::*;
+pub use crate::compiler::digest_cache::{
+    compiler_digest, save_input_digests, set_compiler_digest_cache, set_input_digest_cache,
+    CompilerDigestCache, InputDigestCache,
+};
@@ -14,14 +14,15 @@
 
 use crate::compiler::args::*;
 use crate::compiler::c::{CCompilerImpl, CCompilerKind, Language, ParsedArguments};
+use crate::compiler::response_file::{ExpandResponseFiles, Quoting};
 use crate::compiler::{
     clang, gcc, write_temp_file, Cacheable, ColorMode, CompileCommand, CompilerArguments,
 };
 use crate::dist;
 use crate::mock_command::{CommandCreatorSync, RunCommand};
 use crate::util::{run_input_output, SpawnExt};
-use futures::future::Future;
-use futures_03::executor::ThreadPool;
+use async_trait::async_trait;
+use futures::executor::ThreadPool;
 use local_encoding::{Encoder, Encoding};
 use log::Level::Debug;
 use std::collections::{HashMap, HashSet};
@@ -43,6 +44,7 @@ pub struct MSVC {
     pub is_clang: bool,
 }
 
+#[async_trait(?Send)]
 impl CCompilerImpl for MSVC {
     fn kind(&self) -> CCompilerKind {
         CCompilerKind::MSVC
@@ -58,7 +60,7 @@ impl CCompilerImpl for MSVC {
         parse_arguments(arguments, cwd, self.is_clang)
     }
 
-    fn preprocess<T>(
+    async fn preprocess<T>(
         &self,
         creator: &T,
         executable: &Path,
@@ -67,7 +69,7 @@ impl CCompilerImpl for MSVC {
         env_vars: &[(OsString, OsString)],
         may_dist: bool,
         rewrite_includes_only: bool,
-    ) -> SFuture<process::Output>
+    ) -> Result<process::Output>
     where
         T: CommandCreatorSync,
     {
@@ -82,6 +84,7 @@ impl CCompilerImpl for MSVC {
             rewrite_includes_only,
             self.is_clang,
         )
+        .await
     }
 
     fn generate_compile_commands(
@@ -102,94 +105,84 @@ fn from_local_codepage(bytes: &[u8]) -> io::Result<String> {
 }
 
 /// Detect the prefix included in the output of MSVC's -showIncludes output.
-pub fn detect_showincludes_prefix<T>(
+pub async fn detect_showincludes_prefix<T>(
     creator: &T,
     exe: &OsStr,
     is_clang: bool,
     env: Vec<(OsString, OsString)>,
     pool: &ThreadPool,
-) -> SFuture<String>
+) -> Result<String>
 where
     T: CommandCreatorSync,
 {
-    let write = write_temp_file(pool, "test.c".as_ref(), b"#include \"test.h\"\n".to_vec());
-
-    let exe = exe.to_os_string();
-    let mut creator = creator.clone();
-    let pool = pool.clone();
-    let write2 = write.and_then(move |(tempdir, input)| {
-        let header = tempdir.path().join("test.h");
-        pool.spawn_fn(move || -> Result<_> {
-            let mut file = File::create(&header)?;
-            file.write_all(b"/* empty */\n")?;
-            Ok((tempdir, input))
-        })
-        .fcontext("failed to write temporary file")
-    });
-    let output = write2.and_then(move |(tempdir, input)| {
-        let mut cmd = creator.new_command_sync(&exe);
-        // clang.exe on Windows reports the same set of built-in preprocessor defines as clang-cl,
-        // but it doesn't accept MSVC commandline arguments unless you pass --driver-mode=cl.
-        // clang-cl.exe will accept this argument as well, so always add it in this case.
-        if is_clang {
-            cmd.arg("--driver-mode=cl");
-        }
-        cmd.args(&["-nologo", "-showIncludes", "-c", "-Fonul", "-I."])
-            .arg(&input)
-            .current_dir(&tempdir.path())
-            // The MSDN docs say the -showIncludes output goes to stderr,
-            // but that's not true unless running with -E.
-            .stdout(Stdio::piped())
-            .stderr(Stdio::null());
-        for (k, v) in env {
-            cmd.env(k, v);
-        }
-        trace!("detect_showincludes_prefix: {:?}", cmd);
+    let (tempdir, input) =
+        write_temp_file(pool, "test.c".as_ref(), b"#include \"test.h\"\n".to_vec()).await?;
+
+    let header = tempdir.path().join("test.h");
+    pool.spawn_fn(move || -> Result<_> {
+        let mut file = File::create(&header)?;
+        file.write_all(b"/* empty */\n")?;
+        Ok(())
+    })
+    .await
+    .context("failed to write temporary file")?;
+
+    let mut cmd = creator.clone().new_command_sync(&exe);
+    // clang.exe on Windows reports the same set of built-in preprocessor defines as clang-cl,
+    // but it doesn't accept MSVC commandline arguments unless you pass --driver-mode=cl.
+    // clang-cl.exe will accept this argument as well, so always add it in this case.
+    if is_clang {
+        cmd.arg("--driver-mode=cl");
+    }
+    cmd.args(&["-nologo", "-showIncludes", "-c", "-Fonul", "-I."])
+        .arg(&input)
+        .current_dir(&tempdir.path())
+        // The MSDN docs say the -showIncludes output goes to stderr,
+        // but that's not true unless running with -E.
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+    for (k, v) in env {
+        cmd.env(k, v);
+    }
+    trace!("detect_showincludes_prefix: {:?}", cmd);
 
-        run_input_output(cmd, None).map(|e| {
-            // Keep the tempdir around so test.h still exists for the
-            // checks below.
-            (e, tempdir)
-        })
-    });
+    // Keep the tempdir around so test.h still exists for the checks below.
+    let output = run_input_output(cmd, None).await?;
+    if !output.status.success() {
+        bail!("Failed to detect showIncludes prefix")
+    }
 
-    Box::new(output.and_then(|(output, tempdir)| {
-        if !output.status.success() {
-            bail!("Failed to detect showIncludes prefix")
+    let process::Output {
+        stdout: stdout_bytes,
+        ..
+    } = output;
+    let stdout = from_local_codepage(&stdout_bytes)
+        .context("Failed to convert compiler stdout while detecting showIncludes prefix")?;
+    for line in stdout.lines() {
+        if !line.ends_with("test.h") {
+            continue;
         }
-
-        let process::Output {
-            stdout: stdout_bytes,
-            ..
-        } = output;
-        let stdout = from_local_codepage(&stdout_bytes)
-            .context("Failed to convert compiler stdout while detecting showIncludes prefix")?;
-        for line in stdout.lines() {
-            if !line.ends_with("test.h") {
+        for (i, c) in line.char_indices().rev() {
+            if c != ' ' {
                 continue;
             }
-            for (i, c) in line.char_indices().rev() {
-                if c != ' ' {
-                    continue;
-                }
-                let path = tempdir.path().join(&line[i + 1..]);
-                // See if the rest of this line is a full pathname.
-                if path.exists() {
-                    // Everything from the beginning of the line
-                    // to this index is the prefix.
-                    return Ok(line[..=i].to_owned());
-                }
+            let path = tempdir.path().join(&line[i + 1..]);
+            // See if the rest of this line is a full pathname.
+            if path.exists() {
+                // Everything from the beginning of the line
+                // to this index is the prefix.
+                return Ok(line[..=i].to_owned());
             }
         }
-        drop(tempdir);
+    }
+    drop(tempdir);
 
-        debug!(
-            "failed to detect showIncludes prefix with output: {}",
-            stdout
-        );
+    debug!(
+        "failed to detect showIncludes prefix with output: {}",
+        stdout
+    );
 
-        bail!("Failed to detect showIncludes prefix")
-    }))
+    bail!("Failed to detect showIncludes prefix")
 }
 
 #[cfg(unix)]
@@ -451,6 +444,7 @@ pub fn parse_arguments(
     let mut common_args = vec![];
     let mut preprocessor_args = vec![];
     let mut dependency_args = vec![];
+    let mut index_args = vec![];
     let mut extra_hash_files = vec![];
     let mut compilation = false;
     let mut compilation_flag = OsString::new();
@@ -462,7 +456,10 @@ pub fn parse_arguments(
     let mut clangs: Vec<OsString> = vec![];
     let mut profile_generate = false;
 
-    for arg in ArgsIter::new(arguments.iter().cloned(), (&ARGS[..], &SLASH_ARGS[..])) {
+    // Response files are read with the quoting of Windows command lines, and
+    // any left as `@file` arguments can't be read, so aren't cacheable.
+    let it = ExpandResponseFiles::new(cwd, arguments, Quoting::Windows);
+    for arg in ArgsIter::new(it, (&ARGS[..], &SLASH_ARGS[..])) {
         let arg = try_or_cannot_cache!(arg, "argument parse");
         match arg.get_data() {
             Some(PassThrough) | Some(PassThroughWithPath(_)) | Some(PassThroughWithSuffix(_)) => {}
@@ -505,7 +502,10 @@ pub fn parse_arguments(
                     Argument::Raw(ref val) => {
                         if input_arg.is_some() {
                             // Can't cache compilations with multiple inputs.
-                            cannot_cache!("multiple input files");
+                            cannot_cache!(
+                                "multiple input files",
+                                val.to_string_lossy().into_owned()
+                            );
                         }
                         input_arg = Some(val.clone());
                     }
@@ -579,14 +579,29 @@ pub fn parse_arguments(
         [xclangs, clangs].iter(),
         &[xclang_append, dash_clang_append],
     ) {
-        let it = gcc::ExpandIncludeFile::new(cwd, args);
+        let it = ExpandResponseFiles::new(cwd, args, Quoting::Gnu);
         for arg in ArgsIter::new(it, (&gcc::ARGS[..], &clang::ARGS[..])) {
             let arg = try_or_cannot_cache!(arg, "argument parse");
             // Eagerly bail if it looks like we need to do more complicated work
             use crate::compiler::gcc::ArgData::*;
             let mut args = match arg.get_data() {
-                Some(SplitDwarf) | Some(TestCoverage) | Some(Coverage) | Some(DoCompilation)
-                | Some(Language(_)) | Some(Output(_)) | Some(TooHardFlag) | Some(XClang(_))
+                Some(SplitDwarf(_))
+                | Some(NoSplitDwarf)
+                | Some(ProfileUse(_))
+                | Some(ProfileDir(_))
+                | Some(SaveTemps(_))
+                | Some(SaveTempsWithBitcode(_))
+                | Some(TestCoverage)
+                | Some(Coverage)
+                | Some(DoCompilation)
+                | Some(Language(_))
+                | Some(Output(_))
+                | Some(TooHardFlag)
+                | Some(XClang(_))
+                | Some(TimeTrace(_))
+                | Some(OptimizationRecord(_))
+                | Some(OptimizationRecordFile(_))
+                | Some(CompilationDbFragment(_))
                 | Some(TooHard(_)) => cannot_cache!(arg
                     .flag_str()
                     .unwrap_or("Can't handle complex arguments through clang",)),
@@ -615,6 +630,7 @@ pub fn parse_arguments(
                 Some(DepArgumentPath(_)) | Some(DepTarget(_)) | Some(NeedDepTarget) => {
                     &mut dependency_args
                 }
+                Some(IndexArgument(_)) => &mut index_args,
             };
             // Normalize attributes such as "-I foo", "-D FOO=bar", as
             // "-Ifoo", "-DFOO=bar", etc. and "-includefoo", "idirafterbar" as
@@ -635,8 +651,8 @@ pub fn parse_arguments(
     }
     let (input, language) = match input_arg {
         Some(i) => match Language::from_file_name(Path::new(&i)) {
-            Some(l) => (i.to_owned(), l),
-            None => cannot_cache!("unknown source language"),
+            Some(l) if !l.is_assembler() => (i.to_owned(), l),
+            _ => cannot_cache!("unknown source language", i.to_string_lossy().into_owned()),
         },
         // We can't cache compilation without an input.
         None => cannot_cache!("no input file"),
@@ -679,6 +695,9 @@ pub fn parse_arguments(
         profile_generate,
         // FIXME: implement color_mode for msvc.
         color_mode: ColorMode::Auto,
+        arch_args: vec![],
+        compilation_db_fragment: None,
+        index_args,
     })
 }
 
@@ -718,7 +737,7 @@ fn normpath(path: &str) -> String {
 }
 
 #[allow(clippy::too_many_arguments)]
-pub fn preprocess<T>(
+pub async fn preprocess<T>(
     creator: &T,
     executable: &Path,
     parsed_args: &ParsedArguments,
@@ -728,7 +747,7 @@ pub fn preprocess<T>(
     includes_prefix: &str,
     rewrite_includes_only: bool,
     is_clang: bool,
-) -> SFuture<process::Output>
+) -> Result<process::Output>
 where
     T: CommandCreatorSync,
 {
@@ -765,69 +784,63 @@ where
         debug!("preprocess: {:?}", cmd);
     }
 
-    let parsed_args = parsed_args.clone();
-    let includes_prefix = includes_prefix.to_string();
-    let cwd = cwd.to_owned();
-
-    Box::new(run_input_output(cmd, None).and_then(move |output| {
-        let parsed_args = &parsed_args;
-        if let (Some(ref objfile), &Some(ref depfile)) =
-            (parsed_args.outputs.get("obj"), &parsed_args.depfile)
-        {
-            let f = File::create(cwd.join(depfile))?;
-            let mut f = BufWriter::new(f);
-
-            encode_path(&mut f, &objfile)
-                .with_context(|| format!("Couldn't encode objfile filename: '{:?}'", objfile))?;
-            write!(f, ": ")?;
-            encode_path(&mut f, &parsed_args.input)
-                .with_context(|| format!("Couldn't encode input filename: '{:?}'", objfile))?;
-            write!(f, " ")?;
-            let process::Output {
-                status,
-                stdout,
-                stderr: stderr_bytes,
-            } = output;
-            let stderr = from_local_codepage(&stderr_bytes)
-                .context("Failed to convert preprocessor stderr")?;
-            let mut deps = HashSet::new();
-            let mut stderr_bytes = vec![];
-            for line in stderr.lines() {
-                if line.starts_with(&includes_prefix) {
-                    let dep = normpath(line[includes_prefix.len()..].trim());
-                    trace!("included: {}", dep);
-                    if deps.insert(dep.clone()) && !dep.contains(' ') {
-                        write!(f, "{} ", dep)?;
-                    }
-                    if !parsed_args.msvc_show_includes {
-                        continue;
-                    }
+    let output = run_input_output(cmd, None).await?;
+    if let (Some(ref objfile), &Some(ref depfile)) =
+        (parsed_args.outputs.get("obj"), &parsed_args.depfile)
+    {
+        let f = File::create(cwd.join(depfile))?;
+        let mut f = BufWriter::new(f);
+
+        encode_path(&mut f, &objfile)
+            .with_context(|| format!("Couldn't encode objfile filename: '{:?}'", objfile))?;
+        write!(f, ": ")?;
+        encode_path(&mut f, &parsed_args.input)
+            .with_context(|| format!("Couldn't encode input filename: '{:?}'", objfile))?;
+        write!(f, " ")?;
+        let process::Output {
+            status,
+            stdout,
+            stderr: stderr_bytes,
+        } = output;
+        let stderr =
+            from_local_codepage(&stderr_bytes).context("Failed to convert preprocessor stderr")?;
+        let mut deps = HashSet::new();
+        let mut stderr_bytes = vec![];
+        for line in stderr.lines() {
+            if line.starts_with(&includes_prefix) {
+                let dep = normpath(line[includes_prefix.len()..].trim());
+                trace!("included: {}", dep);
+                if deps.insert(dep.clone()) && !dep.contains(' ') {
+                    write!(f, "{} ", dep)?;
                 }
-                stderr_bytes.extend_from_slice(line.as_bytes());
-                stderr_bytes.push(b'\n');
-            }
-            writeln!(f)?;
-            // Write extra rules for each dependency to handle
-            // removed files.
-            encode_path(&mut f, &parsed_args.input)
-                .with_context(|| format!("Couldn't encode filename: '{:?}'", parsed_args.input))?;
-            writeln!(f, ":")?;
-            let mut sorted = deps.into_iter().collect::<Vec<_>>();
-            sorted.sort();
-            for dep in sorted {
-                if !dep.contains(' ') {
-                    writeln!(f, "{}:", dep)?;
+                if !parsed_args.msvc_show_includes {
+                    continue;
                 }
             }
-            Ok(process::Output {
-                status,
-                stdout,
-                stderr: stderr_bytes,
-            })
-        } else {
-            Ok(output)
+            stderr_bytes.extend_from_slice(line.as_bytes());
+            stderr_bytes.push(b'\n');
+        }
+        writeln!(f)?;
+        // Write extra rules for each dependency to handle
+        // removed files.
+        encode_path(&mut f, &parsed_args.input)
+            .with_context(|| format!("Couldn't encode filename: '{:?}'", parsed_args.input))?;
+        writeln!(f, ":")?;
+        let mut sorted = deps.into_iter().collect::<Vec<_>>();
+        sorted.sort();
+        for dep in sorted {
+            if !dep.contains(' ') {
+                writeln!(f, "{}:", dep)?;
+            }
         }
-    }))
+        Ok(process::Output {
+            status,
+            stdout,
+            stderr: stderr_bytes,
+        })
+    } else {
+        Ok(output)
+    }
 }
 
 fn generate_compile_commands(
@@ -871,6 +884,7 @@ fn generate_compile_commands(
     arguments.extend(parsed_args.preprocessor_args.clone());
     arguments.extend(parsed_args.dependency_args.clone());
     arguments.extend(parsed_args.common_args.clone());
+    arguments.extend(parsed_args.index_args.clone());
 
     let command = CompileCommand {
         executable: executable.to_owned(),
@@ -916,8 +930,7 @@ mod test {
     use crate::compiler::*;
     use crate::mock_command::*;
     use crate::test::utils::*;
-    use futures::Future;
-    use futures_03::executor::ThreadPool;
+    use futures::executor::{block_on, ThreadPool};
 
     fn parse_arguments(arguments: Vec<OsString>) -> CompilerArguments<ParsedArguments> {
         super::parse_arguments(&arguments, &std::env::current_dir().unwrap(), false)
@@ -942,9 +955,14 @@ mod test {
         );
         assert_eq!(
             "blah: ",
-            detect_showincludes_prefix(&creator, "cl.exe".as_ref(), false, Vec::new(), &pool)
-                .wait()
-                .unwrap()
+            block_on(detect_showincludes_prefix(
+                &creator,
+                "cl.exe".as_ref(),
+                false,
+                Vec::new(),
+                &pool
+            ))
+            .unwrap()
         );
     }
 
@@ -1059,6 +1077,10 @@ mod test {
             "-MT",
             "-Xclang",
             "host_dictionary.obj",
+            "-Xclang",
+            "-index-store-path",
+            "-Xclang",
+            "index",
             "-clang:-fprofile-generate",
             "dictionary.c"
         ];
@@ -1067,6 +1089,7 @@ mod test {
             preprocessor_args,
             common_args,
             profile_generate,
+            index_args,
             ..
         } = match parse_arguments(args) {
             CompilerArguments::Ok(args) => args,
@@ -1090,6 +1113,10 @@ mod test {
             )
         );
         assert_eq!(common_args, ovec!("-clang:-fprofile-generate"));
+        assert_eq!(
+            index_args,
+            ovec!("-Xclang", "-index-store-path", "-Xclang", "index")
+        );
     }
 
     #[test]
@@ -1256,7 +1283,7 @@ mod test {
     #[test]
     fn test_parse_arguments_too_many_inputs() {
         assert_eq!(
-            CompilerArguments::CannotCache("multiple input files", None),
+            CompilerArguments::CannotCache("multiple input files", Some("bar.c".to_owned())),
             parse_arguments(ovec!["-c", "foo.c", "-Fofoo.obj", "bar.c"])
         );
     }
@@ -1285,6 +1312,24 @@ mod test {
             CompilerArguments::CannotCache("@", None),
             parse_arguments(ovec!["-c", "foo.c", "@foo", "-Fofoo.obj"])
         );
+
+        // Visual Studio writes response files in UTF-16
+        let td = tempfile::Builder::new()
+            .prefix("sccache")
+            .tempdir()
+            .unwrap();
+        let mut contents = b"\xff\xfe".to_vec();
+        for unit in "/c \"my file.c\"\r\n/Fo\"my \\\"file\\\".obj\"".encode_utf16() {
+            contents.extend(&unit.to_le_bytes());
+        }
+        std::fs::write(td.path().join("foo.rsp"), contents).unwrap();
+        let arg = format!("@{}", td.path().join("foo.rsp").display());
+        let ParsedArguments { input, outputs, .. } = match parse_arguments(ovec![arg]) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(Some("my file.c"), input.to_str());
+        assert_map_contains!(outputs, ("obj", PathBuf::from("my \"file\".obj")));
     }
 
     #[test]
@@ -1320,6 +1365,9 @@ mod test {
             msvc_show_includes: false,
             profile_generate: false,
             color_mode: ColorMode::Auto,
+            arch_args: vec![],
+            compilation_db_fragment: None,
+            index_args: vec![],
         };
         let compiler = &f.bins[0];
         // Compiler invocation.
@@ -1337,7 +1385,7 @@ mod test {
         assert!(dist_command.is_some());
         #[cfg(not(feature = "dist-client"))]
         assert!(dist_command.is_none());
-        let _ = command.execute(&creator).wait();
+        let _ = block_on(command.execute(&creator));
         assert_eq!(Cacheable::Yes, cacheable);
         // Ensure that we ran all processes.
         assert_eq!(0, creator.lock().unwrap().children.len());
@@ -1363,6 +1411,9 @@ mod test {
             msvc_show_includes: false,
             profile_generate: false,
             color_mode: ColorMode::Auto,
+            arch_args: vec![],
+            compilation_db_fragment: None,
+            index_args: vec![],
         };
         let compiler = &f.bins[0];
         // Compiler invocation.
@@ -1380,7 +1431,7 @@ mod test {
         assert!(dist_command.is_some());
         #[cfg(not(feature = "dist-client"))]
         assert!(dist_command.is_none());
-        let _ = command.execute(&creator).wait();
+        let _ = block_on(command.execute(&creator));
         assert_eq!(Cacheable::No, cacheable);
         // Ensure that we ran all processes.
         assert_eq!(0, creator.lock().unwrap().children.len());
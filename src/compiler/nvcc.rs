@@ -21,7 +21,8 @@ use crate::compiler::{gcc, write_temp_file, Cacheable, CompileCommand, CompilerA
 use crate::dist;
 use crate::mock_command::{CommandCreator, CommandCreatorSync, RunCommand};
 use crate::util::{run_input_output, OsStrExt};
-use futures::future::{self, Future};
+use async_trait::async_trait;
+use futures::future;
 use log::Level::Trace;
 use std::ffi::OsString;
 use std::fs::File;
@@ -35,6 +36,7 @@ use crate::errors::*;
 #[derive(Clone, Debug)]
 pub struct NVCC;
 
+#[async_trait(?Send)]
 impl CCompilerImpl for NVCC {
     fn kind(&self) -> CCompilerKind {
         CCompilerKind::NVCC
@@ -47,10 +49,15 @@ impl CCompilerImpl for NVCC {
         arguments: &[OsString],
         cwd: &Path,
     ) -> CompilerArguments<ParsedArguments> {
-        gcc::parse_arguments(arguments, cwd, (&gcc::ARGS[..], &ARGS[..]), false)
+        match gcc::parse_arguments(arguments, cwd, (&gcc::ARGS[..], &ARGS[..]), false) {
+            CompilerArguments::Ok(args) if args.language.is_assembler() => {
+                CompilerArguments::CannotCache("unknown source language", None)
+            }
+            r => r,
+        }
     }
 
-    fn preprocess<T>(
+    async fn preprocess<T>(
         &self,
         creator: &T,
         executable: &Path,
@@ -59,7 +66,7 @@ impl CCompilerImpl for NVCC {
         env_vars: &[(OsString, OsString)],
         may_dist: bool,
         rewrite_includes_only: bool,
-    ) -> SFuture<process::Output>
+    ) -> Result<process::Output>
     where
         T: CommandCreatorSync,
     {
@@ -69,6 +76,9 @@ impl CCompilerImpl for NVCC {
             Language::ObjectiveC => "objective-c",
             Language::ObjectiveCxx => "objective-c++",
             Language::Cuda => "cu",
+            Language::Assembler | Language::AssemblerWithCpp | Language::Resource => {
+                unreachable!()
+            }
         };
 
         let initialize_cmd_and_args = || {
@@ -131,9 +141,10 @@ impl CCompilerImpl for NVCC {
         if !parsed_args.dependency_args.is_empty() {
             let first = run_input_output(dep_before_preprocessor(), None);
             let second = run_input_output(cmd, None);
-            Box::new(first.join(second).map(|(f, s)| s))
+            let (_, output) = future::try_join(first, second).await?;
+            Ok(output)
         } else {
-            Box::new(run_input_output(cmd, None))
+            run_input_output(cmd, None).await
         }
     }
 
@@ -205,7 +216,7 @@ mod test {
     use crate::compiler::*;
     use crate::mock_command::*;
     use crate::test::utils::*;
-    use futures::Future;
+    use futures::executor::block_on;
     use std::collections::HashMap;
     use std::path::PathBuf;
 
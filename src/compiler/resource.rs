@@ -0,0 +1,817 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compiling Windows resource scripts, with the Windows SDK's `rc.exe` into
+//! `.res` files and with GNU `windres` into `.o` or `.res` files.
+//!
+//! Resource scripts are preprocessed like C, but also read the icons,
+//! bitmaps, manifests and other files their resources are made of, which the
+//! preprocessor output doesn't show. `windres` can decompile a script into
+//! one holding their data, which is hashed instead of the preprocessor
+//! output. For `rc.exe`, which can't, the files named by the resource
+//! statements of its preprocessor output are found and their digests hashed
+//! along with it.
+
+use crate::compiler::args::{
+    ArgDisposition, ArgInfo, ArgToStringResult, ArgsIter, Argument, FromArg, IntoArg,
+    NormalizedDisposition, PathTransformerFn, SearchableArgInfo,
+};
+use crate::compiler::c::{CCompilerImpl, CCompilerKind, Language, ParsedArguments};
+use crate::compiler::response_file;
+use crate::compiler::{Cacheable, ColorMode, CompileCommand, CompilerArguments};
+use crate::dist;
+use crate::mock_command::{CommandCreatorSync, RunCommand};
+use crate::util::{run_input_output, Digest};
+use async_trait::async_trait;
+use log::Level::Trace;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::errors::*;
+
+/// A unit struct on which to implement `CCompilerImpl` for the Windows SDK
+/// resource compiler, `rc.exe`.
+#[derive(Clone, Debug)]
+pub struct RC;
+
+/// A unit struct on which to implement `CCompilerImpl` for GNU `windres`.
+#[derive(Clone, Debug)]
+pub struct Windres;
+
+#[async_trait(?Send)]
+impl CCompilerImpl for RC {
+    fn kind(&self) -> CCompilerKind {
+        CCompilerKind::RC
+    }
+    fn plusplus(&self) -> bool {
+        false
+    }
+    fn parse_arguments(
+        &self,
+        arguments: &[OsString],
+        _cwd: &Path,
+    ) -> CompilerArguments<ParsedArguments> {
+        // rc.exe takes its options after either `/` or `-`
+        let arguments: Vec<OsString> = arguments
+            .iter()
+            .map(|arg| match arg.to_str() {
+                Some(s) if s.starts_with('/') => format!("-{}", &s[1..]).into(),
+                _ => arg.clone(),
+            })
+            .collect();
+        parse_arguments(&arguments, &RC_ARGS[..], CCompilerKind::RC)
+    }
+
+    async fn preprocess<T>(
+        &self,
+        creator: &T,
+        executable: &Path,
+        parsed_args: &ParsedArguments,
+        cwd: &Path,
+        env_vars: &[(OsString, OsString)],
+        _may_dist: bool,
+        _rewrite_includes_only: bool,
+    ) -> Result<process::Output>
+    where
+        T: CommandCreatorSync,
+    {
+        preprocess_rc(creator, executable, parsed_args, cwd, env_vars).await
+    }
+
+    fn generate_compile_commands(
+        &self,
+        _path_transformer: &mut dist::PathTransformer,
+        executable: &Path,
+        parsed_args: &ParsedArguments,
+        cwd: &Path,
+        env_vars: &[(OsString, OsString)],
+        _rewrite_includes_only: bool,
+    ) -> Result<(CompileCommand, Option<dist::CompileCommand>, Cacheable)> {
+        generate_compile_commands(CCompilerKind::RC, executable, parsed_args, cwd, env_vars)
+    }
+}
+
+#[async_trait(?Send)]
+impl CCompilerImpl for Windres {
+    fn kind(&self) -> CCompilerKind {
+        CCompilerKind::Windres
+    }
+    fn plusplus(&self) -> bool {
+        false
+    }
+    fn parse_arguments(
+        &self,
+        arguments: &[OsString],
+        _cwd: &Path,
+    ) -> CompilerArguments<ParsedArguments> {
+        parse_arguments(arguments, &WINDRES_ARGS[..], CCompilerKind::Windres)
+    }
+
+    async fn preprocess<T>(
+        &self,
+        creator: &T,
+        executable: &Path,
+        parsed_args: &ParsedArguments,
+        cwd: &Path,
+        env_vars: &[(OsString, OsString)],
+        _may_dist: bool,
+        _rewrite_includes_only: bool,
+    ) -> Result<process::Output>
+    where
+        T: CommandCreatorSync,
+    {
+        preprocess_windres(creator, executable, parsed_args, cwd, env_vars).await
+    }
+
+    fn generate_compile_commands(
+        &self,
+        _path_transformer: &mut dist::PathTransformer,
+        executable: &Path,
+        parsed_args: &ParsedArguments,
+        cwd: &Path,
+        env_vars: &[(OsString, OsString)],
+        _rewrite_includes_only: bool,
+    ) -> Result<(CompileCommand, Option<dist::CompileCommand>, Cacheable)> {
+        generate_compile_commands(
+            CCompilerKind::Windres,
+            executable,
+            parsed_args,
+            cwd,
+            env_vars,
+        )
+    }
+}
+
+ArgData! { pub
+    Input(PathBuf),
+    Output(PathBuf),
+    PreprocessorArgument(OsString),
+    PreprocessorArgumentPath(PathBuf),
+    PassThrough(OsString),
+    NotCompilationFlag,
+    TooHard(OsString),
+}
+
+use self::ArgData::*;
+
+// The options of rc.exe, spelled with `-` and in the cases build systems
+// use. See "Using RC (The RC Command Line)" in the Windows SDK docs.
+counted_array!(pub static RC_ARGS: [ArgInfo<ArgData>; _] = [
+    flag!("-?", NotCompilationFlag),
+    take_arg!("-D", OsString, CanBeSeparated, PreprocessorArgument),
+    take_arg!("-Fo", PathBuf, CanBeSeparated, Output),
+    take_arg!("-I", PathBuf, CanBeSeparated, PreprocessorArgumentPath),
+    take_arg!("-U", OsString, CanBeSeparated, PreprocessorArgument),
+    take_arg!("-c", OsString, CanBeSeparated, PassThrough),
+    take_arg!("-d", OsString, CanBeSeparated, PreprocessorArgument),
+    take_arg!("-fm", OsString, CanBeSeparated, TooHard),
+    take_arg!("-fo", PathBuf, CanBeSeparated, Output),
+    flag!("-h", NotCompilationFlag),
+    take_arg!("-i", PathBuf, CanBeSeparated, PreprocessorArgumentPath),
+    take_arg!("-l", OsString, CanBeSeparated, PassThrough),
+    flag!("-p", NotCompilationFlag),
+    take_arg!("-q", OsString, CanBeSeparated, TooHard),
+    take_arg!("-u", OsString, CanBeSeparated, PreprocessorArgument),
+]);
+
+// See "windres" in the GNU binutils docs.
+counted_array!(pub static WINDRES_ARGS: [ArgInfo<ArgData>; _] = [
+    take_arg!("--codepage", OsString, CanBeSeparated('='), PassThrough),
+    take_arg!("--define", OsString, CanBeSeparated('='), PreprocessorArgument),
+    flag!("--help", NotCompilationFlag),
+    take_arg!("--include-dir", PathBuf, CanBeSeparated('='), PreprocessorArgumentPath),
+    take_arg!("--input", PathBuf, CanBeSeparated('='), Input),
+    take_arg!("--input-format", OsString, CanBeSeparated('='), PassThrough),
+    take_arg!("--language", OsString, CanBeSeparated('='), PassThrough),
+    take_arg!("--output", PathBuf, CanBeSeparated('='), Output),
+    take_arg!("--output-format", OsString, CanBeSeparated('='), PassThrough),
+    take_arg!("--preprocessor", OsString, CanBeSeparated('='), PreprocessorArgument),
+    take_arg!("--preprocessor-arg", OsString, CanBeSeparated('='), PreprocessorArgument),
+    take_arg!("--target", OsString, CanBeSeparated('='), PassThrough),
+    take_arg!("--undefine", OsString, CanBeSeparated('='), PreprocessorArgument),
+    flag!("--version", NotCompilationFlag),
+    take_arg!("-D", OsString, CanBeSeparated, PreprocessorArgument),
+    take_arg!("-F", OsString, CanBeSeparated, PassThrough),
+    take_arg!("-I", PathBuf, CanBeSeparated, PreprocessorArgumentPath),
+    take_arg!("-J", OsString, CanBeSeparated, PassThrough),
+    take_arg!("-O", OsString, CanBeSeparated, PassThrough),
+    take_arg!("-U", OsString, CanBeSeparated, PreprocessorArgument),
+    flag!("-V", NotCompilationFlag),
+    take_arg!("-c", OsString, CanBeSeparated, PassThrough),
+    flag!("-h", NotCompilationFlag),
+    take_arg!("-i", PathBuf, CanBeSeparated, Input),
+    take_arg!("-l", OsString, CanBeSeparated, PassThrough),
+    take_arg!("-o", PathBuf, CanBeSeparated, Output),
+]);
+
+/// Parse `arguments` of a resource compiler of `kind`, determining whether
+/// it is supported.
+///
+/// If any of the entries in `arguments` result in a compilation that
+/// cannot be cached, return `CompilerArguments::CannotCache`.
+/// If the commandline described by `arguments` is not compilation,
+/// return `CompilerArguments::NotCompilation`.
+/// Otherwise, return `CompilerArguments::Ok(ParsedArguments)`, with
+/// the `ParsedArguments` struct containing information parsed from
+/// `arguments`.
+pub fn parse_arguments<S>(
+    arguments: &[OsString],
+    arg_info: S,
+    kind: CCompilerKind,
+) -> CompilerArguments<ParsedArguments>
+where
+    S: SearchableArgInfo<ArgData>,
+{
+    let mut common_args = vec![];
+    let mut preprocessor_args = vec![];
+    let mut input_arg = None;
+    let mut output_arg = None;
+    let mut positional = vec![];
+
+    for arg in ArgsIter::new(arguments.iter().cloned(), arg_info) {
+        let arg = try_or_cannot_cache!(arg, "argument parse");
+        match arg.get_data() {
+            Some(TooHard(_)) => {
+                cannot_cache!(arg.flag_str().expect("Can't be Argument::Raw/UnknownFlag",))
+            }
+            Some(NotCompilationFlag) => return CompilerArguments::NotCompilation,
+            Some(Input(p)) => {
+                if input_arg.is_some() {
+                    cannot_cache!("multiple input files");
+                }
+                input_arg = Some(p.clone());
+            }
+            Some(Output(p)) => output_arg = Some(p.clone()),
+            Some(PreprocessorArgument(_))
+            | Some(PreprocessorArgumentPath(_))
+            | Some(PassThrough(_)) => {}
+            None => match arg {
+                Argument::Raw(ref val) => positional.push(PathBuf::from(val)),
+                Argument::UnknownFlag(_) => {}
+                _ => unreachable!(),
+            },
+        }
+        let args = match arg.get_data() {
+            Some(PreprocessorArgument(_)) | Some(PreprocessorArgumentPath(_)) => {
+                &mut preprocessor_args
+            }
+            Some(PassThrough(_)) => &mut common_args,
+            Some(Input(_)) | Some(Output(_)) => continue,
+            Some(NotCompilationFlag) | Some(TooHard(_)) => unreachable!(),
+            None => match arg {
+                Argument::Raw(_) => continue,
+                Argument::UnknownFlag(_) => &mut common_args,
+                _ => unreachable!(),
+            },
+        };
+        // Normalize attributes such as "-I foo", "-D FOO=bar", as
+        // "-Ifoo", "-DFOO=bar".
+        let norm = match arg.flag_str() {
+            Some(s) if s.len() == 2 => NormalizedDisposition::Concatenated,
+            _ => NormalizedDisposition::Separated,
+        };
+        args.extend(arg.normalize(norm).iter_os_strings());
+    }
+
+    // windres takes its input and output as positional arguments too, as
+    // CMake passes them.
+    let mut positional = positional.into_iter();
+    let input = match input_arg.or_else(|| positional.next()) {
+        Some(input) => input,
+        None => cannot_cache!("no input file"),
+    };
+    if kind == CCompilerKind::Windres && output_arg.is_none() {
+        output_arg = positional.next();
+    }
+    if positional.next().is_some() {
+        cannot_cache!("multiple input files");
+    }
+    let output = match output_arg {
+        Some(output) => output,
+        // rc.exe writes the `.res` next to the script
+        None if kind == CCompilerKind::RC => input.with_extension("res"),
+        None => cannot_cache!("output to stdout"),
+    };
+
+    let mut outputs = HashMap::new();
+    outputs.insert("obj", output);
+
+    CompilerArguments::Ok(ParsedArguments {
+        input,
+        language: Language::Resource,
+        compilation_flag: OsString::new(),
+        depfile: None,
+        outputs,
+        dependency_args: vec![],
+        preprocessor_args,
+        common_args,
+        extra_hash_files: vec![],
+        msvc_show_includes: false,
+        profile_generate: false,
+        color_mode: ColorMode::Auto,
+        arch_args: vec![],
+        compilation_db_fragment: None,
+        index_args: vec![],
+    })
+}
+
+/// The directories rc.exe looks for the files of resources in: the working
+/// directory, the script's directory, the include directories of the
+/// command line and then those of `INCLUDE`, unless `-x` is given.
+fn search_path(
+    parsed_args: &ParsedArguments,
+    cwd: &Path,
+    env_vars: &[(OsString, OsString)],
+) -> Vec<PathBuf> {
+    let mut dirs = vec![cwd.to_owned()];
+    if let Some(dir) = parsed_args.input.parent() {
+        dirs.push(cwd.join(dir));
+    }
+    for arg in &parsed_args.preprocessor_args {
+        let arg = arg.to_string_lossy();
+        if arg.starts_with("-i") || arg.starts_with("-I") {
+            dirs.push(cwd.join(&arg[2..]));
+        }
+    }
+    if !parsed_args.common_args.iter().any(|arg| arg == "-x") {
+        let include = env_vars
+            .iter()
+            .find(|(k, _)| k.to_string_lossy().eq_ignore_ascii_case("INCLUDE"));
+        if let Some((_, include)) = include {
+            dirs.extend(env::split_paths(include).map(|dir| cwd.join(dir)));
+        }
+    }
+    dirs
+}
+
+/// Split a line of a resource script into its tokens, or return `None` if it
+/// has commas, which separate the values of statements that don't name
+/// files.
+fn tokenize(line: &str) -> Option<Vec<String>> {
+    let mut tokens = vec![];
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == ',' {
+            return None;
+        } else if c == '"' {
+            chars.next();
+            let mut token = String::new();
+            while let Some(c) = chars.next() {
+                match c {
+                    // Two quotes in a string are one included
+                    '"' if chars.peek() == Some(&'"') => token.extend(chars.next()),
+                    '"' => break,
+                    c => token.push(c),
+                }
+            }
+            tokens.push(token);
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == ',' || c == '"' {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+    Some(tokens)
+}
+
+/// The names of the files the resource statements of the preprocessed
+/// script `text` read, like the icon of `IDI_APP ICON "app.ico"`: the last
+/// token of the lines of three tokens or more without commas, which covers
+/// icons, cursors, bitmaps, fonts, manifests and the other resources read
+/// whole from a file. Other tokens may turn up too, and are only hashed if
+/// a file of their name is found.
+fn resource_files(text: &str) -> Vec<String> {
+    let mut names = vec![];
+    for line in text.lines() {
+        if line.trim_start().starts_with('#') {
+            continue;
+        }
+        let mut tokens = match tokenize(line) {
+            Some(tokens) if tokens.len() >= 3 => tokens,
+            _ => continue,
+        };
+        let name = tokens.pop().expect("Three tokens or more");
+        match name.as_str() {
+            "BEGIN" | "END" | "{" | "}" => continue,
+            // Scripts written by Visual Studio escape backslashes in names
+            _ => names.push(name.replace("\\\\", "\\")),
+        }
+    }
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Append the digests of the files the resource statements of the
+/// preprocessed script `preprocessed` read, found in `search_path`, to it,
+/// so that changing them changes the hash key.
+fn append_resource_digests(preprocessed: &mut Vec<u8>, search_path: &[PathBuf]) -> Result<()> {
+    // rc.exe keeps the encoding of the script, which may be UTF-16
+    let text = response_file::decode(preprocessed)
+        .unwrap_or_else(|_| String::from_utf8_lossy(preprocessed).into_owned());
+    let mut digests = String::new();
+    for name in resource_files(&text) {
+        let path = search_path
+            .iter()
+            .map(|dir| dir.join(&name))
+            .find(|path| path.is_file());
+        if let Some(path) = path {
+            digests.push_str(&format!("\n// {}: {}", name, Digest::file_sync(&path)?));
+        }
+    }
+    preprocessed.extend(digests.into_bytes());
+    Ok(())
+}
+
+/// Run the preprocessor of rc.exe, which can't write to stdout, so the
+/// output is written to a temporary directory and read back, followed by
+/// the digests of the files of the resources.
+async fn preprocess_rc<T>(
+    creator: &T,
+    executable: &Path,
+    parsed_args: &ParsedArguments,
+    cwd: &Path,
+    env_vars: &[(OsString, OsString)],
+) -> Result<process::Output>
+where
+    T: CommandCreatorSync,
+{
+    let tempdir = tempfile::Builder::new()
+        .prefix("sccache_rc")
+        .tempdir()
+        .context("failed to create temporary directory")?;
+    let preprocessed = tempdir.path().join("preprocessed.rcpp");
+
+    let mut cmd = creator.clone().new_command_sync(&executable);
+    cmd.args(&parsed_args.preprocessor_args)
+        .args(&parsed_args.common_args)
+        .arg("-p")
+        .arg("-fo")
+        .arg(&preprocessed)
+        .arg(&parsed_args.input)
+        .env_clear()
+        .envs(env_vars.iter().map(|&(ref k, ref v)| (k, v)))
+        .current_dir(cwd);
+
+    if log_enabled!(Trace) {
+        trace!("preprocess: {:?}", cmd);
+    }
+    let search_path = search_path(parsed_args, cwd, env_vars);
+    let mut output = run_input_output(cmd, None).await?;
+    let mut stdout = fs::read(&preprocessed).with_context(|| {
+        format!(
+            "failed to read preprocessor output {}",
+            preprocessed.display()
+        )
+    })?;
+    drop(tempdir);
+    append_resource_digests(&mut stdout, &search_path)?;
+    output.stdout = stdout;
+    Ok(output)
+}
+
+/// Decompile the script with windres, to a script with the data of every
+/// resource in it, which stands for the preprocessor output.
+async fn preprocess_windres<T>(
+    creator: &T,
+    executable: &Path,
+    parsed_args: &ParsedArguments,
+    cwd: &Path,
+    env_vars: &[(OsString, OsString)],
+) -> Result<process::Output>
+where
+    T: CommandCreatorSync,
+{
+    let tempdir = tempfile::Builder::new()
+        .prefix("sccache_windres")
+        .tempdir()
+        .context("failed to create temporary directory")?;
+    let decompiled = tempdir.path().join("decompiled.rc");
+
+    // The last output format given is used
+    let mut cmd = creator.clone().new_command_sync(&executable);
+    cmd.args(&parsed_args.preprocessor_args)
+        .args(&parsed_args.common_args)
+        .arg("-i")
+        .arg(&parsed_args.input)
+        .arg("-O")
+        .arg("rc")
+        .arg("-o")
+        .arg(&decompiled)
+        .env_clear()
+        .envs(env_vars.iter().map(|&(ref k, ref v)| (k, v)))
+        .current_dir(cwd);
+
+    if log_enabled!(Trace) {
+        trace!("preprocess: {:?}", cmd);
+    }
+    let mut output = run_input_output(cmd, None).await?;
+    output.stdout = fs::read(&decompiled)
+        .with_context(|| format!("failed to read decompiled script {}", decompiled.display()))?;
+    drop(tempdir);
+    Ok(output)
+}
+
+pub fn generate_compile_commands(
+    kind: CCompilerKind,
+    executable: &Path,
+    parsed_args: &ParsedArguments,
+    cwd: &Path,
+    env_vars: &[(OsString, OsString)],
+) -> Result<(CompileCommand, Option<dist::CompileCommand>, Cacheable)> {
+    trace!("compile");
+
+    let out_file = match parsed_args.outputs.get("obj") {
+        Some(obj) => obj,
+        None => return Err(anyhow!("Missing object file output")),
+    };
+
+    let mut arguments: Vec<OsString> = vec![];
+    arguments.extend(parsed_args.preprocessor_args.clone());
+    arguments.extend(parsed_args.common_args.clone());
+    if kind == CCompilerKind::RC {
+        arguments.extend(vec![
+            "-fo".into(),
+            out_file.into(),
+            parsed_args.input.clone().into(),
+        ]);
+    } else {
+        arguments.extend(vec![
+            "-i".into(),
+            parsed_args.input.clone().into(),
+            "-o".into(),
+            out_file.into(),
+        ]);
+    }
+    let command = CompileCommand {
+        executable: executable.to_owned(),
+        arguments,
+        env_vars: env_vars.to_owned(),
+        cwd: cwd.to_owned(),
+    };
+
+    Ok((command, None, Cacheable::Yes))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::*;
+    use crate::test::utils::*;
+
+    fn parse_rc(arguments: Vec<String>) -> CompilerArguments<ParsedArguments> {
+        let args = arguments.iter().map(OsString::from).collect::<Vec<_>>();
+        RC.parse_arguments(&args, ".".as_ref())
+    }
+
+    fn parse_windres(arguments: Vec<String>) -> CompilerArguments<ParsedArguments> {
+        let args = arguments.iter().map(OsString::from).collect::<Vec<_>>();
+        Windres.parse_arguments(&args, ".".as_ref())
+    }
+
+    #[test]
+    fn test_parse_arguments_rc() {
+        let args = stringvec![
+            "/nologo", "/DWIN32", "-d", "NDEBUG", "/I", "include", "/l", "0x409", "/fo", "app.res",
+            "app.rc"
+        ];
+        let ParsedArguments {
+            input,
+            language,
+            outputs,
+            preprocessor_args,
+            common_args,
+            ..
+        } = match parse_rc(args) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(Some("app.rc"), input.to_str());
+        assert_eq!(Language::Resource, language);
+        assert_map_contains!(outputs, ("obj", PathBuf::from("app.res")));
+        assert_eq!(ovec!["-DWIN32", "-dNDEBUG", "-Iinclude"], preprocessor_args);
+        assert_eq!(ovec!["-nologo", "-l0x409"], common_args);
+    }
+
+    #[test]
+    fn test_parse_arguments_rc_default_name() {
+        let ParsedArguments { outputs, .. } = match parse_rc(stringvec!["res/app.rc"]) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_map_contains!(outputs, ("obj", PathBuf::from("res/app.res")));
+    }
+
+    #[test]
+    fn test_parse_arguments_rc_not_cacheable() {
+        assert_eq!(
+            CompilerArguments::NotCompilation,
+            parse_rc(stringvec!["/p", "app.rc"])
+        );
+        assert_eq!(
+            CompilerArguments::CannotCache("-fm", None),
+            parse_rc(stringvec!["/fm", "app.mui.res", "app.rc"])
+        );
+        assert_eq!(
+            CompilerArguments::CannotCache("multiple input files", None),
+            parse_rc(stringvec!["app.rc", "other.rc"])
+        );
+        assert_eq!(
+            CompilerArguments::CannotCache("no input file", None),
+            parse_rc(stringvec!["/nologo"])
+        );
+    }
+
+    #[test]
+    fn test_parse_arguments_windres() {
+        // As CMake runs it
+        let args = stringvec![
+            "-O",
+            "coff",
+            "-DUNICODE",
+            "-I",
+            "include",
+            "--use-temp-file",
+            "app.rc",
+            "app.rc.obj"
+        ];
+        let ParsedArguments {
+            input,
+            outputs,
+            preprocessor_args,
+            common_args,
+            ..
+        } = match parse_windres(args) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(Some("app.rc"), input.to_str());
+        assert_map_contains!(outputs, ("obj", PathBuf::from("app.rc.obj")));
+        assert_eq!(ovec!["-DUNICODE", "-Iinclude"], preprocessor_args);
+        assert_eq!(ovec!["-Ocoff", "--use-temp-file"], common_args);
+
+        let args = stringvec![
+            "--input=app.rc",
+            "--output",
+            "app.o",
+            "--include-dir=include"
+        ];
+        let ParsedArguments {
+            input,
+            outputs,
+            preprocessor_args,
+            ..
+        } = match parse_windres(args) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(Some("app.rc"), input.to_str());
+        assert_map_contains!(outputs, ("obj", PathBuf::from("app.o")));
+        assert_eq!(ovec!["--include-dir", "include"], preprocessor_args);
+    }
+
+    #[test]
+    fn test_parse_arguments_windres_not_cacheable() {
+        assert_eq!(
+            CompilerArguments::NotCompilation,
+            parse_windres(stringvec!["--version"])
+        );
+        assert_eq!(
+            CompilerArguments::CannotCache("output to stdout", None),
+            parse_windres(stringvec!["-i", "app.rc"])
+        );
+        assert_eq!(
+            CompilerArguments::CannotCache("multiple input files", None),
+            parse_windres(stringvec!["app.rc", "app.o", "extra"])
+        );
+    }
+
+    #[test]
+    fn test_resource_files() {
+        let text = "#line 1 \"app.rc\"\n\
+                    #pragma code_page(65001)\n\
+                    IDI_APP ICON \"res\\\\app.ico\"\n\
+                    1 24 app.manifest\n\
+                    IDB_LOGO BITMAP DISCARDABLE \"logo \"\"quoted\"\".bmp\"\n\
+                    IDD_ABOUT DIALOGEX 0, 0, 170, 62\n\
+                    STYLE DS_MODALFRAME | WS_POPUP\n\
+                    CAPTION \"About\"\n\
+                    BEGIN\n\
+                    LTEXT \"Version 1.0\", IDC_STATIC, 42, 14, 114, 8\n\
+                    END\n\
+                    IDR_DATA RCDATA\n\
+                    BEGIN\n\
+                    END\n\
+                    IDI_APP ICON \"res\\\\app.ico\"\n";
+        assert_eq!(
+            resource_files(text),
+            [
+                "WS_POPUP",
+                "app.manifest",
+                "logo \"quoted\".bmp",
+                "res\\app.ico"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_append_resource_digests() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        fs::create_dir(dir.join("include")).unwrap();
+        let search_path = vec![dir.to_owned(), dir.join("include")];
+        let preprocess = |icon: &[u8]| {
+            fs::write(dir.join("include").join("app.ico"), icon).unwrap();
+            let mut preprocessed = b"IDI_APP ICON app.ico\nSTYLE WS_A | WS_B\n".to_vec();
+            append_resource_digests(&mut preprocessed, &search_path).unwrap();
+            String::from_utf8(preprocessed).unwrap()
+        };
+        let preprocessed = preprocess(b"icon");
+        let script = "IDI_APP ICON app.ico\nSTYLE WS_A | WS_B\n";
+        assert!(preprocessed.starts_with(&format!("{}\n// app.ico: ", script)));
+        assert_eq!(preprocessed.matches("\n// ").count(), 1);
+        assert_ne!(preprocessed, preprocess(b"other icon"));
+    }
+
+    #[test]
+    fn test_search_path() {
+        let parsed_args = match parse_rc(stringvec!["/i", "inc", "/x", "res/app.rc"]) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        let cwd = Path::new("/src");
+        let env_vars = vec![(OsString::from("INCLUDE"), OsString::from("/sdk"))];
+        assert_eq!(
+            search_path(&parsed_args, cwd, &env_vars),
+            [cwd.to_owned(), cwd.join("res"), cwd.join("inc")]
+        );
+        let parsed_args = match parse_rc(stringvec!["app.rc"]) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(
+            search_path(&parsed_args, cwd, &env_vars),
+            [cwd.to_owned(), cwd.to_owned(), cwd.join("/sdk")]
+        );
+    }
+
+    #[test]
+    fn test_compile_simple() {
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let parsed_args = match parse_rc(stringvec!["/DX", "app.rc"]) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        let (command, dist_command, cacheable) = generate_compile_commands(
+            CCompilerKind::RC,
+            &f.bins[0],
+            &parsed_args,
+            f.tempdir.path(),
+            &[],
+        )
+        .unwrap();
+        assert_eq!(ovec!["-DX", "-fo", "app.res", "app.rc"], command.arguments);
+        assert!(dist_command.is_none());
+        assert_eq!(Cacheable::Yes, cacheable);
+
+        let parsed_args = match parse_windres(stringvec!["app.rc", "app.o"]) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        let (command, _, _) = generate_compile_commands(
+            CCompilerKind::Windres,
+            &f.bins[0],
+            &parsed_args,
+            f.tempdir.path(),
+            &[],
+        )
+        .unwrap();
+        assert_eq!(ovec!["-i", "app.rc", "-o", "app.o"], command.arguments);
+        // The compiler isn't run to generate the commands.
+        assert_eq!(0, creator.lock().unwrap().children.len());
+    }
+}
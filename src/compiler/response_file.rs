@@ -0,0 +1,340 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Expanding response files: an `@file` argument stands for the arguments
+//! in `file`, which can themselves name more response files. Build systems
+//! use them for command lines too long for the OS, so every C compiler reads
+//! them, quoted the way of its platform.
+
+use crate::util::OsStrExt;
+use std::ffi::OsString;
+use std::fs;
+use std::iter;
+use std::path::Path;
+use std::str;
+
+use crate::errors::*;
+
+/// How deep response files can name each other, so that ones naming
+/// themselves end.
+const MAX_DEPTH: usize = 32;
+
+/// How the arguments in a response file are quoted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Quoting {
+    /// As GCC, clang and nvcc read them: arguments are separated by
+    /// whitespace, which single or double quotes keep in an argument, and a
+    /// backslash includes the character after it whatever it is.
+    Gnu,
+    /// As MSVC and clang-cl read them, like a Windows command line: double
+    /// quotes keep whitespace in an argument, and backslashes are only
+    /// special before a double quote, which an odd number of them includes.
+    Windows,
+}
+
+/// An iterator over `args` with the response files in them expanded.
+///
+/// A response file that can't be read, like one nested too deep, is left as
+/// its `@file` argument, as the compilers do, which makes the compile
+/// uncacheable.
+pub struct ExpandResponseFiles<'a> {
+    cwd: &'a Path,
+    quoting: Quoting,
+    /// The arguments left, last first, with how many response files deep
+    /// each is.
+    stack: Vec<(OsString, usize)>,
+}
+
+impl<'a> ExpandResponseFiles<'a> {
+    pub fn new(cwd: &'a Path, args: &[OsString], quoting: Quoting) -> Self {
+        ExpandResponseFiles {
+            cwd,
+            quoting,
+            stack: args.iter().rev().map(|arg| (arg.clone(), 0)).collect(),
+        }
+    }
+}
+
+impl<'a> Iterator for ExpandResponseFiles<'a> {
+    type Item = OsString;
+
+    fn next(&mut self) -> Option<OsString> {
+        loop {
+            let (arg, depth) = self.stack.pop()?;
+            let file = match arg.split_prefix("@") {
+                Some(file) if depth < MAX_DEPTH => self.cwd.join(file),
+                _ => return Some(arg),
+            };
+            match read(&file, self.quoting) {
+                Ok(args) => self
+                    .stack
+                    .extend(args.into_iter().rev().map(|arg| (arg.into(), depth + 1))),
+                Err(e) => {
+                    debug!("failed to read @-file `{}`: {:#}", file.display(), e);
+                    return Some(arg);
+                }
+            }
+        }
+    }
+}
+
+/// The arguments in the response file at `path`.
+fn read(path: &Path, quoting: Quoting) -> Result<Vec<String>> {
+    let contents = fs::read(path)?;
+    Ok(split(&decode(&contents)?, quoting))
+}
+
+/// The text of a response file: UTF-8, or UTF-16 after a byte order mark,
+/// as MSVC tools and Visual Studio write them.
+pub fn decode(contents: &[u8]) -> Result<String> {
+    fn utf16(bytes: &[u8], unit: fn([u8; 2]) -> u16) -> Result<String> {
+        if bytes.len() % 2 != 0 {
+            bail!("odd number of bytes in a UTF-16 response file");
+        }
+        let units: Vec<u16> = bytes.chunks(2).map(|c| unit([c[0], c[1]])).collect();
+        String::from_utf16(&units).context("invalid UTF-16 in a response file")
+    }
+    match contents {
+        [0xff, 0xfe, rest @ ..] => utf16(rest, u16::from_le_bytes),
+        [0xfe, 0xff, rest @ ..] => utf16(rest, u16::from_be_bytes),
+        [0xef, 0xbb, 0xbf, rest @ ..] | rest => Ok(str::from_utf8(rest)
+            .context("invalid UTF-8 in a response file")?
+            .to_owned()),
+    }
+}
+
+/// Split the text of a response file into its arguments.
+pub fn split(text: &str, quoting: Quoting) -> Vec<String> {
+    match quoting {
+        Quoting::Gnu => split_gnu(text),
+        Quoting::Windows => split_windows(text),
+    }
+}
+
+/// Split `text` as libiberty's `buildargv`, which GCC and binutils use, does.
+fn split_gnu(text: &str) -> Vec<String> {
+    let mut args = vec![];
+    // Quotes start an argument even if nothing is in them
+    let mut arg: Option<String> = None;
+    let mut quote = None;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            // Backslashes escape in quotes too
+            ('\\', _) => {
+                let arg = arg.get_or_insert_with(String::new);
+                arg.extend(chars.next());
+            }
+            (c, Some(q)) if c == q => quote = None,
+            ('\'', None) | ('"', None) => {
+                quote = Some(c);
+                arg.get_or_insert_with(String::new);
+            }
+            (c, None) if c.is_whitespace() => args.extend(arg.take()),
+            (c, _) => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(arg);
+    args
+}
+
+/// Split `text` as the Microsoft C runtime splits command lines, taking
+/// line breaks as whitespace too.
+fn split_windows(text: &str) -> Vec<String> {
+    let mut args = vec![];
+    let mut arg: Option<String> = None;
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                let mut backslashes = 1;
+                while chars.peek() == Some(&'\\') {
+                    chars.next();
+                    backslashes += 1;
+                }
+                let arg = arg.get_or_insert_with(String::new);
+                if chars.peek() == Some(&'"') {
+                    // 2n backslashes are n and a quote that opens or closes,
+                    // 2n + 1 are n and a quote included
+                    arg.extend(iter::repeat('\\').take(backslashes / 2));
+                    if backslashes % 2 == 1 {
+                        arg.extend(chars.next());
+                    }
+                } else {
+                    arg.extend(iter::repeat('\\').take(backslashes));
+                }
+            }
+            '"' => {
+                let arg = arg.get_or_insert_with(String::new);
+                // Two quotes in quotes are one included
+                if quoted && chars.peek() == Some(&'"') {
+                    arg.extend(chars.next());
+                } else {
+                    quoted = !quoted;
+                }
+            }
+            c if c.is_whitespace() && !quoted => args.extend(arg.take()),
+            c => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(arg);
+    args
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn gnu(text: &str) -> Vec<String> {
+        split(text, Quoting::Gnu)
+    }
+
+    fn windows(text: &str) -> Vec<String> {
+        split(text, Quoting::Windows)
+    }
+
+    #[test]
+    fn test_split_gnu() {
+        let cases: &[(&str, &[&str])] = &[
+            ("", &[]),
+            (" \t\r\n ", &[]),
+            ("-c foo.c", &["-c", "foo.c"]),
+            ("-c\r\n\tfoo.c\n", &["-c", "foo.c"]),
+            ("-DA='b c'", &["-DA=b c"]),
+            ("\"a b\" 'c d'", &["a b", "c d"]),
+            ("a\"b c\"d", &["ab cd"]),
+            ("'a\"b' \"c'd\"", &["a\"b", "c'd"]),
+            ("'' \"\" x", &["", "", "x"]),
+            ("a\\ b", &["a b"]),
+            ("a\\\\b", &["a\\b"]),
+            ("\\\"a\\'", &["\"a'"]),
+            ("'a\\'b'", &["a'b"]),
+            ("\"a\\\"b\"", &["a\"b"]),
+            ("a\\\nb", &["a\nb"]),
+            ("C:\\\\dir\\\\foo.c", &["C:\\dir\\foo.c"]),
+            ("-DX=\"\\\"s\\\"\"", &["-DX=\"s\""]),
+            ("a\\", &["a"]),
+            ("'unterminated arg", &["unterminated arg"]),
+            ("é ü", &["é", "ü"]),
+        ];
+        for &(text, expected) in cases {
+            assert_eq!(gnu(text), expected, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn test_split_windows() {
+        let cases: &[(&str, &[&str])] = &[
+            ("", &[]),
+            (" \t\r\n ", &[]),
+            ("/c foo.c", &["/c", "foo.c"]),
+            ("/c\r\n\tfoo.c\r\n", &["/c", "foo.c"]),
+            ("\"a b\" c", &["a b", "c"]),
+            ("a\"b c\"d", &["ab cd"]),
+            ("'a b'", &["'a", "b'"]),
+            ("\"\" x", &["", "x"]),
+            ("C:\\dir\\foo.c", &["C:\\dir\\foo.c"]),
+            ("\"C:\\Program Files\\\"", &["C:\\Program Files\""]),
+            ("\"C:\\Program Files\\\\\"", &["C:\\Program Files\\"]),
+            ("a\\\\b", &["a\\\\b"]),
+            ("a\\\"b", &["a\"b"]),
+            ("a\\\\\"b c\"", &["a\\b c"]),
+            ("a\\\\\\\"b c", &["a\\\"b", "c"]),
+            ("\"a\"\"b\" c", &["a\"b", "c"]),
+            ("/DX=\\\"s\\\"", &["/DX=\"s\""]),
+            ("\"unterminated arg", &["unterminated arg"]),
+            ("é ü", &["é", "ü"]),
+        ];
+        for &(text, expected) in cases {
+            assert_eq!(windows(text), expected, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode(b"-c foo.c").unwrap(), "-c foo.c");
+        assert_eq!(decode(b"\xef\xbb\xbf-c").unwrap(), "-c");
+        let utf16 = |bom: &[u8], unit: fn(u16) -> [u8; 2]| {
+            let mut bytes = bom.to_vec();
+            for u in "/c \"é.c\"".encode_utf16() {
+                bytes.extend(&unit(u));
+            }
+            bytes
+        };
+        assert_eq!(
+            decode(&utf16(b"\xff\xfe", u16::to_le_bytes)).unwrap(),
+            "/c \"é.c\""
+        );
+        assert_eq!(
+            decode(&utf16(b"\xfe\xff", u16::to_be_bytes)).unwrap(),
+            "/c \"é.c\""
+        );
+        assert!(decode(b"\xff\xfe/\0c\0").is_ok());
+        assert!(decode(b"\xff\xfe/").is_err());
+        assert!(decode(b"\xff-c").is_err());
+    }
+
+    #[test]
+    fn test_expand() {
+        let dir = tempfile::tempdir().unwrap();
+        let write =
+            |name: &str, contents: &[u8]| fs::write(dir.path().join(name), contents).unwrap();
+        write("outer", b"-c \"my file.c\" @inner -o out.o");
+        write("inner", b"-DA='1 2' @sub/deepest");
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        // Nested response files are relative to the working directory
+        write("sub/deepest", b"-Wall");
+        write("self", b"-g @self");
+        let mut utf16 = b"\xff\xfe".to_vec();
+        for u in "/c \"a b.c\"".encode_utf16() {
+            utf16.extend(&u.to_le_bytes());
+        }
+        write("msvc", &utf16);
+        let long: Vec<String> = (0..100_000)
+            .map(|i| format!("-DN{}=\"{}\"", i, i))
+            .collect();
+        write("long", long.join("\n").as_bytes());
+
+        let expand = |args: &[&str], quoting| -> Vec<OsString> {
+            let args: Vec<OsString> = args.iter().map(OsString::from).collect();
+            ExpandResponseFiles::new(dir.path(), &args, quoting).collect()
+        };
+        assert_eq!(
+            expand(&["-x", "@outer", "foo.c"], Quoting::Gnu),
+            ovec![
+                "-x",
+                "-c",
+                "my file.c",
+                "-DA=1 2",
+                "-Wall",
+                "-o",
+                "out.o",
+                "foo.c"
+            ]
+        );
+        assert_eq!(expand(&["@msvc"], Quoting::Windows), ovec!["/c", "a b.c"]);
+        assert_eq!(
+            expand(&["@missing", "-c"], Quoting::Gnu),
+            ovec!["@missing", "-c"]
+        );
+        // Files that name themselves are expanded until they are too deep
+        let recursive = expand(&["@self"], Quoting::Gnu);
+        assert_eq!(recursive.len(), MAX_DEPTH + 1);
+        assert_eq!(recursive[MAX_DEPTH], "@self");
+        let expanded = expand(&["@long"], Quoting::Gnu);
+        assert_eq!(expanded.len(), long.len());
+        assert_eq!(expanded[99_999], "-DN99999=99999");
+    }
+}
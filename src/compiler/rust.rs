@@ -13,23 +13,27 @@
 // limitations under the License.
 
 use crate::compiler::args::*;
+use crate::compiler::digest_cache::{compiler_digests, input_digests};
 use crate::compiler::{
-    Cacheable, ColorMode, Compilation, CompileCommand, Compiler, CompilerArguments, CompilerHasher,
-    CompilerKind, CompilerProxy, HashResult,
+    cache_schema, debug_dir, Cacheable, ColorMode, Compilation, CompileCommand, Compiler,
+    CompilerArguments, CompilerHasher, CompilerKind, CompilerProxy, HashResult,
 };
 #[cfg(feature = "dist-client")]
 use crate::compiler::{DistPackagers, OutputsRewriter};
+use crate::config::HashConfig;
 use crate::dist;
 #[cfg(feature = "dist-client")]
 use crate::dist::pkg;
 #[cfg(feature = "dist-client")]
 use crate::lru_disk_cache::{LruCache, Meter};
 use crate::mock_command::{CommandCreatorSync, RunCommand};
-use crate::util::{fmt_duration_as_secs, hash_all, run_input_output, Digest};
-use crate::util::{ref_env, HashToDigest, OsStrExt, SpawnExt};
+use crate::trace::Span;
+use crate::util::{fmt_duration_as_secs, run_input_output, Digest};
+use crate::util::{ref_env, HashToDigest, OsStrExt, RecordingDigest, SpawnExt};
+use async_trait::async_trait;
 use filetime::FileTime;
-use futures::Future;
-use futures_03::executor::ThreadPool;
+use futures::executor::ThreadPool;
+use futures::future;
 use log::Level::Trace;
 #[cfg(feature = "dist-client")]
 #[cfg(feature = "dist-client")]
@@ -88,6 +92,8 @@ pub struct Rust {
     sysroot: PathBuf,
     /// The digests of all the shared libraries in rustc's $sysroot/lib (or /bin on Windows).
     compiler_shlibs_digests: Vec<String>,
+    /// The first line of `rustc -vV`.
+    version: String,
     /// A shared, caching reader for rlib dependencies
     #[cfg(feature = "dist-client")]
     rlib_dep_reader: Option<Arc<RlibDepReader>>,
@@ -134,8 +140,16 @@ pub struct ParsedArguments {
     crate_name: String,
     /// The crate types that will be generated
     crate_types: CrateTypes,
+    /// The linker passed with `-C linker`, if any.
+    linker: Option<PathBuf>,
+    /// The target triple passed with `--target`, if any.
+    target: Option<String>,
+    /// Whether `-C incremental` was passed.
+    incremental: bool,
     /// If dependency info is being emitted, the name of the dep info file.
     dep_info: Option<PathBuf>,
+    /// If metadata is being emitted, the name of the rmeta file.
+    rmeta: Option<PathBuf>,
     /// rustc says that emits .rlib for --emit=metadata
     /// https://github.com/rust-lang/rust/issues/54852
     emit: HashSet<String>,
@@ -152,6 +166,8 @@ pub struct RustCompilation {
     executable: PathBuf,
     /// The host triple for this rustc.
     host: String,
+    /// The target triple being compiled for.
+    target: String,
     /// The sysroot for this rustc
     sysroot: PathBuf,
     /// A shared, caching reader for rlib dependencies
@@ -175,6 +191,10 @@ pub struct RustCompilation {
     cwd: PathBuf,
     /// The environment variables
     env_vars: Vec<(OsString, OsString)>,
+    /// Whether rustc keeps an incremental directory for this compilation.
+    incremental: bool,
+    /// Why this compilation must not be cached, if it was decided while hashing.
+    not_cacheable: Option<&'static str>,
 }
 
 // The selection of crate types for this compilation
@@ -182,6 +202,16 @@ pub struct RustCompilation {
 pub struct CrateTypes {
     rlib: bool,
     staticlib: bool,
+    proc_macro: bool,
+    /// Only for build scripts.
+    bin: bool,
+}
+
+impl CrateTypes {
+    /// Whether rustc links the output for the host with the system linker.
+    fn is_host(&self) -> bool {
+        self.proc_macro || self.bin
+    }
 }
 
 lazy_static! {
@@ -194,10 +224,10 @@ lazy_static! {
 }
 
 /// Version number for cache key.
-const CACHE_VERSION: &[u8] = b"6";
+pub const CACHE_VERSION: &[u8] = b"8";
 
 /// Get absolute paths for all source files listed in rustc's dep-info output.
-fn get_source_files<T>(
+async fn get_source_files<T>(
     creator: &T,
     crate_name: &str,
     executable: &Path,
@@ -205,16 +235,16 @@ fn get_source_files<T>(
     cwd: &Path,
     env_vars: &[(OsString, OsString)],
     pool: &ThreadPool,
-) -> SFuture<Vec<PathBuf>>
+) -> Result<Vec<PathBuf>>
 where
     T: CommandCreatorSync,
 {
     let start = time::Instant::now();
     // Get the full list of source files from rustc's dep-info.
-    let temp_dir = ftry!(tempfile::Builder::new()
+    let temp_dir = tempfile::Builder::new()
         .prefix("sccache")
         .tempdir()
-        .context("Failed to create temp dir"));
+        .context("Failed to create temp dir")?;
     let dep_file = temp_dir.path().join("deps.d");
     let mut cmd = creator.clone().new_command_sync(executable);
     cmd.args(&arguments)
@@ -225,29 +255,25 @@ where
         .envs(ref_env(env_vars))
         .current_dir(cwd);
     trace!("[{}]: get dep-info: {:?}", crate_name, cmd);
-    let dep_info = run_input_output(cmd, None);
+    run_input_output(cmd, None).await?;
     // Parse the dep-info file, then hash the contents of those files.
-    let pool = pool.clone();
     let cwd = cwd.to_owned();
-    let crate_name = crate_name.to_owned();
-    Box::new(dep_info.and_then(move |_| -> SFuture<_> {
-        let name2 = crate_name.clone();
-        let parsed = pool.spawn_fn(move || {
+    let name = crate_name.to_owned();
+    let files = pool
+        .spawn_fn(move || {
             parse_dep_file(&dep_file, &cwd)
-                .with_context(|| format!("Failed to parse dep info for {}", name2))
-        });
-        Box::new(parsed.map(move |files| {
-            trace!(
-                "[{}]: got {} source files from dep-info in {}",
-                crate_name,
-                files.len(),
-                fmt_duration_as_secs(&start.elapsed())
-            );
-            // Just to make sure we capture temp_dir.
-            drop(temp_dir);
-            files
-        }))
-    }))
+                .with_context(|| format!("Failed to parse dep info for {}", name))
+        })
+        .await?;
+    trace!(
+        "[{}]: got {} source files from dep-info in {}",
+        crate_name,
+        files.len(),
+        fmt_duration_as_secs(&start.elapsed())
+    );
+    // Just to make sure we capture temp_dir.
+    drop(temp_dir);
+    Ok(files)
 }
 
 /// Parse dependency info from `file` and return a Vec of files mentioned.
@@ -315,13 +341,13 @@ where
 }
 
 /// Run `rustc --print file-names` to get the outputs of compilation.
-fn get_compiler_outputs<T>(
+async fn get_compiler_outputs<T>(
     creator: &T,
     executable: &Path,
     arguments: Vec<OsString>,
     cwd: &Path,
     env_vars: &[(OsString, OsString)],
-) -> SFuture<Vec<String>>
+) -> Result<Vec<String>>
 where
     T: CommandCreatorSync,
 {
@@ -334,37 +360,40 @@ where
     if log_enabled!(Trace) {
         trace!("get_compiler_outputs: {:?}", cmd);
     }
-    let outputs = run_input_output(cmd, None);
-    Box::new(outputs.and_then(move |output| -> Result<_> {
-        let outstr = String::from_utf8(output.stdout).context("Error parsing rustc output")?;
-        if log_enabled!(Trace) {
-            trace!("get_compiler_outputs: {:?}", outstr);
-        }
-        Ok(outstr.lines().map(|l| l.to_owned()).collect())
-    }))
+    let output = run_input_output(cmd, None).await?;
+    let outstr = String::from_utf8(output.stdout).context("Error parsing rustc output")?;
+    if log_enabled!(Trace) {
+        trace!("get_compiler_outputs: {:?}", outstr);
+    }
+    Ok(outstr.lines().map(|l| l.to_owned()).collect())
 }
 
 impl Rust {
     /// Create a new Rust compiler instance, calculating the hashes of
     /// all the shared libraries in its sysroot.
-    pub fn new<T>(
+    pub async fn new<T>(
         mut creator: T,
         executable: PathBuf,
         env_vars: &[(OsString, OsString)],
         rustc_verbose_version: &str,
         dist_archive: Option<PathBuf>,
         pool: ThreadPool,
-    ) -> SFuture<Rust>
+    ) -> Result<Rust>
     where
         T: CommandCreatorSync,
     {
         // Taken from Cargo
-        let host = ftry!(rustc_verbose_version
+        let host = rustc_verbose_version
             .lines()
             .find(|l| l.starts_with("host: "))
             .map(|l| &l[6..])
-            .context("rustc verbose version didn't have a line for `host:`"))
-        .to_string();
+            .context("rustc verbose version didn't have a line for `host:`")?
+            .to_string();
+        let version = rustc_verbose_version
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .to_owned();
 
         // it's fine to use the `executable` directly no matter if proxied or not
         let mut cmd = creator.new_command_sync(&executable);
@@ -373,9 +402,8 @@ impl Rust {
             .arg("--print=sysroot")
             .env_clear()
             .envs(ref_env(env_vars));
-        let output = run_input_output(cmd, None);
-        let sysroot_and_libs = output.and_then(move |output| -> Result<_> {
-            //debug!("output.and_then: {}", output);
+        let sysroot_and_libs = async {
+            let output = run_input_output(cmd, None).await?;
             let outstr = String::from_utf8(output.stdout).context("Error parsing sysroot")?;
             let sysroot = PathBuf::from(outstr.trim_end());
             let libs_path = sysroot.join(LIBS_DIR);
@@ -401,45 +429,48 @@ impl Rust {
                 libs.push(path);
             };
             libs.sort();
-            Ok((sysroot, libs))
-        });
-
-        #[cfg(feature = "dist-client")]
-        let rlib_dep_reader = {
-            let executable = executable.clone();
-            let env_vars = env_vars.to_owned();
-            pool.spawn_fn(move || Ok(RlibDepReader::new_with_check(executable, &env_vars)))
+            Ok::<_, Error>((sysroot, libs))
         };
 
         #[cfg(feature = "dist-client")]
-        return Box::new(sysroot_and_libs.join(rlib_dep_reader).and_then(move |((sysroot, libs), rlib_dep_reader)| {
+        {
+            let rlib_dep_reader = {
+                let executable = executable.clone();
+                let env_vars = env_vars.to_owned();
+                pool.spawn_fn(move || Ok(RlibDepReader::new_with_check(executable, &env_vars)))
+            };
+            let ((sysroot, libs), rlib_dep_reader) =
+                future::try_join(sysroot_and_libs, rlib_dep_reader).await?;
             let rlib_dep_reader = match rlib_dep_reader {
                 Ok(r) => Some(Arc::new(r)),
                 Err(e) => {
                     warn!("Failed to initialise RlibDepDecoder, distributed compiles will be inefficient: {}", e);
                     None
-                },
-            };
-            hash_all(&libs, &pool).map(move |digests| {
-                Rust {
-                    executable,
-                    host,
-                    sysroot,
-                    compiler_shlibs_digests: digests,
-                    rlib_dep_reader,
                 }
+            };
+            let digests = compiler_digests(&libs, &pool).await?;
+            Ok(Rust {
+                executable,
+                host,
+                sysroot,
+                compiler_shlibs_digests: digests,
+                version,
+                rlib_dep_reader,
             })
-        }));
+        }
 
         #[cfg(not(feature = "dist-client"))]
-        return Box::new(sysroot_and_libs.and_then(move |(sysroot, libs)| {
-            hash_all(&libs, &pool).map(move |digests| Rust {
+        {
+            let (sysroot, libs) = sysroot_and_libs.await?;
+            let digests = compiler_digests(&libs, &pool).await?;
+            Ok(Rust {
                 executable,
                 host,
                 sysroot,
                 compiler_shlibs_digests: digests,
+                version,
             })
-        }));
+        }
     }
 }
 
@@ -454,6 +485,8 @@ where
     fn get_toolchain_packager(&self) -> Box<dyn pkg::ToolchainPackager> {
         Box::new(RustToolchainPackager {
             sysroot: self.sysroot.clone(),
+            host: self.host.clone(),
+            target: None,
         })
     }
     /// Parse `arguments` as rustc command-line arguments, determine if
@@ -489,63 +522,60 @@ where
         }
     }
 
+    fn identity(&self) -> (String, Option<String>) {
+        let mut m = Digest::new();
+        for digest in &self.compiler_shlibs_digests {
+            m.update(digest.as_bytes());
+        }
+        (m.finish(), Some(self.version.clone()))
+    }
+
     fn box_clone(&self) -> Box<dyn Compiler<T>> {
         Box::new((*self).clone())
     }
 }
 
+#[async_trait(?Send)]
 impl<T> CompilerProxy<T> for RustupProxy
 where
     T: CommandCreatorSync,
 {
-    fn resolve_proxied_executable(
+    async fn resolve_proxied_executable(
         &self,
         mut creator: T,
         cwd: PathBuf,
         env: &[(OsString, OsString)],
-    ) -> SFuture<(PathBuf, FileTime)> {
-        let proxy_executable = self.proxy_executable.clone();
-
-        let mut child = creator.new_command_sync(&proxy_executable);
+    ) -> Result<(PathBuf, FileTime)> {
+        let mut child = creator.new_command_sync(&self.proxy_executable);
         child
             .current_dir(&cwd)
             .env_clear()
             .envs(ref_env(&env))
             .args(&["which", "rustc"]);
 
-        let lookup = run_input_output(child, None)
-            .map_err(|e| anyhow!("Failed to execute rustup which rustc: {}", e))
-            .and_then(move |output| {
-                String::from_utf8(output.stdout)
-                    .map_err(|e| anyhow!("Failed to parse output of rustup which rustc: {}", e))
-                    .and_then(|stdout| {
-                        let proxied_compiler = PathBuf::from(stdout.trim());
-                        trace!(
-                            "proxy: rustup which rustc produced: {:?}",
-                            &proxied_compiler
-                        );
-                        let res = fs::metadata(proxied_compiler.as_path())
-                            .map_err(|e| {
-                                anyhow!(
-                                    "Failed to obtain metadata of the resolved, true rustc: {}",
-                                    e
-                                )
-                            })
-                            .and_then(|attr| {
-                                if attr.is_file() {
-                                    Ok(FileTime::from_last_modification_time(&attr))
-                                } else {
-                                    Err(anyhow!(
-                                        "proxy: rustup resolved compiler is not of type file"
-                                    ))
-                                }
-                            })
-                            .map(|filetime| (proxied_compiler, filetime));
-                        res
-                    })
-            });
-
-        Box::new(lookup)
+        let output = run_input_output(child, None)
+            .await
+            .map_err(|e| anyhow!("Failed to execute rustup which rustc: {}", e))?;
+        let stdout = String::from_utf8(output.stdout)
+            .map_err(|e| anyhow!("Failed to parse output of rustup which rustc: {}", e))?;
+        let proxied_compiler = PathBuf::from(stdout.trim());
+        trace!(
+            "proxy: rustup which rustc produced: {:?}",
+            &proxied_compiler
+        );
+        let attr = fs::metadata(proxied_compiler.as_path()).map_err(|e| {
+            anyhow!(
+                "Failed to obtain metadata of the resolved, true rustc: {}",
+                e
+            )
+        })?;
+        if !attr.is_file() {
+            bail!("proxy: rustup resolved compiler is not of type file");
+        }
+        Ok((
+            proxied_compiler,
+            FileTime::from_last_modification_time(&attr),
+        ))
     }
 
     fn box_clone(&self) -> Box<dyn CompilerProxy<T>> {
@@ -567,23 +597,15 @@ impl RustupProxy {
         })
     }
 
-    pub fn find_proxy_executable<T>(
+    pub async fn find_proxy_executable<T>(
         compiler_executable: &Path,
         proxy_name: &str,
         mut creator: T,
         env: &[(OsString, OsString)],
-    ) -> SFuture<Result<Option<Self>>>
+    ) -> Result<Option<Self>>
     where
         T: CommandCreatorSync,
     {
-        let compiler_executable1 = compiler_executable.to_owned();
-        let compiler_executable2 = compiler_executable.to_owned();
-        let proxy_name1 = proxy_name.to_owned();
-        let proxy_name2 = proxy_name.to_owned();
-
-        let env1 = env.to_owned();
-        let env2 = env.to_owned();
-
         enum ProxyPath {
             Candidate(PathBuf),
             ToBeDiscovered,
@@ -612,94 +634,80 @@ impl RustupProxy {
         // `rustc +stable` returns retcode 1 (!=0) if it is installed via i.e. rpm packages
 
         // verify rustc is proxy
-        let mut child = creator.new_command_sync(compiler_executable.to_owned());
-        child.env_clear().envs(ref_env(&env1)).args(&["+stable"]);
-        let find_candidate = run_input_output(child, None)
-            .map(move |output| {
-                if output.status.success() {
-                    trace!("proxy: Found a compiler proxy managed by rustup");
-                    ProxyPath::ToBeDiscovered
-                } else {
-                    trace!("proxy: Found a regular compiler");
-                    ProxyPath::None
-                }
-            })
-            .and_then(move |state| {
-                let state = match state {
-                    ProxyPath::Candidate(_) => { unreachable!("qed") }
-                    ProxyPath::ToBeDiscovered => {
-                        // simple check: is there a rustup in the same parent dir as rustc?
-                        // that would be the prefered one
-                        Ok(match compiler_executable1.parent().map(|parent| { parent.to_owned() }) {
-                            Some(mut parent) => {
-                                parent.push(proxy_name1);
-                                let proxy_candidate = parent;
-                                if proxy_candidate.exists() {
-                                    trace!("proxy: Found a compiler proxy at {}", proxy_candidate.display());
-                                    ProxyPath::Candidate(proxy_candidate)
-                                } else {
-                                    ProxyPath::ToBeDiscovered
-                                }
-                            },
-                            None => {
-                                ProxyPath::ToBeDiscovered
-                            },
-                        })
-                    },
-                    x => Ok(x),
-                };
-                f_ok(state)
-            }).and_then(move |state| {
-                let state = match state {
-                    Ok(ProxyPath::ToBeDiscovered) => {
-                        // still no rustup found, use which crate to find one
-                        match which::which(&proxy_name2) {
-                            Ok(proxy_candidate) => {
-                                warn!("proxy: rustup found, but not where it was expected (next to rustc {})", compiler_executable2.display());
-                                Ok(ProxyPath::Candidate(proxy_candidate))
-                            },
-                            Err(e) => {
-                                trace!("proxy: rustup is not present: {}", e);
-                                Ok(ProxyPath::ToBeDiscovered)
-                            },
+        let mut child = creator.new_command_sync(compiler_executable);
+        child.env_clear().envs(ref_env(env)).args(&["+stable"]);
+        let output = run_input_output(child, None).await?;
+        let state = if output.status.success() {
+            trace!("proxy: Found a compiler proxy managed by rustup");
+            ProxyPath::ToBeDiscovered
+        } else {
+            trace!("proxy: Found a regular compiler");
+            ProxyPath::None
+        };
+
+        let state = match state {
+            ProxyPath::ToBeDiscovered => {
+                // simple check: is there a rustup in the same parent dir as rustc?
+                // that would be the prefered one
+                match compiler_executable.parent() {
+                    Some(parent) => {
+                        let proxy_candidate = parent.join(proxy_name);
+                        if proxy_candidate.exists() {
+                            trace!(
+                                "proxy: Found a compiler proxy at {}",
+                                proxy_candidate.display()
+                            );
+                            ProxyPath::Candidate(proxy_candidate)
+                        } else {
+                            ProxyPath::ToBeDiscovered
                         }
                     }
-                    x => x,
-                };
-                f_ok(state)
-            });
-
-        let f = find_candidate.and_then(move |state| {
-            match state {
-                Err(e) => f_ok(Err(e)),
-                Ok(ProxyPath::ToBeDiscovered) => f_ok(Err(anyhow!(
-                    "Failed to discover a rustup executable, but rustc behaves like a proxy"
-                ))),
-                Ok(ProxyPath::None) => f_ok(Ok(None)),
-                Ok(ProxyPath::Candidate(proxy_executable)) => {
-                    // verify the candidate is a rustup
-                    let mut child = creator.new_command_sync(proxy_executable.to_owned());
-                    child.env_clear().envs(ref_env(&env2)).args(&["--version"]);
-                    let rustup_candidate_check = run_input_output(child, None).map(move |output| {
-                        String::from_utf8(output.stdout)
-                            .map_err(|_e| {
-                                anyhow!("Response of `rustup --version` is not valid UTF-8")
-                            })
-                            .and_then(|stdout| {
-                                if stdout.trim().starts_with("rustup ") {
-                                    trace!("PROXY rustup --version produced: {}", &stdout);
-                                    Self::new(&proxy_executable).map(Some)
-                                } else {
-                                    Err(anyhow!("Unexpected output or `rustup --version`"))
-                                }
-                            })
-                    });
-                    Box::new(rustup_candidate_check)
+                    None => ProxyPath::ToBeDiscovered,
                 }
             }
-        });
+            x => x,
+        };
+
+        let state = match state {
+            ProxyPath::ToBeDiscovered => {
+                // still no rustup found, use which crate to find one
+                match which::which(proxy_name) {
+                    Ok(proxy_candidate) => {
+                        warn!(
+                            "proxy: rustup found, but not where it was expected (next to rustc {})",
+                            compiler_executable.display()
+                        );
+                        ProxyPath::Candidate(proxy_candidate)
+                    }
+                    Err(e) => {
+                        trace!("proxy: rustup is not present: {}", e);
+                        ProxyPath::ToBeDiscovered
+                    }
+                }
+            }
+            x => x,
+        };
 
-        Box::new(f)
+        match state {
+            ProxyPath::ToBeDiscovered => Err(anyhow!(
+                "Failed to discover a rustup executable, but rustc behaves like a proxy"
+            )),
+            ProxyPath::None => Ok(None),
+            ProxyPath::Candidate(proxy_executable) => {
+                // verify the candidate is a rustup
+                let mut child = creator.new_command_sync(&proxy_executable);
+                child.env_clear().envs(ref_env(env)).args(&["--version"]);
+                let output = run_input_output(child, None).await?;
+                let stdout = String::from_utf8(output.stdout)
+                    .map_err(|_e| anyhow!("Response of `rustup --version` is not valid UTF-8"))?;
+                if stdout.trim().starts_with("rustup ") {
+                    trace!("PROXY rustup --version produced: {}", &stdout);
+                    Self::new(&proxy_executable).map(Some)
+                } else {
+                    Err(anyhow!("Unexpected output or `rustup --version`"))
+                }
+            }
+        }
     }
 }
 
@@ -1009,7 +1017,12 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
     let mut crate_types = CrateTypes {
         rlib: false,
         staticlib: false,
+        proc_macro: false,
+        bin: false,
     };
+    let mut linker = None;
+    let mut target = None;
+    let mut incremental = false;
     let mut extra_filename = None;
     let mut externs = vec![];
     let mut crate_link_paths = vec![];
@@ -1054,8 +1067,13 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
                 staticlib,
                 others,
             })) => {
-                // We can't cache non-rlib/staticlib crates, because rustc invokes the
-                // system linker to link them, and we don't know about all the linker inputs.
+                // We can't cache most other crate types, because rustc invokes the
+                // system linker to link them, and we don't know about all the linker
+                // inputs. Proc macros and build scripts are linked for the host against
+                // little more than the standard library, so we make an exception for them.
+                let mut others = others.clone();
+                crate_types.proc_macro |= others.remove("proc-macro");
+                crate_types.bin |= others.remove("bin");
                 if !others.is_empty() {
                     let others: Vec<&str> = others.iter().map(String::as_str).collect();
                     let others_string = others.join(",");
@@ -1071,15 +1089,13 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
                 match (opt.as_ref(), value) {
                     ("extra-filename", Some(value)) => extra_filename = Some(value.to_owned()),
                     ("extra-filename", None) => cannot_cache!("extra-filename"),
-                    // Incremental compilation makes a mess of sccache's entire world
-                    // view. It produces additional compiler outputs that we don't cache,
-                    // and just letting rustc do its work in incremental mode is likely
-                    // to be faster than trying to fetch a result from cache anyway, so
-                    // don't bother caching compiles where it's enabled currently.
-                    // Longer-term we would like to figure out better integration between
-                    // sccache and rustc in the incremental scenario:
-                    // https://github.com/mozilla/sccache/issues/236
-                    ("incremental", _) => cannot_cache!("incremental"),
+                    ("linker", Some(value)) => linker = Some(PathBuf::from(value)),
+                    // Incremental compilation keeps session state in a directory that
+                    // we leave entirely to rustc. We cache the regular outputs, and on a
+                    // hit rustc just finds its state out of date on the next miss. See
+                    // https://github.com/mozilla/sccache/issues/236 for better
+                    // integration between sccache and rustc in the incremental scenario.
+                    ("incremental", _) => incremental = true,
                     (_, _) => (),
                 }
             }
@@ -1095,9 +1111,9 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
                 has_json = true;
             }
             Some(PassThrough(_)) => (),
-            Some(Target(target)) => match target {
+            Some(Target(arg_target)) => match arg_target {
                 ArgTarget::Path(_) | ArgTarget::Unsure(_) => cannot_cache!("target"),
-                ArgTarget::Name(_) => (),
+                ArgTarget::Name(name) => target = Some(name.clone()),
             },
             None => {
                 match arg {
@@ -1149,10 +1165,17 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
     if let CrateTypes {
         rlib: false,
         staticlib: false,
+        proc_macro: false,
+        bin: false,
     } = crate_types
     {
         cannot_cache!("crate-type", "No crate-type passed".to_owned())
     }
+    // Build scripts are the only binaries whose link we trust to be as simple
+    // as a proc macro's. Checking any other binary doesn't link it at all.
+    if crate_types.bin && emit.contains("link") && !crate_name.starts_with("build_script_") {
+        cannot_cache!("crate-type", "bin".to_owned())
+    }
     // We won't cache invocations that are outputting anything but
     // linker output and dep-info.
     if emit.iter().any(|e| !ALLOWED_EMIT.contains(e.as_str())) {
@@ -1162,7 +1185,7 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
     // Figure out the dep-info filename, if emitting dep-info.
     let dep_info = if emit.contains("dep-info") {
         let mut dep_info = crate_name.clone();
-        if let Some(extra_filename) = extra_filename {
+        if let Some(ref extra_filename) = extra_filename {
             dep_info.push_str(&extra_filename[..]);
         }
         dep_info.push_str(".d");
@@ -1170,6 +1193,17 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
     } else {
         None
     };
+    // Figure out the rmeta filename, if emitting metadata. rustc names it
+    // like an rlib whatever the crate type.
+    let rmeta = if emit.contains("metadata") {
+        Some(format!(
+            "lib{}{}.rmeta",
+            crate_name,
+            extra_filename.as_deref().unwrap_or("")
+        ))
+    } else {
+        None
+    };
     // Locate all static libs specified on the commandline.
     let staticlibs = static_lib_names
         .into_iter()
@@ -1200,22 +1234,27 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
         arguments: args,
         output_dir,
         crate_types,
+        linker,
+        target,
+        incremental,
         externs,
         crate_link_paths,
         staticlibs,
         crate_name,
         dep_info: dep_info.map(|s| s.into()),
+        rmeta: rmeta.map(|s| s.into()),
         emit,
         color_mode,
         has_json,
     })
 }
 
+#[async_trait(?Send)]
 impl<T> CompilerHasher<T> for RustHasher
 where
     T: CommandCreatorSync,
 {
-    fn generate_hash_key(
+    async fn generate_hash_key(
         self: Box<Self>,
         creator: &T,
         cwd: PathBuf,
@@ -1223,7 +1262,9 @@ where
         _may_dist: bool,
         pool: &ThreadPool,
         _rewrite_includes_only: bool,
-    ) -> SFuture<HashResult> {
+        hash_config: &HashConfig,
+        _span: &Span,
+    ) -> Result<HashResult> {
         let RustHasher {
             executable,
             host,
@@ -1240,12 +1281,17 @@ where
                     staticlibs,
                     crate_name,
                     crate_types,
+                    linker,
+                    target,
+                    incremental,
                     dep_info,
+                    rmeta,
                     emit,
                     has_json,
                     ..
                 },
         } = *self;
+        let target = target.unwrap_or_else(|| host.clone());
         trace!("[{}]: generate_hash_key", crate_name);
         // TODO: this doesn't produce correct arguments if they should be concatenated - should use iter_os_strings
         let os_string_arguments: Vec<(OsString, Option<OsString>)> = arguments
@@ -1273,218 +1319,293 @@ where
             .cloned()
             .collect::<Vec<_>>();
         // Find all the source files and hash them
-        let source_hashes_pool = pool.clone();
-        let source_files = get_source_files(
-            creator,
-            &crate_name,
-            &executable,
-            &filtered_arguments,
-            &cwd,
-            &env_vars,
-            pool,
-        );
-        let source_files_and_hashes = source_files.and_then(move |source_files| {
-            hash_all(&source_files, &source_hashes_pool)
-                .map(|source_hashes| (source_files, source_hashes))
-        });
+        let source_files_and_hashes = async {
+            let source_files = get_source_files(
+                creator,
+                &crate_name,
+                &executable,
+                &filtered_arguments,
+                &cwd,
+                &env_vars,
+                pool,
+            )
+            .await?;
+            let source_hashes = input_digests(&source_files, pool).await?;
+            Ok::<_, Error>((source_files, source_hashes))
+        };
         // Hash the contents of the externs listed on the commandline.
         trace!("[{}]: hashing {} externs", crate_name, externs.len());
         let abs_externs = externs.iter().map(|e| cwd.join(e)).collect::<Vec<_>>();
-        let extern_hashes = hash_all(&abs_externs, pool);
+        let extern_hashes = input_digests(&abs_externs, pool);
         // Hash the contents of the staticlibs listed on the commandline.
         trace!("[{}]: hashing {} staticlibs", crate_name, staticlibs.len());
         let abs_staticlibs = staticlibs.iter().map(|s| cwd.join(s)).collect::<Vec<_>>();
-        let staticlib_hashes = hash_all(&abs_staticlibs, pool);
-        let creator = creator.clone();
-        let hashes = source_files_and_hashes.join3(extern_hashes, staticlib_hashes);
-        Box::new(hashes.and_then(
-            move |((source_files, source_hashes), extern_hashes, staticlib_hashes)| -> SFuture<_> {
-                // If you change any of the inputs to the hash, you should change `CACHE_VERSION`.
-                let mut m = Digest::new();
-                // Hash inputs:
-                // 1. A version
-                m.update(CACHE_VERSION);
-                // 2. compiler_shlibs_digests
-                for d in compiler_shlibs_digests {
-                    m.update(d.as_bytes());
-                }
-                let weak_toolchain_key = m.clone().finish();
-                // 3. The full commandline (self.arguments)
-                // TODO: there will be full paths here, it would be nice to
-                // normalize them so we can get cross-machine cache hits.
-                // A few argument types are not passed in a deterministic order
-                // by cargo: --extern, -L, --cfg. We'll filter those out, sort them,
-                // and append them to the rest of the arguments.
-                let args = {
-                    let (mut sortables, rest): (Vec<_>, Vec<_>) = os_string_arguments
-                        .iter()
-                        // We exclude a few arguments from the hash:
-                        //   -L, --extern, --out-dir
-                        // These contain paths which aren't relevant to the output, and the compiler inputs
-                        // in those paths (rlibs and static libs used in the compilation) are used as hash
-                        // inputs below.
-                        .filter(|&&(ref arg, _)| {
-                            !(arg == "--extern" || arg == "-L" || arg == "--out-dir")
-                        })
-                        // A few argument types were not passed in a deterministic order
-                        // by older versions of cargo: --extern, -L, --cfg. We'll filter the rest of those
-                        // out, sort them, and append them to the rest of the arguments.
-                        .partition(|&&(ref arg, _)| arg == "--cfg");
-                    sortables.sort();
-                    rest.into_iter()
-                        .chain(sortables)
-                        .flat_map(|&(ref arg, ref val)| iter::once(arg).chain(val.as_ref()))
-                        .fold(OsString::new(), |mut a, b| {
-                            a.push(b);
-                            a
-                        })
-                };
-                args.hash(&mut HashToDigest { digest: &mut m });
-                // 4. The digest of all source files (this includes src file from cmdline).
-                // 5. The digest of all files listed on the commandline (self.externs).
-                // 6. The digest of all static libraries listed on the commandline (self.staticlibs).
-                for h in source_hashes
-                    .into_iter()
-                    .chain(extern_hashes)
-                    .chain(staticlib_hashes)
-                {
-                    m.update(h.as_bytes());
+        let staticlib_hashes = input_digests(&abs_staticlibs, pool);
+        // Crates linked for the host also depend on the system linker.
+        let links_host = crate_types.is_host() && emit.contains("link");
+        let linkers = if links_host {
+            let default_linker = if host.contains("msvc") {
+                "link.exe"
+            } else {
+                "cc"
+            };
+            let linker = linker.unwrap_or_else(|| default_linker.into());
+            let path = env_vars
+                .iter()
+                .find(|&&(ref k, _)| k == "PATH")
+                .map(|&(_, ref v)| v.clone());
+            which::which_in(&linker, path, &cwd)
+                .ok()
+                .into_iter()
+                .collect()
+        } else {
+            vec![]
+        };
+        let linker_hashes = compiler_digests(&linkers, pool);
+        let not_cacheable = if links_host && hash_config.rust_host_crates == Some(false) {
+            Some("rust host crate")
+        } else if incremental && hash_config.rust_incremental == Some(false) {
+            Some("incremental")
+        } else {
+            None
+        };
+        let ((source_files, source_hashes), extern_hashes, staticlib_hashes, linker_hashes) =
+            future::try_join4(
+                source_files_and_hashes,
+                extern_hashes,
+                staticlib_hashes,
+                linker_hashes,
+            )
+            .await?;
+        // If you change any of the inputs to the hash, you should change `CACHE_VERSION`.
+        let mut m = RecordingDigest::new(debug_dir().is_some());
+        // Hash inputs:
+        // 1. A version
+        m.update_str("cache version", &String::from_utf8_lossy(CACHE_VERSION));
+        // 2. compiler_shlibs_digests
+        for d in compiler_shlibs_digests {
+            m.update_str("compiler digest", &d);
+        }
+        // Only the target's standard library is packaged, so each
+        // target gets its own toolchain
+        let weak_toolchain_key = format!("{}-{}", m.current(), target);
+        // 3. The full commandline (self.arguments)
+        // TODO: there will be full paths here, it would be nice to
+        // normalize them so we can get cross-machine cache hits.
+        // A few argument types are not passed in a deterministic order
+        // by cargo: --extern, -L, --cfg. We'll filter those out, sort them,
+        // and append them to the rest of the arguments.
+        let args = {
+            let (mut sortables, rest): (Vec<_>, Vec<_>) = os_string_arguments
+                .iter()
+                // We exclude a few arguments from the hash:
+                //   -L, --extern, --out-dir
+                // These contain paths which aren't relevant to the output, and the compiler inputs
+                // in those paths (rlibs and static libs used in the compilation) are used as hash
+                // inputs below.
+                // --emit is hashed below as a set, since its order doesn't matter.
+                .filter(|&&(ref arg, _)| {
+                    !(arg == "--extern" || arg == "-L" || arg == "--out-dir" || arg == "--emit")
+                })
+                // The incremental directory is rustc's business, only whether
+                // there is one is hashed below.
+                .filter(|&&(ref arg, ref val)| {
+                    !(arg == "-C"
+                        && val.as_ref().map_or(false, |val| {
+                            val.to_string_lossy().starts_with("incremental=")
+                        }))
+                })
+                // A few argument types were not passed in a deterministic order
+                // by older versions of cargo: --extern, -L, --cfg. We'll filter the rest of those
+                // out, sort them, and append them to the rest of the arguments.
+                .partition(|&&(ref arg, _)| arg == "--cfg");
+            sortables.sort();
+            rest.into_iter()
+                .chain(sortables)
+                .flat_map(|&(ref arg, ref val)| iter::once(arg).chain(val.as_ref()))
+                .fold(OsString::new(), |mut a, b| {
+                    a.push(b);
+                    a
+                })
+        };
+        m.hash("arguments", &args);
+        if incremental {
+            m.update_str("incremental", "-C incremental");
+        }
+        let mut emit_kinds: Vec<_> = emit.iter().collect();
+        emit_kinds.sort();
+        let digest = m.digest_mut();
+        digest.update(b"--emit=");
+        for e in &emit_kinds {
+            digest.update(e.as_bytes());
+            digest.update(b",");
+        }
+        m.record("emit", || format!("{:?}", emit_kinds));
+        // 4. The digest of all source files (this includes src file from cmdline).
+        // 5. The digest of all files listed on the commandline (self.externs).
+        // 6. The digest of all static libraries listed on the commandline (self.staticlibs).
+        for (name, files, hashes) in &[
+            ("source", &source_files, &source_hashes),
+            ("extern", &abs_externs, &extern_hashes),
+            ("staticlib", &abs_staticlibs, &staticlib_hashes),
+        ] {
+            for (file, h) in files.iter().zip(hashes.iter()) {
+                m.digest_mut().update(h.as_bytes());
+                m.record(name, || format!("{} {}", h, file.display()));
+            }
+        }
+        // 7. Environment variables. Ideally we'd use anything referenced
+        // via env! in the program, but we don't have a way to determine that
+        // currently, and hashing all environment variables is too much, so
+        // we'll just hash the CARGO_ env vars and hope that's sufficient.
+        // Upstream Rust issue tracking getting information about env! usage:
+        // https://github.com/rust-lang/rust/issues/40364
+        let mut env_vars: Vec<_> = env_vars
+            .iter()
+            // Filter out RUSTC_COLOR since we control color usage with command line flags.
+            // rustc reports an error when both are present.
+            .filter(|(ref k, _)| k != "RUSTC_COLOR")
+            .cloned()
+            .collect();
+        env_vars.sort();
+        for &(ref var, ref val) in env_vars.iter() {
+            // CARGO_MAKEFLAGS will have jobserver info which is extremely non-cacheable.
+            if var.starts_with("CARGO_") && var != "CARGO_MAKEFLAGS" {
+                let digest = m.digest_mut();
+                var.hash(&mut HashToDigest { digest });
+                digest.update(b"=");
+                val.hash(&mut HashToDigest { digest });
+                m.record("env var", || format!("{:?}={:?}", var, val));
+            }
+        }
+        // 8. The cwd of the compile. This will wind up in the rlib.
+        m.hash("cwd", &cwd);
+        // 9. For crates linked for the host, the host triple and the digest of
+        // the linker.
+        if links_host {
+            m.update_str("host", &host);
+            for h in linker_hashes {
+                m.update_str("linker digest", &h);
+            }
+        }
+        // Turn arguments into a simple Vec<OsString> to calculate outputs.
+        let flat_os_string_arguments: Vec<OsString> = os_string_arguments
+            .into_iter()
+            .flat_map(|(arg, val)| iter::once(arg).chain(val))
+            .collect();
+        let mut outputs = get_compiler_outputs(
+            creator,
+            &executable,
+            flat_os_string_arguments,
+            &cwd,
+            &env_vars,
+        )
+        .await?;
+        // metadata / dep-info don't ever generate binaries, but
+        // rustc still makes them appear in the --print
+        // file-names output (see
+        // https://github.com/rust-lang/rust/pull/68799).
+        //
+        // So if we see a binary in the rustc output and figure
+        // out that we're not _actually_ generating it, then we
+        // can avoid generating everything that isn't an rlib /
+        // rmeta.
+        //
+        // This can go away once the above rustc PR makes it in.
+        let emit_generates_only_metadata =
+            !emit.is_empty() && emit.iter().all(|e| e == "metadata" || e == "dep-info");
+
+        if emit_generates_only_metadata {
+            outputs.retain(|o| o.ends_with(".rlib") || o.ends_with(".rmeta"));
+        }
+
+        if emit.contains("metadata") {
+            // rustc currently does not report rmeta outputs with --print file-names
+            // --emit metadata the rlib is printed, and with --emit metadata,link
+            // only the rlib is printed.
+            let rlibs: HashSet<_> = outputs
+                .iter()
+                .cloned()
+                .filter(|p| p.ends_with(".rlib"))
+                .collect();
+            for lib in rlibs {
+                let rmeta = lib.replacen(".rlib", ".rmeta", 1);
+                // Do this defensively for future versions of rustc that may
+                // be fixed.
+                if !outputs.contains(&rmeta) {
+                    outputs.push(rmeta);
                 }
-                // 7. Environment variables. Ideally we'd use anything referenced
-                // via env! in the program, but we don't have a way to determine that
-                // currently, and hashing all environment variables is too much, so
-                // we'll just hash the CARGO_ env vars and hope that's sufficient.
-                // Upstream Rust issue tracking getting information about env! usage:
-                // https://github.com/rust-lang/rust/issues/40364
-                let mut env_vars: Vec<_> = env_vars
-                    .iter()
-                    // Filter out RUSTC_COLOR since we control color usage with command line flags.
-                    // rustc reports an error when both are present.
-                    .filter(|(ref k, _)| k != "RUSTC_COLOR")
-                    .cloned()
-                    .collect();
-                env_vars.sort();
-                for &(ref var, ref val) in env_vars.iter() {
-                    // CARGO_MAKEFLAGS will have jobserver info which is extremely non-cacheable.
-                    if var.starts_with("CARGO_") && var != "CARGO_MAKEFLAGS" {
-                        var.hash(&mut HashToDigest { digest: &mut m });
-                        m.update(b"=");
-                        val.hash(&mut HashToDigest { digest: &mut m });
-                    }
+                if !emit.contains("link") {
+                    outputs.retain(|p| *p != lib);
                 }
-                // 8. The cwd of the compile. This will wind up in the rlib.
-                cwd.hash(&mut HashToDigest { digest: &mut m });
-                // Turn arguments into a simple Vec<OsString> to calculate outputs.
-                let flat_os_string_arguments: Vec<OsString> = os_string_arguments
-                    .into_iter()
-                    .flat_map(|(arg, val)| iter::once(arg).chain(val))
-                    .collect();
-                Box::new(
-                    get_compiler_outputs(
-                        &creator,
-                        &executable,
-                        flat_os_string_arguments,
-                        &cwd,
-                        &env_vars,
-                    )
-                    .map(move |mut outputs| {
-                        // metadata / dep-info don't ever generate binaries, but
-                        // rustc still makes them appear in the --print
-                        // file-names output (see
-                        // https://github.com/rust-lang/rust/pull/68799).
-                        //
-                        // So if we see a binary in the rustc output and figure
-                        // out that we're not _actually_ generating it, then we
-                        // can avoid generating everything that isn't an rlib /
-                        // rmeta.
-                        //
-                        // This can go away once the above rustc PR makes it in.
-                        let emit_generates_only_metadata = !emit.is_empty()
-                            && emit.iter().all(|e| e == "metadata" || e == "dep-info");
-
-                        if emit_generates_only_metadata {
-                            outputs.retain(|o| o.ends_with(".rlib") || o.ends_with(".rmeta"));
-                        }
+            }
+        }
+        // Nor is the rmeta of a checked binary reported at all.
+        if let Some(rmeta) = rmeta {
+            let rmeta = rmeta.to_string_lossy().into_owned();
+            if !outputs.contains(&rmeta) {
+                outputs.push(rmeta);
+            }
+        }
 
-                        if emit.contains("metadata") {
-                            // rustc currently does not report rmeta outputs with --print file-names
-                            // --emit metadata the rlib is printed, and with --emit metadata,link
-                            // only the rlib is printed.
-                            let rlibs: HashSet<_> = outputs
-                                .iter()
-                                .cloned()
-                                .filter(|p| p.ends_with(".rlib"))
-                                .collect();
-                            for lib in rlibs {
-                                let rmeta = lib.replacen(".rlib", ".rmeta", 1);
-                                // Do this defensively for future versions of rustc that may
-                                // be fixed.
-                                if !outputs.contains(&rmeta) {
-                                    outputs.push(rmeta);
-                                }
-                                if !emit.contains("link") {
-                                    outputs.retain(|p| *p != lib);
-                                }
-                            }
-                        }
+        // Convert output files into a map of basename -> full
+        // path, and remove some unneeded / non-existing ones,
+        // see https://github.com/rust-lang/rust/pull/68799.
+        let mut outputs = outputs
+            .into_iter()
+            .map(|o| {
+                let p = output_dir.join(&o);
+                (o, p)
+            })
+            .collect::<HashMap<_, _>>();
+        let dep_info = if let Some(dep_info) = dep_info {
+            let p = output_dir.join(&dep_info);
+            outputs.insert(dep_info.to_string_lossy().into_owned(), p.clone());
+            Some(p)
+        } else {
+            None
+        };
+        let mut arguments = arguments;
+        // Request color output unless json was requested. The client will strip colors if needed.
+        if !has_json {
+            arguments.push(Argument::WithValue(
+                "--color",
+                ArgData::Color("always".into()),
+                ArgDisposition::Separated,
+            ));
+        }
 
-                        // Convert output files into a map of basename -> full
-                        // path, and remove some unneeded / non-existing ones,
-                        // see https://github.com/rust-lang/rust/pull/68799.
-                        let mut outputs = outputs
-                            .into_iter()
-                            .map(|o| {
-                                let p = output_dir.join(&o);
-                                (o, p)
-                            })
-                            .collect::<HashMap<_, _>>();
-                        let dep_info = if let Some(dep_info) = dep_info {
-                            let p = output_dir.join(&dep_info);
-                            outputs.insert(dep_info.to_string_lossy().into_owned(), p.clone());
-                            Some(p)
-                        } else {
-                            None
-                        };
-                        let mut arguments = arguments;
-                        // Request color output unless json was requested. The client will strip colors if needed.
-                        if !has_json {
-                            arguments.push(Argument::WithValue(
-                                "--color",
-                                ArgData::Color("always".into()),
-                                ArgDisposition::Separated,
-                            ));
-                        }
+        let inputs = source_files
+            .into_iter()
+            .chain(abs_externs)
+            .chain(abs_staticlibs)
+            .collect();
 
-                        let inputs = source_files
-                            .into_iter()
-                            .chain(abs_externs)
-                            .chain(abs_staticlibs)
-                            .collect();
-
-                        HashResult {
-                            key: m.finish(),
-                            compilation: Box::new(RustCompilation {
-                                executable,
-                                host,
-                                sysroot,
-                                arguments,
-                                inputs,
-                                outputs,
-                                crate_link_paths,
-                                crate_name,
-                                crate_types,
-                                dep_info,
-                                cwd,
-                                env_vars,
-                                #[cfg(feature = "dist-client")]
-                                rlib_dep_reader,
-                            }),
-                            weak_toolchain_key,
-                        }
-                    }),
-                )
-            },
-        ))
+        let (key, hash_inputs) = m.finish();
+        Ok(HashResult {
+            key,
+            compilation: Box::new(RustCompilation {
+                executable,
+                host,
+                target,
+                sysroot,
+                arguments,
+                inputs,
+                outputs,
+                crate_link_paths,
+                crate_name,
+                crate_types,
+                dep_info,
+                cwd,
+                env_vars,
+                incremental,
+                not_cacheable,
+                #[cfg(feature = "dist-client")]
+                rlib_dep_reader,
+            }),
+            weak_toolchain_key,
+            hash_inputs,
+        })
     }
 
     fn color_mode(&self) -> ColorMode {
@@ -1495,6 +1616,14 @@ where
         Cow::Borrowed(&self.parsed_args.crate_name)
     }
 
+    fn crate_name(&self) -> Option<&str> {
+        Some(&self.parsed_args.crate_name)
+    }
+
+    fn cache_schema(&self) -> String {
+        cache_schema("rust", CACHE_VERSION)
+    }
+
     fn box_clone(&self) -> Box<dyn CompilerHasher<T>> {
         Box::new((*self).clone())
     }
@@ -1510,6 +1639,8 @@ impl Compilation for RustCompilation {
             ref executable,
             ref arguments,
             ref crate_name,
+            ref crate_types,
+            incremental,
             ref cwd,
             ref env_vars,
             ref host,
@@ -1521,6 +1652,8 @@ impl Compilation for RustCompilation {
         #[cfg(not(feature = "dist-client"))]
         {
             let _ = path_transformer;
+            let _ = crate_types;
+            let _ = incremental;
             let _ = host;
             let _ = sysroot;
         }
@@ -1553,6 +1686,12 @@ impl Compilation for RustCompilation {
                 };
             }
 
+            // The system linker of the build server would link a different host,
+            // and the incremental directory only exists locally.
+            if crate_types.is_host() || incremental {
+                return None;
+            }
+
             let mut dist_arguments = vec![];
             let mut saw_target = false;
 
@@ -1653,6 +1792,8 @@ impl Compilation for RustCompilation {
         let RustCompilation {
             inputs,
             crate_link_paths,
+            host,
+            target,
             sysroot,
             crate_types,
             dep_info,
@@ -1674,7 +1815,11 @@ impl Compilation for RustCompilation {
             path_transformer,
             rlib_dep_reader,
         });
-        let toolchain_packager = Box::new(RustToolchainPackager { sysroot });
+        let toolchain_packager = Box::new(RustToolchainPackager {
+            sysroot,
+            host,
+            target: Some(target),
+        });
         let outputs_rewriter = Box::new(RustOutputsRewriter { dep_info });
 
         Ok((inputs_packager, toolchain_packager, outputs_rewriter))
@@ -1683,6 +1828,10 @@ impl Compilation for RustCompilation {
     fn outputs<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a str, &'a Path)> + 'a> {
         Box::new(self.outputs.iter().map(|(k, v)| (k.as_str(), &**v)))
     }
+
+    fn not_cacheable_reason(&self) -> Option<&'static str> {
+        self.not_cacheable
+    }
 }
 
 // TODO: we do end up with slashes facing the wrong way, but Windows is agnostic so it's
@@ -1848,6 +1997,8 @@ impl pkg::InputsPackager for RustInputsPackager {
             CrateTypes {
                 rlib: true,
                 staticlib: false,
+                proc_macro: false,
+                bin: false,
             }
         );
 
@@ -1896,17 +2047,33 @@ impl pkg::InputsPackager for RustInputsPackager {
 #[allow(unused)]
 struct RustToolchainPackager {
     sysroot: PathBuf,
+    host: String,
+    /// The target to package the standard library for, or `None` for all
+    /// the installed targets.
+    target: Option<String>,
+}
+
+#[cfg(feature = "dist-client")]
+#[cfg(windows)]
+impl pkg::ToolchainPackager for RustToolchainPackager {
+    fn write_pkg(self: Box<Self>, _f: fs::File) -> Result<()> {
+        bail!("Automatic packaging of Rust toolchains is not supported on Windows")
+    }
 }
 
 #[cfg(feature = "dist-client")]
-#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+#[cfg(any(all(target_os = "linux", target_arch = "x86_64"), target_os = "macos"))]
 impl pkg::ToolchainPackager for RustToolchainPackager {
     fn write_pkg(self: Box<Self>, f: fs::File) -> Result<()> {
         info!(
             "Packaging Rust compiler for sysroot {}",
             self.sysroot.display()
         );
-        let RustToolchainPackager { sysroot } = *self;
+        let RustToolchainPackager {
+            sysroot,
+            host,
+            target,
+        } = *self;
 
         let mut package_builder = pkg::ToolchainPackageBuilder::new();
         package_builder.add_common()?;
@@ -1918,13 +2085,50 @@ impl pkg::ToolchainPackager for RustToolchainPackager {
         package_builder.add_dir_contents(&bins_path)?;
         if BINS_DIR != LIBS_DIR {
             let libs_path = sysroot.join(LIBS_DIR);
-            package_builder.add_dir_contents(&libs_path)?
+            match target {
+                None => package_builder.add_dir_contents(&libs_path)?,
+                Some(target) => add_target_libs(&mut package_builder, &libs_path, &host, &target)?,
+            }
         }
 
         package_builder.into_compressed_tar(f)
     }
 }
 
+/// Add the libraries in the sysroot's `libs_path`, with the standard
+/// libraries of only the host, which rustc may need itself, and `target`.
+/// Any number of other targets may be installed.
+#[cfg(feature = "dist-client")]
+#[cfg(any(all(target_os = "linux", target_arch = "x86_64"), target_os = "macos"))]
+fn add_target_libs(
+    package_builder: &mut pkg::ToolchainPackageBuilder,
+    libs_path: &Path,
+    host: &str,
+    target: &str,
+) -> Result<()> {
+    let rustlib_path = libs_path.join("rustlib");
+    let target_path = rustlib_path.join(target);
+    if !target_path.is_dir() {
+        bail!(
+            "The standard library for {} isn't installed in {}",
+            target,
+            rustlib_path.display()
+        )
+    }
+    for entry in fs::read_dir(libs_path)? {
+        let path = entry?.path();
+        if path != rustlib_path {
+            package_builder.add_dir_contents(&path)?
+        }
+    }
+    package_builder.add_dir_contents(&target_path)?;
+    let host_path = rustlib_path.join(host);
+    if host != target && host_path.is_dir() {
+        package_builder.add_dir_contents(&host_path)?
+    }
+    Ok(())
+}
+
 #[cfg(feature = "dist-client")]
 struct RustOutputsRewriter {
     dep_info: Option<PathBuf>,
@@ -2293,6 +2497,7 @@ mod test {
     use crate::compiler::*;
     use crate::mock_command::*;
     use crate::test::utils::*;
+    use futures::executor::block_on;
     use itertools::Itertools;
     use std::ffi::OsStr;
     use std::fs::File;
@@ -2506,7 +2711,7 @@ mod test {
             "--crate-type",
             "lib"
         );
-        let r = fails!(
+        let h = parses!(
             "--emit",
             "link",
             "foo.rs",
@@ -2519,7 +2724,7 @@ mod test {
             "-C",
             "incremental=/foo"
         );
-        assert_eq!(r, CompilerArguments::CannotCache("incremental", None))
+        assert!(h.incremental);
     }
 
     #[test]
@@ -2650,6 +2855,94 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_parse_arguments_host_crates() {
+        let h = parses!(
+            "--crate-name",
+            "foo_derive",
+            "--crate-type",
+            "proc-macro",
+            "--emit=dep-info,link",
+            "-C",
+            "linker=clang",
+            "foo.rs",
+            "--out-dir",
+            "out"
+        );
+        assert!(h.crate_types.proc_macro);
+        assert!(h.crate_types.is_host());
+        assert_eq!(Some(PathBuf::from("clang")), h.linker);
+        let h = parses!(
+            "--crate-name",
+            "build_script_build",
+            "--crate-type",
+            "bin",
+            "--emit=dep-info,link",
+            "build.rs",
+            "--out-dir",
+            "out"
+        );
+        assert!(h.crate_types.bin);
+        assert!(h.crate_types.is_host());
+    }
+
+    #[test]
+    fn test_parse_arguments_target() {
+        let h = parses!(
+            "--crate-name",
+            "foo",
+            "--crate-type",
+            "lib",
+            "--emit=dep-info,link",
+            "--target",
+            "aarch64-unknown-linux-gnu",
+            "foo.rs",
+            "--out-dir",
+            "out"
+        );
+        assert_eq!(Some("aarch64-unknown-linux-gnu".to_owned()), h.target);
+        let h = parses!(
+            "--crate-name",
+            "foo",
+            "--crate-type",
+            "lib",
+            "--emit=dep-info,link",
+            "foo.rs",
+            "--out-dir",
+            "out"
+        );
+        assert_eq!(None, h.target);
+    }
+
+    #[test]
+    fn test_parse_arguments_metadata_only() {
+        let h = parses!(
+            "--crate-name",
+            "foo",
+            "--crate-type",
+            "bin",
+            "--emit=dep-info,metadata",
+            "-C",
+            "extra-filename=-abcxyz",
+            "foo.rs",
+            "--out-dir",
+            "out"
+        );
+        assert!(h.crate_types.bin);
+        assert_eq!(Some(PathBuf::from("libfoo-abcxyz.rmeta")), h.rmeta);
+        let h = parses!(
+            "--crate-name",
+            "foo",
+            "--crate-type",
+            "lib",
+            "--emit=dep-info,link",
+            "foo.rs",
+            "--out-dir",
+            "out"
+        );
+        assert_eq!(None, h.rmeta);
+    }
+
     #[test]
     fn test_parse_arguments_color() {
         let h = parses!(
@@ -2712,14 +3005,13 @@ mod test {
             &creator,
             Ok(MockChild::new(exit_status(0), "foo\nbar\nbaz", "")),
         );
-        let outputs = get_compiler_outputs(
+        let outputs = block_on(get_compiler_outputs(
             &creator,
             "rustc".as_ref(),
             ovec!("a", "b"),
             "cwd".as_ref(),
             &[],
-        )
-        .wait()
+        ))
         .unwrap();
         assert_eq!(outputs, &["foo", "bar", "baz"]);
     }
@@ -2728,14 +3020,13 @@ mod test {
     fn test_get_compiler_outputs_fail() {
         let creator = new_creator();
         next_command(&creator, Ok(MockChild::new(exit_status(1), "", "error")));
-        assert!(get_compiler_outputs(
+        assert!(block_on(get_compiler_outputs(
             &creator,
             "rustc".as_ref(),
             ovec!("a", "b"),
             "cwd".as_ref(),
             &[]
-        )
-        .wait()
+        ))
         .is_err());
     }
 
@@ -2946,8 +3237,14 @@ c:/foo/bar.rs:
                 crate_types: CrateTypes {
                     rlib: true,
                     staticlib: false,
+                    proc_macro: false,
+                    bin: false,
                 },
+                linker: None,
+                target: None,
+                incremental: false,
                 dep_info: None,
+                rmeta: None,
                 emit,
                 color_mode: ColorMode::Auto,
                 has_json: false,
@@ -2957,8 +3254,8 @@ c:/foo/bar.rs:
         mock_dep_info(&creator, &["foo.rs", "bar.rs"]);
         mock_file_names(&creator, &["foo.rlib", "foo.a"]);
         let pool = ThreadPool::sized(1);
-        let res = hasher
-            .generate_hash_key(
+        let res = block_on(
+            hasher.generate_hash_key(
                 &creator,
                 f.tempdir.path().to_owned(),
                 [
@@ -2970,9 +3267,11 @@ c:/foo/bar.rs:
                 false,
                 &pool,
                 false,
-            )
-            .wait()
-            .unwrap();
+                &HashConfig::default(),
+                &Span::default(),
+            ),
+        )
+        .unwrap();
         let m = Digest::new();
         let empty_digest = m.finish();
 
@@ -2981,8 +3280,12 @@ c:/foo/bar.rs:
         m.update(CACHE_VERSION);
         // sysroot shlibs digests.
         m.update(FAKE_DIGEST.as_bytes());
+        // The toolchain is keyed by the target, which is the host by default.
+        let weak_toolchain_key = format!("{}-x86-64-unknown-unknown-unknown", m.clone().finish());
         // Arguments, with cfgs sorted at the end.
         OsStr::new("ab--cfgabc--cfgxyz").hash(&mut HashToDigest { digest: &mut m });
+        // The emit kinds, sorted.
+        m.update(b"--emit=link,metadata,");
         // bar.rs (source file, from dep-info)
         m.update(empty_digest.as_bytes());
         // foo.rs (source file, from dep-info)
@@ -3001,6 +3304,7 @@ c:/foo/bar.rs:
         f.tempdir.path().hash(&mut HashToDigest { digest: &mut m });
         let digest = m.finish();
         assert_eq!(res.key, digest);
+        assert_eq!(res.weak_toolchain_key, weak_toolchain_key);
         let mut out = res
             .compilation
             .outputs()
@@ -3049,24 +3353,60 @@ c:/foo/bar.rs:
         let pool = ThreadPool::sized(1);
         mock_dep_info(&creator, &["foo.rs"]);
         mock_file_names(&creator, &["foo.rlib"]);
-        hasher
-            .generate_hash_key(
-                &creator,
-                f.tempdir.path().to_owned(),
-                env_vars.to_owned(),
-                false,
-                &pool,
-                false,
-            )
-            .wait()
-            .unwrap()
-            .key
+        block_on(hasher.generate_hash_key(
+            &creator,
+            f.tempdir.path().to_owned(),
+            env_vars.to_owned(),
+            false,
+            &pool,
+            false,
+            &HashConfig::default(),
+            &Span::default(),
+        ))
+        .unwrap()
+        .key
     }
 
     fn nothing(_path: &Path) -> Result<()> {
         Ok(())
     }
 
+    #[test]
+    #[cfg(unix)]
+    fn test_hash_key_host_crate_linker() {
+        use std::os::unix::fs::PermissionsExt;
+        let f = TestFixture::new();
+        let args = &[
+            "--emit",
+            "link",
+            "foo.rs",
+            "--out-dir",
+            "out",
+            "--crate-name",
+            "foo",
+            "--crate-type",
+            "proc-macro",
+            "-C",
+            "linker=mylinker",
+        ];
+        let env_vars = vec![(OsString::from("PATH"), f.tempdir.path().into())];
+        let linker = |contents: &'static [u8]| {
+            move |path: &Path| -> Result<()> {
+                let linker = create_file(path, "mylinker", |mut f| f.write_all(contents))?;
+                fs::set_permissions(linker, fs::Permissions::from_mode(0o755))?;
+                Ok(())
+            }
+        };
+        assert_eq!(
+            hash_key(&f, args, &env_vars, linker(b"linker")),
+            hash_key(&f, args, &env_vars, linker(b"linker"))
+        );
+        assert_neq!(
+            hash_key(&f, args, &env_vars, linker(b"linker")),
+            hash_key(&f, args, &env_vars, linker(b"another linker"))
+        );
+    }
+
     #[test]
     fn test_equal_hashes_externs() {
         // Put some content in the extern rlibs so we can verify that the content hashes are
@@ -3219,6 +3559,32 @@ c:/foo/bar.rs:
         );
     }
 
+    #[test]
+    fn test_equal_hashes_emit_order() {
+        let f = TestFixture::new();
+        let args = |emit| {
+            [
+                "--emit",
+                emit,
+                "foo.rs",
+                "--out-dir",
+                "out",
+                "--crate-name",
+                "foo",
+                "--crate-type",
+                "lib",
+            ]
+        };
+        assert_eq!(
+            hash_key(&f, &args("dep-info,metadata"), &[], nothing),
+            hash_key(&f, &args("metadata,dep-info"), &[], nothing)
+        );
+        assert_neq!(
+            hash_key(&f, &args("dep-info,metadata"), &[], nothing),
+            hash_key(&f, &args("dep-info,metadata,link"), &[], nothing)
+        );
+    }
+
     #[test]
     fn test_equal_hashes_cfg_features() {
         let f = TestFixture::new();
@@ -0,0 +1,623 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::compiler::args::{
+    ArgDisposition, ArgInfo, ArgToStringResult, ArgsIter, Argument, FromArg, IntoArg,
+    NormalizedDisposition, PathTransformerFn, SearchableArgInfo,
+};
+use crate::compiler::c::{CCompilerImpl, CCompilerKind, Language, ParsedArguments};
+use crate::compiler::{Cacheable, ColorMode, CompileCommand, CompilerArguments};
+use crate::dist;
+use crate::errors::*;
+use crate::mock_command::{CommandCreatorSync, RunCommand};
+use crate::util::run_input_output;
+use async_trait::async_trait;
+use log::Level::Trace;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// A unit struct on which to implement `CCompilerImpl` for the TI Code
+/// Generation Tools (cl2000, cl430, armcl, cl6x, ...).
+#[derive(Clone, Debug)]
+pub struct TI;
+
+#[async_trait(?Send)]
+impl CCompilerImpl for TI {
+    fn kind(&self) -> CCompilerKind {
+        CCompilerKind::TI
+    }
+    fn plusplus(&self) -> bool {
+        false
+    }
+    fn parse_arguments(
+        &self,
+        arguments: &[OsString],
+        cwd: &Path,
+    ) -> CompilerArguments<ParsedArguments> {
+        parse_arguments(arguments, cwd, &ARGS[..])
+    }
+
+    async fn preprocess<T>(
+        &self,
+        creator: &T,
+        executable: &Path,
+        parsed_args: &ParsedArguments,
+        cwd: &Path,
+        env_vars: &[(OsString, OsString)],
+        may_dist: bool,
+        _rewrite_includes_only: bool,
+    ) -> Result<process::Output>
+    where
+        T: CommandCreatorSync,
+    {
+        preprocess(creator, executable, parsed_args, cwd, env_vars, may_dist).await
+    }
+
+    fn generate_compile_commands(
+        &self,
+        path_transformer: &mut dist::PathTransformer,
+        executable: &Path,
+        parsed_args: &ParsedArguments,
+        cwd: &Path,
+        env_vars: &[(OsString, OsString)],
+        _rewrite_includes_only: bool,
+    ) -> Result<(CompileCommand, Option<dist::CompileCommand>, Cacheable)> {
+        generate_compile_commands(path_transformer, executable, parsed_args, cwd, env_vars)
+    }
+}
+
+ArgData! { pub
+    DoCompilation,
+    Output(PathBuf),
+    ObjDirectory(PathBuf),
+    PreprocessorArgument(OsString),
+    PreprocessorArgumentPath(PathBuf),
+    PreprocWithCompile,
+    DepFile(PathBuf),
+    TooHardFlag,
+    TooHard(OsString),
+}
+
+use self::ArgData::*;
+
+// Both the long and the short spelling of each option are listed, see
+// "Changing the Compiler's Behavior with Options" in the TI C/C++ compiler
+// user's guides (e.g. SPRU514 for C2000, SLAU132 for MSP430).
+counted_array!(pub static ARGS: [ArgInfo<ArgData>; _] = [
+    take_arg!("--cmd_file", OsString, Concatenated('='), TooHard),
+    flag!("--compile_only", DoCompilation),
+    take_arg!("--define", OsString, Concatenated('='), PreprocessorArgument),
+    flag!("--gen_preprocessor_listing", TooHardFlag),
+    take_arg!("--include_path", PathBuf, Concatenated('='), PreprocessorArgumentPath),
+    flag!("--keep_asm", TooHardFlag),
+    take_arg!("--obj_directory", PathBuf, Concatenated('='), ObjDirectory),
+    take_arg!("--output_file", PathBuf, Concatenated('='), Output),
+    take_arg!("--pp_directory", OsString, Concatenated('='), TooHard),
+    take_arg!("--preinclude", PathBuf, Concatenated('='), PreprocessorArgumentPath),
+    take_arg!("--preproc_dependency", PathBuf, Concatenated('='), DepFile),
+    take_arg!("--preproc_includes", PathBuf, Concatenated('='), DepFile),
+    take_arg!("--preproc_macros", OsString, Concatenated('='), TooHard),
+    flag!("--preproc_only", TooHardFlag),
+    flag!("--preproc_with_comment", TooHardFlag),
+    flag!("--preproc_with_compile", PreprocWithCompile),
+    flag!("--preproc_with_line", TooHardFlag),
+    take_arg!("--undefine", OsString, Concatenated('='), PreprocessorArgument),
+    take_arg!("-@", OsString, Concatenated, TooHard),
+    take_arg!("-D", OsString, CanBeSeparated, PreprocessorArgument),
+    take_arg!("-I", PathBuf, CanBeSeparated, PreprocessorArgumentPath),
+    take_arg!("-U", OsString, CanBeSeparated, PreprocessorArgument),
+    flag!("-al", TooHardFlag),
+    flag!("-c", DoCompilation),
+    take_arg!("-fe", PathBuf, Concatenated('='), Output),
+    take_arg!("-fr", PathBuf, Concatenated('='), ObjDirectory),
+    flag!("-k", TooHardFlag),
+    flag!("-pl", TooHardFlag),
+    flag!("-ppa", PreprocWithCompile),
+    flag!("-ppc", TooHardFlag),
+    take_arg!("-ppd", PathBuf, Concatenated('='), DepFile),
+    take_arg!("-ppi", PathBuf, Concatenated('='), DepFile),
+    flag!("-ppl", TooHardFlag),
+    take_arg!("-ppm", OsString, Concatenated('='), TooHard),
+    flag!("-ppo", TooHardFlag),
+]);
+
+/// Parse `arguments`, determining whether it is supported.
+///
+/// If any of the entries in `arguments` result in a compilation that
+/// cannot be cached, return `CompilerArguments::CannotCache`.
+/// If the commandline described by `arguments` is not compilation,
+/// return `CompilerArguments::NotCompilation`.
+/// Otherwise, return `CompilerArguments::Ok(ParsedArguments)`, with
+/// the `ParsedArguments` struct containing information parsed from
+/// `arguments`.
+pub fn parse_arguments<S>(
+    arguments: &[OsString],
+    _cwd: &Path,
+    arg_info: S,
+) -> CompilerArguments<ParsedArguments>
+where
+    S: SearchableArgInfo<ArgData>,
+{
+    let mut common_args = vec![];
+    let mut compilation = false;
+    let mut compilation_flag = OsString::new();
+    let mut input_arg = None;
+    let mut multiple_input = false;
+    let mut output_arg = None;
+    let mut obj_directory = None;
+    let mut dep_flag = None;
+    let mut dep_file = None;
+    let mut preproc_with_compile = false;
+    let mut preprocessor_args = vec![];
+
+    for arg in ArgsIter::new(arguments.iter().cloned(), arg_info) {
+        let arg = try_or_cannot_cache!(arg, "argument parse");
+        match arg.get_data() {
+            Some(TooHardFlag) | Some(TooHard(_)) => {
+                cannot_cache!(arg.flag_str().expect("Can't be Argument::Raw/UnknownFlag",))
+            }
+            Some(DoCompilation) => {
+                compilation = true;
+                compilation_flag =
+                    OsString::from(arg.flag_str().expect("Compilation flag expected"));
+            }
+            Some(Output(p)) => output_arg = Some(p.clone()),
+            Some(ObjDirectory(p)) => obj_directory = Some(p.clone()),
+            Some(PreprocWithCompile) => preproc_with_compile = true,
+            Some(DepFile(p)) => {
+                if dep_flag.is_some() {
+                    cannot_cache!("multiple dependency outputs");
+                }
+                dep_flag = arg.flag_str();
+                dep_file = Some(p.clone());
+            }
+            Some(PreprocessorArgument(_)) | Some(PreprocessorArgumentPath(_)) => {}
+            None => match arg {
+                Argument::Raw(ref val) => {
+                    if input_arg.is_some() {
+                        multiple_input = true;
+                    }
+                    input_arg = Some(val.clone());
+                }
+                Argument::UnknownFlag(_) => {}
+                _ => unreachable!(),
+            },
+        }
+        let args = match arg.get_data() {
+            Some(PreprocessorArgument(_)) | Some(PreprocessorArgumentPath(_)) => {
+                &mut preprocessor_args
+            }
+            Some(DoCompilation)
+            | Some(Output(_))
+            | Some(ObjDirectory(_))
+            | Some(PreprocWithCompile)
+            | Some(DepFile(_)) => continue,
+            Some(TooHardFlag) | Some(TooHard(_)) => unreachable!(),
+            None => match arg {
+                Argument::Raw(_) => continue,
+                Argument::UnknownFlag(_) => &mut common_args,
+                _ => unreachable!(),
+            },
+        };
+        // Normalize attributes such as "-I foo", "-D FOO=bar", as
+        // "-Ifoo", "-DFOO=bar".
+        let norm = match arg.flag_str() {
+            Some(s) if s.len() == 2 => NormalizedDisposition::Concatenated,
+            _ => NormalizedDisposition::Separated,
+        };
+        args.extend(arg.normalize(norm).iter_os_strings());
+    }
+
+    // Dependency generation without `--preproc_with_compile` stops after
+    // preprocessing.
+    if dep_flag.is_some() && !preproc_with_compile {
+        return CompilerArguments::NotCompilation;
+    }
+    // We only support compilation.
+    if !compilation {
+        return CompilerArguments::NotCompilation;
+    }
+    // Can't cache compilations with multiple inputs.
+    if multiple_input {
+        cannot_cache!("multiple input files");
+    }
+    let input = match input_arg {
+        Some(i) => i,
+        // We can't cache compilation without an input.
+        None => cannot_cache!("no input file"),
+    };
+    let language = match Language::from_file_name(Path::new(&input)) {
+        Some(l) if !l.is_assembler() => l,
+        _ => cannot_cache!("unknown source language"),
+    };
+    let file_name = |ext: &str| {
+        PathBuf::from(
+            Path::new(&input)
+                .with_extension(ext)
+                .file_name()
+                .expect("Input file should have a name"),
+        )
+    };
+
+    let output = match output_arg {
+        Some(o) => o,
+        None => {
+            let obj = file_name("obj");
+            match obj_directory {
+                Some(dir) => dir.join(obj),
+                None => obj,
+            }
+        }
+    };
+
+    let mut outputs = HashMap::new();
+    let mut dependency_args = vec![];
+    if let (Some(flag), Some(dep_file)) = (dep_flag, dep_file) {
+        // The compiler names the dependency file after the source file when
+        // no name is given, so spell that out to know what to cache.
+        let dep_file = if dep_file.as_os_str().is_empty() {
+            file_name("pp")
+        } else {
+            dep_file
+        };
+        let mut dep_arg = OsString::from(flag);
+        dep_arg.push("=");
+        dep_arg.push(&dep_file);
+        dependency_args.push(OsString::from("--preproc_with_compile"));
+        dependency_args.push(dep_arg);
+        outputs.insert("dep", dep_file);
+    }
+    outputs.insert("obj", output);
+
+    CompilerArguments::Ok(ParsedArguments {
+        input: input.into(),
+        language,
+        compilation_flag,
+        depfile: None,
+        outputs,
+        dependency_args,
+        preprocessor_args,
+        common_args,
+        extra_hash_files: vec![],
+        msvc_show_includes: false,
+        profile_generate: false,
+        color_mode: ColorMode::Auto,
+        arch_args: vec![],
+        compilation_db_fragment: None,
+        index_args: vec![],
+    })
+}
+
+/// Run the preprocessor.
+///
+/// The TI compilers can't write preprocessed output to stdout, so the
+/// `.pp` file is written to a temporary directory and read back.
+pub async fn preprocess<T>(
+    creator: &T,
+    executable: &Path,
+    parsed_args: &ParsedArguments,
+    cwd: &Path,
+    env_vars: &[(OsString, OsString)],
+    _may_dist: bool,
+) -> Result<process::Output>
+where
+    T: CommandCreatorSync,
+{
+    let tempdir = tempfile::Builder::new()
+        .prefix("sccache_ti")
+        .tempdir()
+        .context("failed to create temporary directory")?;
+    let mut pp_directory = OsString::from("--pp_directory=");
+    pp_directory.push(tempdir.path());
+    let preprocessed = tempdir.path().join(
+        Path::new(&parsed_args.input)
+            .with_extension("pp")
+            .file_name()
+            .expect("Input file should have a name"),
+    );
+
+    let mut cmd = creator.clone().new_command_sync(&executable);
+    cmd.arg("--preproc_only")
+        .arg(pp_directory)
+        .arg(&parsed_args.input)
+        .args(&parsed_args.preprocessor_args)
+        .args(&parsed_args.common_args)
+        .env_clear()
+        .envs(env_vars.iter().map(|&(ref k, ref v)| (k, v)))
+        .current_dir(cwd);
+
+    if log_enabled!(Trace) {
+        trace!("preprocess: {:?}", cmd);
+    }
+    let mut output = run_input_output(cmd, None).await?;
+    output.stdout = fs::read(&preprocessed).with_context(|| {
+        format!(
+            "failed to read preprocessor output {}",
+            preprocessed.display()
+        )
+    })?;
+    drop(tempdir);
+    Ok(output)
+}
+
+pub fn generate_compile_commands(
+    _path_transformer: &mut dist::PathTransformer,
+    executable: &Path,
+    parsed_args: &ParsedArguments,
+    cwd: &Path,
+    env_vars: &[(OsString, OsString)],
+) -> Result<(CompileCommand, Option<dist::CompileCommand>, Cacheable)> {
+    trace!("compile");
+
+    let out_file = match parsed_args.outputs.get("obj") {
+        Some(obj) => obj,
+        None => return Err(anyhow!("Missing object file output")),
+    };
+    let mut output_arg = OsString::from("--output_file=");
+    output_arg.push(out_file);
+
+    let mut arguments: Vec<OsString> = vec![
+        parsed_args.compilation_flag.clone(),
+        parsed_args.input.clone().into(),
+        output_arg,
+    ];
+    arguments.extend(parsed_args.preprocessor_args.clone());
+    arguments.extend(parsed_args.common_args.clone());
+    arguments.extend(parsed_args.dependency_args.clone());
+    let command = CompileCommand {
+        executable: executable.to_owned(),
+        arguments,
+        env_vars: env_vars.to_owned(),
+        cwd: cwd.to_owned(),
+    };
+
+    Ok((command, None, Cacheable::Yes))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        dist, generate_compile_commands, parse_arguments, Language, OsString, ParsedArguments,
+        PathBuf, ARGS,
+    };
+    use crate::compiler::*;
+    use crate::mock_command::*;
+    use crate::test::utils::*;
+    use futures::executor::block_on;
+
+    fn parse_arguments_(arguments: Vec<String>) -> CompilerArguments<ParsedArguments> {
+        let args = arguments.iter().map(OsString::from).collect::<Vec<_>>();
+        parse_arguments(&args, ".".as_ref(), &ARGS[..])
+    }
+
+    #[test]
+    fn test_parse_arguments_simple() {
+        let args = stringvec!["--compile_only", "foo.c", "--output_file=foo.obj"];
+        let ParsedArguments {
+            input,
+            language,
+            compilation_flag,
+            outputs,
+            preprocessor_args,
+            common_args,
+            ..
+        } = match parse_arguments_(args) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(Some("foo.c"), input.to_str());
+        assert_eq!(Language::C, language);
+        assert_eq!(Some("--compile_only"), compilation_flag.to_str());
+        assert_map_contains!(outputs, ("obj", PathBuf::from("foo.obj")));
+        assert!(preprocessor_args.is_empty());
+        assert!(common_args.is_empty());
+    }
+
+    #[test]
+    fn test_parse_arguments_default_name() {
+        let args = stringvec!["-c", "src/foo.cpp"];
+        let ParsedArguments {
+            language, outputs, ..
+        } = match parse_arguments_(args) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(Language::Cxx, language);
+        assert_map_contains!(outputs, ("obj", PathBuf::from("foo.obj")));
+    }
+
+    #[test]
+    fn test_parse_arguments_obj_directory() {
+        let args = stringvec!["-c", "foo.c", "--obj_directory=build"];
+        let ParsedArguments { outputs, .. } = match parse_arguments_(args) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_map_contains!(outputs, ("obj", PathBuf::from("build/foo.obj")));
+    }
+
+    #[test]
+    fn test_parse_arguments_values() {
+        let args = stringvec![
+            "-c",
+            "foo.c",
+            "-v28",
+            "--include_path=include",
+            "-I",
+            "other",
+            "--define=FOO=1",
+            "-fe=foo.obj"
+        ];
+        let ParsedArguments {
+            outputs,
+            preprocessor_args,
+            common_args,
+            ..
+        } = match parse_arguments_(args) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_map_contains!(outputs, ("obj", PathBuf::from("foo.obj")));
+        assert_eq!(
+            ovec!["--include_path=include", "-Iother", "--define=FOO=1"],
+            preprocessor_args
+        );
+        assert_eq!(ovec!["-v28"], common_args);
+    }
+
+    #[test]
+    fn test_parse_arguments_preproc_dependency() {
+        let args = stringvec![
+            "-c",
+            "foo.c",
+            "--preproc_with_compile",
+            "--preproc_dependency=foo.dep",
+            "--output_file=foo.obj"
+        ];
+        let ParsedArguments {
+            outputs,
+            dependency_args,
+            ..
+        } = match parse_arguments_(args) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_map_contains!(
+            outputs,
+            ("obj", PathBuf::from("foo.obj")),
+            ("dep", PathBuf::from("foo.dep"))
+        );
+        assert_eq!(
+            ovec!["--preproc_with_compile", "--preproc_dependency=foo.dep"],
+            dependency_args
+        );
+    }
+
+    #[test]
+    fn test_parse_arguments_preproc_dependency_default_name() {
+        let args = stringvec!["-c", "foo.c", "-ppa", "-ppd"];
+        let ParsedArguments {
+            outputs,
+            dependency_args,
+            ..
+        } = match parse_arguments_(args) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_map_contains!(
+            outputs,
+            ("obj", PathBuf::from("foo.obj")),
+            ("dep", PathBuf::from("foo.pp"))
+        );
+        assert_eq!(
+            ovec!["--preproc_with_compile", "-ppd=foo.pp"],
+            dependency_args
+        );
+    }
+
+    #[test]
+    fn test_parse_arguments_preproc_dependency_only() {
+        assert_eq!(
+            CompilerArguments::NotCompilation,
+            parse_arguments_(stringvec!["-c", "foo.c", "--preproc_dependency=foo.dep"])
+        );
+    }
+
+    #[test]
+    fn test_parse_arguments_not_compile() {
+        assert_eq!(
+            CompilerArguments::NotCompilation,
+            parse_arguments_(stringvec![
+                "foo.obj",
+                "--run_linker",
+                "--output_file=foo.out"
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_arguments_too_hard() {
+        assert_eq!(
+            CompilerArguments::CannotCache("--keep_asm", None),
+            parse_arguments_(stringvec!["-c", "foo.c", "--keep_asm"])
+        );
+        assert_eq!(
+            CompilerArguments::CannotCache("-ppo", None),
+            parse_arguments_(stringvec!["-c", "foo.c", "-ppo"])
+        );
+        assert_eq!(
+            CompilerArguments::CannotCache("--cmd_file", None),
+            parse_arguments_(stringvec!["-c", "foo.c", "--cmd_file=opts.txt"])
+        );
+        assert_eq!(
+            CompilerArguments::CannotCache("multiple dependency outputs", None),
+            parse_arguments_(stringvec!["-c", "foo.c", "-ppa", "-ppd", "-ppi"])
+        );
+    }
+
+    #[test]
+    fn test_parse_arguments_too_many_inputs() {
+        assert_eq!(
+            CompilerArguments::CannotCache("multiple input files", None),
+            parse_arguments_(stringvec!["-c", "foo.c", "bar.c"])
+        );
+    }
+
+    #[test]
+    fn test_compile_simple() {
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let parsed_args = ParsedArguments {
+            input: "foo.c".into(),
+            language: Language::C,
+            compilation_flag: "-c".into(),
+            depfile: None,
+            outputs: vec![("obj", "foo.obj".into())].into_iter().collect(),
+            dependency_args: vec![],
+            preprocessor_args: vec![],
+            common_args: vec![],
+            extra_hash_files: vec![],
+            msvc_show_includes: false,
+            profile_generate: false,
+            color_mode: ColorMode::Auto,
+            arch_args: vec![],
+            compilation_db_fragment: None,
+            index_args: vec![],
+        };
+        let compiler = &f.bins[0];
+        // Compiler invocation.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "", "")));
+        let mut path_transformer = dist::PathTransformer::default();
+        let (command, dist_command, cacheable) = generate_compile_commands(
+            &mut path_transformer,
+            &compiler,
+            &parsed_args,
+            f.tempdir.path(),
+            &[],
+        )
+        .unwrap();
+        assert_eq!(
+            ovec!["-c", "foo.c", "--output_file=foo.obj"],
+            command.arguments
+        );
+        assert!(dist_command.is_none());
+        let _ = block_on(command.execute(&creator));
+        assert_eq!(Cacheable::Yes, cacheable);
+        // Ensure that we ran all processes.
+        assert_eq!(0, creator.lock().unwrap().children.len());
+    }
+}
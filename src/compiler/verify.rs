@@ -0,0 +1,204 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Spot-checking cache hits: with `SCCACHE_VERIFY_HITS` set to a share of
+//! them, such as `1%`, that many hits are also compiled locally and their
+//! objects compared byte for byte with those from the cache. Entries that
+//! don't match are reported, and removed from the storage if
+//! `SCCACHE_VERIFY_QUARANTINE` is `1`.
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+struct Settings {
+    /// The share of hits to check, between 0 and 1.
+    rate: f64,
+    quarantine: bool,
+}
+
+impl Settings {
+    fn from_env() -> Settings {
+        let rate = env::var("SCCACHE_VERIFY_HITS")
+            .ok()
+            .and_then(|value| {
+                let rate = parse_rate(&value);
+                if rate.is_none() {
+                    warn!("Invalid SCCACHE_VERIFY_HITS {:?}, ignoring it", value);
+                }
+                rate
+            })
+            .unwrap_or(0.0);
+        Settings {
+            rate,
+            quarantine: env::var("SCCACHE_VERIFY_QUARANTINE").map_or(false, |value| value == "1"),
+        }
+    }
+}
+
+lazy_static! {
+    static ref SETTINGS: Settings = Settings::from_env();
+}
+
+/// A share of hits, as a percentage like `1%` or a fraction like `0.01`.
+fn parse_rate(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let rate = match value.strip_suffix('%') {
+        Some(percent) => percent.trim().parse::<f64>().ok()? / 100.0,
+        None => value.parse().ok()?,
+    };
+    if (0.0..=1.0).contains(&rate) {
+        Some(rate)
+    } else {
+        None
+    }
+}
+
+/// Whether to check this hit.
+pub fn sample() -> bool {
+    SETTINGS.rate > 0.0 && rand::random::<f64>() < SETTINGS.rate
+}
+
+/// Whether entries that don't match are removed from the storage.
+pub fn quarantine() -> bool {
+    SETTINGS.quarantine
+}
+
+/// What the compile's outputs, named as in the cache entry, hold, or `None`
+/// for those that weren't written.
+pub type Outputs = HashMap<String, Option<Vec<u8>>>;
+
+pub fn read_outputs(paths: &HashMap<String, PathBuf>) -> io::Result<Outputs> {
+    paths
+        .iter()
+        .map(|(name, path)| {
+            let bytes = match fs::read(path) {
+                Ok(bytes) => Some(bytes),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            };
+            Ok((name.clone(), bytes))
+        })
+        .collect()
+}
+
+/// An output of a hit that differs from the one compiled.
+#[derive(Debug, PartialEq)]
+pub struct Mismatch {
+    pub name: String,
+    /// The lengths of the output from the cache and compiled, if written.
+    pub cached: Option<usize>,
+    pub compiled: Option<usize>,
+    /// The offset of the first byte that differs, if both were written.
+    pub first_difference: Option<usize>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let len = |len: Option<usize>| len.map_or("missing".to_owned(), |len| len.to_string());
+        write!(
+            f,
+            "{}: {} bytes from the cache, {} compiled",
+            self.name,
+            len(self.cached),
+            len(self.compiled)
+        )?;
+        if let Some(offset) = self.first_difference {
+            write!(f, ", first differing at byte {}", offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// The outputs that differ between those extracted from the cache and
+/// those compiled, by name.
+pub fn compare(cached: &Outputs, compiled: &Outputs) -> Vec<Mismatch> {
+    let mut names: Vec<_> = cached.keys().chain(compiled.keys()).collect();
+    names.sort();
+    names.dedup();
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let cached = cached.get(name).and_then(Option::as_ref);
+            let compiled = compiled.get(name).and_then(Option::as_ref);
+            if cached == compiled {
+                return None;
+            }
+            let first_difference = match (cached, compiled) {
+                (Some(cached), Some(compiled)) => Some(
+                    cached
+                        .iter()
+                        .zip(compiled.iter())
+                        .position(|(a, b)| a != b)
+                        .unwrap_or_else(|| cached.len().min(compiled.len())),
+                ),
+                _ => None,
+            };
+            Some(Mismatch {
+                name: name.clone(),
+                cached: cached.map(Vec::len),
+                compiled: compiled.map(Vec::len),
+                first_difference,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("1%"), Some(0.01));
+        assert_eq!(parse_rate("0.25"), Some(0.25));
+        assert_eq!(parse_rate("100%"), Some(1.0));
+        assert_eq!(parse_rate("150%"), None);
+        assert_eq!(parse_rate("often"), None);
+    }
+
+    #[test]
+    fn test_compare() {
+        let outputs = |entries: &[(&str, Option<&[u8]>)]| -> Outputs {
+            entries
+                .iter()
+                .map(|&(name, bytes)| (name.to_owned(), bytes.map(<[u8]>::to_vec)))
+                .collect()
+        };
+        let cached = outputs(&[("obj", Some(b"abcd")), ("d", Some(b"deps")), ("pdb", None)]);
+        assert!(compare(&cached, &cached).is_empty());
+
+        let compiled = outputs(&[("obj", Some(b"abXdef")), ("d", None), ("pdb", None)]);
+        assert_eq!(
+            compare(&cached, &compiled),
+            vec![
+                Mismatch {
+                    name: "d".to_owned(),
+                    cached: Some(4),
+                    compiled: None,
+                    first_difference: None,
+                },
+                Mismatch {
+                    name: "obj".to_owned(),
+                    cached: Some(4),
+                    compiled: Some(6),
+                    first_difference: Some(2),
+                },
+            ]
+        );
+    }
+}
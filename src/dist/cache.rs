@@ -29,6 +29,7 @@ mod client {
     #[derive(Clone, Debug)]
     pub struct CustomToolchain {
         archive: PathBuf,
+        archive_signature: Option<PathBuf>,
         compiler_executable: String,
     }
 
@@ -38,6 +39,8 @@ mod client {
         cache: Mutex<TcCache>,
         // Lookup from dist toolchain -> path to custom toolchain archive
         custom_toolchain_archives: Mutex<HashMap<Toolchain, PathBuf>>,
+        // Lookup from dist toolchain -> path to the signature of a custom toolchain archive
+        custom_toolchain_signatures: Mutex<HashMap<Toolchain, PathBuf>>,
         // Lookup from local path -> toolchain details
         // The Option<Toolchain> could be populated on startup, but it's lazy for efficiency
         custom_toolchain_paths: Mutex<HashMap<PathBuf, (CustomToolchain, Option<Toolchain>)>>,
@@ -97,6 +100,7 @@ mod client {
                         compiler_executable,
                         archive,
                         archive_compiler_executable,
+                        archive_signature,
                     } => {
                         debug!(
                             "Registering custom toolchain for {}",
@@ -104,6 +108,7 @@ mod client {
                         );
                         let custom_tc = CustomToolchain {
                             archive: archive.clone(),
+                            archive_signature: archive_signature.clone(),
                             compiler_executable: archive_compiler_executable.clone(),
                         };
                         if custom_toolchain_paths
@@ -144,6 +149,7 @@ mod client {
                 cache_dir,
                 cache,
                 custom_toolchain_archives: Mutex::new(HashMap::new()),
+                custom_toolchain_signatures: Mutex::new(HashMap::new()),
                 custom_toolchain_paths,
                 disabled_toolchains,
                 // TODO: shouldn't clear on restart, but also should have some
@@ -174,6 +180,17 @@ mod client {
             };
             Ok(Some(file))
         }
+        // The signature configured for a custom toolchain
+        pub fn get_custom_toolchain_signature(&self, tc: &Toolchain) -> Result<Option<String>> {
+            match self.custom_toolchain_signatures.lock().unwrap().get(tc) {
+                Some(path) => fs::read_to_string(path)
+                    .map(|signature| Some(signature.trim().to_owned()))
+                    .with_context(|| {
+                        format!("could not read toolchain signature {}", path.display())
+                    }),
+                None => Ok(None),
+            }
+        }
         // If the toolchain doesn't already exist, create it and insert into the cache
         pub fn put_toolchain(
             &self,
@@ -196,19 +213,41 @@ mod client {
             // to create the same toolchain, just a waste of time
             let mut cache = self.cache.lock().unwrap();
             if let Some(archive_id) = self.weak_to_strong(weak_key) {
-                debug!("Using cached toolchain {} -> {}", weak_key, archive_id);
-                return Ok((Toolchain { archive_id }, None));
+                let tc = Toolchain { archive_id };
+                // The toolchain may have been evicted from the cache since
+                if cache.contains_toolchain(&tc) {
+                    debug!("Using cached toolchain {} -> {}", weak_key, tc.archive_id);
+                    return Ok((tc, None));
+                }
+                debug!("Toolchain for weak key {} is no longer cached", weak_key);
+            } else {
+                debug!("Weak key {} appears to be new", weak_key);
             }
-            debug!("Weak key {} appears to be new", weak_key);
             let tmpfile = tempfile::NamedTempFile::new_in(self.cache_dir.join("toolchain_tmp"))?;
             toolchain_packager
                 .write_pkg(tmpfile.reopen()?)
                 .context("Could not package toolchain")?;
             let tc = cache.insert_file(tmpfile.path())?;
-            self.record_weak(weak_key.to_owned(), tc.archive_id.clone())?;
+            self.record_weak(&cache, weak_key.to_owned(), tc.archive_id.clone())?;
             Ok((tc, None))
         }
 
+        /// Remove every packaged toolchain, returning the bytes freed. Custom
+        /// toolchain archives are left alone.
+        pub fn clear(&self) -> Result<u64> {
+            let mut cache = self.cache.lock().unwrap();
+            let freed = cache.size();
+            let capacity = cache.capacity();
+            let tc_cache_dir = self.cache_dir.join("tc");
+            fs::remove_dir_all(&tc_cache_dir).context("failed to remove toolchain cache")?;
+            *cache = TcCache::new(&tc_cache_dir, capacity)
+                .context("failed to initialise a toolchain cache")?;
+            let mut weak_map = self.weak_map.lock().unwrap();
+            weak_map.clear();
+            self.save_weak_map(&weak_map)?;
+            Ok(freed)
+        }
+
         pub fn get_custom_toolchain(
             &self,
             compiler_path: &Path,
@@ -231,6 +270,12 @@ mod client {
                     };
                     let tc = Toolchain { archive_id };
                     *maybe_tc = Some(tc.clone());
+                    if let Some(signature) = &custom_tc.archive_signature {
+                        self.custom_toolchain_signatures
+                            .lock()
+                            .unwrap()
+                            .insert(tc.clone(), signature.clone());
+                    }
                     // If this entry already exists, someone has two custom toolchains with the same strong hash
                     if let Some(old_path) = self
                         .custom_toolchain_archives
@@ -265,14 +310,24 @@ mod client {
                 .get(weak_key)
                 .map(String::to_owned)
         }
-        fn record_weak(&self, weak_key: String, key: String) -> Result<()> {
+        fn record_weak(&self, cache: &TcCache, weak_key: String, key: String) -> Result<()> {
             let mut weak_map = self.weak_map.lock().unwrap();
             weak_map.insert(weak_key, key);
+            // Forget toolchains that have been evicted, so the map doesn't grow forever
+            weak_map.retain(|_, archive_id| {
+                cache.contains_toolchain(&Toolchain {
+                    archive_id: archive_id.clone(),
+                })
+            });
+            self.save_weak_map(&weak_map)
+                .context("failed to enter toolchain in weak map")
+        }
+        fn save_weak_map(&self, weak_map: &HashMap<String, String>) -> Result<()> {
             let weak_map_path = self.cache_dir.join("weak_map.json");
             fs::File::create(weak_map_path)
                 .map_err(Error::from)
-                .and_then(|f| serde_json::to_writer(f, &*weak_map).map_err(Error::from))
-                .context("failed to enter toolchain in weak map")
+                .and_then(|f| serde_json::to_writer(f, weak_map).map_err(Error::from))
+                .context("failed to write toolchain weak map")
         }
     }
 
@@ -290,13 +345,29 @@ mod client {
                 Box::new(PanicToolchainPackager)
             }
         }
-        #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+        #[cfg(any(
+            all(target_os = "linux", target_arch = "x86_64"),
+            target_os = "macos",
+            windows
+        ))]
         impl crate::dist::pkg::ToolchainPackager for PanicToolchainPackager {
             fn write_pkg(self: Box<Self>, _f: ::std::fs::File) -> crate::errors::Result<()> {
                 panic!("should not have called packager")
             }
         }
 
+        struct BytesToolchainPackager(&'static [u8]);
+        #[cfg(any(
+            all(target_os = "linux", target_arch = "x86_64"),
+            target_os = "macos",
+            windows
+        ))]
+        impl crate::dist::pkg::ToolchainPackager for BytesToolchainPackager {
+            fn write_pkg(self: Box<Self>, mut f: ::std::fs::File) -> crate::errors::Result<()> {
+                Ok(f.write_all(self.0)?)
+            }
+        }
+
         #[test]
         fn test_client_toolchains_custom() {
             let td = tempfile::Builder::new()
@@ -314,6 +385,7 @@ mod client {
                     compiler_executable: "/my/compiler".into(),
                     archive: ct1.clone(),
                     archive_compiler_executable: "/my/compiler/in_archive".into(),
+                    archive_signature: None,
                 }],
             )
             .unwrap();
@@ -346,18 +418,21 @@ mod client {
                         compiler_executable: "/my/compiler".into(),
                         archive: ct1.clone(),
                         archive_compiler_executable: "/my/compiler/in_archive".into(),
+                        archive_signature: None,
                     },
                     // Uses the same archive, but a maps a different external compiler to a different achive compiler
                     config::DistToolchainConfig::PathOverride {
                         compiler_executable: "/my/compiler2".into(),
                         archive: ct1.clone(),
                         archive_compiler_executable: "/my/compiler2/in_archive".into(),
+                        archive_signature: None,
                     },
                     // Uses the same archive, but a maps a different external compiler to the same achive compiler as the first
                     config::DistToolchainConfig::PathOverride {
                         compiler_executable: "/my/compiler3".into(),
                         archive: ct1.clone(),
                         archive_compiler_executable: "/my/compiler/in_archive".into(),
+                        archive_signature: None,
                     },
                 ],
             )
@@ -432,6 +507,7 @@ mod client {
                         compiler_executable: "/my/compiler".into(),
                         archive: ct1,
                         archive_compiler_executable: "/my/compiler".into(),
+                        archive_signature: None,
                     },
                     config::DistToolchainConfig::NoDist {
                         compiler_executable: "/my/compiler".into(),
@@ -440,6 +516,38 @@ mod client {
             );
             assert!(client_toolchains.is_err())
         }
+
+        #[test]
+        fn test_client_toolchains_evict_and_clear() {
+            let td = tempfile::Builder::new()
+                .prefix("sccache")
+                .tempdir()
+                .unwrap();
+
+            let client_toolchains =
+                ClientToolchains::new(&td.path().join("cache"), 1024, &[]).unwrap();
+            let put = |weak_key, contents| {
+                client_toolchains
+                    .put_toolchain(
+                        "/my/compiler".as_ref(),
+                        weak_key,
+                        Box::new(BytesToolchainPackager(contents)),
+                    )
+                    .unwrap()
+                    .0
+            };
+            let tc1 = put("weak_key1", &[1; 600]);
+            let tc2 = put("weak_key2", &[2; 600]);
+            assert!(client_toolchains.get_toolchain(&tc1).unwrap().is_none());
+            // The first toolchain was evicted, so is packaged again
+            assert_eq!(put("weak_key1", &[1; 600]), tc1);
+            assert!(client_toolchains.get_toolchain(&tc1).unwrap().is_some());
+            assert!(client_toolchains.get_toolchain(&tc2).unwrap().is_none());
+
+            assert_eq!(client_toolchains.clear().unwrap(), 600);
+            assert!(client_toolchains.get_toolchain(&tc1).unwrap().is_none());
+            assert_eq!(put("weak_key1", &[1; 600]), tc1);
+        }
     }
 }
 
@@ -491,6 +599,14 @@ impl TcCache {
         self.len() == 0
     }
 
+    pub fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.inner.capacity()
+    }
+
     pub fn remove(&mut self, tc: &Toolchain) -> LruResult<()> {
         self.inner.remove(make_lru_key_path(&tc.archive_id))
     }
@@ -505,7 +621,7 @@ impl TcCache {
 }
 
 #[cfg(feature = "dist-client")]
-fn path_key(path: &Path) -> Result<String> {
+pub(crate) fn path_key(path: &Path) -> Result<String> {
     file_key(fs::File::open(path)?)
 }
 
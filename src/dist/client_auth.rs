@@ -1,21 +1,17 @@
-use futures::future;
-use futures::prelude::*;
-use futures::sync::oneshot;
+use futures::channel::oneshot;
 use http::StatusCode;
-use hyper::body::Payload;
 use hyper::server::conn::AddrIncoming;
-use hyper::service::Service;
+use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response, Server};
 use hyperx::header::{ContentLength, ContentType};
 use serde::Serialize;
 use std::collections::HashMap;
+use std::convert::Infallible;
 use std::error::Error as StdError;
 use std::io;
-use std::marker::PhantomData;
 use std::net::{TcpStream, ToSocketAddrs};
 use std::sync::mpsc;
 use std::time::Duration;
-use tokio_compat::runtime::current_thread::Runtime;
 use url::Url;
 use uuid::Uuid;
 
@@ -29,41 +25,25 @@ pub const VALID_PORTS: &[u16] = &[12731, 32492, 56909];
 const MIN_TOKEN_VALIDITY: Duration = Duration::from_secs(2 * 24 * 60 * 60);
 const MIN_TOKEN_VALIDITY_WARNING: &str = "two days";
 
-trait ServeFn:
-    Fn(Request<Body>) -> Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>
-    + Copy
-    + Send
-    + 'static
-{
-}
-impl<T> ServeFn for T where
-    T: Fn(Request<Body>) -> Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>
-        + Copy
-        + Send
-        + 'static
-{
-}
-
-fn serve_sfuture(serve: fn(Request<Body>) -> SFutureSend<Response<Body>>) -> impl ServeFn {
-    move |req: Request<Body>| {
-        let uri = req.uri().to_owned();
-        Box::new(serve(req).or_else(move |e| {
-            // `{:?}` prints the full cause chain and backtrace.
-            let body = format!("{:?}", e);
-            eprintln!(
-                "sccache: Error during a request to {} on the client auth web server\n{}",
-                uri, body
-            );
-            let len = body.len();
-            let mut builder = Response::builder();
-            builder.status(StatusCode::INTERNAL_SERVER_ERROR);
-            Ok(builder
-                .set_header(ContentType::text())
-                .set_header(ContentLength(len as u64))
-                .body(body.into())
-                .unwrap())
-        })) as Box<dyn Future<Item = _, Error = _> + Send>
-    }
+type ServeFn = fn(Request<Body>) -> Result<Response<Body>>;
+
+fn respond(serve: ServeFn, req: Request<Body>) -> Response<Body> {
+    let uri = req.uri().to_owned();
+    serve(req).unwrap_or_else(|e| {
+        // `{:?}` prints the full cause chain and backtrace.
+        let body = format!("{:?}", e);
+        eprintln!(
+            "sccache: Error during a request to {} on the client auth web server\n{}",
+            uri, body
+        );
+        let len = body.len();
+        Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .set_header(ContentType::text())
+            .set_header(ContentLength(len as u64))
+            .body(body.into())
+            .unwrap()
+    })
 }
 
 fn query_pairs(url: &str) -> Result<HashMap<String, String>> {
@@ -128,8 +108,7 @@ mod code_grant_pkce {
         html_response, json_response, query_pairs, MIN_TOKEN_VALIDITY, MIN_TOKEN_VALIDITY_WARNING,
         REDIRECT_WITH_AUTH_JSON,
     };
-    use futures::future;
-    use futures::sync::oneshot;
+    use futures::channel::oneshot;
     use hyper::{Body, Method, Request, Response, StatusCode};
     use rand::{rngs::OsRng, RngCore};
     use sha2::{Digest, Sha256};
@@ -241,19 +220,19 @@ mod code_grant_pkce {
     </html>
     "##;
 
-    pub fn serve(req: Request<Body>) -> SFutureSend<Response<Body>> {
+    pub fn serve(req: Request<Body>) -> Result<Response<Body>> {
         let mut state = STATE.lock().unwrap();
         let state = state.as_mut().unwrap();
         debug!("Handling {} {}", req.method(), req.uri());
         let response = match (req.method(), req.uri().path()) {
             (&Method::GET, "/") => html_response(REDIRECT_WITH_AUTH_JSON),
-            (&Method::GET, "/auth_detail.json") => ftry_send!(json_response(&state.auth_url)),
+            (&Method::GET, "/auth_detail.json") => json_response(&state.auth_url)?,
             (&Method::GET, "/redirect") => {
-                let query_pairs = ftry_send!(query_pairs(&req.uri().to_string()));
-                let (code, auth_state) = ftry_send!(handle_code_response(query_pairs)
-                    .context("Failed to handle response from redirect"));
+                let query_pairs = query_pairs(&req.uri().to_string())?;
+                let (code, auth_state) = handle_code_response(query_pairs)
+                    .context("Failed to handle response from redirect")?;
                 if auth_state != state.auth_state_value {
-                    return ftry_send!(Err(anyhow!("Mismatched auth states after redirect")));
+                    bail!("Mismatched auth states after redirect");
                 }
                 // Deliberately in reverse order for a 'happens-before' relationship
                 state.code_tx.send(code).unwrap();
@@ -269,7 +248,7 @@ mod code_grant_pkce {
             }
         };
 
-        Box::new(future::ok(response))
+        Ok(response)
     }
 
     pub fn code_to_token(
@@ -286,8 +265,8 @@ mod code_grant_pkce {
             grant_type: GRANT_TYPE_PARAM_VALUE,
             redirect_uri,
         };
-        let client = reqwest::Client::new();
-        let mut res = client.post(token_url).json(&token_request).send()?;
+        let client = reqwest::blocking::Client::new();
+        let res = client.post(token_url).json(&token_request).send()?;
         if !res.status().is_success() {
             bail!(
                 "Sending code to {} failed, HTTP error: {}",
@@ -319,8 +298,7 @@ mod implicit {
         html_response, json_response, query_pairs, MIN_TOKEN_VALIDITY, MIN_TOKEN_VALIDITY_WARNING,
         REDIRECT_WITH_AUTH_JSON,
     };
-    use futures::future;
-    use futures::sync::oneshot;
+    use futures::channel::oneshot;
     use hyper::{Body, Method, Request, Response, StatusCode};
     use std::collections::HashMap;
     use std::sync::mpsc;
@@ -420,21 +398,20 @@ mod implicit {
     </html>
     "##;
 
-    pub fn serve(req: Request<Body>) -> SFutureSend<Response<Body>> {
+    pub fn serve(req: Request<Body>) -> Result<Response<Body>> {
         let mut state = STATE.lock().unwrap();
         let state = state.as_mut().unwrap();
         debug!("Handling {} {}", req.method(), req.uri());
         let response = match (req.method(), req.uri().path()) {
             (&Method::GET, "/") => html_response(REDIRECT_WITH_AUTH_JSON),
-            (&Method::GET, "/auth_detail.json") => ftry_send!(json_response(&state.auth_url)),
+            (&Method::GET, "/auth_detail.json") => json_response(&state.auth_url)?,
             (&Method::GET, "/redirect") => html_response(SAVE_AUTH_AFTER_REDIRECT),
             (&Method::POST, "/save_auth") => {
-                let query_pairs = ftry_send!(query_pairs(&req.uri().to_string()));
-                let (token, expires_at, auth_state) = ftry_send!(
-                    handle_response(query_pairs).context("Failed to save auth after redirect")
-                );
+                let query_pairs = query_pairs(&req.uri().to_string())?;
+                let (token, expires_at, auth_state) =
+                    handle_response(query_pairs).context("Failed to save auth after redirect")?;
                 if auth_state != state.auth_state_value {
-                    return ftry_send!(Err(anyhow!("Mismatched auth states after redirect")));
+                    bail!("Mismatched auth states after redirect");
                 }
                 if expires_at - Instant::now() < MIN_TOKEN_VALIDITY {
                     warn!(
@@ -449,7 +426,7 @@ mod implicit {
                 // Deliberately in reverse order for a 'happens-before' relationship
                 state.token_tx.send(token).unwrap();
                 state.shutdown_tx.take().unwrap().send(()).unwrap();
-                ftry_send!(json_response(&""))
+                json_response(&"")?
             }
             _ => {
                 warn!("Route not found");
@@ -460,58 +437,91 @@ mod implicit {
             }
         };
 
-        Box::new(future::ok(response))
+        Ok(response)
     }
 }
 
-fn service_fn<F, R, S>(f: F) -> ServiceFn<F, R>
-where
-    F: Fn(Request<R>) -> S,
-    S: IntoFuture,
-{
-    ServiceFn {
-        f,
-        _req: PhantomData,
-    }
-}
+mod device_code {
+    use crate::dist::oauth2::{self, TokenResponse};
+    use std::thread;
+    use std::time::Duration;
 
-struct ServiceFn<F, R> {
-    f: F,
-    _req: PhantomData<fn(R)>,
-}
+    use crate::errors::*;
 
-impl<F, ReqBody, Ret, ResBody> Service for ServiceFn<F, ReqBody>
-where
-    F: Fn(Request<ReqBody>) -> Ret,
-    ReqBody: Payload,
-    Ret: IntoFuture<Item = Response<ResBody>>,
-    Ret::Error: Into<Box<dyn StdError + Send + Sync>>,
-    ResBody: Payload,
-{
-    type ReqBody = ReqBody;
-    type ResBody = ResBody;
-    type Error = Ret::Error;
-    type Future = Ret::Future;
-
-    fn call(&mut self, req: Request<Self::ReqBody>) -> Self::Future {
-        (self.f)(req).into_future()
+    // Device authorization request - https://tools.ietf.org/html/rfc8628#section-3.1
+    const CLIENT_ID_PARAM: &str = "client_id";
+    const SCOPE_PARAM: &str = "scope";
+    const AUDIENCE_PARAM: &str = "audience";
+    // Device authorization response - https://tools.ietf.org/html/rfc8628#section-3.2
+    #[derive(Deserialize)]
+    pub struct DeviceAuthResponse {
+        pub device_code: String,
+        pub user_code: String,
+        pub verification_uri: String,
+        pub verification_uri_complete: Option<String>,
+        pub interval: Option<u64>,
+    }
+    const DEFAULT_INTERVAL: Duration = Duration::from_secs(5);
+
+    // Token request - https://tools.ietf.org/html/rfc8628#section-3.4
+    const DEVICE_CODE_PARAM: &str = "device_code";
+    const GRANT_TYPE_PARAM: &str = "grant_type";
+    const GRANT_TYPE_PARAM_VALUE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+    // Errors while waiting for the user - https://tools.ietf.org/html/rfc8628#section-3.5
+    const AUTHORIZATION_PENDING_ERROR: &str = "authorization_pending";
+    const SLOW_DOWN_ERROR: &str = "slow_down";
+    const SLOW_DOWN_INTERVAL: Duration = Duration::from_secs(5);
+
+    pub fn request_device_code(
+        client: &reqwest::blocking::Client,
+        client_id: &str,
+        device_auth_url: &str,
+        scope: Option<&str>,
+        audience: Option<&str>,
+    ) -> Result<DeviceAuthResponse> {
+        let mut params = vec![(CLIENT_ID_PARAM, client_id)];
+        params.extend(scope.map(|scope| (SCOPE_PARAM, scope)));
+        params.extend(audience.map(|audience| (AUDIENCE_PARAM, audience)));
+        let res = client.post(device_auth_url).form(&params).send()?;
+        if !res.status().is_success() {
+            bail!(
+                "Requesting a device code from {} failed, HTTP error: {}",
+                device_auth_url,
+                res.status()
+            )
+        }
+        res.json()
+            .context("Failed to parse device authorization response as JSON")
     }
-}
-
-impl<F, R> IntoFuture for ServiceFn<F, R> {
-    type Future = future::FutureResult<Self::Item, Self::Error>;
-    type Item = Self;
-    type Error = hyper::Error;
 
-    fn into_future(self) -> Self::Future {
-        future::ok(self)
+    pub fn poll_for_token(
+        client: &reqwest::blocking::Client,
+        client_id: &str,
+        token_url: &str,
+        device_auth: &DeviceAuthResponse,
+    ) -> Result<TokenResponse> {
+        let mut interval = device_auth
+            .interval
+            .map_or(DEFAULT_INTERVAL, Duration::from_secs);
+        let params = [
+            (GRANT_TYPE_PARAM, GRANT_TYPE_PARAM_VALUE),
+            (DEVICE_CODE_PARAM, &device_auth.device_code),
+            (CLIENT_ID_PARAM, client_id),
+        ];
+        loop {
+            thread::sleep(interval);
+            match oauth2::request_token(client, token_url, &params)? {
+                Ok(token) => return Ok(token),
+                Err(e) if e.error == AUTHORIZATION_PENDING_ERROR => (),
+                Err(e) if e.error == SLOW_DOWN_ERROR => interval += SLOW_DOWN_INTERVAL,
+                Err(e) => return Err(e.into_error(token_url)),
+            }
+        }
     }
 }
 
-fn try_serve<T>(serve: T) -> Result<Server<AddrIncoming, impl Fn() -> ServiceFn<T, Body>>>
-where
-    T: ServeFn,
-{
+// Needs to be called in the context of a runtime
+fn try_bind() -> Result<AddrIncoming> {
     // Try all the valid ports
     for &port in VALID_PORTS {
         let mut addrs = ("localhost", port)
@@ -533,9 +543,8 @@ where
             }
         }
 
-        let new_service = move || service_fn(serve);
-        match Server::try_bind(&addr) {
-            Ok(s) => return Ok(s.serve(new_service)),
+        match AddrIncoming::bind(&addr) {
+            Ok(incoming) => return Ok(incoming),
             Err(ref err)
                 if err
                     .source()
@@ -551,14 +560,44 @@ where
     bail!("Could not bind to any valid port: ({:?})", VALID_PORTS)
 }
 
+// Serve requests with `serve` until told to shut down
+async fn serve_until(
+    incoming: AddrIncoming,
+    serve: ServeFn,
+    shutdown_rx: oneshot::Receiver<()>,
+) -> Result<()> {
+    let make_service = make_service_fn(move |_| async move {
+        Ok::<_, Infallible>(service_fn(move |req| async move {
+            Ok::<_, Infallible>(respond(serve, req))
+        }))
+    });
+    let shutdown_signal = async {
+        if let Err(e) = shutdown_rx.await {
+            warn!(
+                "Something went wrong while waiting for auth server shutdown: {}",
+                e
+            )
+        }
+    };
+    Server::builder(incoming)
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown_signal)
+        .await?;
+    Ok(())
+}
+
 // https://auth0.com/docs/api-auth/tutorials/authorization-code-grant-pkce
 pub fn get_token_oauth2_code_grant_pkce(
     client_id: &str,
     mut auth_url: Url,
     token_url: &str,
 ) -> Result<String> {
-    let server = try_serve(serve_sfuture(code_grant_pkce::serve))?;
-    let port = server.local_addr().port();
+    let runtime = crate::util::runtime()?;
+    let incoming = {
+        let _guard = runtime.enter();
+        try_bind()?
+    };
+    let port = incoming.local_addr().port();
 
     let redirect_uri = format!("http://localhost:{}/redirect", port);
     let auth_state_value = Uuid::new_v4().to_simple_ref().to_string();
@@ -585,15 +624,8 @@ pub fn get_token_oauth2_code_grant_pkce(
         shutdown_tx: Some(shutdown_tx),
     };
     *code_grant_pkce::STATE.lock().unwrap() = Some(state);
-    let shutdown_signal = shutdown_rx.map_err(|e| {
-        warn!(
-            "Something went wrong while waiting for auth server shutdown: {}",
-            e
-        )
-    });
 
-    let mut runtime = Runtime::new()?;
-    runtime.block_on(server.with_graceful_shutdown(shutdown_signal))?;
+    runtime.block_on(serve_until(incoming, code_grant_pkce::serve, shutdown_rx))?;
 
     info!("Server finished, using code to request token");
     let code = code_rx
@@ -605,8 +637,12 @@ pub fn get_token_oauth2_code_grant_pkce(
 
 // https://auth0.com/docs/api-auth/tutorials/implicit-grant
 pub fn get_token_oauth2_implicit(client_id: &str, mut auth_url: Url) -> Result<String> {
-    let server = try_serve(serve_sfuture(implicit::serve))?;
-    let port = server.local_addr().port();
+    let runtime = crate::util::runtime()?;
+    let incoming = {
+        let _guard = runtime.enter();
+        try_bind()?
+    };
+    let port = incoming.local_addr().port();
 
     let redirect_uri = format!("http://localhost:{}/redirect", port);
     let auth_state_value = Uuid::new_v4().to_simple_ref().to_string();
@@ -626,18 +662,37 @@ pub fn get_token_oauth2_implicit(client_id: &str, mut auth_url: Url) -> Result<S
         shutdown_tx: Some(shutdown_tx),
     };
     *implicit::STATE.lock().unwrap() = Some(state);
-    let shutdown_signal = shutdown_rx.map_err(|e| {
-        warn!(
-            "Something went wrong while waiting for auth server shutdown: {}",
-            e
-        )
-    });
 
-    let mut runtime = Runtime::new()?;
-    runtime.block_on(server.with_graceful_shutdown(shutdown_signal))?;
+    runtime.block_on(serve_until(incoming, implicit::serve, shutdown_rx))?;
 
     info!("Server finished, returning token");
     Ok(token_rx
         .try_recv()
         .expect("Hyper shutdown but token not available - internal error"))
 }
+
+// https://tools.ietf.org/html/rfc8628
+// Returns the access token, and a refresh token if the provider issued one
+pub fn get_token_oauth2_device_code(
+    client_id: &str,
+    device_auth_url: &str,
+    token_url: &str,
+    scope: Option<&str>,
+    audience: Option<&str>,
+) -> Result<(String, Option<String>)> {
+    let client = reqwest::blocking::Client::new();
+    let device_auth =
+        device_code::request_device_code(&client, client_id, device_auth_url, scope, audience)?;
+    match device_auth.verification_uri_complete {
+        Some(ref uri) => println!("sccache: Please visit {} in your browser", uri),
+        None => println!(
+            "sccache: Please visit {} in your browser and enter the code {}",
+            device_auth.verification_uri, device_auth.user_code
+        ),
+    }
+
+    let token = device_code::poll_for_token(&client, client_id, token_url, &device_auth)
+        .context("Failed to get a token with the device code")?;
+    info!("Device authorization complete");
+    Ok((token.access_token, token.refresh_token))
+}
@@ -17,12 +17,11 @@ pub use self::client::Client;
 pub use self::server::Server;
 #[cfg(feature = "dist-server")]
 pub use self::server::{
-    ClientAuthCheck, ClientVisibleMsg, Scheduler, ServerAuthCheck, HEARTBEAT_TIMEOUT,
+    drain_server, ClientAuthCheck, ClientVisibleMsg, Scheduler, SchedulerAuth, ServerAuthCheck,
+    HEARTBEAT_TIMEOUT,
 };
 
 mod common {
-    #[cfg(feature = "dist-client")]
-    use futures::{Future, Stream};
     use hyperx::header;
     #[cfg(feature = "dist-server")]
     use std::collections::HashMap;
@@ -37,9 +36,8 @@ mod common {
     pub trait ReqwestRequestBuilderExt: Sized {
         fn bincode<T: serde::Serialize + ?Sized>(self, bincode: &T) -> Result<Self>;
         fn bytes(self, bytes: Vec<u8>) -> Self;
-        fn bearer_auth(self, token: String) -> Self;
     }
-    impl ReqwestRequestBuilderExt for reqwest::RequestBuilder {
+    impl ReqwestRequestBuilderExt for reqwest::blocking::RequestBuilder {
         fn bincode<T: serde::Serialize + ?Sized>(self, bincode: &T) -> Result<Self> {
             let bytes =
                 bincode::serialize(bincode).context("Failed to serialize body to bincode")?;
@@ -50,11 +48,8 @@ mod common {
                 .set_header(header::ContentLength(bytes.len() as u64))
                 .body(bytes)
         }
-        fn bearer_auth(self, token: String) -> Self {
-            self.set_header(header::Authorization(header::Bearer { token }))
-        }
     }
-    impl ReqwestRequestBuilderExt for reqwest::r#async::RequestBuilder {
+    impl ReqwestRequestBuilderExt for reqwest::RequestBuilder {
         fn bincode<T: serde::Serialize + ?Sized>(self, bincode: &T) -> Result<Self> {
             let bytes =
                 bincode::serialize(bincode).context("Failed to serialize body to bincode")?;
@@ -65,13 +60,10 @@ mod common {
                 .set_header(header::ContentLength(bytes.len() as u64))
                 .body(bytes)
         }
-        fn bearer_auth(self, token: String) -> Self {
-            self.set_header(header::Authorization(header::Bearer { token }))
-        }
     }
 
     pub fn bincode_req<T: serde::de::DeserializeOwned + 'static>(
-        req: reqwest::RequestBuilder,
+        req: reqwest::blocking::RequestBuilder,
     ) -> Result<T> {
         // Work around tiny_http issue #151 by disabling HTTP pipeline with
         // `Connection: close`.
@@ -92,41 +84,27 @@ mod common {
         }
     }
     #[cfg(feature = "dist-client")]
-    pub fn bincode_req_fut<T: serde::de::DeserializeOwned + 'static>(
-        req: reqwest::r#async::RequestBuilder,
-    ) -> SFuture<T> {
-        Box::new(
-            // Work around tiny_http issue #151 by disabling HTTP pipeline with
-            // `Connection: close`.
-            req.set_header(header::Connection::close())
-                .send()
-                .map_err(Into::into)
-                .and_then(|res| {
-                    let status = res.status();
-                    res.into_body()
-                        .concat2()
-                        .map(move |b| (status, b))
-                        .map_err(Into::into)
-                })
-                .and_then(|(status, body)| {
-                    if !status.is_success() {
-                        let errmsg = format!(
-                            "Error {}: {}",
-                            status.as_u16(),
-                            String::from_utf8_lossy(&body)
-                        );
-                        if status.is_client_error() {
-                            return f_err(HttpClientError(errmsg));
-                        } else {
-                            return f_err(anyhow!(errmsg));
-                        }
-                    }
-                    match bincode::deserialize(&body) {
-                        Ok(r) => f_ok(r),
-                        Err(e) => f_err(e),
-                    }
-                }),
-        )
+    pub async fn bincode_req_fut<T: serde::de::DeserializeOwned + 'static>(
+        req: reqwest::RequestBuilder,
+    ) -> Result<T> {
+        // Work around tiny_http issue #151 by disabling HTTP pipeline with
+        // `Connection: close`.
+        let res = req.set_header(header::Connection::close()).send().await?;
+        let status = res.status();
+        let body = res.bytes().await?;
+        if !status.is_success() {
+            let errmsg = format!(
+                "Error {}: {}",
+                status.as_u16(),
+                String::from_utf8_lossy(&body)
+            );
+            if status.is_client_error() {
+                bail!(HttpClientError(errmsg));
+            } else {
+                bail!(errmsg);
+            }
+        }
+        Ok(bincode::deserialize(&body)?)
     }
 
     #[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
@@ -135,6 +113,19 @@ mod common {
         pub job_id: dist::JobId,
     }
 
+    /// The `Content-Encoding` of run job requests with zstd compressed
+    /// inputs, and the `Accept-Encoding` of clients that can take zstd
+    /// compressed outputs. Other requests have zlib compressed inputs and
+    /// get zlib compressed outputs.
+    pub const ZSTD_ENCODING: &str = "zstd";
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct AllocJobHttpRequest {
+        pub toolchain: dist::Toolchain,
+        pub priority: u32,
+    }
+
     #[derive(Clone, Debug, Serialize, Deserialize)]
     #[serde(deny_unknown_fields)]
     pub enum AllocJobHttpResponse {
@@ -209,6 +200,16 @@ mod common {
     }
     #[derive(Clone, Debug, Serialize, Deserialize)]
     #[serde(deny_unknown_fields)]
+    pub struct DeregisterServerHttpRequest {
+        pub server_nonce: dist::ServerNonce,
+    }
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct DrainServerHttpRequest {
+        pub drain: bool,
+    }
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
     pub struct RunJobHttpRequest {
         pub command: dist::CompileCommand,
         pub outputs: Vec<String>,
@@ -239,6 +240,16 @@ pub mod urls {
             .join("/api/v1/scheduler/heartbeat_server")
             .expect("failed to create heartbeat url")
     }
+    pub fn scheduler_deregister_server(scheduler_url: &reqwest::Url) -> reqwest::Url {
+        scheduler_url
+            .join("/api/v1/scheduler/deregister_server")
+            .expect("failed to create deregister url")
+    }
+    pub fn scheduler_drain_server(scheduler_url: &reqwest::Url) -> reqwest::Url {
+        scheduler_url
+            .join("/api/v1/scheduler/drain_server")
+            .expect("failed to create drain url")
+    }
     pub fn scheduler_job_state(scheduler_url: &reqwest::Url, job_id: JobId) -> reqwest::Url {
         scheduler_url
             .join(&format!("/api/v1/scheduler/job_state/{}", job_id))
@@ -249,6 +260,11 @@ pub mod urls {
             .join("/api/v1/scheduler/status")
             .expect("failed to create alloc job url")
     }
+    pub fn scheduler_details(scheduler_url: &reqwest::Url) -> reqwest::Url {
+        scheduler_url
+            .join("/api/v1/scheduler/details")
+            .expect("failed to create scheduler details url")
+    }
 
     pub fn server_assign_job(server_id: ServerId, job_id: JobId) -> reqwest::Url {
         let url = format!(
@@ -286,28 +302,46 @@ mod server {
     use std::collections::HashMap;
     use std::io::Read;
     use std::net::SocketAddr;
+    use std::process;
     use std::result::Result as StdResult;
     use std::sync::atomic;
-    use std::sync::Mutex;
+    use std::sync::{Arc, Mutex};
     use std::thread;
-    use std::time::Duration;
+    use std::time::{Duration, Instant};
     use void::Void;
 
     use super::common::{
-        bincode_req, AllocJobHttpResponse, HeartbeatServerHttpRequest, JobJwt,
-        ReqwestRequestBuilderExt, RunJobHttpRequest, ServerCertificateHttpResponse,
+        bincode_req, AllocJobHttpRequest, AllocJobHttpResponse, DeregisterServerHttpRequest,
+        DrainServerHttpRequest, HeartbeatServerHttpRequest, JobJwt, ReqwestRequestBuilderExt,
+        RunJobHttpRequest, ServerCertificateHttpResponse, ZSTD_ENCODING,
     };
     use super::urls;
+    use crate::dist::signing;
+    use crate::dist::tls::{self, TlsCerts, TlsFrontend};
     use crate::dist::{
-        self, AllocJobResult, AssignJobResult, HeartbeatServerResult, InputsReader, JobAuthorizer,
-        JobId, JobState, RunJobResult, SchedulerStatusResult, ServerId, ServerNonce,
-        SubmitToolchainResult, Toolchain, ToolchainReader, UpdateJobStateResult,
+        self, AllocJobResult, AssignJobResult, DeregisterServerResult, DrainServerResult,
+        HeartbeatServerResult, InputsReader, JobAuthorizer, JobId, JobState, RunJobResult,
+        SchedulerAutoscalingResult, SchedulerDetailsResult, SchedulerStatusResult, ServerId,
+        ServerNonce, SubmitToolchainResult, Toolchain, ToolchainReader, UpdateJobStateResult,
     };
     use crate::errors::*;
 
     const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
     const HEARTBEAT_ERROR_INTERVAL: Duration = Duration::from_secs(10);
     pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(90);
+    const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+    // A page showing the scheduler details, for build farm operators
+    const DASHBOARD_HTML: &str = include_str!("dashboard.html");
+
+    fn accepts_zstd(request: &rouille::Request) -> bool {
+        request.header("Accept-Encoding").map_or(false, |header| {
+            header
+                .split(',')
+                .filter_map(|encoding| encoding.split(';').next())
+                .any(|encoding| encoding.trim() == ZSTD_ENCODING)
+        })
+    }
 
     fn create_https_cert_and_privkey(addr: SocketAddr) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>)> {
         let rsa_key = openssl::rsa::Rsa::<openssl::pkey::Private>::generate(2048)
@@ -402,6 +436,9 @@ mod server {
         fn check(&self, token: &str) -> StdResult<(), ClientVisibleMsg>;
     }
     pub type ServerAuthCheck = Box<dyn Fn(&str) -> Option<ServerId> + Send + Sync>;
+    /// Produces the token a server authenticates to the scheduler with, which
+    /// may need renewing.
+    pub type SchedulerAuth = Box<dyn Fn() -> Result<String> + Send + Sync>;
 
     const JWT_KEY_LENGTH: usize = 256 / 8;
     lazy_static! {
@@ -666,6 +703,8 @@ mod server {
         check_client_auth: Box<dyn ClientAuthCheck>,
         // Do we believe the server is who they appear to be?
         check_server_auth: ServerAuthCheck,
+        // Certificates for mutual TLS with clients and servers
+        tls: Option<TlsCerts>,
     }
 
     impl<S: dist::SchedulerIncoming + 'static> Scheduler<S> {
@@ -674,12 +713,14 @@ mod server {
             handler: S,
             check_client_auth: Box<dyn ClientAuthCheck>,
             check_server_auth: ServerAuthCheck,
+            tls: Option<TlsCerts>,
         ) -> Self {
             Self {
                 public_addr,
                 handler,
                 check_client_auth,
                 check_server_auth,
+                tls,
             }
         }
 
@@ -689,33 +730,59 @@ mod server {
                 handler,
                 check_client_auth,
                 check_server_auth,
+                tls,
             } = self;
             let requester = SchedulerRequester {
-                client: Mutex::new(reqwest::Client::new()),
+                client: Mutex::new(
+                    tls::client_builder(tls.as_ref())?
+                        .build()
+                        .context("failed to create a HTTP client")?,
+                ),
             };
+            // Without TLS, requests come straight from the peer or through a
+            // reverse proxy that says who they came from
+            let frontend = match tls {
+                Some(ref tls) => Some(TlsFrontend::bind(public_addr, tls.clone())?),
+                None => None,
+            };
+            let peer_addrs = frontend
+                .as_ref()
+                .map_or_else(Default::default, TlsFrontend::peer_addrs);
+            let behind_frontend = frontend.is_some();
+            let tls = Mutex::new(tls);
+
+            // The IP address a request came from
+            macro_rules! origin_ip_or_err {
+                ($request:ident) => {{
+                    let remote_addr = peer_addrs.peer_addr(&$request.remote_addr());
+                    if behind_frontend {
+                        remote_addr.ip()
+                    } else if let Some(header_val) = $request.header("X-Real-IP") {
+                        trace!("X-Real-IP: {:?}", header_val);
+                        match header_val.parse() {
+                            Ok(ip) => ip,
+                            Err(err) => {
+                                warn!(
+                                    "X-Real-IP value {:?} could not be parsed: {:?}",
+                                    header_val, err
+                                );
+                                return rouille::Response::empty_400();
+                            }
+                        }
+                    } else {
+                        remote_addr.ip()
+                    }
+                }};
+            }
 
             macro_rules! check_server_auth_or_err {
                 ($request:ident) => {{
                     match bearer_http_auth($request).and_then(&*check_server_auth) {
                         Some(server_id) => {
-                            let origin_ip = if let Some(header_val) = $request.header("X-Real-IP") {
-                                trace!("X-Real-IP: {:?}", header_val);
-                                match header_val.parse() {
-                                    Ok(ip) => ip,
-                                    Err(err) => {
-                                        warn!(
-                                            "X-Real-IP value {:?} could not be parsed: {:?}",
-                                            header_val, err
-                                        );
-                                        return rouille::Response::empty_400();
-                                    }
-                                }
-                            } else {
-                                $request.remote_addr().ip()
-                            };
+                            let origin_ip = origin_ip_or_err!($request);
                             if server_id.addr().ip() != origin_ip {
                                 trace!("server ip: {:?}", server_id.addr().ip());
-                                trace!("request ip: {:?}", $request.remote_addr().ip());
+                                trace!("request ip: {:?}", origin_ip);
                                 return make_401("invalid_bearer_token_mismatched_address");
                             } else {
                                 server_id
@@ -727,14 +794,20 @@ mod server {
             }
 
             fn maybe_update_certs(
-                client: &mut reqwest::Client,
+                client: &mut reqwest::blocking::Client,
                 certs: &mut HashMap<ServerId, (Vec<u8>, Vec<u8>)>,
+                tls: &mut Option<TlsCerts>,
                 server_id: ServerId,
                 cert_digest: Vec<u8>,
                 cert_pem: Vec<u8>,
             ) -> Result<()> {
+                // Pick up rotated certificates for talking to servers
+                let new_tls = match tls {
+                    Some(tls) if tls.changed() => Some(tls.reload()?),
+                    _ => None,
+                };
                 if let Some((saved_cert_digest, _)) = certs.get(&server_id) {
-                    if saved_cert_digest == &cert_digest {
+                    if saved_cert_digest == &cert_digest && new_tls.is_none() {
                         return Ok(());
                     }
                 }
@@ -742,7 +815,8 @@ mod server {
                     "Adding new certificate for {} to scheduler",
                     server_id.addr()
                 );
-                let mut client_builder = reqwest::ClientBuilder::new();
+                let mut client_builder =
+                    tls::client_builder(new_tls.as_ref().or_else(|| tls.as_ref()))?;
                 // Add all the certificates we know about
                 client_builder = client_builder.add_root_certificate(
                     reqwest::Certificate::from_pem(&cert_pem)
@@ -760,6 +834,9 @@ mod server {
                 // Use the updated certificates
                 *client = new_client;
                 certs.insert(server_id, (cert_digest, cert_pem));
+                if new_tls.is_some() {
+                    *tls = new_tls;
+                }
                 Ok(())
             }
 
@@ -769,7 +846,13 @@ mod server {
             let server_certificates: Mutex<HashMap<ServerId, (Vec<u8>, Vec<u8>)>> =
                 Default::default();
 
-            let server = rouille::Server::new(public_addr, move |request| {
+            // With TLS, the frontend forwards to the HTTP server on localhost
+            let http_addr = if behind_frontend {
+                SocketAddr::from(([127, 0, 0, 1], 0))
+            } else {
+                public_addr
+            };
+            let server = rouille::Server::new(http_addr, move |request| {
                 let req_id = request_count.fetch_add(1, atomic::Ordering::SeqCst);
                 trace!("Req {} ({}): {:?}", req_id, request.remote_addr(), request);
                 let response = (|| router!(request,
@@ -785,10 +868,11 @@ mod server {
                                 return make_401_with_body("bearer_auth_failed", client_msg)
                             },
                         }
-                        let toolchain = try_or_400_log!(req_id, bincode_input(request));
-                        trace!("Req {}: alloc_job: {:?}", req_id, toolchain);
+                        let client = origin_ip_or_err!(request);
+                        let AllocJobHttpRequest { toolchain, priority } = try_or_400_log!(req_id, bincode_input(request));
+                        trace!("Req {}: alloc_job: {:?} at priority {} for {}", req_id, toolchain, priority, client);
 
-                        let alloc_job_res: AllocJobResult = try_or_500_log!(req_id, handler.handle_alloc_job(&requester, toolchain));
+                        let alloc_job_res: AllocJobResult = try_or_500_log!(req_id, handler.handle_alloc_job(&requester, client, priority, toolchain));
                         let certs = server_certificates.lock().unwrap();
                         let res = AllocJobHttpResponse::from_alloc_job_result(alloc_job_res, &certs);
                         prepare_response(&request, &res)
@@ -812,6 +896,7 @@ mod server {
                         try_or_500_log!(req_id, maybe_update_certs(
                             &mut requester.client.lock().unwrap(),
                             &mut server_certificates.lock().unwrap(),
+                            &mut tls.lock().unwrap(),
                             server_id, cert_digest, cert_pem
                         ));
                         let job_authorizer = JWTJobAuthorizer::new(jwt_key);
@@ -822,6 +907,22 @@ mod server {
                         ));
                         prepare_response(&request, &res)
                     },
+                    (POST) (/api/v1/scheduler/deregister_server) => {
+                        let server_id = check_server_auth_or_err!(request);
+                        let DeregisterServerHttpRequest { server_nonce } = try_or_400_log!(req_id, bincode_input(request));
+                        trace!("Req {}: deregister_server({:?})", req_id, server_id);
+
+                        let res: DeregisterServerResult = try_or_500_log!(req_id, handler.handle_deregister_server(server_id, server_nonce));
+                        prepare_response(&request, &res)
+                    },
+                    (POST) (/api/v1/scheduler/drain_server) => {
+                        let server_id = check_server_auth_or_err!(request);
+                        let DrainServerHttpRequest { drain } = try_or_400_log!(req_id, bincode_input(request));
+                        trace!("Req {}: drain_server({:?}, {})", req_id, server_id, drain);
+
+                        let res: DrainServerResult = try_or_500_log!(req_id, handler.handle_drain_server(server_id, drain));
+                        prepare_response(&request, &res)
+                    },
                     (POST) (/api/v1/scheduler/job_state/{job_id: JobId}) => {
                         let server_id = check_server_auth_or_err!(request);
                         let job_state = try_or_400_log!(req_id, bincode_input(request));
@@ -836,6 +937,21 @@ mod server {
                         let res: SchedulerStatusResult = try_or_500_log!(req_id, handler.handle_status());
                         prepare_response(&request, &res)
                     },
+                    (GET) (/api/v1/scheduler/details) => {
+                        let res: SchedulerDetailsResult = try_or_500_log!(req_id, handler.handle_details());
+                        rouille::Response::json(&res)
+                    },
+                    (GET) (/api/v1/scheduler/autoscaling) => {
+                        let res: SchedulerAutoscalingResult = try_or_500_log!(req_id, handler.handle_autoscaling());
+                        rouille::Response::json(&res)
+                    },
+                    (GET) (/metrics) => {
+                        let metrics = try_or_500_log!(req_id, handler.handle_metrics());
+                        rouille::Response::from_data(dist::metrics::CONTENT_TYPE, dist::metrics::scheduler(&metrics))
+                    },
+                    (GET) (/) => {
+                        rouille::Response::html(DASHBOARD_HTML)
+                    },
                     _ => {
                         warn!("Unknown request {:?}", request);
                         rouille::Response::empty_404()
@@ -844,6 +960,9 @@ mod server {
                 trace!("Res {}: {:?}", req_id, response);
                 response
             }).map_err(|e| anyhow!(format!("Failed to start http server for sccache scheduler: {}", e)))?;
+            if let Some(frontend) = frontend {
+                frontend.start(server.server_addr());
+            }
 
             // This limit is rouille's default for `start_server_with_pool`, which
             // we would use, except that interface doesn't permit any sort of
@@ -856,7 +975,7 @@ mod server {
     }
 
     struct SchedulerRequester {
-        client: Mutex<reqwest::Client>,
+        client: Mutex<reqwest::blocking::Client>,
     }
 
     impl dist::SchedulerOutgoing for SchedulerRequester {
@@ -876,149 +995,352 @@ mod server {
 
     pub struct Server<S> {
         public_addr: SocketAddr,
-        scheduler_url: reqwest::Url,
-        scheduler_auth: String,
+        // The active scheduler first, then any standbys
+        scheduler_urls: Vec<reqwest::Url>,
+        scheduler_auth: SchedulerAuth,
         // HTTPS pieces all the builders will use for connection encryption
         cert_digest: Vec<u8>,
         cert_pem: Vec<u8>,
         privkey_pem: Vec<u8>,
+        // Certificates for mutual TLS with clients and the scheduler, used in
+        // place of the generated certificate
+        tls: Option<TlsCerts>,
         // Key used to sign any requests relating to jobs
         jwt_key: Vec<u8>,
         // Randomly generated nonce to allow the scheduler to detect server restarts
         server_nonce: ServerNonce,
         handler: S,
+        // What to wait for before shutting down, and for how long to let
+        // jobs finish
+        shutdown: Option<(Box<dyn FnOnce() + Send>, Duration)>,
     }
 
     impl<S: dist::ServerIncoming + 'static> Server<S> {
         pub fn new(
             public_addr: SocketAddr,
-            scheduler_url: reqwest::Url,
-            scheduler_auth: String,
+            scheduler_urls: Vec<reqwest::Url>,
+            scheduler_auth: SchedulerAuth,
             handler: S,
+            tls: Option<TlsCerts>,
         ) -> Result<Self> {
-            let (cert_digest, cert_pem, privkey_pem) =
-                create_https_cert_and_privkey(public_addr)
-                    .context("failed to create HTTPS certificate for server")?;
+            let (cert_digest, cert_pem, privkey_pem) = match tls {
+                Some(ref tls) => {
+                    let (cert_digest, cert_pem) = tls.cert_digest_and_pem()?;
+                    (cert_digest, cert_pem, vec![])
+                }
+                None => create_https_cert_and_privkey(public_addr)
+                    .context("failed to create HTTPS certificate for server")?,
+            };
             let mut jwt_key = vec![0; JWT_KEY_LENGTH];
             OsRng.fill_bytes(&mut jwt_key);
             let server_nonce = ServerNonce::new();
 
             Ok(Self {
                 public_addr,
-                scheduler_url,
+                scheduler_urls,
                 scheduler_auth,
                 cert_digest,
                 cert_pem,
                 privkey_pem,
+                tls,
                 jwt_key,
                 server_nonce,
                 handler,
+                shutdown: None,
             })
         }
 
+        /// Once `signal` returns, stop sending heartbeats and tell the
+        /// schedulers that this server is going away, then exit when the
+        /// jobs it has are done, or after `timeout`.
+        pub fn shutdown_on<F: FnOnce() + Send + 'static>(
+            mut self,
+            signal: F,
+            timeout: Duration,
+        ) -> Self {
+            self.shutdown = Some((Box::new(signal), timeout));
+            self
+        }
+
         pub fn start(self) -> Result<Void> {
             let Self {
                 public_addr,
-                scheduler_url,
+                scheduler_urls,
                 scheduler_auth,
                 cert_digest,
                 cert_pem,
                 privkey_pem,
+                tls,
                 jwt_key,
                 server_nonce,
                 handler,
+                shutdown,
             } = self;
-            let heartbeat_req = HeartbeatServerHttpRequest {
+            let handler = Arc::new(handler);
+            let draining = Arc::new(atomic::AtomicBool::new(false));
+            let heartbeat_req = Arc::new(Mutex::new(HeartbeatServerHttpRequest {
                 num_cpus: num_cpus::get(),
                 jwt_key: jwt_key.clone(),
-                server_nonce,
+                server_nonce: server_nonce.clone(),
                 cert_digest,
                 cert_pem: cert_pem.clone(),
-            };
+            }));
             let job_authorizer = JWTJobAuthorizer::new(jwt_key);
-            let heartbeat_url = urls::scheduler_heartbeat_server(&scheduler_url);
+            let scheduler_auth: Arc<dyn Fn() -> Result<String> + Send + Sync> =
+                Arc::from(scheduler_auth);
+            let new_client = |tls: Option<&TlsCerts>| -> Result<reqwest::blocking::Client> {
+                tls::client_builder(tls)?
+                    .build()
+                    .context("failed to create a HTTP client")
+            };
+            let schedulers: Vec<_> = scheduler_urls
+                .into_iter()
+                .map(|url| (url, Arc::new(atomic::AtomicBool::new(true))))
+                .collect();
             let requester = ServerRequester {
-                client: reqwest::Client::new(),
-                scheduler_url,
+                client: Arc::new(Mutex::new(new_client(tls.as_ref())?)),
+                schedulers: schedulers.clone(),
                 scheduler_auth: scheduler_auth.clone(),
+                heartbeat_req: heartbeat_req.clone(),
+                draining: draining.clone(),
+            };
+            let frontend = match tls {
+                Some(ref tls) => Some(TlsFrontend::bind(public_addr, tls.clone())?),
+                None => None,
             };
 
-            // TODO: detect if this panics
-            thread::spawn(move || {
-                let client = reqwest::Client::new();
-                loop {
-                    trace!("Performing heartbeat");
-                    match bincode_req(
-                        client
-                            .post(heartbeat_url.clone())
-                            .bearer_auth(scheduler_auth.clone())
-                            .bincode(&heartbeat_req)
-                            .expect("failed to serialize heartbeat"),
-                    ) {
-                        Ok(HeartbeatServerResult { is_new }) => {
-                            trace!("Heartbeat success is_new={}", is_new);
-                            // TODO: if is_new, terminate all running jobs
-                            thread::sleep(HEARTBEAT_INTERVAL)
+            // Standby schedulers are sent heartbeats too, so that they know
+            // about this server when they take over
+            for (scheduler_url, reachable) in schedulers {
+                let heartbeat_url = urls::scheduler_heartbeat_server(&scheduler_url);
+                let heartbeat_req = heartbeat_req.clone();
+                let mut tls = tls.clone();
+                let scheduler_auth = scheduler_auth.clone();
+                // TODO: detect if this panics
+                let requester_client = requester.client.clone();
+                let draining = draining.clone();
+                thread::spawn(move || {
+                    let mut client =
+                        new_client(tls.as_ref()).expect("failed to create a HTTP client");
+                    let mut registered = false;
+                    loop {
+                        // The scheduler must not see this server as new
+                        if draining.load(atomic::Ordering::SeqCst) {
+                            trace!("Stopping heartbeats to {}", scheduler_url);
+                            return;
                         }
-                        Err(e) => {
-                            error!("Failed to send heartbeat to server: {}", e);
-                            thread::sleep(HEARTBEAT_ERROR_INTERVAL)
+                        // Pick up rotated certificates, and tell the scheduler
+                        // about the new one so that clients can verify it
+                        if let Some(new_tls) = tls.as_ref().filter(|tls| tls.changed()) {
+                            match new_tls.reload().and_then(|new_tls| {
+                                let (cert_digest, cert_pem) = new_tls.cert_digest_and_pem()?;
+                                Ok((new_client(Some(&new_tls))?, new_tls, cert_digest, cert_pem))
+                            }) {
+                                Ok((new_client, new_tls, cert_digest, cert_pem)) => {
+                                    *requester_client.lock().unwrap() = new_client.clone();
+                                    client = new_client;
+                                    tls = Some(new_tls);
+                                    let mut heartbeat_req = heartbeat_req.lock().unwrap();
+                                    heartbeat_req.cert_digest = cert_digest;
+                                    heartbeat_req.cert_pem = cert_pem;
+                                }
+                                Err(e) => error!("Failed to reload TLS certificates: {:?}", e),
+                            }
+                        }
+                        trace!("Performing heartbeat");
+                        let auth = match scheduler_auth() {
+                            Ok(auth) => auth,
+                            Err(e) => {
+                                error!("Failed to get token for the scheduler: {:?}", e);
+                                thread::sleep(HEARTBEAT_ERROR_INTERVAL);
+                                continue;
+                            }
+                        };
+                        let req = heartbeat_req.lock().unwrap().clone();
+                        match bincode_req(
+                            client
+                                .post(heartbeat_url.clone())
+                                .bearer_auth(auth)
+                                .bincode(&req)
+                                .expect("failed to serialize heartbeat"),
+                        ) {
+                            Ok(HeartbeatServerResult { is_new }) => {
+                                trace!("Heartbeat success is_new={}", is_new);
+                                // Running jobs carry on, the scheduler takes
+                                // them over as they update their state
+                                if is_new && registered {
+                                    info!(
+                                        "Registered with scheduler {} again, it may have restarted",
+                                        scheduler_url
+                                    );
+                                }
+                                registered = true;
+                                reachable.store(true, atomic::Ordering::SeqCst);
+                                thread::sleep(HEARTBEAT_INTERVAL)
+                            }
+                            Err(e) => {
+                                error!(
+                                    "Failed to send heartbeat to scheduler {}: {}",
+                                    scheduler_url, e
+                                );
+                                reachable.store(false, atomic::Ordering::SeqCst);
+                                thread::sleep(HEARTBEAT_ERROR_INTERVAL)
+                            }
                         }
                     }
-                }
-            });
+                });
+            }
+
+            if let Some((signal, timeout)) = shutdown {
+                let handler = handler.clone();
+                let client = requester.client.clone();
+                let scheduler_urls: Vec<_> = requester
+                    .schedulers
+                    .iter()
+                    .map(|(url, _)| url.clone())
+                    .collect();
+                let scheduler_auth = scheduler_auth.clone();
+                thread::spawn(move || {
+                    signal();
+                    info!(
+                        "Shutting down, waiting up to {:?} for jobs to finish",
+                        timeout
+                    );
+                    draining.store(true, atomic::Ordering::SeqCst);
+                    let deregister_req = DeregisterServerHttpRequest { server_nonce };
+                    for scheduler_url in scheduler_urls {
+                        let client = client.lock().unwrap().clone();
+                        let res = scheduler_auth().and_then(|auth| {
+                            bincode_req(
+                                client
+                                    .post(urls::scheduler_deregister_server(&scheduler_url))
+                                    .bearer_auth(auth)
+                                    .bincode(&deregister_req)?,
+                            )
+                        });
+                        match res {
+                            Ok(DeregisterServerResult { jobs_assigned }) => info!(
+                                "Deregistered from scheduler {}, with {} jobs assigned",
+                                scheduler_url, jobs_assigned
+                            ),
+                            Err(e) => warn!(
+                                "Failed to deregister from scheduler {}: {}",
+                                scheduler_url, e
+                            ),
+                        }
+                    }
+                    let started = Instant::now();
+                    loop {
+                        match handler.handle_metrics() {
+                            Ok(m) if m.jobs_assigned + m.jobs_running == 0 => break,
+                            Ok(_) if started.elapsed() < timeout => {
+                                thread::sleep(SHUTDOWN_POLL_INTERVAL)
+                            }
+                            Ok(m) => {
+                                warn!(
+                                    "Shutting down with {} jobs unfinished",
+                                    m.jobs_assigned + m.jobs_running
+                                );
+                                break;
+                            }
+                            Err(e) => {
+                                error!("Failed to check for unfinished jobs: {:?}", e);
+                                break;
+                            }
+                        }
+                    }
+                    info!("Server shut down");
+                    process::exit(0)
+                });
+            }
 
             info!("Server listening for clients on {}", public_addr);
             let request_count = atomic::AtomicUsize::new(0);
 
-            let server = rouille::Server::new_ssl(public_addr, move |request| {
+            let handle_request = move |request: &rouille::Request| {
                 let req_id = request_count.fetch_add(1, atomic::Ordering::SeqCst);
                 trace!("Req {} ({}): {:?}", req_id, request.remote_addr(), request);
-                let response = (|| router!(request,
-                    (POST) (/api/v1/distserver/assign_job/{job_id: JobId}) => {
-                        job_auth_or_401!(request, &job_authorizer, job_id);
-                        let toolchain = try_or_400_log!(req_id, bincode_input(request));
-                        trace!("Req {}: assign_job({}): {:?}", req_id, job_id, toolchain);
-
-                        let res: AssignJobResult = try_or_500_log!(req_id, handler.handle_assign_job(job_id, toolchain));
-                        prepare_response(&request, &res)
-                    },
-                    (POST) (/api/v1/distserver/submit_toolchain/{job_id: JobId}) => {
-                        job_auth_or_401!(request, &job_authorizer, job_id);
-                        trace!("Req {}: submit_toolchain({})", req_id, job_id);
+                let response = (|| {
+                    router!(request,
+                        (POST) (/api/v1/distserver/assign_job/{job_id: JobId}) => {
+                            job_auth_or_401!(request, &job_authorizer, job_id);
+                            let toolchain = try_or_400_log!(req_id, bincode_input(request));
+                            trace!("Req {}: assign_job({}): {:?}", req_id, job_id, toolchain);
+
+                            let res: AssignJobResult = try_or_500_log!(req_id, handler.handle_assign_job(job_id, toolchain));
+                            prepare_response(&request, &res)
+                        },
+                        (POST) (/api/v1/distserver/submit_toolchain/{job_id: JobId}) => {
+                            job_auth_or_401!(request, &job_authorizer, job_id);
+                            trace!("Req {}: submit_toolchain({})", req_id, job_id);
+
+                            let signature = request.header(signing::SIGNATURE_HEADER).map(str::to_owned);
+                            let body = request.data().expect("body was already read in submit_toolchain");
+                            let toolchain_rdr = ToolchainReader(Box::new(body));
+                            let res: SubmitToolchainResult = try_or_500_log!(req_id, handler.handle_submit_toolchain(&requester, job_id, signature, toolchain_rdr));
+                            prepare_response(&request, &res)
+                        },
+                        (POST) (/api/v1/distserver/run_job/{job_id: JobId}) => {
+                            job_auth_or_401!(request, &job_authorizer, job_id);
+
+                            let mut body = request.data().expect("body was already read in run_job");
+                            let bincode_length = try_or_500_log!(req_id, body.read_u32::<BigEndian>()
+                                .context("failed to read run job input length")) as u64;
+
+                            let mut bincode_reader = body.take(bincode_length);
+                            let runjob = try_or_500_log!(req_id, bincode::deserialize_from(&mut bincode_reader)
+                                .context("failed to deserialize run job request"));
+                            trace!("Req {}: run_job({}): {:?}", req_id, job_id, runjob);
+                            let RunJobHttpRequest { command, outputs } = runjob;
+                            let body = bincode_reader.into_inner();
+                            let inputs_rdr = if request.header("Content-Encoding") == Some(ZSTD_ENCODING) {
+                                let decoder = try_or_500_log!(req_id, zstd::stream::read::Decoder::new(body)
+                                    .context("failed to start decompressing run job inputs"));
+                                InputsReader(Box::new(decoder))
+                            } else {
+                                InputsReader(Box::new(ZlibReadDecoder::new(body)))
+                            };
+                            let outputs = outputs.into_iter().collect();
+                            let outputs_compression = if accepts_zstd(&request) {
+                                dist::Compression::Zstd(0)
+                            } else {
+                                dist::Compression::Zlib
+                            };
 
-                        let body = request.data().expect("body was already read in submit_toolchain");
-                        let toolchain_rdr = ToolchainReader(Box::new(body));
-                        let res: SubmitToolchainResult = try_or_500_log!(req_id, handler.handle_submit_toolchain(&requester, job_id, toolchain_rdr));
-                        prepare_response(&request, &res)
-                    },
-                    (POST) (/api/v1/distserver/run_job/{job_id: JobId}) => {
-                        job_auth_or_401!(request, &job_authorizer, job_id);
-
-                        let mut body = request.data().expect("body was already read in run_job");
-                        let bincode_length = try_or_500_log!(req_id, body.read_u32::<BigEndian>()
-                            .context("failed to read run job input length")) as u64;
-
-                        let mut bincode_reader = body.take(bincode_length);
-                        let runjob = try_or_500_log!(req_id, bincode::deserialize_from(&mut bincode_reader)
-                            .context("failed to deserialize run job request"));
-                        trace!("Req {}: run_job({}): {:?}", req_id, job_id, runjob);
-                        let RunJobHttpRequest { command, outputs } = runjob;
-                        let body = bincode_reader.into_inner();
-                        let inputs_rdr = InputsReader(Box::new(ZlibReadDecoder::new(body)));
-                        let outputs = outputs.into_iter().collect();
-
-                        let res: RunJobResult = try_or_500_log!(req_id, handler.handle_run_job(&requester, job_id, command, outputs, inputs_rdr));
-                        prepare_response(&request, &res)
-                    },
-                    _ => {
-                        warn!("Unknown request {:?}", request);
-                        rouille::Response::empty_404()
-                    },
-                ))();
+                            let res: RunJobResult = try_or_500_log!(req_id, handler.handle_run_job(&requester, job_id, command, outputs, inputs_rdr, outputs_compression));
+                            prepare_response(&request, &res)
+                        },
+                        (GET) (/metrics) => {
+                            let metrics = try_or_500_log!(req_id, handler.handle_metrics());
+                            rouille::Response::from_data(dist::metrics::CONTENT_TYPE, dist::metrics::server(&metrics))
+                        },
+                        _ => {
+                            warn!("Unknown request {:?}", request);
+                            rouille::Response::empty_404()
+                        },
+                    )
+                })();
                 trace!("Res {}: {:?}", req_id, response);
                 response
-            }, cert_pem, privkey_pem).map_err(|e| anyhow!(format!("Failed to start http server for sccache server: {}", e)))?;
+            };
+            // With TLS, the frontend forwards to the HTTP server on localhost
+            let server = match frontend {
+                Some(_) => {
+                    rouille::Server::new(SocketAddr::from(([127, 0, 0, 1], 0)), handle_request)
+                }
+                None => {
+                    rouille::Server::new_ssl(public_addr, handle_request, cert_pem, privkey_pem)
+                }
+            }
+            .map_err(|e| {
+                anyhow!(format!(
+                    "Failed to start http server for sccache server: {}",
+                    e
+                ))
+            })?;
+            if let Some(frontend) = frontend {
+                frontend.start(server.server_addr());
+            }
 
             // This limit is rouille's default for `start_server_with_pool`, which
             // we would use, except that interface doesn't permit any sort of
@@ -1030,10 +1352,77 @@ mod server {
         }
     }
 
+    /// Drain the server that `scheduler_auth` authenticates as for
+    /// maintenance, or put it back into service, returning the number of jobs
+    /// it has yet to finish. Only schedulers that can be reached are told.
+    pub fn drain_server(
+        scheduler_urls: &[reqwest::Url],
+        scheduler_auth: &SchedulerAuth,
+        tls: Option<&TlsCerts>,
+        drain: bool,
+    ) -> Result<usize> {
+        let client = tls::client_builder(tls)?
+            .build()
+            .context("failed to create a HTTP client")?;
+        let req = DrainServerHttpRequest { drain };
+        let mut jobs_assigned = None;
+        for scheduler_url in scheduler_urls {
+            let res = scheduler_auth().and_then(|auth| {
+                bincode_req(
+                    client
+                        .post(urls::scheduler_drain_server(scheduler_url))
+                        .bearer_auth(auth)
+                        .bincode(&req)?,
+                )
+            });
+            match res {
+                Ok(DrainServerResult { jobs_assigned: n }) => *jobs_assigned.get_or_insert(0) += n,
+                Err(e) => warn!("Failed to drain server with {}: {}", scheduler_url, e),
+            }
+        }
+        jobs_assigned.context("no scheduler could be reached")
+    }
+
     struct ServerRequester {
-        client: reqwest::Client,
-        scheduler_url: reqwest::Url,
-        scheduler_auth: String,
+        client: Arc<Mutex<reqwest::blocking::Client>>,
+        // The schedulers, and whether their last heartbeat succeeded
+        schedulers: Vec<(reqwest::Url, Arc<atomic::AtomicBool>)>,
+        scheduler_auth: Arc<dyn Fn() -> Result<String> + Send + Sync>,
+        heartbeat_req: Arc<Mutex<HeartbeatServerHttpRequest>>,
+        draining: Arc<atomic::AtomicBool>,
+    }
+
+    impl ServerRequester {
+        fn update_job_state(
+            &self,
+            scheduler_url: &reqwest::Url,
+            auth: &str,
+            job_id: JobId,
+            state: JobState,
+        ) -> Result<UpdateJobStateResult> {
+            let client = self.client.lock().unwrap().clone();
+            let url = urls::scheduler_job_state(scheduler_url, job_id);
+            let res = bincode_req(client.post(url).bearer_auth(auth).bincode(&state)?)?;
+            // A scheduler that has just restarted won't have had a heartbeat
+            // yet, so register with it now rather than fail the job
+            match res {
+                UpdateJobStateResult::UnknownServer
+                    if !self.draining.load(atomic::Ordering::SeqCst) =>
+                {
+                    info!("Registering again with scheduler {}", scheduler_url);
+                    let heartbeat_req = self.heartbeat_req.lock().unwrap().clone();
+                    let _: HeartbeatServerResult = bincode_req(
+                        client
+                            .post(urls::scheduler_heartbeat_server(scheduler_url))
+                            .bearer_auth(auth)
+                            .bincode(&heartbeat_req)?,
+                    )?;
+                    let url = urls::scheduler_job_state(scheduler_url, job_id);
+                    bincode_req(client.post(url).bearer_auth(auth).bincode(&state)?)
+                }
+                res => Ok(res),
+            }
+        }
     }
 
     impl dist::ServerOutgoing for ServerRequester {
@@ -1042,14 +1431,29 @@ mod server {
             job_id: JobId,
             state: JobState,
         ) -> Result<UpdateJobStateResult> {
-            let url = urls::scheduler_job_state(&self.scheduler_url, job_id);
-            bincode_req(
-                self.client
-                    .post(url)
-                    .bearer_auth(self.scheduler_auth.clone())
-                    .bincode(&state)?,
-            )
-            .context("POST to scheduler job_state failed")
+            let auth = (self.scheduler_auth)().context("failed to get token for the scheduler")?;
+            // Only the scheduler that allocated the job knows about it, which
+            // is most likely one of those that are up
+            let (reachable, unreachable): (Vec<_>, Vec<_>) = self
+                .schedulers
+                .iter()
+                .partition(|(_, reachable)| reachable.load(atomic::Ordering::SeqCst));
+            let mut res = Err(anyhow!("no schedulers configured"));
+            for (scheduler_url, _) in reachable.into_iter().chain(unreachable) {
+                res = self
+                    .update_job_state(scheduler_url, &auth, job_id, state)
+                    .and_then(|res| match res {
+                        UpdateJobStateResult::UnknownServer => Err(anyhow!(
+                            "scheduler {} doesn't know this server",
+                            scheduler_url
+                        )),
+                        res => Ok(res),
+                    });
+                if res.is_ok() {
+                    break;
+                }
+            }
+            res.context("POST to scheduler job_state failed")
         }
     }
 }
@@ -1058,65 +1462,167 @@ mod server {
 mod client {
     use super::super::cache;
     use crate::config;
+    use crate::dist::oauth2::AuthToken;
     use crate::dist::pkg::{InputsPackager, ToolchainPackager};
+    use crate::dist::signing::{self, ToolchainSigner};
+    use crate::dist::tls::{self, TlsCerts};
     use crate::dist::{
         self, AllocJobResult, CompileCommand, JobAlloc, PathTransformer, RunJobResult,
-        SchedulerStatusResult, SubmitToolchainResult, Toolchain,
+        SchedulerDetailsResult, SchedulerStatusResult, SubmitToolchainResult, Toolchain,
     };
-    use crate::util::SpawnExt;
+    use crate::util::{RequestExt, SpawnExt};
+    use async_trait::async_trait;
     use byteorder::{BigEndian, WriteBytesExt};
-    use flate2::write::ZlibEncoder as ZlibWriteEncoder;
-    use flate2::Compression;
-    use futures::Future;
-    use futures_03::executor::ThreadPool;
+    use futures::executor::ThreadPool;
+    use hyperx::header;
     use std::collections::HashMap;
+    use std::future::Future;
     use std::io::Write;
     use std::path::{Path, PathBuf};
     use std::sync::{Arc, Mutex};
-    use std::time::Duration;
+    use std::time::{Duration, Instant};
 
     use super::common::{
-        bincode_req, bincode_req_fut, AllocJobHttpResponse, ReqwestRequestBuilderExt,
-        RunJobHttpRequest, ServerCertificateHttpResponse,
+        bincode_req, bincode_req_fut, AllocJobHttpRequest, AllocJobHttpResponse,
+        ReqwestRequestBuilderExt, RunJobHttpRequest, ServerCertificateHttpResponse, ZSTD_ENCODING,
     };
     use super::urls;
     use crate::errors::*;
 
     const REQUEST_TIMEOUT_SECS: u64 = 600;
     const CONNECT_TIMEOUT_SECS: u64 = 5;
+    // How long to stay on a standby scheduler before trying the first one again
+    const SCHEDULER_FAILBACK_INTERVAL: Duration = Duration::from_secs(300);
+
+    // The schedulers to use, in order of preference
+    struct Schedulers {
+        urls: Vec<reqwest::Url>,
+        // The one in use, and since when
+        active: Mutex<(usize, Instant)>,
+    }
+
+    impl Schedulers {
+        fn new(urls: Vec<reqwest::Url>) -> Result<Self> {
+            if urls.is_empty() {
+                bail!("no scheduler urls configured")
+            }
+            Ok(Schedulers {
+                urls,
+                active: Mutex::new((0, Instant::now())),
+            })
+        }
+
+        // The order to try the schedulers in, starting with the active one
+        fn order(&self) -> Vec<usize> {
+            let mut active = self.active.lock().unwrap();
+            if active.0 != 0 && active.1.elapsed() > SCHEDULER_FAILBACK_INTERVAL {
+                *active = (0, Instant::now())
+            }
+            let n = self.urls.len();
+            (0..n).map(|i| (active.0 + i) % n).collect()
+        }
+
+        fn succeeded(&self, i: usize) {
+            let mut active = self.active.lock().unwrap();
+            if active.0 != i {
+                warn!("Switching to scheduler {}", self.urls[i]);
+                *active = (i, Instant::now())
+            }
+        }
+
+        fn failed(&self, i: usize, e: &Error) {
+            if self.urls.len() > 1 {
+                warn!("Request to scheduler {} failed: {}", self.urls[i], e)
+            }
+        }
+
+        // Make a request with `f`, trying each scheduler until one succeeds
+        fn request<T>(&self, f: impl Fn(&reqwest::Url) -> Result<T>) -> Result<(reqwest::Url, T)> {
+            let mut last_err = None;
+            for i in self.order() {
+                match f(&self.urls[i]) {
+                    Ok(res) => {
+                        self.succeeded(i);
+                        return Ok((self.urls[i].clone(), res));
+                    }
+                    Err(e) => {
+                        self.failed(i, &e);
+                        last_err = Some(e)
+                    }
+                }
+            }
+            Err(last_err.expect("no schedulers tried"))
+        }
+
+        // As `request`, for async requests
+        async fn request_fut<T, F>(
+            &self,
+            f: impl Fn(&reqwest::Url) -> F,
+        ) -> Result<(reqwest::Url, T)>
+        where
+            F: Future<Output = Result<T>>,
+        {
+            let mut last_err = None;
+            for i in self.order() {
+                match f(&self.urls[i]).await {
+                    Ok(res) => {
+                        self.succeeded(i);
+                        return Ok((self.urls[i].clone(), res));
+                    }
+                    Err(e) => {
+                        self.failed(i, &e);
+                        last_err = Some(e)
+                    }
+                }
+            }
+            Err(last_err.expect("no schedulers tried"))
+        }
+    }
 
     pub struct Client {
-        auth_token: String,
-        scheduler_url: reqwest::Url,
+        auth_token: Arc<AuthToken>,
+        schedulers: Arc<Schedulers>,
         // cert_digest -> cert_pem
         server_certs: Arc<Mutex<HashMap<Vec<u8>, Vec<u8>>>>,
         // TODO: this should really only use the async client, but reqwest async bodies are extremely limited
         // and only support owned bytes, which means the whole toolchain would end up in memory
-        client: Arc<Mutex<reqwest::Client>>,
-        client_async: Arc<Mutex<reqwest::r#async::Client>>,
+        client: Arc<Mutex<reqwest::blocking::Client>>,
+        client_async: Arc<Mutex<reqwest::Client>>,
+        // Certificates for mutual TLS with the scheduler and servers
+        tls: Option<Arc<TlsCerts>>,
         pool: ThreadPool,
         tc_cache: Arc<cache::ClientToolchains>,
+        toolchain_signer: Option<Arc<ToolchainSigner>>,
         rewrite_includes_only: bool,
+        priority: u32,
+        compression_level: i32,
+        fallback: config::DistFallback,
     }
 
     impl Client {
+        #[allow(clippy::too_many_arguments)]
         pub fn new(
             pool: &ThreadPool,
-            scheduler_url: reqwest::Url,
+            scheduler_urls: Vec<reqwest::Url>,
             cache_dir: &Path,
             cache_size: u64,
             toolchain_configs: &[config::DistToolchainConfig],
-            auth_token: String,
+            auth_token: AuthToken,
             rewrite_includes_only: bool,
+            tls: Option<TlsCerts>,
+            priority: u32,
+            compression_level: i32,
+            fallback: config::DistFallback,
+            toolchain_signer: Option<ToolchainSigner>,
         ) -> Result<Self> {
             let timeout = Duration::new(REQUEST_TIMEOUT_SECS, 0);
             let connect_timeout = Duration::new(CONNECT_TIMEOUT_SECS, 0);
-            let client = reqwest::ClientBuilder::new()
+            let client = tls::client_builder(tls.as_ref())?
                 .timeout(timeout)
                 .connect_timeout(connect_timeout)
                 .build()
                 .context("failed to create a HTTP client")?;
-            let client_async = reqwest::r#async::ClientBuilder::new()
+            let client_async = tls::async_client_builder(tls.as_ref())?
                 .timeout(timeout)
                 .connect_timeout(connect_timeout)
                 .build()
@@ -1125,26 +1631,32 @@ mod client {
                 cache::ClientToolchains::new(cache_dir, cache_size, toolchain_configs)
                     .context("failed to initialise client toolchains")?;
             Ok(Self {
-                auth_token,
-                scheduler_url,
+                auth_token: Arc::new(auth_token),
+                schedulers: Arc::new(Schedulers::new(scheduler_urls)?),
                 server_certs: Default::default(),
                 client: Arc::new(Mutex::new(client)),
                 client_async: Arc::new(Mutex::new(client_async)),
+                tls: tls.map(Arc::new),
                 pool: pool.clone(),
                 tc_cache: Arc::new(client_toolchains),
+                toolchain_signer: toolchain_signer.map(Arc::new),
                 rewrite_includes_only,
+                priority,
+                compression_level,
+                fallback,
             })
         }
 
         fn update_certs(
-            client: &mut reqwest::Client,
-            client_async: &mut reqwest::r#async::Client,
+            client: &mut reqwest::blocking::Client,
+            client_async: &mut reqwest::Client,
             certs: &mut HashMap<Vec<u8>, Vec<u8>>,
+            tls: Option<&TlsCerts>,
             cert_digest: Vec<u8>,
             cert_pem: Vec<u8>,
         ) -> Result<()> {
-            let mut client_builder = reqwest::ClientBuilder::new();
-            let mut client_async_builder = reqwest::r#async::ClientBuilder::new();
+            let mut client_builder = tls::client_builder(tls)?;
+            let mut client_async_builder = tls::async_client_builder(tls)?;
             // Add all the certificates we know about
             client_builder = client_builder.add_root_certificate(
                 reqwest::Certificate::from_pem(&cert_pem)
@@ -1180,141 +1692,243 @@ mod client {
         }
     }
 
+    #[async_trait(?Send)]
     impl dist::Client for Client {
-        fn do_alloc_job(&self, tc: Toolchain) -> SFuture<AllocJobResult> {
-            let scheduler_url = self.scheduler_url.clone();
-            let url = urls::scheduler_alloc_job(&scheduler_url);
-            let mut req = self.client_async.lock().unwrap().post(url);
-            req = ftry!(req.bearer_auth(self.auth_token.clone()).bincode(&tc));
-
-            let client = self.client.clone();
-            let client_async = self.client_async.clone();
-            let server_certs = self.server_certs.clone();
-            Box::new(bincode_req_fut(req).and_then(move |res| match res {
+        async fn do_alloc_job(&self, tc: Toolchain) -> Result<AllocJobResult> {
+            // Renewing the token may need a request to the token endpoint
+            let auth_token = self.auth_token.clone();
+            let auth = self.pool.spawn_fn(move || auth_token.token()).await?;
+
+            let alloc_job = AllocJobHttpRequest {
+                toolchain: tc,
+                priority: self.priority,
+            };
+            let (scheduler_url, res) = self
+                .schedulers
+                .request_fut(|scheduler_url| {
+                    let url = urls::scheduler_alloc_job(scheduler_url);
+                    let req = self.client_async.lock().unwrap().post(url);
+                    let req = req.bearer_auth(auth.clone()).bincode(&alloc_job);
+                    async move { bincode_req_fut(req?).await }
+                })
+                .await?;
+            // The certificate is asked for from the scheduler that allocated the job
+            match res {
                 AllocJobHttpResponse::Success {
                     job_alloc,
                     need_toolchain,
                     cert_digest,
                 } => {
                     let server_id = job_alloc.server_id;
-                    let alloc_job_res = f_ok(AllocJobResult::Success {
+                    let alloc_job_res = AllocJobResult::Success {
                         job_alloc,
                         need_toolchain,
-                    });
-                    if server_certs.lock().unwrap().contains_key(&cert_digest) {
-                        return alloc_job_res;
+                    };
+                    if self.server_certs.lock().unwrap().contains_key(&cert_digest) {
+                        return Ok(alloc_job_res);
                     }
                     info!(
                         "Need to request new certificate for server {}",
                         server_id.addr()
                     );
                     let url = urls::scheduler_server_certificate(&scheduler_url, server_id);
-                    let req = client_async.lock().unwrap().get(url);
-                    Box::new(
-                        bincode_req_fut(req)
-                            .map_err(|e| e.context("GET to scheduler server_certificate failed"))
-                            .and_then(move |res: ServerCertificateHttpResponse| {
-                                ftry!(Self::update_certs(
-                                    &mut client.lock().unwrap(),
-                                    &mut client_async.lock().unwrap(),
-                                    &mut server_certs.lock().unwrap(),
-                                    res.cert_digest,
-                                    res.cert_pem,
-                                ));
-                                alloc_job_res
-                            }),
-                    )
+                    let req = self.client_async.lock().unwrap().get(url);
+                    let res: ServerCertificateHttpResponse = bincode_req_fut(req)
+                        .await
+                        .context("GET to scheduler server_certificate failed")?;
+                    // The blocking client can't be built on the event loop
+                    let client = self.client.clone();
+                    let client_async = self.client_async.clone();
+                    let server_certs = self.server_certs.clone();
+                    let tls = self.tls.clone();
+                    self.pool
+                        .spawn_fn(move || {
+                            Self::update_certs(
+                                &mut client.lock().unwrap(),
+                                &mut client_async.lock().unwrap(),
+                                &mut server_certs.lock().unwrap(),
+                                tls.as_deref(),
+                                res.cert_digest,
+                                res.cert_pem,
+                            )
+                        })
+                        .await?;
+                    Ok(alloc_job_res)
                 }
-                AllocJobHttpResponse::Fail { msg } => f_ok(AllocJobResult::Fail { msg }),
-            }))
+                AllocJobHttpResponse::Fail { msg } => Ok(AllocJobResult::Fail { msg }),
+            }
         }
-        fn do_get_status(&self) -> SFuture<SchedulerStatusResult> {
-            let scheduler_url = self.scheduler_url.clone();
-            let url = urls::scheduler_status(&scheduler_url);
-            let req = self.client.lock().unwrap().get(url);
-            Box::new(self.pool.spawn_fn(move || bincode_req(req)))
+        async fn do_get_status(&self) -> Result<SchedulerStatusResult> {
+            let schedulers = self.schedulers.clone();
+            let client = self.client.clone();
+            self.pool
+                .spawn_fn(move || {
+                    let (_, res) = schedulers.request(|scheduler_url| {
+                        let url = urls::scheduler_status(scheduler_url);
+                        bincode_req(client.lock().unwrap().get(url))
+                    })?;
+                    Ok(res)
+                })
+                .await
         }
-        fn do_submit_toolchain(
+        async fn do_get_details(&self) -> Result<SchedulerDetailsResult> {
+            let schedulers = self.schedulers.clone();
+            let client = self.client.clone();
+            self.pool
+                .spawn_fn(move || {
+                    let (_, res) = schedulers.request(|scheduler_url| {
+                        let url = urls::scheduler_details(scheduler_url);
+                        // Served as JSON, for the dashboard
+                        let res = client
+                            .lock()
+                            .unwrap()
+                            .get(url)
+                            .set_header(header::Connection::close())
+                            .send()?
+                            .error_for_status()?;
+                        res.json().context("failed to parse the scheduler details")
+                    })?;
+                    Ok(res)
+                })
+                .await
+        }
+        async fn do_submit_toolchain(
             &self,
             job_alloc: JobAlloc,
             tc: Toolchain,
-        ) -> SFuture<SubmitToolchainResult> {
-            match self.tc_cache.get_toolchain(&tc) {
-                Ok(Some(toolchain_file)) => {
-                    let url = urls::server_submit_toolchain(job_alloc.server_id, job_alloc.job_id);
-                    let req = self.client.lock().unwrap().post(url);
-
-                    Box::new(self.pool.spawn_fn(move || {
-                        let req = req.bearer_auth(job_alloc.auth.clone()).body(toolchain_file);
-                        bincode_req(req)
-                    }))
-                }
-                Ok(None) => f_err(anyhow!("couldn't find toolchain locally")),
-                Err(e) => f_err(e),
+        ) -> Result<SubmitToolchainResult> {
+            // Centrally signed custom toolchains keep their signature
+            let signature = match self.tc_cache.get_custom_toolchain_signature(&tc)? {
+                Some(signature) => Some(signature),
+                None => self.toolchain_signer.as_ref().map(|s| s.sign(&tc)),
+            };
+            let toolchain_file = match self.tc_cache.get_toolchain(&tc)? {
+                Some(toolchain_file) => toolchain_file,
+                None => bail!("couldn't find toolchain locally"),
+            };
+            let url = urls::server_submit_toolchain(job_alloc.server_id, job_alloc.job_id);
+            let mut req = self.client.lock().unwrap().post(url);
+            if let Some(signature) = signature {
+                req = req.header(signing::SIGNATURE_HEADER, signature);
             }
+
+            self.pool
+                .spawn_fn(move || {
+                    let req = req.bearer_auth(job_alloc.auth.clone()).body(toolchain_file);
+                    bincode_req(req)
+                })
+                .await
         }
-        fn do_run_job(
+        async fn do_run_job(
             &self,
             job_alloc: JobAlloc,
             command: CompileCommand,
             outputs: Vec<String>,
             inputs_packager: Box<dyn InputsPackager>,
-        ) -> SFuture<(RunJobResult, PathTransformer)> {
+        ) -> Result<(RunJobResult, PathTransformer)> {
             let url = urls::server_run_job(job_alloc.server_id, job_alloc.job_id);
             let mut req = self.client.lock().unwrap().post(url);
-
-            Box::new(self.pool.spawn_fn(move || {
-                let bincode = bincode::serialize(&RunJobHttpRequest { command, outputs })
-                    .context("failed to serialize run job request")?;
-                let bincode_length = bincode.len();
-
-                let mut body = vec![];
-                body.write_u32::<BigEndian>(bincode_length as u32)
-                    .expect("Infallible write of bincode length to vec failed");
-                body.write_all(&bincode)
-                    .expect("Infallible write of bincode body to vec failed");
-                let path_transformer;
-                {
-                    let mut compressor = ZlibWriteEncoder::new(&mut body, Compression::fast());
-                    path_transformer = inputs_packager
-                        .write_inputs(&mut compressor)
-                        .context("Could not write inputs for compilation")?;
-                    compressor.flush().context("failed to flush compressor")?;
+            let compression_level = self.compression_level;
+
+            self.pool
+                .spawn_fn(move || {
+                    let bincode = bincode::serialize(&RunJobHttpRequest { command, outputs })
+                        .context("failed to serialize run job request")?;
+                    let bincode_length = bincode.len();
+
+                    let mut body = vec![];
+                    body.write_u32::<BigEndian>(bincode_length as u32)
+                        .expect("Infallible write of bincode length to vec failed");
+                    body.write_all(&bincode)
+                        .expect("Infallible write of bincode body to vec failed");
+                    let path_transformer;
+                    {
+                        let mut compressor =
+                            zstd::stream::write::Encoder::new(&mut body, compression_level)
+                                .context("failed to start compressor")?;
+                        path_transformer = inputs_packager
+                            .write_inputs(&mut compressor)
+                            .context("Could not write inputs for compilation")?;
+                        compressor.finish().context("failed to finish compressor")?;
+                    }
                     trace!(
-                        "Compressed inputs from {} -> {}",
-                        compressor.total_in(),
-                        compressor.total_out()
+                        "Compressed inputs to {} bytes",
+                        body.len() - 4 - bincode_length
                     );
-                    compressor.finish().context("failed to finish compressor")?;
-                }
 
-                req = req.bearer_auth(job_alloc.auth.clone()).bytes(body);
-                bincode_req(req).map(|res| (res, path_transformer))
-            }))
+                    let encoding = header::Encoding::EncodingExt(ZSTD_ENCODING.to_owned());
+                    req = req
+                        .bearer_auth(job_alloc.auth.clone())
+                        .set_header(header::ContentEncoding(vec![encoding.clone()]))
+                        .set_header(header::AcceptEncoding(vec![header::qitem(encoding)]))
+                        .bytes(body);
+                    bincode_req(req).map(|res| (res, path_transformer))
+                })
+                .await
         }
 
-        fn put_toolchain(
+        async fn put_toolchain(
             &self,
             compiler_path: &Path,
             weak_key: &str,
             toolchain_packager: Box<dyn ToolchainPackager>,
-        ) -> SFuture<(Toolchain, Option<(String, PathBuf)>)> {
+        ) -> Result<(Toolchain, Option<(String, PathBuf)>)> {
             let compiler_path = compiler_path.to_owned();
             let weak_key = weak_key.to_owned();
             let tc_cache = self.tc_cache.clone();
-            Box::new(self.pool.spawn_fn(move || {
-                tc_cache.put_toolchain(&compiler_path, &weak_key, toolchain_packager)
-            }))
+            self.pool
+                .spawn_fn(move || {
+                    tc_cache.put_toolchain(&compiler_path, &weak_key, toolchain_packager)
+                })
+                .await
         }
 
         fn rewrite_includes_only(&self) -> bool {
             self.rewrite_includes_only
         }
+        fn fallback(&self) -> config::DistFallback {
+            self.fallback
+        }
         fn get_custom_toolchain(&self, exe: &PathBuf) -> Option<PathBuf> {
             match self.tc_cache.get_custom_toolchain(exe) {
                 Some(Ok((_, _, path))) => Some(path),
                 _ => None,
             }
         }
+        fn clear_toolchain_cache(&self) -> Result<u64> {
+            self.tc_cache.clear()
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn test_schedulers_failover() {
+            let urls: Vec<reqwest::Url> = ["https://a.example/", "https://b.example/"]
+                .iter()
+                .map(|u| u.parse().unwrap())
+                .collect();
+            let schedulers = Schedulers::new(urls.clone()).unwrap();
+            let up = |up: &'static [usize]| {
+                let urls = urls.clone();
+                move |url: &reqwest::Url| -> Result<()> {
+                    match up.iter().find(|&&i| urls[i] == *url) {
+                        Some(_) => Ok(()),
+                        None => Err(anyhow!("down")),
+                    }
+                }
+            };
+
+            assert_eq!(schedulers.request(up(&[0, 1])).unwrap().0, urls[0]);
+            assert_eq!(schedulers.request(up(&[1])).unwrap().0, urls[1]);
+            // The standby stays in use until the failback interval passes
+            assert_eq!(schedulers.request(up(&[0, 1])).unwrap().0, urls[1]);
+            schedulers.active.lock().unwrap().1 -=
+                SCHEDULER_FAILBACK_INTERVAL + Duration::from_secs(1);
+            assert_eq!(schedulers.request(up(&[0, 1])).unwrap().0, urls[0]);
+            assert!(schedulers.request(up(&[])).is_err());
+        }
     }
 }
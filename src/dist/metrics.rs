@@ -0,0 +1,209 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Metrics of the scheduler and build servers, in the Prometheus text
+//! exposition format.
+
+use super::{BuildServerMetrics, SchedulerMetrics, ServerDetailsResult};
+use crate::metrics::Metrics;
+
+pub use crate::metrics::CONTENT_TYPE;
+
+pub fn scheduler(m: &SchedulerMetrics) -> String {
+    let mut out = Metrics::default();
+    let num_cpus: usize = m.servers.iter().map(|s| s.num_cpus).sum();
+    out.gauge(
+        "sccache_scheduler_servers",
+        "Build servers sending heartbeats.",
+        m.servers.len() as f64,
+    );
+    out.gauge(
+        "sccache_scheduler_cpus",
+        "Cores across all build servers.",
+        num_cpus as f64,
+    );
+    out.metric(
+        "gauge",
+        "sccache_scheduler_jobs",
+        "Jobs allocated and not yet finished, by state. Pending and ready jobs are queued.",
+        &[
+            (&[("state", "pending")], m.jobs_pending as f64),
+            (&[("state", "ready")], m.jobs_ready as f64),
+            (&[("state", "started")], m.jobs_started as f64),
+        ],
+    );
+
+    let servers: Vec<_> = m
+        .servers
+        .iter()
+        .map(|s| (s.server_id.addr().to_string(), s))
+        .collect();
+    let mut per_server = |name: &str, help: &str, value: fn(&ServerDetailsResult) -> f64| {
+        let labels: Vec<_> = servers
+            .iter()
+            .map(|(id, _)| [("server", id.as_str())])
+            .collect();
+        let samples: Vec<(&[(&str, &str)], f64)> = labels
+            .iter()
+            .zip(&servers)
+            .map(|(labels, (_, s))| (&labels[..], value(s)))
+            .collect();
+        out.metric("gauge", name, help, &samples)
+    };
+    per_server(
+        "sccache_scheduler_server_cpus",
+        "Cores on each build server.",
+        |s| s.num_cpus as f64,
+    );
+    per_server(
+        "sccache_scheduler_server_jobs",
+        "Jobs assigned to each build server.",
+        |s| s.jobs_assigned as f64,
+    );
+    per_server(
+        "sccache_scheduler_server_load",
+        "Jobs assigned to each build server per core.",
+        |s| s.jobs_assigned as f64 / s.num_cpus as f64,
+    );
+    per_server(
+        "sccache_scheduler_server_toolchains",
+        "Toolchains each build server is known to have.",
+        |s| s.toolchains.len() as f64,
+    );
+    per_server(
+        "sccache_scheduler_server_erroring",
+        "Whether each build server has failed to take a job recently.",
+        |s| s.last_error_ms_ago.map_or(0.0, |_| 1.0),
+    );
+    per_server(
+        "sccache_scheduler_server_draining",
+        "Whether each build server is shutting down, so gets no new jobs.",
+        |s| if s.draining { 1.0 } else { 0.0 },
+    );
+
+    out.gauge(
+        "sccache_scheduler_job_wait_seconds",
+        "Average time recent jobs waited between being allocated and starting.",
+        m.average_wait_ms.unwrap_or(0) as f64 / 1000.0,
+    );
+
+    out.counter(
+        "sccache_scheduler_alloc_requests_total",
+        "Requests from clients for a server to run a job on.",
+        m.alloc_requests,
+    );
+    out.counter(
+        "sccache_scheduler_alloc_failures_total",
+        "Requests for a server that were refused, or found no server free.",
+        m.alloc_failures,
+    );
+    out.counter(
+        "sccache_scheduler_assign_failures_total",
+        "Jobs that the chosen build server failed to take.",
+        m.assign_failures,
+    );
+    out.metric(
+        "counter",
+        "sccache_scheduler_jobs_finished_total",
+        "Jobs finished, by outcome.",
+        &[
+            (&[("outcome", "complete")], m.jobs_complete as f64),
+            (&[("outcome", "server_lost")], m.jobs_server_lost as f64),
+            (&[("outcome", "abandoned")], m.jobs_abandoned as f64),
+        ],
+    );
+    out.0
+}
+
+pub fn server(m: &BuildServerMetrics) -> String {
+    let mut out = Metrics::default();
+    out.metric(
+        "gauge",
+        "sccache_server_jobs",
+        "Jobs on this build server, by state. Assigned jobs haven't started running yet.",
+        &[
+            (&[("state", "assigned")], m.jobs_assigned as f64),
+            (&[("state", "running")], m.jobs_running as f64),
+        ],
+    );
+    out.metric(
+        "counter",
+        "sccache_server_builds_total",
+        "Builds run, by result. Failed builds are ones that couldn't be run, not compile errors.",
+        &[
+            (&[("result", "complete")], m.builds_complete as f64),
+            (&[("result", "failed")], m.builds_failed as f64),
+            (
+                &[("result", "out_of_memory")],
+                m.builds_out_of_memory as f64,
+            ),
+            (&[("result", "timed_out")], m.builds_timed_out as f64),
+        ],
+    );
+    out.gauge(
+        "sccache_server_toolchains",
+        "Toolchains in the toolchain cache.",
+        m.toolchains as f64,
+    );
+    out.gauge(
+        "sccache_server_toolchain_cache_bytes",
+        "Size of the toolchain cache.",
+        m.toolchain_cache_size as f64,
+    );
+    out.gauge(
+        "sccache_server_toolchain_cache_capacity_bytes",
+        "Maximum size of the toolchain cache.",
+        m.toolchain_cache_capacity as f64,
+    );
+    out.0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dist::ServerId;
+
+    #[test]
+    fn test_scheduler_metrics() {
+        let metrics = SchedulerMetrics {
+            servers: vec![ServerDetailsResult {
+                server_id: ServerId::new(([10, 0, 0, 1], 10501).into()),
+                num_cpus: 8,
+                jobs_assigned: 4,
+                jobs_unclaimed: 1,
+                jobs_started: 0,
+                last_seen_ms_ago: 0,
+                last_error_ms_ago: None,
+                recent_errors: 0,
+                toolchains: vec!["abc".to_owned()],
+                draining: false,
+            }],
+            jobs_pending: 1,
+            jobs_started: 3,
+            alloc_requests: 5,
+            jobs_complete: 1,
+            ..Default::default()
+        };
+        let text = scheduler(&metrics);
+        assert!(text.contains(
+            "# HELP sccache_scheduler_servers Build servers sending heartbeats.\n\
+             # TYPE sccache_scheduler_servers gauge\n\
+             sccache_scheduler_servers 1\n"
+        ));
+        assert!(text.contains("sccache_scheduler_jobs{state=\"pending\"} 1\n"));
+        assert!(text.contains("sccache_scheduler_server_load{server=\"10.0.0.1:10501\"} 0.5\n"));
+        assert!(text.contains("sccache_scheduler_alloc_requests_total 5\n"));
+        assert!(text.contains("sccache_scheduler_jobs_finished_total{outcome=\"complete\"} 1\n"));
+    }
+}
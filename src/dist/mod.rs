@@ -13,11 +13,12 @@
 // limitations under the License.
 
 use crate::compiler;
+use async_trait::async_trait;
 use rand::{rngs::OsRng, RngCore};
 use std::ffi::OsString;
 use std::fmt;
 use std::io::{self, Read};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
 use std::process;
 use std::str::FromStr;
@@ -32,9 +33,19 @@ mod cache;
 pub mod client_auth;
 #[cfg(any(feature = "dist-client", feature = "dist-server"))]
 pub mod http;
+#[cfg(feature = "dist-server")]
+mod metrics;
+#[cfg(any(feature = "dist-client", feature = "dist-server"))]
+pub mod oauth2;
+#[cfg(any(feature = "dist-client", feature = "dist-server"))]
+pub mod signing;
 #[cfg(test)]
 mod test;
+#[cfg(any(feature = "dist-client", feature = "dist-server"))]
+pub mod tls;
 
+#[cfg(feature = "dist-client")]
+pub use crate::dist::cache::ClientToolchains;
 #[cfg(any(feature = "dist-client", feature = "dist-server"))]
 pub use crate::dist::cache::TcCache;
 
@@ -468,18 +479,38 @@ impl From<ProcessOutput> for process::Output {
     }
 }
 
+/// How job inputs and outputs are compressed for sending between clients and
+/// servers. Clients that can use zstd say so in their request headers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum Compression {
+    Zlib,
+    /// At a zstd compression level, where 0 is zstd's default.
+    Zstd(i32),
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
-pub struct OutputData(Vec<u8>, u64);
+pub struct OutputData(Vec<u8>, u64, Compression);
 impl OutputData {
     #[cfg(any(feature = "dist-server", all(feature = "dist-client", test)))]
-    pub fn try_from_reader<R: Read>(r: R) -> io::Result<Self> {
-        use flate2::read::ZlibEncoder as ZlibReadEncoder;
-        use flate2::Compression;
-        let mut compressor = ZlibReadEncoder::new(r, Compression::fast());
+    pub fn try_from_reader<R: Read>(mut r: R, compression: Compression) -> io::Result<Self> {
         let mut res = vec![];
-        io::copy(&mut compressor, &mut res)?;
-        Ok(OutputData(res, compressor.total_in()))
+        let len = match compression {
+            Compression::Zlib => {
+                use flate2::read::ZlibEncoder as ZlibReadEncoder;
+                let mut compressor = ZlibReadEncoder::new(r, flate2::Compression::fast());
+                io::copy(&mut compressor, &mut res)?;
+                compressor.total_in()
+            }
+            Compression::Zstd(level) => {
+                let mut compressor = zstd::stream::write::Encoder::new(&mut res, level)?;
+                let len = io::copy(&mut r, &mut compressor)?;
+                compressor.finish()?;
+                len
+            }
+        };
+        Ok(OutputData(res, len, compression))
     }
     pub fn lens(&self) -> OutputDataLens {
         OutputDataLens {
@@ -488,9 +519,13 @@ impl OutputData {
         }
     }
     #[cfg(feature = "dist-client")]
-    pub fn into_reader(self) -> impl Read {
+    pub fn into_reader(self) -> io::Result<Box<dyn Read>> {
         use flate2::read::ZlibDecoder as ZlibReadDecoder;
-        ZlibReadDecoder::new(io::Cursor::new(self.0))
+        let data = io::Cursor::new(self.0);
+        Ok(match self.2 {
+            Compression::Zlib => Box::new(ZlibReadDecoder::new(data)),
+            Compression::Zstd(_) => Box::new(zstd::stream::read::Decoder::new(data)?),
+        })
     }
 }
 pub struct OutputDataLens {
@@ -543,7 +578,12 @@ pub struct AssignJobResult {
 #[serde(deny_unknown_fields)]
 pub enum UpdateJobStateResult {
     Success,
-    Fail { msg: String },
+    Fail {
+        msg: String,
+    },
+    /// The server isn't registered, e.g. because the scheduler has restarted,
+    /// and should send a heartbeat before trying again.
+    UnknownServer,
 }
 
 // HeartbeatServer
@@ -554,6 +594,20 @@ pub struct HeartbeatServerResult {
     pub is_new: bool,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeregisterServerResult {
+    /// Jobs the server was assigned before, which it may still finish.
+    pub jobs_assigned: usize,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DrainServerResult {
+    /// Jobs the server has been assigned and not yet finished.
+    pub jobs_assigned: usize,
+}
+
 // RunJob
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -561,6 +615,10 @@ pub struct HeartbeatServerResult {
 pub enum RunJobResult {
     JobNotFound,
     Complete(JobComplete),
+    /// The compile was killed for using more memory than the server allows.
+    OutOfMemory,
+    /// The compile was killed for running for longer than the server allows.
+    TimedOut,
 }
 #[derive(Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -579,6 +637,132 @@ pub struct SchedulerStatusResult {
     pub in_progress: usize,
 }
 
+// Details, for monitoring the scheduler. Times are in milliseconds, and
+// timestamps in milliseconds since the unix epoch.
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SchedulerDetailsResult {
+    pub servers: Vec<ServerDetailsResult>,
+    pub jobs: Vec<JobDetailsResult>,
+    /// The most recently finished jobs, newest first.
+    pub history: Vec<JobHistoryResult>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerDetailsResult {
+    pub server_id: ServerId,
+    pub num_cpus: usize,
+    pub jobs_assigned: usize,
+    /// Jobs assigned that the client hasn't started yet.
+    pub jobs_unclaimed: usize,
+    /// Jobs the server is running now.
+    #[serde(default)]
+    pub jobs_started: usize,
+    pub last_seen_ms_ago: u64,
+    pub last_error_ms_ago: Option<u64>,
+    /// Jobs the server failed to take in the last five minutes.
+    #[serde(default)]
+    pub recent_errors: usize,
+    /// The toolchains the server is known to have been given.
+    pub toolchains: Vec<String>,
+    /// Whether the server is shutting down or drained for maintenance, so
+    /// gets no new jobs.
+    pub draining: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobDetailsResult {
+    pub job_id: JobId,
+    pub server_id: ServerId,
+    pub state: JobState,
+    pub client: IpAddr,
+    pub priority: u32,
+    pub toolchain: String,
+    pub age_ms: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum JobOutcome {
+    Complete,
+    /// The server stopped sending heartbeats or restarted.
+    ServerLost,
+    /// The client never started the job.
+    Abandoned,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobHistoryResult {
+    pub job_id: JobId,
+    pub server_id: ServerId,
+    pub client: IpAddr,
+    pub priority: u32,
+    pub toolchain: String,
+    pub outcome: JobOutcome,
+    pub duration_ms: u64,
+    pub finished_at: u64,
+}
+
+// Signals for scaling the build servers with demand. Times are in
+// milliseconds.
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SchedulerAutoscalingResult {
+    /// Servers taking new jobs.
+    pub num_servers: usize,
+    pub num_cpus: usize,
+    /// Jobs allocated that haven't started yet.
+    pub jobs_queued: usize,
+    pub jobs_running: usize,
+    /// Queued and running jobs per core of the servers taking new jobs, or
+    /// the number of jobs if there are no servers.
+    pub load: f64,
+    /// How long jobs that started recently waited between being allocated
+    /// and starting, on average, if any have started.
+    pub average_wait_ms: Option<u64>,
+}
+
+// Metrics, for Prometheus. Counts are totals since startup.
+
+#[derive(Clone, Debug, Default)]
+pub struct SchedulerMetrics {
+    pub servers: Vec<ServerDetailsResult>,
+    /// Jobs allocated that haven't started yet, waiting for the client to
+    /// submit the toolchain or to run them.
+    pub jobs_pending: usize,
+    pub jobs_ready: usize,
+    pub jobs_started: usize,
+    pub alloc_requests: u64,
+    /// Allocations refused, or failed for lack of a server.
+    pub alloc_failures: u64,
+    /// Allocations that the chosen server failed to take.
+    pub assign_failures: u64,
+    pub jobs_complete: u64,
+    pub jobs_server_lost: u64,
+    pub jobs_abandoned: u64,
+    /// As for `SchedulerAutoscalingResult`.
+    pub average_wait_ms: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct BuildServerMetrics {
+    /// Jobs assigned by the scheduler that the client hasn't run yet.
+    pub jobs_assigned: usize,
+    pub jobs_running: usize,
+    pub builds_complete: u64,
+    pub builds_failed: u64,
+    pub builds_out_of_memory: u64,
+    pub builds_timed_out: u64,
+    pub toolchains: usize,
+    pub toolchain_cache_size: u64,
+    pub toolchain_cache_capacity: u64,
+}
+
 // SubmitToolchain
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -587,6 +771,8 @@ pub enum SubmitToolchainResult {
     Success,
     JobNotFound,
     CannotCache,
+    /// The server only runs toolchains signed by its trusted signers.
+    Untrusted(String),
 }
 
 ///////////////////
@@ -649,10 +835,12 @@ pub trait JobAuthorizer: Send {
 
 #[cfg(feature = "dist-server")]
 pub trait SchedulerIncoming: Send + Sync {
-    // From Client
+    // From Client, identified by its IP address
     fn handle_alloc_job(
         &self,
         requester: &dyn SchedulerOutgoing,
+        client: IpAddr,
+        priority: u32,
         tc: Toolchain,
     ) -> ExtResult<AllocJobResult, Error>;
     // From Server
@@ -664,6 +852,18 @@ pub trait SchedulerIncoming: Send + Sync {
         job_authorizer: Box<dyn JobAuthorizer>,
     ) -> ExtResult<HeartbeatServerResult, Error>;
     // From Server
+    fn handle_deregister_server(
+        &self,
+        server_id: ServerId,
+        server_nonce: ServerNonce,
+    ) -> ExtResult<DeregisterServerResult, Error>;
+    // From Server
+    fn handle_drain_server(
+        &self,
+        server_id: ServerId,
+        drain: bool,
+    ) -> ExtResult<DrainServerResult, Error>;
+    // From Server
     fn handle_update_job_state(
         &self,
         job_id: JobId,
@@ -672,6 +872,12 @@ pub trait SchedulerIncoming: Send + Sync {
     ) -> ExtResult<UpdateJobStateResult, Error>;
     // From anyone
     fn handle_status(&self) -> ExtResult<SchedulerStatusResult, Error>;
+    // From anyone
+    fn handle_details(&self) -> ExtResult<SchedulerDetailsResult, Error>;
+    // From anyone
+    fn handle_metrics(&self) -> ExtResult<SchedulerMetrics, Error>;
+    // From anyone
+    fn handle_autoscaling(&self) -> ExtResult<SchedulerAutoscalingResult, Error>;
 }
 
 #[cfg(feature = "dist-server")]
@@ -683,6 +889,7 @@ pub trait ServerIncoming: Send + Sync {
         &self,
         requester: &dyn ServerOutgoing,
         job_id: JobId,
+        signature: Option<String>,
         tc_rdr: ToolchainReader<'_>,
     ) -> ExtResult<SubmitToolchainResult, Error>;
     // From Client
@@ -693,7 +900,10 @@ pub trait ServerIncoming: Send + Sync {
         command: CompileCommand,
         outputs: Vec<String>,
         inputs_rdr: InputsReader<'_>,
+        outputs_compression: Compression,
     ) -> ExtResult<RunJobResult, Error>;
+    // From anyone
+    fn handle_metrics(&self) -> ExtResult<BuildServerMetrics, Error>;
 }
 
 #[cfg(feature = "dist-server")]
@@ -705,37 +915,44 @@ pub trait BuilderIncoming: Send + Sync {
         command: CompileCommand,
         outputs: Vec<String>,
         inputs_rdr: InputsReader<'_>,
+        outputs_compression: Compression,
         cache: &Mutex<TcCache>,
     ) -> ExtResult<BuildResult, Error>;
 }
 
 /////////
 
+#[async_trait(?Send)]
 pub trait Client {
     // To Scheduler
-    fn do_alloc_job(&self, tc: Toolchain) -> SFuture<AllocJobResult>;
+    async fn do_alloc_job(&self, tc: Toolchain) -> Result<AllocJobResult>;
+    // To Scheduler
+    async fn do_get_status(&self) -> Result<SchedulerStatusResult>;
     // To Scheduler
-    fn do_get_status(&self) -> SFuture<SchedulerStatusResult>;
+    async fn do_get_details(&self) -> Result<SchedulerDetailsResult>;
     // To Server
-    fn do_submit_toolchain(
+    async fn do_submit_toolchain(
         &self,
         job_alloc: JobAlloc,
         tc: Toolchain,
-    ) -> SFuture<SubmitToolchainResult>;
+    ) -> Result<SubmitToolchainResult>;
     // To Server
-    fn do_run_job(
+    async fn do_run_job(
         &self,
         job_alloc: JobAlloc,
         command: CompileCommand,
         outputs: Vec<String>,
         inputs_packager: Box<dyn pkg::InputsPackager>,
-    ) -> SFuture<(RunJobResult, PathTransformer)>;
-    fn put_toolchain(
+    ) -> Result<(RunJobResult, PathTransformer)>;
+    async fn put_toolchain(
         &self,
         compiler_path: &Path,
         weak_key: &str,
         toolchain_packager: Box<dyn pkg::ToolchainPackager>,
-    ) -> SFuture<(Toolchain, Option<(String, PathBuf)>)>;
+    ) -> Result<(Toolchain, Option<(String, PathBuf)>)>;
     fn rewrite_includes_only(&self) -> bool;
+    fn fallback(&self) -> crate::config::DistFallback;
     fn get_custom_toolchain(&self, exe: &PathBuf) -> Option<PathBuf>;
+    /// Remove the packaged toolchains, returning the bytes freed.
+    fn clear_toolchain_cache(&self) -> Result<u64>;
 }
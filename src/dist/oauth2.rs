@@ -0,0 +1,295 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bearer tokens for talking to the scheduler, which may be short-lived
+//! tokens obtained from an OAuth2 token endpoint.
+
+use crate::config;
+use std::result::Result as StdResult;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::errors::*;
+
+/// Get a new token once the current one expires within this long, so that it
+/// doesn't expire while requests using it are in flight.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+// Token response - https://tools.ietf.org/html/rfc6749#section-5.1
+#[derive(Deserialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: Option<u64>, // Technically not required by the spec
+    pub refresh_token: Option<String>,
+}
+const TOKEN_TYPE_RESULT_PARAM_VALUE: &str = "bearer"; // case-insensitive
+
+// Error response - https://tools.ietf.org/html/rfc6749#section-5.2
+#[derive(Deserialize)]
+pub struct ErrorResponse {
+    pub error: String,
+    pub error_description: Option<String>,
+}
+
+/// POST `params` to the token endpoint at `token_url`. Errors reported by the
+/// endpoint are returned as `Ok(Err(..))`, as some flows expect them.
+pub fn request_token(
+    client: &reqwest::blocking::Client,
+    token_url: &str,
+    params: &[(&str, &str)],
+) -> Result<StdResult<TokenResponse, ErrorResponse>> {
+    let res = client
+        .post(token_url)
+        .form(params)
+        .send()
+        .with_context(|| format!("Failed to make request to {}", token_url))?;
+    let status = res.status();
+    let text = res
+        .text()
+        .with_context(|| format!("Failed to read token response from {}", token_url))?;
+    if !status.is_success() {
+        return match serde_json::from_str(&text) {
+            Ok(err) => Ok(Err(err)),
+            Err(_) => bail!(
+                "Token request to {} failed, HTTP error: {}",
+                token_url,
+                status
+            ),
+        };
+    }
+    let token: TokenResponse =
+        serde_json::from_str(&text).context("Failed to parse token response as JSON")?;
+    if token.token_type.to_lowercase() != TOKEN_TYPE_RESULT_PARAM_VALUE {
+        bail!(
+            "Token type in response is not {}",
+            TOKEN_TYPE_RESULT_PARAM_VALUE
+        )
+    }
+    Ok(Ok(token))
+}
+
+impl ErrorResponse {
+    pub fn into_error(self, token_url: &str) -> Error {
+        match self.error_description {
+            Some(description) => anyhow!(
+                "Token request to {} failed: {} ({})",
+                token_url,
+                self.error,
+                description
+            ),
+            None => anyhow!("Token request to {} failed: {}", token_url, self.error),
+        }
+    }
+}
+
+enum Source {
+    Fixed,
+    // https://tools.ietf.org/html/rfc6749#section-4.4
+    ClientCredentials {
+        token_url: String,
+        params: Vec<(&'static str, String)>,
+    },
+    // https://tools.ietf.org/html/rfc6749#section-6
+    Refresh {
+        token_url: String,
+        client_id: String,
+        refresh_token: String,
+    },
+}
+
+struct State {
+    source: Source,
+    // The current token, and when it expires
+    current: Option<(String, Option<Instant>)>,
+}
+
+/// A token to authenticate to the scheduler with, fetching a new one when
+/// the last one is about to expire.
+pub struct AuthToken {
+    client: reqwest::blocking::Client,
+    state: Mutex<State>,
+}
+
+impl AuthToken {
+    fn new(source: Source, current: Option<String>) -> Self {
+        AuthToken {
+            client: reqwest::blocking::Client::new(),
+            state: Mutex::new(State {
+                source,
+                current: current.map(|token| (token, None)),
+            }),
+        }
+    }
+
+    /// A token that never needs renewing.
+    pub fn fixed(token: String) -> Self {
+        Self::new(Source::Fixed, Some(token))
+    }
+
+    /// Tokens obtained with the client credentials grant.
+    pub fn client_credentials(
+        client_id: &str,
+        client_secret: &str,
+        token_url: &str,
+        scope: Option<&str>,
+        audience: Option<&str>,
+    ) -> Self {
+        let mut params = vec![
+            ("grant_type", "client_credentials".to_owned()),
+            ("client_id", client_id.to_owned()),
+            ("client_secret", client_secret.to_owned()),
+        ];
+        params.extend(scope.map(|scope| ("scope", scope.to_owned())));
+        params.extend(audience.map(|audience| ("audience", audience.to_owned())));
+        let source = Source::ClientCredentials {
+            token_url: token_url.to_owned(),
+            params,
+        };
+        Self::new(source, None)
+    }
+
+    /// Tokens obtained with a refresh token, e.g. from the device flow of
+    /// `sccache --dist-auth`. Refresh tokens replaced by the token endpoint
+    /// are saved in the cached config.
+    pub fn refresh(client_id: &str, token_url: &str, refresh_token: String) -> Self {
+        let source = Source::Refresh {
+            token_url: token_url.to_owned(),
+            client_id: client_id.to_owned(),
+            refresh_token,
+        };
+        Self::new(source, None)
+    }
+
+    /// The token to use for the next request.
+    pub fn token(&self) -> Result<String> {
+        let mut state = self.state.lock().unwrap();
+        if let Some((token, expires_at)) = &state.current {
+            if expires_at.map_or(true, |at| Instant::now() + EXPIRY_MARGIN < at) {
+                return Ok(token.clone());
+            }
+        }
+        let requested_at = Instant::now();
+        let res = match &state.source {
+            Source::Fixed => unreachable!("fixed tokens never expire"),
+            Source::ClientCredentials { token_url, params } => {
+                trace!("Requesting client credentials token from {}", token_url);
+                let params: Vec<_> = params.iter().map(|(k, v)| (*k, v.as_str())).collect();
+                request_token(&self.client, token_url, &params)?
+                    .map_err(|e| e.into_error(token_url))?
+            }
+            Source::Refresh {
+                token_url,
+                client_id,
+                refresh_token,
+            } => {
+                trace!("Refreshing token from {}", token_url);
+                let params = [
+                    ("grant_type", "refresh_token"),
+                    ("client_id", client_id),
+                    ("refresh_token", refresh_token),
+                ];
+                request_token(&self.client, token_url, &params)?.map_err(|e| {
+                    e.into_error(token_url)
+                        .context("Failed to refresh token, run |sccache --dist-auth|")
+                })?
+            }
+        };
+        if let (
+            Source::Refresh {
+                token_url,
+                refresh_token,
+                ..
+            },
+            Some(new_refresh_token),
+        ) = (&mut state.source, res.refresh_token)
+        {
+            *refresh_token = new_refresh_token.clone();
+            let saved = config::CachedConfig::reload().and_then(|c| {
+                c.with_mut(|c| {
+                    c.dist
+                        .refresh_tokens
+                        .insert(token_url.clone(), new_refresh_token);
+                })
+            });
+            if let Err(e) = saved {
+                warn!("Failed to save refresh token: {:?}", e)
+            }
+        }
+        let expires_at = res
+            .expires_in
+            .map(|secs| requested_at + Duration::from_secs(secs));
+        state.current = Some((res.access_token.clone(), expires_at));
+        Ok(res.access_token)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Serve a token for each request, expiring in `expires_in[n]` seconds
+    fn serve_tokens(expires_in: &'static [u64]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/token", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for (n, secs) in expires_in.iter().enumerate() {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim().to_lowercase();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(len) = line.strip_prefix("content-length:") {
+                        content_length = len.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                let body = String::from_utf8(body).unwrap();
+                assert!(body.contains("grant_type=client_credentials"));
+                assert!(body.contains("client_secret=secret"));
+                let response = format!(
+                    r#"{{"access_token":"token{}","token_type":"Bearer","expires_in":{}}}"#,
+                    n, secs
+                );
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    response.len(),
+                    response
+                )
+                .unwrap();
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn test_client_credentials_renewed_before_expiry() {
+        let token_url = serve_tokens(&[30, 3600]);
+        let token = AuthToken::client_credentials("sccache", "secret", &token_url, None, None);
+        assert_eq!(token.token().unwrap(), "token0");
+        // The first token expires within the margin, so is replaced straight away
+        assert_eq!(token.token().unwrap(), "token1");
+        assert_eq!(token.token().unwrap(), "token1");
+    }
+}
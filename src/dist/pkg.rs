@@ -35,7 +35,11 @@ pub trait OutputsRepackager {
         -> Result<dist::PathTransformer>;
 }
 
-#[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+#[cfg(not(any(
+    all(target_os = "linux", target_arch = "x86_64"),
+    target_os = "macos",
+    windows
+)))]
 mod toolchain_imp {
     use super::ToolchainPackager;
     use std::fs;
@@ -51,12 +55,85 @@ mod toolchain_imp {
     }
 }
 
-#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+#[cfg(windows)]
+mod toolchain_imp {
+    use crate::dist;
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use walkdir::WalkDir;
+
+    use crate::errors::*;
+
+    /// Windows executables only depend on system DLLs and the files next to
+    /// them, so a toolchain is a set of files placed where the dist compile
+    /// command expects them.
+    pub struct ToolchainPackageBuilder {
+        // Put files in a deterministic order (map from tar_path -> real_path)
+        file_set: BTreeMap<String, PathBuf>,
+        path_transformer: dist::PathTransformer,
+    }
+
+    impl ToolchainPackageBuilder {
+        pub fn new() -> Self {
+            ToolchainPackageBuilder {
+                file_set: BTreeMap::new(),
+                path_transformer: dist::PathTransformer::new(),
+            }
+        }
+
+        pub fn add_file(&mut self, file_path: PathBuf) -> Result<()> {
+            let file_path = super::simplify_path(&file_path)?;
+            if !file_path.is_file() {
+                bail!(format!(
+                    "{} was not a file when readying for tar",
+                    file_path.to_string_lossy()
+                ))
+            }
+            let dist_path = self
+                .path_transformer
+                .as_dist_abs(&file_path)
+                .with_context(|| format!("Cannot package {}", file_path.display()))?;
+            let tar_path = dist_path.trim_start_matches('/').to_owned();
+            self.file_set.insert(tar_path, file_path);
+            Ok(())
+        }
+
+        pub fn add_dir_contents(&mut self, dir_path: &Path) -> Result<()> {
+            for entry in WalkDir::new(&dir_path) {
+                let entry = entry?;
+                if entry.file_type().is_file() {
+                    trace!("walkdir add_file {}", entry.path().display());
+                    self.add_file(entry.path().to_owned())?
+                }
+            }
+            Ok(())
+        }
+
+        pub fn into_compressed_tar<W: Write>(self, writer: W) -> Result<()> {
+            use flate2::write::GzEncoder;
+            let ToolchainPackageBuilder { file_set, .. } = self;
+
+            let mut builder =
+                tar::Builder::new(GzEncoder::new(writer, flate2::Compression::default()));
+            for (tar_path, file_path) in file_set.into_iter() {
+                let file = &mut fs::File::open(file_path)?;
+                builder.append_file(tar_path, file)?
+            }
+            builder.finish().map_err(Into::into)
+        }
+    }
+}
+
+#[cfg(any(all(target_os = "linux", target_arch = "x86_64"), target_os = "macos"))]
 mod toolchain_imp {
     use super::tarify_path;
     use std::collections::BTreeMap;
     use std::fs;
-    use std::io::{Read, Write};
+    #[cfg(target_os = "linux")]
+    use std::io::Read;
+    use std::io::Write;
     use std::path::{Component, Path, PathBuf};
     use std::process;
     use std::str;
@@ -91,15 +168,35 @@ mod toolchain_imp {
                 if self.file_set.contains_key(&tar_path) {
                     continue;
                 }
-                let ldd_libraries = find_ldd_libraries(&obj_path).with_context(|| {
+                #[cfg(target_os = "linux")]
+                let libraries = find_ldd_libraries(&obj_path).with_context(|| {
                     format!("Failed to analyse {} with ldd", obj_path.display())
                 })?;
-                remaining.extend(ldd_libraries);
+                #[cfg(target_os = "macos")]
+                let libraries = find_otool_libraries(&obj_path).with_context(|| {
+                    format!("Failed to analyse {} with otool", obj_path.display())
+                })?;
+                remaining.extend(libraries);
                 self.file_set.insert(tar_path, obj_path);
             }
             Ok(())
         }
 
+        /// Add `executable` and its libraries, with the executable placed at
+        /// `run_path` rather than where it is, for compilers reached through a
+        /// shim like the ones xcrun puts in /usr/bin.
+        #[cfg(target_os = "macos")]
+        pub fn add_executable_and_deps_as(
+            &mut self,
+            executable: PathBuf,
+            run_path: &Path,
+        ) -> Result<()> {
+            assert!(run_path.is_absolute());
+            self.add_executable_and_deps(executable.clone())?;
+            self.file_set.insert(tarify_path(run_path)?, executable);
+            Ok(())
+        }
+
         pub fn add_dir(&mut self, dir_path: PathBuf) -> Result<()> {
             assert!(dir_path.is_absolute());
             if !dir_path.is_dir() {
@@ -159,6 +256,25 @@ mod toolchain_imp {
             Ok(())
         }
 
+        /// Add the contents of `dir_path` as though they were in `run_dir`.
+        #[cfg(target_os = "macos")]
+        pub fn add_dir_contents_as(&mut self, dir_path: &Path, run_dir: &Path) -> Result<()> {
+            assert!(run_dir.is_absolute());
+            for entry in WalkDir::new(&dir_path).follow_links(false) {
+                let entry = entry?;
+                if !fs::metadata(entry.path())?.is_file() {
+                    continue;
+                }
+                let relative = entry
+                    .path()
+                    .strip_prefix(dir_path)
+                    .expect("walkdir entry outside of its root");
+                let tar_path = tarify_path(&run_dir.join(relative))?;
+                self.file_set.insert(tar_path, entry.path().to_owned());
+            }
+            Ok(())
+        }
+
         pub fn into_compressed_tar<W: Write>(self, writer: W) -> Result<()> {
             use flate2::write::GzEncoder;
             let ToolchainPackageBuilder { dir_set, file_set } = self;
@@ -197,6 +313,7 @@ mod toolchain_imp {
     // - static + non-PIE = ET_EXEC, ldd stderrs something like "\tnot a dynamic executable" or
     //   "ldd: a.out: Not a valid dynamic program" and exits with code 1
     //
+    #[cfg(target_os = "linux")]
     fn find_ldd_libraries(executable: &Path) -> Result<Vec<PathBuf>> {
         let process::Output {
             status,
@@ -243,6 +360,7 @@ mod toolchain_imp {
 
     // If it's a static PIE the output will be a line like "\tstatically linked", so be forgiving
     // in the parsing here and treat parsing oddities as an empty list.
+    #[cfg(target_os = "linux")]
     fn parse_ldd_output(stdout: &str) -> Vec<PathBuf> {
         let mut libs = vec![];
         for line in stdout.lines() {
@@ -294,6 +412,103 @@ mod toolchain_imp {
         libs
     }
 
+    // On macOS the libraries are listed by their install names, which may be
+    // relative to the executable or its search paths:
+    // $ otool -L /Library/Developer/CommandLineTools/usr/bin/clang
+    // /Library/Developer/CommandLineTools/usr/bin/clang:
+    //         @rpath/libLTO.dylib (compatibility version 1.0.0, current version 1205.0.22)
+    //         /usr/lib/libxcselect.dylib (compatibility version 1.0.0, current version 1.0.0)
+    //         /usr/lib/libSystem.B.dylib (compatibility version 1.0.0, current version 1292.60.1)
+    //
+    // System libraries are in the dyld shared cache of every Mac rather than on
+    // disk, so they are left out. Toolchains put their own libraries in the
+    // `lib` directory next to `bin`, so that's where @rpath is looked for.
+    #[cfg(target_os = "macos")]
+    fn find_otool_libraries(executable: &Path) -> Result<Vec<PathBuf>> {
+        let process::Output {
+            status,
+            stdout,
+            stderr,
+        } = process::Command::new("otool")
+            .arg("-L")
+            .arg(executable)
+            .output()?;
+        if !status.success() {
+            bail!("otool failed: {}", String::from_utf8_lossy(&stderr))
+        }
+        let stdout = str::from_utf8(&stdout).context("otool output not utf8")?;
+        let mut libs = vec![];
+        for lib in parse_otool_output(stdout, executable) {
+            if lib.exists() {
+                libs.push(lib)
+            } else {
+                warn!(
+                    "Not packaging {}, a dependency of {} that could not be found",
+                    lib.display(),
+                    executable.display()
+                )
+            }
+        }
+        Ok(libs)
+    }
+
+    #[cfg(any(target_os = "macos", test))]
+    fn parse_otool_output(stdout: &str, executable: &Path) -> Vec<PathBuf> {
+        let exe_dir = executable.parent().unwrap_or_else(|| Path::new("/"));
+        let mut libs = vec![];
+        // The first line names the file being inspected
+        for line in stdout.lines().skip(1) {
+            let name = match line.trim().split(" (").next() {
+                Some(name) if !name.is_empty() => name,
+                _ => continue,
+            };
+            let libpath = if let Some(rest) = name.strip_prefix("@rpath/") {
+                exe_dir.join("../lib").join(rest)
+            } else if let Some(rest) = name
+                .strip_prefix("@executable_path/")
+                .or_else(|| name.strip_prefix("@loader_path/"))
+            {
+                exe_dir.join(rest)
+            } else {
+                PathBuf::from(name)
+            };
+            if !libpath.is_absolute()
+                || libpath.starts_with("/usr/lib")
+                || libpath.starts_with("/System")
+            {
+                continue;
+            }
+            libs.push(libpath)
+        }
+        libs
+    }
+
+    #[test]
+    fn test_otool_parse() {
+        let clang_output = "/Library/Developer/CommandLineTools/usr/bin/clang:
+\t@rpath/libLTO.dylib (compatibility version 1.0.0, current version 1205.0.22)
+\t@executable_path/../lib/libclang.dylib (compatibility version 1.0.0, current version 1.0.0)
+\t/usr/lib/libxcselect.dylib (compatibility version 1.0.0, current version 1.0.0)
+\t/System/Library/Frameworks/CoreFoundation.framework/Versions/A/CoreFoundation (compatibility version 150.0.0, current version 1770.255.0)
+\t/opt/llvm/lib/libz.1.dylib (compatibility version 1.0.0, current version 1.2.11)
+";
+        assert_eq!(
+            parse_otool_output(
+                clang_output,
+                Path::new("/Library/Developer/CommandLineTools/usr/bin/clang")
+            )
+            .iter()
+            .map(|p| p.to_str().unwrap())
+            .collect::<Vec<_>>(),
+            &[
+                "/Library/Developer/CommandLineTools/usr/bin/../lib/libLTO.dylib",
+                "/Library/Developer/CommandLineTools/usr/bin/../lib/libclang.dylib",
+                "/opt/llvm/lib/libz.1.dylib",
+            ]
+        )
+    }
+
+    #[cfg(target_os = "linux")]
     #[test]
     fn test_ldd_parse() {
         let ubuntu_ls_output = "\tlinux-vdso.so.1 =>  (0x00007fffcfffe000)
@@ -320,6 +535,7 @@ mod toolchain_imp {
         )
     }
 
+    #[cfg(target_os = "linux")]
     #[test]
     fn test_ldd_parse_static() {
         let static_outputs = &[
@@ -331,6 +547,7 @@ mod toolchain_imp {
         }
     }
 
+    #[cfg(target_os = "linux")]
     #[test]
     fn test_ldd_parse_v2_30() {
         let archlinux_ls_output = "\tlinux-vdso.so.1 (0x00007ffddc1f6000)
@@ -395,7 +612,7 @@ pub fn make_tar_header(src: &Path, dest: &str) -> io::Result<tar::Header> {
 }
 
 /// Simplify the path and strip the leading slash
-#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+#[cfg(any(all(target_os = "linux", target_arch = "x86_64"), target_os = "macos"))]
 fn tarify_path(path: &Path) -> Result<PathBuf> {
     let final_path = simplify_path(path)?;
     let mut components = final_path.components();
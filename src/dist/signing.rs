@@ -0,0 +1,158 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Ed25519 signatures of toolchain archives, so that build servers can refuse
+//! to run toolchains that weren't packaged by someone they trust.
+//!
+//! The archive id of a toolchain is a hash of the whole archive, so signing
+//! the id signs the archive. A signature is written as the base64 public key
+//! of the signer and the base64 signature, separated by a colon.
+
+use crate::dist::cache::path_key;
+use crate::dist::Toolchain;
+use ring::rand::SystemRandom;
+use ring::signature::{self, Ed25519KeyPair, KeyPair, UnparsedPublicKey};
+use std::fs;
+use std::path::Path;
+
+use crate::errors::*;
+
+/// The header a toolchain's signature is sent in when it's submitted.
+pub const SIGNATURE_HEADER: &str = "sccache-toolchain-signature";
+
+// Signatures of toolchains can't be passed off as signatures of anything else
+fn message(tc: &Toolchain) -> Vec<u8> {
+    format!("sccache toolchain {}", tc.archive_id).into_bytes()
+}
+
+/// A private key to sign toolchains with.
+pub struct ToolchainSigner {
+    key: Ed25519KeyPair,
+}
+
+impl ToolchainSigner {
+    /// Generate a new key, as a PKCS#8 document.
+    pub fn generate() -> Result<Vec<u8>> {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|_| anyhow!("failed to generate a toolchain signing key"))?;
+        Ok(pkcs8.as_ref().to_owned())
+    }
+
+    /// Load a PKCS#8 Ed25519 key, as written by `generate` or by
+    /// `openssl genpkey -algorithm ed25519 -outform DER`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let pkcs8 = fs::read(path)
+            .with_context(|| format!("failed to read toolchain signing key {:?}", path))?;
+        Self::from_pkcs8(&pkcs8)
+            .with_context(|| format!("failed to parse toolchain signing key {:?}", path))
+    }
+
+    pub fn from_pkcs8(pkcs8: &[u8]) -> Result<Self> {
+        let key = Ed25519KeyPair::from_pkcs8_maybe_unchecked(pkcs8)
+            .map_err(|e| anyhow!("invalid Ed25519 key: {}", e))?;
+        Ok(ToolchainSigner { key })
+    }
+
+    /// The public key, in base64, for servers to trust.
+    pub fn public_key(&self) -> String {
+        base64::encode(self.key.public_key().as_ref())
+    }
+
+    pub fn sign(&self, tc: &Toolchain) -> String {
+        let signature = self.key.sign(&message(tc));
+        format!(
+            "{}:{}",
+            self.public_key(),
+            base64::encode(signature.as_ref())
+        )
+    }
+
+    /// Sign the toolchain archive at `path`, e.g. one packaged centrally for
+    /// clients to use as a custom toolchain.
+    pub fn sign_archive(&self, path: &Path) -> Result<String> {
+        let archive_id = path_key(path)
+            .with_context(|| format!("failed to read toolchain archive {:?}", path))?;
+        Ok(self.sign(&Toolchain { archive_id }))
+    }
+}
+
+/// The public keys of the signers a build server trusts toolchains from.
+#[derive(Clone, Debug, Default)]
+pub struct TrustedSigners {
+    keys: Vec<Vec<u8>>,
+}
+
+impl TrustedSigners {
+    pub fn new(public_keys: &[String]) -> Result<Self> {
+        let keys = public_keys
+            .iter()
+            .map(|key| match base64::decode(key) {
+                Ok(key) if key.len() == 32 => Ok(key),
+                _ => Err(anyhow!("invalid Ed25519 public key {:?}", key)),
+            })
+            .collect::<Result<_>>()?;
+        Ok(TrustedSigners { keys })
+    }
+
+    /// With no signers, every toolchain is trusted.
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Check that `signature` is a signature of `tc` by a trusted signer.
+    pub fn verify(&self, tc: &Toolchain, signature: &str) -> Result<()> {
+        let mut split = signature.trim().splitn(2, ':');
+        let (public_key, signature) = match (split.next(), split.next()) {
+            (Some(public_key), Some(signature)) => (public_key, signature),
+            _ => bail!("malformed toolchain signature"),
+        };
+        let public_key = base64::decode(public_key).context("malformed signer public key")?;
+        let signature = base64::decode(signature).context("malformed toolchain signature")?;
+        if !self.keys.contains(&public_key) {
+            bail!(
+                "toolchain signed by untrusted signer {}",
+                base64::encode(&public_key)
+            )
+        }
+        UnparsedPublicKey::new(&signature::ED25519, &public_key)
+            .verify(&message(tc), &signature)
+            .map_err(|_| anyhow!("invalid signature of toolchain {}", tc.archive_id))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let signer = ToolchainSigner::from_pkcs8(&ToolchainSigner::generate().unwrap()).unwrap();
+        let other = ToolchainSigner::from_pkcs8(&ToolchainSigner::generate().unwrap()).unwrap();
+        let tc = Toolchain {
+            archive_id: "abc".to_owned(),
+        };
+        let signature = signer.sign(&tc);
+
+        let trusted = TrustedSigners::new(&[signer.public_key()]).unwrap();
+        assert!(trusted.verify(&tc, &signature).is_ok());
+        assert!(trusted.verify(&tc, &other.sign(&tc)).is_err());
+        let tc2 = Toolchain {
+            archive_id: "abd".to_owned(),
+        };
+        assert!(trusted.verify(&tc2, &signature).is_err());
+        assert!(trusted.verify(&tc, "garbage").is_err());
+
+        assert!(TrustedSigners::new(&["bm90IGEga2V5".to_owned()]).is_err());
+    }
+}
@@ -1 +1,25 @@
+#[cfg(feature = "dist-client")]
+#[test]
+fn test_output_data_roundtrip() {
+    use super::{Compression, OutputData};
+    use std::io::Read;
 
+    let data = "int main() { return 0; }\n".repeat(100);
+    for &compression in &[
+        Compression::Zlib,
+        Compression::Zstd(0),
+        Compression::Zstd(19),
+    ] {
+        let output = OutputData::try_from_reader(data.as_bytes(), compression).unwrap();
+        let lens = output.lens();
+        assert_eq!(lens.actual, data.len() as u64);
+        assert!(lens.compressed < lens.actual);
+        let mut roundtrip = String::new();
+        output
+            .into_reader()
+            .unwrap()
+            .read_to_string(&mut roundtrip)
+            .unwrap();
+        assert_eq!(roundtrip, data);
+    }
+}
@@ -0,0 +1,484 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Mutual TLS between dist clients, the scheduler and build servers.
+//!
+//! Every machine presents the certificate in its PKCS#12 identity and only
+//! talks to peers with a certificate signed by one of the configured CAs. The
+//! files are checked for changes whenever they are about to be used, so that
+//! certificates can be rotated without restarting anything.
+
+use crate::config::DistTlsConfig;
+use crate::util::split_pem_certificates;
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::errors::*;
+
+#[cfg(feature = "dist-server")]
+pub use self::frontend::{PeerAddrs, TlsFrontend};
+
+/// The contents of the files named in a `DistTlsConfig`.
+#[derive(Clone)]
+pub struct TlsCerts {
+    config: DistTlsConfig,
+    mtimes: (Option<SystemTime>, Option<SystemTime>),
+    /// The CA certificates, PEM encoded, one per entry.
+    ca_pems: Vec<Vec<u8>>,
+    /// The PKCS#12 archive of the certificate and private key.
+    identity: Vec<u8>,
+}
+
+impl TlsCerts {
+    pub fn load(config: &DistTlsConfig) -> Result<Self> {
+        let mtimes = file_mtimes(config);
+        let ca = fs::read(&config.ca_cert)
+            .with_context(|| format!("failed to read CA certificates {:?}", config.ca_cert))?;
+        let ca_pems = split_pem_certificates(&ca);
+        if ca_pems.is_empty() {
+            bail!("no certificates found in {:?}", config.ca_cert)
+        }
+        let identity = fs::read(&config.identity)
+            .with_context(|| format!("failed to read TLS identity {:?}", config.identity))?;
+        let certs = TlsCerts {
+            config: config.clone(),
+            mtimes,
+            ca_pems,
+            identity,
+        };
+        // Complain about a bad password now rather than on the first connection
+        certs.reqwest_identity()?;
+        Ok(certs)
+    }
+
+    /// Whether the files have been modified since they were loaded.
+    pub fn changed(&self) -> bool {
+        file_mtimes(&self.config) != self.mtimes
+    }
+
+    /// Load the files again, for after they have changed.
+    pub fn reload(&self) -> Result<Self> {
+        info!("Reloading TLS certificates from {:?}", self.config.identity);
+        Self::load(&self.config)
+    }
+
+    fn reqwest_identity(&self) -> Result<reqwest::Identity> {
+        reqwest::Identity::from_pkcs12_der(&self.identity, &self.config.identity_password)
+            .with_context(|| format!("failed to parse TLS identity {:?}", self.config.identity))
+    }
+
+    fn reqwest_ca_certs(&self) -> Result<Vec<reqwest::Certificate>> {
+        self.ca_pems
+            .iter()
+            .map(|pem| {
+                reqwest::Certificate::from_pem(pem)
+                    .context("failed to interpret CA pem as certificate")
+            })
+            .collect()
+    }
+}
+
+fn file_mtimes(config: &DistTlsConfig) -> (Option<SystemTime>, Option<SystemTime>) {
+    let mtime = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    (mtime(&config.ca_cert), mtime(&config.identity))
+}
+
+/// A HTTP client builder that presents the identity and trusts the CAs of
+/// `tls`, if there is one.
+pub fn client_builder(tls: Option<&TlsCerts>) -> Result<reqwest::blocking::ClientBuilder> {
+    let mut builder = reqwest::blocking::ClientBuilder::new();
+    if let Some(tls) = tls {
+        builder = builder.identity(tls.reqwest_identity()?);
+        for cert in tls.reqwest_ca_certs()? {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    Ok(builder)
+}
+
+/// As `client_builder`, for the async HTTP client.
+#[cfg(feature = "dist-client")]
+pub fn async_client_builder(tls: Option<&TlsCerts>) -> Result<reqwest::ClientBuilder> {
+    let mut builder = reqwest::ClientBuilder::new();
+    if let Some(tls) = tls {
+        builder = builder.identity(tls.reqwest_identity()?);
+        for cert in tls.reqwest_ca_certs()? {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    Ok(builder)
+}
+
+#[cfg(feature = "dist-server")]
+mod frontend {
+    use super::TlsCerts;
+    use nix::poll::{poll, PollFd, PollFlags};
+    use openssl::pkcs12::{ParsedPkcs12, Pkcs12};
+    use openssl::ssl::{SslAcceptor, SslMethod, SslStream, SslVerifyMode};
+    use openssl::x509::X509;
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+    use std::os::unix::io::AsRawFd;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use crate::errors::*;
+
+    impl TlsCerts {
+        fn parsed_identity(&self) -> Result<ParsedPkcs12> {
+            Pkcs12::from_der(&self.identity)
+                .and_then(|p| p.parse(&self.config.identity_password))
+                .with_context(|| format!("failed to parse TLS identity {:?}", self.config.identity))
+        }
+
+        /// An acceptor that presents the identity and requires peers to
+        /// present a certificate signed by one of the CAs.
+        pub fn acceptor(&self) -> Result<SslAcceptor> {
+            let identity = self.parsed_identity()?;
+            let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+            builder.set_private_key(&identity.pkey)?;
+            builder.set_certificate(&identity.cert)?;
+            for cert in identity.chain.into_iter().flatten() {
+                builder.add_extra_chain_cert(cert)?;
+            }
+            builder.check_private_key()?;
+            for pem in &self.ca_pems {
+                let ca = X509::from_pem(pem)?;
+                builder.add_client_ca(&ca)?;
+                builder.cert_store_mut().add_cert(ca)?;
+            }
+            builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+            Ok(builder.build())
+        }
+
+        /// The SHA-256 digest and PEM encoding of the certificate presented,
+        /// for handing out to clients like the certificates build servers
+        /// otherwise generate for themselves.
+        pub fn cert_digest_and_pem(&self) -> Result<(Vec<u8>, Vec<u8>)> {
+            let identity = self.parsed_identity()?;
+            let digest = identity
+                .cert
+                .digest(openssl::hash::MessageDigest::sha256())
+                .context("failed to create digest of x509 certificate")?;
+            let pem = identity
+                .cert
+                .to_pem()
+                .context("failed to create pem from x509")?;
+            Ok((digest.to_vec(), pem))
+        }
+    }
+
+    /// The addresses of the peers the frontend is forwarding connections for,
+    /// keyed by the local address of the connection to the HTTP server.
+    #[derive(Default)]
+    pub struct PeerAddrs(Mutex<HashMap<SocketAddr, SocketAddr>>);
+
+    impl PeerAddrs {
+        /// The address a request to the HTTP server from `remote_addr` really
+        /// comes from.
+        pub fn peer_addr(&self, remote_addr: &SocketAddr) -> SocketAddr {
+            self.0
+                .lock()
+                .unwrap()
+                .get(remote_addr)
+                .cloned()
+                .unwrap_or(*remote_addr)
+        }
+    }
+
+    /// Terminates mutual TLS for a plain HTTP server on localhost, as the HTTP
+    /// server can't verify client certificates itself.
+    pub struct TlsFrontend {
+        listener: TcpListener,
+        tls: Mutex<(TlsCerts, SslAcceptor)>,
+        peer_addrs: Arc<PeerAddrs>,
+    }
+
+    impl TlsFrontend {
+        pub fn bind(public_addr: SocketAddr, tls: TlsCerts) -> Result<Self> {
+            let acceptor = tls.acceptor()?;
+            let listener = TcpListener::bind(public_addr)
+                .with_context(|| format!("failed to listen on {}", public_addr))?;
+            Ok(TlsFrontend {
+                listener,
+                tls: Mutex::new((tls, acceptor)),
+                peer_addrs: Default::default(),
+            })
+        }
+
+        pub fn local_addr(&self) -> Result<SocketAddr> {
+            self.listener.local_addr().map_err(Into::into)
+        }
+
+        pub fn peer_addrs(&self) -> Arc<PeerAddrs> {
+            self.peer_addrs.clone()
+        }
+
+        /// Forward the connections to the HTTP server at `http_addr` from a
+        /// background thread.
+        pub fn start(self, http_addr: SocketAddr) {
+            let frontend = Arc::new(self);
+            thread::spawn(move || {
+                for stream in frontend.listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            warn!("Failed to accept TLS connection: {}", e);
+                            continue;
+                        }
+                    };
+                    let frontend = frontend.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = frontend.handle(stream, http_addr) {
+                            debug!("TLS connection failed: {:?}", e)
+                        }
+                    });
+                }
+            });
+        }
+
+        // Pick up rotated certificates, keeping the old ones if the new ones
+        // aren't usable yet (e.g. only one of the files has been replaced).
+        fn acceptor(&self) -> SslAcceptor {
+            let mut tls = self.tls.lock().unwrap();
+            if tls.0.changed() {
+                match tls.0.reload().and_then(|new| {
+                    let acceptor = new.acceptor()?;
+                    Ok((new, acceptor))
+                }) {
+                    Ok(new) => *tls = new,
+                    Err(e) => warn!("Failed to reload TLS certificates: {:?}", e),
+                }
+            }
+            tls.1.clone()
+        }
+
+        fn handle(&self, stream: TcpStream, http_addr: SocketAddr) -> Result<()> {
+            let peer_addr = stream.peer_addr()?;
+            let mut tls_stream = self
+                .acceptor()
+                .accept(stream)
+                .map_err(|e| anyhow!("TLS handshake with {} failed: {}", peer_addr, e))?;
+            let http = TcpStream::connect(http_addr).context("failed to connect to HTTP server")?;
+            let local_addr = http.local_addr()?;
+            self.peer_addrs
+                .0
+                .lock()
+                .unwrap()
+                .insert(local_addr, peer_addr);
+            let res = forward(&mut tls_stream, http);
+            self.peer_addrs.0.lock().unwrap().remove(&local_addr);
+            res
+        }
+    }
+
+    // Copy data both ways until the HTTP server closes the connection.
+    fn forward(tls: &mut SslStream<TcpStream>, mut http: TcpStream) -> Result<()> {
+        let mut buf = [0; 16 * 1024];
+        let mut tls_open = true;
+        loop {
+            // Decrypted data may already be buffered, in which case the socket
+            // won't be readable
+            let read_tls = if tls_open && tls.ssl().pending() > 0 {
+                true
+            } else {
+                let mut fds = vec![PollFd::new(http.as_raw_fd(), PollFlags::POLLIN)];
+                if tls_open {
+                    fds.push(PollFd::new(tls.get_ref().as_raw_fd(), PollFlags::POLLIN));
+                }
+                poll(&mut fds, -1).context("failed to poll connections")?;
+                let ready = |fd: &PollFd| fd.revents().map_or(false, |r| !r.is_empty());
+                if ready(&fds[0]) {
+                    let n = http.read(&mut buf)?;
+                    if n == 0 {
+                        let _ = tls.shutdown();
+                        return Ok(());
+                    }
+                    tls.write_all(&buf[..n])?;
+                }
+                fds.get(1).map_or(false, ready)
+            };
+            if read_tls {
+                let n = tls.read(&mut buf)?;
+                if n == 0 {
+                    tls_open = false;
+                    http.shutdown(Shutdown::Write)?;
+                } else {
+                    http.write_all(&buf[..n])?;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "dist-server")]
+    mod frontend {
+        use super::super::*;
+        use openssl::asn1::Asn1Time;
+        use openssl::bn::BigNum;
+        use openssl::hash::MessageDigest;
+        use openssl::pkcs12::Pkcs12;
+        use openssl::pkey::{PKey, Private};
+        use openssl::rsa::Rsa;
+        use openssl::ssl::{SslConnector, SslMethod, SslStream};
+        use openssl::x509::extension::BasicConstraints;
+        use openssl::x509::{X509NameBuilder, X509};
+        use std::io::{Read, Write};
+        use std::net::{SocketAddr, TcpListener, TcpStream};
+        use std::sync::mpsc;
+        use std::thread;
+
+        fn make_cert(
+            common_name: &str,
+            serial: u32,
+            issuer: Option<&(X509, PKey<Private>)>,
+        ) -> (X509, PKey<Private>) {
+            let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+            let mut name = X509NameBuilder::new().unwrap();
+            name.append_entry_by_text("CN", common_name).unwrap();
+            let name = name.build();
+            let mut builder = X509::builder().unwrap();
+            builder.set_version(2).unwrap();
+            builder
+                .set_serial_number(&BigNum::from_u32(serial).unwrap().to_asn1_integer().unwrap())
+                .unwrap();
+            builder.set_subject_name(&name).unwrap();
+            builder.set_pubkey(&key).unwrap();
+            builder
+                .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+                .unwrap();
+            builder
+                .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+                .unwrap();
+            match issuer {
+                Some((ca, ca_key)) => {
+                    builder.set_issuer_name(ca.subject_name()).unwrap();
+                    builder.sign(ca_key, MessageDigest::sha256()).unwrap();
+                }
+                None => {
+                    builder.set_issuer_name(&name).unwrap();
+                    let ca = BasicConstraints::new().critical().ca().build().unwrap();
+                    builder.append_extension(ca).unwrap();
+                    builder.sign(&key, MessageDigest::sha256()).unwrap();
+                }
+            }
+            (builder.build(), key)
+        }
+
+        fn write_identity(path: &Path, (cert, key): &(X509, PKey<Private>)) {
+            let identity = Pkcs12::builder().build("", "sccache", key, cert).unwrap();
+            fs::write(path, identity.to_der().unwrap()).unwrap();
+        }
+
+        fn connect(
+            addr: SocketAddr,
+            ca: &X509,
+            identity: Option<&(X509, PKey<Private>)>,
+        ) -> std::result::Result<SslStream<TcpStream>, String> {
+            let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+            connector.cert_store_mut().add_cert(ca.clone()).unwrap();
+            if let Some((cert, key)) = identity {
+                connector.set_certificate(cert).unwrap();
+                connector.set_private_key(key).unwrap();
+            }
+            let mut config = connector.build().configure().unwrap();
+            config.set_verify_hostname(false);
+            let mut stream = config
+                .connect("localhost", TcpStream::connect(addr).unwrap())
+                .map_err(|e| e.to_string())?;
+            // With TLS 1.3 the server only rejects the client certificate
+            // after the client thinks the handshake is done
+            stream.write_all(b"ping").map_err(|e| e.to_string())?;
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).map_err(|e| e.to_string())?;
+            assert_eq!(&buf, b"ping");
+            Ok(stream)
+        }
+
+        #[test]
+        fn test_frontend_requires_client_certificates() {
+            let td = tempfile::Builder::new()
+                .prefix("sccache")
+                .tempdir()
+                .unwrap();
+            let ca = make_cert("ca", 1, None);
+            let other_ca = make_cert("other ca", 2, None);
+            let config = DistTlsConfig {
+                ca_cert: td.path().join("ca.pem"),
+                identity: td.path().join("server.p12"),
+                identity_password: String::new(),
+            };
+            fs::write(&config.ca_cert, ca.0.to_pem().unwrap()).unwrap();
+            write_identity(&config.identity, &make_cert("server", 3, Some(&ca)));
+
+            let frontend = TlsFrontend::bind(
+                SocketAddr::from(([127, 0, 0, 1], 0)),
+                TlsCerts::load(&config).unwrap(),
+            )
+            .unwrap();
+            let addr = frontend.local_addr().unwrap();
+            let peer_addrs = frontend.peer_addrs();
+
+            // An HTTP server stand-in that echoes and reports who it's talking
+            // to, once the frontend has started forwarding
+            let http = TcpListener::bind("127.0.0.1:0").unwrap();
+            let http_addr = http.local_addr().unwrap();
+            let (tx, rx) = mpsc::channel();
+            thread::spawn(move || {
+                for stream in http.incoming() {
+                    let mut stream = stream.unwrap();
+                    let tx = tx.clone();
+                    let peer_addrs = peer_addrs.clone();
+                    thread::spawn(move || {
+                        let mut buf = [0; 4];
+                        if stream.read_exact(&mut buf).is_ok() {
+                            let peer_addr = stream.peer_addr().unwrap();
+                            tx.send(peer_addrs.peer_addr(&peer_addr)).unwrap();
+                            let _ = stream.write_all(&buf);
+                        }
+                    });
+                }
+            });
+            frontend.start(http_addr);
+
+            let client = make_cert("client", 4, Some(&ca));
+            let stream = connect(addr, &ca.0, Some(&client)).unwrap();
+            assert_eq!(rx.recv().unwrap(), stream.get_ref().local_addr().unwrap());
+            let peer_cert = stream.ssl().peer_certificate().unwrap();
+            assert_eq!(
+                peer_cert.serial_number().to_bn().unwrap(),
+                BigNum::from_u32(3).unwrap()
+            );
+
+            assert!(connect(addr, &ca.0, None).is_err());
+            let untrusted = make_cert("client", 5, Some(&other_ca));
+            assert!(connect(addr, &ca.0, Some(&untrusted)).is_err());
+
+            // Rotating the certificate takes effect for new connections
+            write_identity(&config.identity, &make_cert("server", 6, Some(&ca)));
+            let mtime = filetime::FileTime::from_unix_time(0, 0);
+            filetime::set_file_mtime(&config.identity, mtime).unwrap();
+            let stream = connect(addr, &ca.0, Some(&client)).unwrap();
+            let peer_cert = stream.ssl().peer_certificate().unwrap();
+            assert_eq!(
+                peer_cert.serial_number().to_bn().unwrap(),
+                BigNum::from_u32(6).unwrap()
+            );
+        }
+    }
+}
@@ -0,0 +1,600 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Resolving the host names of the Redis and memcached servers sccache
+//! connects to: caching the addresses for as long as their records say, up to
+//! `SCCACHE_DNS_TTL` seconds, asking the name servers in `SCCACHE_DNS_SERVERS`
+//! rather than the system's, and mapping the names in `SCCACHE_DNS_HOSTS` to
+//! fixed addresses.
+//!
+//! The HTTP client builds its own connector, which can't be given a resolver,
+//! so HTTP storage and the dist scheduler and servers are resolved by the
+//! system.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::errors::*;
+
+/// How long addresses are cached at most if only name servers or host
+/// mappings are set.
+const DEFAULT_TTL: Duration = Duration::from_secs(60);
+
+/// How long a name server has to answer.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+
+/// The flag of an answer cut short to fit in a UDP datagram.
+const FLAG_TRUNCATED: u8 = 0x02;
+
+/// How host names are resolved.
+#[derive(Debug, Default, PartialEq)]
+pub struct DnsSettings {
+    /// How long resolved addresses are used for at most.
+    pub ttl: Duration,
+    /// The name servers asked, in turn, or none to use the system resolver.
+    pub servers: Vec<SocketAddr>,
+    /// Host names with fixed addresses.
+    pub hosts: HashMap<String, Vec<IpAddr>>,
+    /// The search domains of `/etc/resolv.conf`, tried with names asked of
+    /// `servers`.
+    pub search: Search,
+}
+
+impl DnsSettings {
+    /// The settings from the environment, or `None` if none are set and the
+    /// system resolver is used as it is.
+    pub fn from_env() -> Result<Option<DnsSettings>> {
+        let var = |name| {
+            env::var(name)
+                .ok()
+                .filter(|v: &String| !v.trim().is_empty())
+        };
+        let (ttl, servers, hosts) = (
+            var("SCCACHE_DNS_TTL"),
+            var("SCCACHE_DNS_SERVERS"),
+            var("SCCACHE_DNS_HOSTS"),
+        );
+        if ttl.is_none() && servers.is_none() && hosts.is_none() {
+            return Ok(None);
+        }
+        let ttl = match ttl {
+            Some(ttl) => Duration::from_secs(
+                ttl.trim()
+                    .parse()
+                    .with_context(|| format!("Invalid SCCACHE_DNS_TTL {:?}", ttl))?,
+            ),
+            None => DEFAULT_TTL,
+        };
+        let servers = parse_servers(servers.as_deref().unwrap_or(""))
+            .context("Invalid SCCACHE_DNS_SERVERS")?;
+        // The system resolver does its own searching
+        let search = match fs::read_to_string("/etc/resolv.conf") {
+            Ok(conf) if !servers.is_empty() => Search::parse(&conf),
+            _ => Search::default(),
+        };
+        Ok(Some(DnsSettings {
+            ttl,
+            servers,
+            hosts: parse_hosts(hosts.as_deref().unwrap_or(""))
+                .context("Invalid SCCACHE_DNS_HOSTS")?,
+            search,
+        }))
+    }
+}
+
+/// Parse name servers separated by commas, each an address with an optional
+/// port, 53 by default.
+fn parse_servers(servers: &str) -> Result<Vec<SocketAddr>> {
+    servers
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|server| match server.parse::<IpAddr>() {
+            Ok(ip) => Ok(SocketAddr::new(ip, 53)),
+            Err(_) => server
+                .parse()
+                .with_context(|| format!("bad name server {:?}", server)),
+        })
+        .collect()
+}
+
+/// Parse `name=address` mappings separated by commas. A name may be mapped
+/// several times, to several addresses.
+fn parse_hosts(hosts: &str) -> Result<HashMap<String, Vec<IpAddr>>> {
+    let mut map: HashMap<String, Vec<IpAddr>> = HashMap::new();
+    for mapping in hosts.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let mut parts = mapping.splitn(2, '=');
+        let (name, addr) = match (parts.next(), parts.next()) {
+            (Some(name), Some(addr)) if !name.trim().is_empty() => (name.trim(), addr.trim()),
+            _ => bail!("bad host mapping {:?}, expected name=address", mapping),
+        };
+        let addr = addr
+            .parse()
+            .with_context(|| format!("bad address in host mapping {:?}", mapping))?;
+        map.entry(name.to_ascii_lowercase()).or_default().push(addr);
+    }
+    Ok(map)
+}
+
+/// The domains names are looked up in, as `resolv.conf(5)` describes them.
+#[derive(Debug, PartialEq)]
+pub struct Search {
+    domains: Vec<String>,
+    /// Names with fewer dots than this are looked up in the domains before
+    /// they're looked up as they are.
+    ndots: usize,
+}
+
+impl Default for Search {
+    fn default() -> Search {
+        Search {
+            domains: vec![],
+            ndots: 1,
+        }
+    }
+}
+
+impl Search {
+    /// The search domains of the `resolv.conf` in `conf`. The last `search`
+    /// or `domain` line counts.
+    fn parse(conf: &str) -> Search {
+        let mut search = Search::default();
+        for line in conf.lines() {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("search") | Some("domain") => {
+                    search.domains = words
+                        .map(|d| d.trim_end_matches('.').to_ascii_lowercase())
+                        .filter(|d| !d.is_empty())
+                        .collect();
+                }
+                Some("options") => {
+                    for option in words {
+                        if let Some(ndots) = option.strip_prefix("ndots:") {
+                            search.ndots = ndots.parse().unwrap_or(search.ndots);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        search
+    }
+
+    /// The names to look `name` up as, in turn. Names ending with a dot
+    /// aren't looked up in the domains.
+    fn names(&self, name: &str) -> Vec<String> {
+        if name.ends_with('.') {
+            return vec![name.trim_end_matches('.').to_owned()];
+        }
+        let in_domains = self.domains.iter().map(|d| format!("{}.{}", name, d));
+        if name.matches('.').count() < self.ndots {
+            in_domains.chain(Some(name.to_owned())).collect()
+        } else {
+            Some(name.to_owned())
+                .into_iter()
+                .chain(in_domains)
+                .collect()
+        }
+    }
+}
+
+/// Resolves host names as its settings say, caching the addresses.
+pub struct Resolver {
+    settings: DnsSettings,
+    /// The addresses of names, and when they expire.
+    cache: Mutex<HashMap<String, (Vec<IpAddr>, Instant)>>,
+}
+
+impl Resolver {
+    pub fn new(settings: DnsSettings) -> Resolver {
+        Resolver {
+            settings,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The addresses of `host`.
+    pub fn resolve(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        // Addresses in URLs may be in brackets
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = host.parse() {
+            return Ok(vec![ip]);
+        }
+        let name = host.to_ascii_lowercase();
+        if let Some(addrs) = self.settings.hosts.get(name.trim_end_matches('.')) {
+            return Ok(addrs.clone());
+        }
+        let cached = self.cache.lock().unwrap().get(&name).cloned();
+        if let Some((addrs, expires)) = cached.as_ref() {
+            if Instant::now() < *expires {
+                return Ok(addrs.clone());
+            }
+        }
+        match self.lookup(&name) {
+            Ok((addrs, ttl)) => {
+                trace!("Resolved {} to {:?} for {:?}", name, addrs, ttl);
+                let ttl = ttl.map_or(self.settings.ttl, |ttl| ttl.min(self.settings.ttl));
+                self.cache
+                    .lock()
+                    .unwrap()
+                    .insert(name, (addrs.clone(), Instant::now() + ttl));
+                Ok(addrs)
+            }
+            // Better addresses that may be out of date than none
+            Err(e) => match cached {
+                Some((addrs, _)) => {
+                    debug!("Failed to resolve {}, using it as cached: {}", name, e);
+                    Ok(addrs)
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    /// Look `name` up with the name servers, in its search domains, or with
+    /// the system resolver, with how long the addresses may be kept if the
+    /// name servers say.
+    fn lookup(&self, name: &str) -> io::Result<(Vec<IpAddr>, Option<Duration>)> {
+        if self.settings.servers.is_empty() {
+            let name = name.trim_end_matches('.');
+            let addrs: Vec<_> = (name, 0).to_socket_addrs()?.map(|a| a.ip()).collect();
+            return Ok((addrs, None));
+        }
+        for candidate in self.settings.search.names(name) {
+            let answer = self.lookup_name(&candidate)?;
+            if !answer.addrs.is_empty() {
+                return Ok((answer.addrs, Some(answer.ttl)));
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} has no addresses", name),
+        ))
+    }
+
+    /// Ask the name servers, in turn until one answers, for `name`.
+    fn lookup_name(&self, name: &str) -> io::Result<Answer> {
+        let mut error = None;
+        for server in &self.settings.servers {
+            match query_addrs(*server, name) {
+                Ok(answer) => return Ok(answer),
+                Err(e) => {
+                    debug!("Name server {} failed to resolve {}: {}", server, name, e);
+                    error = Some(e);
+                }
+            }
+        }
+        Err(error.unwrap())
+    }
+}
+
+lazy_static! {
+    static ref RESOLVER: Option<Resolver> = match DnsSettings::from_env() {
+        Ok(settings) => settings.map(Resolver::new),
+        Err(e) => {
+            warn!("Ignoring the DNS settings: {:#}", e);
+            None
+        }
+    };
+}
+
+/// `host` replaced with its first address, if the environment configures
+/// how host names are resolved.
+pub fn resolve_host(host: &str) -> Result<Option<IpAddr>> {
+    let resolver = match RESOLVER.as_ref() {
+        Some(resolver) => resolver,
+        None => return Ok(None),
+    };
+    let addrs = resolver
+        .resolve(host)
+        .with_context(|| format!("failed to resolve {}", host))?;
+    Ok(addrs.into_iter().next())
+}
+
+/// A query for the records of `qtype` of `name`.
+fn encode_query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>> {
+    let mut query = vec![];
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        if label.len() > 63 {
+            bail!("the name {} has a label that's too long", name);
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    // Class IN
+    query.extend_from_slice(&[0, 1]);
+    Ok(query)
+}
+
+/// The offset after the name at `pos` in `msg`.
+fn skip_name(msg: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(pos)? as usize;
+        match len {
+            0 => return Some(pos + 1),
+            // A pointer to the rest of the name ends it
+            l if l & 0xc0 == 0xc0 => return Some(pos + 2),
+            l => pos += l + 1,
+        }
+    }
+}
+
+/// The addresses a name server answered with.
+#[derive(Debug, PartialEq)]
+struct Answer {
+    addrs: Vec<IpAddr>,
+    /// The shortest time to live of the address records.
+    ttl: Duration,
+}
+
+/// The addresses in the answer to query `id`.
+fn parse_response(id: u16, msg: &[u8]) -> Result<Answer> {
+    let u16_at = |pos: usize| -> Option<u16> {
+        Some(u16::from_be_bytes([*msg.get(pos)?, *msg.get(pos + 1)?]))
+    };
+    let u32_at = |pos: usize| -> Option<u32> {
+        Some((u32::from(u16_at(pos)?) << 16) | u32::from(u16_at(pos + 2)?))
+    };
+    let parse = || -> Option<Result<Answer>> {
+        let mut answer = Answer {
+            addrs: vec![],
+            ttl: Duration::from_secs(u64::from(u32::MAX)),
+        };
+        if u16_at(0)? != id || msg.get(2)? & 0x80 == 0 {
+            return Some(Err(anyhow!("not an answer to the query")));
+        }
+        match msg.get(3)? & 0x0f {
+            0 => {}
+            3 => return Some(Ok(answer)),
+            rcode => return Some(Err(anyhow!("the name server failed with code {}", rcode))),
+        }
+        let (questions, answers) = (u16_at(4)?, u16_at(6)?);
+        let mut pos = 12;
+        for _ in 0..questions {
+            pos = skip_name(msg, pos)? + 4;
+        }
+        for _ in 0..answers {
+            pos = skip_name(msg, pos)?;
+            let (rtype, ttl, len) = (u16_at(pos)?, u32_at(pos + 4)?, u16_at(pos + 8)? as usize);
+            let data = msg.get(pos + 10..pos + 10 + len)?;
+            let addr = match (rtype, len) {
+                (TYPE_A, 4) => IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3])),
+                (TYPE_AAAA, 16) => {
+                    let mut octets = [0; 16];
+                    octets.copy_from_slice(data);
+                    IpAddr::V6(Ipv6Addr::from(octets))
+                }
+                // Such as the CNAME records leading to the addresses
+                _ => {
+                    pos += 10 + len;
+                    continue;
+                }
+            };
+            answer.addrs.push(addr);
+            answer.ttl = answer.ttl.min(Duration::from_secs(u64::from(ttl)));
+            pos += 10 + len;
+        }
+        Some(Ok(answer))
+    };
+    parse().unwrap_or_else(|| bail!("the answer was cut short"))
+}
+
+/// Ask `server` for the IPv4 addresses of `name`, or if it has none its
+/// IPv6 ones.
+fn query_addrs(server: SocketAddr, name: &str) -> io::Result<Answer> {
+    let answer = query(server, name, TYPE_A)?;
+    if !answer.addrs.is_empty() {
+        return Ok(answer);
+    }
+    query(server, name, TYPE_AAAA)
+}
+
+/// Ask `server` for the records of `qtype` of `name`, over UDP, or over TCP
+/// if the answer doesn't fit in a datagram.
+fn query(server: SocketAddr, name: &str, qtype: u16) -> io::Result<Answer> {
+    let bind: SocketAddr = if server.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let socket = UdpSocket::bind(bind)?;
+    socket.set_read_timeout(Some(QUERY_TIMEOUT))?;
+    socket.connect(server)?;
+    let id = rand::random();
+    let query = encode_query(id, name, qtype)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    socket.send(&query)?;
+    let mut buf = [0; 4096];
+    let len = loop {
+        let len = socket.recv(&mut buf)?;
+        // Answers to earlier queries may still come in
+        if len >= 3 && buf[..2] == id.to_be_bytes() {
+            break len;
+        }
+    };
+    let response = if buf[2] & FLAG_TRUNCATED != 0 {
+        trace!("The answer for {} was truncated, asking over TCP", name);
+        query_tcp(server, &query)?
+    } else {
+        buf[..len].to_vec()
+    };
+    parse_response(id, &response)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+/// Send `query` to `server` over TCP, returning the answer.
+fn query_tcp(server: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect_timeout(&server, QUERY_TIMEOUT)?;
+    stream.set_read_timeout(Some(QUERY_TIMEOUT))?;
+    stream.set_write_timeout(Some(QUERY_TIMEOUT))?;
+    // Messages over TCP are preceded by their length
+    let mut msg = (query.len() as u16).to_be_bytes().to_vec();
+    msg.extend_from_slice(query);
+    stream.write_all(&msg)?;
+    let mut len = [0; 2];
+    stream.read_exact(&mut len)?;
+    let mut response = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut response)?;
+    Ok(response)
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_settings() {
+        assert_eq!(
+            parse_servers("10.0.0.53, [::1]:5353,").unwrap(),
+            vec![
+                "10.0.0.53:53".parse().unwrap(),
+                "[::1]:5353".parse().unwrap()
+            ]
+        );
+        assert!(parse_servers("dns.example.com").is_err());
+        let hosts = parse_hosts("Cache.Corp=10.1.2.3, cache.corp=::1,sched=10.1.2.4").unwrap();
+        assert_eq!(
+            hosts["cache.corp"],
+            vec![
+                "10.1.2.3".parse::<IpAddr>().unwrap(),
+                "::1".parse().unwrap()
+            ]
+        );
+        assert_eq!(hosts.len(), 2);
+        assert!(parse_hosts("cache.corp").is_err());
+        assert!(parse_hosts("cache.corp=nowhere").is_err());
+    }
+
+    #[test]
+    fn test_search() {
+        let search = Search::parse(
+            "# comment\nnameserver 10.0.0.53\nsearch corp.example. example\noptions ndots:2 rotate\n",
+        );
+        assert_eq!(search.domains, vec!["corp.example", "example"]);
+        assert_eq!(search.ndots, 2);
+        assert_eq!(
+            search.names("cache.eu"),
+            vec!["cache.eu.corp.example", "cache.eu.example", "cache.eu"]
+        );
+        assert_eq!(
+            search.names("cache.eu.corp.example"),
+            vec![
+                "cache.eu.corp.example",
+                "cache.eu.corp.example.corp.example",
+                "cache.eu.corp.example.example"
+            ]
+        );
+        assert_eq!(search.names("cache.corp."), vec!["cache.corp"]);
+        assert_eq!(Search::default().names("cache"), vec!["cache"]);
+    }
+
+    #[test]
+    fn test_resolve_hosts() {
+        let resolver = Resolver::new(DnsSettings {
+            ttl: DEFAULT_TTL,
+            servers: vec![],
+            hosts: parse_hosts("cache.corp=10.1.2.3").unwrap(),
+            search: Search::default(),
+        });
+        let ip: IpAddr = "10.1.2.3".parse().unwrap();
+        assert_eq!(resolver.resolve("CACHE.corp.").unwrap(), vec![ip]);
+        assert_eq!(
+            resolver.resolve("[::1]").unwrap(),
+            vec!["::1".parse::<IpAddr>().unwrap()]
+        );
+    }
+
+    /// An answer to `query` with the A record `10.1.2.3`, for `ttl` seconds.
+    fn answer_with_a(query: &[u8], ttl: u32) -> Vec<u8> {
+        let mut answer = query.to_vec();
+        answer[2] = 0x81;
+        answer[3] = 0x80;
+        answer[7] = 1;
+        answer.extend_from_slice(b"\xc0\x0c\x00\x01\x00\x01");
+        answer.extend_from_slice(&ttl.to_be_bytes());
+        answer.extend_from_slice(b"\x00\x04\x0a\x01\x02\x03");
+        answer
+    }
+
+    #[test]
+    fn test_truncated_answer() {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = udp.local_addr().unwrap();
+        let tcp = std::net::TcpListener::bind(server).unwrap();
+        std::thread::spawn(move || {
+            // The answer over UDP is cut short, as too long for it
+            let mut buf = [0; 512];
+            let (len, from) = udp.recv_from(&mut buf).unwrap();
+            let mut truncated = buf[..len].to_vec();
+            truncated[2] = 0x83;
+            udp.send_to(&truncated, from).unwrap();
+            let (mut stream, _) = tcp.accept().unwrap();
+            let mut len = [0; 2];
+            stream.read_exact(&mut len).unwrap();
+            let mut query = vec![0; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut query).unwrap();
+            let answer = answer_with_a(&query, 30);
+            stream
+                .write_all(&(answer.len() as u16).to_be_bytes())
+                .unwrap();
+            stream.write_all(&answer).unwrap();
+        });
+        let answer = query(server, "cache.corp", TYPE_A).unwrap();
+        assert_eq!(answer.addrs, vec!["10.1.2.3".parse::<IpAddr>().unwrap()]);
+        assert_eq!(answer.ttl, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_dns_messages() {
+        let query = encode_query(0x1234, "ab.c", TYPE_A).unwrap();
+        assert_eq!(
+            query,
+            b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x02ab\x01c\x00\x00\x01\x00\x01"
+        );
+        // The answer repeats the question, then has a CNAME pointing to the
+        // name in the question, and an A record with a pointer to it
+        let mut answer = query.clone();
+        answer[2] = 0x81;
+        answer[3] = 0x80;
+        answer[7] = 2;
+        answer.extend_from_slice(b"\x03www\x00\x00\x05\x00\x01\x00\x00\x00\x3c\x00\x02\xc0\x0c");
+        answer
+            .extend_from_slice(b"\xc0\x0c\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x04\x0a\x01\x02\x03");
+        assert_eq!(
+            parse_response(0x1234, &answer).unwrap(),
+            Answer {
+                addrs: vec!["10.1.2.3".parse::<IpAddr>().unwrap()],
+                ttl: Duration::from_secs(60),
+            }
+        );
+        assert!(parse_response(0x4321, &answer).is_err());
+        assert!(parse_response(0x1234, &answer[..answer.len() - 2]).is_err());
+        // No such name
+        answer[3] = 0x83;
+        assert!(parse_response(0x1234, &answer).unwrap().addrs.is_empty());
+    }
+}
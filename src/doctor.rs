@@ -0,0 +1,446 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `sccache --doctor`: checking the config, the server, the storage and the
+//! compilers on `PATH` for the common ways sccache is misconfigured, and
+//! suggesting how to fix what's found.
+
+use crate::cache::{check_storage, storage_from_config};
+use crate::client::connect_to_server;
+use crate::commands::{describe_server, request_stats};
+use crate::compiler::{get_compiler_info, CompilerKind};
+use crate::config::{self, CacheType, Config};
+use crate::jobserver::Client;
+use crate::mock_command::{CommandCreator, ProcessCommandCreator};
+use crate::protocol::Hello;
+use crate::util;
+use futures::executor::ThreadPool;
+use std::env;
+use std::path::Path;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+use crate::errors::*;
+
+/// The compilers looked for on `PATH`.
+const COMPILERS: &[&str] = &[
+    "cc", "c++", "gcc", "g++", "clang", "clang++", "nvcc", "rustc",
+];
+
+/// How long to wait for the storage check, which may be of a remote service.
+const STORAGE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Status {
+    Ok,
+    Warning,
+    Failed,
+}
+
+/// What a check found, and how to fix it if it's a problem.
+#[derive(Debug)]
+struct Finding {
+    status: Status,
+    what: String,
+    fix: Option<String>,
+}
+
+impl Finding {
+    fn ok(what: impl Into<String>) -> Finding {
+        Finding {
+            status: Status::Ok,
+            what: what.into(),
+            fix: None,
+        }
+    }
+
+    fn warning(what: impl Into<String>, fix: impl Into<String>) -> Finding {
+        Finding {
+            status: Status::Warning,
+            what: what.into(),
+            fix: Some(fix.into()),
+        }
+    }
+
+    fn failed(what: impl Into<String>, fix: impl Into<String>) -> Finding {
+        Finding {
+            status: Status::Failed,
+            what: what.into(),
+            fix: Some(fix.into()),
+        }
+    }
+}
+
+/// Run the checks, with the server on `port`, and print what they found.
+/// Returns the exit status, which is 1 if any check failed.
+pub fn run(port: u16) -> Result<i32> {
+    let runtime = util::runtime()?;
+    let pool = ThreadPool::builder().pool_size(1).create()?;
+
+    let mut findings = vec![];
+    let config = check_config(&mut findings);
+    check_server(port, &mut findings);
+    if let Some(ref config) = config {
+        check_config_storage(config, &runtime, &pool, &mut findings);
+    }
+    let found_rustc = check_compilers(&runtime, &pool, &mut findings);
+    check_env(
+        |name| env::var(name).ok(),
+        config.as_ref(),
+        found_rustc,
+        &mut findings,
+    );
+
+    for finding in &findings {
+        let status = match finding.status {
+            Status::Ok => "ok",
+            Status::Warning => "warning",
+            Status::Failed => "FAILED",
+        };
+        println!("[{}] {}", status, finding.what);
+        if let Some(ref fix) = finding.fix {
+            println!("    fix: {}", fix);
+        }
+    }
+    let count = |status| findings.iter().filter(|f| f.status == status).count();
+    let (failed, warnings) = (count(Status::Failed), count(Status::Warning));
+    println!();
+    if failed == 0 && warnings == 0 {
+        println!("No problems found");
+    } else {
+        println!("Checks: {} failed, {} with warnings", failed, warnings);
+    }
+    Ok(if failed == 0 { 0 } else { 1 })
+}
+
+fn check_config(findings: &mut Vec<Finding>) -> Option<Config> {
+    let path = config::config_file_path();
+    match Config::load() {
+        Ok(config) => {
+            findings.push(Finding::ok(if path.exists() {
+                format!("The config file {} is valid", path.display())
+            } else {
+                format!(
+                    "There's no config file at {}, so only the environment configures sccache",
+                    path.display()
+                )
+            }));
+            Some(config)
+        }
+        Err(e) => {
+            findings.push(Finding::failed(
+                format!("The config can't be loaded: {}", summary(&e)),
+                format!(
+                    "Fix or remove {}, or set SCCACHE_CONF to another one",
+                    path.display()
+                ),
+            ));
+            None
+        }
+    }
+}
+
+fn check_server(port: u16, findings: &mut Vec<Finding>) {
+    let mut conn = match connect_to_server(port) {
+        Ok(conn) => conn,
+        Err(_) => {
+            findings.push(Finding::ok(format!(
+                "No server is running on port {}, and the first compile will start one",
+                port
+            )));
+            return;
+        }
+    };
+    let hello = match conn.hello() {
+        Ok(hello) => hello,
+        Err(e) => {
+            findings.push(Finding::failed(
+                format!(
+                    "Something on port {} isn't answering like an sccache server: {}",
+                    port,
+                    summary(&e)
+                ),
+                "Set SCCACHE_SERVER_PORT to a free port",
+            ));
+            return;
+        }
+    };
+    if hello.as_ref() != Some(&Hello::current()) {
+        findings.push(Finding::failed(
+            format!(
+                "The server on port {} is {}, but this client is version {}",
+                port,
+                describe_server(hello.as_ref()),
+                env!("CARGO_PKG_VERSION")
+            ),
+            "Run `sccache --stop-server`, and the next compile will start this version",
+        ));
+        return;
+    }
+    match request_stats(conn) {
+        Ok(info) => match info.storage_error {
+            Some(e) => findings.push(Finding::failed(
+                format!(
+                    "The server on port {} can't use {}: {}",
+                    port, info.cache_location, e
+                ),
+                format!(
+                    "{}, then run `sccache --reload-config`",
+                    storage_fix(&info.cache_kind)
+                ),
+            )),
+            None => findings.push(Finding::ok(format!(
+                "The server on port {} is running, caching in {}",
+                port, info.cache_location
+            ))),
+        },
+        Err(e) => findings.push(Finding::failed(
+            format!(
+                "The server on port {} didn't send its stats: {}",
+                port,
+                summary(&e)
+            ),
+            "Run `sccache --stop-server` to restart it",
+        )),
+    }
+}
+
+/// The first line of `e` and its causes, leaving out any further output,
+/// such as that of a compiler that didn't run.
+fn summary(e: &Error) -> String {
+    let text = format!("{:#}", e);
+    text.lines().next().unwrap_or_default().to_owned()
+}
+
+/// The `Storage::kind` of a configured cache.
+fn cache_kind(cache: &CacheType) -> &'static str {
+    match cache {
+        CacheType::Azure(_) => "azure",
+        CacheType::GCS(_) => "gcs",
+        CacheType::Memcached(_) => "memcached",
+        CacheType::Redis(_) => "redis",
+        CacheType::S3(_) => "s3",
+    }
+}
+
+/// How to fix storage of `kind` that doesn't work.
+fn storage_fix(kind: &str) -> &'static str {
+    match kind {
+        "disk" => "Check that the cache directory can be written to, or set SCCACHE_DIR to one that can",
+        "s3" => "Check SCCACHE_BUCKET and the AWS credentials, in AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY or ~/.aws/credentials",
+        "gcs" => "Check SCCACHE_GCS_BUCKET and the credentials in SCCACHE_GCS_KEY_PATH or from SCCACHE_GCS_CREDENTIALS_URL",
+        "azure" => "Check SCCACHE_AZURE_CONNECTION_STRING and SCCACHE_AZURE_BLOB_CONTAINER",
+        "redis" => "Check that SCCACHE_REDIS is the URL of a running Redis server",
+        "memcached" => "Check that SCCACHE_MEMCACHED is the URL of a running memcached server",
+        _ => "Check the storage's configuration",
+    }
+}
+
+fn check_config_storage(
+    config: &Config,
+    runtime: &Runtime,
+    pool: &ThreadPool,
+    findings: &mut Vec<Finding>,
+) {
+    let storage = storage_from_config(config, pool);
+    let (kind, location) = (storage.kind(), storage.location());
+    let configured: Vec<_> = config.caches.iter().map(cache_kind).collect();
+    if !configured.is_empty() && !configured.contains(&kind) {
+        findings.push(Finding::failed(
+            format!(
+                "The configured {} storage couldn't be set up, so {} is used instead",
+                configured.join(" and "),
+                location
+            ),
+            "Run `SCCACHE_LOG=warn sccache --doctor` to see why, and check that sccache was built with the feature of that storage",
+        ));
+    } else {
+        findings.push(Finding::ok(format!(
+            "The {} storage is used: {}",
+            kind, location
+        )));
+    }
+    let read_only = storage.is_read_only();
+    let check = async {
+        match tokio::time::timeout(STORAGE_TIMEOUT, check_storage(storage)).await {
+            Ok(res) => res,
+            Err(_) => bail!("it didn't answer within {}s", STORAGE_TIMEOUT.as_secs()),
+        }
+    };
+    match runtime.block_on(check) {
+        Ok(()) if read_only => findings.push(Finding::ok(format!(
+            "{} can be read from, and is read-only",
+            location
+        ))),
+        Ok(()) => findings.push(Finding::ok(format!(
+            "{} can be written to, read from and deleted from",
+            location
+        ))),
+        Err(e) => findings.push(Finding::failed(
+            format!("The storage doesn't work: {}", summary(&e)),
+            storage_fix(kind),
+        )),
+    }
+}
+
+/// Check which of `COMPILERS` are on `PATH` and supported, returning whether
+/// `rustc` is.
+fn check_compilers(runtime: &Runtime, pool: &ThreadPool, findings: &mut Vec<Finding>) -> bool {
+    let jobserver = unsafe { Client::new() };
+    let creator = ProcessCommandCreator::new(&jobserver);
+    let cwd = env::current_dir().unwrap_or_else(|_| Path::new(".").to_owned());
+    let env: Vec<_> = env::vars_os().collect();
+    let mut found_any = false;
+    let mut found_rustc = false;
+    for &name in COMPILERS {
+        let path = match which::which(name) {
+            Ok(path) => path,
+            Err(_) => continue,
+        };
+        found_any = true;
+        let info = get_compiler_info(creator.clone(), &path, &cwd, &env, pool, None);
+        match runtime.block_on(info) {
+            Ok((compiler, _)) => {
+                let kind = match compiler.kind() {
+                    CompilerKind::C(kind) => format!("a C/C++ compiler ({:?})", kind),
+                    kind => format!("a {} compiler", kind.lang_kind()),
+                };
+                found_rustc |= name == "rustc";
+                findings.push(Finding::ok(format!(
+                    "{} ({}) is {}",
+                    name,
+                    path.display(),
+                    kind
+                )));
+            }
+            Err(e) => findings.push(Finding::warning(
+                format!(
+                    "{} ({}) isn't a supported compiler: {}",
+                    name,
+                    path.display(),
+                    summary(&e)
+                ),
+                "Compiles run with it aren't cached",
+            )),
+        }
+    }
+    if !found_any {
+        findings.push(Finding::warning(
+            "No compilers were found on PATH",
+            "Add the directory of your compilers to PATH",
+        ));
+    }
+    found_rustc
+}
+
+/// Check the environment, read with `var`, for settings that stop compiles
+/// being cached.
+fn check_env<F>(var: F, config: Option<&Config>, found_rustc: bool, findings: &mut Vec<Finding>)
+where
+    F: Fn(&str) -> Option<String>,
+{
+    if var("SCCACHE_RECACHE").is_some() {
+        findings.push(Finding::warning(
+            "SCCACHE_RECACHE is set, so every compile ignores the cache",
+            "Unset SCCACHE_RECACHE",
+        ));
+    }
+    if var("SCCACHE_NO_CACHE").as_deref() == Some("1") {
+        findings.push(Finding::warning(
+            "SCCACHE_NO_CACHE is set, so every compile bypasses sccache",
+            "Unset SCCACHE_NO_CACHE",
+        ));
+    }
+    if let Some(path) = var("PATH") {
+        for dir in env::split_paths(&path).filter(|dir| dir.to_string_lossy().contains("ccache")) {
+            findings.push(Finding::warning(
+                format!(
+                    "{} is on PATH, so the compilers found there may be ccache's wrappers",
+                    dir.display()
+                ),
+                "Remove it from PATH, so that compiles aren't cached twice",
+            ));
+        }
+    }
+    if !found_rustc {
+        return;
+    }
+    if let Some(wrapper) = var("RUSTC_WRAPPER") {
+        let is_sccache = Path::new(&wrapper)
+            .file_stem()
+            .map_or(false, |stem| stem == "sccache");
+        if !is_sccache {
+            findings.push(Finding::warning(
+                format!(
+                    "RUSTC_WRAPPER is {:?}, so Cargo doesn't use sccache",
+                    wrapper
+                ),
+                "Set RUSTC_WRAPPER=sccache",
+            ));
+        }
+    }
+    let caches_incremental = config.map_or(true, |c| c.hash.rust_incremental != Some(false));
+    let incremental = var("CARGO_INCREMENTAL").map_or(true, |v| v != "0");
+    if incremental && !caches_incremental {
+        findings.push(Finding::warning(
+            "Incremental Rust compiles aren't cached, as rust_incremental is false, and Cargo compiles workspace members incrementally in the debug profile unless CARGO_INCREMENTAL=0",
+            "Set CARGO_INCREMENTAL=0 for builds that should be cached",
+        ));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn check(vars: &[(&str, &str)], config: Option<&Config>) -> Vec<String> {
+        let vars: HashMap<_, _> = vars
+            .iter()
+            .map(|&(k, v)| (k.to_owned(), v.to_owned()))
+            .collect();
+        let mut findings = vec![];
+        check_env(|name| vars.get(name).cloned(), config, true, &mut findings);
+        assert!(findings.iter().all(|f| f.status == Status::Warning));
+        findings.into_iter().map(|f| f.what).collect()
+    }
+
+    #[test]
+    fn test_check_env() {
+        assert!(check(
+            &[("PATH", "/usr/bin"), ("RUSTC_WRAPPER", "/bin/sccache")],
+            None
+        )
+        .is_empty());
+        let found = check(
+            &[
+                ("PATH", "/usr/lib/ccache:/usr/bin"),
+                ("RUSTC_WRAPPER", "cachepot"),
+                ("SCCACHE_RECACHE", "1"),
+                ("SCCACHE_NO_CACHE", "1"),
+            ],
+            None,
+        );
+        assert_eq!(found.len(), 4);
+        assert!(found[0].starts_with("SCCACHE_RECACHE"));
+        assert!(found[1].starts_with("SCCACHE_NO_CACHE"));
+        assert!(found[2].starts_with("/usr/lib/ccache"));
+        assert!(found[3].starts_with("RUSTC_WRAPPER"));
+
+        let mut config = Config::default();
+        config.hash.rust_incremental = Some(false);
+        assert_eq!(check(&[], Some(&config)).len(), 1);
+        assert!(check(&[("CARGO_INCREMENTAL", "0")], Some(&config)).is_empty());
+    }
+}
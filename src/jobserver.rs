@@ -1,11 +1,10 @@
+use std::env;
 use std::io;
 use std::process::Command;
 use std::sync::Arc;
 
-use futures::future;
-use futures::prelude::*;
-use futures::sync::mpsc;
-use futures::sync::oneshot;
+use futures::channel::mpsc;
+use futures::channel::oneshot;
 
 use crate::errors::*;
 
@@ -13,7 +12,9 @@ use crate::errors::*;
 pub struct Client {
     helper: Option<Arc<jobserver::HelperThread>>,
     tx: Option<mpsc::UnboundedSender<oneshot::Sender<io::Result<jobserver::Acquired>>>>,
-    inner: jobserver::Client,
+    /// `None` for a jobserver of the fifo style, which commands find in
+    /// their environment rather than being passed.
+    inner: Option<jobserver::Client>,
 }
 
 pub struct Acquired {
@@ -25,7 +26,14 @@ impl Client {
     pub unsafe fn new() -> Client {
         match jobserver::Client::from_env() {
             Some(c) => Client::_new(c, true),
-            None => Client::new_num(num_cpus::get()),
+            // Like an inherited jobserver, the build tool already holds a
+            // token for us
+            None if fifo_from_env().is_some() => Client {
+                helper: None,
+                tx: None,
+                inner: None,
+            },
+            None => Client::new_num(max_jobs().unwrap_or_else(num_cpus::get)),
         }
     }
 
@@ -39,11 +47,11 @@ impl Client {
             (None, None)
         } else {
             let (tx, rx) = mpsc::unbounded::<oneshot::Sender<_>>();
-            let mut rx = rx.wait();
+            let mut rx = futures::executor::block_on_stream(rx);
             let helper = inner
                 .clone()
                 .into_helper_thread(move |token| {
-                    if let Some(Ok(sender)) = rx.next() {
+                    if let Some(sender) = rx.next() {
                         drop(sender.send(token));
                     }
                 })
@@ -51,12 +59,24 @@ impl Client {
             (Some(Arc::new(helper)), Some(tx))
         };
 
-        Client { inner, helper, tx }
+        Client {
+            inner: Some(inner),
+            helper,
+            tx,
+        }
+    }
+
+    /// Whether this is the jobserver of the build tool that ran sccache,
+    /// rather than one of its own.
+    pub fn is_inherited(&self) -> bool {
+        self.helper.is_none()
     }
 
     /// Configures this jobserver to be inherited by the specified command
     pub fn configure(&self, cmd: &mut Command) {
-        self.inner.configure(cmd)
+        if let Some(ref inner) = self.inner {
+            inner.configure(cmd)
+        }
     }
 
     /// Returns a future that represents an acquired jobserver token.
@@ -64,18 +84,64 @@ impl Client {
     /// This should be invoked before any "work" is spawned (for whatever the
     /// definition of "work" is) to ensure that the system is properly
     /// rate-limiting itself.
-    pub fn acquire(&self) -> SFuture<Acquired> {
+    pub async fn acquire(&self) -> Result<Acquired> {
         let (helper, tx) = match (self.helper.as_ref(), self.tx.as_ref()) {
             (Some(a), Some(b)) => (a, b),
-            _ => return Box::new(future::ok(Acquired { _token: None })),
+            _ => return Ok(Acquired { _token: None }),
         };
         let (mytx, myrx) = oneshot::channel();
         helper.request_token();
         tx.unbounded_send(mytx).unwrap();
-        Box::new(
-            myrx.fcontext("jobserver helper panicked")
-                .and_then(|t| t.context("failed to acquire jobserver token"))
-                .map(|t| Acquired { _token: Some(t) }),
-        )
+        let token = myrx
+            .await
+            .context("jobserver helper panicked")?
+            .context("failed to acquire jobserver token")?;
+        Ok(Acquired {
+            _token: Some(token),
+        })
+    }
+}
+
+/// The most jobs to run at once without a jobserver, `SCCACHE_MAX_JOBS`.
+pub fn max_jobs() -> Option<usize> {
+    let jobs = env::var("SCCACHE_MAX_JOBS").ok()?;
+    match jobs.parse() {
+        Ok(jobs) if jobs > 0 => Some(jobs),
+        _ => {
+            warn!("Ignoring invalid SCCACHE_MAX_JOBS: {}", jobs);
+            None
+        }
+    }
+}
+
+/// The path of the fifo of a jobserver in the environment, which newer GNU
+/// make passes as `--jobserver-auth=fifo:<path>`.
+fn fifo_from_env() -> Option<String> {
+    let flags = env::var("CARGO_MAKEFLAGS")
+        .or_else(|_| env::var("MAKEFLAGS"))
+        .or_else(|_| env::var("MFLAGS"))
+        .ok()?;
+    fifo_path(&flags).map(str::to_owned)
+}
+
+fn fifo_path(flags: &str) -> Option<&str> {
+    flags
+        .split(' ')
+        .filter_map(|flag| flag.strip_prefix("--jobserver-auth=fifo:"))
+        .last()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fifo_path() {
+        assert_eq!(
+            fifo_path("-j8 --jobserver-auth=fifo:/tmp/GMfifo1234"),
+            Some("/tmp/GMfifo1234")
+        );
+        assert_eq!(fifo_path("-j8 --jobserver-auth=3,4"), None);
+        assert_eq!(fifo_path("-j8"), None);
     }
 }
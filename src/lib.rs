@@ -19,8 +19,6 @@
 extern crate clap;
 #[macro_use]
 extern crate counted_array;
-#[macro_use]
-extern crate futures;
 #[cfg(feature = "jsonwebtoken")]
 use jsonwebtoken as jwt;
 #[macro_use]
@@ -38,34 +36,54 @@ extern crate serde_derive;
 #[macro_use]
 mod test;
 
-#[macro_use]
 pub mod errors;
+#[macro_use]
+#[doc(hidden)]
+pub mod logging;
 
 #[cfg(feature = "azure")]
 mod azure;
-mod cache;
+#[cfg(feature = "hyper")]
+mod bazel;
+#[doc(hidden)]
+pub mod cache;
+mod ccache_env;
 mod client;
+mod cmake;
 mod cmdline;
 mod commands;
 mod compiler;
 pub mod config;
 pub mod dist;
+mod dns;
+mod doctor;
 mod jobserver;
 pub mod lru_disk_cache;
+mod metrics;
 mod mock_command;
+mod msbuild;
+mod ninja;
+mod offline;
+mod prewarm;
 mod protocol;
+mod secrets;
 pub mod server;
 #[cfg(feature = "simple-s3")]
 mod simples3;
+mod statsd;
+#[cfg(unix)]
+mod systemd;
+mod top;
+mod trace;
 #[doc(hidden)]
 pub mod util;
-
-use std::env;
+mod xcode;
 
 const LOGGING_ENV: &str = "SCCACHE_LOG";
 
 pub fn main() {
-    init_logging();
+    logging::init(LOGGING_ENV);
+    ccache_env::apply();
     std::process::exit(match cmdline::parse() {
         Ok(cmd) => match commands::run_command(cmd) {
             Ok(s) => s,
@@ -88,12 +106,3 @@ pub fn main() {
         }
     });
 }
-
-fn init_logging() {
-    if env::var(LOGGING_ENV).is_ok() {
-        match env_logger::Builder::from_env(LOGGING_ENV).try_init() {
-            Ok(_) => (),
-            Err(e) => panic!(format!("Failed to initalize logging: {:?}", e)),
-        }
-    }
-}
@@ -0,0 +1,321 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Logging to stderr, as text or as one JSON object per line for log
+//! pipelines to parse.
+//!
+//! Events are log records with fields, logged with `log_event!`. As text the
+//! fields follow the message as `key=value` pairs, and as JSON they're fields
+//! of the object alongside `time`, `level`, `target` and `message`.
+//!
+//! The server's stderr can be a log file, which it rotates itself.
+
+use crate::config::parse_size;
+use crate::errors::{Context, Result};
+use log::kv::{self, Key, Value, Visitor};
+use log::{Level, Metadata, Record};
+use serde_json::{Map, Number};
+use std::borrow::Cow;
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How log records are written, `text` (the default) or `json`.
+pub const FORMAT_ENV: &str = "SCCACHE_LOG_FORMAT";
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+pub use log::kv::ToValue;
+
+/// Log an event with fields, e.g.
+/// `log_event!(Level::Info, "compile finished"; "result" => "hit")`.
+#[macro_export]
+macro_rules! log_event {
+    ($level:expr, $message:expr; $($key:expr => $value:expr),* $(,)?) => {
+        $crate::logging::event(
+            $level,
+            module_path!(),
+            $message,
+            &[$(($key, &$value as &dyn $crate::logging::ToValue)),*],
+        )
+    };
+}
+
+/// Log to stderr with the filter in `filter_env`, if it's set.
+pub fn init(filter_env: &str) {
+    if env::var(filter_env).is_err() {
+        return;
+    }
+    let mut builder = env_logger::Builder::from_env(filter_env);
+    let format = env::var(FORMAT_ENV).ok();
+    if let Some("json") = format.as_deref() {
+        JSON.store(true, Ordering::Relaxed);
+        builder.format(|buf, record| {
+            let time = buf.timestamp_millis().to_string();
+            serde_json::to_writer(&mut *buf, &to_json(record, time))?;
+            writeln!(buf)
+        });
+    }
+    if let Err(e) = builder.try_init() {
+        panic!("Failed to initalize logging: {:?}", e)
+    }
+    match format.as_deref() {
+        None | Some("text") | Some("json") => (),
+        Some(format) => warn!("Unknown {} {:?}, logging as text", FORMAT_ENV, format),
+    }
+}
+
+pub fn event(level: Level, target: &str, message: &str, fields: &[(&str, &dyn ToValue)]) {
+    let metadata = Metadata::builder().level(level).target(target).build();
+    if level > log::max_level() || !log::logger().enabled(&metadata) {
+        return;
+    }
+    let message = if JSON.load(Ordering::Relaxed) {
+        Cow::Borrowed(message)
+    } else {
+        let mut text = message.to_owned();
+        for (key, value) in fields {
+            text.push_str(&format!(" {}={}", key, value.to_value()));
+        }
+        Cow::Owned(text)
+    };
+    log::logger().log(
+        &Record::builder()
+            .metadata(metadata)
+            .args(format_args!("{}", message))
+            .module_path(Some(target))
+            .key_values(&fields)
+            .build(),
+    );
+}
+
+fn to_json(record: &Record<'_>, time: String) -> serde_json::Value {
+    let mut object = Map::new();
+    object.insert("time".to_owned(), time.into());
+    object.insert("level".to_owned(), record.level().to_string().into());
+    object.insert("target".to_owned(), record.target().into());
+    object.insert("message".to_owned(), record.args().to_string().into());
+    let _ = record.key_values().visit(&mut Fields(&mut object));
+    object.into()
+}
+
+struct Fields<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'kvs> Visitor<'kvs> for Fields<'_> {
+    fn visit_pair(
+        &mut self,
+        key: Key<'kvs>,
+        value: Value<'kvs>,
+    ) -> std::result::Result<(), kv::Error> {
+        let value = if let Some(n) = value.to_u64() {
+            n.into()
+        } else if let Some(n) = value.to_i64() {
+            n.into()
+        } else if let Some(n) = value.to_f64().and_then(Number::from_f64) {
+            n.into()
+        } else if let Some(b) = value.to_bool() {
+            b.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.as_str().to_owned(), value);
+        Ok(())
+    }
+}
+
+/// When to rotate a log file, and how many old ones to keep.
+#[derive(Debug, PartialEq)]
+pub struct Rotation {
+    pub max_size: Option<u64>,
+    pub max_age: Option<Duration>,
+    pub keep: usize,
+}
+
+impl Rotation {
+    /// Get the rotation of the error log from `SCCACHE_ERROR_LOG_MAX_SIZE`,
+    /// `SCCACHE_ERROR_LOG_MAX_AGE` in seconds and `SCCACHE_ERROR_LOG_KEEP`, if
+    /// either limit is set.
+    pub fn from_env() -> Result<Option<Rotation>> {
+        let max_size = match env::var("SCCACHE_ERROR_LOG_MAX_SIZE") {
+            Ok(size) => Some(parse_size(&size).with_context(|| {
+                format!(
+                    "Invalid SCCACHE_ERROR_LOG_MAX_SIZE {:?}, such as 100M",
+                    size
+                )
+            })?),
+            Err(_) => None,
+        };
+        let max_age = match env::var("SCCACHE_ERROR_LOG_MAX_AGE") {
+            Ok(secs) => Some(Duration::from_secs(secs.parse().with_context(|| {
+                format!("Invalid SCCACHE_ERROR_LOG_MAX_AGE {:?}", secs)
+            })?)),
+            Err(_) => None,
+        };
+        if max_size.is_none() && max_age.is_none() {
+            return Ok(None);
+        }
+        let keep = match env::var("SCCACHE_ERROR_LOG_KEEP") {
+            Ok(keep) => keep
+                .parse()
+                .with_context(|| format!("Invalid SCCACHE_ERROR_LOG_KEEP {:?}", keep))?,
+            Err(_) => DEFAULT_KEEP,
+        };
+        Ok(Some(Rotation {
+            max_size,
+            max_age,
+            keep,
+        }))
+    }
+
+    fn is_due(&self, size: u64, age: Duration) -> bool {
+        self.max_size.map_or(false, |max| size >= max)
+            || self.max_age.map_or(false, |max| age >= max)
+    }
+
+    /// Rotate the log at `path`, which `log` is open for, on a background
+    /// thread. Each new log is passed to `reopen`.
+    pub fn start<F>(self, path: PathBuf, mut log: File, reopen: F)
+    where
+        F: Fn(File) + Send + 'static,
+    {
+        // How often to check the log, which can grow past its limit until then
+        const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+        thread::spawn(move || {
+            let mut opened = Instant::now();
+            loop {
+                thread::sleep(CHECK_INTERVAL);
+                let size = match log.metadata() {
+                    Ok(metadata) => metadata.len(),
+                    Err(_) => continue,
+                };
+                if !self.is_due(size, opened.elapsed()) {
+                    continue;
+                }
+                match rotate(&path, self.keep).and_then(|()| open_log(&path)) {
+                    Ok(new_log) => {
+                        log = new_log;
+                        opened = Instant::now();
+                        match log.try_clone() {
+                            Ok(f) => reopen(f),
+                            Err(e) => warn!("Failed to use the new log: {}", e),
+                        }
+                    }
+                    Err(e) => warn!("Failed to rotate {}: {}", path.display(), e),
+                }
+            }
+        });
+    }
+}
+
+/// How many rotated logs are kept, by default.
+const DEFAULT_KEEP: usize = 5;
+
+/// Open a log file for appending to.
+pub fn open_log(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Move the log at `path` to `path.1`, and the older ones at `path.1`.. to
+/// `path.2`.., removing those past `keep`.
+fn rotate(path: &Path, keep: usize) -> io::Result<()> {
+    let numbered = |n: usize| {
+        let mut name = OsString::from(path);
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    };
+    let ignore_missing = |res: io::Result<()>| match res {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    };
+    if keep == 0 {
+        return ignore_missing(fs::remove_file(path));
+    }
+    ignore_missing(fs::remove_file(numbered(keep)))?;
+    for n in (1..keep).rev() {
+        ignore_missing(fs::rename(numbered(n), numbered(n + 1)))?;
+    }
+    fs::rename(path, numbered(1))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_to_json() {
+        let fields: &[(&str, &dyn ToValue)] = &[("key", &"abc"), ("duration_ms", &12u64)];
+        let json = to_json(
+            &Record::builder()
+                .level(Level::Info)
+                .target("sccache::server")
+                .args(format_args!("compile finished"))
+                .key_values(&fields)
+                .build(),
+            "2020-01-01T00:00:00.000Z".to_owned(),
+        );
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "time": "2020-01-01T00:00:00.000Z",
+                "level": "INFO",
+                "target": "sccache::server",
+                "message": "compile finished",
+                "key": "abc",
+                "duration_ms": 12,
+            })
+        );
+    }
+
+    #[test]
+    fn test_rotate() {
+        let tempdir = tempfile::Builder::new()
+            .prefix("sccache_test_logging")
+            .tempdir()
+            .unwrap();
+        let path = tempdir.path().join("log");
+        for content in &["a", "b", "c", "d"] {
+            fs::write(&path, content).unwrap();
+            rotate(&path, 2).unwrap();
+        }
+        assert!(!path.exists());
+        let read = |name| fs::read_to_string(tempdir.path().join(name)).unwrap();
+        assert_eq!(read("log.1"), "d");
+        assert_eq!(read("log.2"), "c");
+        assert!(!tempdir.path().join("log.3").exists());
+    }
+
+    #[test]
+    fn test_rotation_is_due() {
+        let rotation = Rotation {
+            max_size: Some(100),
+            max_age: None,
+            keep: 1,
+        };
+        assert!(!rotation.is_due(99, Duration::from_secs(1_000_000)));
+        assert!(rotation.is_due(100, Duration::from_secs(0)));
+        let rotation = Rotation {
+            max_size: None,
+            max_age: Some(Duration::from_secs(60)),
+            keep: 1,
+        };
+        assert!(!rotation.is_due(1_000_000, Duration::from_secs(59)));
+        assert!(rotation.is_due(0, Duration::from_secs(60)));
+    }
+}
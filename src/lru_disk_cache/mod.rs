@@ -155,15 +155,22 @@ impl LruDiskCache {
         self.root.as_path()
     }
 
+    /// Return the path the files are stored under, as one that can be
+    /// longer than `MAX_PATH` on Windows.
+    fn abs_root(&self) -> PathBuf {
+        crate::util::long_path(&self.root).into_owned()
+    }
+
     /// Return the path that `key` would be stored at.
     fn rel_to_abs_path<K: AsRef<Path>>(&self, rel_path: K) -> PathBuf {
-        self.root.join(rel_path)
+        self.abs_root().join(rel_path)
     }
 
     /// Scan `self.root` for existing files and store them.
     fn init(mut self) -> Result<Self> {
-        fs::create_dir_all(&self.root)?;
-        for (file, size) in get_all_files(&self.root) {
+        let root = self.abs_root();
+        fs::create_dir_all(&root)?;
+        for (file, size) in get_all_files(&root) {
             if !self.can_store(size) {
                 fs::remove_file(file).unwrap_or_else(|e| {
                     error!(
@@ -189,8 +196,9 @@ impl LruDiskCache {
         if !self.can_store(size) {
             return Err(Error::FileTooLarge);
         }
+        let root = self.abs_root();
         let rel_path = match addfile_path {
-            AddFile::AbsPath(ref p) => p.strip_prefix(&self.root).expect("Bad path?").as_os_str(),
+            AddFile::AbsPath(ref p) => p.strip_prefix(&root).expect("Bad path?").as_os_str(),
             AddFile::RelPath(p) => p,
         };
         //TODO: ideally LRUCache::insert would give us back the entries it had to remove.
@@ -311,6 +319,18 @@ impl LruDiskCache {
             None => Ok(()),
         }
     }
+
+    /// Remove every file from the cache.
+    pub fn clear(&mut self) -> Result<()> {
+        while let Some((rel_path, _)) = self.lru.remove_lru() {
+            let path = self.rel_to_abs_path(rel_path);
+            fs::remove_file(&path).map_err(|e| {
+                error!("Error removing file from cache: `{:?}`: {}", path, e);
+                e
+            })?;
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -442,6 +462,26 @@ mod tests {
         assert!(!f.tmp().join("a/b/c").exists());
     }
 
+    #[test]
+    fn test_insert_bytes_long_path() {
+        // Paths longer than MAX_PATH on Windows
+        let f = TestFixture::new();
+        let mut c = LruDiskCache::new(f.tmp(), 25).unwrap();
+        let key: PathBuf = (0..6).map(|i| format!("{}{}", i, "d".repeat(50))).collect();
+        assert!(f.tmp().join(&key).as_os_str().len() > 260);
+        c.insert_bytes(&key, &[0; 10]).unwrap();
+        assert!(c.contains_key(&key));
+        let mut contents = vec![];
+        c.get(&key).unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, [0u8; 10]);
+        // And found again
+        drop(c);
+        let mut c = LruDiskCache::new(f.tmp(), 25).unwrap();
+        assert_eq!(c.size(), 10);
+        c.remove(&key).unwrap();
+        assert_eq!(c.size(), 0);
+    }
+
     #[test]
     fn test_insert_bytes_exact() {
         // Test that files adding up to exactly the size limit works.
@@ -567,4 +607,19 @@ mod tests {
         assert!(!f.tmp().join("cache").join("file2").exists());
         assert!(!p4.exists());
     }
+
+    #[test]
+    fn test_clear() {
+        let f = TestFixture::new();
+        let mut c = LruDiskCache::new(f.tmp().join("cache"), 25).unwrap();
+        c.insert_bytes("a/file1", &[0; 10]).unwrap();
+        c.insert_bytes("file2", &[0; 10]).unwrap();
+        c.clear().unwrap();
+        assert!(c.is_empty());
+        assert_eq!(c.size(), 0);
+        assert!(!f.tmp().join("cache").join("a").join("file1").exists());
+        assert!(!f.tmp().join("cache").join("file2").exists());
+        c.insert_bytes("file1", &[0; 10]).unwrap();
+        assert_eq!(c.len(), 1);
+    }
 }
@@ -0,0 +1,412 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Metrics in the Prometheus text exposition format, and those of the local
+//! server.
+
+use std::fmt::Write;
+
+use crate::server::{DurationHistogram, PerLanguageCount, ServerStats, DURATION_BUCKETS};
+
+/// The `Content-Type` of the text exposition format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+#[derive(Default)]
+pub struct Metrics(pub String);
+
+impl Metrics {
+    pub fn metric(
+        &mut self,
+        kind: &str,
+        name: &str,
+        help: &str,
+        samples: &[(&[(&str, &str)], f64)],
+    ) {
+        self.header(kind, name, help);
+        for (labels, value) in samples {
+            self.sample(name, labels, *value)
+        }
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: f64) {
+        self.metric("gauge", name, help, &[(&[], value)])
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.metric("counter", name, help, &[(&[], value as f64)])
+    }
+
+    /// A histogram of durations, in seconds.
+    pub fn histogram(&mut self, name: &str, help: &str, histogram: &DurationHistogram) {
+        self.header("histogram", name, help);
+        let bucket = format!("{}_bucket", name);
+        let mut count = 0;
+        for (le, n) in DURATION_BUCKETS.iter().zip(&histogram.buckets) {
+            count += n;
+            self.sample(&bucket, &[("le", &le.to_string())], count as f64)
+        }
+        self.sample(&bucket, &[("le", "+Inf")], histogram.count as f64);
+        self.sample(&format!("{}_sum", name), &[], histogram.sum.as_secs_f64());
+        self.sample(&format!("{}_count", name), &[], histogram.count as f64);
+    }
+
+    fn header(&mut self, kind: &str, name: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.0.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<_> = labels
+                .iter()
+                .map(|(label, value)| format!("{}=\"{}\"", label, escape(value)))
+                .collect();
+            let _ = write!(self.0, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.0, " {}", value);
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// The metrics of the local server, with `compiles_active` compiles in
+/// progress.
+pub fn server(stats: &ServerStats, compiles_active: usize) -> String {
+    let mut out = Metrics::default();
+    out.counter(
+        "sccache_compile_requests_total",
+        "Requests from clients to compile.",
+        stats.compile_requests,
+    );
+    out.metric(
+        "counter",
+        "sccache_requests_total",
+        "Compile requests, by how they were handled.",
+        &[
+            (&[("outcome", "executed")], stats.requests_executed as f64),
+            (
+                &[("outcome", "unsupported_compiler")],
+                stats.requests_unsupported_compiler as f64,
+            ),
+            (
+                &[("outcome", "not_compile")],
+                stats.requests_not_compile as f64,
+            ),
+            (
+                &[("outcome", "not_cacheable")],
+                stats.requests_not_cacheable as f64,
+            ),
+        ],
+    );
+    out.gauge(
+        "sccache_compiles_active",
+        "Compiles in progress, including those waiting for a jobserver token.",
+        compiles_active as f64,
+    );
+    out.histogram(
+        "sccache_compile_duration_seconds",
+        "How long executed compile requests took, including cache lookups.",
+        &stats.compile_durations,
+    );
+    out.histogram(
+        "sccache_hash_duration_seconds",
+        "How long hashing compiles took, including preprocessing.",
+        &stats.hash_durations,
+    );
+    out.histogram(
+        "sccache_cache_get_duration_seconds",
+        "How long cache lookups took.",
+        &stats.cache_get_durations,
+    );
+    out.histogram(
+        "sccache_cache_put_duration_seconds",
+        "How long cache writes took.",
+        &stats.cache_put_durations,
+    );
+    out.histogram(
+        "sccache_compiler_duration_seconds",
+        "How long running the compiler took, on cache misses.",
+        &stats.compiler_durations,
+    );
+
+    per_language(
+        &mut out,
+        "sccache_cache_hits_total",
+        "Cache hits, by language.",
+        &stats.cache_hits,
+    );
+    per_language(
+        &mut out,
+        "sccache_cache_misses_total",
+        "Cache misses, by language.",
+        &stats.cache_misses,
+    );
+    per_language(
+        &mut out,
+        "sccache_cache_errors_total",
+        "Errors handling compile requests, by language.",
+        &stats.cache_errors,
+    );
+    out.counter(
+        "sccache_cache_timeouts_total",
+        "Cache misses because the cache took too long to respond.",
+        stats.cache_timeouts,
+    );
+    out.counter(
+        "sccache_cache_read_errors_total",
+        "Errors reading cache entries.",
+        stats.cache_read_errors,
+    );
+    out.metric(
+        "counter",
+        "sccache_cache_shed_total",
+        "Cache operations skipped because too many were waiting for the storage.",
+        &[
+            (&[("op", "read")], stats.cache_reads_shed as f64),
+            (&[("op", "write")], stats.cache_writes_shed as f64),
+        ],
+    );
+    out.counter(
+        "sccache_cache_writes_total",
+        "Entries written to the cache.",
+        stats.cache_writes,
+    );
+    out.counter(
+        "sccache_cache_writes_suppressed_total",
+        "Entries not written to the cache because it's read-only.",
+        stats.cache_writes_suppressed,
+    );
+    out.counter(
+        "sccache_cache_hits_checked_total",
+        "Cache hits checked against compiling them.",
+        stats.cache_hits_checked,
+    );
+    out.counter(
+        "sccache_cache_hit_mismatches_total",
+        "Cache hits checked whose outputs didn't match compiling them.",
+        stats.cache_hit_mismatches,
+    );
+    out.counter(
+        "sccache_cache_write_errors_total",
+        "Errors writing entries to the cache.",
+        stats.cache_write_errors,
+    );
+    out.metric(
+        "counter",
+        "sccache_cache_read_seconds_total",
+        "Time spent looking up cache entries, by whether they were found.",
+        &[
+            (
+                &[("result", "hit")],
+                stats.cache_read_hit_duration.as_secs_f64(),
+            ),
+            (
+                &[("result", "miss")],
+                stats.cache_read_miss_duration.as_secs_f64(),
+            ),
+        ],
+    );
+    out.metric(
+        "counter",
+        "sccache_cache_write_seconds_total",
+        "Time spent writing cache entries.",
+        &[(&[], stats.cache_write_duration.as_secs_f64())],
+    );
+    out.counter(
+        "sccache_cache_hit_bytes_total",
+        "The size of the cache entries of hits, as they're stored.",
+        stats.hit_bytes(),
+    );
+    out.gauge(
+        "sccache_estimated_time_saved_seconds",
+        "The time hits are estimated to have saved, from how long misses of the same compiler took.",
+        stats.time_saved().as_secs_f64(),
+    );
+    out.counter(
+        "sccache_forced_recaches_total",
+        "Compiles that ignored the cache because they were asked to.",
+        stats.forced_recaches,
+    );
+    out.counter(
+        "sccache_non_cacheable_compilations_total",
+        "Compiles that succeeded but couldn't be cached.",
+        stats.non_cacheable_compilations,
+    );
+    out.counter(
+        "sccache_compile_fails_total",
+        "Compiles that failed.",
+        stats.compile_fails,
+    );
+
+    let dist_compiles: u64 = stats.dist_compiles.values().map(|&n| n as u64).sum();
+    out.counter(
+        "sccache_dist_compiles_total",
+        "Compiles run on build servers.",
+        dist_compiles,
+    );
+    let mut fallbacks: Vec<_> = stats.dist_fallbacks.iter().collect();
+    fallbacks.sort();
+    let labels: Vec<_> = fallbacks
+        .iter()
+        .map(|(cause, _)| [("cause", cause.as_str())])
+        .collect();
+    let samples: Vec<(&[(&str, &str)], f64)> = labels
+        .iter()
+        .zip(&fallbacks)
+        .map(|(labels, (_, &n))| (&labels[..], n as f64))
+        .collect();
+    out.metric(
+        "counter",
+        "sccache_dist_fallbacks_total",
+        "Distributed compiles that failed and were run locally, by cause.",
+        &samples,
+    );
+    backends(&mut out, stats);
+    out.0
+}
+
+/// The lookups and writes of each storage backend, labelled by its
+/// location.
+fn backends(out: &mut Metrics, stats: &ServerStats) {
+    let mut backends: Vec<_> = stats.backends.iter().collect();
+    backends.sort_by(|(l1, _), (l2, _)| l1.cmp(l2));
+    let mut labels = vec![];
+    for (location, counts) in &backends {
+        for &(op, result, n) in &[
+            ("get", "hit", counts.hits),
+            ("get", "miss", counts.misses),
+            ("get", "error", counts.errors),
+            ("put", "ok", counts.writes),
+            ("put", "error", counts.write_errors),
+        ] {
+            labels.push((
+                [
+                    ("location", location.as_str()),
+                    ("op", op),
+                    ("result", result),
+                ],
+                n,
+            ));
+        }
+    }
+    let samples: Vec<(&[(&str, &str)], f64)> = labels
+        .iter()
+        .map(|(labels, n)| (&labels[..], *n as f64))
+        .collect();
+    out.metric(
+        "counter",
+        "sccache_backend_operations_total",
+        "Lookups and writes of each storage backend, by their result.",
+        &samples,
+    );
+    let labels: Vec<_> = backends
+        .iter()
+        .flat_map(|(location, counts)| {
+            vec![
+                (
+                    [("location", location.as_str()), ("op", "get")],
+                    counts.read_duration,
+                ),
+                (
+                    [("location", location.as_str()), ("op", "put")],
+                    counts.write_duration,
+                ),
+            ]
+        })
+        .collect();
+    let samples: Vec<(&[(&str, &str)], f64)> = labels
+        .iter()
+        .map(|(labels, duration)| (&labels[..], duration.as_secs_f64()))
+        .collect();
+    out.metric(
+        "counter",
+        "sccache_backend_seconds_total",
+        "Time spent on lookups and writes of each storage backend.",
+        &samples,
+    );
+}
+
+fn per_language(out: &mut Metrics, name: &str, help: &str, counts: &PerLanguageCount) {
+    let mut counts: Vec<_> = counts.iter().collect();
+    counts.sort();
+    let labels: Vec<_> = counts
+        .iter()
+        .map(|(language, _)| [("language", language.as_str())])
+        .collect();
+    let samples: Vec<(&[(&str, &str)], f64)> = labels
+        .iter()
+        .zip(&counts)
+        .map(|(labels, (_, &n))| (&labels[..], n as f64))
+        .collect();
+    out.metric("counter", name, help, &samples)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::metered::BackendStats;
+    use std::time::Duration;
+
+    #[test]
+    fn test_server_metrics() {
+        let mut stats = ServerStats {
+            compile_requests: 3,
+            cache_write_duration: Duration::from_millis(1500),
+            ..Default::default()
+        };
+        stats.compile_durations.record(Duration::from_millis(30));
+        stats.compile_durations.record(Duration::from_secs(1000));
+        stats.cache_get_durations.record(Duration::from_millis(200));
+        stats
+            .dist_fallbacks
+            .insert("toolchain_upload".to_owned(), 2);
+        stats.backends.insert(
+            "Local disk".to_owned(),
+            BackendStats {
+                misses: 4,
+                ..Default::default()
+            },
+        );
+        let text = server(&stats, 1);
+        assert!(text.contains(
+            "# HELP sccache_compile_requests_total Requests from clients to compile.\n\
+             # TYPE sccache_compile_requests_total counter\n\
+             sccache_compile_requests_total 3\n"
+        ));
+        assert!(text.contains("sccache_compiles_active 1\n"));
+        assert!(text.contains("sccache_compile_duration_seconds_bucket{le=\"0.01\"} 0\n"));
+        assert!(text.contains("sccache_compile_duration_seconds_bucket{le=\"0.05\"} 1\n"));
+        assert!(text.contains("sccache_compile_duration_seconds_bucket{le=\"300\"} 1\n"));
+        assert!(text.contains("sccache_compile_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("sccache_compile_duration_seconds_count 2\n"));
+        assert!(text.contains("sccache_cache_get_duration_seconds_bucket{le=\"0.25\"} 1\n"));
+        assert!(text.contains("sccache_cache_put_duration_seconds_count 0\n"));
+        assert!(text.contains("sccache_cache_write_seconds_total 1.5\n"));
+        assert!(text.contains("sccache_dist_fallbacks_total{cause=\"toolchain_upload\"} 2\n"));
+        assert!(text.contains(
+            "sccache_backend_operations_total{location=\"Local disk\",op=\"get\",result=\"miss\"} 4\n"
+        ));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
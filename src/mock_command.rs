@@ -47,7 +47,7 @@
 
 use crate::errors::*;
 use crate::jobserver::{Acquired, Client};
-use futures::future::{self, Future};
+use async_trait::async_trait;
 use std::boxed::Box;
 use std::ffi::{OsStr, OsString};
 use std::fmt;
@@ -55,17 +55,18 @@ use std::io;
 use std::path::Path;
 use std::process::{Command, ExitStatus, Output, Stdio};
 use std::sync::{Arc, Mutex};
-use tokio_io::{AsyncRead, AsyncWrite};
-use tokio_process::{self, ChildStderr, ChildStdin, ChildStdout, CommandExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::process::{self, ChildStderr, ChildStdin, ChildStdout};
 
 /// A trait that provides a subset of the methods of `std::process::Child`.
+#[async_trait(?Send)]
 pub trait CommandChild {
     /// The type of the process' standard input.
-    type I: AsyncWrite + Sync + Send + 'static;
+    type I: AsyncWrite + Unpin + Sync + Send + 'static;
     /// The type of the process' standard output.
-    type O: AsyncRead + Sync + Send + 'static;
+    type O: AsyncRead + Unpin + Sync + Send + 'static;
     /// The type of the process' standard error.
-    type E: AsyncRead + Sync + Send + 'static;
+    type E: AsyncRead + Unpin + Sync + Send + 'static;
 
     /// Take the stdin object from the process, if available.
     fn take_stdin(&mut self) -> Option<Self::I>;
@@ -74,12 +75,13 @@ pub trait CommandChild {
     /// Take the stderr object from the process, if available.
     fn take_stderr(&mut self) -> Option<Self::E>;
     /// Wait for the process to complete and return its exit status.
-    fn wait(self) -> Box<dyn Future<Item = ExitStatus, Error = io::Error>>;
+    async fn wait(self) -> io::Result<ExitStatus>;
     /// Wait for the process to complete and return its output.
-    fn wait_with_output(self) -> Box<dyn Future<Item = Output, Error = io::Error>>;
+    async fn wait_with_output(self) -> io::Result<Output>;
 }
 
 /// A trait that provides a subset of the methods of `std::process::Command`.
+#[async_trait(?Send)]
 pub trait RunCommand: fmt::Debug {
     /// The type returned by `spawn`.
     type C: CommandChild + 'static;
@@ -112,7 +114,7 @@ pub trait RunCommand: fmt::Debug {
     /// Set the process' stderr from `cfg`.
     fn stderr(&mut self, cfg: Stdio) -> &mut Self;
     /// Execute the process and return a process object.
-    fn spawn(&mut self) -> SFuture<Self::C>;
+    async fn spawn(&mut self) -> Result<Self::C>;
 }
 
 /// A trait that provides a means to create objects implementing `RunCommand`.
@@ -141,40 +143,39 @@ pub trait CommandCreatorSync: Clone + 'static {
 }
 
 pub struct Child {
-    inner: tokio_process::Child,
+    inner: process::Child,
     token: Acquired,
 }
 
 /// Trivial implementation of `CommandChild` for `std::process::Child`.
+#[async_trait(?Send)]
 impl CommandChild for Child {
     type I = ChildStdin;
     type O = ChildStdout;
     type E = ChildStderr;
 
     fn take_stdin(&mut self) -> Option<ChildStdin> {
-        self.inner.stdin().take()
+        self.inner.stdin.take()
     }
     fn take_stdout(&mut self) -> Option<ChildStdout> {
-        self.inner.stdout().take()
+        self.inner.stdout.take()
     }
     fn take_stderr(&mut self) -> Option<ChildStderr> {
-        self.inner.stderr().take()
+        self.inner.stderr.take()
     }
 
-    fn wait(self) -> Box<dyn Future<Item = ExitStatus, Error = io::Error>> {
-        let Child { inner, token } = self;
-        Box::new(inner.map(|ret| {
-            drop(token);
-            ret
-        }))
+    async fn wait(self) -> io::Result<ExitStatus> {
+        let Child { mut inner, token } = self;
+        let ret = inner.wait().await;
+        drop(token);
+        ret
     }
 
-    fn wait_with_output(self) -> Box<dyn Future<Item = Output, Error = io::Error>> {
+    async fn wait_with_output(self) -> io::Result<Output> {
         let Child { inner, token } = self;
-        Box::new(inner.wait_with_output().map(|ret| {
-            drop(token);
-            ret
-        }))
+        let ret = inner.wait_with_output().await;
+        drop(token);
+        ret
     }
 }
 
@@ -197,6 +198,7 @@ impl AsyncCommand {
 }
 
 /// Trivial implementation of `RunCommand` for `std::process::Command`.
+#[async_trait(?Send)]
 impl RunCommand for AsyncCommand {
     type C = Child;
 
@@ -259,21 +261,21 @@ impl RunCommand for AsyncCommand {
         self.inner().stderr(cfg);
         self
     }
-    fn spawn(&mut self) -> SFuture<Child> {
+    async fn spawn(&mut self) -> Result<Child> {
         let mut inner = self.inner.take().unwrap();
         inner.env_remove("MAKEFLAGS");
         inner.env_remove("MFLAGS");
         inner.env_remove("CARGO_MAKEFLAGS");
         self.jobserver.configure(&mut inner);
-        Box::new(self.jobserver.acquire().and_then(move |token| {
-            let child = inner
-                .spawn_async()
-                .with_context(|| format!("failed to spawn {:?}", inner))?;
-            Ok(Child {
-                inner: child,
-                token,
-            })
-        }))
+        let token = self.jobserver.acquire().await?;
+        let mut inner = process::Command::from(inner);
+        let child = inner
+            .spawn()
+            .with_context(|| format!("failed to spawn {:?}", inner))?;
+        Ok(Child {
+            inner: child,
+            token,
+        })
     }
 }
 
@@ -377,6 +379,7 @@ impl MockChild {
     }
 }
 
+#[async_trait(?Send)]
 impl CommandChild for MockChild {
     type I = io::Cursor<Vec<u8>>;
     type O = io::Cursor<Vec<u8>>;
@@ -392,11 +395,11 @@ impl CommandChild for MockChild {
         self.stderr.take()
     }
 
-    fn wait(mut self) -> Box<dyn Future<Item = ExitStatus, Error = io::Error>> {
-        Box::new(future::result(self.wait_result.take().unwrap()))
+    async fn wait(mut self) -> io::Result<ExitStatus> {
+        self.wait_result.take().unwrap()
     }
 
-    fn wait_with_output(self) -> Box<dyn Future<Item = Output, Error = io::Error>> {
+    async fn wait_with_output(self) -> io::Result<Output> {
         let MockChild {
             stdout,
             stderr,
@@ -408,7 +411,7 @@ impl CommandChild for MockChild {
             stdout: stdout.map(|c| c.into_inner()).unwrap_or_else(Vec::new),
             stderr: stderr.map(|c| c.into_inner()).unwrap_or_else(Vec::new),
         });
-        Box::new(future::result(result))
+        result
     }
 }
 
@@ -434,6 +437,7 @@ pub struct MockCommand {
     pub args: Vec<OsString>,
 }
 
+#[async_trait(?Send)]
 impl RunCommand for MockCommand {
     type C = MockChild;
 
@@ -479,10 +483,10 @@ impl RunCommand for MockCommand {
     fn stderr(&mut self, _cfg: Stdio) -> &mut MockCommand {
         self
     }
-    fn spawn(&mut self) -> SFuture<MockChild> {
+    async fn spawn(&mut self) -> Result<MockChild> {
         match self.child.take().unwrap() {
-            ChildOrCall::Child(c) => Box::new(future::result(c)),
-            ChildOrCall::Call(f) => Box::new(future::result(f(&self.args))),
+            ChildOrCall::Child(c) => c,
+            ChildOrCall::Call(f) => f(&self.args),
         }
     }
 }
@@ -549,7 +553,7 @@ mod test {
     use super::*;
     use crate::jobserver::Client;
     use crate::test::utils::*;
-    use futures::Future;
+    use futures::executor::block_on;
     use std::ffi::OsStr;
     use std::io;
     use std::process::{ExitStatus, Output};
@@ -560,21 +564,23 @@ mod test {
         creator: &mut T,
         program: S,
     ) -> Result<<<T as CommandCreator>::Cmd as RunCommand>::C> {
-        creator.new_command(program).spawn().wait()
+        block_on(creator.new_command(program).spawn())
     }
 
     fn spawn_wait_command<T: CommandCreator, S: AsRef<OsStr>>(
         creator: &mut T,
         program: S,
     ) -> Result<ExitStatus> {
-        Ok(spawn_command(creator, program)?.wait().wait()?)
+        Ok(block_on(spawn_command(creator, program)?.wait())?)
     }
 
     fn spawn_output_command<T: CommandCreator, S: AsRef<OsStr>>(
         creator: &mut T,
         program: S,
     ) -> Result<Output> {
-        Ok(spawn_command(creator, program)?.wait_with_output().wait()?)
+        Ok(block_on(
+            spawn_command(creator, program)?.wait_with_output(),
+        )?)
     }
 
     fn spawn_on_thread<T: CommandCreatorSync + Send + 'static>(
@@ -583,13 +589,10 @@ mod test {
     ) -> ExitStatus {
         thread::spawn(move || {
             if really {
-                t.new_command_sync("foo")
-                    .spawn()
-                    .wait()
-                    .unwrap()
-                    .wait()
-                    .wait()
-                    .unwrap()
+                block_on(async {
+                    let child = t.new_command_sync("foo").spawn().await.unwrap();
+                    child.wait().await.unwrap()
+                })
             } else {
                 exit_status(1)
             }
@@ -619,7 +622,7 @@ mod test {
         // new_command should panic.
         let client = Client::new_num(1);
         let mut creator = MockCommandCreator::new(&client);
-        creator.new_command("foo").spawn().wait().unwrap();
+        block_on(creator.new_command("foo").spawn()).unwrap();
     }
 
     #[test]
@@ -0,0 +1,119 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `sccache --generate-msbuild-props`: writing the `Directory.Build.props`
+//! and `Directory.Build.targets` that make MSBuild run its `cl.exe` compiles
+//! through sccache.
+//!
+//! MSBuild runs the compiler named by `CLToolExe` in `CLToolPath`, so those
+//! point at a copy of sccache named `cl.exe`, which runs the `cl.exe` on
+//! `PATH` through sccache as it would any other compiler it's copied as.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use crate::errors::*;
+
+/// The directory, next to the generated files, the copy of sccache is put in.
+const WRAPPER_DIR: &str = ".sccache";
+
+/// Set the tool MSBuild compiles with, and the way it runs it.
+const PROPS: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<!-- Generated by sccache, to run the compiles through it. -->
+<Project>
+  <PropertyGroup>
+    <!-- A copy of sccache, which runs the cl.exe on PATH through sccache -->
+    <CLToolPath>$(MSBuildThisFileDirectory).sccache</CLToolPath>
+    <CLToolExe>cl.exe</CLToolExe>
+    <!-- sccache caches compiles of one source, so run a cl.exe for each
+         source, in parallel, instead of one with /MP for them all -->
+    <UseMultiToolTask>true</UseMultiToolTask>
+    <EnforceProcessCountAcrossBuilds>true</EnforceProcessCountAcrossBuilds>
+    <!-- The file tracker follows the processes a compile starts, which
+         includes the sccache server when a compile starts it, while the
+         files the compile reads are read by the server anyway -->
+    <TrackFileAccess>false</TrackFileAccess>
+  </PropertyGroup>
+</Project>
+"#;
+
+/// Override the compile options of the projects that keep compiles from
+/// being cached, which has to come after the projects set them.
+const TARGETS: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<!-- Generated by sccache, to run the compiles through it. -->
+<Project>
+  <ItemDefinitionGroup>
+    <ClCompile>
+      <!-- A PDB shared by the compiles (/Zi) can't be cached, so embed the
+           debug info in the objects (/Z7) instead -->
+      <DebugInformationFormat Condition="'%(ClCompile.DebugInformationFormat)' != 'None'">OldStyle</DebugInformationFormat>
+      <MultiProcessorCompilation>false</MultiProcessorCompilation>
+    </ClCompile>
+  </ItemDefinitionGroup>
+</Project>
+"#;
+
+/// Write `Directory.Build.props` and `Directory.Build.targets` to `dir`,
+/// which applies them to the projects under it, and the copy of sccache
+/// they use.
+pub fn generate_props(dir: &Path) -> Result<()> {
+    if !cfg!(windows) {
+        bail!("MSBuild compiles can only be run through sccache on Windows");
+    }
+    let files = [
+        (dir.join("Directory.Build.props"), PROPS),
+        (dir.join("Directory.Build.targets"), TARGETS),
+    ];
+    for (path, _) in &files {
+        if path.exists() {
+            bail!(
+                "{} already exists, generate the files in another directory and merge them into it",
+                path.display()
+            );
+        }
+    }
+    let sccache = env::current_exe().context("couldn't find the sccache executable")?;
+    let wrapper = dir.join(WRAPPER_DIR).join("cl.exe");
+    fs::create_dir_all(dir.join(WRAPPER_DIR))
+        .and_then(|_| fs::copy(&sccache, &wrapper))
+        .with_context(|| format!("couldn't copy sccache to {}", wrapper.display()))?;
+    for (path, contents) in &files {
+        fs::write(path, contents).with_context(|| format!("couldn't write {}", path.display()))?;
+        println!("Wrote {}", path.display());
+    }
+    println!("Copied sccache to {}", wrapper.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_props_are_consistent() {
+        // The props point at the copy of sccache
+        assert!(PROPS.contains(&format!(
+            "<CLToolPath>$(MSBuildThisFileDirectory){}</CLToolPath>",
+            WRAPPER_DIR
+        )));
+        for xml in &[PROPS, TARGETS] {
+            assert!(xml.starts_with("<?xml"));
+            assert_eq!(xml.matches("<Project>").count(), 1);
+            assert!(xml.trim_end().ends_with("</Project>"));
+            // Which XML comments can't contain
+            assert!(!xml.replace("<!--", "").replace("-->", "").contains("--"));
+        }
+    }
+}
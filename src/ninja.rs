@@ -0,0 +1,266 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reading what a Ninja build directory knows of its compiles for
+//! `sccache --prefetch-ninja`: their commands, from `ninja -t compdb`, and
+//! the headers they read, from the `.ninja_deps` log or the depfiles left
+//! next to their outputs, to tell which of them the next build runs.
+
+use byteorder::{ByteOrder, LittleEndian};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::SystemTime;
+
+use crate::errors::*;
+
+const DEPS_LOG_SIGNATURE: &[u8] = b"# ninjadeps\n";
+
+/// The compilation database of every edge of the build in `build_dir`, as
+/// `ninja -t compdb` writes it.
+pub fn compile_commands(build_dir: &Path) -> Result<Vec<u8>> {
+    let output = Command::new("ninja")
+        .arg("-C")
+        .arg(build_dir)
+        .args(&["-t", "compdb"])
+        .output()
+        .context("failed to run ninja")?;
+    if !output.status.success() {
+        bail!(
+            "ninja -t compdb failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
+
+/// The inputs of each output recorded in a `.ninja_deps` log, with paths
+/// as Ninja wrote them, relative to the build directory.
+pub fn read_deps_log(data: &[u8]) -> Result<HashMap<PathBuf, Vec<PathBuf>>> {
+    if !data.starts_with(DEPS_LOG_SIGNATURE) || data.len() < DEPS_LOG_SIGNATURE.len() + 4 {
+        bail!("not a Ninja deps log");
+    }
+    let version = LittleEndian::read_u32(&data[DEPS_LOG_SIGNATURE.len()..]);
+    // Version 4 has 64-bit mtimes, version 3 32-bit ones
+    let mtime_words = match version {
+        3 => 1,
+        4 => 2,
+        _ => bail!("unsupported Ninja deps log version {}", version),
+    };
+    let mut paths: Vec<PathBuf> = vec![];
+    let mut deps = HashMap::new();
+    let mut rest = &data[DEPS_LOG_SIGNATURE.len() + 4..];
+    while rest.len() >= 4 {
+        let header = LittleEndian::read_u32(rest);
+        let size = (header & 0x7fff_ffff) as usize;
+        if rest.len() < 4 + size || size % 4 != 0 {
+            // Ninja was killed while writing the last record, and drops it
+            break;
+        }
+        let record = &rest[4..4 + size];
+        rest = &rest[4 + size..];
+        let path_of = |word: &[u8]| paths.get(LittleEndian::read_u32(word) as usize).cloned();
+        if header & 0x8000_0000 != 0 {
+            let words: Vec<_> = record.chunks(4).collect();
+            if words.len() < 1 + mtime_words {
+                bail!("truncated record in the Ninja deps log");
+            }
+            let output = path_of(words[0]).context("unknown output in the Ninja deps log")?;
+            let inputs = words[1 + mtime_words..]
+                .iter()
+                .map(|&word| path_of(word).context("unknown input in the Ninja deps log"))
+                .collect::<Result<_>>()?;
+            // Later records replace earlier ones
+            deps.insert(output, inputs);
+        } else {
+            if size < 4 {
+                bail!("truncated record in the Ninja deps log");
+            }
+            let name = &record[..size - 4];
+            let end = name.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+            let name = String::from_utf8_lossy(&name[..end]).into_owned();
+            paths.push(name.into());
+        }
+    }
+    Ok(deps)
+}
+
+/// The inputs of the targets of a Makefile-style depfile.
+pub fn read_depfile(data: &str) -> Vec<PathBuf> {
+    let mut inputs = vec![];
+    for rule in data.replace("\\\r\n", " ").replace("\\\n", " ").lines() {
+        // Skip the targets, past a colon not part of a Windows drive
+        let colon = rule
+            .match_indices(':')
+            .map(|(i, _)| i)
+            .find(|&i| rule[i + 1..].starts_with(char::is_whitespace) || i + 1 == rule.len());
+        let prerequisites = match colon {
+            Some(i) => &rule[i + 1..],
+            None => continue,
+        };
+        let mut input = String::new();
+        let mut chars = prerequisites.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' if chars.peek() == Some(&' ') => input.push(chars.next().unwrap()),
+                '$' if chars.peek() == Some(&'$') => input.push(chars.next().unwrap()),
+                c if c.is_whitespace() => {
+                    if !input.is_empty() {
+                        inputs.push(PathBuf::from(std::mem::take(&mut input)));
+                    }
+                }
+                c => input.push(c),
+            }
+        }
+        if !input.is_empty() {
+            inputs.push(input.into());
+        }
+    }
+    inputs
+}
+
+/// The depfile named by the `-MF` argument of a compile, if it has one.
+pub fn depfile_argument(args: &[String]) -> Option<&str> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "-MF" {
+            return args.next().map(String::as_str);
+        } else if let Some(path) = arg.strip_prefix("-MF") {
+            return Some(path);
+        }
+    }
+    None
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Whether Ninja would run the edge writing `output` from `inputs`, paths
+/// relative to `build_dir`: if the output is missing, or older than one of
+/// the inputs, or an input is missing.
+pub fn is_out_of_date<'a>(
+    build_dir: &Path,
+    output: &Path,
+    inputs: impl IntoIterator<Item = &'a Path>,
+) -> bool {
+    let output = match modified(&build_dir.join(output)) {
+        Some(output) => output,
+        None => return true,
+    };
+    inputs
+        .into_iter()
+        .any(|input| modified(&build_dir.join(input)).map_or(true, |input| input > output))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn path_record(name: &str, id: u32) -> Vec<u8> {
+        let mut name = name.as_bytes().to_vec();
+        while name.len() % 4 != 0 {
+            name.push(0);
+        }
+        let mut record = (name.len() as u32 + 4).to_le_bytes().to_vec();
+        record.extend(name);
+        record.extend(&(!id).to_le_bytes());
+        record
+    }
+
+    fn deps_record(output: u32, inputs: &[u32]) -> Vec<u8> {
+        let mut words = vec![output, 1234, 0];
+        words.extend(inputs);
+        let mut record = ((words.len() as u32 * 4) | 0x8000_0000)
+            .to_le_bytes()
+            .to_vec();
+        for word in words {
+            record.extend(&word.to_le_bytes());
+        }
+        record
+    }
+
+    #[test]
+    fn test_read_deps_log() {
+        let mut log = DEPS_LOG_SIGNATURE.to_vec();
+        log.extend(&4u32.to_le_bytes());
+        for (id, name) in ["foo.o", "../src/foo.c", "/usr/include/stdio.h", "foo.h"]
+            .iter()
+            .enumerate()
+        {
+            log.extend(path_record(name, id as u32));
+        }
+        log.extend(deps_record(0, &[1, 2]));
+        log.extend(deps_record(0, &[1, 3]));
+        // Cut short
+        log.extend(&deps_record(0, &[1])[..6]);
+
+        let deps = read_deps_log(&log).unwrap();
+        assert_eq!(
+            deps[Path::new("foo.o")],
+            [PathBuf::from("../src/foo.c"), PathBuf::from("foo.h")]
+        );
+        assert_eq!(deps.len(), 1);
+        assert!(read_deps_log(b"# ninjalog\n").is_err());
+    }
+
+    #[test]
+    fn test_read_depfile() {
+        let depfile = "obj/foo.o: ../src/foo.c ../src/my\\ header.h \\\n  C:\\sdk\\a$$b.h\n";
+        assert_eq!(
+            read_depfile(depfile),
+            [
+                PathBuf::from("../src/foo.c"),
+                PathBuf::from("../src/my header.h"),
+                PathBuf::from("C:\\sdk\\a$b.h"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_depfile_argument() {
+        let args = |args: &[&str]| -> Vec<String> { args.iter().map(|&a| a.to_owned()).collect() };
+        assert_eq!(
+            depfile_argument(&args(&["cc", "-MD", "-MF", "foo.o.d", "-c", "foo.c"])),
+            Some("foo.o.d")
+        );
+        assert_eq!(depfile_argument(&args(&["cc", "-MFfoo.d"])), Some("foo.d"));
+        assert_eq!(depfile_argument(&args(&["cc", "-c", "foo.c"])), None);
+    }
+
+    #[test]
+    fn test_is_out_of_date() {
+        let dir = tempfile::tempdir().unwrap();
+        let set_mtime = |name: &str, secs| {
+            let path = dir.path().join(name);
+            fs::write(&path, b"").unwrap();
+            let mtime = filetime::FileTime::from_system_time(
+                SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+            );
+            filetime::set_file_mtime(&path, mtime).unwrap();
+        };
+        set_mtime("foo.o", 2000);
+        set_mtime("foo.c", 1000);
+        set_mtime("foo.h", 3000);
+        let stale = |output: &str, inputs: &[&str]| {
+            is_out_of_date(dir.path(), Path::new(output), inputs.iter().map(Path::new))
+        };
+        assert!(!stale("foo.o", &["foo.c"]));
+        assert!(stale("foo.o", &["foo.c", "foo.h"]));
+        assert!(stale("foo.o", &["gone.h"]));
+        assert!(stale("bar.o", &["foo.c"]));
+    }
+}
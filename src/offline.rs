@@ -0,0 +1,222 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Taking the server offline: using the local disk cache alone, without
+//! distributing compiles, when remote storage can't be reached. That's on
+//! request, with `sccache --offline on`, or, if `SCCACHE_OFFLINE_AFTER` is
+//! set, when cache lookups keep timing out or failing, until the storage
+//! answers again.
+
+use crate::cache::disk::DiskCache;
+use crate::cache::Storage;
+use crate::compiler::MissType;
+use crate::config::Config;
+use futures::executor::ThreadPool;
+use std::env;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long a probe of the storage may take before it counts as
+/// unanswered.
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many probes of the storage in a row have to be answered for the
+/// server to go back online.
+const PROBES_TO_RECOVER: u32 = 2;
+
+/// Whether the server uses its remote storage and distributed compilation.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum NetworkMode {
+    Online,
+    /// Offline by an admin request, until another.
+    Offline,
+    /// Offline since cache lookups kept failing, until the storage answers
+    /// probes again.
+    OfflineDetected,
+}
+
+impl NetworkMode {
+    pub fn is_offline(self) -> bool {
+        self != NetworkMode::Online
+    }
+}
+
+/// Tells when remote storage stopped answering, and when it answers again.
+/// Going offline takes several failed lookups in a row, and going back
+/// online several answered probes, so that the server doesn't flip between
+/// the two on a flaky connection.
+#[derive(Debug)]
+pub struct OfflineDetector {
+    /// How many failed lookups in a row take the server offline, or 0 for
+    /// it to never go offline on its own.
+    failures_to_go_offline: u32,
+    /// The failed lookups since the last that didn't fail.
+    failures: u32,
+    /// The answered probes since the last that wasn't answered.
+    answers: u32,
+    /// How often the storage is probed while the server is offline.
+    pub probe_interval: Duration,
+}
+
+impl OfflineDetector {
+    pub fn new(failures_to_go_offline: u32, probe_interval: Duration) -> OfflineDetector {
+        OfflineDetector {
+            failures_to_go_offline,
+            failures: 0,
+            answers: 0,
+            probe_interval,
+        }
+    }
+
+    /// Going offline after `SCCACHE_OFFLINE_AFTER` failed lookups in a row,
+    /// never by default, and probing the storage every
+    /// `SCCACHE_OFFLINE_RETRY` seconds, 30 by default.
+    pub fn from_env() -> OfflineDetector {
+        let var = |name: &str, default| {
+            env::var(name)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(default)
+        };
+        OfflineDetector::new(
+            var("SCCACHE_OFFLINE_AFTER", 0) as u32,
+            Duration::from_secs(var("SCCACHE_OFFLINE_RETRY", 30)),
+        )
+    }
+
+    /// Count a lookup of the storage, returning whether the server should
+    /// go offline.
+    pub fn lookup(&mut self, failed: bool) -> bool {
+        if !failed {
+            self.failures = 0;
+            return false;
+        }
+        self.failures += 1;
+        self.failures_to_go_offline > 0 && self.failures >= self.failures_to_go_offline
+    }
+
+    /// Count a probe of the storage, returning whether the server should go
+    /// back online.
+    pub fn probe(&mut self, answered: bool) -> bool {
+        if !answered {
+            self.answers = 0;
+            return false;
+        }
+        self.answers += 1;
+        if self.answers < PROBES_TO_RECOVER {
+            return false;
+        }
+        self.reset();
+        true
+    }
+
+    /// Forget the lookups and probes counted, as the server was taken
+    /// offline or online by a request.
+    pub fn reset(&mut self) {
+        self.failures = 0;
+        self.answers = 0;
+    }
+}
+
+/// Whether a lookup of the storage that missed as `miss_type`, or hit,
+/// failed, or `None` if the storage wasn't looked up. Only lookups that timed
+/// out or errored count, however slow a miss was, since a miss on storage
+/// that answers says nothing about whether it can be reached.
+pub fn lookup_failed(miss_type: Option<&MissType>) -> Option<bool> {
+    match miss_type {
+        None | Some(MissType::Normal) => Some(false),
+        Some(MissType::TimedOut) | Some(MissType::CacheReadError) => Some(true),
+        Some(MissType::ForcedRecache) | Some(MissType::Shed) => None,
+    }
+}
+
+/// The storage compiles use while the server is offline.
+pub enum LocalStorage {
+    /// The storage is only on the local disk, and is used as it is.
+    Same,
+    /// The local disk part of the storage.
+    Open(Arc<dyn Storage>),
+    /// The local disk cache, by directory and size, opened when the server
+    /// first goes offline, for storage without one.
+    Unopened(PathBuf, u64),
+}
+
+impl LocalStorage {
+    /// The local storage of `storage`, configured by `config`.
+    pub fn new(storage: &Arc<dyn Storage>, config: &Config) -> LocalStorage {
+        if storage.kind() == "disk" {
+            return LocalStorage::Same;
+        }
+        match storage.local() {
+            Some(local) => LocalStorage::Open(local),
+            None => LocalStorage::Unopened(
+                config.fallback_cache.dir.clone(),
+                config.fallback_cache.size,
+            ),
+        }
+    }
+
+    /// Whether the server going offline changes the storage it uses.
+    pub fn is_remote(&self) -> bool {
+        !matches!(*self, LocalStorage::Same)
+    }
+
+    /// The storage to use instead of `storage` while offline.
+    pub fn get(&mut self, storage: &Arc<dyn Storage>, pool: &ThreadPool) -> Arc<dyn Storage> {
+        if let LocalStorage::Unopened(ref dir, size) = *self {
+            info!("Opening the local disk cache at {:?} to use offline", dir);
+            *self = LocalStorage::Open(Arc::new(DiskCache::new(dir, size, pool)));
+        }
+        match *self {
+            LocalStorage::Open(ref local) => local.clone(),
+            _ => storage.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hysteresis() {
+        let mut detector = OfflineDetector::new(3, Duration::from_secs(30));
+        assert!(!detector.lookup(true));
+        assert!(!detector.lookup(true));
+        // A lookup that works starts the count again
+        assert!(!detector.lookup(false));
+        assert!(!detector.lookup(true));
+        assert!(!detector.lookup(true));
+        assert!(detector.lookup(true));
+
+        assert!(!detector.probe(true));
+        assert!(!detector.probe(false));
+        assert!(!detector.probe(true));
+        assert!(detector.probe(true));
+        assert!(!detector.lookup(true));
+
+        let mut never = OfflineDetector::new(0, Duration::from_secs(30));
+        assert!((0..10).all(|_| !never.lookup(true)));
+    }
+
+    #[test]
+    fn test_lookup_failed() {
+        assert_eq!(lookup_failed(None), Some(false));
+        assert_eq!(lookup_failed(Some(&MissType::Normal)), Some(false));
+        assert_eq!(lookup_failed(Some(&MissType::TimedOut)), Some(true));
+        assert_eq!(lookup_failed(Some(&MissType::CacheReadError)), Some(true));
+        assert_eq!(lookup_failed(Some(&MissType::Shed)), None);
+    }
+}
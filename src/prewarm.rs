@@ -0,0 +1,282 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `sccache --prewarm`: looking up the entry of every compile in a
+//! `compile_commands.json` ahead of a build, without compiling those that
+//! miss, so that storage with a local cache in front of it has the entries
+//! on the local disk when the build asks for them.
+//!
+//! `sccache --prefetch-ninja` does the same for the compiles of a Ninja build
+//! directory that the next build will run, as Ninja would tell from the
+//! mtimes of their outputs and of the inputs it recorded.
+
+use crate::client::connect_to_server;
+use crate::commands::request_prewarm;
+use crate::ninja;
+use crate::protocol::{Compile, PrewarmResult};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use which::which_in;
+
+use crate::errors::*;
+
+/// A compile in a `compile_commands.json`, with either its `arguments` or
+/// its `command` as a shell would split it.
+#[derive(Deserialize, Debug)]
+struct Entry {
+    directory: PathBuf,
+    file: PathBuf,
+    arguments: Option<Vec<String>>,
+    command: Option<String>,
+    /// What the compile writes, relative to `directory`.
+    output: Option<PathBuf>,
+}
+
+/// Look up the compiles in the `compile_commands.json` at `path` with the
+/// server on `port`, `jobs` at once, and print how many were found.
+pub fn run(port: u16, path: &Path, jobs: usize) -> Result<()> {
+    let entries: Vec<Entry> = serde_json::from_slice(
+        &fs::read(path).with_context(|| format!("failed to read {}", path.display()))?,
+    )
+    .with_context(|| format!("failed to parse {}", path.display()))?;
+    prewarm(port, entries, jobs)
+}
+
+/// Look up the compiles that are out of date in the Ninja build directory
+/// `build_dir` with the server on `port`, `jobs` at once, and print how many
+/// were found.
+pub fn run_ninja(port: u16, build_dir: &Path, jobs: usize) -> Result<()> {
+    let entries: Vec<Entry> = serde_json::from_slice(&ninja::compile_commands(build_dir)?)
+        .context("failed to parse the output of ninja -t compdb")?;
+    let deps_log = build_dir.join(".ninja_deps");
+    let deps = match fs::read(&deps_log) {
+        Ok(data) => ninja::read_deps_log(&data)
+            .with_context(|| format!("failed to read {}", deps_log.display()))?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", deps_log.display())),
+    };
+    let total = entries.len();
+    let entries: Vec<_> = entries
+        .into_iter()
+        .filter(|entry| is_out_of_date(entry, &deps))
+        .collect();
+    println!("{} of {} edges are out of date", entries.len(), total);
+    prewarm(port, entries, jobs)
+}
+
+/// Whether Ninja would run the compile of `entry`, from the inputs recorded
+/// for its output in `deps` or in the depfile it writes.
+fn is_out_of_date(entry: &Entry, deps: &HashMap<PathBuf, Vec<PathBuf>>) -> bool {
+    let output = match entry.output {
+        Some(ref output) => output,
+        // Ninja always names it
+        None => return true,
+    };
+    let depfile_inputs;
+    let inputs = match deps.get(output) {
+        Some(inputs) => inputs,
+        None => {
+            let depfile = command_line(entry)
+                .ok()
+                .and_then(|args| ninja::depfile_argument(&args).map(PathBuf::from));
+            depfile_inputs = depfile
+                .and_then(|depfile| fs::read_to_string(entry.directory.join(depfile)).ok())
+                .map_or_else(Vec::new, |depfile| ninja::read_depfile(&depfile));
+            &depfile_inputs
+        }
+    };
+    // The paths are relative to the build directory, which Ninja runs the
+    // compiles in
+    ninja::is_out_of_date(
+        &entry.directory,
+        output,
+        std::iter::once(entry.file.as_path()).chain(inputs.iter().map(PathBuf::as_path)),
+    )
+}
+
+/// Look up `entries` with the server on `port`, `jobs` at once, and print
+/// how many were found.
+fn prewarm(port: u16, entries: Vec<Entry>, jobs: usize) -> Result<()> {
+    let total = entries.len();
+    let queue = Arc::new(Mutex::new(entries.into_iter()));
+    let workers: Vec<_> = (0..jobs.max(1))
+        .map(|_| {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                let mut results = vec![];
+                loop {
+                    let entry = match queue.lock().unwrap().next() {
+                        Some(entry) => entry,
+                        None => return results,
+                    };
+                    let result = compile_of(&entry)
+                        .and_then(|compile| request_prewarm(connect_to_server(port)?, compile))
+                        .unwrap_or_else(|e| PrewarmResult::Failed(format!("{:#}", e)));
+                    results.push((entry.directory.join(entry.file), result));
+                }
+            })
+        })
+        .collect();
+
+    let (mut hits, mut misses, mut not_cacheable, mut failed) = (0, 0, 0, 0);
+    for worker in workers {
+        let results = worker
+            .join()
+            .map_err(|_| anyhow!("a prewarm thread panicked"))?;
+        for (file, result) in results {
+            match result {
+                PrewarmResult::Hit => hits += 1,
+                PrewarmResult::Miss => misses += 1,
+                PrewarmResult::NotCacheable(_) => not_cacheable += 1,
+                PrewarmResult::Failed(e) => {
+                    eprintln!("sccache: {}: {}", file.display(), e);
+                    failed += 1;
+                }
+            }
+        }
+    }
+    println!(
+        "Looked up {} compiles: {} hits, {} misses, {} not cacheable, {} failed",
+        total, hits, misses, not_cacheable, failed
+    );
+    Ok(())
+}
+
+/// The compile request for `entry`, run in its directory with this
+/// process's environment.
+fn compile_of(entry: &Entry) -> Result<Compile> {
+    let mut args = command_line(entry)?.into_iter();
+    let exe = args.next().context("the entry has no command")?;
+    let exe = which_in(&exe, env::var_os("PATH"), &entry.directory)
+        .with_context(|| format!("failed to find {}", exe))?;
+    Ok(Compile {
+        exe: exe.into(),
+        cwd: entry.directory.clone().into(),
+        args: args.map(Into::into).collect(),
+        env_vars: env::vars_os().collect(),
+    })
+}
+
+/// The command line of `entry`, without sccache if it's run through it.
+fn command_line(entry: &Entry) -> Result<Vec<String>> {
+    let mut args = match (&entry.arguments, &entry.command) {
+        (Some(arguments), _) => arguments.clone(),
+        (None, Some(command)) => split_command(command)?,
+        (None, None) => bail!("the entry has neither arguments nor a command"),
+    };
+    let is_sccache = args
+        .first()
+        .and_then(|exe| Path::new(exe).file_stem())
+        .map_or(false, |stem| stem == "sccache");
+    if is_sccache {
+        args.remove(0);
+    }
+    Ok(args)
+}
+
+/// Split `command` into arguments as a POSIX shell would, with quotes and
+/// backslashes but no expansions.
+fn split_command(command: &str) -> Result<Vec<String>> {
+    let mut args = vec![];
+    let mut arg: Option<String> = None;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => args.extend(arg.take()),
+            '\'' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => arg.push(c),
+                        None => bail!("unterminated ' in {:?}", command),
+                    }
+                }
+            }
+            '"' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) if "\\\"$`".contains(c) => arg.push(c),
+                            Some(c) => {
+                                arg.push('\\');
+                                arg.push(c);
+                            }
+                            None => bail!("unterminated \" in {:?}", command),
+                        },
+                        Some(c) => arg.push(c),
+                        None => bail!("unterminated \" in {:?}", command),
+                    }
+                }
+            }
+            '\\' => {
+                let c = chars
+                    .next()
+                    .with_context(|| format!("trailing \\ in {:?}", command))?;
+                arg.get_or_insert_with(String::new).push(c);
+            }
+            c => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(arg);
+    Ok(args)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_command() {
+        assert_eq!(
+            split_command(r#"cc -DA="a b" -DB='c "d"' -DC=e\ f "" -I/x  file.c"#).unwrap(),
+            [
+                "cc",
+                "-DA=a b",
+                r#"-DB=c "d""#,
+                "-DC=e f",
+                "",
+                "-I/x",
+                "file.c"
+            ]
+        );
+        assert_eq!(
+            split_command(r#"cc "-DA=\"x\" \y""#).unwrap(),
+            ["cc", r#"-DA="x" \y"#]
+        );
+        assert!(split_command("cc 'file.c").is_err());
+    }
+
+    #[test]
+    fn test_command_line() {
+        let entries: Vec<Entry> = serde_json::from_str(
+            r#"[
+                {"directory": "/b", "file": "a.c", "arguments": ["cc", "-c", "a.c"]},
+                {"directory": "/b", "file": "b.c", "command": "/usr/bin/sccache cc -c 'b.c'"},
+                {"directory": "/b", "file": "c.c"}
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(command_line(&entries[0]).unwrap(), ["cc", "-c", "a.c"]);
+        assert_eq!(command_line(&entries[1]).unwrap(), ["cc", "-c", "b.c"]);
+        assert!(command_line(&entries[2]).is_err());
+    }
+}
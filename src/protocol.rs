@@ -1,8 +1,19 @@
+use crate::cache::ClearScope;
 use crate::compiler::ColorMode;
-use crate::server::{DistInfo, ServerInfo};
+use crate::server::{DistStatus, ServerInfo, StatsDelta, TopSnapshot};
 use std::ffi::OsString;
 
+/// The version of the protocol, changed whenever a request or response
+/// changes other than by adding requests or responses at the end.
+///
+/// `Compile` and `Run`, and their responses, are kept the same so that any
+/// client can compile with any server, without a handshake. Other requests
+/// need the client and server to have the same version.
+pub const PROTOCOL_VERSION: u32 = 8;
+
 /// A client request.
+///
+/// Requests are encoded by their position, so new ones go at the end.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Request {
     /// Zero the server's statistics.
@@ -11,10 +22,39 @@ pub enum Request {
     GetStats,
     /// Get dist status.
     DistStatus,
+    /// Remove the toolchains packaged for distributed compilation.
+    ClearToolchainCache,
+    /// Re-read the config file, and use the cache it configures.
+    ReloadConfig,
     /// Shut the server down gracefully.
     Shutdown,
     /// Execute a compile or fetch a cached compilation result.
     Compile(Compile),
+    /// Execute a command with declared inputs and outputs or fetch its
+    /// cached outputs. Answered like `Compile`.
+    Run(Run),
+    /// Open the named build session, keeping the server from shutting down
+    /// when it's idle until it's ended.
+    StartSession(String),
+    /// End the named build session.
+    EndSession(String),
+    /// Get the statistics of the named project.
+    GetProjectStats(String),
+    /// Zero the statistics of the named project.
+    ZeroProjectStats(String),
+    /// Tell the server which version the client is, and ask which it is.
+    /// Servers from before this was added close the connection.
+    Hello(Hello),
+    /// Administer the server, with the token it wrote for its user.
+    Admin(String, AdminRequest),
+    /// Stream updates of the stats until the server shuts down.
+    WatchStats,
+    /// Stream what the server is doing every second, until it shuts down.
+    Top,
+    /// Hash a compile and look up its entry, without compiling it.
+    Prewarm(Compile),
+    /// Parse and hash a compile, without looking it up or compiling it.
+    DryRun(Compile),
 }
 
 /// A server response.
@@ -22,14 +62,125 @@ pub enum Request {
 pub enum Response {
     /// Response for `Request::Compile`.
     Compile(CompileResponse),
-    /// Response for `Request::GetStats`, containing server statistics.
+    /// Response for `Request::GetStats` and the other requests for stats,
+    /// containing server statistics.
     Stats(Box<ServerInfo>),
     /// Response for `Request::DistStatus`, containing client info.
-    DistStatus(DistInfo),
+    DistStatus(DistStatus),
+    /// Response for `Request::ClearToolchainCache`, containing the bytes freed.
+    ToolchainCacheCleared(u64),
+    /// Response for `Request::ReloadConfig`, containing server statistics.
+    ConfigReloaded(Box<ServerInfo>),
+    /// Response for `Request::ReloadConfig` when the config couldn't be loaded.
+    ConfigReloadFailed(String),
     /// Response for `Request::Shutdown`, containing server statistics.
     ShuttingDown(Box<ServerInfo>),
     /// Second response for `Request::Compile`, containing the results of the compilation.
     CompileFinished(CompileFinished),
+    /// Response for `Request::StartSession` and `Request::EndSession`,
+    /// containing the names of the sessions still open.
+    Sessions(Vec<String>),
+    /// Response for `Request::Hello`.
+    Hello(Hello),
+    /// Response for `Request::Admin`.
+    Admin(AdminResponse),
+    /// Response for `Request::WatchStats`, sent whenever the stats change.
+    StatsDelta(StatsDelta),
+    /// The last response for `Request::WatchStats` and `Request::Top`, as
+    /// the server shuts down.
+    StatsWatchEnded,
+    /// Response for `Request::Top`, sent every second.
+    TopSnapshot(Box<TopSnapshot>),
+    /// Response for `Request::Prewarm`.
+    Prewarmed(PrewarmResult),
+    /// Response for `Request::DryRun`.
+    DryRun(DryRunResult),
+}
+
+/// What to do to the server, for debugging its cache.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum AdminRequest {
+    /// Forget the compilers the server has detected, and remove every entry
+    /// of its storage, which only local disk storage supports.
+    Flush,
+    /// Look up a cache key in each part of the storage.
+    QueryKey(String),
+    /// Stop writing to the storage, or start again.
+    SetReadOnly(bool),
+    /// Use only the local disk cache, without distributing compiles, or
+    /// go back to using remote storage.
+    SetOffline(bool),
+    /// Remove every entry of the storage in the scope.
+    Clear(ClearScope),
+}
+
+/// The result of an `AdminRequest`.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum AdminResponse {
+    /// The token was wrong, or the server doesn't take admin requests.
+    Denied,
+    /// The compilers were forgotten, with the error flushing the storage if
+    /// it couldn't be.
+    Flushed(Result<(), String>),
+    /// Whether the key was found in each part of the storage, by location.
+    KeyStatus(Vec<(String, Result<bool, String>)>),
+    /// Whether the server is now read-only.
+    ReadOnly(bool),
+    /// Whether the server is now offline.
+    Offline(bool),
+    /// The storage was cleared, or the error clearing it.
+    Cleared(Result<(), String>),
+}
+
+/// What was found looking up the entry of a compile for `Request::Prewarm`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum PrewarmResult {
+    Hit,
+    Miss,
+    /// The compile can't be cached, for the reason given.
+    NotCacheable(String),
+    /// The compile couldn't be hashed or looked up, with the error.
+    Failed(String),
+}
+
+/// Whether a compile could be cached, for `Request::DryRun`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum DryRunResult {
+    /// The compile would be cached under `key`, in the namespace of
+    /// unpinned toolchains if the compiler isn't `pinned`.
+    Cacheable {
+        compiler: String,
+        key: String,
+        pinned: bool,
+    },
+    /// The compile can't be cached, for the reason given, with the
+    /// argument or error behind it if there is one.
+    NotCacheable {
+        reason: String,
+        detail: Option<String>,
+    },
+    /// The compiler isn't supported, or the compile couldn't be hashed, with
+    /// the error.
+    Failed(String),
+}
+
+/// The version of an sccache client or server.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Hello {
+    /// The version of sccache.
+    pub version: String,
+    /// Its `PROTOCOL_VERSION`.
+    pub protocol: u32,
+}
+
+impl Hello {
+    /// The version of this sccache.
+    pub fn current() -> Hello {
+        Hello {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            protocol: PROTOCOL_VERSION,
+        }
+    }
 }
 
 /// Possible responses from the server for a `Compile` request.
@@ -70,3 +221,20 @@ pub struct Compile {
     /// The environment variables present when the compiler was executed, as (var, val).
     pub env_vars: Vec<(OsString, OsString)>,
 }
+
+/// The contents of a run request from a client.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Run {
+    /// The full path to the executable.
+    pub exe: OsString,
+    /// The current working directory in which to execute the command.
+    pub cwd: OsString,
+    /// The commandline arguments passed to the executable.
+    pub args: Vec<OsString>,
+    /// The environment variables present when the command was executed, as (var, val).
+    pub env_vars: Vec<(OsString, OsString)>,
+    /// The files the command reads.
+    pub inputs: Vec<OsString>,
+    /// The files the command writes.
+    pub outputs: Vec<OsString>,
+}
@@ -0,0 +1,504 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Credentials for the storage backends, fetched from HashiCorp Vault or
+//! AWS Secrets Manager as the server starts, and again before they expire,
+//! so that it needn't be given long-lived keys.
+//!
+//! Each field of the secret stands for the variable of the same name, such
+//! as `AWS_SECRET_ACCESS_KEY` or `SCCACHE_REDIS_PASSWORD`, which
+//! `util::secret_var` looks it up by when the variable isn't set.
+
+// Without the secrets feature there's never a secret to look fields up in
+#![cfg_attr(not(feature = "secrets"), allow(dead_code))]
+
+use serde_json::Value;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::env;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::errors::*;
+
+/// How often to fetch a secret without a lease, in case it's been rotated.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How long to wait to try again after failing to fetch a secret.
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Where Kubernetes mounts the token of the pod's service account.
+const K8S_TOKEN_PATH: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
+
+lazy_static! {
+    static ref FIELDS: Mutex<Option<HashMap<String, String>>> = Mutex::new(None);
+}
+
+thread_local! {
+    /// Set while fetching the secret, whose own credentials mustn't come
+    /// from it.
+    static FETCHING: Cell<bool> = Cell::new(false);
+}
+
+/// The fields of a fetched secret, and how long they're valid for, if
+/// they expire.
+#[derive(Debug, PartialEq)]
+struct Secret {
+    fields: HashMap<String, String>,
+    lease: Option<Duration>,
+}
+
+impl Secret {
+    fn new(fields: &serde_json::Map<String, Value>, lease: Option<Duration>) -> Secret {
+        let mut fields: HashMap<_, _> = fields
+            .iter()
+            .filter_map(|(name, value)| {
+                let value = match value {
+                    Value::Null => return None,
+                    Value::String(s) => s.clone(),
+                    value => value.to_string(),
+                };
+                Some((name.clone(), value))
+            })
+            .collect();
+        // As Vault's AWS secrets engine names the credentials it issues
+        for &(field, var) in &[
+            ("access_key", "AWS_ACCESS_KEY_ID"),
+            ("secret_key", "AWS_SECRET_ACCESS_KEY"),
+            ("security_token", "AWS_SESSION_TOKEN"),
+        ] {
+            if let Some(value) = fields.get(field).cloned() {
+                fields.entry(var.to_owned()).or_insert(value);
+            }
+        }
+        Secret { fields, lease }
+    }
+
+    /// How long until the secret should be fetched again: two thirds of
+    /// its lease, so that there's time to try again if that fails.
+    fn refresh_in(&self) -> Duration {
+        match self.lease {
+            Some(lease) => std::cmp::max(lease * 2 / 3, RETRY_INTERVAL),
+            None => REFRESH_INTERVAL,
+        }
+    }
+}
+
+/// How to log in to Vault.
+#[derive(Debug)]
+enum VaultAuth {
+    Token(String),
+    AppRole { role_id: String, secret_id: String },
+    Kubernetes { role: String, token_path: PathBuf },
+}
+
+/// Where the secret is kept.
+#[derive(Debug)]
+enum Provider {
+    Vault {
+        addr: String,
+        path: String,
+        auth: VaultAuth,
+    },
+    SecretsManager {
+        secret_id: String,
+        region: String,
+    },
+}
+
+impl Provider {
+    /// The provider configured by the environment, if there is one.
+    fn from_env() -> Result<Option<Provider>> {
+        let var = |name: &str| env::var(name).ok().filter(|v: &String| !v.is_empty());
+        match (var("SCCACHE_VAULT_SECRET"), var("SCCACHE_AWS_SECRET")) {
+            (Some(_), Some(_)) => {
+                bail!("Only one of SCCACHE_VAULT_SECRET and SCCACHE_AWS_SECRET can be set")
+            }
+            (Some(path), None) => {
+                let addr = var("SCCACHE_VAULT_ADDR")
+                    .or_else(|| var("VAULT_ADDR"))
+                    .context(
+                        "SCCACHE_VAULT_SECRET is set, but not SCCACHE_VAULT_ADDR or VAULT_ADDR",
+                    )?;
+                let auth = if let Some(role_id) = var("SCCACHE_VAULT_ROLE_ID") {
+                    let secret_id = crate::util::secret_var("SCCACHE_VAULT_SECRET_ID")?
+                        .context("SCCACHE_VAULT_ROLE_ID is set, but not SCCACHE_VAULT_SECRET_ID")?;
+                    VaultAuth::AppRole { role_id, secret_id }
+                } else if let Some(role) = var("SCCACHE_VAULT_K8S_ROLE") {
+                    let token_path = var("SCCACHE_VAULT_K8S_TOKEN_PATH")
+                        .unwrap_or_else(|| K8S_TOKEN_PATH.to_owned());
+                    VaultAuth::Kubernetes {
+                        role,
+                        token_path: token_path.into(),
+                    }
+                } else if let Some(token) = crate::util::secret_var("VAULT_TOKEN")? {
+                    VaultAuth::Token(token)
+                } else {
+                    bail!(
+                        "SCCACHE_VAULT_SECRET is set, but not SCCACHE_VAULT_ROLE_ID, \
+                         SCCACHE_VAULT_K8S_ROLE or VAULT_TOKEN to log in to Vault with"
+                    )
+                };
+                Ok(Some(Provider::Vault {
+                    addr: addr.trim_end_matches('/').to_owned(),
+                    path: path.trim_matches('/').to_owned(),
+                    auth,
+                }))
+            }
+            (None, Some(secret_id)) => {
+                // The region is part of the ARN of a secret, if it's named by that
+                let region = match secret_id.split(':').nth(3) {
+                    Some(region) if secret_id.starts_with("arn:") => Some(region.to_owned()),
+                    _ => var("AWS_REGION").or_else(|| var("AWS_DEFAULT_REGION")),
+                };
+                let region = region.context(
+                    "SCCACHE_AWS_SECRET is set, but not AWS_REGION or AWS_DEFAULT_REGION",
+                )?;
+                Ok(Some(Provider::SecretsManager { secret_id, region }))
+            }
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Provider::Vault { addr, path, .. } => format!("Vault secret {} at {}", path, addr),
+            Provider::SecretsManager { secret_id, region } => {
+                format!("AWS Secrets Manager secret {} in {}", secret_id, region)
+            }
+        }
+    }
+}
+
+/// Fetch the secret configured by the environment, if there is one, and
+/// keep fetching it again before it expires, for the life of the process.
+pub fn init() -> Result<()> {
+    let provider = match Provider::from_env()? {
+        Some(provider) => provider,
+        None => return Ok(()),
+    };
+    #[cfg(feature = "secrets")]
+    {
+        info!("Fetching credentials from {}", provider.describe());
+        let secret =
+            fetch(&provider).with_context(|| format!("Failed to fetch {}", provider.describe()))?;
+        let refresh_in = secret.refresh_in();
+        *FIELDS.lock().unwrap() = Some(secret.fields);
+        std::thread::spawn(move || refresh(provider, refresh_in));
+    }
+    #[cfg(not(feature = "secrets"))]
+    warn!(
+        "sccache was built without the secrets feature, not fetching {}",
+        provider.describe()
+    );
+    Ok(())
+}
+
+#[cfg(feature = "secrets")]
+fn refresh(provider: Provider, mut refresh_in: Duration) {
+    loop {
+        std::thread::sleep(refresh_in);
+        refresh_in = match fetch(&provider) {
+            Ok(secret) => {
+                debug!("Fetched {} again", provider.describe());
+                let refresh_in = secret.refresh_in();
+                *FIELDS.lock().unwrap() = Some(secret.fields);
+                refresh_in
+            }
+            Err(e) => {
+                warn!("Failed to fetch {}: {}", provider.describe(), e);
+                RETRY_INTERVAL
+            }
+        };
+    }
+}
+
+/// The field `name` of the secret, if one's been fetched.
+pub fn get(name: &str) -> Option<String> {
+    if FETCHING.with(Cell::get) {
+        return None;
+    }
+    FIELDS.lock().unwrap().as_ref()?.get(name).cloned()
+}
+
+#[cfg(feature = "secrets")]
+fn fetch(provider: &Provider) -> Result<Secret> {
+    FETCHING.with(|fetching| fetching.set(true));
+    let secret = match provider {
+        Provider::Vault { addr, path, auth } => fetch_vault(addr, path, auth),
+        Provider::SecretsManager { secret_id, region } => fetch_secrets_manager(secret_id, region),
+    };
+    FETCHING.with(|fetching| fetching.set(false));
+    secret
+}
+
+#[cfg(feature = "secrets")]
+fn fetch_vault(addr: &str, path: &str, auth: &VaultAuth) -> Result<Secret> {
+    let client = reqwest::blocking::Client::new();
+    // Log in again for each fetch, rather than keep a token to renew
+    let token = match auth {
+        VaultAuth::Token(token) => token.clone(),
+        VaultAuth::AppRole { role_id, secret_id } => {
+            let login = serde_json::json!({ "role_id": role_id, "secret_id": secret_id });
+            vault_login(&client, addr, "approle", &login)?
+        }
+        VaultAuth::Kubernetes { role, token_path } => {
+            let jwt = std::fs::read_to_string(token_path).with_context(|| {
+                format!(
+                    "Failed to read service account token {}",
+                    token_path.display()
+                )
+            })?;
+            let login = serde_json::json!({ "role": role, "jwt": jwt.trim() });
+            vault_login(&client, addr, "kubernetes", &login)?
+        }
+    };
+    let res: Value = client
+        .get(&format!("{}/v1/{}", addr, path))
+        .header("X-Vault-Token", token)
+        .send()
+        .and_then(|res| res.error_for_status())
+        .and_then(|res| res.json())
+        .context("Failed to read the secret")?;
+    vault_secret(&res)
+}
+
+/// Log in to Vault with the auth method mounted at `mount`, for a token.
+#[cfg(feature = "secrets")]
+fn vault_login(
+    client: &reqwest::blocking::Client,
+    addr: &str,
+    mount: &str,
+    login: &Value,
+) -> Result<String> {
+    let res: Value = client
+        .post(&format!("{}/v1/auth/{}/login", addr, mount))
+        .json(login)
+        .send()
+        .and_then(|res| res.error_for_status())
+        .and_then(|res| res.json())
+        .with_context(|| format!("Failed to log in to Vault with {}", mount))?;
+    let token = res
+        .pointer("/auth/client_token")
+        .and_then(Value::as_str)
+        .context("Vault's login response has no client token")?;
+    Ok(token.to_owned())
+}
+
+/// The secret in Vault's response to reading it.
+fn vault_secret(res: &Value) -> Result<Secret> {
+    let data = res.get("data").context("Vault's response has no data")?;
+    // Version 2 of the key/value secrets engine puts the secret in `data`
+    // next to its metadata
+    let fields = match (data.get("data"), data.get("metadata")) {
+        (Some(fields), Some(_)) => fields,
+        _ => data,
+    };
+    let fields = fields
+        .as_object()
+        .context("The secret in Vault's response isn't an object")?;
+    let lease = res
+        .get("lease_duration")
+        .and_then(Value::as_u64)
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs);
+    Ok(Secret::new(fields, lease))
+}
+
+#[cfg(feature = "secrets")]
+fn fetch_secrets_manager(secret_id: &str, region: &str) -> Result<Secret> {
+    use crate::simples3::{ChainProvider, ProvideAwsCredentials};
+
+    let creds = crate::util::runtime()?.block_on(ChainProvider::new().credentials())?;
+    let host = format!("secretsmanager.{}.amazonaws.com", region);
+    let body = serde_json::json!({ "SecretId": secret_id }).to_string();
+    let date = chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+    let mut headers = vec![
+        ("content-type", "application/x-amz-json-1.1".to_owned()),
+        ("host", host.clone()),
+        ("x-amz-date", date),
+        ("x-amz-target", "secretsmanager.GetSecretValue".to_owned()),
+    ];
+    if let Some(token) = creds.token() {
+        headers.insert(3, ("x-amz-security-token", token.clone()));
+    }
+    let authorization = sign_v4(&creds, "secretsmanager", region, "POST", &headers, &body);
+    let mut req = reqwest::blocking::Client::new().post(&format!("https://{}/", host));
+    for (name, value) in headers {
+        if name != "host" {
+            req = req.header(name, value);
+        }
+    }
+    let res: Value = req
+        .header("authorization", authorization)
+        .body(body)
+        .send()
+        .and_then(|res| res.error_for_status())
+        .and_then(|res| res.json())
+        .context("Failed to get the secret's value")?;
+    secrets_manager_secret(&res)
+}
+
+/// The secret in Secrets Manager's response to `GetSecretValue`.
+fn secrets_manager_secret(res: &Value) -> Result<Secret> {
+    let string = res
+        .get("SecretString")
+        .and_then(Value::as_str)
+        .context("The secret has no string value")?;
+    let fields: Value = serde_json::from_str(string).context("The secret's value isn't JSON")?;
+    let fields = fields
+        .as_object()
+        .context("The secret's value isn't a JSON object")?;
+    // Secrets Manager rotates secrets, rather than leasing them
+    Ok(Secret::new(fields, None))
+}
+
+/// The `Authorization` header of a request to the root of an AWS `service`
+/// in `region`, signed with version 4 of AWS's signing process. `headers`
+/// are those to sign, lowercase and in order, including `host` and
+/// `x-amz-date`.
+#[cfg(feature = "secrets")]
+fn sign_v4(
+    creds: &crate::simples3::AwsCredentials,
+    service: &str,
+    region: &str,
+    method: &str,
+    headers: &[(&str, String)],
+    body: &str,
+) -> String {
+    use hmac::{Hmac, Mac, NewMac};
+    use sha2::{Digest, Sha256};
+
+    fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+        let mut hmac = Hmac::<Sha256>::new_varkey(key).expect("HMAC can take key of any size");
+        hmac.update(data.as_bytes());
+        hmac.finalize().into_bytes().to_vec()
+    }
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    let date = headers
+        .iter()
+        .find(|(name, _)| *name == "x-amz-date")
+        .map(|(_, date)| date.as_str())
+        .expect("x-amz-date must be signed");
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|&(name, _)| name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "{}\n/\n\n{}\n{}\n{}",
+        method,
+        canonical_headers,
+        signed_headers,
+        hex(&Sha256::digest(body.as_bytes()))
+    );
+    let scope = format!("{}/{}/{}/aws4_request", &date[..8], region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        date,
+        scope,
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+    let key = format!("AWS4{}", creds.aws_secret_access_key());
+    let key = hmac(key.as_bytes(), &date[..8]);
+    let key = hmac(&key, region);
+    let key = hmac(&key, service);
+    let key = hmac(&key, "aws4_request");
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        creds.aws_access_key_id(),
+        scope,
+        signed_headers,
+        hex(&hmac(&key, &string_to_sign))
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_vault_secret() {
+        // A key/value secret, of version 2 of the engine
+        let secret = vault_secret(&json!({
+            "lease_duration": 0,
+            "data": {
+                "data": { "SCCACHE_REDIS_PASSWORD": "hunter2", "port": 6379 },
+                "metadata": { "version": 3 },
+            },
+        }))
+        .unwrap();
+        assert_eq!(secret.fields["SCCACHE_REDIS_PASSWORD"], "hunter2");
+        assert_eq!(secret.fields["port"], "6379");
+        assert_eq!(secret.lease, None);
+        assert_eq!(secret.refresh_in(), REFRESH_INTERVAL);
+
+        // Credentials leased by the AWS secrets engine
+        let secret = vault_secret(&json!({
+            "lease_duration": 3600,
+            "data": { "access_key": "AKIA", "secret_key": "secret", "security_token": null },
+        }))
+        .unwrap();
+        assert_eq!(secret.fields["AWS_ACCESS_KEY_ID"], "AKIA");
+        assert_eq!(secret.fields["AWS_SECRET_ACCESS_KEY"], "secret");
+        assert!(!secret.fields.contains_key("AWS_SESSION_TOKEN"));
+        assert_eq!(secret.refresh_in(), Duration::from_secs(2400));
+
+        assert!(vault_secret(&json!({ "errors": [] })).is_err());
+    }
+
+    #[test]
+    fn test_secrets_manager_secret() {
+        let secret = secrets_manager_secret(&json!({
+            "Name": "sccache",
+            "SecretString": "{\"AWS_ACCESS_KEY_ID\": \"AKIA\"}",
+        }))
+        .unwrap();
+        assert_eq!(secret.fields["AWS_ACCESS_KEY_ID"], "AKIA");
+        assert_eq!(secret.lease, None);
+
+        assert!(secrets_manager_secret(&json!({ "SecretString": "hunter2" })).is_err());
+        assert!(secrets_manager_secret(&json!({ "SecretBinary": "aHVudGVyMg==" })).is_err());
+    }
+
+    #[cfg(feature = "secrets")]
+    #[test]
+    fn test_sign_v4() {
+        // The get-vanilla case of AWS's test suite
+        let creds = crate::simples3::AwsCredentials::new(
+            "AKIDEXAMPLE",
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            None,
+            chrono::Utc::now(),
+        );
+        let headers = [
+            ("host", "example.amazonaws.com".to_owned()),
+            ("x-amz-date", "20150830T123600Z".to_owned()),
+        ];
+        assert_eq!(
+            sign_v4(&creds, "service", "us-east-1", "GET", &headers, ""),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+    }
+}
@@ -12,64 +12,88 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-// For tokio_io::codec::length_delimited::Framed;
-#![allow(deprecated)]
-
-use crate::cache::{storage_from_config, Storage};
+#[cfg(feature = "hyper")]
+use crate::bazel::BazelCache;
+use crate::cache::audit::AuditedStorage;
+use crate::cache::layered::LayeredStorage;
+use crate::cache::limited::{LimitedStorage, Overloaded};
+use crate::cache::metered::{BackendMeters, BackendStats, MeteredStorage};
+use crate::cache::{
+    check_storage, storage_from_config, Cache, ClearScope, NamespacedStorage, ReadOnly,
+    ReadOnlyStorage, Storage,
+};
 use crate::compiler::{
-    get_compiler_info, CacheControl, CompileResult, Compiler, CompilerArguments, CompilerHasher,
-    CompilerKind, CompilerProxy, DistType, MissType,
+    compiler_digest, get_compiler_info, save_input_digests, set_compiler_digest_cache,
+    set_input_digest_cache, CacheControl, CommandHasher, CompileResult, Compiler,
+    CompilerArguments, CompilerDigestCache, CompilerHasher, CompilerKind, CompilerProxy, DistType,
+    HashResult, HitCheck, InputDigestCache, MissType,
 };
-#[cfg(feature = "dist-client")]
 use crate::config;
-use crate::config::Config;
+use crate::config::{
+    CompilerCheck, Config, HashConfig, ProjectConfig, ProjectsConfig, ToolchainPinsConfig,
+    UnpinnedToolchains,
+};
 use crate::dist;
-use crate::jobserver::Client;
+use crate::jobserver::{self, Client};
+use crate::metrics;
 use crate::mock_command::{CommandCreatorSync, ProcessCommandCreator};
-use crate::protocol::{Compile, CompileFinished, CompileResponse, Request, Response};
+use crate::offline::{self, LocalStorage, NetworkMode, OfflineDetector};
+use crate::protocol::{
+    AdminRequest, AdminResponse, Compile, CompileFinished, CompileResponse, DryRunResult, Hello,
+    PrewarmResult, Request, Response, Run,
+};
+use crate::statsd;
+#[cfg(unix)]
+use crate::systemd;
+use crate::trace::{self, Span};
 use crate::util;
 #[cfg(feature = "dist-client")]
+use crate::util::SpawnExt;
 use anyhow::Context as _;
+use bytes::Bytes;
 use filetime::FileTime;
-use futures::sync::mpsc;
-use futures::{future, stream, Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
-use futures_03::compat::Compat;
-use futures_03::executor::ThreadPool;
+use futures::channel::mpsc;
+use futures::executor::ThreadPool;
+use futures::future::{self, LocalBoxFuture};
+use futures::{FutureExt, SinkExt, Stream, StreamExt};
 use number_prefix::NumberPrefix;
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::env;
 use std::ffi::{OsStr, OsString};
+#[cfg(unix)]
+use std::fs;
 use std::fs::metadata;
+use std::future::Future;
 use std::io::{self, Write};
-#[cfg(feature = "dist-client")]
+#[cfg(any(feature = "dist-client", windows))]
 use std::mem;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::process::{ExitStatus, Output};
 use std::rc::Rc;
 use std::sync::Arc;
-#[cfg(feature = "dist-client")]
-use std::sync::Mutex;
 use std::task::{Context, Waker};
 use std::time::Duration;
 use std::time::Instant;
 use std::u64;
-use tokio_compat::runtime::current_thread::Runtime;
-use tokio_io::codec::length_delimited;
-use tokio_io::codec::length_delimited::Framed;
-use tokio_io::{AsyncRead, AsyncWrite};
-use tokio_serde_bincode::{ReadBincode, WriteBincode};
-use tokio_tcp::TcpListener;
-use tokio_timer::{Delay, Timeout};
-use tower::Service;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+use tokio::task::{self, LocalSet};
+use tokio_util::codec::LengthDelimitedCodec;
 
 use crate::errors::*;
 
 /// If the server is idle for this many seconds, shut down.
 const DEFAULT_IDLE_TIMEOUT: u64 = 600;
 
+/// If the server is shutting down, how long to wait for compiles in progress
+/// and their cache writes to finish. In seconds.
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 30;
+
 /// If the dist client couldn't be created, retry creation at this number
 /// of seconds from now (or later)
 #[cfg(feature = "dist-client")]
@@ -88,13 +112,154 @@ pub enum ServerStartup {
     Err { reason: String },
 }
 
-/// Get the time the server should idle for before shutting down.
-fn get_idle_timeout() -> u64 {
-    // A value of 0 disables idle shutdown entirely.
-    env::var("SCCACHE_IDLE_TIMEOUT")
+fn compiler_digest_cache(config: &Config) -> Option<CompilerDigestCache> {
+    match config.hash.compiler_check {
+        Some(CompilerCheck::Content) => None,
+        Some(CompilerCheck::Mtime) | None => Some(CompilerDigestCache::load(
+            config.fallback_cache.dir.join("compiler_digests.json"),
+        )),
+    }
+}
+
+fn input_digest_cache(config: &Config) -> Option<InputDigestCache> {
+    match config.hash.input_check {
+        Some(CompilerCheck::Content) => None,
+        Some(CompilerCheck::Mtime) | None => Some(InputDigestCache::load(
+            config.fallback_cache.dir.join("input_digests.json"),
+        )),
+    }
+}
+
+/// How often the stats are written to their file, if they changed.
+const STATS_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The file in the cache dir the stats are kept in across restarts.
+fn stats_file(config: &Config) -> PathBuf {
+    config.fallback_cache.dir.join("stats.json")
+}
+
+/// Get the address to listen on set in the variable `var`, if any: that
+/// of `SCCACHE_METRICS_ADDR` to serve Prometheus metrics on, or that of
+/// `SCCACHE_BAZEL_CACHE_ADDR` to serve the storage to Bazel on.
+fn get_listen_addr(var: &str) -> Option<SocketAddr> {
+    let addr = env::var(var).ok()?;
+    match addr.parse() {
+        Ok(addr) => Some(addr),
+        Err(_) => {
+            warn!("Ignoring invalid {}: {}", var, addr);
+            None
+        }
+    }
+}
+
+/// Get the path of the Unix socket to listen on alongside the TCP port, if
+/// any: `SCCACHE_SERVER_UDS`, or one for `port` in `XDG_RUNTIME_DIR`. An
+/// empty `SCCACHE_SERVER_UDS` disables it.
+#[cfg(unix)]
+pub fn get_socket_path(port: u16) -> Option<PathBuf> {
+    match env::var_os("SCCACHE_SERVER_UDS") {
+        Some(path) if path.is_empty() => None,
+        Some(path) => Some(path.into()),
+        None => {
+            let dir = env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty())?;
+            Some(PathBuf::from(dir).join(format!("sccache-{}.sock", port)))
+        }
+    }
+}
+
+/// Get the path of the file with the token that admin requests to the server
+/// on `port` need: `SCCACHE_ADMIN_TOKEN_PATH`, or one for `port` in the
+/// user's data directory. An empty `SCCACHE_ADMIN_TOKEN_PATH` disables admin
+/// requests.
+pub fn get_admin_token_path(port: u16) -> Option<PathBuf> {
+    match env::var_os("SCCACHE_ADMIN_TOKEN_PATH") {
+        Some(path) if path.is_empty() => None,
+        Some(path) => Some(path.into()),
+        None => Some(config::default_data_dir()?.join(format!("admin-token-{}", port))),
+    }
+}
+
+/// Get the name of the named pipe to listen on alongside the TCP port, if
+/// any: `SCCACHE_SERVER_PIPE`, or one for the user and `port`. An empty
+/// `SCCACHE_SERVER_PIPE` disables it.
+#[cfg(windows)]
+pub fn get_pipe_name(port: u16) -> Option<String> {
+    match env::var("SCCACHE_SERVER_PIPE") {
+        Ok(name) if name.is_empty() => None,
+        Ok(name) => Some(name),
+        Err(_) => {
+            let user = env::var("USERNAME").ok().filter(|user| !user.is_empty())?;
+            Some(format!(r"\\.\pipe\sccache-{}-{}", user, port))
+        }
+    }
+}
+
+/// What the server last did, which decides how long it idles for before
+/// shutting down.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Activity {
+    /// A compile or command request.
+    Compile,
+    /// Any other request, such as for stats.
+    Other,
+}
+
+/// How long the server idles for before shutting down, by what it last did.
+/// A zero duration disables idle shutdown.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IdleTimeouts {
+    /// After starting, before any requests.
+    pub startup: Duration,
+    /// After a compile or command request.
+    pub compile: Duration,
+    /// After any other request.
+    pub other: Duration,
+}
+
+impl IdleTimeouts {
+    /// The same timeout after everything.
+    pub fn all(timeout: Duration) -> IdleTimeouts {
+        IdleTimeouts {
+            startup: timeout,
+            compile: timeout,
+            other: timeout,
+        }
+    }
+
+    /// Get the timeouts from `SCCACHE_IDLE_TIMEOUT`, in seconds, and its
+    /// overrides `SCCACHE_IDLE_TIMEOUT_STARTUP`, `SCCACHE_IDLE_TIMEOUT_COMPILE`
+    /// and `SCCACHE_IDLE_TIMEOUT_OTHER`.
+    fn from_env() -> IdleTimeouts {
+        let secs = |var: &str, default| {
+            env::var(var)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(default)
+        };
+        let timeout = secs("SCCACHE_IDLE_TIMEOUT", DEFAULT_IDLE_TIMEOUT);
+        IdleTimeouts {
+            startup: Duration::from_secs(secs("SCCACHE_IDLE_TIMEOUT_STARTUP", timeout)),
+            compile: Duration::from_secs(secs("SCCACHE_IDLE_TIMEOUT_COMPILE", timeout)),
+            other: Duration::from_secs(secs("SCCACHE_IDLE_TIMEOUT_OTHER", timeout)),
+        }
+    }
+
+    fn after(&self, activity: Activity) -> Duration {
+        match activity {
+            Activity::Compile => self.compile,
+            Activity::Other => self.other,
+        }
+    }
+}
+
+/// Get how long the server should wait for work in progress when shutting
+/// down.
+fn get_shutdown_timeout() -> Duration {
+    let secs = env::var("SCCACHE_SHUTDOWN_TIMEOUT")
         .ok()
         .and_then(|s| s.parse().ok())
-        .unwrap_or(DEFAULT_IDLE_TIMEOUT)
+        .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT);
+    Duration::from_secs(secs)
 }
 
 fn notify_server_startup_internal<W: Write>(mut w: W, status: ServerStartup) -> Result<()> {
@@ -138,7 +303,7 @@ fn get_signal(_status: ExitStatus) -> i32 {
 pub struct DistClientContainer {
     // The actual dist client state
     #[cfg(feature = "dist-client")]
-    state: Mutex<DistClientState>,
+    state: futures::lock::Mutex<DistClientState>,
 }
 
 #[cfg(feature = "dist-client")]
@@ -148,11 +313,17 @@ struct DistClientConfig {
 
     // From the static dist configuration
     scheduler_url: Option<config::HTTPUrl>,
+    standby_scheduler_urls: Vec<config::HTTPUrl>,
     auth: config::DistAuth,
     cache_dir: PathBuf,
     toolchain_cache_size: u64,
     toolchains: Vec<config::DistToolchainConfig>,
     rewrite_includes_only: bool,
+    tls: Option<config::DistTlsConfig>,
+    priority: u32,
+    compression_level: i32,
+    fallback: config::DistFallback,
+    toolchain_signing_key: Option<PathBuf>,
 }
 
 #[cfg(feature = "dist-client")]
@@ -169,7 +340,7 @@ enum DistClientState {
 #[cfg(not(feature = "dist-client"))]
 impl DistClientContainer {
     #[cfg(not(feature = "dist-client"))]
-    fn new(config: &Config, _: &ThreadPool) -> Self {
+    async fn new(config: &Config, _: &ThreadPool) -> Self {
         if config.dist.scheduler_url.is_some() {
             warn!("Scheduler address configured but dist feature disabled, disabling distributed sccache")
         }
@@ -180,45 +351,58 @@ impl DistClientContainer {
         Self {}
     }
 
-    pub fn reset_state(&self) {}
+    pub async fn reset_state(&self) {}
 
-    pub fn get_status(&self) -> DistInfo {
+    pub async fn get_status(&self) -> DistInfo {
         DistInfo::Disabled("dist-client feature not selected".to_string())
     }
 
-    fn get_client(&self) -> Result<Option<Arc<dyn dist::Client>>> {
+    pub async fn get_builders(&self) -> Option<Vec<dist::ServerDetailsResult>> {
+        None
+    }
+
+    async fn get_client(&self) -> Result<Option<Arc<dyn dist::Client>>> {
         Ok(None)
     }
+
+    async fn clear_toolchain_cache(&self) -> Result<u64> {
+        bail!("dist-client feature not selected")
+    }
 }
 
 #[cfg(feature = "dist-client")]
 impl DistClientContainer {
-    fn new(config: &Config, pool: &ThreadPool) -> Self {
+    async fn new(config: &Config, pool: &ThreadPool) -> Self {
         let config = DistClientConfig {
             pool: pool.clone(),
             scheduler_url: config.dist.scheduler_url.clone(),
+            standby_scheduler_urls: config.dist.standby_scheduler_urls.clone(),
             auth: config.dist.auth.clone(),
             cache_dir: config.dist.cache_dir.clone(),
             toolchain_cache_size: config.dist.toolchain_cache_size,
             toolchains: config.dist.toolchains.clone(),
             rewrite_includes_only: config.dist.rewrite_includes_only,
+            tls: config.dist.tls.clone(),
+            priority: config.dist.priority,
+            compression_level: config.dist.compression_level,
+            fallback: config.dist.fallback,
+            toolchain_signing_key: config.dist.toolchain_signing_key.clone(),
         };
-        let state = Self::create_state(config);
+        let state = Self::create_state(config).await;
         Self {
-            state: Mutex::new(state),
+            state: futures::lock::Mutex::new(state),
         }
     }
 
     pub fn new_disabled() -> Self {
         Self {
-            state: Mutex::new(DistClientState::Disabled),
+            state: futures::lock::Mutex::new(DistClientState::Disabled),
         }
     }
 
-    pub fn reset_state(&self) {
-        let mut guard = self.state.lock();
-        let state = guard.as_mut().unwrap();
-        let state: &mut DistClientState = &mut **state;
+    pub async fn reset_state(&self) {
+        let mut guard = self.state.lock().await;
+        let state: &mut DistClientState = &mut guard;
         match mem::replace(state, DistClientState::Disabled) {
             DistClientState::Some(cfg, _)
             | DistClientState::FailWithMessage(cfg, _)
@@ -231,10 +415,9 @@ impl DistClientContainer {
         }
     }
 
-    pub fn get_status(&self) -> DistInfo {
-        let mut guard = self.state.lock();
-        let state = guard.as_mut().unwrap();
-        let state: &mut DistClientState = &mut **state;
+    pub async fn get_status(&self) -> DistInfo {
+        let mut guard = self.state.lock().await;
+        let state: &mut DistClientState = &mut guard;
         match state {
             DistClientState::Disabled => DistInfo::Disabled("disabled".to_string()),
             DistClientState::FailWithMessage(cfg, _) => DistInfo::NotConnected(
@@ -245,7 +428,7 @@ impl DistClientContainer {
                 cfg.scheduler_url.clone(),
                 "enabled, not connected, will retry".to_string(),
             ),
-            DistClientState::Some(cfg, client) => match client.do_get_status().wait() {
+            DistClientState::Some(cfg, client) => match client.do_get_status().await {
                 Ok(res) => DistInfo::SchedulerStatus(cfg.scheduler_url.clone(), res),
                 Err(_) => DistInfo::NotConnected(
                     cfg.scheduler_url.clone(),
@@ -255,13 +438,33 @@ impl DistClientContainer {
         }
     }
 
-    fn get_client(&self) -> Result<Option<Arc<dyn dist::Client>>> {
-        let mut guard = self.state.lock();
-        let state = guard.as_mut().unwrap();
-        let state: &mut DistClientState = &mut **state;
-        Self::maybe_recreate_state(state);
+    /// The details of the scheduler's build servers, if it's connected to
+    /// and serves them.
+    pub async fn get_builders(&self) -> Option<Vec<dist::ServerDetailsResult>> {
+        let client = match &*self.state.lock().await {
+            DistClientState::Some(_, client) => client.clone(),
+            _ => return None,
+        };
+        match client.do_get_details().await {
+            Ok(details) => Some(details.servers),
+            Err(e) => {
+                debug!("Failed to get the scheduler details: {:#}", e);
+                None
+            }
+        }
+    }
+
+    async fn get_client(&self) -> Result<Option<Arc<dyn dist::Client>>> {
+        let mut guard = self.state.lock().await;
+        let state: &mut DistClientState = &mut guard;
+        Self::maybe_recreate_state(state).await;
         let res = match state {
             DistClientState::Some(_, dc) => Ok(Some(dc.clone())),
+            DistClientState::RetryCreateAt(cfg, _)
+                if cfg.fallback == config::DistFallback::FailFast =>
+            {
+                bail!("Could not connect to the scheduler, and `dist.fallback` is `fail_fast`")
+            }
             DistClientState::Disabled | DistClientState::RetryCreateAt(_, _) => Ok(None),
             DistClientState::FailWithMessage(_, msg) => Err(anyhow!(msg.clone())),
         };
@@ -278,7 +481,25 @@ impl DistClientContainer {
         res
     }
 
-    fn maybe_recreate_state(state: &mut DistClientState) {
+    async fn clear_toolchain_cache(&self) -> Result<u64> {
+        let mut guard = self.state.lock().await;
+        let state: &mut DistClientState = &mut guard;
+        match state {
+            DistClientState::Some(_, client) => client.clear_toolchain_cache(),
+            // Without a client, the toolchains are still on disk
+            DistClientState::FailWithMessage(cfg, _) | DistClientState::RetryCreateAt(cfg, _) => {
+                dist::ClientToolchains::new(
+                    &cfg.cache_dir.join("client"),
+                    cfg.toolchain_cache_size,
+                    &cfg.toolchains,
+                )
+                .and_then(|toolchains| toolchains.clear())
+            }
+            DistClientState::Disabled => bail!("distributed compilation is not configured"),
+        }
+    }
+
+    async fn maybe_recreate_state(state: &mut DistClientState) {
         if let DistClientState::RetryCreateAt(_, instant) = *state {
             if instant > Instant::now() {
                 return;
@@ -288,12 +509,12 @@ impl DistClientContainer {
                 _ => unreachable!(),
             };
             info!("Attempting to recreate the dist client");
-            *state = Self::create_state(*config)
+            *state = Self::create_state(*config).await
         }
     }
 
     // Attempt to recreate the dist client
-    fn create_state(config: DistClientConfig) -> DistClientState {
+    async fn create_state(config: DistClientConfig) -> DistClientState {
         macro_rules! try_or_retry_later {
             ($v:expr) => {{
                 match $v {
@@ -331,27 +552,96 @@ impl DistClientContainer {
                 let url = addr.to_url();
                 info!("Enabling distributed sccache to {}", url);
                 let auth_token = match &config.auth {
-                    config::DistAuth::Token { token } => Ok(token.to_owned()),
+                    config::DistAuth::Token { token } => {
+                        Ok(dist::oauth2::AuthToken::fixed(token.to_owned()))
+                    }
                     config::DistAuth::Oauth2CodeGrantPKCE { auth_url, .. }
                     | config::DistAuth::Oauth2Implicit { auth_url, .. } => {
                         Self::get_cached_config_auth_token(auth_url)
+                            .map(dist::oauth2::AuthToken::fixed)
                     }
+                    config::DistAuth::Oauth2DeviceCode {
+                        client_id,
+                        token_url,
+                        ..
+                    } => match Self::get_cached_config_refresh_token(token_url) {
+                        Some(refresh_token) => Ok(dist::oauth2::AuthToken::refresh(
+                            client_id,
+                            token_url,
+                            refresh_token,
+                        )),
+                        // Without a refresh token the token from
+                        // |sccache --dist-auth| is used until it expires
+                        None => Self::get_cached_config_auth_token(token_url)
+                            .map(dist::oauth2::AuthToken::fixed),
+                    },
+                    config::DistAuth::Oauth2ClientCredentials {
+                        client_id,
+                        client_secret,
+                        token_url,
+                        scope,
+                        audience,
+                    } => Ok(dist::oauth2::AuthToken::client_credentials(
+                        client_id,
+                        client_secret,
+                        token_url,
+                        scope.as_deref(),
+                        audience.as_deref(),
+                    )),
                 };
                 let auth_token = try_or_fail_with_message!(auth_token
                     .context("could not load client auth token, run |sccache --dist-auth|"));
-                let dist_client = dist::http::Client::new(
-                    &config.pool,
-                    url,
-                    &config.cache_dir.join("client"),
-                    config.toolchain_cache_size,
-                    &config.toolchains,
-                    auth_token,
-                    config.rewrite_includes_only,
-                );
+                let tls = match config.tls {
+                    Some(ref tls) => {
+                        Some(try_or_fail_with_message!(dist::tls::TlsCerts::load(tls)
+                            .context("could not load TLS certificates")))
+                    }
+                    None => None,
+                };
+                let toolchain_signer = match config.toolchain_signing_key {
+                    Some(ref path) => Some(try_or_fail_with_message!(
+                        dist::signing::ToolchainSigner::load(path)
+                            .context("could not load toolchain signing key")
+                    )),
+                    None => None,
+                };
+                let urls = std::iter::once(url)
+                    .chain(config.standby_scheduler_urls.iter().map(|u| u.to_url()))
+                    .collect();
+                // The client makes blocking requests too, and the HTTP client
+                // for those can't be created on the event loop
+                let dist_client = {
+                    let pool = config.pool.clone();
+                    let cache_dir = config.cache_dir.join("client");
+                    let toolchain_cache_size = config.toolchain_cache_size;
+                    let toolchains = config.toolchains.clone();
+                    let rewrite_includes_only = config.rewrite_includes_only;
+                    let (priority, compression_level, fallback) =
+                        (config.priority, config.compression_level, config.fallback);
+                    config
+                        .pool
+                        .spawn_fn(move || {
+                            dist::http::Client::new(
+                                &pool,
+                                urls,
+                                &cache_dir,
+                                toolchain_cache_size,
+                                &toolchains,
+                                auth_token,
+                                rewrite_includes_only,
+                                tls,
+                                priority,
+                                compression_level,
+                                fallback,
+                                toolchain_signer,
+                            )
+                        })
+                        .await
+                };
                 let dist_client =
                     try_or_retry_later!(dist_client.context("failure during dist client creation"));
                 use crate::dist::Client;
-                match dist_client.do_get_status().wait() {
+                match dist_client.do_get_status().await {
                     Ok(res) => {
                         info!(
                             "Successfully created dist client with {:?} cores across {:?} servers",
@@ -381,6 +671,11 @@ impl DistClientContainer {
             .with(|c| c.dist.auth_tokens.get(auth_url).map(String::to_owned))
             .with_context(|| format!("token for url {} not present in cached config", auth_url))
     }
+
+    fn get_cached_config_refresh_token(token_url: &str) -> Option<String> {
+        let cached_config = config::CachedConfig::reload().ok()?;
+        cached_config.with(|c| c.dist.refresh_tokens.get(token_url).map(String::to_owned))
+    }
 }
 
 /// Start an sccache server, listening on `port`.
@@ -389,28 +684,101 @@ impl DistClientContainer {
 /// requests a shutdown.
 pub fn start_server(config: &Config, port: u16) -> Result<()> {
     info!("start_server: port: {}", port);
+    trace::init_exporter();
     let client = unsafe { Client::new() };
-    let runtime = Runtime::new()?;
+    let runtime = util::runtime()?;
     let pool = ThreadPool::builder()
         .pool_size(std::cmp::max(20, 2 * num_cpus::get()))
         .create()?;
-    let dist_client = DistClientContainer::new(config, &pool);
-    let storage = storage_from_config(config, &pool);
-    let res = SccacheServer::<ProcessCommandCreator>::new(
-        port,
-        pool,
-        runtime,
-        client,
-        dist_client,
-        storage,
+    let dist_client = runtime.block_on(DistClientContainer::new(config, &pool));
+    let backend_meters = BackendMeters::default();
+    let storage = LayeredStorage::local_cache_from_env(
+        LimitedStorage::from_env(MeteredStorage::wrap(
+            AuditedStorage::from_env(storage_from_config(config, &pool), config),
+            &backend_meters,
+        )),
+        config,
+        &pool,
+        &backend_meters,
     );
+    let local_storage = LocalStorage::new(&storage, config);
+    set_compiler_digest_cache(compiler_digest_cache(config));
+    set_input_digest_cache(input_digest_cache(config));
+    // Sockets passed by systemd are listened on instead of binding them
+    #[cfg(unix)]
+    let (tcp_listener, unix_listener) = {
+        let (mut tcp_listener, mut unix_listener) = (None, None);
+        let listeners = systemd::listeners().context("failed to use sockets passed by systemd")?;
+        for listener in listeners {
+            match listener {
+                systemd::Listener::Tcp(l) if tcp_listener.is_none() => tcp_listener = Some(l),
+                systemd::Listener::Unix(l) if unix_listener.is_none() => unix_listener = Some(l),
+                _ => warn!("ignoring an extra socket passed by systemd"),
+            }
+        }
+        (tcp_listener, unix_listener)
+    };
+    #[cfg(not(unix))]
+    let tcp_listener: Option<std::net::TcpListener> = None;
+    let listener = match tcp_listener {
+        Some(listener) => Ok(listener),
+        None => bind_port(port),
+    };
+    let res = listener.map_err(Error::from).and_then(|listener| {
+        SccacheServer::<ProcessCommandCreator>::with_listener(
+            listener,
+            pool,
+            runtime,
+            client,
+            dist_client,
+            storage,
+            config.hash.clone(),
+            config.toolchain_pins.clone(),
+        )
+    });
     let notify = env::var_os("SCCACHE_STARTUP_NOTIFY");
     match res {
-        Ok(srv) => {
+        Ok(mut srv) => {
             let port = srv.port();
+            srv.set_projects(config.projects.clone());
+            srv.set_backend_meters(backend_meters);
+            srv.set_local_storage(local_storage);
+            srv.set_stats_file(stats_file(config));
+            if let Some(path) = get_admin_token_path(port) {
+                if let Err(e) = srv.set_admin_token(path.clone()) {
+                    warn!(
+                        "failed to write the admin token to {}: {}",
+                        path.display(),
+                        e
+                    );
+                }
+            }
+            #[cfg(unix)]
+            {
+                if let Some(listener) = unix_listener {
+                    srv.add_unix_listener(listener)?;
+                    info!("listening on the unix socket passed by systemd");
+                } else if let Some(path) = get_socket_path(port) {
+                    match srv.listen_unix(path.clone()) {
+                        Ok(()) => info!("listening on {}", path.display()),
+                        Err(e) => warn!("failed to listen on {}: {}", path.display(), e),
+                    }
+                }
+            }
+            #[cfg(windows)]
+            {
+                if let Some(name) = get_pipe_name(port) {
+                    match srv.listen_pipe(name.clone()) {
+                        Ok(()) => info!("listening on {}", name),
+                        Err(e) => warn!("failed to listen on {}: {}", name, e),
+                    }
+                }
+            }
             info!("server started, listening on port {}", port);
             notify_server_startup(&notify, ServerStartup::Ok { port })?;
-            srv.run(future::empty::<(), ()>())?;
+            #[cfg(unix)]
+            systemd::notify("READY=1");
+            srv.run(future::pending::<()>())?;
             Ok(())
         }
         Err(e) => {
@@ -432,13 +800,26 @@ pub fn start_server(config: &Config, port: u16) -> Result<()> {
 pub struct SccacheServer<C: CommandCreatorSync> {
     runtime: Runtime,
     listener: TcpListener,
+    #[cfg(unix)]
+    unix_listener: Option<std::os::unix::net::UnixListener>,
+    /// The socket file to remove when shutting down, if the server created it.
+    #[cfg(unix)]
+    unix_socket: Option<PathBuf>,
+    #[cfg(windows)]
+    pipe_listener: Option<mpsc::UnboundedReceiver<miow::pipe::NamedPipe>>,
+    metrics_listener: Option<TcpListener>,
+    #[cfg(feature = "hyper")]
+    bazel_cache: Option<BazelCache>,
+    /// The admin token file to remove when shutting down.
+    admin_token_file: Option<PathBuf>,
     rx: mpsc::Receiver<ServerMessage>,
-    timeout: Duration,
+    idle_timeouts: IdleTimeouts,
     service: SccacheService<C>,
     wait: WaitUntilZero,
 }
 
 impl<C: CommandCreatorSync> SccacheServer<C> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         port: u16,
         pool: ThreadPool,
@@ -446,22 +827,91 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
         client: Client,
         dist_client: DistClientContainer,
         storage: Arc<dyn Storage>,
+        hash_config: HashConfig,
+        toolchain_pins: ToolchainPinsConfig,
+    ) -> Result<SccacheServer<C>> {
+        SccacheServer::with_listener(
+            bind_port(port)?,
+            pool,
+            runtime,
+            client,
+            dist_client,
+            storage,
+            hash_config,
+            toolchain_pins,
+        )
+    }
+
+    /// Create a server listening on `listener`, rather than on a port it
+    /// binds.
+    #[allow(clippy::too_many_arguments)]
+    pub fn with_listener(
+        listener: std::net::TcpListener,
+        pool: ThreadPool,
+        runtime: Runtime,
+        client: Client,
+        dist_client: DistClientContainer,
+        storage: Arc<dyn Storage>,
+        hash_config: HashConfig,
+        toolchain_pins: ToolchainPinsConfig,
     ) -> Result<SccacheServer<C>> {
-        let addr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port);
-        let listener = TcpListener::bind(&SocketAddr::V4(addr))?;
+        // The listeners are registered with the runtime they're served on
+        let (listener, metrics_listener) = {
+            let _guard = runtime.enter();
+            let metrics_listener = match get_listen_addr("SCCACHE_METRICS_ADDR") {
+                Some(addr) => Some(
+                    std::net::TcpListener::bind(&addr)
+                        .and_then(tokio_listener)
+                        .with_context(|| format!("failed to listen for metrics on {}", addr))?,
+                ),
+                None => None,
+            };
+            (tokio_listener(listener)?, metrics_listener)
+        };
+        #[cfg(feature = "hyper")]
+        let bazel_cache = BazelCache::from_env(get_listen_addr("SCCACHE_BAZEL_CACHE_ADDR"))?;
+        #[cfg(not(feature = "hyper"))]
+        if env::var_os("SCCACHE_BAZEL_CACHE_ADDR").is_some() {
+            warn!("Not serving the Bazel cache, as sccache was built without HTTP support");
+        }
+
+        let statsd = statsd::Client::from_env().unwrap_or_else(|e| {
+            warn!("Not sending metrics to statsd: {:?}", e);
+            None
+        });
 
         // Prepare the service which we'll use to service all incoming TCP
         // connections.
         let (tx, rx) = mpsc::channel(1);
         let (wait, info) = WaitUntilZero::new();
-        let service = SccacheService::new(dist_client, storage, &client, pool, tx, info);
+        let mut service = SccacheService::new(
+            dist_client,
+            storage,
+            hash_config,
+            toolchain_pins,
+            &client,
+            pool,
+            tx,
+            info,
+        );
+        service.statsd = statsd.map(Rc::new);
 
         Ok(SccacheServer {
             runtime,
             listener,
+            #[cfg(unix)]
+            unix_listener: None,
+            #[cfg(unix)]
+            unix_socket: None,
+            #[cfg(windows)]
+            pipe_listener: None,
+            metrics_listener,
+            #[cfg(feature = "hyper")]
+            bazel_cache,
+            admin_token_file: None,
             rx,
             service,
-            timeout: Duration::from_secs(get_idle_timeout()),
+            idle_timeouts: IdleTimeouts::from_env(),
             wait,
         })
     }
@@ -469,13 +919,139 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
     /// Configures how long this server will be idle before shutting down.
     #[allow(dead_code)]
     pub fn set_idle_timeout(&mut self, timeout: Duration) {
-        self.timeout = timeout;
+        self.idle_timeouts = IdleTimeouts::all(timeout);
+    }
+
+    /// Configures how long this server will be idle before shutting down,
+    /// by what it last did.
+    #[allow(dead_code)]
+    pub fn set_idle_timeouts(&mut self, timeouts: IdleTimeouts) {
+        self.idle_timeouts = timeouts;
+    }
+
+    /// Configures the projects whose stats are kept separately too.
+    pub fn set_projects(&mut self, projects: ProjectsConfig) {
+        *self.service.projects.borrow_mut() = projects;
+    }
+
+    /// Configures where the lookups and writes of each storage backend are
+    /// counted.
+    pub fn set_backend_meters(&mut self, meters: BackendMeters) {
+        self.service.backend_meters = meters;
+    }
+
+    /// Configures the storage used while the server is offline.
+    pub fn set_local_storage(&mut self, local: LocalStorage) {
+        *self.service.local_storage.borrow_mut() = local;
+    }
+
+    /// Keep the stats in `path` across restarts, starting with those saved
+    /// there if there are any.
+    pub fn set_stats_file(&mut self, path: PathBuf) {
+        let saved = SavedStats::load(&path);
+        self.service
+            .backend_meters
+            .reset(saved.stats.backends.clone());
+        *self.service.stats.borrow_mut() = saved.stats;
+        *self.service.project_stats.borrow_mut() = saved.projects;
+        self.service.stats_file = Some(Rc::new(path));
+    }
+
+    /// Take admin requests with a new token, written to `path` for only its
+    /// owner to read.
+    pub fn set_admin_token(&mut self, path: PathBuf) -> io::Result<()> {
+        let token = format!("{:032x}", rand::random::<u128>());
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // A token left over from another server is replaced rather than
+        // written to, as it may be readable by others
+        match fs::remove_file(&path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            res => res?,
+        }
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options.open(&path)?.write_all(token.as_bytes())?;
+        self.admin_token_file = Some(path);
+        self.service.admin_token = Some(Rc::new(token));
+        Ok(())
+    }
+
+    /// Also listen for clients on a Unix socket at `path`, which only its
+    /// owner can connect to.
+    #[cfg(unix)]
+    pub fn listen_unix(&mut self, path: PathBuf) -> io::Result<()> {
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+        // A server still listening here would hold our port too, so a socket
+        // is left over from one that didn't shut down cleanly, but anything
+        // else isn't ours to replace
+        if let Ok(metadata) = fs::symlink_metadata(&path) {
+            if !metadata.file_type().is_socket() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and isn't a socket", path.display()),
+                ));
+            }
+        }
+        // Bound in a directory only we can enter, so that nobody else can
+        // connect before the socket is restricted, then moved into place
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let dir = tempfile::Builder::new()
+            .prefix(".sccache-sock")
+            .tempdir_in(parent)?;
+        let bound = dir.path().join("sock");
+        let listener = std::os::unix::net::UnixListener::bind(&bound)?;
+        fs::set_permissions(&bound, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&bound, &path)?;
+        self.unix_socket = Some(path);
+        self.add_unix_listener(listener)
+    }
+
+    /// Also listen for clients on the Unix socket `listener`.
+    #[cfg(unix)]
+    pub fn add_unix_listener(
+        &mut self,
+        listener: std::os::unix::net::UnixListener,
+    ) -> io::Result<()> {
+        listener.set_nonblocking(true)?;
+        self.unix_listener = Some(listener);
+        Ok(())
+    }
+
+    /// Also listen for clients on the named pipe `name`, which only local
+    /// clients can connect to, and only its owner can write to.
+    #[cfg(windows)]
+    pub fn listen_pipe(&mut self, name: String) -> io::Result<()> {
+        // As the first instance, this fails if another process has the name
+        let pipe = create_pipe(&name, true)?;
+        let overlapped = miow::Overlapped::initialize_with_autoreset_event()?;
+        let (tx, rx) = mpsc::unbounded();
+        std::thread::spawn(move || {
+            if let Err(e) = accept_pipe_connections(pipe, &name, &overlapped, &tx) {
+                error!("failed to accept a connection on {}: {}", name, e);
+            }
+            unsafe {
+                winapi::um::handleapi::CloseHandle(overlapped.event());
+            }
+        });
+        self.pipe_listener = Some(rx);
+        Ok(())
     }
 
     /// Set the storage this server will use.
     #[allow(dead_code)]
     pub fn set_storage(&mut self, storage: Arc<dyn Storage>) {
-        self.service.storage = storage;
+        *self.service.storage.borrow_mut() = storage;
     }
 
     /// Returns a reference to a thread pool to run work on
@@ -505,103 +1081,352 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
     where
         F: Future,
     {
-        self._run(Box::new(shutdown.then(|_| Ok(()))))
+        self._run(Box::pin(shutdown.map(|_| ())))
     }
 
-    fn _run<'a>(self, shutdown: Box<dyn Future<Item = (), Error = ()> + 'a>) -> io::Result<()> {
+    fn _run<'a>(self, shutdown: LocalBoxFuture<'a, ()>) -> io::Result<()> {
         let SccacheServer {
-            mut runtime,
+            runtime,
             listener,
+            #[cfg(unix)]
+            unix_listener,
+            #[cfg(unix)]
+            unix_socket,
+            #[cfg(windows)]
+            pipe_listener,
+            metrics_listener,
+            #[cfg(feature = "hyper")]
+            bazel_cache,
+            admin_token_file,
             rx,
             service,
-            timeout,
+            idle_timeouts,
             wait,
         } = self;
+        let stats = service.stats.clone();
+        let sessions = service.sessions.clone();
+        let stopping = service.stopping.clone();
+        let save_stats = service.stats_saver();
+
+        // Connections and compiles are served as tasks of their own on this
+        // thread, as the service isn't `Send`
+        let local = LocalSet::new();
+        let saver = save_stats.clone();
+        local.block_on(&runtime, async move {
+            // Find out about broken storage now, rather than from the errors of
+            // every compile
+            task::spawn_local(service.check_storage());
+
+            if let Some(save_stats) = saver {
+                task::spawn_local(every(STATS_SAVE_INTERVAL, save_stats));
+            }
 
-        // Create our "server future" which will simply handle all incoming
-        // connections in separate tasks.
-        let server = listener.incoming().for_each(move |socket| {
-            trace!("incoming connection");
-            tokio_compat::runtime::current_thread::TaskExecutor::current()
-                .spawn_local(Box::new(service.clone().bind(socket).map_err(|err| {
-                    error!("{}", err);
-                })))
-                .unwrap();
-            Ok(())
-        });
+            // Digests of inputs are saved as often, rather than as they're hashed
+            task::spawn_local(every(STATS_SAVE_INTERVAL, save_input_digests));
+
+            if let Some(metrics_listener) = metrics_listener {
+                let stats = service.stats.clone();
+                let backend_meters = service.backend_meters.clone();
+                let compiles_active = service.compiles_active.clone();
+                task::spawn_local(async move {
+                    loop {
+                        let socket = match metrics_listener.accept().await {
+                            Ok((socket, _)) => socket,
+                            Err(e) => {
+                                error!("failed to accept a metrics connection: {}", e);
+                                return;
+                            }
+                        };
+                        let stats = stats.clone();
+                        let backend_meters = backend_meters.clone();
+                        let compiles_active = compiles_active.clone();
+                        task::spawn_local(async move {
+                            let metrics = move || {
+                                let stats = stats.borrow().with_backends(&backend_meters);
+                                metrics::server(&stats, compiles_active.get())
+                            };
+                            if let Err(e) = serve_metrics(socket, metrics).await {
+                                debug!("failed to serve metrics: {}", e);
+                            }
+                        });
+                    }
+                });
+            }
 
-        // Right now there's a whole bunch of ways to shut down this server for
-        // various purposes. These include:
-        //
-        // 1. The `shutdown` future above.
-        // 2. An RPC indicating the server should shut down
-        // 3. A period of inactivity (no requests serviced)
-        //
-        // These are all encapsulated wih the future that we're creating below.
-        // The `ShutdownOrInactive` indicates the RPC or the period of
-        // inactivity, and this is then select'd with the `shutdown` future
-        // passed to this function.
-
-        let shutdown = shutdown.map(|a| {
-            info!("shutting down due to explicit signal");
-            a
-        });
+            #[cfg(feature = "hyper")]
+            if let Some(bazel_cache) = bazel_cache {
+                let service = service.clone();
+                task::spawn_local(bazel_cache.serve(move || service.storage()));
+            }
 
-        let mut futures = vec![
-            Box::new(server) as Box<dyn Future<Item = _, Error = _>>,
-            Box::new(
-                shutdown
-                    .map_err(|()| io::Error::new(io::ErrorKind::Other, "shutdown signal failed")),
-            ),
-        ];
+            // Tell systemd the server's alive as long as it's handling events
+            #[cfg(unix)]
+            {
+                if let Some(interval) = systemd::watchdog_interval() {
+                    task::spawn_local(every(interval / 2, || systemd::notify("WATCHDOG=1")));
+                }
+            }
 
-        let shutdown_idle = ShutdownOrInactive {
-            rx,
-            timeout: if timeout != Duration::new(0, 0) {
-                Some(Delay::new(Instant::now() + timeout))
-            } else {
-                None
-            },
-            timeout_dur: timeout,
-        };
-        futures.push(Box::new(shutdown_idle.map(|a| {
-            info!("shutting down due to being idle or request");
-            a
-        })));
+            // This holds on to the service, so it has to stop with the server
+            // rather than being spawned
+            #[cfg(unix)]
+            let reloads = {
+                use tokio::signal::unix::{signal, SignalKind};
+
+                let service = service.clone();
+                async move {
+                    match signal(SignalKind::hangup()) {
+                        Ok(mut hangups) => {
+                            while hangups.recv().await.is_some() {
+                                info!("Reloading the config on SIGHUP");
+                                if let Err(e) = service.reload_config() {
+                                    error!("Failed to reload the config: {:?}", e);
+                                }
+                            }
+                        }
+                        Err(e) => error!("failed to listen for SIGHUP: {}", e),
+                    }
+                    future::pending::<io::Result<()>>().await
+                }
+            };
+
+            #[cfg(unix)]
+            let unix_server = match unix_listener {
+                Some(listener) => {
+                    let listener = tokio::net::UnixListener::from_std(listener)?;
+                    let service = service.clone();
+                    Some(async move {
+                        loop {
+                            let (socket, _) = listener.accept().await?;
+                            trace!("incoming connection on the unix socket");
+                            service.clone().spawn_bind(socket);
+                        }
+                    })
+                }
+                None => None,
+            };
 
-        let server = future::select_all(futures);
-        runtime.block_on(server).map_err(|p| p.0)?;
+            #[cfg(windows)]
+            let pipe_server = pipe_listener.map(|mut pipes| {
+                use std::os::windows::io::{FromRawHandle, IntoRawHandle};
+                use tokio::net::windows::named_pipe::NamedPipeServer;
+
+                let service = service.clone();
+                async move {
+                    while let Some(pipe) = pipes.next().await {
+                        trace!("incoming connection on the named pipe");
+                        let pipe =
+                            unsafe { NamedPipeServer::from_raw_handle(pipe.into_raw_handle())? };
+                        service.clone().spawn_bind(pipe);
+                    }
+                    // Clients can still use the port if the pipe stops accepting
+                    future::pending::<io::Result<()>>().await
+                }
+            });
 
-        info!(
-            "moving into the shutdown phase now, waiting at most 10 seconds \
-             for all client requests to complete"
-        );
+            // Create our "server future" which will simply handle all incoming
+            // connections in separate tasks.
+            let server = async move {
+                loop {
+                    let (socket, _) = listener.accept().await?;
+                    trace!("incoming connection");
+                    service.clone().spawn_bind(socket);
+                }
+            };
 
-        // Once our server has shut down either due to inactivity or a manual
-        // request we still need to give a bit of time for all active
-        // connections to finish. This `wait` future will resolve once all
-        // instances of `SccacheService` have been dropped.
-        //
-        // Note that we cap the amount of time this can take, however, as we
-        // don't want to wait *too* long.
-        runtime
-            .block_on(Timeout::new(Compat::new(wait), Duration::new(30, 0)))
-            .map_err(|e| {
-                if e.is_inner() {
-                    e.into_inner().unwrap()
-                } else {
-                    io::Error::new(io::ErrorKind::Other, e)
+            // Right now there's a whole bunch of ways to shut down this server for
+            // various purposes. These include:
+            //
+            // 1. The `shutdown` future above.
+            // 2. An RPC indicating the server should shut down
+            // 3. A period of inactivity (no requests serviced)
+            //
+            // These are all encapsulated wih the future that we're creating below.
+            // The `shutdown_or_inactive` indicates the RPC or the period of
+            // inactivity, and this is then select'd with the `shutdown` future
+            // passed to this function.
+
+            let shutdown = shutdown.map(|()| {
+                info!("shutting down due to explicit signal");
+                Ok(())
+            });
+
+            let mut futures: Vec<LocalBoxFuture<'_, io::Result<()>>> =
+                vec![Box::pin(server), Box::pin(shutdown)];
+
+            #[cfg(unix)]
+            futures.push(Box::pin(reloads));
+            #[cfg(unix)]
+            {
+                if let Some(unix_server) = unix_server {
+                    futures.push(Box::pin(unix_server));
                 }
-            })?;
+            }
+            #[cfg(windows)]
+            {
+                if let Some(pipe_server) = pipe_server {
+                    futures.push(Box::pin(pipe_server));
+                }
+            }
+
+            let shutdown_idle = shutdown_or_inactive(rx, idle_timeouts, sessions);
+            futures.push(Box::pin(shutdown_idle.map(|()| {
+                info!("shutting down due to being idle or request");
+                Ok(())
+            })));
+
+            // The futures that didn't finish are dropped with the rest of the
+            // selection, along with the service they hold on to
+            future::select_all(futures).await.0?;
+            stopping.set(true);
+
+            #[cfg(unix)]
+            systemd::notify("STOPPING=1");
+            let shutdown_timeout = get_shutdown_timeout();
+            info!(
+                "moving into the shutdown phase now, waiting at most {} seconds \
+                 for all client requests to complete",
+                shutdown_timeout.as_secs()
+            );
 
+            // Once our server has shut down either due to inactivity or a manual
+            // request we still need to give a bit of time for all active
+            // connections to finish. This `wait` future will resolve once all
+            // instances of `SccacheService` have been dropped, which includes
+            // those held by compiles in progress until their cache writes finish.
+            //
+            // Note that we cap the amount of time this can take, however, as we
+            // don't want to wait *too* long.
+            tokio::time::timeout(shutdown_timeout, wait)
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+        })?;
+
+        #[cfg(unix)]
+        {
+            if let Some(path) = unix_socket {
+                let _ = fs::remove_file(path);
+            }
+        }
+        if let Some(path) = admin_token_file {
+            let _ = fs::remove_file(path);
+        }
+        if let Some(save_stats) = save_stats {
+            save_stats();
+        }
+        save_input_digests();
+
+        let stats = stats.borrow();
+        log_event!(
+            log::Level::Info, "server stopped";
+            "compile_requests" => stats.compile_requests,
+            "cache_hits" => stats.cache_hits.all(),
+            "cache_misses" => stats.cache_misses.all(),
+            "cache_writes" => stats.cache_writes,
+            "cache_write_errors" => stats.cache_write_errors,
+        );
         info!("ok, fully shutting down now");
 
         Ok(())
     }
 }
 
+#[cfg(windows)]
+fn create_pipe(name: &str, first: bool) -> io::Result<miow::pipe::NamedPipe> {
+    miow::pipe::NamedPipeBuilder::new(name)
+        .first(first)
+        .accept_remote(false)
+        .create()
+}
+
+/// Wait for clients to connect to `pipe`, sending each connected instance to
+/// `tx` and creating another for the next client, until `tx` is closed.
+/// `overlapped` must have an event to wait on.
+#[cfg(windows)]
+fn accept_pipe_connections(
+    mut pipe: miow::pipe::NamedPipe,
+    name: &str,
+    overlapped: &miow::Overlapped,
+    tx: &mpsc::UnboundedSender<miow::pipe::NamedPipe>,
+) -> io::Result<()> {
+    use winapi::um::synchapi::WaitForSingleObject;
+    use winapi::um::winbase::{INFINITE, WAIT_OBJECT_0};
+
+    loop {
+        // The pipe is opened for overlapped I/O, so connecting has to be too
+        unsafe {
+            if !pipe.connect_overlapped(overlapped.raw())? {
+                if WaitForSingleObject(overlapped.event(), INFINITE) != WAIT_OBJECT_0 {
+                    return Err(io::Error::last_os_error());
+                }
+                pipe.result(overlapped.raw())?;
+            }
+        }
+        let next = create_pipe(name, false)?;
+        if tx.unbounded_send(mem::replace(&mut pipe, next)).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Listen on `port` of localhost.
+fn bind_port(port: u16) -> io::Result<std::net::TcpListener> {
+    let addr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port);
+    std::net::TcpListener::bind(&SocketAddr::V4(addr))
+}
+
+/// Accept the connections to `listener` on the runtime in this context.
+fn tokio_listener(listener: std::net::TcpListener) -> io::Result<TcpListener> {
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
+
+/// Call `f` every `period`, starting a `period` from now.
+async fn every<F: FnMut()>(period: Duration, mut f: F) {
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        f();
+    }
+}
+
+// Just enough HTTP for Prometheus to scrape `/metrics`
+async fn serve_metrics<F>(mut socket: TcpStream, metrics: F) -> io::Result<()>
+where
+    F: FnOnce() -> String,
+{
+    let mut buf = vec![0; 1024];
+    let len = socket.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let response = match request.split_whitespace().nth(1) {
+        Some("/metrics") => {
+            let body = metrics();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                metrics::CONTENT_TYPE,
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned(),
+    };
+    socket.write_all(response.as_bytes()).await
+}
+
 type CompilerMap<C> = HashMap<PathBuf, Option<CompilerCacheEntry<C>>>;
 
+/// The namespace of the entries of toolchains that aren't pinned.
+const UNPINNED_NAMESPACE: &str = "unpinned";
+
+/// Whether `SCCACHE_NO_CACHE=1` is set for a compile, so that it's run as if
+/// sccache wasn't there.
+fn bypasses_cache(env_vars: &[(OsString, OsString)]) -> bool {
+    env_vars
+        .iter()
+        .any(|(k, v)| k.as_os_str() == OsStr::new("SCCACHE_NO_CACHE") && v.as_os_str() == "1")
+}
+
 /// entry of the compiler cache
 struct CompilerCacheEntry<C: CommandCreatorSync> {
     /// compiler argument trait obj
@@ -634,11 +1459,41 @@ struct SccacheService<C: CommandCreatorSync> {
     /// Server statistics.
     stats: Rc<RefCell<ServerStats>>,
 
+    /// How many times `stats` has been zeroed.
+    stats_generation: Rc<Cell<u64>>,
+
+    /// Statistics of the compiles of each project, which are counted in
+    /// `stats` too.
+    project_stats: Rc<RefCell<HashMap<String, ServerStats>>>,
+
+    /// The file the stats are kept in across restarts, if they are.
+    stats_file: Option<Rc<PathBuf>>,
+
+    /// Whether the stats changed since they were last saved.
+    stats_changed: Rc<Cell<bool>>,
+
+    /// The statsd agent to send the stats of each request to, if any.
+    statsd: Option<Rc<statsd::Client>>,
+
+    /// The stats of each storage backend, which are counted as the storage
+    /// is used rather than by request.
+    backend_meters: BackendMeters,
+
     /// Distributed sccache client
     dist_client: Rc<DistClientContainer>,
 
-    /// Cache storage.
-    storage: Arc<dyn Storage>,
+    /// Cache storage. This and the options below are replaced when the
+    /// config is reloaded, and compiles in progress keep the old ones.
+    storage: Rc<RefCell<Arc<dyn Storage>>>,
+
+    /// Options controlling how compilations are hashed.
+    hash_config: Rc<RefCell<Arc<HashConfig>>>,
+
+    /// The toolchains allowed to use the cache.
+    toolchain_pins: Rc<RefCell<Arc<ToolchainPinsConfig>>>,
+
+    /// The directories of projects, for compiles that don't name theirs.
+    projects: Rc<RefCell<ProjectsConfig>>,
 
     /// A cache of known compiler info.
     compilers: Rc<RefCell<CompilerMap<C>>>,
@@ -668,9 +1523,56 @@ struct SccacheService<C: CommandCreatorSync> {
 
     /// Information tracking how many services (connected clients) are active.
     info: ActiveInfo,
+
+    /// The number of compiles in progress.
+    compiles_active: Rc<Cell<usize>>,
+
+    /// The number of cache writes in progress, for finished compiles.
+    cache_writes_active: Rc<Cell<usize>>,
+
+    /// The id of the last compile request, to tell them apart in logs.
+    last_request_id: Rc<Cell<u64>>,
+
+    /// The compiles in progress by request id, for `sccache --top`.
+    active_compiles: Rc<RefCell<BTreeMap<u64, (ActiveCompile, Instant)>>>,
+
+    /// The compiles finished last, newest first, for `sccache --top`.
+    recent_compiles: Rc<RefCell<VecDeque<RecentCompile>>>,
+
+    /// The build sessions open, which keep the server from shutting down
+    /// when it's idle.
+    sessions: Rc<RefCell<BTreeSet<String>>>,
+
+    /// Why the storage didn't work when it was last checked, if it didn't.
+    storage_error: Rc<RefCell<Option<String>>>,
+
+    /// Slots for the compiles to run at once, local or distributed, when
+    /// they're limited by `SCCACHE_MAX_JOBS` rather than a build tool's
+    /// jobserver.
+    job_slots: Option<Client>,
+
+    /// The token admin requests need, if the server takes them.
+    admin_token: Option<Rc<String>>,
+
+    /// Whether compiles have been stopped from writing to the storage by an
+    /// admin request, which lasts across config reloads.
+    read_only: Rc<Cell<bool>>,
+
+    /// Whether the server only uses the local disk cache, and doesn't
+    /// distribute compiles, which lasts across config reloads.
+    network_mode: Rc<Cell<NetworkMode>>,
+
+    /// Tells when the storage stopped answering, and when it does again.
+    offline_detector: Rc<RefCell<OfflineDetector>>,
+
+    /// The storage used while the server is offline.
+    local_storage: Rc<RefCell<LocalStorage>>,
+
+    /// Whether the server is shutting down, after it stopped taking new
+    /// connections.
+    stopping: Rc<Cell<bool>>,
 }
 
-type SccacheRequest = Message<Request, Body<()>>;
 type SccacheResponse = Message<Response, Body<Response>>;
 
 /// Messages sent from all services to the main event loop indicating activity.
@@ -680,65 +1582,121 @@ type SccacheResponse = Message<Response, Body<Response>>;
 /// a server shutdown was requested via an RPC.
 pub enum ServerMessage {
     /// A message sent whenever a request is received.
-    Request,
+    Request(Activity),
     /// Message sent whenever a shutdown request is received.
     Shutdown,
 }
 
-impl<C> Service<SccacheRequest> for SccacheService<C>
+impl<C> SccacheService<C>
 where
     C: CommandCreatorSync + 'static,
 {
-    type Response = SccacheResponse;
-    type Error = Error;
-    type Future = SFuture<Self::Response>;
-
-    fn call(&mut self, req: SccacheRequest) -> Self::Future {
+    async fn call(&self, req: Request) -> Result<SccacheResponse> {
         trace!("handle_client");
 
         // Opportunistically let channel know that we've received a request. We
         // ignore failures here as well as backpressure as it's not imperative
         // that every message is received.
-        drop(self.tx.clone().start_send(ServerMessage::Request));
+        let activity = match req {
+            Request::Compile(_) | Request::Run(_) => Activity::Compile,
+            _ => Activity::Other,
+        };
+        drop(self.tx.clone().try_send(ServerMessage::Request(activity)));
 
-        let res: SFuture<Response> = match req.into_inner() {
+        let res = match req {
             Request::Compile(compile) => {
                 debug!("handle_client: compile");
-                self.stats.borrow_mut().compile_requests += 1;
-                return self.handle_compile(compile);
+                return Ok(self.handle_compile(compile).await);
+            }
+            Request::Run(run) => {
+                debug!("handle_client: run");
+                return Ok(self.handle_run(run).await);
             }
             Request::GetStats => {
                 debug!("handle_client: get_stats");
-                Box::new(self.get_info().map(|i| Response::Stats(Box::new(i))))
+                Response::Stats(Box::new(self.get_info().await?))
             }
             Request::DistStatus => {
                 debug!("handle_client: dist_status");
-                Box::new(self.get_dist_status().map(Response::DistStatus))
+                Response::DistStatus(self.get_dist_status().await)
+            }
+            Request::Hello(client) => {
+                debug!("handle_client: hello from {:?}", client);
+                Response::Hello(Hello::current())
+            }
+            Request::WatchStats => {
+                debug!("handle_client: watch_stats");
+                return Ok(self.watch_stats());
+            }
+            Request::Top => {
+                debug!("handle_client: top");
+                return self.top().await;
+            }
+            Request::Prewarm(compile) => {
+                debug!("handle_client: prewarm");
+                Response::Prewarmed(self.handle_prewarm(compile).await?)
+            }
+            Request::DryRun(compile) => {
+                debug!("handle_client: dry_run");
+                Response::DryRun(self.handle_dry_run(compile).await)
+            }
+            Request::Admin(token, request) => {
+                debug!("handle_client: admin {:?}", request);
+                Response::Admin(self.handle_admin(&token, request).await)
+            }
+            Request::ClearToolchainCache => {
+                debug!("handle_client: clear_toolchain_cache");
+                Response::ToolchainCacheCleared(self.dist_client.clear_toolchain_cache().await?)
+            }
+            Request::ReloadConfig => {
+                debug!("handle_client: reload_config");
+                match self.reload_config() {
+                    Ok(()) => Response::ConfigReloaded(Box::new(self.get_info().await?)),
+                    Err(e) => Response::ConfigReloadFailed(format!("{:?}", e)),
+                }
             }
             Request::ZeroStats => {
                 debug!("handle_client: zero_stats");
                 self.zero_stats();
-                Box::new(self.get_info().map(|i| Response::Stats(Box::new(i))))
+                Response::Stats(Box::new(self.get_info().await?))
+            }
+            Request::GetProjectStats(project) => {
+                debug!("handle_client: get_project_stats");
+                Response::Stats(Box::new(self.get_project_info(project).await?))
+            }
+            Request::ZeroProjectStats(project) => {
+                debug!("handle_client: zero_project_stats");
+                self.project_stats.borrow_mut().remove(&project);
+                self.stats_changed.set(true);
+                Response::Stats(Box::new(self.get_project_info(project).await?))
+            }
+            Request::StartSession(name) => {
+                debug!("handle_client: start_session");
+                info!("build session {:?} started", name);
+                let mut sessions = self.sessions.borrow_mut();
+                sessions.insert(name);
+                Response::Sessions(sessions.iter().cloned().collect())
+            }
+            Request::EndSession(name) => {
+                debug!("handle_client: end_session");
+                let mut sessions = self.sessions.borrow_mut();
+                if sessions.remove(&name) {
+                    info!("build session {:?} ended", name);
+                }
+                Response::Sessions(sessions.iter().cloned().collect())
             }
             Request::Shutdown => {
                 debug!("handle_client: shutdown");
-                let future = self
-                    .tx
-                    .clone()
-                    .send(ServerMessage::Shutdown)
-                    .then(|_| Ok(()));
-                let info_future = self.get_info();
-                return Box::new(future.join(info_future).map(move |(_, info)| {
-                    Message::WithoutBody(Response::ShuttingDown(Box::new(info)))
-                }));
+                // Stop taking new requests, then reply with the final stats
+                // once the work in progress is done
+                let _ = self.tx.clone().send(ServerMessage::Shutdown).await;
+                self.drain(get_shutdown_timeout()).await;
+                let info = self.get_info().await?;
+                return Ok(Message::WithoutBody(Response::ShuttingDown(Box::new(info))));
             }
         };
 
-        Box::new(res.map(Message::WithoutBody))
-    }
-
-    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
-        Ok(Async::Ready(()))
+        Ok(Message::WithoutBody(res))
     }
 }
 
@@ -746,9 +1704,12 @@ impl<C> SccacheService<C>
 where
     C: CommandCreatorSync,
 {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         dist_client: DistClientContainer,
         storage: Arc<dyn Storage>,
+        hash_config: HashConfig,
+        toolchain_pins: ToolchainPinsConfig,
         client: &Client,
         pool: ThreadPool,
         tx: mpsc::Sender<ServerMessage>,
@@ -756,22 +1717,63 @@ where
     ) -> SccacheService<C> {
         SccacheService {
             stats: Rc::new(RefCell::new(ServerStats::default())),
+            stats_generation: Rc::new(Cell::new(0)),
+            project_stats: Rc::new(RefCell::new(HashMap::new())),
+            stats_file: None,
+            stats_changed: Rc::new(Cell::new(false)),
+            statsd: None,
+            backend_meters: BackendMeters::default(),
             dist_client: Rc::new(dist_client),
-            storage,
+            storage: Rc::new(RefCell::new(storage)),
+            hash_config: Rc::new(RefCell::new(Arc::new(hash_config))),
+            toolchain_pins: Rc::new(RefCell::new(Arc::new(toolchain_pins))),
+            projects: Rc::new(RefCell::new(ProjectsConfig::default())),
             compilers: Rc::new(RefCell::new(HashMap::new())),
             compiler_proxies: Rc::new(RefCell::new(HashMap::new())),
             pool,
             creator: C::new(client),
             tx,
             info,
+            compiles_active: Rc::new(Cell::new(0)),
+            cache_writes_active: Rc::new(Cell::new(0)),
+            last_request_id: Rc::new(Cell::new(0)),
+            active_compiles: Rc::new(RefCell::new(BTreeMap::new())),
+            recent_compiles: Rc::new(RefCell::new(VecDeque::new())),
+            sessions: Rc::new(RefCell::new(BTreeSet::new())),
+            storage_error: Rc::new(RefCell::new(None)),
+            job_slots: if client.is_inherited() {
+                None
+            } else {
+                jobserver::max_jobs().map(Client::new_num)
+            },
+            admin_token: None,
+            read_only: Rc::new(Cell::new(false)),
+            network_mode: Rc::new(Cell::new(NetworkMode::Online)),
+            offline_detector: Rc::new(RefCell::new(OfflineDetector::from_env())),
+            local_storage: Rc::new(RefCell::new(LocalStorage::Same)),
+            stopping: Rc::new(Cell::new(false)),
         }
     }
 
-    fn bind<T>(mut self, socket: T) -> impl Future<Item = (), Error = Error>
+    /// Serve the client connected on `socket` on a task of its own.
+    fn spawn_bind<T>(self, socket: T)
+    where
+        T: AsyncRead + AsyncWrite + Unpin + 'static,
+    {
+        task::spawn_local(async move {
+            if let Err(err) = self.bind(socket).await {
+                error!("{}", err);
+            }
+        });
+    }
+
+    /// Answer the requests of the client connected on `socket`, each
+    /// framed with its length and encoded with bincode, until it hangs up.
+    async fn bind<T>(self, socket: T) -> Result<()>
     where
-        T: AsyncRead + AsyncWrite + 'static,
+        T: AsyncRead + AsyncWrite + Unpin + 'static,
     {
-        let mut builder = length_delimited::Builder::new();
+        let mut builder = LengthDelimitedCodec::builder();
         if let Ok(max_frame_length_str) = env::var("SCCACHE_MAX_FRAME_LENGTH") {
             if let Ok(max_frame_length) = max_frame_length_str.parse::<usize>() {
                 builder.max_frame_length(max_frame_length);
@@ -779,64 +1781,505 @@ where
                 warn!("Content of SCCACHE_MAX_FRAME_LENGTH is  not a valid number, using default");
             }
         }
-        let io = builder.new_framed(socket);
-
-        let (sink, stream) = SccacheTransport {
-            inner: WriteBincode::new(ReadBincode::new(io)),
-        }
-        .split();
-        let sink = sink.sink_from_err::<Error>();
-
-        stream
-            .from_err::<Error>()
-            .and_then(move |input| self.call(input))
-            .and_then(|message| {
-                let f: Box<dyn Stream<Item = _, Error = _>> = match message {
-                    Message::WithoutBody(message) => Box::new(stream::once(Ok(Frame::Message {
-                        message,
-                        body: false,
-                    }))),
-                    Message::WithBody(message, body) => Box::new(
-                        stream::once(Ok(Frame::Message {
-                            message,
-                            body: true,
-                        }))
-                        .chain(Compat::new(body).map(|chunk| Frame::Body { chunk: Some(chunk) }))
-                        .chain(stream::once(Ok(Frame::Body { chunk: None }))),
-                    ),
-                };
-                Ok(f.from_err::<Error>())
-            })
-            .flatten()
-            .forward(sink)
-            .map(|_| ())
+        let mut io = builder.new_framed(socket);
+
+        while let Some(frame) = io.next().await {
+            let request = bincode::deserialize(&frame?).map_err(|e| {
+                error!("failed to decode a request: {}", e);
+                e
+            })?;
+            match self.call(request).await? {
+                Message::WithoutBody(response) => io.send(encode(&response)?).await?,
+                Message::WithBody(response, mut body) => {
+                    io.send(encode(&response)?).await?;
+                    while let Some(chunk) = body.next().await {
+                        io.send(encode(&chunk?)?).await?;
+                    }
+                }
+            }
+        }
+        Ok(())
     }
 
     /// Get dist status.
-    fn get_dist_status(&self) -> SFuture<DistInfo> {
-        f_ok(self.dist_client.get_status())
+    async fn get_dist_status(&self) -> DistStatus {
+        let info = self.dist_client.get_status().await;
+        let builders = self.dist_client.get_builders().await;
+        let stats = self.stats.borrow();
+        DistStatus {
+            info,
+            builders,
+            fallbacks: stats.dist_errors,
+            fallback_causes: stats.dist_fallbacks.clone(),
+        }
     }
 
-    /// Get info and stats about the cache.
-    fn get_info(&self) -> SFuture<ServerInfo> {
-        let stats = self.stats.borrow().clone();
-        let cache_location = self.storage.location();
-        Box::new(
-            self.storage
-                .current_size()
-                .join(self.storage.max_size())
-                .map(move |(cache_size, max_cache_size)| ServerInfo {
-                    stats,
-                    cache_location,
+    /// Re-read the config, and use its cache, hashing, toolchain pinning and
+    /// project settings for compiles from now on. The environment is still the one
+    /// the server was started in.
+    fn reload_config(&self) -> Result<()> {
+        let config = Config::load()?;
+        let storage = LayeredStorage::local_cache_from_env(
+            LimitedStorage::from_env(MeteredStorage::wrap(
+                AuditedStorage::from_env(storage_from_config(&config, &self.pool), &config),
+                &self.backend_meters,
+            )),
+            &config,
+            &self.pool,
+            &self.backend_meters,
+        );
+        info!("Reloaded the config, caching in {}", storage.location());
+        if self.hash_config.borrow().compiler_check != config.hash.compiler_check {
+            set_compiler_digest_cache(compiler_digest_cache(&config));
+        }
+        if self.hash_config.borrow().input_check != config.hash.input_check {
+            set_input_digest_cache(input_digest_cache(&config));
+        }
+        *self.local_storage.borrow_mut() = LocalStorage::new(&storage, &config);
+        *self.storage.borrow_mut() = storage;
+        *self.hash_config.borrow_mut() = Arc::new(config.hash);
+        *self.toolchain_pins.borrow_mut() = Arc::new(config.toolchain_pins);
+        *self.projects.borrow_mut() = config.projects;
+        task::spawn_local(self.check_storage());
+        Ok(())
+    }
+
+    /// Check that the storage works, logging and keeping the error if it
+    /// doesn't.
+    fn check_storage(&self) -> impl Future<Output = ()> {
+        let storage_error = self.storage_error.clone();
+        let storage = self.storage.borrow().clone();
+        async move {
+            *storage_error.borrow_mut() = match check_storage(storage).await {
+                Ok(()) => {
+                    debug!("Checked the cache storage");
+                    None
+                }
+                Err(e) => {
+                    error!("Cache storage check failed: {:?}", e);
+                    Some(format!("{:#}", e))
+                }
+            };
+        }
+    }
+
+    /// Wait for compiles in progress and their cache writes to finish, for at
+    /// most `timeout`.
+    async fn drain(&self, timeout: Duration) {
+        // There's nothing to wake us when they finish, so check regularly
+        const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let active = self.compiles_active.get() + self.cache_writes_active.get();
+            if active == 0 {
+                return;
+            }
+            if Instant::now() >= deadline {
+                warn!(
+                    "Shutting down with {} compiles and cache writes unfinished",
+                    active
+                );
+                return;
+            }
+            trace!("Waiting for {} compiles and cache writes", active);
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Count a lookup of the storage, taking the server offline if lookups
+    /// kept failing.
+    fn count_lookup(&self, failed: bool) {
+        if self.network_mode.get().is_offline() || !self.local_storage.borrow().is_remote() {
+            return;
+        }
+        if !self.offline_detector.borrow_mut().lookup(failed) {
+            return;
+        }
+        let interval = self.offline_detector.borrow().probe_interval;
+        warn!(
+            "Going offline as cache lookups kept failing, retrying every {}s",
+            interval.as_secs()
+        );
+        self.network_mode.set(NetworkMode::OfflineDetected);
+        task::spawn_local(self.probe_storage(interval));
+    }
+
+    /// Probe the storage every `interval` while the server went offline on
+    /// its own, taking it back online once the storage answers again.
+    fn probe_storage(&self, interval: Duration) -> impl Future<Output = ()> {
+        // Held on to rather than the service, as with `watch_stats`
+        let (mode, detector) = (self.network_mode.clone(), self.offline_detector.clone());
+        let storage = self.storage.clone();
+        async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                // Storage that can't be reached may still answer lookups
+                // with misses, but can't read back what it was written
+                let storage = storage.borrow().clone();
+                let answer =
+                    tokio::time::timeout(offline::PROBE_TIMEOUT, check_storage(storage)).await;
+                // An admin request took the server online or offline
+                if mode.get() != NetworkMode::OfflineDetected {
+                    return;
+                }
+                if detector.borrow_mut().probe(matches!(answer, Ok(Ok(())))) {
+                    info!("Going back online as the cache storage answers again");
+                    mode.set(NetworkMode::Online);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// The storage for compiles, which is only the local disk cache while
+    /// the server is offline, and doesn't write to the storage if an admin
+    /// request made the server read-only.
+    fn storage(&self) -> Arc<dyn Storage> {
+        let mut storage = self.storage.borrow().clone();
+        if self.network_mode.get().is_offline() {
+            storage = self.local_storage.borrow_mut().get(&storage, &self.pool);
+        }
+        if self.read_only.get() {
+            Arc::new(ReadOnlyStorage::new(storage))
+        } else {
+            storage
+        }
+    }
+
+    /// Handle an admin request, if it has the server's token.
+    async fn handle_admin(&self, token: &str, request: AdminRequest) -> AdminResponse {
+        // Compared in constant time, as any local user can connect
+        let authorized = self.admin_token.as_ref().map_or(false, |expected| {
+            util::constant_time_eq(expected.as_bytes(), token.as_bytes())
+        });
+        if !authorized {
+            warn!("Denied an admin request without the admin token");
+            return AdminResponse::Denied;
+        }
+        match request {
+            AdminRequest::Flush => {
+                info!("Flushing the compilers and the storage for an admin request");
+                self.compilers.borrow_mut().clear();
+                self.compiler_proxies.borrow_mut().clear();
+                let storage = self.storage.borrow().clone();
+                let res = storage.clear(ClearScope::Local).await;
+                AdminResponse::Flushed(res.map_err(|e| format!("{:?}", e)))
+            }
+            AdminRequest::QueryKey(key) => {
+                let storage = self.storage.borrow().clone();
+                let parts: Vec<Arc<dyn Storage>> = vec![
+                    storage.clone(),
+                    Arc::new(NamespacedStorage::new(storage, UNPINNED_NAMESPACE)),
+                ];
+                let lookups = parts.into_iter().map(|storage| {
+                    let key = &key;
+                    async move {
+                        let found = match storage.get(key).await {
+                            Ok(Cache::Hit(_)) => Ok(true),
+                            Ok(_) => Ok(false),
+                            Err(e) => Err(format!("{:?}", e)),
+                        };
+                        (storage.location(), found)
+                    }
+                });
+                AdminResponse::KeyStatus(future::join_all(lookups).await)
+            }
+            AdminRequest::SetReadOnly(read_only) => {
+                info!(
+                    "{} writing to the storage for an admin request",
+                    if read_only { "Stopped" } else { "Resumed" }
+                );
+                self.read_only.set(read_only);
+                AdminResponse::ReadOnly(read_only)
+            }
+            AdminRequest::SetOffline(offline) => {
+                info!(
+                    "Went {} for an admin request",
+                    if offline { "offline" } else { "online" }
+                );
+                self.offline_detector.borrow_mut().reset();
+                self.network_mode.set(if offline {
+                    NetworkMode::Offline
+                } else {
+                    NetworkMode::Online
+                });
+                AdminResponse::Offline(offline)
+            }
+            AdminRequest::Clear(scope) => {
+                info!("Clearing the {:?} storage for an admin request", scope);
+                // Storage made read-only by an admin request keeps its remote
+                // entries too
+                let res = self.storage().clear(scope).await;
+                AdminResponse::Cleared(res.map_err(|e| format!("{:#}", e)))
+            }
+        }
+    }
+
+    /// Stream updates of the stats to a client, starting with their current
+    /// counts, until the server shuts down.
+    fn watch_stats(&self) -> SccacheResponse {
+        // How often to check for changes to send
+        const WATCH_INTERVAL: Duration = Duration::from_millis(250);
+
+        // The watch holds on to the stats rather than the service, so that it
+        // doesn't keep the server from shutting down
+        let (stats, generation) = (self.stats.clone(), self.stats_generation.clone());
+        let (compiles_active, cache_writes_active) = (
+            self.compiles_active.clone(),
+            self.cache_writes_active.clone(),
+        );
+        let stopping = self.stopping.clone();
+        // The update since the counts of a generation of the stats, and the
+        // current ones
+        let update = move |last: Option<(u64, LiveCounts)>| {
+            let now = (generation.get(), LiveCounts::of(&stats.borrow()));
+            let (zeroed, counts) = match last {
+                Some((generation, ref counts)) if generation == now.0 => {
+                    (false, now.1.since(counts))
+                }
+                _ => (true, now.1),
+            };
+            let delta = StatsDelta {
+                zeroed,
+                counts,
+                compiles_active: compiles_active.get(),
+                cache_writes_active: cache_writes_active.get(),
+            };
+            (delta, now)
+        };
+
+        let (first, now) = update(None);
+        let (mut tx, rx) = Body::pair();
+        task::spawn_local(async move {
+            let (mut last_delta, mut last) = (first, now);
+            loop {
+                tokio::time::sleep(WATCH_INTERVAL).await;
+                if stopping.get() {
+                    if tx.send(Ok(Response::StatsWatchEnded)).await.is_err() {
+                        debug!("Stopped streaming stats: the client stopped watching");
+                    }
+                    return;
+                }
+                let (delta, now) = update(Some(last));
+                last = now;
+                let unchanged = !delta.zeroed
+                    && delta.counts == LiveCounts::default()
+                    && delta.compiles_active == last_delta.compiles_active
+                    && delta.cache_writes_active == last_delta.cache_writes_active;
+                if unchanged {
+                    continue;
+                }
+                if tx.send(Ok(Response::StatsDelta(delta))).await.is_err() {
+                    debug!("Stopped streaming stats: the client stopped watching");
+                    return;
+                }
+                last_delta = delta;
+            }
+        });
+        Message::WithBody(Response::StatsDelta(first), rx)
+    }
+
+    /// Stream a snapshot of what the server is doing every second, until
+    /// it shuts down.
+    async fn top(&self) -> Result<SccacheResponse> {
+        const TOP_INTERVAL: Duration = Duration::from_secs(1);
+
+        // As with `watch_stats`, this holds on to parts of the service rather
+        // than the service itself
+        let (stats, storage) = (self.stats.clone(), self.storage.clone());
+        let (active, recent) = (self.active_compiles.clone(), self.recent_compiles.clone());
+        let cache_writes_active = self.cache_writes_active.clone();
+        let stopping = self.stopping.clone();
+        let snapshot = move || {
+            let stats = stats.borrow();
+            let mean = |total: Duration, count: u64| match count {
+                0 => None,
+                count => Some(total / count as u32),
+            };
+            let now = Instant::now();
+            let mut snapshot = TopSnapshot {
+                counts: LiveCounts::of(&stats),
+                active: active
+                    .borrow()
+                    .values()
+                    .map(|(compile, started)| ActiveCompile {
+                        elapsed: now.duration_since(*started),
+                        ..compile.clone()
+                    })
+                    .collect(),
+                recent: recent.borrow().iter().cloned().collect(),
+                cache_writes_active: cache_writes_active.get(),
+                cache_read_hit_mean: mean(stats.cache_read_hit_duration, stats.cache_hits.all()),
+                cache_read_miss_mean: mean(
+                    stats.cache_read_miss_duration,
+                    stats.cache_misses.all(),
+                ),
+                cache_write_mean: mean(stats.cache_write_duration, stats.cache_writes),
+                cache_location: String::new(),
+                cache_size: None,
+                max_cache_size: None,
+            };
+            let storage = storage.borrow().clone();
+            snapshot.cache_location = storage.location();
+            async move {
+                let (cache_size, max_cache_size) =
+                    future::try_join(storage.current_size(), storage.max_size()).await?;
+                Ok::<_, Error>(TopSnapshot {
                     cache_size,
                     max_cache_size,
-                }),
-        )
+                    ..snapshot
+                })
+            }
+        };
+
+        let first = snapshot().await?;
+        let (mut tx, rx) = Body::pair();
+        task::spawn_local(async move {
+            loop {
+                tokio::time::sleep(TOP_INTERVAL).await;
+                let snapshot = match snapshot().await {
+                    Ok(snapshot) => snapshot,
+                    Err(e) => {
+                        debug!("Stopped streaming top: {}", e);
+                        return;
+                    }
+                };
+                let (response, done) = if stopping.get() {
+                    (Response::StatsWatchEnded, true)
+                } else {
+                    (Response::TopSnapshot(Box::new(snapshot)), false)
+                };
+                if tx.send(Ok(response)).await.is_err() {
+                    debug!("Stopped streaming top: the client stopped watching");
+                    return;
+                }
+                if done {
+                    return;
+                }
+            }
+        });
+        Ok(Message::WithBody(
+            Response::TopSnapshot(Box::new(first)),
+            rx,
+        ))
+    }
+
+    /// Get info and stats about the cache.
+    async fn get_info(&self) -> Result<ServerInfo> {
+        let stats = self.stats.borrow().with_backends(&self.backend_meters);
+        let mut projects: Vec<_> = self.project_stats.borrow().keys().cloned().collect();
+        projects.sort();
+        let storage = self.storage();
+        let cache_location = storage.location();
+        let cache_kind = storage.kind().to_owned();
+        let cache_read_only = storage.is_read_only();
+        let storage_error = self.storage_error.borrow().clone();
+        let network_mode = self.network_mode.get();
+        let probe_interval = self.offline_detector.borrow().probe_interval;
+        let (cache_size, max_cache_size) =
+            future::try_join(storage.current_size(), storage.max_size()).await?;
+        Ok(ServerInfo {
+            stats,
+            project: None,
+            projects,
+            cache_location,
+            cache_kind,
+            cache_read_only,
+            storage_error,
+            network_mode,
+            probe_interval,
+            cache_size,
+            max_cache_size,
+        })
+    }
+
+    /// Get info about the cache, with the stats of `project`.
+    async fn get_project_info(&self, project: String) -> Result<ServerInfo> {
+        let stats = self
+            .project_stats
+            .borrow()
+            .get(&project)
+            .cloned()
+            .unwrap_or_default();
+        let info = self.get_info().await?;
+        Ok(ServerInfo {
+            stats,
+            project: Some(project),
+            ..info
+        })
+    }
+
+    /// Remember a finished compile for `sccache --top`, forgetting the
+    /// oldest past `RECENT_COMPILES`.
+    fn record_recent(&self, compile: RecentCompile) {
+        let mut recent = self.recent_compiles.borrow_mut();
+        recent.push_front(compile);
+        recent.truncate(RECENT_COMPILES);
     }
 
-    /// Zero stats about the cache.
+    /// Zero stats about the cache, including those of projects.
     fn zero_stats(&self) {
         *self.stats.borrow_mut() = ServerStats::default();
+        self.stats_generation.set(self.stats_generation.get() + 1);
+        self.project_stats.borrow_mut().clear();
+        self.backend_meters.reset(HashMap::new());
+        // Saved now, so that the stats don't come back if the server doesn't
+        // shut down cleanly
+        self.stats_changed.set(true);
+        if let Some(save_stats) = self.stats_saver() {
+            save_stats();
+        }
+    }
+
+    /// A function writing the stats to their file if they changed, if
+    /// they're kept in one. It holds on to the stats rather than the
+    /// service, so that it doesn't keep the server from shutting down.
+    fn stats_saver(&self) -> Option<impl Fn() + Clone> {
+        let path = self.stats_file.clone()?;
+        let (stats, project_stats) = (self.stats.clone(), self.project_stats.clone());
+        let (changed, backend_meters) = (self.stats_changed.clone(), self.backend_meters.clone());
+        Some(move || {
+            if !changed.replace(false) {
+                return;
+            }
+            let saved = SavedStats {
+                stats: stats.borrow().with_backends(&backend_meters),
+                projects: project_stats.borrow().clone(),
+            };
+            if let Err(e) = saved.save(&path) {
+                warn!("Failed to save the stats: {:?}", e);
+            }
+        })
+    }
+
+    /// The project of a compile run in `cwd` with `env_vars`, as named by
+    /// its `SCCACHE_PROJECT` or else by the directory it's run in.
+    fn project_of(&self, cwd: &Path, env_vars: &[(OsString, OsString)]) -> Option<String> {
+        env_vars
+            .iter()
+            .find(|(k, _)| k.as_os_str() == OsStr::new("SCCACHE_PROJECT"))
+            .and_then(|(_, v)| v.to_str())
+            .filter(|v| !v.is_empty())
+            .map(str::to_owned)
+            .or_else(|| self.projects.borrow().find(cwd).map(str::to_owned))
+    }
+
+    /// Add the `stats` of a request to the server's, and to those of its
+    /// `project` if it has one.
+    fn record_stats(&self, project: Option<&str>, stats: &ServerStats) {
+        self.stats.borrow_mut().add(stats);
+        self.stats_changed.set(true);
+        if let Some(ref statsd) = self.statsd {
+            statsd.send(stats);
+        }
+        if let Some(project) = project {
+            self.project_stats
+                .borrow_mut()
+                .entry(project.to_owned())
+                .or_default()
+                .add(stats);
+        }
     }
 
     /// Handle a compile request from a client.
@@ -844,196 +2287,286 @@ where
     /// This will handle a compile request entirely, generating a response with
     /// the inital information and an optional body which will eventually
     /// contain the results of the compilation.
-    fn handle_compile(&self, compile: Compile) -> SFuture<SccacheResponse> {
+    async fn handle_compile(&self, compile: Compile) -> SccacheResponse {
         let exe = compile.exe;
         let cmd = compile.args;
         let cwd: PathBuf = compile.cwd.into();
         let env_vars = compile.env_vars;
-        let me = self.clone();
+        let project = self.project_of(&cwd, &env_vars);
+        self.record_stats(project.as_deref(), &ServerStats::compile_request());
+        if bypasses_cache(&env_vars) {
+            debug!("handle_compile: SCCACHE_NO_CACHE is set: {:?}", cmd);
+            return self.bypass_cache(project.as_deref());
+        }
 
-        Box::new(
-            self.compiler_info(exe.into(), cwd.clone(), &env_vars)
-                .map(move |info| me.check_compiler(info, cmd, cwd, env_vars)),
-        )
+        let info = self.compiler_info(exe.into(), cwd.clone(), &env_vars).await;
+        let mut stats = ServerStats::default();
+        let res = self.check_compiler(info, cmd, cwd, env_vars, project.clone(), &mut stats);
+        self.record_stats(project.as_deref(), &stats);
+        res
+    }
+
+    /// Handle a run request from a client, like `handle_compile` but for a
+    /// command with declared inputs and outputs.
+    async fn handle_run(&self, run: Run) -> SccacheResponse {
+        let exe = PathBuf::from(&run.exe);
+        let project = self.project_of(Path::new(&run.cwd), &run.env_vars);
+        self.record_stats(project.as_deref(), &ServerStats::compile_request());
+        if bypasses_cache(&run.env_vars) {
+            debug!("handle_run: SCCACHE_NO_CACHE is set: {:?}", run.args);
+            return self.bypass_cache(project.as_deref());
+        }
+        let digest = compiler_digest(&exe, &self.pool).await;
+        let mut stats = ServerStats::default();
+        let res = self.check_command(exe, digest, run, project.clone(), &mut stats);
+        self.record_stats(project.as_deref(), &stats);
+        res
+    }
+
+    /// Hash a compile and look up its entry in the storage without compiling
+    /// it, so that storage with a local cache in front of it has the entry
+    /// there when the build asks for it.
+    async fn handle_prewarm(&self, compile: Compile) -> Result<PrewarmResult> {
+        let cwd: PathBuf = compile.cwd.into();
+        let (cmd, env_vars) = (compile.args, compile.env_vars);
+        let not_cacheable = |why: &str| Ok(PrewarmResult::NotCacheable(why.to_owned()));
+        let compiler = match self
+            .compiler_info(compile.exe.into(), cwd.clone(), &env_vars)
+            .await
+        {
+            Ok(compiler) => compiler,
+            Err(e) => {
+                return Ok(PrewarmResult::Failed(format!(
+                    "unsupported compiler: {}",
+                    e
+                )))
+            }
+        };
+        let hasher = match compiler.parse_arguments(&cmd, &cwd) {
+            CompilerArguments::Ok(hasher) => hasher,
+            CompilerArguments::CannotCache(why, _) => return not_cacheable(why),
+            CompilerArguments::NotCompilation => return not_cacheable("not a compilation"),
+        };
+        let (hash_config, storage) = self.project_settings(&cwd, self.storage());
+        let storage: Arc<dyn Storage> = if self.is_pinned(&*compiler) {
+            storage
+        } else if self.toolchain_pins.borrow().unpinned == UnpinnedToolchains::Isolate {
+            Arc::new(NamespacedStorage::new(storage, UNPINNED_NAMESPACE))
+        } else {
+            return not_cacheable("unpinned toolchain");
+        };
+        hasher
+            .prewarm(
+                self.creator.clone(),
+                storage,
+                cwd,
+                env_vars,
+                hash_config,
+                self.pool.clone(),
+            )
+            .await
+    }
+
+    /// Detect the compiler of a compile, parse its arguments and hash it,
+    /// without looking it up or compiling it, to show whether and how it
+    /// would be cached.
+    async fn handle_dry_run(&self, compile: Compile) -> DryRunResult {
+        let cwd: PathBuf = compile.cwd.into();
+        let (cmd, env_vars) = (compile.args, compile.env_vars);
+        let not_cacheable = |reason: &str, detail| DryRunResult::NotCacheable {
+            reason: reason.to_owned(),
+            detail,
+        };
+        let compiler = match self
+            .compiler_info(compile.exe.into(), cwd.clone(), &env_vars)
+            .await
+        {
+            Ok(compiler) => compiler,
+            Err(e) => return DryRunResult::Failed(format!("unsupported compiler: {}", e)),
+        };
+        let hasher = match compiler.parse_arguments(&cmd, &cwd) {
+            CompilerArguments::Ok(hasher) => hasher,
+            CompilerArguments::CannotCache(why, extra_info) => {
+                return not_cacheable(why, extra_info)
+            }
+            CompilerArguments::NotCompilation => return not_cacheable("not a compilation", None),
+        };
+        let pinned = self.is_pinned(&*compiler);
+        if !pinned && self.toolchain_pins.borrow().unpinned != UnpinnedToolchains::Isolate {
+            let (digest, _) = compiler.identity();
+            return not_cacheable("unpinned toolchain", Some(digest));
+        }
+        let (hash_config, _) = self.project_settings(&cwd, self.storage());
+        let result = hasher
+            .generate_hash_key(
+                &self.creator,
+                cwd,
+                env_vars,
+                false,
+                &self.pool,
+                false,
+                &hash_config,
+                &Span::default(),
+            )
+            .await;
+        match result {
+            Ok(HashResult {
+                key, compilation, ..
+            }) => match compilation.not_cacheable_reason() {
+                Some(why) => DryRunResult::NotCacheable {
+                    reason: why.to_owned(),
+                    detail: None,
+                },
+                None => DryRunResult::Cacheable {
+                    compiler: format!("{:?}", compiler.kind()),
+                    key,
+                    pinned,
+                },
+            },
+            Err(e) => DryRunResult::Failed(format!("failed to hash: {:#}", e)),
+        }
     }
 
     /// Look up compiler info from the cache for the compiler `path`.
     /// If not cached, determine the compiler type and cache the result.
-    fn compiler_info(
+    async fn compiler_info(
         &self,
         path: PathBuf,
         cwd: PathBuf,
         env: &[(OsString, OsString)],
-    ) -> SFuture<Result<Box<dyn Compiler<C>>>> {
+    ) -> Result<Box<dyn Compiler<C>>> {
         trace!("compiler_info");
 
-        let me = self.clone();
-        let me1 = self.clone();
-
         // lookup if compiler proxy exists for the current compiler path
+        // The proxy is cloned, as the map can't stay borrowed while it
+        // resolves the compiler
+        let compiler_proxy = self
+            .compiler_proxies
+            .borrow()
+            .get(&path)
+            .map(|(compiler_proxy, _filetime)| compiler_proxy.box_clone());
+        let resolved_with_proxy = match compiler_proxy {
+            Some(compiler_proxy) => compiler_proxy
+                .resolve_proxied_executable(self.creator.clone(), cwd.clone(), env)
+                .await
+                .ok(),
+            None => None,
+        };
 
-        let path2 = path.clone();
-        let path1 = path.clone();
-        let env = env.to_vec();
-
-        let resolve_w_proxy = {
-            let compiler_proxies_borrow = self.compiler_proxies.borrow();
-
-            if let Some((compiler_proxy, _filetime)) = compiler_proxies_borrow.get(&path) {
-                let fut = compiler_proxy.resolve_proxied_executable(
-                    self.creator.clone(),
-                    cwd.clone(),
-                    env.as_slice(),
-                );
-                Box::new(fut.then(|res: Result<_>| Ok(res.ok())))
-            } else {
-                f_ok(None)
+        // use the supplied compiler path as fallback, lookup its modification time too
+        let opt = match resolved_with_proxy {
+            Some(x) => Some(x), // TODO resolve the path right away
+            None => {
+                // fallback to using the path directly
+                metadata(&path)
+                    .map(|attr| FileTime::from_last_modification_time(&attr))
+                    .ok()
+                    .map(|filetime| (path.clone(), filetime))
             }
         };
+        let (resolved_compiler_path, mtime) =
+            opt.expect("Must contain sane data, otherwise mtime is not avail");
 
-        // use the supplied compiler path as fallback, lookup its modification time too
-        let w_fallback = resolve_w_proxy.then(move |res: Result<Option<(PathBuf, FileTime)>>| {
-            let opt = match res {
-                Ok(Some(x)) => Some(x), // TODO resolve the path right away
-                _ => {
-                    // fallback to using the path directly
-                    metadata(&path2)
+        let dist_info = match self.dist_client.get_client().await {
+            Ok(Some(ref client)) => {
+                if let Some(archive) = client.get_custom_toolchain(&resolved_compiler_path) {
+                    match metadata(&archive)
                         .map(|attr| FileTime::from_last_modification_time(&attr))
-                        .ok()
-                        .map(move |filetime| (path2, filetime))
-                }
-            };
-            f_ok(opt)
-        });
-
-        let lookup_compiler = w_fallback.and_then(move |opt: Option<(PathBuf, FileTime)>| {
-            let (resolved_compiler_path, mtime) =
-                opt.expect("Must contain sane data, otherwise mtime is not avail");
-
-            let dist_info = match me1.dist_client.get_client() {
-                Ok(Some(ref client)) => {
-                    if let Some(archive) = client.get_custom_toolchain(&resolved_compiler_path) {
-                        match metadata(&archive)
-                            .map(|attr| FileTime::from_last_modification_time(&attr))
-                        {
-                            Ok(mtime) => Some((archive, mtime)),
-                            _ => None,
-                        }
-                    } else {
-                        None
+                    {
+                        Ok(mtime) => Some((archive, mtime)),
+                        _ => None,
                     }
+                } else {
+                    None
                 }
-                _ => None,
-            };
+            }
+            _ => None,
+        };
 
-            let opt = match me1.compilers.borrow().get(&resolved_compiler_path) {
-                // It's a hit only if the mtime and dist archive data matches.
-                Some(&Some(ref entry)) => {
-                    if entry.mtime == mtime && entry.dist_info == dist_info {
-                        Some(entry.compiler.clone())
-                    } else {
-                        None
-                    }
+        let opt = match self.compilers.borrow().get(&resolved_compiler_path) {
+            // It's a hit only if the mtime and dist archive data matches.
+            Some(&Some(ref entry)) => {
+                if entry.mtime == mtime && entry.dist_info == dist_info {
+                    Some(entry.compiler.clone())
+                } else {
+                    None
                 }
-                _ => None,
-            };
-            f_ok((resolved_compiler_path, mtime, opt, dist_info))
-        });
-
-        let obtain = lookup_compiler.and_then(
-            move |(resolved_compiler_path, mtime, opt, dist_info): (
-                PathBuf,
-                FileTime,
-                Option<Box<dyn Compiler<C>>>,
-                Option<(PathBuf, FileTime)>,
-            )| {
-                match opt {
-                    Some(info) => {
-                        trace!("compiler_info cache hit");
-                        f_ok(Ok(info))
-                    }
-                    None => {
-                        trace!("compiler_info cache miss");
-                        // Check the compiler type and return the result when
-                        // finished. This generally involves invoking the compiler,
-                        // so do it asynchronously.
-
-                        // the compiler path might be compiler proxy, so it is important to use
-                        // `path` (or its clone `path1`) to resolve using that one, not using `resolved_compiler_path`
-                        let x = get_compiler_info::<C>(
-                            me.creator.clone(),
-                            &path1,
-                            &cwd,
-                            env.as_slice(),
-                            &me.pool,
-                            dist_info.clone().map(|(p, _)| p),
-                        );
+            }
+            _ => None,
+        };
 
-                        Box::new(x.then(
-                            move |info: Result<(
-                                Box<dyn Compiler<C>>,
-                                Option<Box<dyn CompilerProxy<C>>>,
-                            )>| {
-                                match info {
-                                    Ok((ref c, ref proxy)) => {
-                                        // register the proxy for this compiler, so it will be used directly from now on
-                                        // and the true/resolved compiler will create table hits in the hash map
-                                        // based on the resolved path
-                                        if let Some(proxy) = proxy {
-                                            trace!(
-                                                "Inserting new path proxy {:?} @ {:?} -> {:?}",
-                                                &path,
-                                                &cwd,
-                                                resolved_compiler_path
-                                            );
-                                            let proxy: Box<dyn CompilerProxy<C>> =
-                                                proxy.box_clone();
-                                            me.compiler_proxies
-                                                .borrow_mut()
-                                                .insert(path, (proxy, mtime));
-                                        }
-                                        // TODO add some safety checks in case a proxy exists, that the initial `path` is not
-                                        // TODO the same as the resolved compiler binary
-
-                                        // cache
-                                        let map_info =
-                                            CompilerCacheEntry::new(c.clone(), mtime, dist_info);
-                                        trace!(
-                                            "Inserting POSSIBLY PROXIED cache map info for {:?}",
-                                            &resolved_compiler_path
-                                        );
-                                        me.compilers
-                                            .borrow_mut()
-                                            .insert(resolved_compiler_path, Some(map_info));
-                                    }
-                                    Err(_) => {
-                                        trace!("Inserting PLAIN cache map info for {:?}", &path);
-                                        me.compilers.borrow_mut().insert(path, None);
-                                    }
-                                }
-                                // drop the proxy information, response is compiler only
-                                let r: Result<Box<dyn Compiler<C>>> = info.map(|info| info.0);
-                                f_ok(r)
-                            },
-                        ))
-                    }
+        if let Some(info) = opt {
+            trace!("compiler_info cache hit");
+            return Ok(info);
+        }
+        trace!("compiler_info cache miss");
+        // Check the compiler type and return the result when
+        // finished. This generally involves invoking the compiler,
+        // so do it asynchronously.
+
+        // the compiler path might be compiler proxy, so it is important to use
+        // `path` to resolve using that one, not using `resolved_compiler_path`
+        let info = get_compiler_info::<C>(
+            self.creator.clone(),
+            &path,
+            &cwd,
+            env,
+            &self.pool,
+            dist_info.clone().map(|(p, _)| p),
+        )
+        .await;
+
+        match info {
+            Ok((ref c, ref proxy)) => {
+                // register the proxy for this compiler, so it will be used directly from now on
+                // and the true/resolved compiler will create table hits in the hash map
+                // based on the resolved path
+                if let Some(proxy) = proxy {
+                    trace!(
+                        "Inserting new path proxy {:?} @ {:?} -> {:?}",
+                        &path,
+                        &cwd,
+                        resolved_compiler_path
+                    );
+                    let proxy: Box<dyn CompilerProxy<C>> = proxy.box_clone();
+                    self.compiler_proxies
+                        .borrow_mut()
+                        .insert(path, (proxy, mtime));
                 }
-            },
-        );
-
-        Box::new(obtain)
+                // TODO add some safety checks in case a proxy exists, that the initial `path` is not
+                // TODO the same as the resolved compiler binary
+
+                // cache
+                let map_info = CompilerCacheEntry::new(c.clone(), mtime, dist_info);
+                trace!(
+                    "Inserting POSSIBLY PROXIED cache map info for {:?}",
+                    &resolved_compiler_path
+                );
+                self.compilers
+                    .borrow_mut()
+                    .insert(resolved_compiler_path, Some(map_info));
+            }
+            Err(_) => {
+                trace!("Inserting PLAIN cache map info for {:?}", &path);
+                self.compilers.borrow_mut().insert(path, None);
+            }
+        }
+        // drop the proxy information, response is compiler only
+        info.map(|info| info.0)
     }
 
     /// Check that we can handle and cache `cmd` when run with `compiler`.
-    /// If so, run `start_compile_task` to execute it.
+    /// If so, run `start_compile_task` to execute it. What happened is
+    /// counted in `stats`.
     fn check_compiler(
         &self,
         compiler: Result<Box<dyn Compiler<C>>>,
         cmd: Vec<OsString>,
         cwd: PathBuf,
         env_vars: Vec<(OsString, OsString)>,
+        project: Option<String>,
+        stats: &mut ServerStats,
     ) -> SccacheResponse {
-        let mut stats = self.stats.borrow_mut();
         match compiler {
             Err(e) => {
                 debug!("check_compiler: Unsupported compiler: {}", e.to_string());
@@ -1049,14 +2582,44 @@ where
                 match c.parse_arguments(&cmd, &cwd) {
                     CompilerArguments::Ok(hasher) => {
                         debug!("parse_arguments: Ok: {:?}", cmd);
-                        stats.requests_executed += 1;
-                        let (tx, rx) = Body::pair();
-                        self.start_compile_task(c, hasher, cmd, cwd, env_vars, tx);
-                        let res = CompileResponse::CompileStarted;
-                        return Message::WithBody(Response::Compile(res), rx);
+                        let pinned = self.is_pinned(&*c);
+                        if !pinned {
+                            // Log what it would take to pin the toolchain.
+                            let (digest, version) = c.identity();
+                            info!(
+                                "Unpinned toolchain {:?} (digest: {}, version: {:?})",
+                                c.kind(),
+                                digest,
+                                version
+                            );
+                        }
+                        if pinned
+                            || self.toolchain_pins.borrow().unpinned == UnpinnedToolchains::Isolate
+                        {
+                            stats.requests_executed += 1;
+                            let (tx, rx) = Body::pair();
+                            self.start_compile_task(
+                                c.kind(),
+                                pinned,
+                                hasher,
+                                cmd,
+                                cwd,
+                                env_vars,
+                                project,
+                                tx,
+                            );
+                            let res = CompileResponse::CompileStarted;
+                            return Message::WithBody(Response::Compile(res), rx);
+                        }
+                        let bucket = c.kind().stats_bucket(hasher.language());
+                        stats.requests_not_cacheable += 1;
+                        stats.count_not_cacheable(&bucket, Some("unpinned toolchain"), None);
+                        if let Some(name) = hasher.crate_name() {
+                            stats.count_crate(&bucket, name);
+                        }
                     }
                     CompilerArguments::CannotCache(why, extra_info) => {
-                        if let Some(extra_info) = extra_info {
+                        if let Some(ref extra_info) = extra_info {
                             debug!(
                                 "parse_arguments: CannotCache({}, {}): {:?}",
                                 why, extra_info, cmd
@@ -1065,7 +2628,11 @@ where
                             debug!("parse_arguments: CannotCache({}): {:?}", why, cmd)
                         }
                         stats.requests_not_cacheable += 1;
-                        *stats.not_cached.entry(why.to_string()).or_insert(0) += 1;
+                        stats.count_not_cacheable(
+                            &c.kind().stats_bucket(None),
+                            Some(why),
+                            extra_info.as_deref(),
+                        );
                     }
                     CompilerArguments::NotCompilation => {
                         debug!("parse_arguments: NotCompilation: {:?}", cmd);
@@ -1079,58 +2646,271 @@ where
         Message::WithoutBody(Response::Compile(res))
     }
 
+    /// Check that the command to `run` may be cached, and if so start it,
+    /// counting what happened in `stats`.
+    fn check_command(
+        &self,
+        exe: PathBuf,
+        digest: Result<String>,
+        run: Run,
+        project: Option<String>,
+        stats: &mut ServerStats,
+    ) -> SccacheResponse {
+        let digest = match digest {
+            Ok(digest) => digest,
+            Err(e) => {
+                debug!("check_command: Unsupported command: {}", e.to_string());
+                stats.requests_unsupported_compiler += 1;
+                return Message::WithoutBody(Response::Compile(
+                    CompileResponse::UnsupportedCompiler(OsString::from(e.to_string())),
+                ));
+            }
+        };
+        // Commands have no version to pin, only their digest.
+        let pinned = self.toolchain_pins.borrow().is_pinned(&digest, None);
+        if !pinned {
+            info!("Unpinned command {:?} (digest: {})", exe, digest);
+        }
+        if pinned || self.toolchain_pins.borrow().unpinned == UnpinnedToolchains::Isolate {
+            stats.requests_executed += 1;
+            let hasher = CommandHasher::new(
+                exe,
+                digest,
+                run.args.clone(),
+                run.inputs.into_iter().map(PathBuf::from).collect(),
+                run.outputs.into_iter().map(PathBuf::from).collect(),
+            );
+            let (tx, rx) = Body::pair();
+            self.start_compile_task(
+                CompilerKind::Command,
+                pinned,
+                Box::new(hasher),
+                run.args,
+                run.cwd.into(),
+                run.env_vars,
+                project,
+                tx,
+            );
+            let res = CompileResponse::CompileStarted;
+            return Message::WithBody(Response::Compile(res), rx);
+        }
+        stats.requests_not_cacheable += 1;
+        stats.count_not_cacheable(
+            &CompilerKind::Command.stats_bucket(None),
+            Some("unpinned toolchain"),
+            None,
+        );
+        Message::WithoutBody(Response::Compile(CompileResponse::UnhandledCompile))
+    }
+
+    /// Have the client run a compile itself, as it does those that can't be
+    /// cached, because `SCCACHE_NO_CACHE` is set for it.
+    fn bypass_cache(&self, project: Option<&str>) -> SccacheResponse {
+        let mut stats = ServerStats {
+            requests_not_cacheable: 1,
+            ..Default::default()
+        };
+        stats.not_cached.insert("SCCACHE_NO_CACHE".to_owned(), 1);
+        self.record_stats(project, &stats);
+        Message::WithoutBody(Response::Compile(CompileResponse::UnhandledCompile))
+    }
+
+    /// Check whether `compiler` may use the shared cache.
+    fn is_pinned(&self, compiler: &dyn Compiler<C>) -> bool {
+        let (digest, version) = compiler.identity();
+        self.toolchain_pins
+            .borrow()
+            .is_pinned(&digest, version.as_deref())
+    }
+
+    /// The hash settings of a compile run in `cwd`, and the `storage` it
+    /// uses, as set by the `.sccache.toml` of its project if it has one.
+    fn project_settings(
+        &self,
+        cwd: &Path,
+        storage: Arc<dyn Storage>,
+    ) -> (Arc<HashConfig>, Arc<dyn Storage>) {
+        let hash_config = self.hash_config.borrow().clone();
+        let (path, project) = match ProjectConfig::find(cwd) {
+            Ok(Some(found)) => found,
+            Ok(None) => return (hash_config, storage),
+            Err(e) => {
+                warn!("Ignoring the project config of {}: {:?}", cwd.display(), e);
+                return (hash_config, storage);
+            }
+        };
+        trace!("Using the project config {}", path.display());
+        let storage: Arc<dyn Storage> = match project.key_prefix.clone() {
+            Some(prefix) => Arc::new(NamespacedStorage::new(storage, prefix)),
+            None => storage,
+        };
+        (Arc::new(project.hash_config(&hash_config)), storage)
+    }
+
     /// Given compiler arguments `arguments`, look up
     /// a compile result in the cache or execute the compilation and store
-    /// the result in the cache.
+    /// the result in the cache. Compilers that aren't `pinned` get a cache
+    /// namespace of their own. The stats are counted for `project` too.
+    #[allow(clippy::too_many_arguments)]
     fn start_compile_task(
         &self,
-        compiler: Box<dyn Compiler<C>>,
+        kind: CompilerKind,
+        pinned: bool,
         hasher: Box<dyn CompilerHasher<C>>,
         arguments: Vec<OsString>,
         cwd: PathBuf,
         env_vars: Vec<(OsString, OsString)>,
-        tx: mpsc::Sender<Result<Response>>,
+        project: Option<String>,
+        mut tx: mpsc::Sender<Result<Response>>,
     ) {
         let force_recache = env_vars
             .iter()
             .any(|&(ref k, ref _v)| k.as_os_str() == OsStr::new("SCCACHE_RECACHE"));
-        let cache_control = if force_recache {
+        // Overrides of what the compile may do with the storage, for builds
+        // that share a server with others that may do more.
+        let env_flag = |name: &str| {
+            env_vars
+                .iter()
+                .any(|(k, v)| k.as_os_str() == OsStr::new(name) && v.as_os_str() == "1")
+        };
+        let (read_only, write_only) = (env_flag("SCCACHE_READONLY"), env_flag("SCCACHE_WRITEONLY"));
+        // Write-only compiles don't look up their entries, like recaches
+        let cache_control = if force_recache || write_only {
             CacheControl::ForceRecache
         } else {
             CacheControl::Default
         };
+        let storage: Arc<dyn Storage> = if read_only {
+            Arc::new(ReadOnlyStorage::new(self.storage()))
+        } else {
+            self.storage()
+        };
+        let (hash_config, storage) = self.project_settings(&cwd, storage);
+        // Commands run as they are, not through a compiler wrapper
+        let hash_config = if kind == CompilerKind::Command && hash_config.next_wrapper.is_some() {
+            Arc::new(HashConfig {
+                next_wrapper: None,
+                ..(*hash_config).clone()
+            })
+        } else {
+            hash_config
+        };
+        let storage: Arc<dyn Storage> = if pinned {
+            storage
+        } else {
+            Arc::new(NamespacedStorage::new(storage, UNPINNED_NAMESPACE))
+        };
         let out_pretty = hasher.output_pretty().into_owned();
+        let bucket = kind.stats_bucket(hasher.language());
+        let source = hasher.input_pretty().into_owned();
+        let crate_name = hasher.crate_name().map(str::to_owned);
+        let active = ActiveCompile {
+            source: source.clone(),
+            compiler: kind.lang_kind(),
+            elapsed: Duration::default(),
+        };
         let color_mode = hasher.color_mode();
-        let result = hasher.get_cached_or_compile(
-            self.dist_client.get_client(),
-            self.creator.clone(),
-            self.storage.clone(),
-            arguments,
-            cwd,
-            env_vars,
-            cache_control,
-            self.pool.clone(),
-        );
+        // Commands never leave this machine, nor do compiles while offline,
+        // or those run through a compiler wrapper, which does its own
+        // distributing if any.
+        let network_mode = self.network_mode.get();
+        let local_only = kind == CompilerKind::Command
+            || network_mode.is_offline()
+            || hash_config.next_wrapper.is_some();
+        let span = Span::root("compile request");
+        span.set_attribute("sccache.output", out_pretty.clone());
+        span.set_attribute("sccache.compiler", kind.lang_kind());
+        let (creator, pool) = (self.creator.clone(), self.pool.clone());
+        let (dist_container, job_slots) = (self.dist_client.clone(), self.job_slots.clone());
+        let compile_span = span.clone();
+        let result = async move {
+            let dist_client = if local_only {
+                Ok(None)
+            } else {
+                dist_container.get_client().await
+            };
+            // Without a slot the compile doesn't even start hashing
+            let _slot = match job_slots {
+                Some(ref slots) => Some(slots.acquire().await?),
+                None => None,
+            };
+            hasher
+                .get_cached_or_compile(
+                    dist_client,
+                    creator,
+                    storage,
+                    arguments,
+                    cwd,
+                    env_vars,
+                    cache_control,
+                    hash_config,
+                    pool,
+                    compile_span,
+                )
+                .await
+        };
         let me = self.clone();
-        let kind = compiler.kind();
-        let task = result.then(move |result| {
+        let start = Instant::now();
+        let request_id = me.last_request_id.get() + 1;
+        me.last_request_id.set(request_id);
+        me.compiles_active.set(me.compiles_active.get() + 1);
+        me.active_compiles
+            .borrow_mut()
+            .insert(request_id, (active, start));
+        task::spawn_local(async move {
+            let result = result.await;
             let mut cache_write = None;
-            let mut stats = me.stats.borrow_mut();
+            me.compiles_active.set(me.compiles_active.get() - 1);
+            me.active_compiles.borrow_mut().remove(&request_id);
+            let mut stats = ServerStats::default();
+            stats.compile_durations.record(start.elapsed());
             let mut res = CompileFinished {
                 color_mode,
                 ..Default::default()
             };
+            let mut outcome = "error";
+            let mut cache_read = Duration::default();
+            let mut lookup_failed = None;
             match result {
-                Ok((compiled, out)) => {
+                Ok((compiled, out, timings)) => {
+                    if let Some(duration) = timings.hash {
+                        stats.hash_durations.record(duration);
+                    }
+                    if let Some(duration) = timings.cache_get {
+                        stats.cache_get_durations.record(duration);
+                    }
+                    if let Some(duration) = timings.compile {
+                        stats.compiler_durations.record(duration);
+                    }
                     match compiled {
                         CompileResult::Error => {
                             stats.cache_errors.increment(&kind);
+                            stats.compiler(&bucket).errors += 1;
                         }
-                        CompileResult::CacheHit(duration) => {
+                        CompileResult::CacheHit(duration, size, check, version) => {
+                            outcome = "hit";
+                            match check {
+                                HitCheck::Unchecked => {}
+                                HitCheck::Matched => stats.cache_hits_checked += 1,
+                                HitCheck::Mismatched => {
+                                    stats.cache_hits_checked += 1;
+                                    stats.cache_hit_mismatches += 1;
+                                }
+                            }
+                            cache_read = duration;
+                            lookup_failed = offline::lookup_failed(None);
                             stats.cache_hits.increment(&kind);
+                            *stats.cache_hit_versions.entry(version).or_insert(0) += 1;
+                            let compiler = stats.compiler(&bucket);
+                            compiler.hits += 1;
+                            compiler.hit_read_duration += duration;
+                            compiler.hit_bytes += size;
                             stats.cache_read_hit_duration += duration;
                         }
-                        CompileResult::CacheMiss(miss_type, dist_type, duration, future) => {
+                        CompileResult::CacheMiss(miss_type, dist_type, duration, key, future) => {
+                            outcome = "miss";
+                            cache_read = duration;
+                            lookup_failed = offline::lookup_failed(Some(&miss_type));
                             match dist_type {
                                 DistType::NoDist => {}
                                 DistType::Ok(id) => {
@@ -1139,7 +2919,13 @@ where
                                         stats.dist_compiles.entry(server).or_insert(0);
                                     *server_count += 1;
                                 }
-                                DistType::Error => stats.dist_errors += 1,
+                                DistType::Error(failure) => {
+                                    stats.dist_errors += 1;
+                                    *stats
+                                        .dist_fallbacks
+                                        .entry(failure.cause.name().to_owned())
+                                        .or_insert(0) += 1;
+                                }
                             }
                             match miss_type {
                                 MissType::Normal => {}
@@ -1151,20 +2937,41 @@ where
                                 }
                                 MissType::CacheReadError => {
                                     stats.cache_errors.increment(&kind);
+                                    stats.compiler(&bucket).errors += 1;
+                                }
+                                MissType::Shed => {
+                                    stats.cache_reads_shed += 1;
                                 }
                             }
                             stats.cache_misses.increment(&kind);
+                            let compiler = stats.compiler(&bucket);
+                            compiler.misses += 1;
+                            compiler.miss_compile_duration += timings.compile.unwrap_or_default();
                             stats.cache_read_miss_duration += duration;
+                            stats.slowest.push(SlowCompile {
+                                source,
+                                compiler: bucket.clone(),
+                                duration: timings.compile.unwrap_or_default(),
+                                key,
+                            });
                             cache_write = Some(future);
                         }
-                        CompileResult::NotCacheable => {
+                        CompileResult::NotCacheable(why) => {
+                            outcome = "not_cacheable";
                             stats.cache_misses.increment(&kind);
                             stats.non_cacheable_compilations += 1;
+                            stats.count_not_cacheable(&bucket, why, None);
                         }
                         CompileResult::CompileFailed => {
+                            outcome = "failed";
                             stats.compile_fails += 1;
                         }
                     };
+                    // Lookups of the local disk cache while offline tell
+                    // nothing of the remote storage
+                    if let (Some(failed), false) = (lookup_failed, network_mode.is_offline()) {
+                        me.count_lookup(failed);
+                    }
                     let Output {
                         status,
                         stdout,
@@ -1182,6 +2989,7 @@ where
                     match err.downcast::<ProcessError>() {
                         Ok(ProcessError(output)) => {
                             debug!("Compilation failed: {:?}", output);
+                            outcome = "failed";
                             stats.compile_fails += 1;
                             match output.status.code() {
                                 Some(code) => res.retcode = Some(code),
@@ -1192,7 +3000,7 @@ where
                         }
                         Err(err) => match err.downcast::<HttpClientError>() {
                             Ok(HttpClientError(msg)) => {
-                                me.dist_client.reset_state();
+                                me.dist_client.reset_state().await;
                                 let errmsg =
                                     format!("[{:?}] http error status: {}", out_pretty, msg);
                                 error!("{}", errmsg);
@@ -1211,6 +3019,7 @@ where
                                     let _ = writeln!(error, "sccache: caused by: {}", e);
                                 }
                                 stats.cache_errors.increment(&kind);
+                                stats.compiler(&bucket).errors += 1;
                                 //TODO: figure out a better way to communicate this?
                                 res.retcode = Some(-2);
                                 res.stderr = error.into_bytes();
@@ -1219,14 +3028,60 @@ where
                     }
                 }
             };
+            if let Some(ref name) = crate_name {
+                stats.count_crate(&bucket, name);
+            }
+            me.record_stats(project.as_deref(), &stats);
+            log_event!(log::Level::Info, "compile finished";
+                "request_id" => request_id,
+                "compiler" => kind.lang_kind(),
+                "output" => out_pretty,
+                "result" => outcome,
+                "duration_ms" => start.elapsed().as_millis() as u64,
+                "cache_read_ms" => cache_read.as_millis() as u64,
+            );
+            me.record_recent(RecentCompile {
+                output: out_pretty.clone(),
+                compiler: kind.lang_kind(),
+                result: outcome.to_owned(),
+                duration: start.elapsed(),
+                cache_read,
+            });
+            span.set_attribute("sccache.result", outcome);
             let send = tx.send(Ok(Response::CompileFinished(res)));
 
-            let me = me.clone();
-            let cache_write = cache_write.then(move |result| {
+            let writing = cache_write.is_some();
+            if writing {
+                me.cache_writes_active.set(me.cache_writes_active.get() + 1);
+            }
+            let cache_write = async {
+                let result = match cache_write {
+                    Some(cache_write) => cache_write.await.map(Some),
+                    None => Ok(None),
+                };
+                if writing {
+                    me.cache_writes_active.set(me.cache_writes_active.get() - 1);
+                }
                 match result {
                     Err(e) => {
                         debug!("Error executing cache write: {}", e);
-                        me.stats.borrow_mut().cache_write_errors += 1;
+                        let stats = if e.is::<Overloaded>() {
+                            ServerStats {
+                                cache_writes_shed: 1,
+                                ..Default::default()
+                            }
+                        } else if e.is::<ReadOnly>() {
+                            ServerStats {
+                                cache_writes_suppressed: 1,
+                                ..Default::default()
+                            }
+                        } else {
+                            ServerStats {
+                                cache_write_errors: 1,
+                                ..Default::default()
+                            }
+                        };
+                        me.record_stats(project.as_deref(), &stats);
                     }
                     //TODO: save cache stats!
                     Ok(Some(info)) => {
@@ -1235,21 +3090,24 @@ where
                             info.object_file_pretty,
                             util::fmt_duration_as_secs(&info.duration)
                         );
-                        me.stats.borrow_mut().cache_writes += 1;
-                        me.stats.borrow_mut().cache_write_duration += info.duration;
+                        let mut stats = ServerStats {
+                            cache_writes: 1,
+                            cache_write_duration: info.duration,
+                            ..Default::default()
+                        };
+                        stats.cache_put_durations.record(info.duration);
+                        me.record_stats(project.as_deref(), &stats);
                     }
 
                     Ok(None) => {}
                 }
-                Ok(())
-            });
+            };
 
-            send.join(cache_write).then(|_| Ok(()))
+            // The result is sent while the entry is written, which goes on
+            // even if the client has gone
+            let _ = future::join(send, cache_write).await;
+            span.end();
         });
-
-        tokio_compat::runtime::current_thread::TaskExecutor::current()
-            .spawn_local(Box::new(task))
-            .unwrap();
     }
 }
 
@@ -1265,6 +3123,12 @@ impl PerLanguageCount {
         *count += 1;
     }
 
+    fn add(&mut self, other: &PerLanguageCount) {
+        for (key, n) in &other.counts {
+            *self.counts.entry(key.clone()).or_insert(0) += n;
+        }
+    }
+
     pub fn all(&self) -> u64 {
         self.counts.values().sum()
     }
@@ -1273,13 +3137,88 @@ impl PerLanguageCount {
         self.counts.get(key)
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &u64)> {
+        self.counts.iter()
+    }
+
     pub fn new() -> PerLanguageCount {
         Self::default()
     }
 }
 
+/// The upper bounds of the buckets of a `DurationHistogram`, in seconds.
+pub const DURATION_BUCKETS: &[f64] = &[
+    0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+];
+
+/// Counts of durations in their buckets, for Prometheus histograms.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DurationHistogram {
+    /// The count in each of `DURATION_BUCKETS`, not including those in
+    /// earlier buckets.
+    pub buckets: Vec<u64>,
+    pub sum: Duration,
+    pub count: u64,
+}
+
+impl DurationHistogram {
+    pub fn record(&mut self, duration: Duration) {
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; DURATION_BUCKETS.len()];
+        }
+        let secs = duration.as_secs_f64();
+        if let Some(i) = DURATION_BUCKETS.iter().position(|&le| secs <= le) {
+            self.buckets[i] += 1;
+        }
+        self.sum += duration;
+        self.count += 1;
+    }
+
+    /// The upper bound, in seconds, of the bucket the `p`th percentile of
+    /// the durations is in, which is infinite past the last bucket, or
+    /// `None` if there are none.
+    pub fn percentile(&self, p: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((p / 100.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut cumulative = 0;
+        for (le, n) in DURATION_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += n;
+            if cumulative >= rank {
+                return Some(*le);
+            }
+        }
+        Some(f64::INFINITY)
+    }
+
+    fn add(&mut self, other: &DurationHistogram) {
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; DURATION_BUCKETS.len()];
+        }
+        for (bucket, n) in self.buckets.iter_mut().zip(&other.buckets) {
+            *bucket += n;
+        }
+        self.sum += other.sum;
+        self.count += other.count;
+    }
+}
+
+/// How many arguments or conditions behind a reason compiles weren't cached
+/// are counted, before the rest are counted together as
+/// `OTHER_NOT_CACHED_DETAILS`.
+const NOT_CACHED_DETAILS: usize = 20;
+const OTHER_NOT_CACHED_DETAILS: &str = "(others)";
+
+/// How many of the slowest compiles that missed the cache are kept.
+const SLOWEST_COMPILES: usize = 20;
+
 /// Statistics about the server.
+///
+/// Counters added since the stats were saved to their file start from zero
+/// when they're loaded.
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
 pub struct ServerStats {
     /// The count of client compile requests.
     pub compile_requests: u64,
@@ -1301,6 +3240,9 @@ pub struct ServerStats {
     pub cache_timeouts: u64,
     /// The count of errors reading cache entries.
     pub cache_read_errors: u64,
+    /// The count of cache lookups skipped because the storage was
+    /// overloaded.
+    pub cache_reads_shed: u64,
     /// The count of compilations which were successful but couldn't be cached.
     pub non_cacheable_compilations: u64,
     /// The count of compilations which forcibly ignored the cache.
@@ -1309,6 +3251,17 @@ pub struct ServerStats {
     pub cache_write_errors: u64,
     /// The number of successful cache writes.
     pub cache_writes: u64,
+    /// The count of cache writes skipped because the storage was overloaded.
+    pub cache_writes_shed: u64,
+    /// The count of cache writes refused because the storage is read-only.
+    pub cache_writes_suppressed: u64,
+    /// The count of cache hits checked against compiling them.
+    pub cache_hits_checked: u64,
+    /// The count of those whose outputs didn't match.
+    pub cache_hit_mismatches: u64,
+    /// Counts of cache hits by the versions of the hashing and format of
+    /// their entries, as `CacheRead::version` describes them.
+    pub cache_hit_versions: HashMap<String, usize>,
     /// The total time spent writing cache entries.
     pub cache_write_duration: Duration,
     /// The total time spent reading cache hits.
@@ -1319,18 +3272,286 @@ pub struct ServerStats {
     pub compile_fails: u64,
     /// Counts of reasons why compiles were not cached.
     pub not_cached: HashMap<String, usize>,
+    /// Counts of the arguments or conditions behind the reasons compiles
+    /// were not cached, by reason, for the reasons that have them. At most
+    /// `NOT_CACHED_DETAILS` are counted for a reason, and the rest together.
+    pub not_cached_details: HashMap<String, HashMap<String, usize>>,
     /// The count of compilations that were successfully distributed indexed
     /// by the server that ran those compilations.
     pub dist_compiles: HashMap<String, usize>,
     /// The count of compilations that were distributed but failed and had to be re-run locally
     pub dist_errors: u64,
+    /// Counts of the causes of distributed compilations failing.
+    pub dist_fallbacks: HashMap<String, usize>,
+    /// How long executed compile requests took.
+    pub compile_durations: DurationHistogram,
+    /// How long hashing compiles took, including preprocessing.
+    pub hash_durations: DurationHistogram,
+    /// How long cache lookups took.
+    pub cache_get_durations: DurationHistogram,
+    /// How long cache writes took.
+    pub cache_put_durations: DurationHistogram,
+    /// How long running the compiler took, on cache misses.
+    pub compiler_durations: DurationHistogram,
+    /// The outcomes of compiles by compiler and language, as named by
+    /// `CompilerKind::stats_bucket`.
+    pub compilers: HashMap<String, CompilerStats>,
+    /// The outcomes of the lookups and writes of each storage backend, by
+    /// its location. Only the server's stats have them.
+    pub backends: HashMap<String, BackendStats>,
+    /// The slowest compiles that missed the cache, slowest first, at most
+    /// `SLOWEST_COMPILES` of them.
+    pub slowest: Vec<SlowCompile>,
+    /// The outcomes of the compiles of each Rust crate, by its
+    /// `--crate-name`.
+    pub crates: HashMap<String, CompilerStats>,
+}
+
+/// A compile that missed the cache, for the leaderboard of the slowest.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SlowCompile {
+    /// The file compiled, as shown by `sccache --top`.
+    pub source: String,
+    /// The compiler and language, as named by `CompilerKind::stats_bucket`.
+    pub compiler: String,
+    /// How long running the compiler took.
+    pub duration: Duration,
+    /// The key its entry is stored under.
+    pub key: String,
+}
+
+/// The outcomes of the compiles of one compiler and language.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct CompilerStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub errors: u64,
+    /// The count of compiles that couldn't be cached, or compile requests
+    /// that couldn't be.
+    pub not_cacheable: u64,
+    /// Counts of reasons why compiles were not cached.
+    pub not_cached: HashMap<String, usize>,
+    /// The total time running the compiler took on misses, which a hit is
+    /// estimated to save the average of.
+    pub miss_compile_duration: Duration,
+    /// The total time looking up hits took.
+    pub hit_read_duration: Duration,
+    /// The total size of the entries of hits, as they're stored.
+    pub hit_bytes: u64,
+}
+
+impl CompilerStats {
+    fn add(&mut self, other: &CompilerStats) {
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.errors += other.errors;
+        self.not_cacheable += other.not_cacheable;
+        self.miss_compile_duration += other.miss_compile_duration;
+        self.hit_read_duration += other.hit_read_duration;
+        self.hit_bytes += other.hit_bytes;
+        for (reason, n) in &other.not_cached {
+            *self.not_cached.entry(reason.clone()).or_insert(0) += n;
+        }
+    }
+
+    /// An estimate of the time hits saved: the average time the compiler
+    /// took on a miss for each hit, less the time looking them up took. It's
+    /// unknown until there's been a miss.
+    pub fn time_saved(&self) -> Option<Duration> {
+        if self.misses == 0 {
+            return None;
+        }
+        let average = self.miss_compile_duration.as_secs_f64() / self.misses as f64;
+        let saved = Duration::from_secs_f64(average * self.hits as f64);
+        Some(
+            saved
+                .checked_sub(self.hit_read_duration)
+                .unwrap_or_default(),
+        )
+    }
+
+    /// The most common reason compiles weren't cached, if any weren't.
+    fn top_reason(&self) -> Option<&str> {
+        self.not_cached
+            .iter()
+            .max_by(|(r1, c1), (r2, c2)| c1.cmp(c2).then(r2.cmp(r1)))
+            .map(|(reason, _)| reason.as_str())
+    }
+}
+
+/// The stats of the server and of its projects, as saved to their file.
+#[derive(Serialize, Deserialize, Default)]
+struct SavedStats {
+    stats: ServerStats,
+    projects: HashMap<String, ServerStats>,
+}
+
+impl SavedStats {
+    /// Load the stats saved at `path`. A missing or unreadable file just
+    /// starts them from zero.
+    fn load(path: &Path) -> SavedStats {
+        match fs::File::open(path) {
+            Ok(f) => serde_json::from_reader(io::BufReader::new(f)).unwrap_or_else(|e| {
+                warn!("Ignoring corrupt stats file {:?}: {}", path, e);
+                SavedStats::default()
+            }),
+            Err(_) => SavedStats::default(),
+        }
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Write to a temporary file first so that a server starting up
+        // never sees partially written stats.
+        let tmp = path.with_extension("tmp");
+        fs::File::create(&tmp)
+            .map_err(Error::from)
+            .and_then(|f| serde_json::to_writer(f, self).map_err(Error::from))
+            .and_then(|_| fs::rename(&tmp, path).map_err(Error::from))
+            .with_context(|| format!("failed to write {:?}", path))
+    }
+}
+
+/// The counts of compiles that `sccache --watch-stats` shows.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct LiveCounts {
+    pub compile_requests: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub cache_errors: u64,
+    pub compile_fails: u64,
+    pub cache_writes: u64,
+}
+
+impl LiveCounts {
+    fn of(stats: &ServerStats) -> LiveCounts {
+        LiveCounts {
+            compile_requests: stats.compile_requests,
+            cache_hits: stats.cache_hits.all(),
+            cache_misses: stats.cache_misses.all(),
+            cache_errors: stats.cache_errors.all(),
+            compile_fails: stats.compile_fails,
+            cache_writes: stats.cache_writes,
+        }
+    }
+
+    /// How much each count went up by since `earlier`.
+    fn since(&self, earlier: &LiveCounts) -> LiveCounts {
+        LiveCounts {
+            compile_requests: self.compile_requests - earlier.compile_requests,
+            cache_hits: self.cache_hits - earlier.cache_hits,
+            cache_misses: self.cache_misses - earlier.cache_misses,
+            cache_errors: self.cache_errors - earlier.cache_errors,
+            compile_fails: self.compile_fails - earlier.compile_fails,
+            cache_writes: self.cache_writes - earlier.cache_writes,
+        }
+    }
+
+    /// Add the increments in `delta`.
+    pub fn add(&mut self, delta: &LiveCounts) {
+        self.compile_requests += delta.compile_requests;
+        self.cache_hits += delta.cache_hits;
+        self.cache_misses += delta.cache_misses;
+        self.cache_errors += delta.cache_errors;
+        self.compile_fails += delta.compile_fails;
+        self.cache_writes += delta.cache_writes;
+    }
+}
+
+/// How the stats changed since the last update to a `sccache --watch-stats`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct StatsDelta {
+    /// Whether the counts start again from zero, as in the first update and
+    /// after the stats are zeroed.
+    pub zeroed: bool,
+    /// How much the counts went up by.
+    pub counts: LiveCounts,
+    /// The compiles in progress.
+    pub compiles_active: usize,
+    /// The cache writes in progress, of finished compiles.
+    pub cache_writes_active: usize,
+}
+
+impl StatsDelta {
+    /// Apply this update to `counts`.
+    pub fn apply(&self, counts: &mut LiveCounts) {
+        if self.zeroed {
+            *counts = LiveCounts::default();
+        }
+        counts.add(&self.counts);
+    }
+}
+
+/// How many finished compiles `sccache --top` shows.
+const RECENT_COMPILES: usize = 40;
+
+/// A compile in progress, as shown by `sccache --top`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ActiveCompile {
+    /// The file being compiled.
+    pub source: String,
+    pub compiler: String,
+    /// How long ago the compile was requested.
+    pub elapsed: Duration,
+}
+
+/// A finished compile, as shown by `sccache --top`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RecentCompile {
+    /// The file compiled to.
+    pub output: String,
+    pub compiler: String,
+    /// `hit`, `miss`, `not_cacheable`, `failed` or `error`, as logged.
+    pub result: String,
+    pub duration: Duration,
+    /// How long the lookup in the cache took.
+    pub cache_read: Duration,
+}
+
+/// What the server is doing, for `sccache --top`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct TopSnapshot {
+    pub counts: LiveCounts,
+    /// The compiles in progress, oldest first.
+    pub active: Vec<ActiveCompile>,
+    /// The compiles finished last, newest first.
+    pub recent: Vec<RecentCompile>,
+    /// The cache writes in progress, of finished compiles.
+    pub cache_writes_active: usize,
+    /// The mean time a lookup took that hit, since the stats were zeroed.
+    pub cache_read_hit_mean: Option<Duration>,
+    /// The mean time a lookup took that missed.
+    pub cache_read_miss_mean: Option<Duration>,
+    /// The mean time a cache write took.
+    pub cache_write_mean: Option<Duration>,
+    pub cache_location: String,
+    pub cache_size: Option<u64>,
+    pub max_cache_size: Option<u64>,
 }
 
 /// Info and stats about the server.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ServerInfo {
     pub stats: ServerStats,
+    /// The project the stats are of, if they're not the whole server's.
+    pub project: Option<String>,
+    /// The projects with stats of their own.
+    pub projects: Vec<String>,
     pub cache_location: String,
+    /// The kind of storage backend, such as `disk` or `s3`.
+    pub cache_kind: String,
+    pub cache_read_only: bool,
+    /// Why the storage didn't work when it was last checked, if it didn't.
+    pub storage_error: Option<String>,
+    /// Whether the server is offline, when `cache_location` is that of the
+    /// local disk cache.
+    pub network_mode: NetworkMode,
+    /// How often the storage is probed while the server went offline on its
+    /// own.
+    pub probe_interval: Duration,
     pub cache_size: Option<u64>,
     pub max_cache_size: Option<u64>,
 }
@@ -1345,6 +3566,18 @@ pub enum DistInfo {
     SchedulerStatus(Option<config::HTTPUrl>, dist::SchedulerStatusResult),
 }
 
+/// Status of the dist client, and how often compiles fell back to compiling
+/// locally since the stats were zeroed.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DistStatus {
+    pub info: DistInfo,
+    /// The scheduler's build servers, if it could be asked for them.
+    pub builders: Option<Vec<dist::ServerDetailsResult>>,
+    pub fallbacks: u64,
+    /// Counts of fallbacks by cause.
+    pub fallback_causes: HashMap<String, usize>,
+}
+
 impl Default for ServerStats {
     fn default() -> ServerStats {
         ServerStats {
@@ -1358,22 +3591,219 @@ impl Default for ServerStats {
             cache_misses: PerLanguageCount::new(),
             cache_timeouts: u64::default(),
             cache_read_errors: u64::default(),
+            cache_reads_shed: u64::default(),
             non_cacheable_compilations: u64::default(),
             forced_recaches: u64::default(),
             cache_write_errors: u64::default(),
             cache_writes: u64::default(),
+            cache_writes_shed: u64::default(),
+            cache_writes_suppressed: u64::default(),
+            cache_hits_checked: u64::default(),
+            cache_hit_mismatches: u64::default(),
+            cache_hit_versions: HashMap::new(),
             cache_write_duration: Duration::new(0, 0),
             cache_read_hit_duration: Duration::new(0, 0),
             cache_read_miss_duration: Duration::new(0, 0),
             compile_fails: u64::default(),
             not_cached: HashMap::new(),
+            not_cached_details: HashMap::new(),
             dist_compiles: HashMap::new(),
             dist_errors: u64::default(),
+            dist_fallbacks: HashMap::new(),
+            compile_durations: DurationHistogram::default(),
+            hash_durations: DurationHistogram::default(),
+            cache_get_durations: DurationHistogram::default(),
+            cache_put_durations: DurationHistogram::default(),
+            compiler_durations: DurationHistogram::default(),
+            compilers: HashMap::new(),
+            backends: HashMap::new(),
+            slowest: Vec::new(),
+            crates: HashMap::new(),
         }
     }
 }
 
 impl ServerStats {
+    /// The stats of a compile request that's yet to be handled.
+    fn compile_request() -> ServerStats {
+        ServerStats {
+            compile_requests: 1,
+            ..Default::default()
+        }
+    }
+
+    /// The estimated time hits saved, of the compilers it's known for.
+    pub fn time_saved(&self) -> Duration {
+        self.compilers
+            .values()
+            .filter_map(CompilerStats::time_saved)
+            .sum()
+    }
+
+    /// The total size of the entries of hits, as they're stored.
+    pub fn hit_bytes(&self) -> u64 {
+        self.compilers.values().map(|c| c.hit_bytes).sum()
+    }
+
+    /// These stats, with those of the storage backends counted in `meters`.
+    fn with_backends(&self, meters: &BackendMeters) -> ServerStats {
+        ServerStats {
+            backends: meters.snapshot(),
+            ..self.clone()
+        }
+    }
+
+    /// The counts of the compiles of the compiler and language `bucket`.
+    fn compiler(&mut self, bucket: &str) -> &mut CompilerStats {
+        self.compilers.entry(bucket.to_owned()).or_default()
+    }
+
+    /// Count the compile of the compiler and language `bucket` that these
+    /// are the stats of for the Rust crate `name` too.
+    fn count_crate(&mut self, bucket: &str, name: &str) {
+        if let Some(counts) = self.compilers.get(bucket) {
+            let counts = counts.clone();
+            self.crates.insert(name.to_owned(), counts);
+        }
+    }
+
+    /// Count a compile of the compiler and language `bucket` that couldn't
+    /// be cached, and why if it's known, with the argument or condition
+    /// behind it if there is one.
+    fn count_not_cacheable(&mut self, bucket: &str, why: Option<&str>, detail: Option<&str>) {
+        if let Some(why) = why {
+            *self.not_cached.entry(why.to_owned()).or_insert(0) += 1;
+            if let Some(detail) = detail {
+                *self
+                    .not_cached_details
+                    .entry(why.to_owned())
+                    .or_default()
+                    .entry(detail.to_owned())
+                    .or_insert(0) += 1;
+            }
+        }
+        let compiler = self.compiler(bucket);
+        compiler.not_cacheable += 1;
+        if let Some(why) = why {
+            *compiler.not_cached.entry(why.to_owned()).or_insert(0) += 1;
+        }
+    }
+
+    /// Add the counts in `other` to these.
+    pub fn add(&mut self, other: &ServerStats) {
+        let ServerStats {
+            compile_requests,
+            requests_unsupported_compiler,
+            requests_not_compile,
+            requests_not_cacheable,
+            requests_executed,
+            cache_errors,
+            cache_hits,
+            cache_misses,
+            cache_timeouts,
+            cache_read_errors,
+            cache_reads_shed,
+            non_cacheable_compilations,
+            forced_recaches,
+            cache_write_errors,
+            cache_writes,
+            cache_writes_shed,
+            cache_writes_suppressed,
+            cache_hits_checked,
+            cache_hit_mismatches,
+            cache_hit_versions,
+            cache_write_duration,
+            cache_read_hit_duration,
+            cache_read_miss_duration,
+            compile_fails,
+            not_cached,
+            not_cached_details,
+            dist_compiles,
+            dist_errors,
+            dist_fallbacks,
+            compile_durations,
+            hash_durations,
+            cache_get_durations,
+            cache_put_durations,
+            compiler_durations,
+            compilers,
+            backends,
+            slowest,
+            crates,
+        } = other;
+        fn add_counts(counts: &mut HashMap<String, usize>, other: &HashMap<String, usize>) {
+            for (key, n) in other {
+                *counts.entry(key.clone()).or_insert(0) += n;
+            }
+        }
+        self.compile_requests += compile_requests;
+        self.requests_unsupported_compiler += requests_unsupported_compiler;
+        self.requests_not_compile += requests_not_compile;
+        self.requests_not_cacheable += requests_not_cacheable;
+        self.requests_executed += requests_executed;
+        self.cache_errors.add(cache_errors);
+        self.cache_hits.add(cache_hits);
+        self.cache_misses.add(cache_misses);
+        self.cache_timeouts += cache_timeouts;
+        self.cache_read_errors += cache_read_errors;
+        self.cache_reads_shed += cache_reads_shed;
+        self.non_cacheable_compilations += non_cacheable_compilations;
+        self.forced_recaches += forced_recaches;
+        self.cache_write_errors += cache_write_errors;
+        self.cache_writes += cache_writes;
+        self.cache_writes_shed += cache_writes_shed;
+        self.cache_writes_suppressed += cache_writes_suppressed;
+        self.cache_hits_checked += cache_hits_checked;
+        self.cache_hit_mismatches += cache_hit_mismatches;
+        add_counts(&mut self.cache_hit_versions, cache_hit_versions);
+        self.cache_write_duration += *cache_write_duration;
+        self.cache_read_hit_duration += *cache_read_hit_duration;
+        self.cache_read_miss_duration += *cache_read_miss_duration;
+        self.compile_fails += compile_fails;
+        add_counts(&mut self.not_cached, not_cached);
+        for (reason, details) in not_cached_details {
+            let counts = self.not_cached_details.entry(reason.clone()).or_default();
+            for (detail, n) in details {
+                let detail = if counts.len() < NOT_CACHED_DETAILS || counts.contains_key(detail) {
+                    detail.as_str()
+                } else {
+                    OTHER_NOT_CACHED_DETAILS
+                };
+                *counts.entry(detail.to_owned()).or_insert(0) += n;
+            }
+        }
+        add_counts(&mut self.dist_compiles, dist_compiles);
+        self.dist_errors += dist_errors;
+        add_counts(&mut self.dist_fallbacks, dist_fallbacks);
+        self.compile_durations.add(compile_durations);
+        self.hash_durations.add(hash_durations);
+        self.cache_get_durations.add(cache_get_durations);
+        self.cache_put_durations.add(cache_put_durations);
+        self.compiler_durations.add(compiler_durations);
+        for (bucket, counts) in compilers {
+            self.compiler(bucket).add(counts);
+        }
+        for (location, counts) in backends {
+            self.backends
+                .entry(location.clone())
+                .or_default()
+                .add(counts);
+        }
+        for compile in slowest {
+            // A compile that keeps missing is only on the board once
+            match self.slowest.iter_mut().find(|c| c.key == compile.key) {
+                Some(c) if c.duration < compile.duration => *c = compile.clone(),
+                Some(_) => {}
+                None => self.slowest.push(compile.clone()),
+            }
+        }
+        self.slowest.sort_by(|c1, c2| c2.duration.cmp(&c1.duration));
+        self.slowest.truncate(SLOWEST_COMPILES);
+        for (name, counts) in crates {
+            self.crates.entry(name.clone()).or_default().add(counts);
+        }
+    }
+
     /// Print stats to stdout in a human-readable format.
     ///
     /// Return the formatted width of each of the (name, value) columns.
@@ -1422,6 +3852,21 @@ impl ServerStats {
         set_stat!(stats_vec, self.cache_read_errors, "Cache read errors");
         set_stat!(stats_vec, self.forced_recaches, "Forced recaches");
         set_stat!(stats_vec, self.cache_write_errors, "Cache write errors");
+        if self.cache_reads_shed > 0 || self.cache_writes_shed > 0 {
+            set_stat!(stats_vec, self.cache_reads_shed, "Cache lookups shed");
+            set_stat!(stats_vec, self.cache_writes_shed, "Cache writes shed");
+        }
+        if self.cache_writes_suppressed > 0 {
+            set_stat!(
+                stats_vec,
+                self.cache_writes_suppressed,
+                "Cache writes suppressed (read-only)"
+            );
+        }
+        if self.cache_hits_checked > 0 {
+            set_stat!(stats_vec, self.cache_hits_checked, "Cache hits checked");
+            set_stat!(stats_vec, self.cache_hit_mismatches, "Cache hit mismatches");
+        }
         set_stat!(stats_vec, self.compile_fails, "Compilation failures");
         set_lang_stat!(stats_vec, self.cache_errors, "Cache errors");
         set_stat!(
@@ -1467,6 +3912,16 @@ impl ServerStats {
             self.dist_errors,
             "Failed distributed compilations"
         );
+        set_stat!(
+            stats_vec,
+            fmt_duration_as_hours(self.time_saved()),
+            "Estimated time saved"
+        );
+        set_stat!(
+            stats_vec,
+            fmt_size(self.hit_bytes()),
+            "Bytes served from cache"
+        );
         let name_width = stats_vec
             .iter()
             .map(|&(ref n, _, _)| n.len())
@@ -1486,6 +3941,9 @@ impl ServerStats {
                 stat_width = stat_width + suffix_len
             );
         }
+        self.print_percentiles();
+        self.print_compilers();
+        self.print_backends();
         if !self.dist_compiles.is_empty() {
             println!("\nSuccessful distributed compiles");
             let mut counts: Vec<_> = self.dist_compiles.iter().collect();
@@ -1512,23 +3970,780 @@ impl ServerStats {
                     name_width = name_width,
                     stat_width = stat_width
                 );
+                let mut details: Vec<_> = match self.not_cached_details.get(reason) {
+                    Some(details) => details.iter().collect(),
+                    None => continue,
+                };
+                details.sort_by(|(d1, c1), (d2, c2)| c1.cmp(c2).reverse().then(d1.cmp(d2)));
+                for (detail, count) in details {
+                    println!(
+                        "  {:<name_width$} {:>stat_width$}",
+                        detail,
+                        count,
+                        name_width = name_width - 2,
+                        stat_width = stat_width
+                    );
+                }
+            }
+            println!();
+        }
+        if !self.cache_hit_versions.is_empty() {
+            println!("\nCache hits by entry version:");
+            let mut counts: Vec<_> = self.cache_hit_versions.iter().collect();
+            counts.sort_by(|(_, c1), (_, c2)| c1.cmp(c2).reverse());
+            for (version, count) in counts {
+                println!(
+                    "{:<name_width$} {:>stat_width$}",
+                    version,
+                    count,
+                    name_width = name_width,
+                    stat_width = stat_width
+                );
+            }
+            println!();
+        }
+        if !self.dist_fallbacks.is_empty() {
+            println!("\nDistributed compilation failures:");
+            let mut counts: Vec<_> = self.dist_fallbacks.iter().collect();
+            counts.sort_by(|(_, c1), (_, c2)| c1.cmp(c2).reverse());
+            for (cause, count) in counts {
+                println!(
+                    "{:<name_width$} {:>stat_width$}",
+                    cause,
+                    count,
+                    name_width = name_width,
+                    stat_width = stat_width
+                );
             }
             println!();
         }
         (name_width, stat_width)
     }
+
+    /// Print percentiles of the durations of the steps of compiles, if any
+    /// were timed.
+    fn print_percentiles(&self) {
+        let histograms = [
+            ("Compile requests", &self.compile_durations),
+            ("Hashing", &self.hash_durations),
+            ("Cache lookups", &self.cache_get_durations),
+            ("Cache writes", &self.cache_put_durations),
+            ("Compiler runs", &self.compiler_durations),
+        ];
+        if histograms.iter().all(|(_, h)| h.count == 0) {
+            return;
+        }
+        let fmt = |secs: Option<f64>| match secs {
+            None => "-".to_owned(),
+            Some(secs) if secs.is_infinite() => {
+                format!(">{}s", DURATION_BUCKETS[DURATION_BUCKETS.len() - 1])
+            }
+            Some(secs) => format!("<={}s", secs),
+        };
+        println!("\nDurations:");
+        println!(
+            "{:<16}  {:>7}  {:>8}  {:>8}  {:>8}",
+            "", "COUNT", "P50", "P90", "P99"
+        );
+        for (name, histogram) in &histograms {
+            println!(
+                "{:<16}  {:>7}  {:>8}  {:>8}  {:>8}",
+                name,
+                histogram.count,
+                fmt(histogram.percentile(50.0)),
+                fmt(histogram.percentile(90.0)),
+                fmt(histogram.percentile(99.0))
+            );
+        }
+    }
+
+    /// Print a table of the outcomes of compiles by compiler and language,
+    /// if there's more than one.
+    fn print_compilers(&self) {
+        if self.compilers.len() < 2 {
+            return;
+        }
+        let mut compilers: Vec<_> = self.compilers.iter().collect();
+        compilers.sort_by(|(b1, _), (b2, _)| b1.cmp(b2));
+        let width = compilers
+            .iter()
+            .map(|(bucket, _)| bucket.len())
+            .max()
+            .unwrap_or(0)
+            .max("COMPILER".len());
+        println!("\nCompiles by compiler:");
+        println!(
+            "{:<width$}  {:>7}  {:>7}  {:>7}  {:>13}  TOP REASON NOT CACHED",
+            "COMPILER",
+            "HITS",
+            "MISSES",
+            "ERRORS",
+            "NOT CACHEABLE",
+            width = width
+        );
+        for (bucket, counts) in compilers {
+            println!(
+                "{:<width$}  {:>7}  {:>7}  {:>7}  {:>13}  {}",
+                bucket,
+                counts.hits,
+                counts.misses,
+                counts.errors,
+                counts.not_cacheable,
+                counts.top_reason().unwrap_or("-"),
+                width = width
+            );
+        }
+    }
+
+    /// Print a table of the outcomes of the lookups and writes of each
+    /// storage backend, if any were counted.
+    fn print_backends(&self) {
+        if self.backends.is_empty() {
+            return;
+        }
+        let mut backends: Vec<_> = self.backends.iter().collect();
+        backends.sort_by(|(l1, _), (l2, _)| l1.cmp(l2));
+        let fmt = |mean: Option<Duration>| match mean {
+            Some(mean) => util::fmt_duration_as_secs(&mean),
+            None => "-".to_owned(),
+        };
+        println!("\nStorage backends:");
+        println!(
+            "{:>7}  {:>7}  {:>7}  {:>7}  {:>12}  {:>9}  {:>9}  LOCATION",
+            "HITS", "MISSES", "ERRORS", "WRITES", "WRITE ERRORS", "AVG READ", "AVG WRITE"
+        );
+        for (location, counts) in backends {
+            println!(
+                "{:>7}  {:>7}  {:>7}  {:>7}  {:>12}  {:>9}  {:>9}  {}",
+                counts.hits,
+                counts.misses,
+                counts.errors,
+                counts.writes,
+                counts.write_errors,
+                fmt(counts.read_mean()),
+                fmt(counts.write_mean()),
+                location
+            );
+        }
+    }
+}
+
+/// The version of the schema of `--stats-format json` and `csv`, changed
+/// only when a field or counter is renamed or removed, or its meaning changes.
+pub const STATS_SCHEMA_VERSION: u32 = 1;
+
+impl ServerStats {
+    /// The counters by stable names, for machine-readable output. Counts of
+    /// a language, reason or server are named `<counter>.<key>`, durations
+    /// are in seconds, and the buckets of the duration histograms are
+    /// cumulative like Prometheus's.
+    pub fn counters(&self) -> BTreeMap<String, serde_json::Value> {
+        let mut counters = BTreeMap::new();
+        for &(name, count) in &[
+            ("compile_requests", self.compile_requests),
+            ("requests_executed", self.requests_executed),
+            (
+                "requests_unsupported_compiler",
+                self.requests_unsupported_compiler,
+            ),
+            ("requests_not_compile", self.requests_not_compile),
+            ("requests_not_cacheable", self.requests_not_cacheable),
+            ("cache_timeouts", self.cache_timeouts),
+            ("cache_read_errors", self.cache_read_errors),
+            ("cache_reads_shed", self.cache_reads_shed),
+            (
+                "non_cacheable_compilations",
+                self.non_cacheable_compilations,
+            ),
+            ("forced_recaches", self.forced_recaches),
+            ("cache_write_errors", self.cache_write_errors),
+            ("cache_writes", self.cache_writes),
+            ("cache_writes_shed", self.cache_writes_shed),
+            ("cache_writes_suppressed", self.cache_writes_suppressed),
+            ("cache_hits_checked", self.cache_hits_checked),
+            ("cache_hit_mismatches", self.cache_hit_mismatches),
+            ("compile_fails", self.compile_fails),
+            ("dist_errors", self.dist_errors),
+        ] {
+            counters.insert(name.to_owned(), count.into());
+        }
+        for &(name, counts) in &[
+            ("cache_hits", &self.cache_hits),
+            ("cache_misses", &self.cache_misses),
+            ("cache_errors", &self.cache_errors),
+        ] {
+            counters.insert(name.to_owned(), counts.all().into());
+            for (lang, &count) in counts.iter() {
+                counters.insert(format!("{}.{}", name, lang), count.into());
+            }
+        }
+        for &(name, counts) in &[
+            ("not_cached", &self.not_cached),
+            ("cache_hit_versions", &self.cache_hit_versions),
+            ("dist_compiles", &self.dist_compiles),
+            ("dist_fallbacks", &self.dist_fallbacks),
+        ] {
+            for (key, &count) in counts {
+                counters.insert(format!("{}.{}", name, key), (count as u64).into());
+            }
+        }
+        for (reason, details) in &self.not_cached_details {
+            for (detail, &count) in details {
+                counters.insert(
+                    format!("not_cached_detail.{}.{}", reason, detail),
+                    (count as u64).into(),
+                );
+            }
+        }
+        for &(name, duration) in &[
+            ("cache_write_seconds", self.cache_write_duration),
+            ("cache_read_hit_seconds", self.cache_read_hit_duration),
+            ("cache_read_miss_seconds", self.cache_read_miss_duration),
+        ] {
+            counters.insert(name.to_owned(), duration.as_secs_f64().into());
+        }
+        for (bucket, counts) in &self.compilers {
+            for &(name, count) in &[
+                ("hits", counts.hits),
+                ("misses", counts.misses),
+                ("errors", counts.errors),
+                ("not_cacheable", counts.not_cacheable),
+            ] {
+                counters.insert(format!("compilers.{}.{}", bucket, name), count.into());
+            }
+            for (reason, &count) in &counts.not_cached {
+                counters.insert(
+                    format!("compilers.{}.not_cached.{}", bucket, reason),
+                    (count as u64).into(),
+                );
+            }
+            counters.insert(
+                format!("compilers.{}.hit_bytes", bucket),
+                counts.hit_bytes.into(),
+            );
+            for &(name, duration) in &[
+                ("miss_compile_seconds", counts.miss_compile_duration),
+                ("hit_read_seconds", counts.hit_read_duration),
+            ] {
+                counters.insert(
+                    format!("compilers.{}.{}", bucket, name),
+                    duration.as_secs_f64().into(),
+                );
+            }
+        }
+        for (location, counts) in &self.backends {
+            for &(name, count) in &[
+                ("hits", counts.hits),
+                ("misses", counts.misses),
+                ("errors", counts.errors),
+                ("writes", counts.writes),
+                ("write_errors", counts.write_errors),
+            ] {
+                counters.insert(format!("backends.{}.{}", location, name), count.into());
+            }
+            for &(name, duration) in &[
+                ("read_seconds", counts.read_duration),
+                ("write_seconds", counts.write_duration),
+            ] {
+                counters.insert(
+                    format!("backends.{}.{}", location, name),
+                    duration.as_secs_f64().into(),
+                );
+            }
+        }
+        counters.insert(
+            "estimated_time_saved_seconds".to_owned(),
+            self.time_saved().as_secs_f64().into(),
+        );
+        counters.insert("cache_hit_bytes".to_owned(), self.hit_bytes().into());
+        for &(name, histogram) in &[
+            ("compile_duration_seconds", &self.compile_durations),
+            ("hash_duration_seconds", &self.hash_durations),
+            ("cache_get_duration_seconds", &self.cache_get_durations),
+            ("cache_put_duration_seconds", &self.cache_put_durations),
+            ("compiler_duration_seconds", &self.compiler_durations),
+        ] {
+            counters.insert(format!("{}.count", name), histogram.count.into());
+            counters.insert(format!("{}.sum", name), histogram.sum.as_secs_f64().into());
+            let mut cumulative = 0;
+            for (i, le) in DURATION_BUCKETS.iter().enumerate() {
+                cumulative += histogram.buckets.get(i).cloned().unwrap_or(0);
+                counters.insert(format!("{}.le_{}", name, le), cumulative.into());
+            }
+        }
+        counters
+    }
+}
+
+/// The storage, as reported by `--stats-format json` and `csv`.
+#[derive(Serialize)]
+struct StorageReport<'a> {
+    kind: &'a str,
+    location: &'a str,
+    read_only: bool,
+    offline: bool,
+    size: Option<u64>,
+    max_size: Option<u64>,
+    error: Option<&'a str>,
+}
+
+/// A compile on the leaderboard of the slowest, as reported by
+/// `--show-stats --slowest --stats-format json` and `csv`.
+#[derive(Serialize)]
+struct SlowCompileReport<'a> {
+    source: &'a str,
+    compiler: &'a str,
+    duration_seconds: f64,
+    key: &'a str,
+}
+
+/// The outcomes of the compiles of a Rust crate, as reported by
+/// `--show-stats --rust --stats-format json` and `csv`.
+#[derive(Serialize)]
+struct CrateReport<'a> {
+    name: &'a str,
+    hits: u64,
+    misses: u64,
+    errors: u64,
+    not_cacheable: u64,
+    miss_compile_seconds: f64,
+    top_reason_not_cached: Option<&'a str>,
+}
+
+/// The share of lookups that hit, such as `83.3%`, or `-` if there were
+/// none.
+fn fmt_hit_rate(hits: u64, misses: u64) -> String {
+    match hits + misses {
+        0 => "-".to_owned(),
+        total => format!("{:.1}%", hits as f64 * 100.0 / total as f64),
+    }
+}
+
+/// `text` for Markdown, with what would end a table cell or be read as
+/// formatting escaped.
+fn md_escape(text: &str) -> String {
+    let mut cell = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '|' | '\\' | '`' | '*' | '_' | '<' | '>' | '[' | ']' => {
+                cell.push('\\');
+                cell.push(c);
+            }
+            '\n' | '\r' => cell.push(' '),
+            c => cell.push(c),
+        }
+    }
+    cell
+}
+
+/// Format a long duration in hours, minutes and seconds, such as
+/// `2h 05m 09s`.
+fn fmt_duration_as_hours(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {:02}s", m, s),
+        (h, m, s) => format!("{}h {:02}m {:02}s", h, m, s),
+    }
+}
+
+/// Format a size in bytes with a binary prefix, such as `12 MiB`.
+fn fmt_size(size: u64) -> String {
+    match NumberPrefix::binary(size as f64) {
+        NumberPrefix::Standalone(bytes) => format!("{} bytes", bytes),
+        NumberPrefix::Prefixed(prefix, n) => format!("{:.0} {}B", n, prefix),
+    }
+}
+
+/// Quote a CSV field if it has to be.
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
 }
 
 impl ServerInfo {
+    fn storage_report(&self) -> StorageReport<'_> {
+        StorageReport {
+            kind: &self.cache_kind,
+            location: &self.cache_location,
+            read_only: self.cache_read_only,
+            offline: self.network_mode.is_offline(),
+            size: self.cache_size,
+            max_size: self.max_cache_size,
+            error: self.storage_error.as_deref(),
+        }
+    }
+
+    /// Write the info as JSON: its fields, and `schema_version`, `storage`
+    /// and `counters` in the schema of `STATS_SCHEMA_VERSION`.
+    pub fn write_json<W: Write>(&self, w: W) -> Result<()> {
+        #[derive(Serialize)]
+        struct Report<'a> {
+            schema_version: u32,
+            #[serde(flatten)]
+            info: &'a ServerInfo,
+            storage: StorageReport<'a>,
+            counters: BTreeMap<String, serde_json::Value>,
+        }
+
+        serde_json::to_writer(
+            w,
+            &Report {
+                schema_version: STATS_SCHEMA_VERSION,
+                info: self,
+                storage: self.storage_report(),
+                counters: self.stats.counters(),
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Write the info as CSV, a `name,value` row for each of the storage's
+    /// fields and the counters, named as in `write_json`.
+    pub fn write_csv<W: Write>(&self, mut w: W) -> Result<()> {
+        let storage = serde_json::to_value(self.storage_report())?;
+        let mut rows = vec![(
+            "schema_version".to_owned(),
+            serde_json::Value::from(STATS_SCHEMA_VERSION),
+        )];
+        if let Some(ref project) = self.project {
+            rows.push(("project".to_owned(), project.as_str().into()));
+        }
+        if let serde_json::Value::Object(fields) = storage {
+            rows.extend(
+                fields
+                    .into_iter()
+                    .map(|(name, value)| (format!("storage.{}", name), value)),
+            );
+        }
+        rows.extend(self.stats.counters());
+        writeln!(w, "name,value")?;
+        for (name, value) in rows {
+            let value = match value {
+                serde_json::Value::Null => String::new(),
+                serde_json::Value::String(s) => s,
+                value => value.to_string(),
+            };
+            writeln!(w, "{},{}", csv_field(&name), csv_field(&value))?;
+        }
+        Ok(())
+    }
+
+    /// Write the info as Markdown, for a GitHub Actions job summary: a table
+    /// of the hits and misses of each compiler with their hit rates, and a
+    /// warning listing why compiles couldn't be cached if any couldn't.
+    pub fn write_markdown<W: Write>(&self, mut w: W) -> Result<()> {
+        let stats = &self.stats;
+        match self.project {
+            Some(ref project) => writeln!(w, "### sccache: {}\n", md_escape(project))?,
+            None => writeln!(w, "### sccache\n")?,
+        }
+        writeln!(
+            w,
+            "| Compiler | Hits | Misses | Errors | Not cacheable | Hit rate |"
+        )?;
+        writeln!(w, "| --- | ---: | ---: | ---: | ---: | ---: |")?;
+        let mut compilers: Vec<_> = stats.compilers.iter().collect();
+        compilers.sort_by(|(b1, _), (b2, _)| b1.cmp(b2));
+        for (bucket, counts) in compilers {
+            writeln!(
+                w,
+                "| {} | {} | {} | {} | {} | {} |",
+                md_escape(bucket),
+                counts.hits,
+                counts.misses,
+                counts.errors,
+                counts.not_cacheable,
+                fmt_hit_rate(counts.hits, counts.misses)
+            )?;
+        }
+        let (hits, misses) = (stats.cache_hits.all(), stats.cache_misses.all());
+        writeln!(
+            w,
+            "| **Total** | {} | {} | {} | {} | {} |\n",
+            hits,
+            misses,
+            stats.cache_errors.all(),
+            stats.requests_not_cacheable + stats.non_cacheable_compilations,
+            fmt_hit_rate(hits, misses)
+        )?;
+        writeln!(
+            w,
+            "Estimated time saved: {}, {} served from cache\n",
+            fmt_duration_as_hours(stats.time_saved()),
+            fmt_size(stats.hit_bytes())
+        )?;
+        if !stats.not_cached.is_empty() {
+            let total: usize = stats.not_cached.values().sum();
+            writeln!(
+                w,
+                "> **Warning**\n> {} compile{} couldn't be cached:",
+                total,
+                if total == 1 { "" } else { "s" }
+            )?;
+            let mut counts: Vec<_> = stats.not_cached.iter().collect();
+            counts.sort_by(|(r1, c1), (r2, c2)| c1.cmp(c2).reverse().then(r1.cmp(r2)));
+            for (reason, count) in counts {
+                writeln!(w, "> - {}: {}", md_escape(reason), count)?;
+            }
+            writeln!(w)?;
+        }
+        if let Some(ref e) = self.storage_error {
+            writeln!(
+                w,
+                "> **Warning**\n> The cache storage failed: {}\n",
+                md_escape(e)
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Write the slowest compiles that missed the cache as JSON, their
+    /// durations in seconds.
+    pub fn write_slowest_json<W: Write>(&self, w: W) -> Result<()> {
+        #[derive(Serialize)]
+        struct Report<'a> {
+            schema_version: u32,
+            project: Option<&'a str>,
+            slowest: Vec<SlowCompileReport<'a>>,
+        }
+
+        serde_json::to_writer(
+            w,
+            &Report {
+                schema_version: STATS_SCHEMA_VERSION,
+                project: self.project.as_deref(),
+                slowest: self.slowest_report(),
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Write the slowest compiles that missed the cache as CSV, a row for
+    /// each with the columns named as in `write_slowest_json`.
+    pub fn write_slowest_csv<W: Write>(&self, mut w: W) -> Result<()> {
+        writeln!(w, "source,compiler,duration_seconds,key")?;
+        for compile in self.slowest_report() {
+            writeln!(
+                w,
+                "{},{},{},{}",
+                csv_field(compile.source),
+                csv_field(compile.compiler),
+                compile.duration_seconds,
+                compile.key
+            )?;
+        }
+        Ok(())
+    }
+
+    fn slowest_report(&self) -> Vec<SlowCompileReport<'_>> {
+        self.stats
+            .slowest
+            .iter()
+            .map(|compile| SlowCompileReport {
+                source: &compile.source,
+                compiler: &compile.compiler,
+                duration_seconds: compile.duration.as_secs_f64(),
+                key: &compile.key,
+            })
+            .collect()
+    }
+
+    /// Print the slowest compiles that missed the cache to stdout, slowest
+    /// first.
+    pub fn print_slowest(&self) {
+        let slowest = &self.stats.slowest;
+        if slowest.is_empty() {
+            println!("No compiles have missed the cache since the statistics were zeroed");
+            return;
+        }
+        let compiler_width = slowest
+            .iter()
+            .map(|compile| compile.compiler.len())
+            .max()
+            .unwrap_or(0)
+            .max("COMPILER".len());
+        let source_width = slowest
+            .iter()
+            .map(|compile| compile.source.len())
+            .max()
+            .unwrap_or(0)
+            .max("SOURCE".len());
+        println!(
+            "{:>9}  {:<compiler_width$}  {:<source_width$}  KEY",
+            "DURATION",
+            "COMPILER",
+            "SOURCE",
+            compiler_width = compiler_width,
+            source_width = source_width
+        );
+        for compile in slowest {
+            println!(
+                "{:>9}  {:<compiler_width$}  {:<source_width$}  {}",
+                util::fmt_duration_as_secs(&compile.duration),
+                compile.compiler,
+                compile.source,
+                compile.key,
+                compiler_width = compiler_width,
+                source_width = source_width
+            );
+        }
+    }
+
+    /// Write the outcomes of the compiles of each Rust crate as JSON, the
+    /// time compiling its misses in seconds.
+    pub fn write_crates_json<W: Write>(&self, w: W) -> Result<()> {
+        #[derive(Serialize)]
+        struct Report<'a> {
+            schema_version: u32,
+            project: Option<&'a str>,
+            crates: Vec<CrateReport<'a>>,
+        }
+
+        serde_json::to_writer(
+            w,
+            &Report {
+                schema_version: STATS_SCHEMA_VERSION,
+                project: self.project.as_deref(),
+                crates: self.crates_report(),
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Write the outcomes of the compiles of each Rust crate as CSV, a row
+    /// for each with the columns named as in `write_crates_json`.
+    pub fn write_crates_csv<W: Write>(&self, mut w: W) -> Result<()> {
+        writeln!(
+            w,
+            "name,hits,misses,errors,not_cacheable,miss_compile_seconds,top_reason_not_cached"
+        )?;
+        for krate in self.crates_report() {
+            writeln!(
+                w,
+                "{},{},{},{},{},{},{}",
+                csv_field(krate.name),
+                krate.hits,
+                krate.misses,
+                krate.errors,
+                krate.not_cacheable,
+                krate.miss_compile_seconds,
+                csv_field(krate.top_reason_not_cached.unwrap_or(""))
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The crates, those that missed the most first.
+    fn crates_report(&self) -> Vec<CrateReport<'_>> {
+        let mut crates: Vec<_> = self
+            .stats
+            .crates
+            .iter()
+            .map(|(name, counts)| CrateReport {
+                name,
+                hits: counts.hits,
+                misses: counts.misses,
+                errors: counts.errors,
+                not_cacheable: counts.not_cacheable,
+                miss_compile_seconds: counts.miss_compile_duration.as_secs_f64(),
+                top_reason_not_cached: counts.top_reason(),
+            })
+            .collect();
+        crates.sort_by(|c1, c2| c2.misses.cmp(&c1.misses).then(c1.name.cmp(c2.name)));
+        crates
+    }
+
+    /// Print the outcomes of the compiles of each Rust crate to stdout,
+    /// those that missed the most first.
+    pub fn print_crates(&self) {
+        let crates = self.crates_report();
+        if crates.is_empty() {
+            println!("No Rust crates have been compiled since the statistics were zeroed");
+            return;
+        }
+        let width = crates
+            .iter()
+            .map(|krate| krate.name.len())
+            .max()
+            .unwrap_or(0)
+            .max("CRATE".len());
+        println!(
+            "{:<width$}  {:>7}  {:>7}  {:>7}  {:>13}  {:>12}  TOP REASON NOT CACHED",
+            "CRATE",
+            "HITS",
+            "MISSES",
+            "ERRORS",
+            "NOT CACHEABLE",
+            "COMPILE TIME",
+            width = width
+        );
+        for krate in crates {
+            println!(
+                "{:<width$}  {:>7}  {:>7}  {:>7}  {:>13}  {:>12}  {}",
+                krate.name,
+                krate.hits,
+                krate.misses,
+                krate.errors,
+                krate.not_cacheable,
+                util::fmt_duration_as_secs(&Duration::from_secs_f64(krate.miss_compile_seconds)),
+                krate.top_reason_not_cached.unwrap_or("-"),
+                width = width
+            );
+        }
+    }
+
     /// Print info to stdout in a human-readable format.
     pub fn print(&self) {
         let (name_width, stat_width) = self.stats.print();
+        if let Some(ref project) = self.project {
+            println!(
+                "{:<name_width$} {}",
+                "Project",
+                project,
+                name_width = name_width
+            );
+        } else if !self.projects.is_empty() {
+            println!(
+                "{:<name_width$} {}",
+                "Projects",
+                self.projects.join(", "),
+                name_width = name_width
+            );
+        }
         println!(
             "{:<name_width$} {}",
             "Cache location",
             self.cache_location,
             name_width = name_width
         );
+        let offline = match self.network_mode {
+            NetworkMode::Online => None,
+            NetworkMode::Offline => Some("requested with `sccache --offline on`".to_owned()),
+            NetworkMode::OfflineDetected => Some(format!(
+                "cache lookups kept failing, retrying every {}s",
+                self.probe_interval.as_secs()
+            )),
+        };
+        if let Some(why) = offline {
+            println!(
+                "{:<name_width$} {}",
+                "Offline",
+                why,
+                name_width = name_width
+            );
+        }
+        if let Some(ref e) = self.storage_error {
+            println!(
+                "{:<name_width$} {}",
+                "Cache error",
+                e,
+                name_width = name_width
+            );
+        }
         for &(name, val) in &[
             ("Cache size", &self.cache_size),
             ("Max cache size", &self.max_cache_size),
@@ -1553,9 +4768,106 @@ impl ServerInfo {
     }
 }
 
-enum Frame<R, R1> {
-    Body { chunk: Option<R1> },
-    Message { message: R, body: bool },
+impl DistStatus {
+    /// Write the status in a human-readable format: the scheduler, the
+    /// fallbacks, and a row for each build server.
+    pub fn write_text<W: Write>(&self, mut w: W) -> Result<()> {
+        match self.info {
+            DistInfo::Disabled(ref why) => writeln!(w, "Distributed compilation: {}", why)?,
+            #[cfg(feature = "dist-client")]
+            DistInfo::NotConnected(ref url, ref why) => {
+                writeln!(w, "Scheduler: {}: {}", describe_url(url), why)?
+            }
+            #[cfg(feature = "dist-client")]
+            DistInfo::SchedulerStatus(ref url, ref status) => writeln!(
+                w,
+                "Scheduler: {}: {} servers, {} cores, {} jobs in progress",
+                describe_url(url),
+                status.num_servers,
+                status.num_cpus,
+                status.in_progress
+            )?,
+        }
+        let mut causes: Vec<_> = self.fallback_causes.iter().collect();
+        causes.sort();
+        let causes: Vec<_> = causes
+            .into_iter()
+            .map(|(cause, count)| format!("{}: {}", cause, count))
+            .collect();
+        if causes.is_empty() {
+            writeln!(w, "Local fallbacks: {}", self.fallbacks)?;
+        } else {
+            writeln!(
+                w,
+                "Local fallbacks: {} ({})",
+                self.fallbacks,
+                causes.join(", ")
+            )?;
+        }
+
+        let builders = match self.builders {
+            Some(ref builders) if !builders.is_empty() => builders,
+            _ => return Ok(()),
+        };
+        let width = builders
+            .iter()
+            .map(|b| b.server_id.addr().to_string().len())
+            .max()
+            .unwrap_or(0)
+            .max("SERVER".len());
+        writeln!(w)?;
+        writeln!(
+            w,
+            "{:<width$}  {:>5}  {:>7}  {:>6}  {:>4}  {:>6}  {:>10}  {:>10}  STATE",
+            "SERVER",
+            "CORES",
+            "RUNNING",
+            "QUEUED",
+            "LOAD",
+            "ERRORS",
+            "LAST ERROR",
+            "TOOLCHAINS",
+            width = width
+        )?;
+        for b in builders {
+            // Load is as the scheduler sees it, jobs assigned per core
+            let load = b.jobs_assigned as f64 / b.num_cpus.max(1) as f64;
+            let last_error = b
+                .last_error_ms_ago
+                .map_or_else(|| "-".to_owned(), |ms| format!("{}s ago", ms / 1000));
+            writeln!(
+                w,
+                "{:<width$}  {:>5}  {:>7}  {:>6}  {:>4.2}  {:>6}  {:>10}  {:>10}  {}",
+                b.server_id.addr(),
+                b.num_cpus,
+                b.jobs_started,
+                b.jobs_assigned.saturating_sub(b.jobs_started),
+                load,
+                b.recent_errors,
+                last_error,
+                b.toolchains.len(),
+                if b.draining { "draining" } else { "ok" },
+                width = width
+            )?;
+        }
+        writeln!(w)?;
+        writeln!(w, "Toolchains cached:")?;
+        for b in builders {
+            let toolchains = if b.toolchains.is_empty() {
+                "none".to_owned()
+            } else {
+                b.toolchains.join(", ")
+            };
+            writeln!(w, "  {}: {}", b.server_id.addr(), toolchains)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "dist-client")]
+fn describe_url(url: &Option<config::HTTPUrl>) -> String {
+    url.as_ref()
+        .map_or_else(|| "none".to_owned(), |url| url.to_url().to_string())
 }
 
 struct Body<R> {
@@ -1569,16 +4881,13 @@ impl<R> Body<R> {
     }
 }
 
-impl<R> futures_03::Stream for Body<R> {
+impl<R> Stream for Body<R> {
     type Item = Result<R>;
     fn poll_next(
         mut self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
+        cx: &mut Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        match Pin::new(&mut self.receiver).poll().unwrap() {
-            Async::Ready(item) => std::task::Poll::Ready(item),
-            Async::NotReady => std::task::Poll::Pending,
-        }
+        Pin::new(&mut self.receiver).poll_next(cx)
     }
 }
 
@@ -1587,109 +4896,53 @@ enum Message<R, B> {
     WithoutBody(R),
 }
 
-impl<R, B> Message<R, B> {
-    fn into_inner(self) -> R {
-        match self {
-            Message::WithBody(r, _) => r,
-            Message::WithoutBody(r) => r,
-        }
-    }
-}
-
-/// Implementation of `Stream + Sink` that tokio-proto is expecting
-///
-/// This type is composed of a few layers:
-///
-/// * First there's `I`, the I/O object implementing `AsyncRead` and
-///   `AsyncWrite`
-/// * Next that's framed using the `length_delimited` module in tokio-io giving
-///   us a `Sink` and `Stream` of `BytesMut`.
-/// * Next that sink/stream is wrapped in `ReadBincode` which will cause the
-///   `Stream` implementation to switch from `BytesMut` to `Request` by parsing
-///   the bytes  bincode.
-/// * Finally that sink/stream is wrapped in `WriteBincode` which will cause the
-///   `Sink` implementation to switch from `BytesMut` to `Response` meaning that
-///   all `Response` types pushed in will be converted to `BytesMut` and pushed
-///   below.
-struct SccacheTransport<I: AsyncRead + AsyncWrite> {
-    inner: WriteBincode<ReadBincode<Framed<I>, Request>, Response>,
-}
-
-impl<I: AsyncRead + AsyncWrite> Stream for SccacheTransport<I> {
-    type Item = Message<Request, Body<()>>;
-    type Error = io::Error;
-
-    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
-        let msg = try_ready!(self.inner.poll().map_err(|e| {
-            error!("SccacheTransport::poll failed: {}", e);
-            io::Error::new(io::ErrorKind::Other, e)
-        }));
-        Ok(msg.map(Message::WithoutBody).into())
-    }
+/// A frame with `response` encoded for the client.
+fn encode(response: &Response) -> Result<Bytes> {
+    Ok(Bytes::from(bincode::serialize(response)?))
 }
 
-impl<I: AsyncRead + AsyncWrite> Sink for SccacheTransport<I> {
-    type SinkItem = Frame<Response, Response>;
-    type SinkError = io::Error;
-
-    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, io::Error> {
-        match item {
-            Frame::Message { message, body } => match self.inner.start_send(message)? {
-                AsyncSink::Ready => Ok(AsyncSink::Ready),
-                AsyncSink::NotReady(message) => {
-                    Ok(AsyncSink::NotReady(Frame::Message { message, body }))
+/// Resolves once a shutdown is requested, or the server has been idle for as
+/// long as `timeouts` allow while no build session is open.
+async fn shutdown_or_inactive(
+    mut rx: mpsc::Receiver<ServerMessage>,
+    timeouts: IdleTimeouts,
+    sessions: Rc<RefCell<BTreeSet<String>>>,
+) {
+    let mut deadline = idle_deadline(timeouts.startup);
+    loop {
+        let idle = async move {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => future::pending().await,
+            }
+        };
+        tokio::select! {
+            message = rx.next() => match message {
+                // Shutdown received!
+                Some(ServerMessage::Shutdown) => return,
+                Some(ServerMessage::Request(activity)) => {
+                    deadline = idle_deadline(timeouts.after(activity));
                 }
+                // All services have shut down, in theory this isn't possible...
+                None => return,
             },
-            Frame::Body { chunk: Some(chunk) } => match self.inner.start_send(chunk)? {
-                AsyncSink::Ready => Ok(AsyncSink::Ready),
-                AsyncSink::NotReady(chunk) => {
-                    Ok(AsyncSink::NotReady(Frame::Body { chunk: Some(chunk) }))
+            () = idle => {
+                // Ending the last session is a request, which restarts the timer
+                if sessions.borrow().is_empty() {
+                    return;
                 }
-            },
-            Frame::Body { chunk: None } => Ok(AsyncSink::Ready),
+                deadline = None;
+            }
         }
     }
-
-    fn poll_complete(&mut self) -> Poll<(), io::Error> {
-        self.inner.poll_complete()
-    }
-
-    fn close(&mut self) -> Poll<(), io::Error> {
-        self.inner.close()
-    }
 }
 
-struct ShutdownOrInactive {
-    rx: mpsc::Receiver<ServerMessage>,
-    timeout: Option<Delay>,
-    timeout_dur: Duration,
-}
-
-impl Future for ShutdownOrInactive {
-    type Item = ();
-    type Error = io::Error;
-
-    fn poll(&mut self) -> Poll<(), io::Error> {
-        loop {
-            match self.rx.poll().unwrap() {
-                Async::NotReady => break,
-                // Shutdown received!
-                Async::Ready(Some(ServerMessage::Shutdown)) => return Ok(().into()),
-                Async::Ready(Some(ServerMessage::Request)) => {
-                    if self.timeout_dur != Duration::new(0, 0) {
-                        self.timeout = Some(Delay::new(Instant::now() + self.timeout_dur));
-                    }
-                }
-                // All services have shut down, in theory this isn't possible...
-                Async::Ready(None) => return Ok(().into()),
-            }
-        }
-        match self.timeout {
-            None => Ok(Async::NotReady),
-            Some(ref mut timeout) => timeout
-                .poll()
-                .map_err(|err| io::Error::new(io::ErrorKind::Other, err)),
-        }
+/// When idling for `timeout` from now ends, unless it's zero.
+fn idle_deadline(timeout: Duration) -> Option<tokio::time::Instant> {
+    if timeout != Duration::new(0, 0) {
+        Some(tokio::time::Instant::now() + timeout)
+    } else {
+        None
     }
 }
 
@@ -1740,7 +4993,7 @@ impl Drop for ActiveInfo {
     }
 }
 
-impl std::future::Future for WaitUntilZero {
+impl Future for WaitUntilZero {
     type Output = io::Result<()>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> std::task::Poll<Self::Output> {
@@ -2,18 +2,16 @@
 //! Types for loading and managing AWS access credentials for API requests.
 #![allow(dead_code)]
 
+use async_trait::async_trait;
 use chrono::{offset, DateTime, Duration};
 use directories::UserDirs;
-use futures::future::{self, Shared};
-use futures::{Async, Future, Stream};
 use hyper::client::HttpConnector;
-use hyper::{Client, Request};
+use hyper::{Body, Client, Request};
 use hyperx::header::Connection;
 use regex::Regex;
 use serde_json::{from_str, Value};
 #[allow(unused_imports, deprecated)]
 use std::ascii::AsciiExt;
-use std::cell::RefCell;
 use std::collections::HashMap;
 use std::env::*;
 use std::fs::{self, File};
@@ -21,10 +19,10 @@ use std::io::prelude::*;
 use std::io::BufReader;
 use std::path::{Path, PathBuf};
 use std::time::Duration as StdDuration;
-use tokio_timer::Timeout;
+use tokio::sync::Mutex;
 
 use crate::errors::*;
-use crate::util::RequestExt;
+use crate::util::{secret_var, RequestExt};
 
 /// AWS API access credentials, including access key, secret key, token (for IAM profiles), and
 /// expiration timestamp.
@@ -86,24 +84,27 @@ impl AwsCredentials {
 }
 
 /// A trait for types that produce `AwsCredentials`.
+#[async_trait(?Send)]
 pub trait ProvideAwsCredentials {
     /// Produce a new `AwsCredentials`.
-    fn credentials(&self) -> SFuture<AwsCredentials>;
+    async fn credentials(&self) -> Result<AwsCredentials>;
 }
 
 /// Provides AWS credentials from environment variables.
 pub struct EnvironmentProvider;
 
+#[async_trait(?Send)]
 impl ProvideAwsCredentials for EnvironmentProvider {
-    fn credentials(&self) -> SFuture<AwsCredentials> {
-        Box::new(future::result(credentials_from_environment()))
+    async fn credentials(&self) -> Result<AwsCredentials> {
+        credentials_from_environment()
     }
 }
 
 fn credentials_from_environment() -> Result<AwsCredentials> {
-    let env_key = var("AWS_ACCESS_KEY_ID").context("No AWS_ACCESS_KEY_ID in environment")?;
+    let env_key =
+        secret_var("AWS_ACCESS_KEY_ID")?.context("No AWS_ACCESS_KEY_ID in environment")?;
     let env_secret =
-        var("AWS_SECRET_ACCESS_KEY").context("No AWS_SECRET_ACCESS_KEY in environment")?;
+        secret_var("AWS_SECRET_ACCESS_KEY")?.context("No AWS_SECRET_ACCESS_KEY in environment")?;
 
     if env_key.is_empty() || env_secret.is_empty() {
         bail!(
@@ -112,16 +113,7 @@ fn credentials_from_environment() -> Result<AwsCredentials> {
     }
 
     // Present when using temporary credentials, e.g. on Lambda with IAM roles
-    let token = match var("AWS_SESSION_TOKEN") {
-        Ok(val) => {
-            if val.is_empty() {
-                None
-            } else {
-                Some(val)
-            }
-        }
-        Err(_) => None,
-    };
+    let token = secret_var("AWS_SESSION_TOKEN")?.filter(|val| !val.is_empty());
 
     Ok(AwsCredentials::new(
         env_key,
@@ -197,12 +189,11 @@ impl ProfileProvider {
     }
 }
 
+#[async_trait(?Send)]
 impl ProvideAwsCredentials for ProfileProvider {
-    fn credentials(&self) -> SFuture<AwsCredentials> {
-        let result = parse_credentials_file(self.file_path());
-        let result = result
-            .and_then(|mut profiles| profiles.remove(self.profile()).context("profile not found"));
-        Box::new(future::result(result))
+    async fn credentials(&self) -> Result<AwsCredentials> {
+        let mut profiles = parse_credentials_file(self.file_path())?;
+        profiles.remove(self.profile()).context("profile not found")
     }
 }
 
@@ -290,150 +281,137 @@ impl IamProvider {
         }
     }
 
-    fn iam_role(&self) -> SFuture<String> {
+    async fn iam_role(&self) -> Result<String> {
         // First get the IAM role
         let address = "http://169.254.169.254/latest/meta-data/iam/security-credentials/";
         let req = Request::get(address)
             .set_header(Connection::close())
-            .body("".into())
+            .body(Body::empty())
             .unwrap();
-        let response = self.client.request(req).and_then(|response| {
-            response.into_body().fold(Vec::new(), |mut body, chunk| {
-                body.extend_from_slice(&chunk);
-                Ok::<_, hyper::Error>(body)
-            })
-        });
-
-        Box::new(
-            response
-                .then(|res| {
-                    let bytes = res.context("couldn't connect to metadata service")?;
-                    String::from_utf8(bytes)
-                        .context("Didn't get a parsable response body from metadata service")
-                })
-                .map(move |body| {
-                    let mut address = address.to_string();
-                    address.push_str(&body);
-                    address
-                }),
-        )
+        let bytes = async {
+            let response = self.client.request(req).await?;
+            hyper::body::to_bytes(response.into_body()).await
+        };
+        let bytes = bytes
+            .await
+            .context("couldn't connect to metadata service")?;
+        let body = String::from_utf8(bytes.to_vec())
+            .context("Didn't get a parsable response body from metadata service")?;
+        let mut address = address.to_string();
+        address.push_str(&body);
+        Ok(address)
     }
-}
 
-impl ProvideAwsCredentials for IamProvider {
-    fn credentials(&self) -> SFuture<AwsCredentials> {
+    async fn fetch_credentials(&self) -> Result<AwsCredentials> {
         let url = match var("AWS_IAM_CREDENTIALS_URL") {
-            Ok(url) => f_ok(url),
-            Err(_) => self.iam_role(),
+            Ok(url) => url,
+            Err(_) => self.iam_role().await?,
+        };
+        let address = url
+            .parse::<hyper::Uri>()
+            .with_context(|| format!("failed to parse `{}` as url", url))?;
+
+        debug!("Attempting to fetch credentials from {}", address);
+        let req = Request::get(address)
+            .set_header(Connection::close())
+            .body(Body::empty())
+            .unwrap();
+        let body = async {
+            let response = self
+                .client
+                .request(req)
+                .await
+                .context("failed to send http request")?;
+            hyper::body::to_bytes(response.into_body())
+                .await
+                .context("failed to read http body")
         };
-        let url = url.and_then(|url| {
-            url.parse::<hyper::Uri>()
-                .with_context(|| format!("failed to parse `{}` as url", url))
-        });
-
-        let client = self.client.clone();
-        let response = url.and_then(move |address| {
-            debug!("Attempting to fetch credentials from {}", address);
-            let req = Request::get(address)
-                .set_header(Connection::close())
-                .body("".into())
-                .unwrap();
-            client.request(req).fcontext("failed to send http request")
-        });
-        let body = response.and_then(|response| {
-            response
-                .into_body()
-                .fold(Vec::new(), |mut body, chunk| {
-                    body.extend_from_slice(&chunk);
-                    Ok::<_, hyper::Error>(body)
-                })
-                .fcontext("failed to read http body")
-        });
         let body = body
-            .map_err(|e| anyhow!("Failed to get IAM credentials: {}", e))
-            .and_then(|body| String::from_utf8(body).context("failed to read iam role response"));
-
-        let creds = body.and_then(|body| {
-            let json_object: Value;
-            match from_str(&body) {
-                Err(_) => bail!("Couldn't parse metadata response body."),
-                Ok(val) => json_object = val,
-            };
-
-            let access_key;
-            match json_object.get("AccessKeyId") {
-                None => bail!("Couldn't find AccessKeyId in response."),
-                Some(val) => {
-                    access_key = val
-                        .as_str()
-                        .expect("AccessKeyId value was not a string")
-                        .to_owned()
-                        .replace("\"", "")
-                }
-            };
-
-            let secret_key;
-            match json_object.get("SecretAccessKey") {
-                None => bail!("Couldn't find SecretAccessKey in response."),
-                Some(val) => {
-                    secret_key = val
-                        .as_str()
-                        .expect("SecretAccessKey value was not a string")
-                        .to_owned()
-                        .replace("\"", "")
-                }
-            };
-
-            let expiration;
-            match json_object.get("Expiration") {
-                None => bail!("Couldn't find Expiration in response."),
-                Some(val) => {
-                    expiration = val
-                        .as_str()
-                        .expect("Expiration value was not a string")
-                        .to_owned()
-                        .replace("\"", "")
-                }
-            };
-
-            let expiration_time = expiration
-                .parse()
-                .context("failed to parse expiration time")?;
-
-            let token_from_response;
-            match json_object.get("Token") {
-                None => bail!("Couldn't find Token in response."),
-                Some(val) => {
-                    token_from_response = val
-                        .as_str()
-                        .expect("Token value was not a string")
-                        .to_owned()
-                        .replace("\"", "")
-                }
-            };
-
-            Ok(AwsCredentials::new(
-                access_key,
-                secret_key,
-                Some(token_from_response),
-                expiration_time,
-            ))
-        });
+            .await
+            .map_err(|e| anyhow!("Failed to get IAM credentials: {}", e))?;
+        let body = String::from_utf8(body.to_vec()).context("failed to read iam role response")?;
+
+        let json_object: Value;
+        match from_str(&body) {
+            Err(_) => bail!("Couldn't parse metadata response body."),
+            Ok(val) => json_object = val,
+        };
+
+        let access_key;
+        match json_object.get("AccessKeyId") {
+            None => bail!("Couldn't find AccessKeyId in response."),
+            Some(val) => {
+                access_key = val
+                    .as_str()
+                    .expect("AccessKeyId value was not a string")
+                    .to_owned()
+                    .replace("\"", "")
+            }
+        };
+
+        let secret_key;
+        match json_object.get("SecretAccessKey") {
+            None => bail!("Couldn't find SecretAccessKey in response."),
+            Some(val) => {
+                secret_key = val
+                    .as_str()
+                    .expect("SecretAccessKey value was not a string")
+                    .to_owned()
+                    .replace("\"", "")
+            }
+        };
 
+        let expiration;
+        match json_object.get("Expiration") {
+            None => bail!("Couldn't find Expiration in response."),
+            Some(val) => {
+                expiration = val
+                    .as_str()
+                    .expect("Expiration value was not a string")
+                    .to_owned()
+                    .replace("\"", "")
+            }
+        };
+
+        let expiration_time = expiration
+            .parse()
+            .context("failed to parse expiration time")?;
+
+        let token_from_response;
+        match json_object.get("Token") {
+            None => bail!("Couldn't find Token in response."),
+            Some(val) => {
+                token_from_response = val
+                    .as_str()
+                    .expect("Token value was not a string")
+                    .to_owned()
+                    .replace("\"", "")
+            }
+        };
+
+        Ok(AwsCredentials::new(
+            access_key,
+            secret_key,
+            Some(token_from_response),
+            expiration_time,
+        ))
+    }
+}
+
+#[async_trait(?Send)]
+impl ProvideAwsCredentials for IamProvider {
+    async fn credentials(&self) -> Result<AwsCredentials> {
         //XXX: this is crappy, but this blocks on non-EC2 machines like
         // our mac builders.
-        let timeout = Timeout::new(creds, StdDuration::from_secs(2));
-
-        Box::new(timeout.then(|result| match result {
-            Ok(creds) => Ok(creds),
-            Err(err) => match err.into_inner() {
-                None => bail!("took too long to fetch credentials"),
-                Some(e) => {
-                    warn!("Failed to fetch IAM credentials: {}", e);
-                    Err(e)
-                }
-            },
-        }))
+        let timeout = StdDuration::from_secs(2);
+        match tokio::time::timeout(timeout, self.fetch_credentials()).await {
+            Ok(Ok(creds)) => Ok(creds),
+            Ok(Err(e)) => {
+                warn!("Failed to fetch IAM credentials: {}", e);
+                Err(e)
+            }
+            Err(_) => bail!("took too long to fetch credentials"),
+        }
     }
 }
 
@@ -442,30 +420,30 @@ impl ProvideAwsCredentials for IamProvider {
 /// they have expired, in which case they are retrieved from the wrapped provider again.
 pub struct AutoRefreshingProvider<P> {
     credentials_provider: P,
-    cached_credentials: RefCell<Shared<SFuture<AwsCredentials>>>,
+    cached_credentials: Mutex<Option<AwsCredentials>>,
 }
 
 impl<P: ProvideAwsCredentials> AutoRefreshingProvider<P> {
     pub fn new(provider: P) -> AutoRefreshingProvider<P> {
         AutoRefreshingProvider {
-            cached_credentials: RefCell::new(provider.credentials().shared()),
+            cached_credentials: Mutex::new(None),
             credentials_provider: provider,
         }
     }
 }
 
+#[async_trait(?Send)]
 impl<P: ProvideAwsCredentials> ProvideAwsCredentials for AutoRefreshingProvider<P> {
-    fn credentials(&self) -> SFuture<AwsCredentials> {
-        let mut future = self.cached_credentials.borrow_mut();
-        if let Ok(Async::Ready(creds)) = future.poll() {
-            if creds.credentials_are_expired() {
-                *future = self.credentials_provider.credentials().shared();
-            }
+    async fn credentials(&self) -> Result<AwsCredentials> {
+        // Held while they're fetched, so that lookups at once share them
+        let mut cached = self.cached_credentials.lock().await;
+        match *cached {
+            Some(ref creds) if !creds.credentials_are_expired() => return Ok(creds.clone()),
+            _ => {}
         }
-        Box::new(future.clone().then(|result| match result {
-            Ok(e) => Ok((*e).clone()),
-            Err(e) => Err(anyhow!(e.to_string())),
-        }))
+        let creds = self.credentials_provider.credentials().await?;
+        *cached = Some(creds.clone());
+        Ok(creds)
     }
 }
 
@@ -483,31 +461,27 @@ pub struct ChainProvider {
     profile_providers: Vec<ProfileProvider>,
 }
 
+#[async_trait(?Send)]
 impl ProvideAwsCredentials for ChainProvider {
-    fn credentials(&self) -> SFuture<AwsCredentials> {
-        let creds = EnvironmentProvider.credentials().map(|c| {
+    async fn credentials(&self) -> Result<AwsCredentials> {
+        if let Ok(creds) = EnvironmentProvider.credentials().await {
             debug!("Using AWS credentials from environment");
-            c
-        });
-        let mut creds = Box::new(creds) as SFuture<_>;
+            return Ok(creds);
+        }
         for provider in self.profile_providers.iter() {
-            let alternate = provider.credentials();
-            creds = Box::new(creds.or_else(|_| alternate));
+            if let Ok(creds) = provider.credentials().await {
+                return Ok(creds);
+            }
+        }
+        match IamProvider::new().credentials().await {
+            Ok(creds) => {
+                debug!("Using AWS credentials from IAM");
+                Ok(creds)
+            }
+            Err(_) => bail!(
+                "Couldn't find AWS credentials in environment, credentials file, or IAM role."
+            ),
         }
-        Box::new(
-            creds
-                .or_else(move |_| {
-                    IamProvider::new().credentials().map(|c| {
-                        debug!("Using AWS credentials from IAM");
-                        c
-                    })
-                })
-                .map_err(|_| {
-                    anyhow!(
-                    "Couldn't find AWS credentials in environment, credentials file, or IAM role."
-                )
-                }),
-        )
     }
 }
 
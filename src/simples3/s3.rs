@@ -5,17 +5,17 @@
 use std::ascii::AsciiExt;
 use std::fmt;
 
+use crate::cache::ranged;
 use crate::simples3::credential::*;
-use futures::{Future, Stream};
 use hmac::{Hmac, Mac, NewMac};
-use hyper::header::HeaderValue;
+use hyper::header::{HeaderMap, HeaderValue};
 use hyper::Method;
 use hyperx::header;
-use reqwest::r#async::{Client, Request};
+use reqwest::{Client, Request};
 use sha1::Sha1;
 
 use crate::errors::*;
-use crate::util::HeadersExt;
+use crate::util::{http_client, HeadersExt};
 
 #[derive(Debug, Copy, Clone)]
 #[allow(dead_code)]
@@ -68,20 +68,19 @@ impl Bucket {
         Ok(Bucket {
             name: name.to_owned(),
             base_url,
-            client: Client::new(),
+            client: http_client("S3")?,
         })
     }
 
-    pub fn get(&self, key: &str, creds: Option<&AwsCredentials>) -> SFuture<Vec<u8>> {
+    pub async fn get(&self, key: &str, creds: Option<&AwsCredentials>) -> Result<Vec<u8>> {
         let url = format!("{}{}", self.base_url, key);
         debug!("GET {}", url);
-        let url2 = url.clone();
-        let mut request = Request::new(Method::GET, url.parse().unwrap());
+        let mut headers = HeaderMap::new();
         if let Some(creds) = creds {
             let mut canonical_headers = String::new();
 
             if let Some(token) = creds.token().as_ref().map(|s| s.as_str()) {
-                request.headers_mut().insert(
+                headers.insert(
                     "x-amz-security-token",
                     HeaderValue::from_str(token).expect("Invalid `x-amz-security-token` header"),
                 );
@@ -90,56 +89,27 @@ impl Bucket {
             }
             let date = chrono::offset::Utc::now().to_rfc2822();
             let auth = self.auth("GET", &date, key, "", &canonical_headers, "", creds);
-            request.headers_mut().insert(
+            headers.insert(
                 "Date",
                 HeaderValue::from_str(&date).expect("Invalid date header"),
             );
-            request.headers_mut().insert(
+            headers.insert(
                 "Authorization",
                 HeaderValue::from_str(&auth).expect("Invalid authentication"),
             );
         }
 
-        Box::new(
-            self.client
-                .execute(request)
-                .fwith_context(move || format!("failed GET: {}", url))
-                .and_then(|res| {
-                    if res.status().is_success() {
-                        let content_length = res
-                            .headers()
-                            .get_hyperx::<header::ContentLength>()
-                            .map(|header::ContentLength(len)| len);
-                        Ok((res.into_body(), content_length))
-                    } else {
-                        Err(BadHttpStatusError(res.status()).into())
-                    }
-                })
-                .and_then(|(body, content_length)| {
-                    body.fold(Vec::new(), |mut body, chunk| {
-                        body.extend_from_slice(&chunk);
-                        Ok::<_, reqwest::Error>(body)
-                    })
-                    .fcontext("failed to read HTTP body")
-                    .and_then(move |bytes| {
-                        if let Some(len) = content_length {
-                            if len != bytes.len() as u64 {
-                                bail!(format!(
-                                    "Bad HTTP body size read: {}, expected {}",
-                                    bytes.len(),
-                                    len
-                                ));
-                            } else {
-                                info!("Read {} bytes from {}", bytes.len(), url2);
-                            }
-                        }
-                        Ok(bytes)
-                    })
-                }),
-        )
+        let request = || {
+            let mut request = Request::new(Method::GET, url.parse().unwrap());
+            *request.headers_mut() = headers.clone();
+            request
+        };
+        let bytes = ranged::download(&self.client, request).await?;
+        info!("Read {} bytes from {}", bytes.len(), url);
+        Ok(bytes)
     }
 
-    pub fn put(&self, key: &str, content: Vec<u8>, creds: &AwsCredentials) -> SFuture<()> {
+    pub async fn put(&self, key: &str, content: Vec<u8>, creds: &AwsCredentials) -> Result<()> {
         let url = format!("{}{}", self.base_url, key);
         debug!("PUT {}", url);
         let mut request = Request::new(Method::PUT, url.parse().unwrap());
@@ -189,7 +159,7 @@ impl Bucket {
         );
         *request.body_mut() = Some(content.into());
 
-        Box::new(self.client.execute(request).then(|result| match result {
+        match self.client.execute(request).await {
             Ok(res) => {
                 if res.status().is_success() {
                     trace!("PUT succeeded");
@@ -203,7 +173,103 @@ impl Bucket {
                 trace!("PUT failed with error: {:?}", e);
                 Err(e.into())
             }
-        }))
+        }
+    }
+
+    pub async fn delete(&self, key: &str, creds: &AwsCredentials) -> Result<()> {
+        let url = format!("{}{}", self.base_url, key);
+        debug!("DELETE {}", url);
+        let mut request = Request::new(Method::DELETE, url.parse().unwrap());
+
+        let mut canonical_headers = String::new();
+        if let Some(token) = creds.token().as_ref().map(|s| s.as_str()) {
+            request.headers_mut().insert(
+                "x-amz-security-token",
+                HeaderValue::from_str(token).expect("Invalid `x-amz-security-token` header"),
+            );
+            canonical_headers.push_str(format!("{}:{}\n", "x-amz-security-token", token).as_ref());
+        }
+        let date = chrono::offset::Utc::now().to_rfc2822();
+        let auth = self.auth("DELETE", &date, key, "", &canonical_headers, "", creds);
+        request.headers_mut().insert(
+            "Date",
+            HeaderValue::from_str(&date).expect("Invalid date header"),
+        );
+        request.headers_mut().insert(
+            "Authorization",
+            HeaderValue::from_str(&auth).expect("Invalid authentication"),
+        );
+
+        match self.client.execute(request).await {
+            Ok(res) => {
+                if res.status().is_success() {
+                    trace!("DELETE succeeded");
+                    Ok(())
+                } else {
+                    trace!("DELETE failed with HTTP status: {}", res.status());
+                    Err(BadHttpStatusError(res.status()).into())
+                }
+            }
+            Err(e) => {
+                trace!("DELETE failed with error: {:?}", e);
+                Err(e.into())
+            }
+        }
+    }
+
+    /// List the keys starting with `prefix`, after `marker` if it's given, a
+    /// page at a time. Returns the keys, and whether there are more after
+    /// them.
+    pub async fn list(
+        &self,
+        prefix: &str,
+        marker: Option<&str>,
+        creds: &AwsCredentials,
+    ) -> Result<(Vec<String>, bool)> {
+        let mut url: reqwest::Url = match self.base_url.parse() {
+            Ok(url) => url,
+            Err(e) => bail!("invalid bucket URL {}: {}", self.base_url, e),
+        };
+        {
+            let mut query = url.query_pairs_mut();
+            query.append_pair("prefix", prefix);
+            if let Some(marker) = marker {
+                query.append_pair("marker", marker);
+            }
+        }
+        debug!("GET {}", url);
+        let mut request = Request::new(Method::GET, url.clone());
+
+        let mut canonical_headers = String::new();
+        if let Some(token) = creds.token().as_ref().map(|s| s.as_str()) {
+            request.headers_mut().insert(
+                "x-amz-security-token",
+                HeaderValue::from_str(token).expect("Invalid `x-amz-security-token` header"),
+            );
+            canonical_headers.push_str(format!("{}:{}\n", "x-amz-security-token", token).as_ref());
+        }
+        // The query isn't part of the signed resource
+        let date = chrono::offset::Utc::now().to_rfc2822();
+        let auth = self.auth("GET", &date, "", "", &canonical_headers, "", creds);
+        request.headers_mut().insert(
+            "Date",
+            HeaderValue::from_str(&date).expect("Invalid date header"),
+        );
+        request.headers_mut().insert(
+            "Authorization",
+            HeaderValue::from_str(&auth).expect("Invalid authentication"),
+        );
+
+        let res = self
+            .client
+            .execute(request)
+            .await
+            .with_context(|| format!("failed GET: {}", url))?;
+        if !res.status().is_success() {
+            return Err(BadHttpStatusError(res.status()).into());
+        }
+        let body = res.bytes().await.context("failed to read HTTP body")?;
+        Ok(parse_list(&String::from_utf8_lossy(&body)))
     }
 
     // http://docs.aws.amazon.com/AmazonS3/latest/dev/RESTAuthentication.html
@@ -232,10 +298,48 @@ impl Bucket {
     }
 }
 
+/// The keys in a `ListObjects` response, and whether there are more.
+fn parse_list(xml: &str) -> (Vec<String>, bool) {
+    let keys = xml
+        .split("<Key>")
+        .skip(1)
+        .filter_map(|rest| rest.split("</Key>").next())
+        .map(|key| {
+            key.replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&quot;", "\"")
+                .replace("&apos;", "'")
+                .replace("&amp;", "&")
+        })
+        .collect();
+    (keys, xml.contains("<IsTruncated>true</IsTruncated>"))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn test_parse_list() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<ListBucketResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+  <Name>bucket</Name><Prefix>a&amp;b/</Prefix><IsTruncated>true</IsTruncated>
+  <Contents><Key>a&amp;b/0/1/2/012</Key><Size>10</Size></Contents>
+  <Contents><Key>a&amp;b/3/4/5/345</Key><Size>20</Size></Contents>
+</ListBucketResult>"#;
+        assert_eq!(
+            parse_list(xml),
+            (
+                vec!["a&b/0/1/2/012".to_owned(), "a&b/3/4/5/345".to_owned()],
+                true
+            )
+        );
+        assert_eq!(
+            parse_list("<ListBucketResult><IsTruncated>false</IsTruncated></ListBucketResult>"),
+            (vec![], false)
+        );
+    }
+
     #[test]
     fn test_signature() {
         assert_eq!(
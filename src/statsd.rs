@@ -0,0 +1,188 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sending the counts and timings of the server's requests to a statsd or
+//! DogStatsD agent, as they're handled.
+
+use std::env;
+use std::fmt::Write;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+use crate::server::{DurationHistogram, ServerStats};
+
+use crate::errors::*;
+
+/// A statsd agent to send metrics to over UDP.
+pub struct Client {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    prefix: String,
+    /// DogStatsD tags for every metric, such as `env:ci,team:build`.
+    tags: Option<String>,
+}
+
+impl Client {
+    /// The agent at `SCCACHE_STATSD_ADDR`, if it's set, naming metrics with
+    /// `SCCACHE_STATSD_PREFIX` and tagging them with `SCCACHE_STATSD_TAGS`.
+    pub fn from_env() -> Result<Option<Client>> {
+        let addr = match env::var("SCCACHE_STATSD_ADDR") {
+            Ok(addr) => addr,
+            Err(_) => return Ok(None),
+        };
+        let prefix = env::var("SCCACHE_STATSD_PREFIX").unwrap_or_else(|_| "sccache".to_owned());
+        let tags = env::var("SCCACHE_STATSD_TAGS")
+            .ok()
+            .filter(|tags| !tags.is_empty());
+        Client::new(&addr, prefix, tags).map(Some)
+    }
+
+    pub fn new(addr: &str, prefix: String, tags: Option<String>) -> Result<Client> {
+        // Resolved once, so a name such as that of a Datadog agent's
+        // container isn't looked up for every request
+        let addr = addr
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .with_context(|| format!("invalid SCCACHE_STATSD_ADDR: {}", addr))?;
+        let local: SocketAddr = if addr.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.set_nonblocking(true)?;
+        Ok(Client {
+            socket,
+            addr,
+            prefix,
+            tags,
+        })
+    }
+
+    /// Send the counts and timings of a request, as recorded in `stats`.
+    /// Metrics that are lost, as UDP ones may be, aren't retried.
+    pub fn send(&self, stats: &ServerStats) {
+        let packet = self.format(stats);
+        if packet.is_empty() {
+            return;
+        }
+        if let Err(e) = self.socket.send_to(packet.as_bytes(), self.addr) {
+            debug!("failed to send metrics to {}: {}", self.addr, e);
+        }
+    }
+
+    /// The metrics of `stats` as a statsd packet, a metric per line. Only
+    /// counters that went up are sent.
+    fn format(&self, stats: &ServerStats) -> String {
+        let mut packet = String::new();
+        let mut metric = |name: &str, value: String, kind: &str| {
+            let _ = write!(packet, "{}.{}:{}|{}", self.prefix, name, value, kind);
+            if let Some(ref tags) = self.tags {
+                let _ = write!(packet, "|#{}", tags);
+            }
+            packet.push('\n');
+        };
+        for &(name, count) in &[
+            ("compile_requests", stats.compile_requests),
+            ("cache_hits", stats.cache_hits.all()),
+            ("cache_misses", stats.cache_misses.all()),
+            ("cache_errors", stats.cache_errors.all()),
+            ("cache_timeouts", stats.cache_timeouts),
+            ("cache_read_errors", stats.cache_read_errors),
+            ("cache_writes", stats.cache_writes),
+            ("cache_write_errors", stats.cache_write_errors),
+            ("requests_not_cacheable", stats.requests_not_cacheable),
+            (
+                "non_cacheable_compilations",
+                stats.non_cacheable_compilations,
+            ),
+            ("compile_fails", stats.compile_fails),
+            ("dist_errors", stats.dist_errors),
+        ] {
+            if count > 0 {
+                metric(name, count.to_string(), "c");
+            }
+        }
+        for &(name, histogram) in &[
+            ("compile_duration", &stats.compile_durations),
+            ("hash_duration", &stats.hash_durations),
+            ("cache_get_duration", &stats.cache_get_durations),
+            ("cache_put_duration", &stats.cache_put_durations),
+            ("compiler_duration", &stats.compiler_durations),
+        ] {
+            if let Some(duration) = only_duration(histogram) {
+                metric(name, format_ms(duration), "ms");
+            }
+        }
+        packet
+    }
+}
+
+/// The duration recorded in `histogram`, if it's the only one, as in the
+/// stats of a single request.
+fn only_duration(histogram: &DurationHistogram) -> Option<Duration> {
+    if histogram.count == 1 {
+        Some(histogram.sum)
+    } else {
+        None
+    }
+}
+
+fn format_ms(duration: Duration) -> String {
+    format!("{:.3}", duration.as_secs_f64() * 1000.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let client = Client::new("127.0.0.1:8125", "sccache".to_owned(), None).unwrap();
+        let mut stats = ServerStats {
+            compile_requests: 1,
+            cache_writes: 1,
+            ..Default::default()
+        };
+        stats
+            .cache_get_durations
+            .record(Duration::from_micros(1500));
+        assert_eq!(
+            client.format(&stats),
+            "sccache.compile_requests:1|c\n\
+             sccache.cache_writes:1|c\n\
+             sccache.cache_get_duration:1.500|ms\n"
+        );
+        assert_eq!(client.format(&ServerStats::default()), "");
+
+        let client = Client::new(
+            "127.0.0.1:8125",
+            "ci.sccache".to_owned(),
+            Some("env:ci,team:build".to_owned()),
+        )
+        .unwrap();
+        assert_eq!(
+            client.format(&stats),
+            "ci.sccache.compile_requests:1|c|#env:ci,team:build\n\
+             ci.sccache.cache_writes:1|c|#env:ci,team:build\n\
+             ci.sccache.cache_get_duration:1.500|ms|#env:ci,team:build\n"
+        );
+    }
+
+    #[test]
+    fn test_invalid_addr() {
+        assert!(Client::new("nowhere", "sccache".to_owned(), None).is_err());
+    }
+}
@@ -0,0 +1,175 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Running the server as a systemd service: sockets passed by socket
+//! activation, and notifying systemd of readiness and liveness.
+//!
+//! This is the protocol of `sd_listen_fds(3)` and `sd_notify(3)`, without
+//! linking to libsystemd.
+
+use std::env;
+use std::ffi::OsStr;
+use std::io;
+use std::mem;
+use std::net::TcpListener;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::process;
+use std::time::Duration;
+
+/// The first file descriptor passed by systemd.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// A socket passed by systemd.
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Take the sockets systemd passed to this process, if it was socket
+/// activated. They're removed from the environment so that compilers don't
+/// think they were passed them too.
+pub fn listeners() -> io::Result<Vec<Listener>> {
+    let pid = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok());
+    let fds = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|fds| fds.parse::<RawFd>().ok());
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    let fds = match (pid, fds) {
+        // Otherwise they're meant for a parent that didn't clear them
+        (Some(pid), Some(fds)) if pid == process::id() => fds,
+        _ => return Ok(vec![]),
+    };
+    (LISTEN_FDS_START..LISTEN_FDS_START + fds)
+        .map(listener)
+        .collect()
+}
+
+fn listener(fd: RawFd) -> io::Result<Listener> {
+    // The descriptors are inherited without close-on-exec
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of_val(&addr) as libc::socklen_t;
+    if unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) } == -1
+    {
+        return Err(io::Error::last_os_error());
+    }
+    match libc::c_int::from(addr.ss_family) {
+        libc::AF_INET | libc::AF_INET6 => {
+            Ok(Listener::Tcp(unsafe { TcpListener::from_raw_fd(fd) }))
+        }
+        libc::AF_UNIX => Ok(Listener::Unix(unsafe { UnixListener::from_raw_fd(fd) })),
+        family => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "socket {} passed by systemd has unsupported family {}",
+                fd, family
+            ),
+        )),
+    }
+}
+
+/// Tell systemd about the state of the service, e.g. `READY=1`, if it's
+/// managing it.
+pub fn notify(state: &str) {
+    let socket = match env::var_os("NOTIFY_SOCKET") {
+        Some(socket) => socket,
+        None => return,
+    };
+    if let Err(e) = send(&socket, state) {
+        warn!("Failed to notify systemd of {:?}: {}", state, e);
+    }
+}
+
+fn send(socket: &OsStr, state: &str) -> io::Result<()> {
+    let path = socket.as_bytes();
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    if path.is_empty() || path.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid NOTIFY_SOCKET",
+        ));
+    }
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    for (dst, &src) in addr.sun_path.iter_mut().zip(path) {
+        *dst = src as libc::c_char;
+    }
+    // A socket in the abstract namespace
+    if path[0] == b'@' {
+        addr.sun_path[0] = 0;
+    }
+    let len = mem::size_of::<libc::sa_family_t>() + path.len();
+    unsafe {
+        let fd = libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM, 0);
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let sent = libc::sendto(
+            fd,
+            state.as_ptr() as *const libc::c_void,
+            state.len(),
+            0,
+            &addr as *const _ as *const libc::sockaddr,
+            len as libc::socklen_t,
+        );
+        let res = if sent == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        };
+        libc::close(fd);
+        res
+    }
+}
+
+/// How often systemd expects `WATCHDOG=1`, if it's watching this process.
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Some(pid) = env::var("WATCHDOG_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+    {
+        if pid != process::id() {
+            return None;
+        }
+    }
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn test_send() {
+        let tempdir = tempfile::Builder::new()
+            .prefix("sccache_test_systemd")
+            .tempdir()
+            .unwrap();
+        let path = tempdir.path().join("notify");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        send(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 16];
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+    }
+}
@@ -14,13 +14,15 @@
 
 use crate::cache::{Cache, CacheWrite, Storage};
 use crate::errors::*;
-use futures::future;
+use async_trait::async_trait;
+use futures::future::{self, FutureExt, LocalBoxFuture};
+use futures::Future;
 use std::cell::RefCell;
 use std::time::Duration;
 
 /// A mock `Storage` implementation.
 pub struct MockStorage {
-    gets: RefCell<Vec<SFuture<Cache>>>,
+    gets: RefCell<Vec<LocalBoxFuture<'static, Result<Cache>>>>,
 }
 
 impl MockStorage {
@@ -32,30 +34,48 @@ impl MockStorage {
     }
 
     /// Queue up `res` to be returned as the next result from `Storage::get`.
-    pub fn next_get(&self, res: SFuture<Cache>) {
-        self.gets.borrow_mut().push(res)
+    pub fn next_get(&self, res: Result<Cache>) {
+        self.next_get_future(future::ready(res))
+    }
+
+    /// Queue up `f` to be awaited for the next result from `Storage::get`.
+    pub fn next_get_future<F>(&self, f: F)
+    where
+        F: Future<Output = Result<Cache>> + 'static,
+    {
+        self.gets.borrow_mut().push(f.boxed_local())
     }
 }
 
+#[async_trait(?Send)]
 impl Storage for MockStorage {
-    fn get(&self, _key: &str) -> SFuture<Cache> {
-        let mut g = self.gets.borrow_mut();
-        assert!(
-            g.len() > 0,
-            "MockStorage get called, but no get results available"
-        );
-        g.remove(0)
+    async fn get(&self, _key: &str) -> Result<Cache> {
+        let next = {
+            let mut g = self.gets.borrow_mut();
+            assert!(
+                g.len() > 0,
+                "MockStorage get called, but no get results available"
+            );
+            g.remove(0)
+        };
+        next.await
     }
-    fn put(&self, _key: &str, _entry: CacheWrite) -> SFuture<Duration> {
-        f_ok(Duration::from_secs(0))
+    async fn put(&self, _key: &str, _entry: CacheWrite) -> Result<Duration> {
+        Ok(Duration::from_secs(0))
+    }
+    async fn delete(&self, _key: &str) -> Result<()> {
+        Ok(())
     }
     fn location(&self) -> String {
         "Mock Storage".to_string()
     }
-    fn current_size(&self) -> SFuture<Option<u64>> {
-        Box::new(future::ok(None))
+    fn kind(&self) -> &'static str {
+        "mock"
+    }
+    async fn current_size(&self) -> Result<Option<u64>> {
+        Ok(None)
     }
-    fn max_size(&self) -> SFuture<Option<u64>> {
-        Box::new(future::ok(None))
+    async fn max_size(&self) -> Result<Option<u64>> {
+        Ok(None)
     }
 }
@@ -13,34 +13,65 @@
 // limitations under the License.
 
 use crate::cache::disk::DiskCache;
+use crate::cache::metered::BackendStats;
+use crate::cache::ClearScope;
 use crate::client::connect_to_server;
-use crate::commands::{do_compile, request_shutdown, request_stats};
+#[cfg(unix)]
+use crate::client::connect_to_socket;
+use crate::commands::{
+    do_compile, do_run, request_admin, request_dry_run, request_end_session, request_prewarm,
+    request_project_stats, request_shutdown, request_start_session, request_stats, request_top,
+    request_watch_stats, request_zero_project_stats, request_zero_stats,
+};
+use crate::config::{HashConfig, ProjectsConfig, ToolchainPinsConfig};
+use crate::dist::{ServerDetailsResult, ServerId};
 use crate::jobserver::Client;
 use crate::mock_command::*;
-use crate::server::{DistClientContainer, SccacheServer, ServerMessage};
+use crate::offline::NetworkMode;
+use crate::protocol::{AdminRequest, AdminResponse, Compile, DryRunResult, Hello, PrewarmResult};
+use crate::server::{
+    CompilerStats, DistClientContainer, DistInfo, DistStatus, DurationHistogram, IdleTimeouts,
+    SccacheServer, ServerMessage, ServerStats, SlowCompile,
+};
 use crate::test::utils::*;
-use futures::sync::oneshot::{self, Sender};
-use futures_03::executor::ThreadPool;
-use std::fs::File;
+use crate::util;
+use futures::channel::oneshot::{self, Sender};
+use futures::executor::ThreadPool;
+use std::ffi::OsString;
+use std::fs::{self, File};
 use std::io::{Cursor, Write};
 #[cfg(not(target_os = "macos"))]
 use std::net::TcpListener;
-use std::path::Path;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
 #[cfg(not(target_os = "macos"))]
 use std::process::Command;
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 use std::u64;
-use tokio_compat::runtime::current_thread::Runtime;
 
 /// Options for running the server in tests.
 #[derive(Default)]
 struct ServerOptions {
     /// The server's idle shutdown timeout.
     idle_timeout: Option<u64>,
+    /// The server's idle shutdown timeouts, by what it last did.
+    idle_timeouts: Option<IdleTimeouts>,
     /// The maximum size of the disk cache.
     cache_size: Option<u64>,
+    /// The toolchains allowed to use the cache.
+    toolchain_pins: ToolchainPinsConfig,
+    /// The projects with stats of their own.
+    projects: ProjectsConfig,
+    /// Where to write the admin token, to take admin requests.
+    admin_token: Option<PathBuf>,
+    /// The file to keep the stats in across restarts.
+    stats_file: Option<PathBuf>,
+    /// A Unix socket to listen on too.
+    #[cfg(unix)]
+    unix_socket: Option<PathBuf>,
 }
 
 /// Run a server on a background thread, and return a tuple of useful things.
@@ -71,6 +102,10 @@ where
         .and_then(|o| o.cache_size.as_ref())
         .copied()
         .unwrap_or(u64::MAX);
+    let toolchain_pins = options
+        .as_ref()
+        .map(|o| o.toolchain_pins.clone())
+        .unwrap_or_default();
     // Create a server on a background thread, get some useful bits from it.
     let (tx, rx) = mpsc::channel();
     let (shutdown_tx, shutdown_rx) = oneshot::channel();
@@ -79,15 +114,41 @@ where
         let dist_client = DistClientContainer::new_disabled();
         let storage = Arc::new(DiskCache::new(&cache_dir, cache_size, &pool));
 
-        let runtime = Runtime::new().unwrap();
+        let runtime = util::runtime().unwrap();
         let client = unsafe { Client::new() };
-        let srv = SccacheServer::new(0, pool, runtime, client, dist_client, storage).unwrap();
+        let srv = SccacheServer::new(
+            0,
+            pool,
+            runtime,
+            client,
+            dist_client,
+            storage,
+            HashConfig::default(),
+            toolchain_pins,
+        )
+        .unwrap();
         let mut srv: SccacheServer<Arc<Mutex<MockCommandCreator>>> = srv;
         assert!(srv.port() > 0);
         if let Some(options) = options {
             if let Some(timeout) = options.idle_timeout {
                 srv.set_idle_timeout(Duration::from_millis(timeout));
             }
+            if let Some(timeouts) = options.idle_timeouts {
+                srv.set_idle_timeouts(timeouts);
+            }
+            srv.set_projects(options.projects);
+            if let Some(path) = options.admin_token {
+                srv.set_admin_token(path).unwrap();
+            }
+            if let Some(path) = options.stats_file {
+                srv.set_stats_file(path);
+            }
+            #[cfg(unix)]
+            {
+                if let Some(path) = options.unix_socket {
+                    srv.listen_unix(path).unwrap();
+                }
+            }
         }
         let port = srv.port();
         let creator = srv.command_creator().clone();
@@ -110,6 +171,17 @@ fn test_server_shutdown() {
     child.join().unwrap();
 }
 
+#[test]
+fn test_server_hello() {
+    let f = TestFixture::new();
+    let (port, _sender, _storage, child) = run_server_thread(&f.tempdir.path(), None);
+    let mut conn = connect_to_server(port).unwrap();
+    assert_eq!(conn.hello().unwrap(), Some(Hello::current()));
+    // The connection is still usable after the handshake
+    request_shutdown(conn).unwrap();
+    child.join().unwrap();
+}
+
 /// The server will shutdown when requested when the idle timeout is disabled.
 #[test]
 fn test_server_shutdown_no_idle() {
@@ -146,6 +218,45 @@ fn test_server_idle_timeout() {
     child.join().unwrap();
 }
 
+/// The timeout before any requests can differ from the one after them.
+#[test]
+fn test_server_idle_timeout_startup() {
+    let f = TestFixture::new();
+    let (port, _sender, _storage, child) = run_server_thread(
+        &f.tempdir.path(),
+        ServerOptions {
+            idle_timeouts: Some(IdleTimeouts {
+                startup: Duration::new(0, 0),
+                ..IdleTimeouts::all(Duration::from_millis(100))
+            }),
+            ..Default::default()
+        },
+    );
+    thread::sleep(Duration::from_millis(300));
+    // It's still running, and shuts down after this request.
+    request_stats(connect_to_server(port).unwrap()).unwrap();
+    child.join().unwrap();
+}
+
+/// The server doesn't shut down when idle while a build session is open.
+#[test]
+fn test_server_session() {
+    let f = TestFixture::new();
+    let (port, _sender, _storage, child) = run_server_thread(
+        &f.tempdir.path(),
+        ServerOptions {
+            idle_timeout: Some(100),
+            ..Default::default()
+        },
+    );
+    let sessions = request_start_session(connect_to_server(port).unwrap(), "ci").unwrap();
+    assert_eq!(sessions, vec!["ci".to_owned()]);
+    thread::sleep(Duration::from_millis(300));
+    let sessions = request_end_session(connect_to_server(port).unwrap(), "ci").unwrap();
+    assert!(sessions.is_empty());
+    child.join().unwrap();
+}
+
 #[test]
 fn test_server_stats() {
     let f = TestFixture::new();
@@ -161,6 +272,339 @@ fn test_server_stats() {
     child.join().unwrap();
 }
 
+#[test]
+fn test_server_stats_formats() {
+    let f = TestFixture::new();
+    let (port, sender, _storage, child) = run_server_thread(&f.tempdir.path(), None);
+    let mut info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+    assert_eq!(info.cache_kind, "disk");
+    info.cache_location = "a \"quoted\", location".to_owned();
+    info.stats.compile_requests = 3;
+    info.stats.cache_write_duration = Duration::from_millis(1500);
+    info.stats
+        .not_cached
+        .insert("multiple input files".to_owned(), 2);
+    info.stats
+        .compile_durations
+        .record(Duration::from_millis(30));
+    let mut gcc = CompilerStats {
+        hits: 4,
+        not_cacheable: 2,
+        ..Default::default()
+    };
+    gcc.not_cached.insert("multiple input files".to_owned(), 2);
+    gcc.hit_bytes = 2048;
+    info.stats.compilers.insert("C++ (GCC)".to_owned(), gcc);
+
+    let mut json = vec![];
+    info.write_json(&mut json).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    assert_eq!(json["schema_version"], 1);
+    assert_eq!(json["stats"]["compile_requests"], 3);
+    assert_eq!(json["storage"]["kind"], "disk");
+    assert_eq!(json["storage"]["location"], "a \"quoted\", location");
+    assert_eq!(json["storage"]["read_only"], false);
+    let counters = &json["counters"];
+    assert_eq!(counters["compile_requests"], 3);
+    assert_eq!(counters["cache_hits"], 0);
+    assert_eq!(counters["cache_write_seconds"].to_string(), "1.5");
+    assert_eq!(counters["not_cached.multiple input files"], 2);
+    assert_eq!(counters["compilers.C++ (GCC).hits"], 4);
+    assert_eq!(counters["compilers.C++ (GCC).misses"], 0);
+    assert_eq!(
+        counters["compilers.C++ (GCC).not_cached.multiple input files"],
+        2
+    );
+    assert_eq!(counters["compilers.C++ (GCC).hit_bytes"], 2048);
+    assert_eq!(counters["cache_hit_bytes"], 2048);
+    assert_eq!(counters["estimated_time_saved_seconds"].to_string(), "0.0");
+    assert_eq!(counters["compile_duration_seconds.count"], 1);
+    assert_eq!(counters["compile_duration_seconds.le_0.01"], 0);
+    assert_eq!(counters["compile_duration_seconds.le_0.05"], 1);
+    assert_eq!(counters["compile_duration_seconds.le_1"], 1);
+    assert_eq!(counters["cache_get_duration_seconds.count"], 0);
+
+    let mut csv = vec![];
+    info.write_csv(&mut csv).unwrap();
+    let csv = String::from_utf8(csv).unwrap();
+    let lines: Vec<_> = csv.lines().collect();
+    assert_eq!(&lines[..2], &["name,value", "schema_version,1"]);
+    assert!(lines.contains(&"storage.location,\"a \"\"quoted\"\", location\""));
+    assert!(lines.contains(&"storage.kind,disk"));
+    assert!(lines.contains(&"storage.error,"));
+    assert!(lines.contains(&"compile_requests,3"));
+    assert!(lines.contains(&"cache_write_seconds,1.5"));
+}
+
+#[test]
+fn test_server_markdown_summary() {
+    let f = TestFixture::new();
+    let (port, sender, _storage, child) = run_server_thread(&f.tempdir.path(), None);
+    let mut info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+    info.stats.compilers.insert(
+        "C++ (GCC)".to_owned(),
+        CompilerStats {
+            hits: 5,
+            misses: 1,
+            ..Default::default()
+        },
+    );
+    info.stats.not_cached.insert("-save-temps".to_owned(), 2);
+    info.stats.requests_not_cacheable = 2;
+
+    let mut md = vec![];
+    info.write_markdown(&mut md).unwrap();
+    let md = String::from_utf8(md).unwrap();
+    assert!(md.starts_with("### sccache\n"));
+    assert!(md.contains("| C++ (GCC) | 5 | 1 | 0 | 0 | 83.3% |\n"));
+    assert!(md.contains("| **Total** | 0 | 0 | 0 | 2 | - |\n"));
+    assert!(md.contains("> 2 compiles couldn't be cached:\n> - -save-temps: 2\n"));
+}
+
+/// The crates that missed the most come first.
+#[test]
+fn test_server_crates_formats() {
+    let f = TestFixture::new();
+    let (port, sender, _storage, child) = run_server_thread(&f.tempdir.path(), None);
+    let mut info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+    let mut dep = CompilerStats {
+        hits: 1,
+        misses: 2,
+        miss_compile_duration: Duration::from_millis(2500),
+        ..Default::default()
+    };
+    dep.not_cached.insert("crate-type dylib".to_owned(), 1);
+    info.stats.crates.insert("serde".to_owned(), dep);
+    info.stats.crates.insert(
+        "libc".to_owned(),
+        CompilerStats {
+            hits: 3,
+            ..Default::default()
+        },
+    );
+
+    let mut json = vec![];
+    info.write_crates_json(&mut json).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    assert_eq!(json["crates"][0]["name"], "serde");
+    assert_eq!(json["crates"][0]["misses"], 2);
+    assert_eq!(
+        json["crates"][0]["top_reason_not_cached"],
+        "crate-type dylib"
+    );
+    assert_eq!(json["crates"][1]["name"], "libc");
+
+    let mut csv = vec![];
+    info.write_crates_csv(&mut csv).unwrap();
+    let csv = String::from_utf8(csv).unwrap();
+    let lines: Vec<_> = csv.lines().collect();
+    assert_eq!(
+        lines,
+        &[
+            "name,hits,misses,errors,not_cacheable,miss_compile_seconds,top_reason_not_cached",
+            "serde,1,2,0,0,2.5,crate-type dylib",
+            "libc,3,0,0,0,0,",
+        ]
+    );
+}
+
+/// Hits save the average time of a miss of the same compiler, less the time
+/// looking them up took.
+#[test]
+fn test_time_saved() {
+    let mut gcc = CompilerStats {
+        hits: 3,
+        misses: 2,
+        miss_compile_duration: Duration::from_secs(4),
+        hit_read_duration: Duration::from_millis(500),
+        hit_bytes: 1000,
+        ..Default::default()
+    };
+    assert_eq!(gcc.time_saved(), Some(Duration::from_millis(5500)));
+    let rust = CompilerStats {
+        hits: 1,
+        hit_bytes: 24,
+        ..Default::default()
+    };
+    // Unknown without misses
+    assert_eq!(rust.time_saved(), None);
+    let mut stats = ServerStats::default();
+    stats.compilers.insert("C (GCC)".to_owned(), gcc.clone());
+    stats.compilers.insert("Rust".to_owned(), rust);
+    assert_eq!(stats.time_saved(), Duration::from_millis(5500));
+    assert_eq!(stats.hit_bytes(), 1024);
+    // Lookups slower than compiling save nothing
+    gcc.hit_read_duration = Duration::from_secs(10);
+    assert_eq!(gcc.time_saved(), Some(Duration::from_secs(0)));
+}
+
+#[test]
+fn test_backend_stats() {
+    let remote = BackendStats {
+        hits: 1,
+        misses: 2,
+        errors: 1,
+        writes: 2,
+        read_duration: Duration::from_secs(2),
+        write_duration: Duration::from_secs(1),
+        ..Default::default()
+    };
+    assert_eq!(remote.read_mean(), Some(Duration::from_millis(500)));
+    assert_eq!(remote.write_mean(), Some(Duration::from_millis(500)));
+    assert_eq!(BackendStats::default().read_mean(), None);
+    let mut stats = ServerStats::default();
+    stats
+        .backends
+        .insert("Redis: redis://cache".to_owned(), remote);
+    let counters = stats.counters();
+    assert_eq!(counters["backends.Redis: redis://cache.errors"], 1);
+    assert_eq!(counters["backends.Redis: redis://cache.write_errors"], 0);
+    assert_eq!(
+        counters["backends.Redis: redis://cache.read_seconds"].to_string(),
+        "2.0"
+    );
+}
+
+/// Only the slowest compiles are kept, each once.
+#[test]
+fn test_slowest_compiles() {
+    let compile = |key: &str, secs| ServerStats {
+        slowest: vec![SlowCompile {
+            source: format!("{}.c", key),
+            compiler: "C (GCC)".to_owned(),
+            duration: Duration::from_secs(secs),
+            key: key.to_owned(),
+        }],
+        ..Default::default()
+    };
+    let mut stats = ServerStats::default();
+    for i in 0..30 {
+        stats.add(&compile(&format!("key{}", i), i));
+    }
+    stats.add(&compile("key0", 100));
+    let keys: Vec<_> = stats.slowest.iter().map(|c| c.key.as_str()).collect();
+    assert_eq!(keys.len(), 20);
+    assert_eq!(&keys[..3], &["key0", "key29", "key28"]);
+    assert_eq!(keys[19], "key11");
+}
+
+/// Percentiles are the upper bounds of the buckets they fall in.
+#[test]
+fn test_duration_percentiles() {
+    let mut histogram = DurationHistogram::default();
+    assert_eq!(histogram.percentile(50.0), None);
+    for _ in 0..9 {
+        histogram.record(Duration::from_millis(20));
+    }
+    histogram.record(Duration::from_secs(1000));
+    assert_eq!(histogram.percentile(50.0), Some(0.05));
+    assert_eq!(histogram.percentile(90.0), Some(0.05));
+    assert_eq!(histogram.percentile(99.0), Some(f64::INFINITY));
+}
+
+/// The dist status shows a row for each build server, and the toolchains
+/// they have.
+#[test]
+fn test_dist_status_text() {
+    let server = |addr: [u8; 4], toolchains: &[&str]| ServerDetailsResult {
+        server_id: ServerId::new((addr, 10501).into()),
+        num_cpus: 8,
+        jobs_assigned: 6,
+        jobs_unclaimed: 1,
+        jobs_started: 4,
+        last_seen_ms_ago: 0,
+        last_error_ms_ago: Some(12_000),
+        recent_errors: 2,
+        toolchains: toolchains.iter().map(|&tc| tc.to_owned()).collect(),
+        draining: false,
+    };
+    let mut status = DistStatus {
+        info: DistInfo::Disabled("disabled in config".to_owned()),
+        builders: Some(vec![
+            server([10, 0, 0, 1], &["abc", "def"]),
+            server([10, 0, 0, 2], &[]),
+        ]),
+        fallbacks: 3,
+        fallback_causes: vec![("scheduler".to_owned(), 3)].into_iter().collect(),
+    };
+
+    let mut text = vec![];
+    status.write_text(&mut text).unwrap();
+    let text = String::from_utf8(text).unwrap();
+    let lines: Vec<_> = text.lines().collect();
+    assert_eq!(lines[0], "Distributed compilation: disabled in config");
+    assert_eq!(lines[1], "Local fallbacks: 3 (scheduler: 3)");
+    assert!(lines[3].starts_with("SERVER          CORES  RUNNING  QUEUED  LOAD  ERRORS"));
+    assert_eq!(
+        lines[4],
+        "10.0.0.1:10501      8        4       2  0.75       2     12s ago           2  ok"
+    );
+    assert!(lines.contains(&"  10.0.0.1:10501: abc, def"));
+    assert!(lines.contains(&"  10.0.0.2:10501: none"));
+
+    status.builders = None;
+    let mut text = vec![];
+    status.write_text(&mut text).unwrap();
+    assert_eq!(String::from_utf8(text).unwrap().lines().count(), 2);
+}
+
+/// Broken storage is found at startup, and reported in the stats.
+#[test]
+fn test_server_storage_check() {
+    let f = TestFixture::new();
+    // Entries are stored in directories named after the first characters of
+    // their keys, which can't be created with files in the way
+    let cache_dir = f.tempdir.path().join("cache");
+    fs::create_dir(&cache_dir).unwrap();
+    File::create(cache_dir.join("c")).unwrap();
+    let (port, sender, _storage, child) = run_server_thread(&cache_dir, None);
+    let mut storage_error = None;
+    for _ in 0..100 {
+        let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+        storage_error = info.storage_error;
+        if storage_error.is_some() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    let storage_error = storage_error.expect("storage check didn't fail");
+    assert!(
+        storage_error.starts_with("couldn't write to Local disk"),
+        "{}",
+        storage_error
+    );
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_server_unix_socket() {
+    let f = TestFixture::new();
+    let path = f.tempdir.path().join("sccache.sock");
+    let (_port, _sender, _storage, child) = run_server_thread(
+        &f.tempdir.path(),
+        ServerOptions {
+            unix_socket: Some(path.clone()),
+            ..Default::default()
+        },
+    );
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    let info = request_stats(connect_to_socket(&path).unwrap()).unwrap();
+    assert_eq!(0, info.stats.compile_requests);
+    request_shutdown(connect_to_socket(&path).unwrap()).unwrap();
+    child.join().unwrap();
+    // The socket is removed as the server shuts down
+    assert!(!path.exists());
+}
+
 #[test]
 fn test_server_unsupported_compiler() {
     let f = TestFixture::new();
@@ -184,16 +628,17 @@ fn test_server_unsupported_compiler() {
     let mut stdout = Cursor::new(Vec::new());
     let mut stderr = Cursor::new(Vec::new());
     let path = Some(f.paths);
-    let mut runtime = Runtime::new().unwrap();
+    let runtime = util::runtime().unwrap();
     let res = do_compile(
         client_creator,
-        &mut runtime,
+        &runtime,
         conn,
         exe,
         cmdline,
         cwd,
         path,
         vec![],
+        None,
         &mut stdout,
         &mut stderr,
     );
@@ -209,6 +654,412 @@ fn test_server_unsupported_compiler() {
     child.join().unwrap();
 }
 
+/// Compiles are counted in the stats of the project named in their
+/// environment, or else of the directory they're run in.
+#[test]
+fn test_server_project_stats() {
+    let f = TestFixture::new();
+    let mut projects = ProjectsConfig::default();
+    projects
+        .0
+        .insert("fixture".to_owned(), f.tempdir.path().to_owned());
+    let (port, sender, server_creator, child) = run_server_thread(
+        &f.tempdir.path(),
+        ServerOptions {
+            projects,
+            ..Default::default()
+        },
+    );
+    let runtime = util::runtime().unwrap();
+    let env_vars = vec![vec![("SCCACHE_PROJECT".into(), "named".into())], vec![]];
+    for env_vars in env_vars {
+        server_creator
+            .lock()
+            .unwrap()
+            .next_command_spawns(Ok(MockChild::new(exit_status(0), "hello", "error")));
+        // An unsupported compiler, which is still a compile request
+        let res = do_compile(
+            new_creator(),
+            &runtime,
+            connect_to_server(port).unwrap(),
+            &f.bins[0],
+            vec!["-c".into(), "file.c".into()],
+            f.tempdir.path(),
+            Some(f.paths.clone()),
+            env_vars,
+            None,
+            &mut Cursor::new(Vec::new()),
+            &mut Cursor::new(Vec::new()),
+        );
+        assert!(res.is_err());
+    }
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(2, info.stats.compile_requests);
+    assert_eq!(vec!["fixture", "named"], info.projects);
+    for project in &["named", "fixture"] {
+        let info = request_project_stats(connect_to_server(port).unwrap(), project).unwrap();
+        assert_eq!(Some(project.to_string()), info.project);
+        assert_eq!(1, info.stats.compile_requests);
+        assert_eq!(1, info.stats.requests_unsupported_compiler);
+    }
+    let info = request_zero_project_stats(connect_to_server(port).unwrap(), "named").unwrap();
+    assert_eq!(0, info.stats.compile_requests);
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(2, info.stats.compile_requests);
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_watch_stats() {
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    let (tx, rx) = mpsc::channel();
+    let watcher = thread::spawn(move || {
+        let mut counts = Default::default();
+        request_watch_stats(connect_to_server(port).unwrap(), |delta| {
+            delta.apply(&mut counts);
+            tx.send(counts).unwrap();
+            Ok(())
+        })
+    });
+    // The first update has the counts so far
+    assert_eq!(rx.recv().unwrap().compile_requests, 0);
+    server_creator
+        .lock()
+        .unwrap()
+        .next_command_spawns(Ok(MockChild::new(exit_status(0), "hello", "error")));
+    let runtime = util::runtime().unwrap();
+    let res = do_compile(
+        new_creator(),
+        &runtime,
+        connect_to_server(port).unwrap(),
+        &f.bins[0],
+        vec!["-c".into(), "file.c".into()],
+        f.tempdir.path(),
+        Some(f.paths.clone()),
+        vec![],
+        None,
+        &mut Cursor::new(Vec::new()),
+        &mut Cursor::new(Vec::new()),
+    );
+    assert!(res.is_err());
+    let counts = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert_eq!(counts.compile_requests, 1);
+    // The watch ends when the server shuts down
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    watcher.join().unwrap().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_top() {
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    let (tx, rx) = mpsc::channel();
+    let watcher = thread::spawn(move || {
+        request_top(connect_to_server(port).unwrap(), |snapshot| {
+            tx.send(snapshot.clone()).unwrap();
+            Ok(())
+        })
+    });
+    let first = rx.recv().unwrap();
+    assert!(first.active.is_empty() && first.recent.is_empty());
+    assert!(first.cache_size.is_some());
+    {
+        let mut c = server_creator.lock().unwrap();
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "gcc", "")));
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessor", "")));
+        let obj = f.tempdir.path().join("file.o");
+        c.next_command_calls(move |_| {
+            fs::write(&obj, b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+    }
+    let runtime = util::runtime().unwrap();
+    let res = do_compile(
+        new_creator(),
+        &runtime,
+        connect_to_server(port).unwrap(),
+        &f.bins[0],
+        vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()],
+        f.tempdir.path(),
+        Some(f.paths.clone()),
+        vec![],
+        None,
+        &mut Cursor::new(Vec::new()),
+        &mut Cursor::new(Vec::new()),
+    );
+    assert_eq!(res.unwrap(), 0);
+    // The finished compile is in the next snapshots
+    let snapshot = (0..10)
+        .map(|_| rx.recv_timeout(Duration::from_secs(10)).unwrap())
+        .find(|snapshot| !snapshot.recent.is_empty())
+        .unwrap();
+    assert!(snapshot.active.is_empty());
+    assert_eq!(snapshot.recent.len(), 1);
+    assert_eq!(snapshot.recent[0].output, "file.o");
+    assert_eq!(snapshot.recent[0].compiler, "C/C++");
+    assert_eq!(snapshot.recent[0].result, "miss");
+    assert_eq!(snapshot.counts.cache_misses, 1);
+    // The snapshots end when the server shuts down
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    watcher.join().unwrap().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_prewarm() {
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    let compile = || Compile {
+        exe: f.bins[0].clone().into(),
+        cwd: f.tempdir.path().to_owned().into(),
+        args: vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()],
+        env_vars: vec![],
+    };
+    {
+        let mut c = server_creator.lock().unwrap();
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "gcc", "")));
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessor", "")));
+    }
+    let prewarm = |compile| request_prewarm(connect_to_server(port).unwrap(), compile).unwrap();
+    // Looking up a compile doesn't compile it
+    assert_eq!(prewarm(compile()), PrewarmResult::Miss);
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    {
+        let mut c = server_creator.lock().unwrap();
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessor", "")));
+        let obj = f.tempdir.path().join("file.o");
+        c.next_command_calls(move |_| {
+            fs::write(&obj, b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessor", "")));
+    }
+    let runtime = util::runtime().unwrap();
+    let res = do_compile(
+        new_creator(),
+        &runtime,
+        connect_to_server(port).unwrap(),
+        &f.bins[0],
+        compile().args,
+        f.tempdir.path(),
+        Some(f.paths.clone()),
+        vec![],
+        None,
+        &mut Cursor::new(Vec::new()),
+        &mut Cursor::new(Vec::new()),
+    );
+    assert_eq!(res.unwrap(), 0);
+    // The cache write finishes after the compile does
+    let found = (0..50).any(|_| {
+        if request_stats(connect_to_server(port).unwrap())
+            .unwrap()
+            .stats
+            .cache_writes
+            == 1
+        {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(100));
+        false
+    });
+    assert!(found);
+    assert_eq!(prewarm(compile()), PrewarmResult::Hit);
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+/// `SCCACHE_READONLY` and `SCCACHE_WRITEONLY` apply to the compiles they're
+/// set for, not the whole server.
+#[test]
+fn test_server_per_compile_read_write_overrides() {
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    let args = || vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let compile = |env_vars: Vec<(OsString, OsString)>| {
+        {
+            let mut c = server_creator.lock().unwrap();
+            c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessor", "")));
+            let obj = f.tempdir.path().join("file.o");
+            c.next_command_calls(move |_| {
+                fs::write(&obj, b"file contents")?;
+                Ok(MockChild::new(exit_status(0), "", ""))
+            });
+        }
+        let runtime = util::runtime().unwrap();
+        let res = do_compile(
+            new_creator(),
+            &runtime,
+            connect_to_server(port).unwrap(),
+            &f.bins[0],
+            args(),
+            f.tempdir.path(),
+            Some(f.paths.clone()),
+            env_vars,
+            None,
+            &mut Cursor::new(Vec::new()),
+            &mut Cursor::new(Vec::new()),
+        );
+        assert_eq!(res.unwrap(), 0);
+    };
+    let wait_for_writes = |writes, suppressed| {
+        let found = (0..50).any(|_| {
+            let stats = request_stats(connect_to_server(port).unwrap())
+                .unwrap()
+                .stats;
+            if (stats.cache_writes, stats.cache_writes_suppressed) == (writes, suppressed) {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(100));
+            false
+        });
+        assert!(found);
+    };
+    let prewarm = || {
+        server_creator
+            .lock()
+            .unwrap()
+            .next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessor", "")));
+        let compile = Compile {
+            exe: f.bins[0].clone().into(),
+            cwd: f.tempdir.path().to_owned().into(),
+            args: args(),
+            env_vars: vec![],
+        };
+        request_prewarm(connect_to_server(port).unwrap(), compile).unwrap()
+    };
+    let flag = |name: &str| vec![(OsString::from(name), OsString::from("1"))];
+
+    server_creator
+        .lock()
+        .unwrap()
+        .next_command_spawns(Ok(MockChild::new(exit_status(0), "gcc", "")));
+    // A read-only compile doesn't write its entry
+    compile(flag("SCCACHE_READONLY"));
+    wait_for_writes(0, 1);
+    assert_eq!(prewarm(), PrewarmResult::Miss);
+    // But the next compile does
+    compile(vec![]);
+    wait_for_writes(1, 1);
+    assert_eq!(prewarm(), PrewarmResult::Hit);
+    // A write-only compile doesn't look its entry up
+    compile(flag("SCCACHE_WRITEONLY"));
+    let stats = request_stats(connect_to_server(port).unwrap())
+        .unwrap()
+        .stats;
+    assert_eq!(stats.cache_hits.all(), 0);
+    assert_eq!(stats.forced_recaches, 1);
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_dry_run() {
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    let compile = |args: &[&str]| Compile {
+        exe: f.bins[0].clone().into(),
+        cwd: f.tempdir.path().to_owned().into(),
+        args: args.iter().map(|&arg| arg.into()).collect(),
+        env_vars: vec![],
+    };
+    {
+        let mut c = server_creator.lock().unwrap();
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "gcc", "")));
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessor", "")));
+    }
+    let dry_run = |compile| request_dry_run(connect_to_server(port).unwrap(), compile).unwrap();
+    // The compile is hashed, but not compiled
+    match dry_run(compile(&["-c", "file.c", "-o", "file.o"])) {
+        DryRunResult::Cacheable { key, pinned, .. } => {
+            assert!(!key.is_empty());
+            assert!(pinned);
+        }
+        result => panic!("unexpected dry run result: {:?}", result),
+    }
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    assert_eq!(
+        dry_run(compile(&["-c", "a.c", "b.c"])),
+        DryRunResult::NotCacheable {
+            reason: "multiple input files".to_owned(),
+            detail: Some("b.c".to_owned()),
+        }
+    );
+    // Dry runs aren't counted as compiles
+    let stats = request_stats(connect_to_server(port).unwrap())
+        .unwrap()
+        .stats;
+    assert_eq!(stats.compile_requests, 0);
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_admin() {
+    let f = TestFixture::new();
+    let token_path = f.tempdir.path().join("token").join("admin-token");
+    let (port, sender, _storage, child) = run_server_thread(
+        &f.tempdir.path().join("cache"),
+        ServerOptions {
+            admin_token: Some(token_path.clone()),
+            ..Default::default()
+        },
+    );
+    let token = fs::read_to_string(&token_path).unwrap();
+    let admin = |token: &str, request| {
+        request_admin(connect_to_server(port).unwrap(), token, request).unwrap()
+    };
+    assert!(matches!(
+        admin("wrong", AdminRequest::SetReadOnly(true)),
+        AdminResponse::Denied
+    ));
+    assert!(matches!(
+        admin(&token, AdminRequest::SetReadOnly(true)),
+        AdminResponse::ReadOnly(true)
+    ));
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert!(info.cache_location.ends_with("(read-only)"));
+    // The local disk can be cleared while the server is read-only, but
+    // remote storage can't
+    assert!(matches!(
+        admin(&token, AdminRequest::Clear(ClearScope::Local)),
+        AdminResponse::Cleared(Ok(()))
+    ));
+    assert!(matches!(
+        admin(&token, AdminRequest::Clear(ClearScope::All)),
+        AdminResponse::Cleared(Err(_))
+    ));
+    match admin(&token, AdminRequest::QueryKey("abcdef".to_owned())) {
+        AdminResponse::KeyStatus(found) => {
+            assert_eq!(found.len(), 2);
+            assert!(found.iter().all(|(_, found)| *found == Ok(false)));
+        }
+        res => panic!("Unexpected response {:?}", res),
+    }
+    assert!(matches!(
+        admin(&token, AdminRequest::SetOffline(true)),
+        AdminResponse::Offline(true)
+    ));
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(info.network_mode, NetworkMode::Offline);
+    assert!(matches!(
+        admin(&token, AdminRequest::SetOffline(false)),
+        AdminResponse::Offline(false)
+    ));
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(info.network_mode, NetworkMode::Online);
+    assert!(matches!(
+        admin(&token, AdminRequest::Flush),
+        AdminResponse::Flushed(Ok(()))
+    ));
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+    // The token is removed when the server shuts down
+    assert!(!token_path.exists());
+}
+
 #[test]
 fn test_server_compile() {
     let _ = env_logger::try_init();
@@ -251,18 +1102,19 @@ fn test_server_compile() {
     let mut stdout = Cursor::new(Vec::new());
     let mut stderr = Cursor::new(Vec::new());
     let path = Some(f.paths);
-    let mut runtime = Runtime::new().unwrap();
+    let runtime = util::runtime().unwrap();
     assert_eq!(
         0,
         do_compile(
             client_creator,
-            &mut runtime,
+            &runtime,
             conn,
             exe,
             cmdline,
             cwd,
             path,
             vec![],
+            None,
             &mut stdout,
             &mut stderr
         )
@@ -272,12 +1124,314 @@ fn test_server_compile() {
     assert_eq!(0, server_creator.lock().unwrap().children.len());
     assert_eq!(STDOUT, stdout.into_inner().as_slice());
     assert_eq!(STDERR, stderr.into_inner().as_slice());
+    // The miss is counted for the compiler and language
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    let gcc = &info.stats.compilers["C (GCC)"];
+    assert_eq!((gcc.hits, gcc.misses, gcc.errors), (0, 1, 0));
+    // And it's on the board of the slowest compiles
+    let slowest = &info.stats.slowest;
+    assert_eq!(slowest.len(), 1);
+    assert_eq!(
+        (slowest[0].source.as_str(), slowest[0].compiler.as_str()),
+        ("file.c", "C (GCC)")
+    );
+    assert!(!slowest[0].key.is_empty());
     // Shut down the server.
     sender.send(ServerMessage::Shutdown).ok().unwrap();
     // Ensure that it shuts down.
     child.join().unwrap();
 }
 
+/// Shutting down waits for the cache write of a compile that just finished.
+#[test]
+fn test_server_shutdown_drains() {
+    let f = TestFixture::new();
+    let (port, _sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    let conn = connect_to_server(port).unwrap();
+    {
+        let mut c = server_creator.lock().unwrap();
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "gcc", "")));
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessed", "")));
+        let obj = f.tempdir.path().join("file.o");
+        c.next_command_calls(move |_| {
+            File::create(&obj)?.write_all(b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+    }
+    let cmdline = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let runtime = util::runtime().unwrap();
+    let status = do_compile(
+        new_creator(),
+        &runtime,
+        conn,
+        &f.bins[0],
+        cmdline,
+        f.tempdir.path(),
+        Some(f.paths.clone()),
+        vec![],
+        None,
+        &mut Cursor::new(Vec::new()),
+        &mut Cursor::new(Vec::new()),
+    )
+    .unwrap();
+    assert_eq!(0, status);
+    let info = request_shutdown(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(1, info.stats.cache_writes);
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_run_command() {
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    f.touch("a.proto").unwrap();
+    let out = f.tempdir.path().join("a.pb.rs");
+    {
+        let out = out.clone();
+        let mut c = server_creator.lock().unwrap();
+        // The first run executes the command, which writes its output.
+        c.next_command_calls(move |_| {
+            let mut f = File::create(&out)?;
+            f.write_all(b"generated")?;
+            Ok(MockChild::new(exit_status(0), "some stdout", ""))
+        });
+    }
+    let exe = &f.bins[0];
+    let cwd = f.tempdir.path();
+    let runtime = util::runtime().unwrap();
+    for _ in 0..2 {
+        let client_creator = new_creator();
+        let mut stdout = Cursor::new(Vec::new());
+        let mut stderr = Cursor::new(Vec::new());
+        assert_eq!(
+            0,
+            do_run(
+                client_creator,
+                &runtime,
+                connect_to_server(port).unwrap(),
+                exe,
+                vec!["a.proto".into()],
+                cwd,
+                Some(f.paths.clone()),
+                vec![],
+                vec!["a.proto".into()],
+                vec!["a.pb.rs".into()],
+                &mut stdout,
+                &mut stderr
+            )
+            .unwrap()
+        );
+        assert_eq!(b"some stdout", stdout.into_inner().as_slice());
+        assert_eq!(b"generated", fs::read(&out).unwrap().as_slice());
+        // The second run restores it from the cache.
+        fs::remove_file(&out).unwrap();
+    }
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(Some(&1), info.stats.cache_hits.get("Command"));
+    assert_eq!(Some(&1), info.stats.cache_misses.get("Command"));
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_unpinned_toolchain() {
+    let f = TestFixture::new();
+    let options = ServerOptions {
+        toolchain_pins: ToolchainPinsConfig {
+            versions: vec!["some other gcc".to_owned()],
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), options);
+    let conn = connect_to_server(port).unwrap();
+    {
+        let mut c = server_creator.lock().unwrap();
+        // The server will check the compiler. Pretend it's GCC, and nothing
+        // else runs on the server since the toolchain isn't pinned.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "gcc", "")));
+    }
+    // The client runs the compiler itself instead.
+    let client_creator = new_creator();
+    next_command(&client_creator, Ok(MockChild::new(exit_status(0), "", "")));
+    let exe = &f.bins[0];
+    let cmdline = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let cwd = f.tempdir.path();
+    let mut stdout = Cursor::new(Vec::new());
+    let mut stderr = Cursor::new(Vec::new());
+    let path = Some(f.paths);
+    let runtime = util::runtime().unwrap();
+    assert_eq!(
+        0,
+        do_compile(
+            client_creator.clone(),
+            &runtime,
+            conn,
+            exe,
+            cmdline,
+            cwd,
+            path,
+            vec![],
+            None,
+            &mut stdout,
+            &mut stderr
+        )
+        .unwrap()
+    );
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    assert_eq!(0, client_creator.lock().unwrap().children.len());
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(Some(&1), info.stats.not_cached.get("unpinned toolchain"));
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+/// Compiles that can't be cached are counted with the argument behind their
+/// reason.
+#[test]
+fn test_server_not_cacheable_detail() {
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    {
+        let mut c = server_creator.lock().unwrap();
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "gcc", "")));
+    }
+    let client_creator = new_creator();
+    next_command(&client_creator, Ok(MockChild::new(exit_status(0), "", "")));
+    let runtime = util::runtime().unwrap();
+    assert_eq!(
+        0,
+        do_compile(
+            client_creator.clone(),
+            &runtime,
+            connect_to_server(port).unwrap(),
+            &f.bins[0],
+            vec![
+                "-c".into(),
+                "file.c".into(),
+                "other.c".into(),
+                "-o".into(),
+                "file.o".into()
+            ],
+            f.tempdir.path(),
+            Some(f.paths.clone()),
+            vec![],
+            None,
+            &mut Cursor::new(Vec::new()),
+            &mut Cursor::new(Vec::new()),
+        )
+        .unwrap()
+    );
+    assert_eq!(0, client_creator.lock().unwrap().children.len());
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(Some(&1), info.stats.not_cached.get("multiple input files"));
+    assert_eq!(
+        Some(&1),
+        info.stats.not_cached_details["multiple input files"].get("other.c")
+    );
+    let counters = info.stats.counters();
+    assert_eq!(
+        counters["not_cached_detail.multiple input files.other.c"],
+        1
+    );
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+/// With `SCCACHE_NO_CACHE=1`, the server doesn't even detect the compiler,
+/// and the client runs the compile itself.
+#[test]
+fn test_server_no_cache() {
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    let client_creator = new_creator();
+    next_command(&client_creator, Ok(MockChild::new(exit_status(0), "", "")));
+    let runtime = util::runtime().unwrap();
+    assert_eq!(
+        0,
+        do_compile(
+            client_creator.clone(),
+            &runtime,
+            connect_to_server(port).unwrap(),
+            &f.bins[0],
+            vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()],
+            f.tempdir.path(),
+            Some(f.paths.clone()),
+            vec![("SCCACHE_NO_CACHE".into(), "1".into())],
+            None,
+            &mut Cursor::new(Vec::new()),
+            &mut Cursor::new(Vec::new()),
+        )
+        .unwrap()
+    );
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    assert_eq!(0, client_creator.lock().unwrap().children.len());
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(info.stats.compile_requests, 1);
+    assert_eq!(Some(&1), info.stats.not_cached.get("SCCACHE_NO_CACHE"));
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_persists_stats() {
+    let f = TestFixture::new();
+    let stats_file = f.tempdir.path().join("stats.json");
+    let run_server = || {
+        run_server_thread(
+            &f.tempdir.path(),
+            ServerOptions {
+                stats_file: Some(stats_file.clone()),
+                ..Default::default()
+            },
+        )
+    };
+    let compile = |port| {
+        let client_creator = new_creator();
+        next_command(&client_creator, Ok(MockChild::new(exit_status(0), "", "")));
+        let runtime = util::runtime().unwrap();
+        do_compile(
+            client_creator,
+            &runtime,
+            connect_to_server(port).unwrap(),
+            &f.bins[0],
+            vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()],
+            f.tempdir.path(),
+            Some(f.paths.clone()),
+            vec![("SCCACHE_NO_CACHE".into(), "1".into())],
+            None,
+            &mut Cursor::new(Vec::new()),
+            &mut Cursor::new(Vec::new()),
+        )
+        .unwrap()
+    };
+
+    let (port, _sender, _creator, child) = run_server();
+    assert_eq!(0, compile(port));
+    request_shutdown(connect_to_server(port).unwrap()).unwrap();
+    child.join().unwrap();
+
+    // The next server counts on from the stats saved as the last shut down
+    let (port, _sender, _creator, child) = run_server();
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(info.stats.compile_requests, 1);
+    assert_eq!(Some(&1), info.stats.not_cached.get("SCCACHE_NO_CACHE"));
+    assert_eq!(0, compile(port));
+    // Zeroing them is saved right away
+    request_zero_stats(connect_to_server(port).unwrap()).unwrap();
+    let saved = fs::read_to_string(&stats_file).unwrap();
+    assert!(saved.contains("\"compile_requests\":0"), "{}", saved);
+    request_shutdown(connect_to_server(port).unwrap()).unwrap();
+    child.join().unwrap();
+
+    let (port, _sender, _creator, child) = run_server();
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(info.stats.compile_requests, 0);
+    request_shutdown(connect_to_server(port).unwrap()).unwrap();
+    child.join().unwrap();
+}
+
 #[test]
 // test fails intermittently on macos:
 // https://github.com/mozilla/sccache/issues/234
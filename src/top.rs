@@ -0,0 +1,240 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `sccache --top`: a screen of what the server is doing, redrawn every
+//! second, with the compiles in progress, the results of those that just
+//! finished, how long the storage takes and how full the cache is.
+//!
+//! The screen is drawn with plain terminal escapes, moving the cursor home
+//! and clearing each line as it's redrawn, so there's nothing to restore
+//! when it's interrupted.
+
+use crate::client::ServerConnection;
+use crate::commands::{live_stats_line, request_top};
+use crate::server::{StatsDelta, TopSnapshot};
+use atty::Stream;
+use number_prefix::NumberPrefix;
+use std::io::{self, Write};
+use std::time::Duration;
+
+use crate::errors::*;
+
+/// The size of the terminal if it can't be found, in columns and rows.
+const DEFAULT_SIZE: (usize, usize) = (80, 24);
+
+/// Show the screen until the server shuts down.
+pub fn run(conn: ServerConnection) -> Result<()> {
+    if !atty::is(Stream::Stdout) {
+        bail!("sccache --top needs a terminal, use --watch-stats to stream the stats");
+    }
+    print!("\x1b[2J");
+    request_top(conn, |snapshot| {
+        let (width, height) = terminal_size();
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        write!(stdout, "\x1b[H")?;
+        // A row is left for the cursor, so the screen doesn't scroll
+        for line in render(snapshot, width, height.saturating_sub(1)) {
+            write!(stdout, "{}\x1b[K\r\n", line)?;
+        }
+        write!(stdout, "\x1b[J")?;
+        stdout.flush()?;
+        Ok(())
+    })
+    .context("failed to watch the server")?;
+    println!("The server shut down");
+    Ok(())
+}
+
+#[cfg(unix)]
+fn terminal_size() -> (usize, usize) {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0
+        && size.ws_col > 0
+        && size.ws_row > 0
+    {
+        (size.ws_col.into(), size.ws_row.into())
+    } else {
+        DEFAULT_SIZE
+    }
+}
+
+#[cfg(not(unix))]
+fn terminal_size() -> (usize, usize) {
+    DEFAULT_SIZE
+}
+
+/// The lines of the screen for `snapshot`, at most `width` columns wide and
+/// `height` rows high.
+fn render(snapshot: &TopSnapshot, width: usize, height: usize) -> Vec<String> {
+    let delta = StatsDelta {
+        zeroed: true,
+        counts: snapshot.counts,
+        compiles_active: snapshot.active.len(),
+        cache_writes_active: snapshot.cache_writes_active,
+    };
+    let size = match (snapshot.cache_size, snapshot.max_cache_size) {
+        (Some(size), Some(max)) => format!(", {} of {}", format_size(size), format_size(max)),
+        (Some(size), None) => format!(", {}", format_size(size)),
+        _ => String::new(),
+    };
+    let mean = |mean: Option<Duration>| mean.map_or_else(|| "-".to_owned(), format_duration);
+    let mut lines = vec![
+        live_stats_line(&snapshot.counts, &delta),
+        format!("Cache: {}{}", snapshot.cache_location, size),
+        format!(
+            "Mean lookup: {} hit, {} missed; mean write: {}",
+            mean(snapshot.cache_read_hit_mean),
+            mean(snapshot.cache_read_miss_mean),
+            mean(snapshot.cache_write_mean)
+        ),
+    ];
+
+    // The compiles in progress get the rows the recent ones don't need, and
+    // at least half
+    let rows = height.saturating_sub(lines.len() + 6);
+    let active = snapshot
+        .active
+        .len()
+        .min((rows / 2).max(rows.saturating_sub(snapshot.recent.len())));
+    let recent = snapshot.recent.len().min(rows - active);
+
+    lines.push(String::new());
+    lines.push(if active < snapshot.active.len() {
+        format!("Compiling: {} ({} shown)", snapshot.active.len(), active)
+    } else {
+        format!("Compiling: {}", snapshot.active.len())
+    });
+    lines.push(format!("{:>8}  {:<8}  SOURCE", "ELAPSED", "COMPILER"));
+    for compile in &snapshot.active[..active] {
+        let line = format!(
+            "{:>8}  {:<8}  ",
+            format_duration(compile.elapsed),
+            compile.compiler
+        );
+        lines.push(elide_left(line, &compile.source, width));
+    }
+
+    lines.push(String::new());
+    lines.push("Recent:".to_owned());
+    lines.push(format!(
+        "{:<13}  {:>8}  {:>8}  {:<8}  OUTPUT",
+        "RESULT", "TIME", "LOOKUP", "COMPILER"
+    ));
+    for compile in &snapshot.recent[..recent] {
+        let line = format!(
+            "{:<13}  {:>8}  {:>8}  {:<8}  ",
+            compile.result,
+            format_duration(compile.duration),
+            format_duration(compile.cache_read),
+            compile.compiler
+        );
+        lines.push(elide_left(line, &compile.output, width));
+    }
+
+    lines.truncate(height);
+    for line in &mut lines {
+        if let Some((end, _)) = line.char_indices().nth(width) {
+            line.truncate(end);
+        }
+    }
+    lines
+}
+
+/// `line` followed by `path`, with the start of the path cut off if the
+/// line would be wider than `width`.
+fn elide_left(mut line: String, path: &str, width: usize) -> String {
+    let room = width.saturating_sub(line.chars().count());
+    let len = path.chars().count();
+    if len <= room {
+        line.push_str(path);
+    } else if room > 0 {
+        line.push('…');
+        line.extend(path.chars().skip(len - room + 1));
+    }
+    line
+}
+
+fn format_duration(duration: Duration) -> String {
+    if duration < Duration::from_secs(1) {
+        format!("{}ms", duration.as_millis())
+    } else {
+        format!("{:.1}s", duration.as_secs_f64())
+    }
+}
+
+fn format_size(size: u64) -> String {
+    match NumberPrefix::binary(size as f64) {
+        NumberPrefix::Standalone(bytes) => format!("{} bytes", bytes),
+        NumberPrefix::Prefixed(prefix, n) => format!("{:.1} {}B", n, prefix),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::{ActiveCompile, RecentCompile};
+
+    #[test]
+    fn test_render() {
+        let compile = |output: &str| RecentCompile {
+            output: output.to_owned(),
+            compiler: "C/C++".to_owned(),
+            result: "hit".to_owned(),
+            duration: Duration::from_millis(15),
+            cache_read: Duration::from_millis(3),
+        };
+        let mut snapshot = TopSnapshot {
+            active: vec![ActiveCompile {
+                source: "/src/project/lib/foo.c".to_owned(),
+                compiler: "C/C++".to_owned(),
+                elapsed: Duration::from_millis(2500),
+            }],
+            recent: vec![compile("bar.o"), compile("baz.o")],
+            cache_read_hit_mean: Some(Duration::from_millis(3)),
+            cache_location: "Local disk: \"/cache\"".to_owned(),
+            cache_size: Some(1536),
+            max_cache_size: Some(10 << 30),
+            ..Default::default()
+        };
+        snapshot.counts.compile_requests = 3;
+        snapshot.counts.cache_hits = 2;
+
+        let lines = render(&snapshot, 80, 24);
+        assert_eq!(
+            lines[1..],
+            [
+                "Cache: Local disk: \"/cache\", 1.5 KiB of 10.0 GiB",
+                "Mean lookup: 3ms hit, - missed; mean write: -",
+                "",
+                "Compiling: 1",
+                " ELAPSED  COMPILER  SOURCE",
+                "    2.5s  C/C++     /src/project/lib/foo.c",
+                "",
+                "Recent:",
+                "RESULT             TIME    LOOKUP  COMPILER  OUTPUT",
+                "hit                15ms       3ms  C/C++     bar.o",
+                "hit                15ms       3ms  C/C++     baz.o",
+            ]
+        );
+
+        // The source is cut from the start to fit, and the recent compiles
+        // are cut to fit the height
+        let lines = render(&snapshot, 40, 11);
+        assert_eq!(lines.len(), 11);
+        assert_eq!(lines[6], "    2.5s  C/C++     …c/project/lib/foo.c");
+        assert_eq!(lines[9], "RESULT             TIME    LOOKUP  COMPI");
+        assert_eq!(lines[10], "hit                15ms       3ms  C/C++");
+    }
+}
@@ -0,0 +1,282 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Traces of compile requests, exported to an OpenTelemetry collector with
+//! OTLP over HTTP, in its JSON encoding.
+//!
+//! A trace is sent once its root span ends, so child spans must end before
+//! it. Without an exporter, spans do nothing.
+
+// Without the otlp feature there's never an exporter to read traces
+#![cfg_attr(not(feature = "otlp"), allow(dead_code))]
+
+use serde_json::json;
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::SyncSender;
+#[cfg(feature = "otlp")]
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How many finished traces can wait for the exporter before new ones are
+/// dropped.
+const QUEUE_LEN: usize = 1024;
+
+lazy_static! {
+    static ref EXPORTER: Mutex<Option<SyncSender<Vec<FinishedSpan>>>> = Mutex::new(None);
+}
+
+/// Start exporting traces if `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` or
+/// `OTEL_EXPORTER_OTLP_ENDPOINT` is set.
+pub fn init_exporter() {
+    let endpoint = match env::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT") {
+        Ok(endpoint) => endpoint,
+        Err(_) => match env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+            Ok(endpoint) => format!("{}/v1/traces", endpoint.trim_end_matches('/')),
+            Err(_) => return,
+        },
+    };
+    #[cfg(feature = "otlp")]
+    {
+        let service = env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "sccache".to_owned());
+        let (tx, rx) = mpsc::sync_channel(QUEUE_LEN);
+        info!("Exporting traces to {}", endpoint);
+        std::thread::spawn(move || export(&endpoint, &service, rx));
+        *EXPORTER.lock().unwrap() = Some(tx);
+    }
+    #[cfg(not(feature = "otlp"))]
+    warn!(
+        "sccache was built without the otlp feature, not exporting traces to {}",
+        endpoint
+    );
+}
+
+#[cfg(feature = "otlp")]
+fn export(endpoint: &str, service: &str, rx: Receiver<Vec<FinishedSpan>>) {
+    // Send traces in batches, rather than a request for every compile
+    const BATCH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+    let client = reqwest::blocking::Client::new();
+    while let Ok(mut spans) = rx.recv() {
+        std::thread::sleep(BATCH_INTERVAL);
+        spans.extend(rx.try_iter().flatten());
+        let res = client
+            .post(endpoint)
+            .json(&to_otlp(service, &spans))
+            .send()
+            .and_then(|res| res.error_for_status());
+        if let Err(e) = res {
+            warn!("Failed to export {} spans: {}", spans.len(), e);
+        }
+    }
+}
+
+type TraceId = [u8; 16];
+type SpanId = [u8; 8];
+
+struct Trace {
+    id: TraceId,
+    spans: Mutex<Vec<FinishedSpan>>,
+    exporter: SyncSender<Vec<FinishedSpan>>,
+}
+
+struct SpanInner {
+    trace: Arc<Trace>,
+    id: SpanId,
+    parent: Option<SpanId>,
+    name: &'static str,
+    start: SystemTime,
+    attributes: Mutex<Vec<(&'static str, String)>>,
+    ended: AtomicBool,
+}
+
+struct FinishedSpan {
+    trace_id: TraceId,
+    id: SpanId,
+    parent: Option<SpanId>,
+    name: &'static str,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, String)>,
+}
+
+/// A span of a trace, started when it's created. Clones refer to the same
+/// span, which is finished by `end`.
+#[derive(Clone, Default)]
+pub struct Span(Option<Arc<SpanInner>>);
+
+impl Span {
+    /// Start a new trace, if they're being exported.
+    pub fn root(name: &'static str) -> Span {
+        match *EXPORTER.lock().unwrap() {
+            Some(ref exporter) => Span::root_with(name, exporter.clone()),
+            None => Span(None),
+        }
+    }
+
+    fn root_with(name: &'static str, exporter: SyncSender<Vec<FinishedSpan>>) -> Span {
+        let trace = Arc::new(Trace {
+            id: rand::random(),
+            spans: Mutex::new(vec![]),
+            exporter,
+        });
+        Span::start(trace, None, name)
+    }
+
+    fn start(trace: Arc<Trace>, parent: Option<SpanId>, name: &'static str) -> Span {
+        Span(Some(Arc::new(SpanInner {
+            trace,
+            id: rand::random(),
+            parent,
+            name,
+            start: SystemTime::now(),
+            attributes: Mutex::new(vec![]),
+            ended: AtomicBool::new(false),
+        })))
+    }
+
+    /// Start a span within this one.
+    pub fn child(&self, name: &'static str) -> Span {
+        match self.0 {
+            Some(ref inner) => Span::start(inner.trace.clone(), Some(inner.id), name),
+            None => Span(None),
+        }
+    }
+
+    pub fn set_attribute(&self, key: &'static str, value: impl Into<String>) {
+        if let Some(ref inner) = self.0 {
+            inner.attributes.lock().unwrap().push((key, value.into()))
+        }
+    }
+
+    /// Finish the span, and send the trace if this is its root. Further
+    /// calls do nothing.
+    pub fn end(&self) {
+        let inner = match self.0 {
+            Some(ref inner) if !inner.ended.swap(true, Ordering::SeqCst) => inner,
+            _ => return,
+        };
+        let trace = &inner.trace;
+        let span = FinishedSpan {
+            trace_id: trace.id,
+            id: inner.id,
+            parent: inner.parent,
+            name: inner.name,
+            start: inner.start,
+            end: SystemTime::now(),
+            attributes: inner.attributes.lock().unwrap().clone(),
+        };
+        let mut spans = trace.spans.lock().unwrap();
+        spans.push(span);
+        if inner.parent.is_none() {
+            let spans = std::mem::replace(&mut *spans, vec![]);
+            if trace.exporter.try_send(spans).is_err() {
+                debug!("Dropped a trace, the exporter is behind");
+            }
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    since_epoch.as_nanos().to_string()
+}
+
+fn attributes(attributes: &[(&str, String)]) -> Vec<serde_json::Value> {
+    attributes
+        .iter()
+        .map(|(key, value)| json!({"key": key, "value": {"stringValue": value}}))
+        .collect()
+}
+
+// https://github.com/open-telemetry/opentelemetry-proto/blob/main/docs/specification.md#json-protobuf-encoding
+fn to_otlp(service: &str, spans: &[FinishedSpan]) -> serde_json::Value {
+    let spans: Vec<_> = spans
+        .iter()
+        .map(|span| {
+            let mut json = json!({
+                "traceId": hex(&span.trace_id),
+                "spanId": hex(&span.id),
+                "name": span.name,
+                // SPAN_KIND_INTERNAL
+                "kind": 1,
+                "startTimeUnixNano": unix_nanos(span.start),
+                "endTimeUnixNano": unix_nanos(span.end),
+                "attributes": attributes(&span.attributes),
+            });
+            if let Some(parent) = span.parent {
+                json["parentSpanId"] = hex(&parent).into();
+            }
+            json
+        })
+        .collect();
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": attributes(&[("service.name", service.to_owned())]),
+            },
+            "scopeSpans": [{
+                "scope": {"name": "sccache", "version": env!("CARGO_PKG_VERSION")},
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_trace() {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let root = Span::root_with("compile request", tx);
+        root.set_attribute("output", "foo.o");
+        let child = root.child("hash");
+        let clone = child.clone();
+        child.end();
+        clone.end();
+        assert!(rx.try_recv().is_err());
+        root.end();
+        let spans = rx.try_recv().unwrap();
+        assert_eq!(spans.len(), 2);
+
+        let json = to_otlp("sccache", &spans);
+        let scope = &json["resourceSpans"][0]["scopeSpans"][0];
+        let (child, root) = (&scope["spans"][0], &scope["spans"][1]);
+        assert_eq!(child["name"], "hash");
+        assert_eq!(child["parentSpanId"], root["spanId"]);
+        assert_eq!(child["traceId"], root["traceId"]);
+        assert_eq!(root["traceId"].as_str().unwrap().len(), 32);
+        assert!(root.get("parentSpanId").is_none());
+        assert_eq!(
+            root["attributes"],
+            json!([{"key": "output", "value": {"stringValue": "foo.o"}}])
+        );
+    }
+
+    #[test]
+    fn test_disabled() {
+        let root = Span::default();
+        root.set_attribute("output", "foo.o");
+        root.child("hash").end();
+        root.end();
+    }
+}
@@ -15,31 +15,31 @@
 use crate::mock_command::{CommandChild, RunCommand};
 use blake3::Hasher as blake3_Hasher;
 use byteorder::{BigEndian, ByteOrder};
-use futures::{future, Future};
-use futures_03::executor::ThreadPool;
-use futures_03::future::TryFutureExt;
-use futures_03::task;
+use futures::executor::ThreadPool;
+use futures::future::{BoxFuture, FutureExt};
+use futures::task;
+use futures::Future;
 use serde::Serialize;
+use std::borrow::Cow;
 use std::ffi::{OsStr, OsString};
+use std::fmt;
 use std::fs::File;
-use std::hash::Hasher;
+use std::hash::{Hash, Hasher};
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 use std::process::{self, Stdio};
-use std::time;
 use std::time::Duration;
 
 use crate::errors::*;
 
 pub trait SpawnExt: task::SpawnExt {
-    fn spawn_fn<F, T>(&self, f: F) -> SFuture<T>
+    fn spawn_fn<F, T>(&self, f: F) -> BoxFuture<'static, Result<T>>
     where
         F: FnOnce() -> Result<T> + std::marker::Send + 'static,
         T: std::marker::Send + 'static,
     {
-        self.spawn_with_handle(async move { f() })
-            .map(|f| Box::new(f.compat()) as _)
-            .unwrap_or_else(f_err)
+        let handle = self.spawn_with_handle(async move { f() });
+        async move { handle?.await }.boxed()
     }
 }
 
@@ -59,7 +59,7 @@ impl Digest {
 
     /// Calculate the BLAKE3 digest of the contents of `path`, running
     /// the actual hash computation on a background thread in `pool`.
-    pub fn file<T>(path: T, pool: &ThreadPool) -> SFuture<String>
+    pub fn file<T>(path: T, pool: &ThreadPool) -> impl Future<Output = Result<String>> + 'static
     where
         T: AsRef<Path>,
     {
@@ -84,16 +84,43 @@ impl Digest {
 
     /// Calculate the BLAKE3 digest of the contents of `path`, running
     /// the actual hash computation on a background thread in `pool`.
-    pub fn reader(path: PathBuf, pool: &ThreadPool) -> SFuture<String> {
-        Box::new(pool.spawn_fn(move || -> Result<_> {
-            let reader = File::open(&path)
-                .with_context(|| format!("Failed to open file for hashing: {:?}", path))?;
-            Digest::reader_sync(reader)
-        }))
+    pub fn reader(
+        path: PathBuf,
+        pool: &ThreadPool,
+    ) -> impl Future<Output = Result<String>> + 'static {
+        pool.spawn_fn(move || Digest::file_sync(&path))
+    }
+
+    /// Calculate the BLAKE3 digest of the contents of `path`, reading a
+    /// large file in chunks big enough to be hashed in parallel.
+    pub fn file_sync(path: &Path) -> Result<String> {
+        let mut file = File::open(path)
+            .with_context(|| format!("Failed to open file for hashing: {:?}", path))?;
+        if file.metadata()?.len() < LARGE_HASH_LEN {
+            return Digest::reader_sync(file);
+        }
+        let mut m = Digest::new();
+        let mut buffer = Vec::with_capacity(LARGE_HASH_CHUNK);
+        loop {
+            buffer.clear();
+            let count = (&mut file)
+                .take(LARGE_HASH_CHUNK as u64)
+                .read_to_end(&mut buffer)?;
+            if count == 0 {
+                break;
+            }
+            m.update(&buffer);
+        }
+        Ok(m.finish())
     }
 
     pub fn update(&mut self, bytes: &[u8]) {
-        self.inner.update(bytes);
+        if bytes.len() >= PARALLEL_HASH_LEN {
+            self.inner
+                .update_with_join::<blake3::join::RayonJoin>(bytes);
+        } else {
+            self.inner.update(bytes);
+        }
     }
 
     pub fn finish(self) -> String {
@@ -107,6 +134,99 @@ impl Default for Digest {
     }
 }
 
+/// Files at least this big, such as precompiled headers, are read in chunks
+/// of `LARGE_HASH_CHUNK` bytes to hash.
+const LARGE_HASH_LEN: u64 = 16 * 1024 * 1024;
+
+const LARGE_HASH_CHUNK: usize = 4 * PARALLEL_HASH_LEN;
+
+/// Append `len` bytes of `from`, starting at `offset`, to `to`.
+///
+/// On Linux the kernel copies them, sharing the blocks between the files
+/// where the filesystem can, rather than reading and writing them here.
+pub fn copy_file_range(from: &File, offset: u64, len: u64, to: &mut File) -> std::io::Result<()> {
+    let (offset, len) = kernel_copy_file_range(from, offset, len, to)?;
+    if len == 0 {
+        return Ok(());
+    }
+    let mut from = from.try_clone()?;
+    from.seek(std::io::SeekFrom::Start(offset))?;
+    let copied = std::io::copy(&mut from.take(len), to)?;
+    if copied < len {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// Copy what the kernel can of a range of `from` to `to`, returning the
+/// range left to copy.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn kernel_copy_file_range(
+    from: &File,
+    mut offset: u64,
+    mut len: u64,
+    to: &mut File,
+) -> std::io::Result<(u64, u64)> {
+    use std::os::unix::io::AsRawFd;
+
+    while len > 0 {
+        let mut off_in = offset as libc::loff_t;
+        let copied = unsafe {
+            libc::copy_file_range(
+                from.as_raw_fd(),
+                &mut off_in,
+                to.as_raw_fd(),
+                std::ptr::null_mut(),
+                len as usize,
+                0,
+            )
+        };
+        if copied > 0 {
+            offset += copied as u64;
+            len -= copied as u64;
+            continue;
+        }
+        if copied == 0 {
+            // The file is shorter than it should be, which copying the rest
+            // reports
+            break;
+        }
+        let e = std::io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::EINTR) => {}
+            // Not supported by the kernel or between these filesystems
+            Some(libc::ENOSYS)
+            | Some(libc::EXDEV)
+            | Some(libc::EINVAL)
+            | Some(libc::EOPNOTSUPP) => break,
+            _ => return Err(e),
+        }
+    }
+    Ok((offset, len))
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+fn kernel_copy_file_range(
+    _from: &File,
+    offset: u64,
+    len: u64,
+    _to: &mut File,
+) -> std::io::Result<(u64, u64)> {
+    Ok((offset, len))
+}
+
+/// Inputs at least this big, such as the output of the preprocessor for a
+/// large translation unit, are hashed on several threads of rayon's global
+/// pool, which is as big as there are CPUs. Below it, handing work to other
+/// threads costs more than it saves.
+pub const PARALLEL_HASH_LEN: usize = 1024 * 1024;
+
+/// Whether `a` and `b` are the same, taking as long whichever of their
+/// bytes differ, to compare secrets such as tokens.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
 pub fn hex(bytes: &[u8]) -> String {
     let mut s = String::with_capacity(bytes.len() * 2);
     for &byte in bytes {
@@ -123,28 +243,13 @@ pub fn hex(bytes: &[u8]) -> String {
     }
 }
 
-/// Calculate the digest of each file in `files` on background threads in
-/// `pool`.
-pub fn hash_all(files: &[PathBuf], pool: &ThreadPool) -> SFuture<Vec<String>> {
-    let start = time::Instant::now();
-    let count = files.len();
-    let pool = pool.clone();
-    Box::new(
-        future::join_all(
-            files
-                .iter()
-                .map(move |f| Digest::file(f, &pool))
-                .collect::<Vec<_>>(),
-        )
-        .map(move |hashes| {
-            trace!(
-                "Hashed {} files in {}",
-                count,
-                fmt_duration_as_secs(&start.elapsed())
-            );
-            hashes
-        }),
-    )
+/// Build a runtime that runs the futures spawned on it on the current thread,
+/// with its I/O and timers enabled.
+pub fn runtime() -> Result<tokio::runtime::Runtime> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Failed to create the tokio runtime")
 }
 
 /// Format `duration` as seconds with a fractional component.
@@ -152,52 +257,109 @@ pub fn fmt_duration_as_secs(duration: &Duration) -> String {
     format!("{}.{:03} s", duration.as_secs(), duration.subsec_millis())
 }
 
-/// If `input`, write it to `child`'s stdin while also reading `child`'s stdout and stderr, then wait on `child` and return its status and output.
+/// If `input`, write it to `child`'s stdin while also reading `child`'s stdout and stderr, then wait on `child` and return its status and output,
+/// and the digest of its stdout if `hash_stdout`.
 ///
 /// This was lifted from `std::process::Child::wait_with_output` and modified
 /// to also write to stdin.
-fn wait_with_input_output<T>(mut child: T, input: Option<Vec<u8>>) -> SFuture<process::Output>
+async fn wait_with_input_output<T>(
+    mut child: T,
+    input: Option<Vec<u8>>,
+    hash_stdout: bool,
+) -> Result<(process::Output, Option<String>)>
 where
     T: CommandChild + 'static,
 {
-    use tokio_io::io::{read_to_end, write_all};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
     let stdin = input.and_then(|i| {
-        child
-            .take_stdin()
-            .map(|stdin| write_all(stdin, i).fcontext("failed to write stdin"))
+        child.take_stdin().map(|mut stdin| async move {
+            stdin.write_all(&i).await.context("failed to write stdin")
+        })
     });
-    let stdout = child
-        .take_stdout()
-        .map(|io| read_to_end(io, Vec::new()).fcontext("failed to read stdout"));
-    let stderr = child
-        .take_stderr()
-        .map(|io| read_to_end(io, Vec::new()).fcontext("failed to read stderr"));
-
+    let stdout = child.take_stdout();
+    let stdout = async {
+        let mut io = match stdout {
+            Some(io) => io,
+            None => return Ok((None, vec![])),
+        };
+        // Hashed as it's read, rather than once the command has finished
+        let mut digest = if hash_stdout {
+            Some(Digest::new())
+        } else {
+            None
+        };
+        let mut stdout = vec![];
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let count = io
+                .read(&mut buffer)
+                .await
+                .context("failed to read stdout")?;
+            if count == 0 {
+                break;
+            }
+            if let Some(ref mut digest) = digest {
+                digest.update(&buffer[..count]);
+            }
+            stdout.extend_from_slice(&buffer[..count]);
+        }
+        Ok((digest.map(Digest::finish), stdout))
+    };
+    let stderr_io = child.take_stderr();
+    let stderr = async {
+        let mut stderr = vec![];
+        if let Some(mut io) = stderr_io {
+            io.read_to_end(&mut stderr)
+                .await
+                .context("failed to read stderr")?;
+        }
+        Ok(stderr)
+    };
     // Finish writing stdin before waiting, because waiting drops stdin.
-    let status = Future::and_then(stdin, |io| {
-        drop(io);
-        child.wait().fcontext("failed to wait for child")
-    });
-
-    Box::new(status.join3(stdout, stderr).map(|(status, out, err)| {
-        let stdout = out.map(|p| p.1);
-        let stderr = err.map(|p| p.1);
-        process::Output {
-            status,
-            stdout: stdout.unwrap_or_default(),
-            stderr: stderr.unwrap_or_default(),
+    let status = async {
+        if let Some(stdin) = stdin {
+            stdin.await?;
         }
-    }))
+        child.wait().await.context("failed to wait for child")
+    };
+    let (status, (digest, stdout), stderr) = futures::try_join!(status, stdout, stderr)?;
+    let output = process::Output {
+        status,
+        stdout,
+        stderr,
+    };
+    Ok((output, digest))
 }
 
 /// Run `command`, writing `input` to its stdin if it is `Some` and return the exit status and output.
 ///
 /// If the command returns a non-successful exit status, an error of `SccacheError::ProcessError`
 /// will be returned containing the process output.
-pub fn run_input_output<C>(
+pub async fn run_input_output<C>(command: C, input: Option<Vec<u8>>) -> Result<process::Output>
+where
+    C: RunCommand,
+{
+    let (output, _) = run_input_output_with(command, input, false).await?;
+    Ok(output)
+}
+
+/// Run `command` like `run_input_output`, also returning the digest of its
+/// stdout, hashed as it's read rather than once the command has finished.
+pub async fn run_input_output_hashed<C>(command: C) -> Result<(process::Output, String)>
+where
+    C: RunCommand,
+{
+    let (output, digest) = run_input_output_with(command, None, true).await?;
+    // Without stdout to read, it's empty
+    let digest = digest.unwrap_or_else(|| Digest::new().finish());
+    Ok((output, digest))
+}
+
+async fn run_input_output_with<C>(
     mut command: C,
     input: Option<Vec<u8>>,
-) -> impl Future<Item = process::Output, Error = Error>
+    hash_stdout: bool,
+) -> Result<(process::Output, Option<String>)>
 where
     C: RunCommand,
 {
@@ -210,17 +372,15 @@ where
         })
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
-        .spawn();
-
-    child.and_then(|child| {
-        wait_with_input_output(child, input).and_then(|output| {
-            if output.status.success() {
-                f_ok(output)
-            } else {
-                f_err(ProcessError(output))
-            }
-        })
-    })
+        .spawn()
+        .await?;
+
+    let (output, digest) = wait_with_input_output(child, input, hash_stdout).await?;
+    if output.status.success() {
+        Ok((output, digest))
+    } else {
+        Err(ProcessError(output).into())
+    }
 }
 
 /// Write `data` to `writer` with bincode serialization, prefixed by a `u32` length.
@@ -354,11 +514,281 @@ impl<'a> Hasher for HashToDigest<'a> {
     }
 }
 
+/// An input of a hash key, named, and what was hashed or the digest of it.
+pub type HashInput = (String, String);
+
+/// A `Digest` that can also record each input hashed into it, to explain
+/// which of them changed when a key does.
+pub struct RecordingDigest {
+    digest: Digest,
+    inputs: Option<Vec<HashInput>>,
+}
+
+impl RecordingDigest {
+    /// A digest that records its inputs if `record` is set.
+    pub fn new(record: bool) -> RecordingDigest {
+        RecordingDigest {
+            digest: Digest::new(),
+            inputs: if record { Some(vec![]) } else { None },
+        }
+    }
+
+    /// The digest, to hash something made of several parts, recorded with
+    /// `record`.
+    pub fn digest_mut(&mut self) -> &mut Digest {
+        &mut self.digest
+    }
+
+    /// Record an input, if inputs are recorded, as `value` shows it.
+    pub fn record(&mut self, name: &str, value: impl FnOnce() -> String) {
+        if let Some(ref mut inputs) = self.inputs {
+            inputs.push((name.to_owned(), value()));
+        }
+    }
+
+    /// Hash `bytes`, recording their digest.
+    pub fn update(&mut self, name: &str, bytes: &[u8]) {
+        self.digest.update(bytes);
+        self.record(name, || {
+            let mut m = Digest::new();
+            m.update(bytes);
+            format!("{} ({} bytes)", m.finish(), bytes.len())
+        });
+    }
+
+    /// Hash `digest`, of `len` bytes hashed elsewhere, recording it as
+    /// `update` records the bytes.
+    pub fn update_digest(&mut self, name: &str, digest: &str, len: usize) {
+        self.digest.update(digest.as_bytes());
+        self.record(name, || format!("{} ({} bytes)", digest, len));
+    }
+
+    /// Hash `text`, such as a digest or a version, recording it as it is.
+    pub fn update_str(&mut self, name: &str, text: &str) {
+        self.digest.update(text.as_bytes());
+        self.record(name, || text.to_owned());
+    }
+
+    /// Hash `value` as `Hash` does, recording it as `Debug` shows it.
+    pub fn hash<H: Hash + fmt::Debug + ?Sized>(&mut self, name: &str, value: &H) {
+        value.hash(&mut HashToDigest {
+            digest: &mut self.digest,
+        });
+        self.record(name, || format!("{:?}", value));
+    }
+
+    /// The digest of the inputs so far.
+    pub fn current(&self) -> String {
+        self.digest.clone().finish()
+    }
+
+    /// The digest, and the inputs if they were recorded.
+    pub fn finish(self) -> (String, Vec<HashInput>) {
+        (self.digest.finish(), self.inputs.unwrap_or_default())
+    }
+}
+
+/// The value of the environment variable `name`, or else the contents of the
+/// file named by `<name>_FILE`, as mounted by Docker or Kubernetes secrets,
+/// without a trailing newline, or else the field `name` of the secret
+/// fetched from Vault or AWS Secrets Manager. `None` if none of them is set.
+pub fn secret_var(name: &str) -> Result<Option<String>> {
+    if let Ok(value) = std::env::var(name) {
+        return Ok(Some(value));
+    }
+    let file_var = format!("{}_FILE", name);
+    let path = match std::env::var_os(&file_var) {
+        Some(path) => PathBuf::from(path),
+        None => return Ok(crate::secrets::get(name)),
+    };
+    let value = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {} from {}", file_var, path.display()))?;
+    Ok(Some(value.trim_end_matches(&['\r', '\n'][..]).to_owned()))
+}
+
 /// Turns a slice of environment var tuples into the type expected by Command::envs.
 pub fn ref_env(env: &[(OsString, OsString)]) -> impl Iterator<Item = (&OsString, &OsString)> {
     env.iter().map(|&(ref k, ref v)| (k, v))
 }
 
+/// Match `s` against a shell-style `pattern` in which `*` stands for any
+/// (possibly empty) sequence of characters.
+pub fn glob_match(pattern: &str, s: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let mut rest = match s.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let mut parts = parts.collect::<Vec<_>>();
+    let last = match parts.pop() {
+        Some(last) => last,
+        // No `*` at all: the whole string must match.
+        None => return rest.is_empty(),
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Rewrite absolute paths under `base_dir` in `bytes` into paths relative to
+/// `cwd`, so that the same build from a checkout in another location produces
+/// the same bytes.
+///
+/// Nothing is rewritten unless `cwd` is itself inside `base_dir`.
+pub fn relativize_base_dir<'a>(bytes: &'a [u8], base_dir: &Path, cwd: &Path) -> Cow<'a, [u8]> {
+    let depth = match cwd.strip_prefix(base_dir) {
+        Ok(rel) => rel.components().count(),
+        Err(_) => return Cow::Borrowed(bytes),
+    };
+    let base = base_dir.to_string_lossy();
+    let base = base.trim_end_matches(std::path::is_separator).as_bytes();
+    if base.is_empty() || !bytes.windows(base.len()).any(|w| w == base) {
+        return Cow::Borrowed(bytes);
+    }
+    let rel = if depth == 0 {
+        ".".to_owned()
+    } else {
+        vec![".."; depth].join(&std::path::MAIN_SEPARATOR.to_string())
+    };
+    // The match must end on a component boundary, so `/src` doesn't match
+    // in `/srcdir`.
+    let continues_name = |b: u8| b.is_ascii_alphanumeric() || b >= 0x80 || b"_-.+~".contains(&b);
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let rest = &bytes[i..];
+        if rest.starts_with(base) && rest.get(base.len()).map_or(true, |&b| !continues_name(b)) {
+            result.extend_from_slice(rel.as_bytes());
+            i += base.len();
+        } else {
+            result.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Cow::Owned(result)
+}
+
+/// `path` as an extended-length path, `\\?\C:\...` or `\\?\UNC\...`, so
+/// that it can be longer than `MAX_PATH` on Windows. Such paths aren't
+/// normalized by Windows, so `.` and `..` are resolved here; relative paths
+/// are returned as they are.
+#[cfg(windows)]
+pub fn long_path(path: &Path) -> Cow<'_, Path> {
+    use std::path::{Component, Prefix};
+
+    let mut components = path.components();
+    let mut long = match components.next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::Disk(letter) => OsString::from(format!(r"\\?\{}:", letter as char)),
+            Prefix::UNC(server, share) => {
+                let mut long = OsString::from(r"\\?\UNC\");
+                long.push(server);
+                long.push(r"\");
+                long.push(share);
+                long
+            }
+            // Already extended-length, or a device
+            _ => return Cow::Borrowed(path),
+        },
+        _ => return Cow::Borrowed(path),
+    };
+    // `C:foo` is relative to the current directory of the drive
+    if components.next() != Some(Component::RootDir) {
+        return Cow::Borrowed(path);
+    }
+    let mut names = vec![];
+    for component in components {
+        match component {
+            Component::Normal(name) => names.push(name),
+            Component::ParentDir => {
+                names.pop();
+            }
+            _ => {}
+        }
+    }
+    if names.is_empty() {
+        long.push(r"\");
+    }
+    for name in names {
+        long.push(r"\");
+        long.push(name);
+    }
+    Cow::Owned(long.into())
+}
+
+/// `path`, which can be as long as the platform allows.
+#[cfg(not(windows))]
+pub fn long_path(path: &Path) -> Cow<'_, Path> {
+    Cow::Borrowed(path)
+}
+
+/// Split a PEM bundle into its certificates, which the HTTP client can only
+/// take one at a time.
+pub fn split_pem_certificates(pem: &[u8]) -> Vec<Vec<u8>> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+    let pem = String::from_utf8_lossy(pem);
+    let mut rest = &pem[..];
+    let mut certs = vec![];
+    while let Some(start) = rest.find(BEGIN) {
+        let end = match rest[start..].find(END) {
+            Some(end) => start + end + END.len(),
+            None => break,
+        };
+        certs.push(rest[start..end].as_bytes().to_owned());
+        rest = &rest[end..];
+    }
+    certs
+}
+
+/// The client a storage backend makes all its requests with, so that they
+/// reuse its pool of connections rather than connecting, and handshaking
+/// TLS, each time. `SCCACHE_HTTP_POOL_SIZE` limits how many idle
+/// connections to each host are kept open.
+///
+/// The client also trusts the CA certificates in the PEM file that
+/// `SCCACHE_<BACKEND>_CA_BUNDLE` names, `backend` being e.g. `S3`, or if
+/// it's unset `SCCACHE_CA_BUNDLE`, for storage behind an internal CA.
+#[cfg(feature = "reqwest")]
+pub fn http_client(backend: &str) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder();
+    if let Ok(value) = std::env::var("SCCACHE_HTTP_POOL_SIZE") {
+        match value.parse() {
+            Ok(size) => builder = builder.pool_max_idle_per_host(size),
+            Err(_) => warn!("Invalid SCCACHE_HTTP_POOL_SIZE {:?}, ignoring it", value),
+        }
+    }
+    let ca_bundle = std::env::var_os(format!("SCCACHE_{}_CA_BUNDLE", backend))
+        .or_else(|| std::env::var_os("SCCACHE_CA_BUNDLE"));
+    if let Some(path) = ca_bundle {
+        for cert in ca_bundle_certificates(Path::new(&path))? {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    builder.build().context("failed to create HTTP client")
+}
+
+#[cfg(feature = "reqwest")]
+fn ca_bundle_certificates(path: &Path) -> Result<Vec<reqwest::Certificate>> {
+    let pem = std::fs::read(path)
+        .with_context(|| format!("failed to read CA bundle {}", path.display()))?;
+    let pems = split_pem_certificates(&pem);
+    if pems.is_empty() {
+        bail!("no certificates found in CA bundle {}", path.display())
+    }
+    pems.iter()
+        .map(|pem| {
+            reqwest::Certificate::from_pem(pem)
+                .with_context(|| format!("bad certificate in CA bundle {}", path.display()))
+        })
+        .collect()
+}
+
 #[cfg(feature = "hyperx")]
 pub use self::http_extension::{HeadersExt, RequestExt};
 
@@ -384,7 +814,7 @@ mod http_extension {
         {
             self.insert(
                 H::header_name(),
-                HeaderValue::from_shared(header.to_string().into()).unwrap(),
+                HeaderValue::from_maybe_shared(header.to_string()).unwrap(),
             );
         }
 
@@ -392,8 +822,9 @@ mod http_extension {
         where
             H: hyperx::header::Header,
         {
-            http::HeaderMap::get(self, H::header_name())
-                .and_then(|header| H::parse_header(&header.as_bytes().into()).ok())
+            http::HeaderMap::get(self, H::header_name()).and_then(|header| {
+                H::parse_header(&hyperx::header::Raw::from(header.as_bytes())).ok()
+            })
         }
     }
 
@@ -404,53 +835,51 @@ mod http_extension {
     }
 
     impl RequestExt for http::request::Builder {
-        fn set_header<H>(mut self, header: H) -> Self
+        fn set_header<H>(self, header: H) -> Self
         where
             H: hyperx::header::Header + fmt::Display,
         {
             self.header(
                 H::header_name(),
-                HeaderValue::from_shared(header.to_string().into()).unwrap(),
-            );
-            self
+                HeaderValue::from_maybe_shared(header.to_string()).unwrap(),
+            )
         }
     }
 
     impl RequestExt for http::response::Builder {
-        fn set_header<H>(mut self, header: H) -> Self
+        fn set_header<H>(self, header: H) -> Self
         where
             H: hyperx::header::Header + fmt::Display,
         {
             self.header(
                 H::header_name(),
-                HeaderValue::from_shared(header.to_string().into()).unwrap(),
-            );
-            self
+                HeaderValue::from_maybe_shared(header.to_string()).unwrap(),
+            )
         }
     }
 
     #[cfg(feature = "reqwest")]
-    impl RequestExt for ::reqwest::r#async::RequestBuilder {
+    impl RequestExt for ::reqwest::RequestBuilder {
         fn set_header<H>(self, header: H) -> Self
         where
             H: hyperx::header::Header + fmt::Display,
         {
             self.header(
                 H::header_name(),
-                HeaderValue::from_shared(header.to_string().into()).unwrap(),
+                HeaderValue::from_maybe_shared(header.to_string()).unwrap(),
             )
         }
     }
 
     #[cfg(feature = "reqwest")]
-    impl RequestExt for ::reqwest::RequestBuilder {
+    impl RequestExt for ::reqwest::blocking::RequestBuilder {
         fn set_header<H>(self, header: H) -> Self
         where
             H: hyperx::header::Header + fmt::Display,
         {
             self.header(
                 H::header_name(),
-                HeaderValue::from_shared(header.to_string().into()).unwrap(),
+                HeaderValue::from_maybe_shared(header.to_string()).unwrap(),
             )
         }
     }
@@ -546,8 +975,129 @@ pub fn daemonize() -> Result<()> {
 
 #[cfg(test)]
 mod tests {
-    use super::OsStrExt;
+    use super::{
+        copy_file_range, glob_match, hex, relativize_base_dir, secret_var, split_pem_certificates,
+        Digest, HashToDigest, OsStrExt, RecordingDigest, LARGE_HASH_LEN, PARALLEL_HASH_LEN,
+    };
     use std::ffi::{OsStr, OsString};
+    use std::fs::File;
+    use std::hash::Hash;
+    use std::io::{Read, Seek, Write};
+    use std::path::Path;
+
+    #[test]
+    fn test_secret_var() {
+        let dir = tempfile::Builder::new()
+            .prefix("sccache_secret_var")
+            .tempdir()
+            .unwrap();
+        let path = dir.path().join("password");
+        std::fs::write(&path, "hunter2\n").unwrap();
+        std::env::remove_var("SCCACHE_TEST_SECRET");
+        std::env::set_var("SCCACHE_TEST_SECRET_FILE", &path);
+        assert_eq!(
+            secret_var("SCCACHE_TEST_SECRET").unwrap().as_deref(),
+            Some("hunter2")
+        );
+        // The variable itself goes first
+        std::env::set_var("SCCACHE_TEST_SECRET", "letmein");
+        assert_eq!(
+            secret_var("SCCACHE_TEST_SECRET").unwrap().as_deref(),
+            Some("letmein")
+        );
+        std::env::remove_var("SCCACHE_TEST_SECRET");
+        std::env::set_var("SCCACHE_TEST_SECRET_FILE", dir.path().join("missing"));
+        assert!(secret_var("SCCACHE_TEST_SECRET").is_err());
+        std::env::remove_var("SCCACHE_TEST_SECRET_FILE");
+        assert_eq!(secret_var("SCCACHE_TEST_SECRET").unwrap(), None);
+    }
+
+    #[test]
+    fn test_digest_large_file() {
+        let dir = tempfile::Builder::new()
+            .prefix("sccache_digest_large_file")
+            .tempdir()
+            .unwrap();
+        let path = dir.path().join("precompiled.pch");
+        let bytes: Vec<u8> = (0..LARGE_HASH_LEN + 13).map(|i| (i / 3) as u8).collect();
+        std::fs::write(&path, &bytes).unwrap();
+        assert_eq!(
+            Digest::file_sync(&path).unwrap(),
+            Digest::reader_sync(&bytes[..]).unwrap()
+        );
+        assert!(Digest::file_sync(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_copy_file_range() {
+        let dir = tempfile::Builder::new()
+            .prefix("sccache_copy_file_range")
+            .tempdir()
+            .unwrap();
+        let path = dir.path().join("entry");
+        std::fs::write(&path, b"header object trailer").unwrap();
+        let from = File::open(&path).unwrap();
+        let mut to = tempfile::tempfile().unwrap();
+        to.write_all(b"an ").unwrap();
+        copy_file_range(&from, 7, 6, &mut to).unwrap();
+        let mut copied = String::new();
+        to.seek(std::io::SeekFrom::Start(0)).unwrap();
+        to.read_to_string(&mut copied).unwrap();
+        assert_eq!(copied, "an object");
+
+        // Past the end of the file
+        assert!(copy_file_range(&from, 14, 10, &mut to).is_err());
+    }
+
+    #[test]
+    fn test_digest_large_input() {
+        // Hashed on several threads, with the digest of hashing on one
+        let bytes: Vec<u8> = (0..PARALLEL_HASH_LEN * 5 + 7).map(|i| i as u8).collect();
+        let mut m = Digest::new();
+        m.update(b"header");
+        m.update(&bytes);
+        let mut expected = blake3::Hasher::new();
+        expected.update(b"header");
+        expected.update(&bytes);
+        assert_eq!(m.finish(), hex(expected.finalize().as_bytes()));
+    }
+
+    #[test]
+    fn test_recording_digest() {
+        let mut expected = Digest::new();
+        expected.update(b"1");
+        OsStr::new("-c").hash(&mut HashToDigest {
+            digest: &mut expected,
+        });
+        expected.update(b"hello world");
+        let expected = expected.finish();
+
+        for &record in &[false, true] {
+            let mut m = RecordingDigest::new(record);
+            m.update_str("version", "1");
+            m.hash("argument", OsStr::new("-c"));
+            m.update("source", b"hello world");
+            let (key, inputs) = m.finish();
+            assert_eq!(key, expected);
+            if !record {
+                assert!(inputs.is_empty());
+                continue;
+            }
+            let mut source = Digest::new();
+            source.update(b"hello world");
+            assert_eq!(
+                inputs,
+                vec![
+                    ("version".to_owned(), "1".to_owned()),
+                    ("argument".to_owned(), "\"-c\"".to_owned()),
+                    (
+                        "source".to_owned(),
+                        format!("{} (11 bytes)", source.finish())
+                    ),
+                ]
+            );
+        }
+    }
 
     #[test]
     fn simple_starts_with() {
@@ -575,4 +1125,102 @@ mod tests {
         assert_eq!(a.split_prefix("foo2"), None);
         assert_eq!(a.split_prefix("b"), None);
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn relativize_paths_under_base_dir() {
+        let base = Path::new("/home/user/src");
+        let relativize = |bytes: &str, cwd: &str| {
+            String::from_utf8(
+                relativize_base_dir(bytes.as_bytes(), base, cwd.as_ref()).into_owned(),
+            )
+            .unwrap()
+        };
+        assert_eq!(
+            "# 1 \"../include/foo.h\"",
+            relativize(
+                "# 1 \"/home/user/src/include/foo.h\"",
+                "/home/user/src/build"
+            )
+        );
+        assert_eq!(
+            "-I../..",
+            relativize("-I/home/user/src", "/home/user/src/a/b")
+        );
+        assert_eq!(
+            "./foo.c:1: error",
+            relativize("/home/user/src/foo.c:1: error", "/home/user/src")
+        );
+        // Only whole path components match.
+        assert_eq!(
+            "/home/user/srcdir/foo.c",
+            relativize("/home/user/srcdir/foo.c", "/home/user/src/build")
+        );
+        // Working directories outside the base directory are left alone.
+        assert_eq!(
+            "/home/user/src/foo.c",
+            relativize("/home/user/src/foo.c", "/tmp")
+        );
+    }
+
+    #[test]
+    fn glob_matches() {
+        assert!(glob_match("-fmessage-length=*", "-fmessage-length=80"));
+        assert!(glob_match("-fmessage-length=*", "-fmessage-length="));
+        assert!(!glob_match("-fmessage-length=*", "-fmessage-length"));
+        assert!(glob_match("-W", "-W"));
+        assert!(!glob_match("-W", "-Wall"));
+        assert!(glob_match("-W*-paths", "-Wmissing-include-paths"));
+        assert!(!glob_match("-W*-paths", "-Wmissing-include-paths-for"));
+        assert!(glob_match("*TIMESTAMP*", "-DBUILD_TIMESTAMP=1234"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*b*a", "aba"));
+        assert!(!glob_match("a*b*a", "ab"));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_long_path() {
+        let long = |path: &str| super::long_path(Path::new(path)).into_owned();
+        assert_eq!(long(r"C:\a\.\b\..\c"), Path::new(r"\\?\C:\a\c"));
+        assert_eq!(long("C:/a/b"), Path::new(r"\\?\C:\a\b"));
+        assert_eq!(long(r"C:\"), Path::new(r"\\?\C:\"));
+        assert_eq!(
+            long(r"\\server\share\a"),
+            Path::new(r"\\?\UNC\server\share\a")
+        );
+        assert_eq!(long(r"\\?\C:\a\..\b"), Path::new(r"\\?\C:\a\..\b"));
+        assert_eq!(long(r"a\b"), Path::new(r"a\b"));
+        assert_eq!(long("C:a"), Path::new("C:a"));
+    }
+
+    #[cfg(feature = "reqwest")]
+    #[test]
+    fn test_ca_bundle_certificates() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ca.pem");
+        assert!(super::ca_bundle_certificates(&path).is_err());
+        std::fs::write(&path, "not a certificate\n").unwrap();
+        let err = super::ca_bundle_certificates(&path).unwrap_err();
+        assert!(err.to_string().contains("no certificates found"));
+    }
+
+    #[test]
+    fn test_split_pem_certificates() {
+        let pem = b"# Root CA
+-----BEGIN CERTIFICATE-----
+AAAA
+-----END CERTIFICATE-----
+-----BEGIN CERTIFICATE-----
+BBBB
+-----END CERTIFICATE-----
+";
+        assert_eq!(
+            split_pem_certificates(pem),
+            vec![
+                b"-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----".to_vec(),
+                b"-----BEGIN CERTIFICATE-----\nBBBB\n-----END CERTIFICATE-----".to_vec(),
+            ]
+        );
+    }
 }
@@ -0,0 +1,137 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `sccache --generate-xcode-config`: writing the `sccache.xcconfig` and
+//! compiler shims that make Xcode run its clang compiles through sccache.
+//!
+//! Xcode runs the compilers named by the `CC` and `CXX` build settings, so
+//! those point at shell scripts that run the clang of the selected Xcode,
+//! as `xcrun` finds it, through sccache.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use crate::errors::*;
+
+/// The directory, next to the xcconfig, the shims are put in.
+const SHIM_DIR: &str = ".sccache";
+
+/// The compilers that are shimmed, with the build setting naming each.
+const COMPILERS: &[(&str, &str)] = &[("CC", "clang"), ("CXX", "clang++")];
+
+/// The shim running `compiler` through `sccache`.
+fn shim(sccache: &Path, compiler: &str) -> String {
+    format!(
+        "#!/bin/sh\n\
+         # Generated by sccache, to run the {compiler} of the selected Xcode through it.\n\
+         exec {sccache} \"$(/usr/bin/xcrun --find {compiler})\" \"$@\"\n",
+        sccache = shell_quote(&sccache.to_string_lossy()),
+        compiler = compiler
+    )
+}
+
+/// The xcconfig setting the compilers to the shims in `shim_dir`.
+fn xcconfig(shim_dir: &Path) -> String {
+    let mut config = String::from(
+        "// Generated by sccache, to run the compiles through it. Use it with\n\
+         // `xcodebuild -xcconfig <this file>`, or #include it in the xcconfig of\n\
+         // a configuration.\n",
+    );
+    for &(setting, compiler) in COMPILERS {
+        config.push_str(&format!(
+            "{} = {}\n",
+            setting,
+            shim_dir.join(compiler).display()
+        ));
+    }
+    config
+}
+
+/// Quote `s` for the shell, in single quotes.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Write `sccache.xcconfig` to `dir`, with the shims it uses.
+pub fn generate_config(dir: &Path) -> Result<()> {
+    if !cfg!(target_os = "macos") {
+        bail!("Xcode compiles can only be run through sccache on macOS");
+    }
+    let path = dir.join("sccache.xcconfig");
+    if path.exists() {
+        bail!(
+            "{} already exists, generate the files in another directory and merge them into it",
+            path.display()
+        );
+    }
+    let sccache = env::current_exe().context("couldn't find the sccache executable")?;
+    // Xcode runs the compilers from directories of its own
+    let dir = dir
+        .canonicalize()
+        .with_context(|| format!("couldn't find {}", dir.display()))?;
+    let shim_dir = dir.join(SHIM_DIR);
+    fs::create_dir_all(&shim_dir)
+        .with_context(|| format!("couldn't create {}", shim_dir.display()))?;
+    for &(_, compiler) in COMPILERS {
+        let shim_path = shim_dir.join(compiler);
+        write_executable(&shim_path, &shim(&sccache, compiler))
+            .with_context(|| format!("couldn't write {}", shim_path.display()))?;
+    }
+    fs::write(&path, xcconfig(&shim_dir))
+        .with_context(|| format!("couldn't write {}", path.display()))?;
+    println!("Wrote {}", path.display());
+    println!("Wrote the compiler shims to {}", shim_dir.display());
+    Ok(())
+}
+
+#[cfg(unix)]
+fn write_executable(path: &Path, contents: &str) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::write(path, contents)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+}
+
+#[cfg(not(unix))]
+fn write_executable(path: &Path, contents: &str) -> std::io::Result<()> {
+    fs::write(path, contents)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shim() {
+        let shim = shim(Path::new("/Users/me/bin/it's/sccache"), "clang++");
+        assert!(shim.starts_with("#!/bin/sh\n"));
+        assert!(shim.ends_with(
+            "\nexec '/Users/me/bin/it'\\''s/sccache' \"$(/usr/bin/xcrun --find clang++)\" \"$@\"\n"
+        ));
+    }
+
+    #[test]
+    fn test_xcconfig() {
+        let shim_dir = Path::new("/src/app/.sccache");
+        let config = xcconfig(shim_dir);
+        let setting =
+            |name, compiler| format!("\n{} = {}\n", name, shim_dir.join(compiler).display());
+        assert!(config.contains(&setting("CC", "clang")));
+        assert!(config.contains(&setting("CXX", "clang++")));
+        for line in config.lines() {
+            assert!(line.starts_with("//") || line.contains(" = "), "{}", line);
+        }
+    }
+}
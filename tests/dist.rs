@@ -12,8 +12,9 @@ use crate::harness::{
 use assert_cmd::prelude::*;
 use sccache::config::HTTPUrl;
 use sccache::dist::{
-    AssignJobResult, CompileCommand, InputsReader, JobId, JobState, RunJobResult, ServerIncoming,
-    ServerOutgoing, SubmitToolchainResult, Toolchain, ToolchainReader,
+    AssignJobResult, BuildServerMetrics, CompileCommand, Compression, InputsReader, JobId,
+    JobOutcome, JobState, RunJobResult, ServerIncoming, ServerOutgoing, SubmitToolchainResult,
+    Toolchain, ToolchainReader,
 };
 use std::ffi::OsStr;
 use std::path::Path;
@@ -87,6 +88,17 @@ fn test_dist_basic() {
         assert_eq!(0, info.stats.cache_hits.all());
         assert_eq!(1, info.stats.cache_misses.all());
     });
+
+    let details = system.scheduler_details();
+    assert_eq!(1, details.servers.len());
+    assert_eq!(1, details.servers[0].toolchains.len());
+    assert!(details.jobs.is_empty());
+    assert_eq!(1, details.history.len());
+    assert_eq!(JobOutcome::Complete, details.history[0].outcome);
+    assert_eq!(
+        details.servers[0].toolchains[0],
+        details.history[0].toolchain
+    );
 }
 
 #[test]
@@ -171,6 +183,7 @@ impl ServerIncoming for FailingServer {
         &self,
         _requester: &dyn ServerOutgoing,
         _job_id: JobId,
+        _signature: Option<String>,
         _tc_rdr: ToolchainReader,
     ) -> Result<SubmitToolchainResult> {
         panic!("should not have submitted toolchain")
@@ -182,12 +195,16 @@ impl ServerIncoming for FailingServer {
         _command: CompileCommand,
         _outputs: Vec<String>,
         _inputs_rdr: InputsReader,
+        _outputs_compression: Compression,
     ) -> Result<RunJobResult> {
         requester
             .do_update_job_state(job_id, JobState::Started)
             .context("Updating job state failed")?;
         bail!("internal build failure")
     }
+    fn handle_metrics(&self) -> Result<BuildServerMetrics> {
+        Ok(BuildServerMetrics::default())
+    }
 }
 
 #[test]
@@ -1,6 +1,6 @@
 #[cfg(any(feature = "dist-client", feature = "dist-server"))]
 use sccache::config::HTTPUrl;
-use sccache::dist::{self, SchedulerStatusResult, ServerId};
+use sccache::dist::{self, SchedulerDetailsResult, SchedulerStatusResult, ServerId};
 use sccache::server::ServerInfo;
 #[cfg(feature = "dist-server")]
 use std::env;
@@ -140,15 +140,29 @@ pub fn sccache_client_cfg(tmpdir: &Path) -> sccache::config::FileConfig {
             memcached: None,
             redis: None,
             s3: None,
+            layers: vec![],
+            readonly: false,
+            encryption: None,
         },
         dist: sccache::config::DistConfig {
             auth: Default::default(), // dangerously_insecure
             scheduler_url: None,
+            standby_scheduler_urls: vec![],
             cache_dir: tmpdir.join(dist_cache_relpath),
             toolchains: vec![],
             toolchain_cache_size: TC_CACHE_SIZE,
             rewrite_includes_only: false, // TODO
+            tls: None,
+            priority: 0,
+            compression_level: 0,
+            fallback: sccache::config::DistFallback::AlwaysFallback,
+            toolchain_signing_key: None,
         },
+        hash: Default::default(),
+        toolchain_pins: Default::default(),
+        projects: Default::default(),
+        profile: Default::default(),
+        include: vec![],
     }
 }
 #[cfg(feature = "dist-server")]
@@ -159,6 +173,8 @@ fn sccache_scheduler_cfg() -> sccache::config::scheduler::Config {
         server_auth: sccache::config::scheduler::ServerAuth::Token {
             token: DIST_SERVER_TOKEN.to_owned(),
         },
+        tls: None,
+        scheduling: Default::default(),
     }
 }
 #[cfg(feature = "dist-server")]
@@ -178,10 +194,16 @@ fn sccache_server_cfg(
         cache_dir: Path::new(CONFIGS_CONTAINER_PATH).join(relpath),
         public_addr: SocketAddr::new(server_ip, SERVER_PORT),
         scheduler_url,
+        standby_scheduler_urls: vec![],
         scheduler_auth: sccache::config::server::SchedulerAuth::Token {
             token: DIST_SERVER_TOKEN.to_owned(),
         },
         toolchain_cache_size: TC_CACHE_SIZE,
+        tls: None,
+        job_limits: Default::default(),
+        shutdown_timeout_secs: 0,
+        toolchain_signers: vec![],
+        distcc: None,
     }
 }
 
@@ -296,8 +318,14 @@ impl DistSystem {
         wait_for(
             || {
                 let status = self.scheduler_status();
-                if matches!(self.scheduler_status(), SchedulerStatusResult { num_servers: 0, num_cpus: _, in_progress: 0 })
-                {
+                if matches!(
+                    self.scheduler_status(),
+                    SchedulerStatusResult {
+                        num_servers: 0,
+                        num_cpus: _,
+                        in_progress: 0
+                    }
+                ) {
                     Ok(())
                 } else {
                     Err(format!("{:?}", status))
@@ -381,9 +409,14 @@ impl DistSystem {
             listener.local_addr().unwrap()
         };
         let token = create_server_token(ServerId::new(server_addr), DIST_SERVER_TOKEN);
-        let server =
-            dist::http::Server::new(server_addr, self.scheduler_url().to_url(), token, handler)
-                .unwrap();
+        let server = dist::http::Server::new(
+            server_addr,
+            vec![self.scheduler_url().to_url()],
+            Box::new(move || Ok(token.clone())),
+            handler,
+            None,
+        )
+        .unwrap();
         let pid = match unsafe { nix::unistd::fork() }.unwrap() {
             ForkResult::Parent { child } => {
                 self.server_pids.push(child);
@@ -430,8 +463,14 @@ impl DistSystem {
         wait_for(
             || {
                 let status = self.scheduler_status();
-                if matches!(self.scheduler_status(), SchedulerStatusResult { num_servers: 1, num_cpus: _, in_progress: 0 })
-                {
+                if matches!(
+                    self.scheduler_status(),
+                    SchedulerStatusResult {
+                        num_servers: 1,
+                        num_cpus: _,
+                        in_progress: 0
+                    }
+                ) {
                     Ok(())
                 } else {
                     Err(format!("{:?}", status))
@@ -449,7 +488,7 @@ impl DistSystem {
     }
 
     fn scheduler_status(&self) -> SchedulerStatusResult {
-        let res = reqwest::get(dist::http::urls::scheduler_status(
+        let res = reqwest::blocking::get(dist::http::urls::scheduler_status(
             &self.scheduler_url().to_url(),
         ))
         .unwrap();
@@ -457,6 +496,15 @@ impl DistSystem {
         bincode::deserialize_from(res).unwrap()
     }
 
+    pub fn scheduler_details(&self) -> SchedulerDetailsResult {
+        let res = reqwest::blocking::get(dist::http::urls::scheduler_details(
+            &self.scheduler_url().to_url(),
+        ))
+        .unwrap();
+        assert!(res.status().is_success());
+        res.json().unwrap()
+    }
+
     fn container_ip(&self, name: &str) -> IpAddr {
         let output = Command::new("docker")
             .args(&[
@@ -635,7 +683,11 @@ fn wait_for_http(url: HTTPUrl, interval: Duration, max_wait: Duration) {
     wait_for(
         || {
             //match reqwest::get(url.to_url()) {
-            match net::TcpStream::connect(url.to_url()) {
+            let addrs = url
+                .to_url()
+                .socket_addrs(|| None)
+                .map_err(|e| e.to_string())?;
+            match net::TcpStream::connect(&*addrs) {
                 Ok(_) => Ok(()),
                 Err(e) => Err(e.to_string()),
             }
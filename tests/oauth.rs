@@ -54,11 +54,22 @@ fn config_with_dist_auth(
         dist: sccache::config::DistConfig {
             auth: auth_config,
             scheduler_url: None,
+            standby_scheduler_urls: vec![],
             cache_dir: tmpdir.join("unused-cache"),
             toolchains: vec![],
             toolchain_cache_size: 0,
             rewrite_includes_only: true,
+            tls: None,
+            priority: 0,
+            compression_level: 0,
+            fallback: sccache::config::DistFallback::AlwaysFallback,
+            toolchain_signing_key: None,
         },
+        hash: Default::default(),
+        toolchain_pins: Default::default(),
+        projects: Default::default(),
+        profile: Default::default(),
+        include: vec![],
     }
 }
 
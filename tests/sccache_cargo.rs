@@ -114,15 +114,14 @@ fn test_rust_cargo_cmd(cmd: &str) {
         .assert()
         .stderr(predicates::str::contains("\x1b[").from_utf8())
         .success();
-    // Now get the stats and ensure that we had a cache hit for the second build.
-    // Ideally we'd check the stats more usefully here--the test crate has one dependency (itoa)
-    // so there are two separate compilations, but cargo will build the test crate with
-    // incremental compilation enabled, so sccache will not cache it.
+    // Now get the stats and ensure that we had cache hits for the second build.
+    // The test crate has one dependency (itoa) so there are two separate compilations,
+    // and the test crate itself is built with incremental compilation enabled.
     trace!("sccache --show-stats");
     sccache_command()
         .args(&["--show-stats", "--stats-format=json"])
         .assert()
-        .stdout(predicates::str::contains(r#""cache_hits":{"counts":{"Rust":1}}"#).from_utf8())
+        .stdout(predicates::str::contains(r#""cache_hits":{"counts":{"Rust":2}}"#).from_utf8())
         .success();
     stop();
 }